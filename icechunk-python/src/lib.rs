@@ -319,7 +319,7 @@ impl PyIcechunkStore {
         self.consolidated.storage == other.consolidated().storage
     }
 
-    fn as_bytes(&self) -> PyResult<Cow<[u8]>> {
+    fn as_bytes(&self) -> PyResult<Cow<'_, [u8]>> {
         let consolidated =
             pyo3_asyncio_0_21::tokio::get_runtime().block_on(self.as_consolidated())?;
 
@@ -938,12 +938,12 @@ async fn do_distributed_commit(
     Ok(String::from(&oid))
 }
 
-async fn do_reset<'py>(store: Arc<RwLock<Store>>) -> PyResult<()> {
+async fn do_reset(store: Arc<RwLock<Store>>) -> PyResult<()> {
     store.write().await.reset().await.map_err(PyIcechunkStoreError::StoreError)?;
     Ok(())
 }
 
-async fn do_new_branch<'py>(
+async fn do_new_branch(
     store: Arc<RwLock<Store>>,
     branch_name: String,
 ) -> PyResult<String> {
@@ -955,7 +955,7 @@ async fn do_new_branch<'py>(
     Ok(String::from(&oid))
 }
 
-async fn do_tag<'py>(
+async fn do_tag(
     store: Arc<RwLock<Store>>,
     tag: String,
     snapshot_id: String,
@@ -7,6 +7,7 @@ use icechunk::{
     storage::{
         s3::{S3Config, S3Credentials, StaticS3Credentials},
         virtual_ref::ObjectStoreVirtualChunkResolverConfig,
+        KeyLayout,
     },
     zarr::StorageConfig,
 };
@@ -152,12 +153,14 @@ fn mk_credentials(config: Option<&PyS3Credentials>, anon: bool) -> S3Credentials
 impl From<&PyStorageConfig> for StorageConfig {
     fn from(storage: &PyStorageConfig) -> Self {
         match storage {
-            PyStorageConfig::Memory { prefix } => {
-                StorageConfig::InMemory { prefix: prefix.clone() }
-            }
-            PyStorageConfig::Filesystem { root } => {
-                StorageConfig::LocalFileSystem { root: PathBuf::from(root.clone()) }
-            }
+            PyStorageConfig::Memory { prefix } => StorageConfig::InMemory {
+                prefix: prefix.clone(),
+                layout: KeyLayout::default(),
+            },
+            PyStorageConfig::Filesystem { root } => StorageConfig::LocalFileSystem {
+                root: PathBuf::from(root.clone()),
+                layout: KeyLayout::default(),
+            },
             PyStorageConfig::S3 {
                 bucket,
                 prefix,
@@ -172,12 +175,14 @@ impl From<&PyStorageConfig> for StorageConfig {
                     credentials: mk_credentials(credentials.as_ref(), *anon),
                     endpoint: endpoint_url.clone(),
                     allow_http: allow_http.unwrap_or(false),
+                    ..Default::default()
                 };
 
                 StorageConfig::S3ObjectStore {
                     bucket: bucket.clone(),
                     prefix: prefix.clone(),
-                    config: Some(s3_config),
+                    config: Some(Box::new(s3_config)),
+                    layout: KeyLayout::default(),
                 }
             }
         }
@@ -258,6 +263,7 @@ impl From<&PyVirtualRefConfig> for ObjectStoreVirtualChunkResolverConfig {
                 endpoint: endpoint_url.clone(),
                 credentials: mk_credentials(credentials.as_ref(), *anon),
                 allow_http: allow_http.unwrap_or(false),
+                ..Default::default()
             }),
         }
     }
@@ -0,0 +1,73 @@
+#![allow(clippy::unwrap_used, clippy::expect_used)]
+//! End to end commit benchmarks against in-memory storage.
+//!
+//! Run with `cargo bench -p icechunk --bench commit`.
+use std::{num::NonZeroU64, sync::Arc};
+
+use bytes::Bytes;
+use criterion::{
+    criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use icechunk::{
+    format::{manifest::ChunkPayload, ChunkIndices},
+    repository::{
+        ChunkKeyEncoding, ChunkShape, DataType, FillValue, Path, ZarrArrayMetadata,
+    },
+    ObjectStorage, Repository, Storage,
+};
+use tokio::runtime::Runtime;
+
+fn array_metadata(chunks: u64) -> ZarrArrayMetadata {
+    ZarrArrayMetadata {
+        shape: vec![chunks],
+        data_type: DataType::Int32,
+        chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+        chunk_key_encoding: ChunkKeyEncoding::Slash,
+        fill_value: FillValue::Int32(0),
+        codecs: vec![],
+        storage_transformers: None,
+        dimension_names: None,
+        rectilinear_grid: None,
+    }
+}
+
+/// A repository with an array and `chunks` uncommitted inline chunks
+async fn repository_with_changes(chunks: u64) -> Repository {
+    let storage: Arc<dyn Storage> = Arc::new(ObjectStorage::new_in_memory_store(None));
+    let mut repo = Repository::init(storage, false).await.unwrap().build();
+    let path: Path = "/array".try_into().unwrap();
+    repo.add_array(path.clone(), array_metadata(chunks)).await.unwrap();
+    for i in 0..chunks {
+        repo.set_chunk_ref(
+            path.clone(),
+            ChunkIndices(vec![i]),
+            Some(ChunkPayload::Inline(Bytes::copy_from_slice(&i.to_le_bytes()))),
+        )
+        .await
+        .unwrap();
+    }
+    repo
+}
+
+fn commit(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("commit");
+    group.sample_size(10);
+    for chunks in [1_000, 100_000] {
+        group.throughput(Throughput::Elements(chunks));
+        group.bench_with_input(BenchmarkId::from_parameter(chunks), &chunks, |b, &n| {
+            b.iter_batched(
+                || runtime.block_on(repository_with_changes(n)),
+                |mut repo| {
+                    runtime.block_on(repo.commit("main", "bench", None)).unwrap();
+                    repo
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, commit);
+criterion_main!(benches);
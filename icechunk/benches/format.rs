@@ -0,0 +1,108 @@
+#![allow(clippy::unwrap_used, clippy::expect_used)]
+//! Benchmarks for building, searching and serializing manifests.
+//!
+//! Run with `cargo bench -p icechunk --bench format`. The 10M chunk manifests need a
+//! couple of GB of memory.
+use std::convert::Infallible;
+
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion,
+    Throughput,
+};
+use futures::stream;
+use icechunk::format::{
+    manifest::{ChunkInfo, ChunkPayload, ChunkRef, Manifest},
+    ChunkId, ChunkIndices,
+};
+
+const NODE: u32 = 1;
+
+/// Coordinates in a cube with `n` chunks per side, in row-major order
+fn cube_coords(n: u64) -> impl Iterator<Item = ChunkIndices> {
+    (0..n * n * n).map(move |i| ChunkIndices(vec![i / (n * n), (i / n) % n, i % n]))
+}
+
+fn chunk_infos(n: u64) -> impl Iterator<Item = ChunkInfo> {
+    let id = ChunkId::random();
+    cube_coords(n).enumerate().map(move |(i, coord)| ChunkInfo {
+        node: NODE,
+        coord,
+        payload: ChunkPayload::Ref(ChunkRef {
+            id: id.clone(),
+            offset: i as u64 * 1024,
+            length: 1024,
+        }),
+    })
+}
+
+fn build_manifest(n: u64) -> Manifest {
+    let chunks = stream::iter(chunk_infos(n).map(Ok::<_, Infallible>));
+    futures::executor::block_on(Manifest::from_stream(chunks)).unwrap()
+}
+
+fn manifest_build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("manifest_build");
+    group.sample_size(10);
+    // 100^3 and 216^3 chunks, ~1M and ~10M
+    for side in [100, 216] {
+        let chunks = side * side * side;
+        group.throughput(Throughput::Elements(chunks));
+        group.bench_with_input(BenchmarkId::from_parameter(chunks), &side, |b, &side| {
+            b.iter(|| build_manifest(side))
+        });
+    }
+    group.finish();
+}
+
+fn chunk_lookup(c: &mut Criterion) {
+    let side = 50;
+    let manifest = build_manifest(side);
+    let coords: Vec<_> = cube_coords(side).step_by(997).collect();
+    let mut group = c.benchmark_group("chunk_lookup");
+
+    // clones start without a hash index, so the first lookups search the sorted map
+    let unindexed = 4;
+    group.throughput(Throughput::Elements(unindexed as u64));
+    group.bench_function("linear", |b| {
+        b.iter_batched(
+            || manifest.clone(),
+            |fresh| {
+                for coord in coords.iter().take(unindexed) {
+                    black_box(fresh.get_chunk_payload(NODE, coord.clone()).unwrap());
+                }
+                fresh
+            },
+            BatchSize::LargeInput,
+        )
+    });
+
+    for coord in coords.iter().cycle().take(16) {
+        manifest.get_chunk_payload(NODE, coord.clone()).unwrap();
+    }
+    group.throughput(Throughput::Elements(coords.len() as u64));
+    group.bench_function("indexed", |b| {
+        b.iter(|| {
+            for coord in coords.iter() {
+                black_box(manifest.get_chunk_payload(NODE, coord.clone()).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+fn coords_msgpack(c: &mut Criterion) {
+    let manifest = build_manifest(50);
+    let bytes = rmp_serde::to_vec(&manifest).unwrap();
+    let mut group = c.benchmark_group("coords_msgpack");
+    group.throughput(Throughput::Elements(manifest.len() as u64));
+    group.bench_function("encode", |b| {
+        b.iter(|| rmp_serde::to_vec(black_box(&manifest)).unwrap())
+    });
+    group.bench_function("decode", |b| {
+        b.iter(|| rmp_serde::from_slice::<Manifest>(black_box(&bytes)).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, manifest_build, chunk_lookup, coords_msgpack);
+criterion_main!(benches);
@@ -0,0 +1,52 @@
+#![allow(clippy::unwrap_used, clippy::expect_used)]
+//! Benchmarks for operations that walk the history of a repository.
+//!
+//! Run with `cargo bench -p icechunk --bench history`.
+use std::sync::Arc;
+
+use chrono::{TimeDelta, Utc};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures::TryStreamExt;
+use icechunk::{
+    gc::{garbage_collect, GcConfig},
+    synthetic::{generate, SyntheticRepoConfig},
+    ObjectStorage, Repository, Storage,
+};
+use tokio::runtime::Runtime;
+
+fn history(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("history");
+    group.sample_size(10);
+    for commits in [10, 100] {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let config = SyntheticRepoConfig { commits, ..Default::default() };
+        let synthetic =
+            runtime.block_on(generate(Arc::clone(&storage), &config)).unwrap();
+
+        let repo =
+            Repository::update(Arc::clone(&storage), synthetic.tip().clone()).build();
+        group.bench_function(BenchmarkId::new("ancestry", commits), |b| {
+            b.iter(|| {
+                runtime.block_on(async {
+                    let parents: Vec<_> =
+                        repo.ancestry().await.unwrap().try_collect().await.unwrap();
+                    parents
+                })
+            })
+        });
+
+        // nothing is old enough to be collected, this measures the reachability walk
+        let gc_config = GcConfig::new(Utc::now() - TimeDelta::hours(1));
+        group.bench_function(BenchmarkId::new("gc", commits), |b| {
+            b.iter(|| {
+                runtime.block_on(garbage_collect(storage.as_ref(), &gc_config)).unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, history);
+criterion_main!(benches);
@@ -19,16 +19,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!(
         r#"
 ```
-let storage: Arc<dyn Storage + Send + Sync> = Arc::new(InMemoryStorage::new());
-let storage: Arc<dyn Storage + Send + Sync> =
+let storage: Arc<dyn Storage> = Arc::new(InMemoryStorage::new());
+let storage: Arc<dyn Storage> =
     Arc::new(MemCachingStorage::new(storage, 100_000_000));
 let mut ds = Repository::create(Arc::clone(&storage));
 ```
 "#,
     );
 
-    let storage: Arc<dyn Storage + Send + Sync> =
-        Arc::new(ObjectStorage::new_in_memory_store(None));
+    let storage: Arc<dyn Storage> = Arc::new(ObjectStorage::new_in_memory_store(None));
     let mut ds = Repository::init(
         Arc::new(MemCachingStorage::new(Arc::clone(&storage), 2, 2, 0, 0)),
         false,
@@ -90,6 +89,7 @@ let zarr_meta1 = ZarrArrayMetadata {{
         Some("y".to_string()),
         Some("t".to_string()),
     ]),
+    rectilinear_grid: None,
 }};
 
 let array1_path: Path = "/group1/array1".into();
@@ -128,6 +128,7 @@ ds.add_array(array1_path.clone(), zarr_meta1).await?;
             Some("y".to_string()),
             Some("t".to_string()),
         ]),
+        rectilinear_grid: None,
     };
     let array1_path: Path = "/group1/array1".try_into().unwrap();
     ds.add_array(array1_path.clone(), zarr_meta1).await?;
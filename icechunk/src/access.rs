@@ -0,0 +1,170 @@
+//! Chunk access logging.
+//!
+//! A [`ChunkAccessListener`] registered with
+//! [`crate::RepositoryBuilder::with_chunk_access_listener`] learns about every chunk read
+//! by the repository: which chunk, how many bytes, where they came from and whether a
+//! cache served them. Aggregated over time, the accesses show which arrays and chunks
+//! are popular, to plan caching tiers and manifest sharding. [`AccessCounter`] is a
+//! listener that counts reads per chunk.
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Mutex, PoisonError},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    format::{ChunkIndices, Path},
+    storage::CacheStatus,
+};
+
+/// Where the bytes of a chunk are stored
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ChunkSource {
+    /// In the manifest
+    Inline,
+    /// In a chunk object written by icechunk
+    Stored,
+    /// In a file outside the repository
+    Virtual,
+}
+
+/// One successful chunk read
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkAccess {
+    pub path: Path,
+    pub coords: ChunkIndices,
+    /// The bytes returned, for partial reads only the requested range
+    pub bytes: u64,
+    pub source: ChunkSource,
+    pub cache: CacheStatus,
+}
+
+/// Receives every chunk read, implementations must be cheap and must not block
+pub trait ChunkAccessListener: fmt::Debug + Send + Sync {
+    fn on_chunk_read(&self, access: &ChunkAccess);
+}
+
+/// The reads of one chunk, see [`AccessCounter`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkReads {
+    pub reads: u64,
+    pub bytes: u64,
+    pub cache_hits: u64,
+}
+
+/// A [`ChunkAccessListener`] that counts the reads of every chunk
+#[derive(Debug, Default)]
+pub struct AccessCounter {
+    counts: Mutex<HashMap<(Path, ChunkIndices), ChunkReads>>,
+}
+
+impl AccessCounter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn reads(&self, path: &Path, coords: &ChunkIndices) -> ChunkReads {
+        let counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        counts.get(&(path.clone(), coords.clone())).copied().unwrap_or_default()
+    }
+
+    /// The `n` most read chunks, most read first
+    pub fn hottest(&self, n: usize) -> Vec<(Path, ChunkIndices, ChunkReads)> {
+        let counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut res: Vec<_> = counts
+            .iter()
+            .map(|((path, coords), reads)| (path.clone(), coords.clone(), *reads))
+            .collect();
+        res.sort_by(|a, b| {
+            b.2.reads.cmp(&a.2.reads).then_with(|| (&a.0, &a.1).cmp(&(&b.0, &b.1)))
+        });
+        res.truncate(n);
+        res
+    }
+
+    /// Forget all reads
+    pub fn reset(&self) {
+        self.counts.lock().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+}
+
+impl ChunkAccessListener for AccessCounter {
+    fn on_chunk_read(&self, access: &ChunkAccess) {
+        let mut counts = self.counts.lock().unwrap_or_else(PoisonError::into_inner);
+        let reads =
+            counts.entry((access.path.clone(), access.coords.clone())).or_default();
+        reads.reads += 1;
+        reads.bytes += access.bytes;
+        if access.cache == CacheStatus::Hit {
+            reads.cache_hits += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::ByteRange, metadata::FillValue, repository::get_chunk,
+        synthetic::vector_metadata, MemCachingStorage, ObjectStorage, Repository,
+        Storage,
+    };
+
+    #[tokio::test]
+    async fn test_access_counter() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let storage: Arc<dyn Storage> =
+            Arc::new(MemCachingStorage::new(backend, 2, 2, 2, 2));
+        let counter = Arc::new(AccessCounter::new());
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(2)
+            .with_chunk_access_listener(counter.clone())
+            .build();
+        let array: Path = "/array".try_into()?;
+        repo.add_group(Path::root()).await?;
+        repo.add_array(array.clone(), vector_metadata(2, 1, FillValue::UInt8(0))).await?;
+        let stored = ChunkIndices(vec![0]);
+        let inline = ChunkIndices(vec![1]);
+        let payload = repo.get_chunk_writer()(Bytes::from(vec![1; 16])).await?;
+        repo.set_chunk_ref(array.clone(), stored.clone(), Some(payload)).await?;
+        let payload = repo.get_chunk_writer()(Bytes::from_static(b"a")).await?;
+        repo.set_chunk_ref(array.clone(), inline.clone(), Some(payload)).await?;
+
+        for _ in 0..3 {
+            let reader = repo.get_chunk_reader(&array, &stored, &ByteRange::ALL).await?;
+            get_chunk(reader).await?;
+        }
+        let reader = repo.get_chunk_reader(&array, &inline, &ByteRange::ALL).await?;
+        get_chunk(reader).await?;
+
+        // the first read of the stored chunk misses the cache
+        assert_eq!(
+            counter.reads(&array, &stored),
+            ChunkReads { reads: 3, bytes: 48, cache_hits: 2 }
+        );
+        assert_eq!(
+            counter.hottest(2),
+            vec![
+                (
+                    array.clone(),
+                    stored,
+                    ChunkReads { reads: 3, bytes: 48, cache_hits: 2 }
+                ),
+                (array, inline, ChunkReads { reads: 1, bytes: 1, cache_hits: 0 }),
+            ]
+        );
+        counter.reset();
+        assert_eq!(counter.hottest(2), vec![]);
+        Ok(())
+    }
+}
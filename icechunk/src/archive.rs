@@ -0,0 +1,418 @@
+//! Single file copies of a snapshot, for air-gapped transfers and long-term archival.
+//!
+//! [`export_archive`] writes a tarball with every object a snapshot needs: the snapshot,
+//! the snapshots its concatenated arrays read from, their manifests, packfiles, attribute
+//! tables and chunks. The first entry, `manifest.json`, lists the keys of all the others,
+//! in the order they follow. Objects are written in dependency order, snapshots last,
+//! and [`import_archive`] stores them in the same order, so an interrupted import leaves
+//! no snapshot without its objects.
+//!
+//! Refs and the ancestors of the snapshot are not archived: create a tag or a branch
+//! pointing to the imported snapshot with [`crate::refs`]. Virtual chunks keep pointing
+//! to their original location.
+//!
+//! Archives are plain ustar files, written and read without buffering the objects of the
+//! whole archive.
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt,
+    io::{self, Read, Write},
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    error::ErrorKind,
+    format::{
+        manifest::ChunkPayload, AttributesId, ByteRange, ChunkId, ManifestId, SnapshotId,
+    },
+    gc::{reachable_objects, GcError, Reachable},
+    progress::{NoProgress, ProgressListener, ProgressOperation, ProgressTracker},
+    Storage, StorageError,
+};
+
+/// The version of the archive layout written by [`export_archive`]
+pub const ARCHIVE_FORMAT_VERSION: u16 = 1;
+
+const MANIFEST_ENTRY: &str = "manifest.json";
+const BLOCK_SIZE: usize = 512;
+// the ustar name field, without a prefix
+const MAX_NAME_LEN: usize = 100;
+// the largest size that fits the 11 octal digits of the size field
+const MAX_ENTRY_SIZE: u64 = 0o77777777777;
+
+/// The first entry of an archive
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    pub version: u16,
+    pub snapshot: SnapshotId,
+    /// The keys of the other entries, like `chunks/{id}`, in the order they are written
+    pub keys: Vec<String>,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ArchiveError {
+    #[error("error contacting storage {0}")]
+    Storage(#[from] StorageError),
+    #[error("cannot find the objects of the snapshot {0}")]
+    Gc(#[from] GcError),
+    #[error("archive i/o error {0}")]
+    Io(#[from] io::Error),
+    #[error("cannot serialize object {0}")]
+    Serialization(#[from] rmp_serde::encode::Error),
+    #[error("cannot deserialize object {0}")]
+    Deserialization(#[from] rmp_serde::decode::Error),
+    #[error("invalid archive manifest {0}")]
+    Manifest(#[from] serde_json::Error),
+    #[error("invalid archive: {0}")]
+    Invalid(String),
+}
+
+impl ArchiveError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            ArchiveError::Storage(err) | ArchiveError::Gc(GcError::Storage(err)) => {
+                err.kind()
+            }
+            ArchiveError::Deserialization(_)
+            | ArchiveError::Manifest(_)
+            | ArchiveError::Invalid(_) => ErrorKind::Corruption,
+            ArchiveError::Gc(_)
+            | ArchiveError::Io(_)
+            | ArchiveError::Serialization(_) => ErrorKind::Other,
+        }
+    }
+}
+
+pub type ArchiveResult<A> = Result<A, ArchiveError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ArchiveKey {
+    Chunk(ChunkId),
+    Attributes(AttributesId),
+    Manifest(ManifestId),
+    Snapshot(SnapshotId),
+}
+
+impl ArchiveKey {
+    fn parse(key: &str) -> ArchiveResult<Self> {
+        let invalid = || ArchiveError::Invalid(format!("unknown entry `{key}`"));
+        let (prefix, id) = key.split_once('/').ok_or_else(invalid)?;
+        match prefix {
+            "chunks" => id.try_into().map(ArchiveKey::Chunk),
+            "attributes" => id.try_into().map(ArchiveKey::Attributes),
+            "manifests" => id.try_into().map(ArchiveKey::Manifest),
+            "snapshots" => id.try_into().map(ArchiveKey::Snapshot),
+            _ => return Err(invalid()),
+        }
+        .map_err(|_| invalid())
+    }
+}
+
+impl fmt::Display for ArchiveKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveKey::Chunk(id) => write!(f, "chunks/{id}"),
+            ArchiveKey::Attributes(id) => write!(f, "attributes/{id}"),
+            ArchiveKey::Manifest(id) => write!(f, "manifests/{id}"),
+            ArchiveKey::Snapshot(id) => write!(f, "snapshots/{id}"),
+        }
+    }
+}
+
+// the keys of the objects `snapshot` needs, in the order they can be stored
+async fn archive_keys(
+    storage: &dyn Storage,
+    snapshot: &SnapshotId,
+) -> ArchiveResult<Vec<ArchiveKey>> {
+    let Reachable { snapshots, manifests, attributes, packs } =
+        reachable_objects(storage, HashSet::from([snapshot.clone()])).await?;
+    let attributes: BTreeSet<_> = attributes.into_iter().collect();
+    let mut chunks: BTreeSet<_> = packs.into_iter().collect();
+    for id in manifests.iter() {
+        let manifest = storage.fetch_manifests(id).await?;
+        chunks.extend(manifest.chunks().values().filter_map(|payload| match payload {
+            ChunkPayload::Ref(chunk_ref) => Some(chunk_ref.id.clone()),
+            _ => None,
+        }));
+    }
+    let manifests: BTreeSet<_> = manifests.into_iter().collect();
+    // the archived snapshot goes last, after the sources of its concatenated arrays
+    let sources: BTreeSet<_> =
+        snapshots.into_iter().filter(|id| id != snapshot).collect();
+    Ok(chunks
+        .into_iter()
+        .map(ArchiveKey::Chunk)
+        .chain(attributes.into_iter().map(ArchiveKey::Attributes))
+        .chain(manifests.into_iter().map(ArchiveKey::Manifest))
+        .chain(sources.into_iter().map(ArchiveKey::Snapshot))
+        .chain([ArchiveKey::Snapshot(snapshot.clone())])
+        .collect())
+}
+
+/// Write the objects of `snapshot` as a tarball to `writer`, returning its manifest
+pub async fn export_archive<W: Write>(
+    storage: &dyn Storage,
+    snapshot: &SnapshotId,
+    writer: W,
+) -> ArchiveResult<ArchiveManifest> {
+    export_archive_with_progress(storage, snapshot, writer, Arc::new(NoProgress)).await
+}
+
+/// Like [`export_archive`], reporting each object written to `progress` as a
+/// [`ProgressOperation::ArchiveExport`]
+pub async fn export_archive_with_progress<W: Write>(
+    storage: &dyn Storage,
+    snapshot: &SnapshotId,
+    mut writer: W,
+    progress: Arc<dyn ProgressListener>,
+) -> ArchiveResult<ArchiveManifest> {
+    let keys = archive_keys(storage, snapshot).await?;
+    let progress = ProgressTracker::new(
+        progress,
+        ProgressOperation::ArchiveExport,
+        Some(keys.len() as u64),
+    );
+    let manifest = ArchiveManifest {
+        version: ARCHIVE_FORMAT_VERSION,
+        snapshot: snapshot.clone(),
+        keys: keys.iter().map(ToString::to_string).collect(),
+    };
+    write_entry(&mut writer, MANIFEST_ENTRY, &serde_json::to_vec(&manifest)?)?;
+    for key in keys {
+        let bytes: Bytes = match &key {
+            ArchiveKey::Chunk(id) => storage.fetch_chunk(id, &ByteRange::ALL).await?,
+            ArchiveKey::Attributes(id) => {
+                rmp_serde::to_vec(storage.fetch_attributes(id).await?.as_ref())?.into()
+            }
+            ArchiveKey::Manifest(id) => {
+                rmp_serde::to_vec(storage.fetch_manifests(id).await?.as_ref())?.into()
+            }
+            ArchiveKey::Snapshot(id) => {
+                rmp_serde::to_vec(storage.fetch_snapshot(id).await?.as_ref())?.into()
+            }
+        };
+        write_entry(&mut writer, &key.to_string(), &bytes)?;
+        let chunk_bytes = match key {
+            ArchiveKey::Chunk(_) => bytes.len() as u64,
+            _ => 0,
+        };
+        progress.advance(1, chunk_bytes);
+    }
+    // the end of archive marker
+    writer.write_all(&[0; 2 * BLOCK_SIZE])?;
+    writer.flush()?;
+    Ok(manifest)
+}
+
+/// Store the objects of an archive written by [`export_archive`] in `storage`.
+///
+/// Entries are checked against the manifest as they are read, objects stored before an
+/// invalid entry is found stay in storage, unreachable.
+pub async fn import_archive<R: Read>(
+    storage: &dyn Storage,
+    mut reader: R,
+) -> ArchiveResult<ArchiveManifest> {
+    let manifest: ArchiveManifest = match read_entry(&mut reader)? {
+        Some((name, bytes)) if name == MANIFEST_ENTRY => serde_json::from_slice(&bytes)?,
+        _ => {
+            return Err(ArchiveError::Invalid(format!(
+                "the first entry is not `{MANIFEST_ENTRY}`"
+            )))
+        }
+    };
+    if manifest.version > ARCHIVE_FORMAT_VERSION {
+        return Err(ArchiveError::Invalid(format!(
+            "archive version {} is newer than {ARCHIVE_FORMAT_VERSION}",
+            manifest.version
+        )));
+    }
+    for expected in manifest.keys.iter() {
+        let Some((name, bytes)) = read_entry(&mut reader)? else {
+            return Err(ArchiveError::Invalid(format!("missing entry `{expected}`")));
+        };
+        if &name != expected {
+            return Err(ArchiveError::Invalid(format!(
+                "found entry `{name}`, expected `{expected}`"
+            )));
+        }
+        match ArchiveKey::parse(&name)? {
+            ArchiveKey::Chunk(id) => storage.write_chunk(id, Bytes::from(bytes)).await?,
+            ArchiveKey::Attributes(id) => {
+                let table = Arc::new(rmp_serde::from_slice(&bytes)?);
+                storage.write_attributes(id, table).await?
+            }
+            ArchiveKey::Manifest(id) => {
+                let table = Arc::new(rmp_serde::from_slice(&bytes)?);
+                storage.write_manifests(id, table).await?
+            }
+            ArchiveKey::Snapshot(id) => {
+                let table = Arc::new(rmp_serde::from_slice(&bytes)?);
+                storage.write_snapshot(id, table).await?
+            }
+        }
+    }
+    if let Some((name, _)) = read_entry(&mut reader)? {
+        return Err(ArchiveError::Invalid(format!("unexpected entry `{name}`")));
+    }
+    Ok(manifest)
+}
+
+fn write_octal(field: &mut [u8], value: u64) {
+    // the last byte of the field is a terminating NUL
+    let digits = format!("{value:0width$o}", width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+}
+
+fn checksum(header: &[u8; BLOCK_SIZE]) -> u64 {
+    // computed with the checksum field as spaces
+    header
+        .iter()
+        .enumerate()
+        .map(
+            |(index, byte)| {
+                if (148..156).contains(&index) {
+                    32
+                } else {
+                    u64::from(*byte)
+                }
+            },
+        )
+        .sum()
+}
+
+fn write_entry(writer: &mut impl Write, name: &str, data: &[u8]) -> ArchiveResult<()> {
+    let size = data.len() as u64;
+    if name.len() >= MAX_NAME_LEN || size > MAX_ENTRY_SIZE {
+        return Err(ArchiveError::Invalid(format!("entry `{name}` cannot be archived")));
+    }
+    let mut header = [0u8; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], 0o644);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size);
+    write_octal(&mut header[136..148], 0);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    let sum = checksum(&header);
+    write_octal(&mut header[148..155], sum);
+    header[155] = b' ';
+
+    writer.write_all(&header)?;
+    writer.write_all(data)?;
+    let padding = (BLOCK_SIZE - data.len() % BLOCK_SIZE) % BLOCK_SIZE;
+    writer.write_all(&[0; BLOCK_SIZE][..padding])?;
+    Ok(())
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let digits = std::str::from_utf8(field).ok()?.trim_matches(['\0', ' ']);
+    u64::from_str_radix(digits, 8).ok()
+}
+
+// the name and contents of the next entry, None at the end of the archive
+fn read_entry(reader: &mut impl Read) -> ArchiveResult<Option<(String, Vec<u8>)>> {
+    let mut header = [0u8; BLOCK_SIZE];
+    reader.read_exact(&mut header)?;
+    if header.iter().all(|byte| *byte == 0) {
+        return Ok(None);
+    }
+    let invalid = |message: &str| ArchiveError::Invalid(message.to_string());
+    if parse_octal(&header[148..156]) != Some(checksum(&header)) {
+        return Err(invalid("wrong header checksum"));
+    }
+    if !matches!(header[156], b'0' | 0) {
+        return Err(invalid("only regular files can be archived"));
+    }
+    let name_len = header[..MAX_NAME_LEN].iter().position(|byte| *byte == 0);
+    let name = std::str::from_utf8(&header[..name_len.unwrap_or(MAX_NAME_LEN)])
+        .map_err(|_| invalid("entry name is not utf-8"))?
+        .to_string();
+    let size =
+        parse_octal(&header[124..136]).ok_or_else(|| invalid("wrong entry size"))?;
+    let size = usize::try_from(size).map_err(|_| invalid("entry too large"))?;
+
+    let mut data = vec![0; size];
+    reader.read_exact(&mut data)?;
+    let padding = (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE;
+    reader.read_exact(&mut [0; BLOCK_SIZE][..padding])?;
+    Ok(Some((name, data)))
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{ChunkIndices, Path},
+        metadata::{FillValue, UserAttributes},
+        progress::{Progress, RecordingProgress},
+        refs::create_tag,
+        synthetic::vector_metadata,
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_archive_round_trip() -> Result<(), Box<dyn Error>> {
+        let source: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("source".into())));
+        let mut repo = Repository::init(Arc::clone(&source), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let path: Path = "/array".try_into().unwrap();
+        repo.add_group(Path::root()).await?;
+        repo.add_array(path.clone(), vector_metadata(4, 2, FillValue::UInt8(0))).await?;
+        let atts = UserAttributes { parsed: serde_json::json!({ "units": "m" }) };
+        repo.set_user_attributes(path.clone(), Some(atts)).await?;
+        let payload = repo.get_chunk_writer()(Bytes::from_static(b"ab")).await?;
+        repo.set_chunk_ref(path.clone(), ChunkIndices(vec![1]), Some(payload)).await?;
+        let snapshot = repo.commit("main", "data", None).await?;
+
+        let mut archive = Vec::new();
+        let progress = Arc::new(RecordingProgress::default());
+        let exported = export_archive_with_progress(
+            source.as_ref(),
+            &snapshot,
+            &mut archive,
+            progress.clone(),
+        )
+        .await?;
+        assert_eq!(exported.keys.last(), Some(&format!("snapshots/{snapshot}")));
+        assert!(exported.keys.iter().any(|key| key.starts_with("chunks/")));
+        let objects = exported.keys.len() as u64;
+        assert_eq!(progress.updates().len() as u64, objects);
+        assert_eq!(
+            progress.updates().last(),
+            Some(&(
+                ProgressOperation::ArchiveExport,
+                Progress { done: objects, total: Some(objects), bytes: 2 }
+            ))
+        );
+
+        let target: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("target".into())));
+        let imported = import_archive(target.as_ref(), archive.as_slice()).await?;
+        assert_eq!(imported, exported);
+        create_tag(target.as_ref(), "archived", snapshot.clone(), false).await?;
+        let copy = Repository::update(Arc::clone(&target), snapshot).build();
+        let reader =
+            copy.get_chunk_reader(&path, &ChunkIndices(vec![1]), &ByteRange::ALL).await?;
+        assert_eq!(reader.unwrap().await?, Bytes::from_static(b"ab"));
+
+        // truncated archives are rejected
+        let truncated = &archive[..archive.len() - 3 * BLOCK_SIZE];
+        assert!(import_archive(target.as_ref(), truncated).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+//! A log of the operations that rewrite the history of a repository.
+//!
+//! Commits are recorded by the snapshots they write. Operations that change existing
+//! objects or history instead, like [`crate::redaction::redact_chunk`] or
+//! [`crate::filter::filter_path`], leave an [`AuditRecord`] in
+//! this log, so changes to history can be reviewed later. Records are stored with
+//! [`Storage::write_audit_record`], one object each, and are never modified.
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    clock::Clock, error::ErrorKind, filter::FilteredHistory, format::AuditRecordId,
+    redaction::Redaction, Storage, StorageError,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    pub id: AuditRecordId,
+    pub written_at: DateTime<Utc>,
+    pub event: AuditEvent,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum AuditEvent {
+    ChunkRedacted(Redaction),
+    HistoryFiltered(FilteredHistory),
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum AuditError {
+    #[error("error contacting storage {0}")]
+    Storage(#[from] StorageError),
+    #[error("invalid audit record {0}")]
+    InvalidRecord(#[from] serde_json::Error),
+}
+
+impl AuditError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            AuditError::Storage(err) => err.kind(),
+            AuditError::InvalidRecord(_) => ErrorKind::Corruption,
+        }
+    }
+}
+
+pub type AuditResult<A> = Result<A, AuditError>;
+
+/// Add a record of `event` to the log of the repository, written at the time of `clock`
+pub async fn append_record(
+    storage: &dyn Storage,
+    clock: &dyn Clock,
+    event: AuditEvent,
+) -> AuditResult<AuditRecord> {
+    let record =
+        AuditRecord { id: AuditRecordId::random(), written_at: clock.now(), event };
+    let bytes = Bytes::from(serde_json::to_vec(&record)?);
+    storage.write_audit_record(&record.id, bytes).await?;
+    Ok(record)
+}
+
+/// All the records in the log of the repository, oldest first
+pub async fn audit_log(storage: &dyn Storage) -> AuditResult<Vec<AuditRecord>> {
+    let mut records = storage
+        .fetch_audit_records()
+        .await?
+        .iter()
+        .map(|bytes| serde_json::from_slice(bytes))
+        .collect::<Result<Vec<AuditRecord>, _>>()?;
+    records.sort_by(|a, b| (a.written_at, &a.id).cmp(&(b.written_at, &b.id)));
+    Ok(records)
+}
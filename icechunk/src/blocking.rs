@@ -0,0 +1,246 @@
+//! A synchronous API for code that doesn't use async.
+//!
+//! [`Repository`] wraps an async [`crate::Repository`] together with the Tokio runtime
+//! that drives it, every method blocks the calling thread until the operation completes.
+//! As with other blocking clients, it must not be used from inside an async runtime,
+//! doing so panics.
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::runtime::Runtime;
+
+use crate::{
+    format::{snapshot::SnapshotProperties, ByteRange, ChunkIndices, Path, SnapshotId},
+    repository::{
+        get_chunk, ChunkPayload, RepositoryBuilder, RepositoryError, RepositoryResult,
+        ZarrArrayMetadata,
+    },
+    Storage,
+};
+
+#[derive(Debug)]
+pub struct Repository {
+    inner: crate::Repository,
+    runtime: Runtime,
+}
+
+impl Repository {
+    /// Initialize a new repository in `storage`, creating its default branch
+    pub fn init(storage: Arc<dyn Storage>) -> RepositoryResult<Self> {
+        Self::build(|runtime| runtime.block_on(crate::Repository::init(storage, false)))
+    }
+
+    /// Open the repository in `storage` at the tip of `branch`
+    pub fn open(storage: Arc<dyn Storage>, branch: &str) -> RepositoryResult<Self> {
+        Self::build(|runtime| {
+            runtime.block_on(crate::Repository::from_branch_tip(storage, branch))
+        })
+    }
+
+    /// Wrap a repository created with the async API.
+    ///
+    /// Repositories that use a different runtime, for example for their virtual chunks
+    /// resolver, must not be wrapped.
+    pub fn wrap(inner: crate::Repository) -> RepositoryResult<Self> {
+        Ok(Self { inner, runtime: new_runtime()? })
+    }
+
+    fn build(
+        builder: impl FnOnce(&Runtime) -> RepositoryResult<RepositoryBuilder>,
+    ) -> RepositoryResult<Self> {
+        let runtime = new_runtime()?;
+        // the repository is built inside the runtime, to be able to start its clients
+        let inner = {
+            let _guard = runtime.enter();
+            builder(&runtime)?.build()
+        };
+        Ok(Self { inner, runtime })
+    }
+
+    /// The async repository, its methods can be called with [`Repository::block_on`]
+    pub fn inner(&self) -> &crate::Repository {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut crate::Repository {
+        &mut self.inner
+    }
+
+    /// Run a future to completion in the runtime of the repository
+    pub fn block_on<F: std::future::Future>(&self, future: F) -> F::Output {
+        self.runtime.block_on(future)
+    }
+
+    pub fn snapshot_id(&self) -> &SnapshotId {
+        self.inner.snapshot_id()
+    }
+
+    /// Move to a snapshot, fails if there are uncommitted changes
+    pub fn checkout_snapshot(&mut self, snapshot_id: SnapshotId) -> RepositoryResult<()> {
+        self.check_no_changes()?;
+        self.inner.set_snapshot_id(snapshot_id);
+        Ok(())
+    }
+
+    /// Move to the tip of a branch, fails if there are uncommitted changes
+    pub fn checkout_branch(&mut self, branch: &str) -> RepositoryResult<()> {
+        self.check_no_changes()?;
+        self.runtime.block_on(self.inner.set_snapshot_from_branch(branch))
+    }
+
+    /// Move to a tag, fails if there are uncommitted changes
+    pub fn checkout_tag(&mut self, tag: &str) -> RepositoryResult<()> {
+        self.check_no_changes()?;
+        self.runtime.block_on(self.inner.set_snapshot_from_tag(tag))
+    }
+
+    fn check_no_changes(&self) -> RepositoryResult<()> {
+        if self.inner.has_uncommitted_changes() {
+            Err(RepositoryError::UncommittedChanges)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn add_group(&mut self, path: Path) -> RepositoryResult<()> {
+        self.runtime.block_on(self.inner.add_group(path))
+    }
+
+    pub fn add_array(
+        &mut self,
+        path: Path,
+        metadata: ZarrArrayMetadata,
+    ) -> RepositoryResult<()> {
+        self.runtime.block_on(self.inner.add_array(path, metadata))
+    }
+
+    /// Read the bytes of a chunk, `None` if the chunk doesn't exist
+    pub fn get_chunk(
+        &self,
+        path: &Path,
+        coords: &ChunkIndices,
+        byte_range: &ByteRange,
+    ) -> RepositoryResult<Option<Bytes>> {
+        self.runtime.block_on(async {
+            get_chunk(self.inner.get_chunk_reader(path, coords, byte_range).await?).await
+        })
+    }
+
+    /// Write the bytes of a chunk, uploading them if they are not small enough to inline
+    pub fn set_chunk(
+        &mut self,
+        path: Path,
+        coords: ChunkIndices,
+        data: Bytes,
+    ) -> RepositoryResult<()> {
+        self.runtime.block_on(async {
+            let payload = self.inner.get_chunk_writer()(data).await?;
+            self.inner.set_chunk_ref(path, coords, Some(payload)).await
+        })
+    }
+
+    pub fn get_chunk_ref(
+        &self,
+        path: &Path,
+        coords: &ChunkIndices,
+    ) -> RepositoryResult<Option<ChunkPayload>> {
+        self.runtime.block_on(self.inner.get_chunk_ref(path, coords))
+    }
+
+    pub fn set_chunk_ref(
+        &mut self,
+        path: Path,
+        coords: ChunkIndices,
+        data: Option<ChunkPayload>,
+    ) -> RepositoryResult<()> {
+        self.runtime.block_on(self.inner.set_chunk_ref(path, coords, data))
+    }
+
+    /// Commit the changes to `branch`, see [`crate::Repository::commit`]
+    pub fn commit(
+        &mut self,
+        branch: &str,
+        message: &str,
+        properties: Option<SnapshotProperties>,
+    ) -> RepositoryResult<SnapshotId> {
+        self.runtime.block_on(self.inner.commit(branch, message, properties))
+    }
+
+    /// Discard the uncommitted changes, see [`crate::Repository::abort`]
+    pub fn abort(&mut self) -> RepositoryResult<()> {
+        self.runtime.block_on(self.inner.abort())
+    }
+}
+
+impl Drop for Repository {
+    fn drop(&mut self) {
+        // the runtime is dropped with the repository, so the cleanup cannot be spawned
+        if self.inner.config().cleanup_on_drop {
+            let _ = self.runtime.block_on(self.inner.abort());
+        }
+    }
+}
+
+fn new_runtime() -> RepositoryResult<Runtime> {
+    // a worker thread keeps background tasks, like client connections, running between
+    // calls
+    Ok(tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()?)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{metadata::FillValue, synthetic::vector_metadata, ObjectStorage};
+
+    #[test]
+    fn test_blocking_repository() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage))?;
+        let array: Path = "/array".try_into()?;
+        repo.add_group(Path::root())?;
+        repo.add_array(array.clone(), vector_metadata(2, 1, FillValue::UInt8(0)))?;
+        let big = Bytes::from(vec![1; 1024]);
+        repo.set_chunk(array.clone(), ChunkIndices(vec![0]), big.clone())?;
+        repo.set_chunk(array.clone(), ChunkIndices(vec![1]), Bytes::from_static(b"x"))?;
+        assert!(matches!(
+            repo.checkout_branch("main"),
+            Err(RepositoryError::UncommittedChanges)
+        ));
+        let first = repo.commit("main", "first", None)?;
+
+        let mut other = Repository::open(storage, "main")?;
+        assert_eq!(other.snapshot_id(), &first);
+        assert_eq!(
+            other.get_chunk(&array, &ChunkIndices(vec![0]), &ByteRange::ALL)?,
+            Some(big)
+        );
+        assert_eq!(
+            other.get_chunk(&array, &ChunkIndices(vec![1]), &ByteRange::ALL)?,
+            Some(Bytes::from_static(b"x"))
+        );
+        other.set_chunk(
+            array.clone(),
+            ChunkIndices(vec![1]),
+            Bytes::from_static(b"y"),
+        )?;
+        let second = other.commit("main", "second", None)?;
+
+        repo.checkout_branch("main")?;
+        assert_eq!(repo.snapshot_id(), &second);
+        repo.checkout_snapshot(first)?;
+        assert_eq!(
+            repo.get_chunk(&array, &ChunkIndices(vec![1]), &ByteRange::ALL)?,
+            Some(Bytes::from_static(b"x"))
+        );
+        Ok(())
+    }
+}
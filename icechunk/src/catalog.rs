@@ -0,0 +1,194 @@
+//! Many repositories in one bucket.
+//!
+//! Platforms hosting many datasets keep each repository under its own prefix of a
+//! shared root, like `tenants/acme/sst`. The functions here open repositories by name,
+//! the name is the path of the repository relative to the root, and discover existing
+//! ones with [`list_repositories`].
+//!
+//! A prefix holds a repository if it has the first version of the main branch, the ref
+//! [`crate::Repository::init`] writes. Repositories cannot be nested, names are
+//! validated so that one repository can't reach the objects of another.
+use std::sync::Arc;
+
+use thiserror::Error;
+
+use crate::{
+    refs::Ref,
+    repository::{RepositoryError, RepositoryResult},
+    Repository, RepositoryBuilder, Storage, StorageError,
+};
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum CatalogError {
+    #[error("error contacting storage {0}")]
+    Storage(#[from] StorageError),
+    #[error("repository error {0}")]
+    Repository(#[from] RepositoryError),
+    #[error("invalid repository name `{name}`: {message}")]
+    InvalidName { name: String, message: &'static str },
+}
+
+pub type CatalogResult<A> = Result<A, CatalogError>;
+
+// the directories of a repository, they cannot start a repository name
+const RESERVED: [&str; 8] = [
+    "refs",
+    "snapshots",
+    "manifests",
+    "chunks",
+    "attributes",
+    "artifacts",
+    "audit",
+    "trash",
+];
+
+fn validate_name(name: &str) -> CatalogResult<()> {
+    let invalid = |message| CatalogError::InvalidName { name: name.to_string(), message };
+    if name.is_empty() {
+        return Err(invalid("the name is empty"));
+    }
+    for part in name.split('/') {
+        match part {
+            "" => return Err(invalid("empty path components are not allowed")),
+            "." | ".." => {
+                return Err(invalid("relative path components are not allowed"))
+            }
+            part if RESERVED.contains(&part) => {
+                return Err(invalid("the name uses a directory reserved by repositories"))
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn join(root_prefix: &str, name: &str) -> String {
+    let root = root_prefix.trim_matches('/');
+    match (root.is_empty(), name.is_empty()) {
+        (true, _) => name.to_string(),
+        (false, true) => root.to_string(),
+        (false, false) => format!("{root}/{name}"),
+    }
+}
+
+/// The storage of the repository `name` under `root_prefix`
+pub fn repository_storage(
+    storage: &dyn Storage,
+    root_prefix: &str,
+    name: &str,
+) -> CatalogResult<Arc<dyn Storage>> {
+    validate_name(name)?;
+    Ok(storage.sub_storage(&join(root_prefix, name))?)
+}
+
+async fn is_repository(storage: &dyn Storage, prefix: &str) -> RepositoryResult<bool> {
+    let storage = storage.sub_storage(prefix)?;
+    Repository::exists(storage.as_ref()).await
+}
+
+/// The names of the repositories under `root_prefix`, sorted.
+///
+/// Prefixes are listed one level at a time, the objects inside repositories are not
+/// listed.
+pub async fn list_repositories(
+    storage: &dyn Storage,
+    root_prefix: &str,
+) -> CatalogResult<Vec<String>> {
+    let mut found = Vec::new();
+    let mut pending = vec![String::new()];
+    while let Some(name) = pending.pop() {
+        let prefix = join(root_prefix, &name);
+        let children = storage.list_prefixes(&prefix).await?;
+        if !name.is_empty()
+            && children.iter().any(|child| child == "refs")
+            && is_repository(storage, &prefix).await?
+        {
+            found.push(name);
+            continue;
+        }
+        pending.extend(
+            children
+                .into_iter()
+                .filter(|child| !RESERVED.contains(&child.as_str()))
+                .map(|child| join(&name, &child)),
+        );
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Create the repository `name` under `root_prefix`
+pub async fn create_repository(
+    storage: &dyn Storage,
+    root_prefix: &str,
+    name: &str,
+) -> CatalogResult<RepositoryBuilder> {
+    let storage = repository_storage(storage, root_prefix, name)?;
+    Ok(Repository::init(storage, false).await?)
+}
+
+/// Open the tip of the main branch of the repository `name` under `root_prefix`
+pub async fn open_repository(
+    storage: &dyn Storage,
+    root_prefix: &str,
+    name: &str,
+) -> CatalogResult<RepositoryBuilder> {
+    let storage = repository_storage(storage, root_prefix, name)?;
+    Ok(Repository::from_branch_tip(storage, Ref::DEFAULT_BRANCH).await?)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{format::Path, ObjectStorage};
+
+    #[tokio::test]
+    async fn test_list_and_open_repositories() {
+        let storage = ObjectStorage::new_in_memory_store(Some("bucket".to_string()));
+        for name in ["acme/sst", "acme/wind", "other/rain"] {
+            create_repository(&storage, "tenants", name).await.unwrap();
+        }
+        create_repository(&storage, "", "outside").await.unwrap();
+
+        let mut repo =
+            open_repository(&storage, "tenants/", "acme/sst").await.unwrap().build();
+        repo.add_group(Path::root()).await.unwrap();
+        repo.commit(Ref::DEFAULT_BRANCH, "root", None).await.unwrap();
+
+        assert_eq!(
+            list_repositories(&storage, "tenants").await.unwrap(),
+            vec!["acme/sst", "acme/wind", "other/rain"]
+        );
+        assert_eq!(
+            list_repositories(&storage, "tenants/acme").await.unwrap(),
+            vec!["sst", "wind"]
+        );
+        assert_eq!(list_repositories(&storage, "").await.unwrap().len(), 4);
+        assert!(list_repositories(&storage, "nothing").await.unwrap().is_empty());
+
+        // the commit is only visible in its own repository
+        let sst = open_repository(&storage, "tenants", "acme/sst").await.unwrap().build();
+        assert!(sst.get_node(&Path::root()).await.is_ok());
+        let wind =
+            open_repository(&storage, "tenants/acme", "wind").await.unwrap().build();
+        assert!(wind.get_node(&Path::root()).await.is_err());
+
+        for name in ["", "../other/rain", "acme//sst", "acme/sst/refs", "/acme"] {
+            assert!(
+                matches!(
+                    open_repository(&storage, "tenants", name).await,
+                    Err(CatalogError::InvalidName { .. })
+                ),
+                "{name}"
+            );
+        }
+        assert!(matches!(
+            open_repository(&storage, "tenants", "acme/missing").await,
+            Err(CatalogError::Repository(_))
+        ));
+    }
+}
@@ -1,5 +1,6 @@
 use std::{
     collections::{HashMap, HashSet},
+    hash::Hash,
     iter,
     mem::take,
 };
@@ -9,9 +10,12 @@ use serde::{Deserialize, Serialize};
 
 use crate::{
     format::{
-        manifest::{ChunkInfo, ManifestExtents, ManifestRef},
-        snapshot::{NodeData, NodeSnapshot, UserAttributesSnapshot},
-        ManifestId, NodeId,
+        manifest::{ChunkExtra, ChunkInfo, ManifestRef},
+        snapshot::{
+            Concatenation, NodeData, NodeSnapshot, SnapshotDependency,
+            UserAttributesSnapshot,
+        },
+        NodeId,
     },
     metadata::UserAttributes,
     repository::{ChunkIndices, ChunkPayload, Path, RepositoryResult, ZarrArrayMetadata},
@@ -29,6 +33,18 @@ pub struct ChangeSet {
     set_chunks: HashMap<NodeId, HashMap<ChunkIndices, Option<ChunkPayload>>>,
     deleted_groups: HashSet<Path>,
     deleted_arrays: HashSet<Path>,
+    // Extra data for chunks, it's dropped when the chunk is set again
+    #[serde(default)]
+    chunk_extras: HashMap<NodeId, HashMap<ChunkIndices, ChunkExtra>>,
+    // The layout of the new arrays that are concatenations of other arrays
+    #[serde(default)]
+    concatenations: HashMap<NodeId, Concatenation>,
+    // Upstream dataset versions to record in the next snapshot
+    #[serde(default)]
+    dependencies: Vec<SnapshotDependency>,
+    // Committed chunks whose references are replaced with tombstones on flush
+    #[serde(default)]
+    tombstones: HashMap<NodeId, HashSet<ChunkIndices>>,
 }
 
 impl ChangeSet {
@@ -36,6 +52,26 @@ impl ChangeSet {
         self == &ChangeSet::default()
     }
 
+    /// True if no nodes, metadata or attributes were changed, only chunks
+    pub fn has_only_chunk_changes(&self) -> bool {
+        self.new_groups.is_empty()
+            && self.new_arrays.is_empty()
+            && self.updated_arrays.is_empty()
+            && self.updated_attributes.is_empty()
+            && self.deleted_groups.is_empty()
+            && self.deleted_arrays.is_empty()
+    }
+
+    pub fn add_dependency(&mut self, dependency: SnapshotDependency) {
+        if !self.dependencies.contains(&dependency) {
+            self.dependencies.push(dependency);
+        }
+    }
+
+    pub fn dependencies(&self) -> &[SnapshotDependency] {
+        &self.dependencies
+    }
+
     pub fn add_group(&mut self, path: Path, node_id: NodeId) {
         self.new_groups.insert(path, node_id);
     }
@@ -97,10 +133,26 @@ impl ChangeSet {
         self.new_arrays.insert(path, (node_id, metadata));
     }
 
+    pub fn add_concatenated_array(
+        &mut self,
+        path: Path,
+        node_id: NodeId,
+        metadata: ZarrArrayMetadata,
+        concatenation: Concatenation,
+    ) {
+        self.new_arrays.insert(path, (node_id, metadata));
+        self.concatenations.insert(node_id, concatenation);
+    }
+
     pub fn update_array(&mut self, node_id: NodeId, metadata: ZarrArrayMetadata) {
         self.updated_arrays.insert(node_id, metadata);
     }
 
+    /// Forget the update of the Zarr metadata of `node_id`
+    pub fn unset_updated_array(&mut self, node_id: NodeId) {
+        self.updated_arrays.remove(&node_id);
+    }
+
     pub fn delete_array(&mut self, path: Path, node_id: NodeId) {
         // if deleting a new array created in this session, just remove the entry
         // from new_arrays
@@ -111,6 +163,8 @@ impl ChangeSet {
         self.updated_arrays.remove(&node_id);
         self.updated_attributes.remove(&node_id);
         self.set_chunks.remove(&node_id);
+        self.tombstones.remove(&node_id);
+        self.concatenations.remove(&node_id);
         if !is_new_array {
             self.deleted_arrays.insert(path);
         }
@@ -141,6 +195,11 @@ impl ChangeSet {
         self.updated_attributes.insert(node_id, atts);
     }
 
+    /// Forget the update of the user attributes of `node_id`
+    pub fn unset_user_attributes(&mut self, node_id: NodeId) {
+        self.updated_attributes.remove(&node_id);
+    }
+
     pub fn get_user_attributes(
         &self,
         node_id: NodeId,
@@ -154,6 +213,10 @@ impl ChangeSet {
         coord: ChunkIndices,
         data: Option<ChunkPayload>,
     ) {
+        if let Some(extras) = self.chunk_extras.get_mut(&node_id) {
+            extras.remove(&coord);
+        }
+        self.untombstone_chunk(node_id, &coord);
         // this implementation makes delete idempotent
         // it allows deleting a deleted chunk by repeatedly setting None.
         self.set_chunks
@@ -164,6 +227,47 @@ impl ChangeSet {
             .or_insert(HashMap::from([(coord, data)]));
     }
 
+    /// Forget the write or delete of a chunk, and its extra data
+    ///
+    /// Unlike setting `None`, this leaves the committed chunk in place.
+    pub fn unset_chunk_ref(&mut self, node_id: NodeId, coord: &ChunkIndices) {
+        if let Some(extras) = self.chunk_extras.get_mut(&node_id) {
+            extras.remove(coord);
+            if extras.is_empty() {
+                self.chunk_extras.remove(&node_id);
+            }
+        }
+        self.untombstone_chunk(node_id, coord);
+        if let Some(chunks) = self.set_chunks.get_mut(&node_id) {
+            chunks.remove(coord);
+            if chunks.is_empty() {
+                self.set_chunks.remove(&node_id);
+            }
+        }
+    }
+
+    /// Replace the committed reference to a chunk with a tombstone on flush, see
+    /// [`crate::format::manifest::Manifest::redact_chunk`]
+    pub fn tombstone_chunk(&mut self, node_id: NodeId, coord: ChunkIndices) {
+        self.tombstones.entry(node_id).or_default().insert(coord);
+    }
+
+    fn untombstone_chunk(&mut self, node_id: NodeId, coord: &ChunkIndices) {
+        if let Some(coords) = self.tombstones.get_mut(&node_id) {
+            coords.remove(coord);
+            if coords.is_empty() {
+                self.tombstones.remove(&node_id);
+            }
+        }
+    }
+
+    /// The chunks staged with [`ChangeSet::tombstone_chunk`]
+    pub fn tombstones_iterator(&self) -> impl Iterator<Item = (NodeId, &ChunkIndices)> {
+        self.tombstones
+            .iter()
+            .flat_map(|(node, coords)| coords.iter().map(move |coord| (*node, coord)))
+    }
+
     pub fn get_chunk_ref(
         &self,
         node_id: NodeId,
@@ -172,6 +276,40 @@ impl ChangeSet {
         self.set_chunks.get(&node_id).and_then(|h| h.get(coords))
     }
 
+    /// The extra data for a chunk, new values are merged into the persisted ones on flush
+    pub fn chunk_extra_mut(
+        &mut self,
+        node_id: NodeId,
+        coord: ChunkIndices,
+    ) -> &mut ChunkExtra {
+        self.chunk_extras.entry(node_id).or_default().entry(coord).or_default()
+    }
+
+    pub fn get_chunk_extra(
+        &self,
+        node_id: NodeId,
+        coords: &ChunkIndices,
+    ) -> Option<&ChunkExtra> {
+        self.chunk_extras.get(&node_id).and_then(|h| h.get(coords))
+    }
+
+    /// The coordinates of every chunk written or deleted, with the node they belong to
+    pub fn chunk_changes_iterator(
+        &self,
+    ) -> impl Iterator<Item = (NodeId, &ChunkIndices)> {
+        self.set_chunks
+            .iter()
+            .flat_map(|(node, h)| h.keys().map(move |coord| (*node, coord)))
+    }
+
+    pub fn chunk_extras_iterator(
+        &self,
+    ) -> impl Iterator<Item = (NodeId, &ChunkIndices, &ChunkExtra)> {
+        self.chunk_extras.iter().flat_map(|(node, h)| {
+            h.iter().map(move |(coord, extra)| (*node, coord, extra))
+        })
+    }
+
     pub fn array_chunks_iterator(
         &self,
         node_id: NodeId,
@@ -236,6 +374,30 @@ impl ChangeSet {
         self.updated_attributes.extend(other.updated_attributes);
         self.deleted_groups.extend(other.deleted_groups);
         self.deleted_arrays.extend(other.deleted_arrays);
+        self.concatenations.extend(other.concatenations);
+        for dependency in other.dependencies {
+            self.add_dependency(dependency);
+        }
+
+        for (node, other_chunks) in other.set_chunks.iter() {
+            // extra data and tombstones of chunks overwritten by `other` are stale
+            if let Some(extras) = self.chunk_extras.get_mut(node) {
+                extras.retain(|coord, _| !other_chunks.contains_key(coord));
+            }
+            if let Some(coords) = self.tombstones.get_mut(node) {
+                coords.retain(|coord| !other_chunks.contains_key(coord));
+            }
+        }
+        for (node, other_coords) in other.tombstones.into_iter() {
+            self.tombstones.entry(node).or_default().extend(other_coords);
+        }
+        self.tombstones.retain(|_, coords| !coords.is_empty());
+        for (node, other_extras) in other.chunk_extras.into_iter() {
+            let extras = self.chunk_extras.entry(node).or_default();
+            for (coord, extra) in other_extras {
+                extras.entry(coord).or_default().merge(extra);
+            }
+        }
 
         for (node, other_chunks) in other.set_chunks.into_iter() {
             match self.set_chunks.remove(&node) {
@@ -250,6 +412,77 @@ impl ChangeSet {
         }
     }
 
+    /// The changes in `self` that are not in `base`, for a change set that started as a
+    /// copy of `base`.
+    ///
+    /// Entries set to a different value are changes, entries removed from `base`, like
+    /// the new nodes that were deleted, are not.
+    pub fn changes_since(&self, base: &ChangeSet) -> ChangeSet {
+        fn changed<K: Eq + Hash + Clone, V: PartialEq + Clone>(
+            map: &HashMap<K, V>,
+            base: &HashMap<K, V>,
+        ) -> HashMap<K, V> {
+            map.iter()
+                .filter(|(key, value)| base.get(key) != Some(value))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect()
+        }
+        fn changed_per_node<V: PartialEq + Clone>(
+            map: &HashMap<NodeId, HashMap<ChunkIndices, V>>,
+            base: &HashMap<NodeId, HashMap<ChunkIndices, V>>,
+        ) -> HashMap<NodeId, HashMap<ChunkIndices, V>> {
+            let empty = HashMap::new();
+            map.iter()
+                .map(|(node, values)| {
+                    (*node, changed(values, base.get(node).unwrap_or(&empty)))
+                })
+                .filter(|(_, values)| !values.is_empty())
+                .collect()
+        }
+        ChangeSet {
+            new_groups: changed(&self.new_groups, &base.new_groups),
+            new_arrays: changed(&self.new_arrays, &base.new_arrays),
+            updated_arrays: changed(&self.updated_arrays, &base.updated_arrays),
+            updated_attributes: changed(
+                &self.updated_attributes,
+                &base.updated_attributes,
+            ),
+            set_chunks: changed_per_node(&self.set_chunks, &base.set_chunks),
+            deleted_groups: self
+                .deleted_groups
+                .difference(&base.deleted_groups)
+                .cloned()
+                .collect(),
+            deleted_arrays: self
+                .deleted_arrays
+                .difference(&base.deleted_arrays)
+                .cloned()
+                .collect(),
+            chunk_extras: changed_per_node(&self.chunk_extras, &base.chunk_extras),
+            concatenations: changed(&self.concatenations, &base.concatenations),
+            dependencies: self
+                .dependencies
+                .iter()
+                .filter(|dependency| !base.dependencies.contains(dependency))
+                .cloned()
+                .collect(),
+            tombstones: self
+                .tombstones
+                .iter()
+                .map(|(node, coords)| {
+                    let base = base.tombstones.get(node);
+                    let coords: HashSet<_> = coords
+                        .iter()
+                        .filter(|coord| !base.is_some_and(|base| base.contains(*coord)))
+                        .cloned()
+                        .collect();
+                    (*node, coords)
+                })
+                .filter(|(_, coords)| !coords.is_empty())
+                .collect(),
+        }
+    }
+
     pub fn merge_many<T: IntoIterator<Item = ChangeSet>>(&mut self, others: T) {
         others.into_iter().fold(self, |res, change_set| {
             res.merge(change_set);
@@ -298,7 +531,12 @@ impl ChangeSet {
                 user_attributes: atts.flatten().map(UserAttributesSnapshot::Inline),
                 // We put no manifests in new arrays, see get_chunk_ref to understand how chunks get
                 // fetched for those arrays
-                node_data: NodeData::Array(meta.clone(), vec![]),
+                node_data: match self.concatenations.get(id) {
+                    Some(concatenation) => {
+                        NodeData::Concatenated(meta, concatenation.clone())
+                    }
+                    None => NodeData::Array(meta, vec![]),
+                },
             }
         })
     }
@@ -317,7 +555,7 @@ impl ChangeSet {
 
     pub fn new_nodes_iterator<'a>(
         &'a self,
-        manifest_id: Option<&'a ManifestId>,
+        manifest_refs: Option<&'a HashMap<NodeId, Vec<ManifestRef>>>,
     ) -> impl Iterator<Item = NodeSnapshot> + 'a {
         self.new_nodes().filter_map(move |path| {
             if self.is_deleted(path) {
@@ -328,15 +566,10 @@ impl ChangeSet {
             #[allow(clippy::expect_used)]
             let node = self.get_new_node(path).expect("Bug in new_nodes implementation");
             match node.node_data {
-                NodeData::Group => Some(node),
+                NodeData::Group | NodeData::Concatenated(..) => Some(node),
                 NodeData::Array(meta, _no_manifests_yet) => {
-                    let new_manifests = manifest_id
-                        .map(|mid| {
-                            vec![ManifestRef {
-                                object_id: mid.clone(),
-                                extents: ManifestExtents(vec![]),
-                            }]
-                        })
+                    let new_manifests = manifest_refs
+                        .and_then(|refs| refs.get(&node.id).cloned())
                         .unwrap_or_default();
                     Some(NodeSnapshot {
                         node_data: NodeData::Array(meta, new_manifests),
@@ -362,7 +595,9 @@ impl ChangeSet {
             .map(|a| a.map(UserAttributesSnapshot::Inline));
         let new_atts = session_atts.unwrap_or(node.user_attributes);
         match node.node_data {
-            NodeData::Group => Some(NodeSnapshot { user_attributes: new_atts, ..node }),
+            NodeData::Group | NodeData::Concatenated(..) => {
+                Some(NodeSnapshot { user_attributes: new_atts, ..node })
+            }
             NodeData::Array(old_zarr_meta, _) => {
                 let new_zarr_meta = self
                     .get_updated_zarr_metadata(node.id)
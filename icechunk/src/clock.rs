@@ -0,0 +1,129 @@
+//! Time sources for the timestamps recorded in snapshots.
+//!
+//! Repositories use the [`SystemClock`] by default. Tests can freeze time with a
+//! [`FixedClock`], and deployments where clients may have skewed clocks can use a
+//! [`MonotonicClock`], so that snapshots are always newer than their parents and
+//! checking out by timestamp follows the history order.
+use std::{
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Called with timestamps read from the repository, like the one of the parent
+    /// snapshot before a commit. Clocks can use them to correct for skew.
+    fn observe(&self, _timestamp: DateTime<Utc>) {}
+}
+
+/// The system wall clock, this is the default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// A clock that only moves when told to, intended for tests
+#[derive(Debug)]
+pub struct FixedClock(Mutex<DateTime<Utc>>);
+
+impl FixedClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        Self(Mutex::new(now))
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner) = now;
+    }
+
+    pub fn advance(&self, delta: TimeDelta) {
+        let mut now = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        *now += delta;
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A hybrid logical clock on top of another clock.
+///
+/// Timestamps never go backwards, and are always later than any observed timestamp, so
+/// a commit is always newer than its parent even if this client's clock is behind the
+/// clock of the parent's writer.
+#[derive(Debug)]
+pub struct MonotonicClock {
+    inner: Arc<dyn Clock>,
+    last: Mutex<Option<DateTime<Utc>>>,
+}
+
+impl MonotonicClock {
+    /// The smallest step between two consecutive timestamps
+    pub const TICK: TimeDelta = TimeDelta::microseconds(1);
+
+    pub fn new(inner: Arc<dyn Clock>) -> Self {
+        Self { inner, last: Mutex::new(None) }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock))
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> DateTime<Utc> {
+        let mut last = self.last.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = match *last {
+            Some(last) => self.inner.now().max(last + Self::TICK),
+            None => self.inner.now(),
+        };
+        *last = Some(now);
+        now
+    }
+
+    fn observe(&self, timestamp: DateTime<Utc>) {
+        let mut last = self.last.lock().unwrap_or_else(PoisonError::into_inner);
+        *last = Some(last.map_or(timestamp, |last| last.max(timestamp)));
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_monotonic_clock() {
+        let t0 = DateTime::from_timestamp(1_000_000, 0).unwrap();
+        let fixed = Arc::new(FixedClock::new(t0));
+        let clock = MonotonicClock::new(fixed.clone());
+
+        assert_eq!(clock.now(), t0);
+        // a stopped clock still ticks forward
+        assert_eq!(clock.now(), t0 + MonotonicClock::TICK);
+
+        // and a clock going backwards too
+        fixed.set(t0 - TimeDelta::hours(1));
+        assert_eq!(clock.now(), t0 + MonotonicClock::TICK * 2);
+
+        // observed timestamps from the future move the clock
+        let future = t0 + TimeDelta::days(1);
+        clock.observe(future);
+        assert_eq!(clock.now(), future + MonotonicClock::TICK);
+
+        // once the wall clock catches up it's used again
+        fixed.set(future + TimeDelta::days(1));
+        assert_eq!(clock.now(), future + TimeDelta::days(1));
+    }
+}
@@ -0,0 +1,568 @@
+//! The zarr v3 codec pipeline, to encode and decode chunks.
+//!
+//! Icechunk stores chunks as opaque bytes, but some features need their values:
+//! [`crate::Repository::compute_chunk_statistics`], fill value detection and decoded
+//! reads. A [`CodecPipeline`] is built from the codecs of an array, and converts between
+//! the stored bytes of a chunk and its decoded form: the elements in row-major order,
+//! little-endian.
+//!
+//! Supported codecs are `transpose`, `bytes`, and the compressors `gzip`, `zstd` and
+//! `blosc`, each behind the cargo feature of the same name.
+use std::fmt;
+
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::{
+    error::ErrorKind,
+    format::snapshot::ZarrArrayMetadata,
+    metadata::{Codec, DataType, FillValue},
+};
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum CodecError {
+    #[error("codec `{codec}` is not supported")]
+    UnsupportedCodec { codec: String },
+    #[error("data type {0} has no fixed size, it cannot be decoded")]
+    UnsupportedDataType(DataType),
+    #[error("invalid configuration for codec `{codec}`: {message}")]
+    InvalidConfiguration { codec: String, message: String },
+    #[error("chunk has {found} bytes, expected {expected}")]
+    WrongSize { expected: usize, found: usize },
+    #[error("codec `{codec}` failed: {message}")]
+    Failed { codec: &'static str, message: String },
+}
+
+impl CodecError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            CodecError::UnsupportedCodec { .. } | CodecError::UnsupportedDataType(_) => {
+                ErrorKind::Unsupported
+            }
+            CodecError::InvalidConfiguration { .. } => ErrorKind::InvalidRequest,
+            CodecError::WrongSize { .. } | CodecError::Failed { .. } => {
+                ErrorKind::Corruption
+            }
+        }
+    }
+}
+
+pub type CodecResult<T> = Result<T, CodecError>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BytesCodec {
+    Gzip { level: u32 },
+    Zstd { level: i32, checksum: bool },
+    Blosc(BloscConfig),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct BloscConfig {
+    cname: String,
+    clevel: u8,
+    shuffle: String,
+    typesize: Option<usize>,
+    blocksize: Option<usize>,
+}
+
+impl BytesCodec {
+    fn name(&self) -> &'static str {
+        match self {
+            BytesCodec::Gzip { .. } => "gzip",
+            BytesCodec::Zstd { .. } => "zstd",
+            BytesCodec::Blosc(_) => "blosc",
+        }
+    }
+
+    fn failed(&self, err: &dyn fmt::Debug) -> CodecError {
+        CodecError::Failed { codec: self.name(), message: format!("{err:?}") }
+    }
+
+    fn unsupported(&self) -> CodecError {
+        CodecError::UnsupportedCodec { codec: self.name().to_string() }
+    }
+}
+
+/// The codecs of an array, ready to encode and decode its chunks
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodecPipeline {
+    element_size: usize,
+    /// The size of the parts of an element swapped by the `bytes` codec, complex
+    /// numbers swap each component separately
+    word_size: usize,
+    /// The axis orders of the `transpose` codecs
+    transposes: Vec<Vec<usize>>,
+    big_endian: bool,
+    compressors: Vec<BytesCodec>,
+}
+
+fn invalid(codec: &Codec, message: impl Into<String>) -> CodecError {
+    CodecError::InvalidConfiguration {
+        codec: codec.name.clone(),
+        message: message.into(),
+    }
+}
+
+fn config<'a>(codec: &'a Codec, key: &str) -> Option<&'a Value> {
+    codec.configuration.as_ref().and_then(|config| config.get(key))
+}
+
+fn transpose_order(codec: &Codec, ndim: usize) -> CodecResult<Vec<usize>> {
+    let order: Vec<usize> = match config(codec, "order") {
+        Some(Value::String(order)) if order == "C" => (0..ndim).collect(),
+        Some(Value::String(order)) if order == "F" => (0..ndim).rev().collect(),
+        Some(Value::Array(axes)) => axes
+            .iter()
+            .map(|axis| axis.as_u64().map(|axis| axis as usize))
+            .collect::<Option<_>>()
+            .ok_or_else(|| invalid(codec, "order must be a list of axes"))?,
+        _ => return Err(invalid(codec, "missing order")),
+    };
+    let mut sorted = order.clone();
+    sorted.sort_unstable();
+    if sorted != (0..ndim).collect::<Vec<_>>() {
+        return Err(invalid(
+            codec,
+            format!("{order:?} is not a permutation of the axes"),
+        ));
+    }
+    Ok(order)
+}
+
+fn bytes_codec(codec: &Codec) -> CodecResult<Option<BytesCodec>> {
+    let int = |key: &str| config(codec, key).and_then(Value::as_i64);
+    let res = match codec.name.as_str() {
+        "gzip" => BytesCodec::Gzip {
+            level: int("level")
+                .and_then(|level| u32::try_from(level).ok())
+                .filter(|level| *level <= 9)
+                .ok_or_else(|| invalid(codec, "level must be between 0 and 9"))?,
+        },
+        "zstd" => BytesCodec::Zstd {
+            level: int("level")
+                .and_then(|level| i32::try_from(level).ok())
+                .ok_or_else(|| invalid(codec, "missing level"))?,
+            checksum: config(codec, "checksum").and_then(Value::as_bool).unwrap_or(false),
+        },
+        "blosc" => {
+            let string = |key: &str| {
+                config(codec, key)
+                    .and_then(Value::as_str)
+                    .map(str::to_string)
+                    .ok_or_else(|| invalid(codec, format!("missing {key}")))
+            };
+            let size = |key: &str| {
+                int(key)
+                    .and_then(|size| usize::try_from(size).ok())
+                    .filter(|size| *size > 0)
+            };
+            BytesCodec::Blosc(BloscConfig {
+                cname: string("cname")?,
+                clevel: int("clevel")
+                    .and_then(|level| u8::try_from(level).ok())
+                    .filter(|level| *level <= 9)
+                    .ok_or_else(|| invalid(codec, "clevel must be between 0 and 9"))?,
+                shuffle: string("shuffle")?,
+                typesize: size("typesize"),
+                blocksize: size("blocksize"),
+            })
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(res))
+}
+
+/// Reorder the axes of `data`, an array of `element_size` byte elements with `shape`:
+/// axis `i` of the result is axis `order[i]` of `data`
+fn transpose(
+    data: &[u8],
+    element_size: usize,
+    shape: &[usize],
+    order: &[usize],
+) -> Vec<u8> {
+    let mut strides = vec![element_size; shape.len()];
+    for axis in (0..shape.len().saturating_sub(1)).rev() {
+        strides[axis] = strides[axis + 1] * shape[axis + 1];
+    }
+    let shape: Vec<usize> = order.iter().map(|axis| shape[*axis]).collect();
+    let strides: Vec<usize> = order.iter().map(|axis| strides[*axis]).collect();
+
+    let mut res = Vec::with_capacity(data.len());
+    let mut index = vec![0; shape.len()];
+    for _ in 0..data.len() / element_size {
+        let offset: usize =
+            index.iter().zip(&strides).map(|(i, stride)| i * stride).sum();
+        res.extend_from_slice(&data[offset..offset + element_size]);
+        for axis in (0..shape.len()).rev() {
+            index[axis] += 1;
+            if index[axis] < shape[axis] {
+                break;
+            }
+            index[axis] = 0;
+        }
+    }
+    res
+}
+
+fn swap_bytes(data: &mut [u8], word_size: usize) {
+    if word_size > 1 {
+        data.chunks_exact_mut(word_size).for_each(<[u8]>::reverse);
+    }
+}
+
+/// The encoded value of `fill_value`, `None` if it has no fixed size encoding
+fn fill_value_bytes(fill_value: &FillValue) -> Option<Vec<u8>> {
+    let bytes = match fill_value {
+        FillValue::Bool(value) => vec![u8::from(*value)],
+        FillValue::Int8(value) => value.to_le_bytes().to_vec(),
+        FillValue::Int16(value) => value.to_le_bytes().to_vec(),
+        FillValue::Int32(value) => value.to_le_bytes().to_vec(),
+        FillValue::Int64(value) => value.to_le_bytes().to_vec(),
+        FillValue::UInt8(value) => value.to_le_bytes().to_vec(),
+        FillValue::UInt16(value) => value.to_le_bytes().to_vec(),
+        FillValue::UInt32(value) => value.to_le_bytes().to_vec(),
+        FillValue::UInt64(value) => value.to_le_bytes().to_vec(),
+        FillValue::Float32(value) => value.to_le_bytes().to_vec(),
+        FillValue::Float64(value) => value.to_le_bytes().to_vec(),
+        FillValue::Complex64(re, im) => [re.to_le_bytes(), im.to_le_bytes()].concat(),
+        FillValue::Complex128(re, im) => [re.to_le_bytes(), im.to_le_bytes()].concat(),
+        FillValue::Float16(_) | FillValue::String(_) | FillValue::Bytes(_) => {
+            return None
+        }
+    };
+    Some(bytes)
+}
+
+impl CodecPipeline {
+    /// The pipeline for the codecs of `metadata`. Arrays without codecs store their
+    /// elements in row-major order, little-endian.
+    pub fn new(metadata: &ZarrArrayMetadata) -> CodecResult<Self> {
+        let data_type = &metadata.data_type;
+        let element_size = data_type
+            .size()
+            .ok_or_else(|| CodecError::UnsupportedDataType(data_type.clone()))?;
+        let word_size = match data_type {
+            DataType::Complex64 | DataType::Complex128 => element_size / 2,
+            _ => element_size,
+        };
+        let mut pipeline = CodecPipeline {
+            element_size,
+            word_size,
+            transposes: Vec::new(),
+            big_endian: false,
+            compressors: Vec::new(),
+        };
+
+        let mut seen_bytes = false;
+        for codec in &metadata.codecs {
+            match codec.name.as_str() {
+                "transpose" if !seen_bytes => {
+                    pipeline.transposes.push(transpose_order(codec, metadata.ndim())?)
+                }
+                "bytes" if !seen_bytes => {
+                    seen_bytes = true;
+                    pipeline.big_endian = match config(codec, "endian") {
+                        None => false,
+                        Some(endian) if endian == "little" => false,
+                        Some(endian) if endian == "big" => true,
+                        Some(endian) => {
+                            return Err(invalid(
+                                codec,
+                                format!("unknown endian {endian}"),
+                            ))
+                        }
+                    };
+                }
+                "transpose" | "bytes" => {
+                    return Err(invalid(codec, "must come before bytes to bytes codecs"))
+                }
+                _ => match bytes_codec(codec)? {
+                    Some(compressor) => {
+                        seen_bytes = true;
+                        pipeline.compressors.push(compressor)
+                    }
+                    None => {
+                        return Err(CodecError::UnsupportedCodec {
+                            codec: codec.name.clone(),
+                        })
+                    }
+                },
+            }
+        }
+        Ok(pipeline)
+    }
+
+    /// Encode the chunk `data`, decoded elements of a chunk with `shape`
+    pub fn encode(&self, data: &[u8], shape: &[u64]) -> CodecResult<Vec<u8>> {
+        let mut shape = self.check_size(data, shape)?;
+        let mut data = data.to_vec();
+        for order in &self.transposes {
+            data = transpose(&data, self.element_size, &shape, order);
+            shape = order.iter().map(|axis| shape[*axis]).collect();
+        }
+        if self.big_endian {
+            swap_bytes(&mut data, self.word_size);
+        }
+        for compressor in &self.compressors {
+            data = compress(compressor, &data)?;
+        }
+        Ok(data)
+    }
+
+    /// Decode the stored bytes of a chunk with `shape`
+    pub fn decode(&self, encoded: &[u8], shape: &[u64]) -> CodecResult<Vec<u8>> {
+        let mut data = self.decode_elements(encoded)?;
+        let mut shapes = vec![self.check_size(&data, shape)?];
+        for order in &self.transposes {
+            let last = &shapes[shapes.len() - 1];
+            shapes.push(order.iter().map(|axis| last[*axis]).collect());
+        }
+        for (order, shape) in self.transposes.iter().zip(&shapes[1..]).rev() {
+            let mut inverse = vec![0; order.len()];
+            for (i, axis) in order.iter().enumerate() {
+                inverse[*axis] = i;
+            }
+            data = transpose(&data, self.element_size, shape, &inverse);
+        }
+        Ok(data)
+    }
+
+    /// Decode the stored bytes of a chunk, leaving the elements in their stored order.
+    ///
+    /// This is enough to compute properties that don't depend on the order of the
+    /// elements, and doesn't need the shape of the chunk.
+    pub fn decode_elements(&self, encoded: &[u8]) -> CodecResult<Vec<u8>> {
+        let mut data = encoded.to_vec();
+        for compressor in self.compressors.iter().rev() {
+            data = decompress(compressor, &data)?;
+        }
+        if !data.len().is_multiple_of(self.element_size) {
+            return Err(CodecError::WrongSize {
+                expected: data.len().next_multiple_of(self.element_size),
+                found: data.len(),
+            });
+        }
+        if self.big_endian {
+            swap_bytes(&mut data, self.word_size);
+        }
+        Ok(data)
+    }
+
+    /// True if every element of the chunk stored as `encoded` is bitwise equal to
+    /// `fill_value`, so the chunk doesn't need to be stored
+    pub fn is_fill_value(
+        &self,
+        encoded: &[u8],
+        fill_value: &FillValue,
+    ) -> CodecResult<bool> {
+        let Some(fill_value) =
+            fill_value_bytes(fill_value).filter(|bytes| bytes.len() == self.element_size)
+        else {
+            return Ok(false);
+        };
+        let data = self.decode_elements(encoded)?;
+        Ok(data.chunks_exact(self.element_size).all(|element| element == fill_value))
+    }
+
+    fn check_size(&self, data: &[u8], shape: &[u64]) -> CodecResult<Vec<usize>> {
+        let shape: Vec<usize> = shape.iter().map(|size| *size as usize).collect();
+        let expected = shape.iter().product::<usize>() * self.element_size;
+        if data.len() != expected {
+            return Err(CodecError::WrongSize { expected, found: data.len() });
+        }
+        Ok(shape)
+    }
+}
+
+#[allow(unused_variables)]
+fn compress(codec: &BytesCodec, data: &[u8]) -> CodecResult<Vec<u8>> {
+    let failed = |err: &dyn fmt::Debug| codec.failed(err);
+    match codec {
+        #[cfg(feature = "gzip")]
+        BytesCodec::Gzip { level } => {
+            use std::io::Write as _;
+            let mut encoder = flate2::write::GzEncoder::new(
+                Vec::new(),
+                flate2::Compression::new(*level),
+            );
+            encoder.write_all(data).map_err(|err| failed(&err))?;
+            encoder.finish().map_err(|err| failed(&err))
+        }
+        #[cfg(feature = "zstd")]
+        BytesCodec::Zstd { level, checksum } => {
+            use std::io::Write as _;
+            let mut encoder = zstd::stream::Encoder::new(Vec::new(), *level)
+                .map_err(|err| failed(&err))?;
+            encoder.include_checksum(*checksum).map_err(|err| failed(&err))?;
+            encoder.write_all(data).map_err(|err| failed(&err))?;
+            encoder.finish().map_err(|err| failed(&err))
+        }
+        #[cfg(feature = "blosc")]
+        BytesCodec::Blosc(config) => {
+            let compressor = match config.cname.as_str() {
+                "blosclz" => blosc::Compressor::BloscLZ,
+                "lz4" => blosc::Compressor::LZ4,
+                "lz4hc" => blosc::Compressor::LZ4HC,
+                "snappy" => blosc::Compressor::Snappy,
+                "zlib" => blosc::Compressor::Zlib,
+                "zstd" => blosc::Compressor::Zstd,
+                other => return Err(failed(&format!("unknown compressor {other}"))),
+            };
+            let shuffle = match config.shuffle.as_str() {
+                "noshuffle" => blosc::ShuffleMode::None,
+                "shuffle" => blosc::ShuffleMode::Byte,
+                "bitshuffle" => blosc::ShuffleMode::Bit,
+                other => return Err(failed(&format!("unknown shuffle {other}"))),
+            };
+            let clevel = match config.clevel {
+                0 => blosc::Clevel::None,
+                1 => blosc::Clevel::L1,
+                2 => blosc::Clevel::L2,
+                3 => blosc::Clevel::L3,
+                4 => blosc::Clevel::L4,
+                5 => blosc::Clevel::L5,
+                6 => blosc::Clevel::L6,
+                7 => blosc::Clevel::L7,
+                8 => blosc::Clevel::L8,
+                _ => blosc::Clevel::L9,
+            };
+            let context = blosc::Context::new()
+                .compressor(compressor)
+                .map_err(|err| failed(&err))?
+                .clevel(clevel)
+                .shuffle(shuffle)
+                .typesize(config.typesize)
+                .blocksize(config.blocksize);
+            Ok(context.compress(data).as_ref().to_vec())
+        }
+        #[allow(unreachable_patterns)]
+        _ => Err(codec.unsupported()),
+    }
+}
+
+#[allow(unused_variables)]
+fn decompress(codec: &BytesCodec, data: &[u8]) -> CodecResult<Vec<u8>> {
+    let failed = |err: &dyn fmt::Debug| codec.failed(err);
+    match codec {
+        #[cfg(feature = "gzip")]
+        BytesCodec::Gzip { .. } => {
+            use std::io::Read as _;
+            let mut res = Vec::new();
+            flate2::read::GzDecoder::new(data)
+                .read_to_end(&mut res)
+                .map_err(|err| failed(&err))?;
+            Ok(res)
+        }
+        #[cfg(feature = "zstd")]
+        BytesCodec::Zstd { .. } => {
+            zstd::stream::decode_all(data).map_err(|err| failed(&err))
+        }
+        #[cfg(feature = "blosc")]
+        BytesCodec::Blosc(_) => {
+            // SAFETY: every bit pattern is a valid u8
+            unsafe { blosc::decompress_bytes::<u8>(data) }.map_err(|err| failed(&err))
+        }
+        #[allow(unreachable_patterns)]
+        _ => Err(codec.unsupported()),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{collections::HashMap, num::NonZeroU64};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::metadata::{ChunkKeyEncoding, ChunkShape};
+
+    fn codec(name: &str, configuration: Value) -> Codec {
+        let configuration: HashMap<String, Value> =
+            serde_json::from_value(configuration).unwrap();
+        Codec { name: name.to_string(), configuration: Some(configuration) }
+    }
+
+    #[test]
+    fn test_codec_pipeline() {
+        let mut metadata = ZarrArrayMetadata {
+            shape: vec![2, 3],
+            data_type: DataType::UInt16,
+            chunk_shape: ChunkShape(vec![
+                NonZeroU64::new(2).unwrap(),
+                NonZeroU64::new(3).unwrap(),
+            ]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::UInt16(7),
+            codecs: vec![
+                codec("transpose", serde_json::json!({"order": [1, 0]})),
+                codec("bytes", serde_json::json!({"endian": "big"})),
+            ],
+            storage_transformers: None,
+            dimension_names: None,
+            rectilinear_grid: None,
+        };
+        let values: Vec<u8> = (0u16..6).flat_map(u16::to_le_bytes).collect();
+        let pipeline = CodecPipeline::new(&metadata).unwrap();
+        let encoded = pipeline.encode(&values, &[2, 3]).unwrap();
+        // columns first, big-endian
+        assert_eq!(encoded, vec![0, 0, 0, 3, 0, 1, 0, 4, 0, 2, 0, 5]);
+        assert_eq!(pipeline.decode(&encoded, &[2, 3]).unwrap(), values);
+        assert!(matches!(
+            pipeline.decode(&encoded, &[2, 2]),
+            Err(CodecError::WrongSize { expected: 8, found: 12 })
+        ));
+
+        let fill: Vec<u8> = [7u16; 6].iter().flat_map(|v| v.to_be_bytes()).collect();
+        assert!(pipeline.is_fill_value(&fill, &metadata.fill_value).unwrap());
+        assert!(!pipeline.is_fill_value(&encoded, &metadata.fill_value).unwrap());
+
+        for (compressor, enabled) in [
+            (codec("gzip", serde_json::json!({"level": 5})), cfg!(feature = "gzip")),
+            (
+                codec("zstd", serde_json::json!({"level": 3, "checksum": true})),
+                cfg!(feature = "zstd"),
+            ),
+            (
+                codec(
+                    "blosc",
+                    serde_json::json!({
+                        "cname": "lz4", "clevel": 5, "shuffle": "shuffle", "typesize": 2
+                    }),
+                ),
+                cfg!(feature = "blosc"),
+            ),
+        ] {
+            metadata.codecs.push(compressor);
+            let pipeline = CodecPipeline::new(&metadata).unwrap();
+            match pipeline.encode(&values, &[2, 3]) {
+                Ok(encoded) => {
+                    assert!(enabled);
+                    assert_eq!(pipeline.decode(&encoded, &[2, 3]).unwrap(), values);
+                }
+                Err(err) => {
+                    assert!(!enabled);
+                    assert_eq!(err.kind(), ErrorKind::Unsupported);
+                }
+            }
+            metadata.codecs.pop();
+        }
+
+        metadata.codecs.reverse();
+        assert!(matches!(
+            CodecPipeline::new(&metadata),
+            Err(CodecError::InvalidConfiguration { .. })
+        ));
+        metadata.codecs = vec![codec("transpose", serde_json::json!({"order": [0, 0]}))];
+        assert!(matches!(
+            CodecPipeline::new(&metadata),
+            Err(CodecError::InvalidConfiguration { .. })
+        ));
+        metadata.codecs = vec![codec("sharding_indexed", serde_json::json!({}))];
+        assert!(matches!(
+            CodecPipeline::new(&metadata),
+            Err(CodecError::UnsupportedCodec { .. })
+        ));
+    }
+}
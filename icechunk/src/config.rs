@@ -0,0 +1,643 @@
+//! Validated construction of [`RepositoryConfig`].
+//!
+//! [`RepositoryConfigBuilder`] checks the settings together when the configuration is
+//! built, and reports every problem found at once. Services can load the settings from
+//! JSON documents or environment variables.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    format::{manifest::ManifestSplitPolicy, Path},
+    naming::PathPolicy,
+    notify::NotificationConfig,
+    open::OpenOptions,
+    protection::BranchRule,
+    repair::RepairOptions,
+    repository::EmptyCommitPolicy,
+    validation::ArrayConstraints,
+    verification::VerificationPolicy,
+    RepositoryConfig,
+};
+
+/// A problem found validating a configuration
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub enum ConfigIssue {
+    /// Inline chunks wouldn't fit in a manifest
+    InlineThresholdAboveManifestSize { threshold: u16, max_manifest_bytes: u64 },
+    /// Manifests limited to zero rows or bytes cannot hold anything
+    EmptyManifestLimit { setting: &'static str },
+    /// A setting that cannot be parsed
+    InvalidValue { setting: String, value: String, message: String },
+    /// A setting that needs a cargo feature this build doesn't have
+    MissingFeature { setting: &'static str, feature: &'static str },
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigIssue::InlineThresholdAboveManifestSize {
+                threshold,
+                max_manifest_bytes,
+            } => {
+                write!(
+                    f,
+                    "inline chunk threshold ({threshold} bytes) is larger than the maximum manifest size ({max_manifest_bytes} bytes)"
+                )
+            }
+            ConfigIssue::EmptyManifestLimit { setting } => {
+                write!(f, "{setting} must be greater than zero")
+            }
+            ConfigIssue::InvalidValue { setting, value, message } => {
+                write!(f, "invalid value `{value}` for {setting}: {message}")
+            }
+            ConfigIssue::MissingFeature { setting, feature } => {
+                write!(f, "{setting} needs the `{feature}` feature")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error, Serialize)]
+#[error("invalid repository configuration: {}", .issues.iter().map(|issue| issue.to_string()).collect::<Vec<_>>().join("; "))]
+pub struct ConfigError {
+    pub issues: Vec<ConfigIssue>,
+}
+
+pub type ConfigResult<A> = Result<A, ConfigError>;
+
+/// The settings of a [`RepositoryConfig`] as they are written in JSON, all optional
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepositoryConfigFile {
+    pub inline_chunk_threshold_bytes: Option<u16>,
+    pub unsafe_overwrite_refs: Option<bool>,
+    pub cleanup_on_drop: Option<bool>,
+    pub manifest_split_policy: Option<ManifestSplitPolicy>,
+    pub compute_chunk_statistics: Option<bool>,
+    pub manifest_fetch_concurrency: Option<u16>,
+    pub array_constraints: Option<BTreeMap<Path, ArrayConstraints>>,
+    pub compute_chunk_checksums: Option<bool>,
+    pub verification: Option<VerificationPolicy>,
+    pub skip_noop_writes: Option<bool>,
+    pub empty_commits: Option<EmptyCommitPolicy>,
+    pub notifications: Option<NotificationConfig>,
+    pub protected_tags: Option<bool>,
+    pub branch_protection: Option<Vec<BranchRule>>,
+    pub validate_virtual_refs: Option<bool>,
+    pub path_policy: Option<PathPolicy>,
+    pub open: Option<OpenOptions>,
+    pub repair: Option<RepairOptions>,
+    pub coordinate_arrays: Option<BTreeMap<Path, BTreeMap<String, Path>>>,
+    pub frozen_arrays: Option<BTreeSet<Path>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RepositoryConfigBuilder {
+    config: RepositoryConfig,
+    // problems found while loading settings, reported by `build`
+    issues: Vec<ConfigIssue>,
+}
+
+impl RepositoryConfigBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Settings from a JSON document, missing settings keep their defaults
+    pub fn from_json(json: &[u8]) -> ConfigResult<Self> {
+        let file: RepositoryConfigFile =
+            serde_json::from_slice(json).map_err(|err| ConfigError {
+                issues: vec![ConfigIssue::InvalidValue {
+                    setting: "configuration".to_string(),
+                    value: String::from_utf8_lossy(json).to_string(),
+                    message: err.to_string(),
+                }],
+            })?;
+        let mut builder = Self::new();
+        builder.with_file(file);
+        Ok(builder)
+    }
+
+    /// Settings from the environment variables named `{prefix}{SETTING}`, for example
+    /// `ICECHUNK_INLINE_CHUNK_THRESHOLD_BYTES`.
+    ///
+    /// The manifest split policy is read from `MANIFEST_MAX_ROWS`, `MANIFEST_MAX_BYTES`,
+    /// `MANIFEST_SPLIT_AXIS` and `MANIFEST_COORDINATE_ORDER`, the path policy from
+    /// `PATH_UNICODE_FORM` and `PATH_CASE_HANDLING`.
+    pub fn from_env(prefix: &str) -> Self {
+        Self::from_vars(prefix, std::env::vars())
+    }
+
+    /// Like [`RepositoryConfigBuilder::from_env`], reading from `vars` instead
+    pub fn from_vars(
+        prefix: &str,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Self {
+        let vars: HashMap<String, String> = vars
+            .into_iter()
+            .filter_map(|(name, value)| {
+                Some((name.strip_prefix(prefix)?.to_string(), value))
+            })
+            .collect();
+        let mut builder = Self::new();
+        let policy = ManifestSplitPolicy {
+            max_rows: builder.parse_var(prefix, &vars, "MANIFEST_MAX_ROWS"),
+            max_bytes: builder.parse_var(prefix, &vars, "MANIFEST_MAX_BYTES"),
+            split_axis: builder.parse_var(prefix, &vars, "MANIFEST_SPLIT_AXIS"),
+            coordinate_order: builder
+                .parse_var(prefix, &vars, "MANIFEST_COORDINATE_ORDER")
+                .unwrap_or_default(),
+        };
+        let path_policy = PathPolicy {
+            unicode: builder
+                .parse_var(prefix, &vars, "PATH_UNICODE_FORM")
+                .unwrap_or_default(),
+            case: builder
+                .parse_var(prefix, &vars, "PATH_CASE_HANDLING")
+                .unwrap_or_default(),
+        };
+        let file = RepositoryConfigFile {
+            inline_chunk_threshold_bytes: builder.parse_var(
+                prefix,
+                &vars,
+                "INLINE_CHUNK_THRESHOLD_BYTES",
+            ),
+            unsafe_overwrite_refs: builder.parse_var(
+                prefix,
+                &vars,
+                "UNSAFE_OVERWRITE_REFS",
+            ),
+            cleanup_on_drop: builder.parse_var(prefix, &vars, "CLEANUP_ON_DROP"),
+            manifest_split_policy: (policy != ManifestSplitPolicy::default())
+                .then_some(policy),
+            compute_chunk_statistics: builder.parse_var(
+                prefix,
+                &vars,
+                "COMPUTE_CHUNK_STATISTICS",
+            ),
+            manifest_fetch_concurrency: builder.parse_var(
+                prefix,
+                &vars,
+                "MANIFEST_FETCH_CONCURRENCY",
+            ),
+            // constraints are structured, they can only be set from JSON
+            array_constraints: None,
+            compute_chunk_checksums: builder.parse_var(
+                prefix,
+                &vars,
+                "COMPUTE_CHUNK_CHECKSUMS",
+            ),
+            // like constraints, the verification policy can only be set from JSON
+            verification: None,
+            skip_noop_writes: builder.parse_var(prefix, &vars, "SKIP_NOOP_WRITES"),
+            empty_commits: builder.parse_var(prefix, &vars, "EMPTY_COMMITS"),
+            // the notification target is structured too
+            notifications: None,
+            protected_tags: builder.parse_var(prefix, &vars, "PROTECTED_TAGS"),
+            // protection rules are structured too
+            branch_protection: None,
+            validate_virtual_refs: builder.parse_var(
+                prefix,
+                &vars,
+                "VALIDATE_VIRTUAL_REFS",
+            ),
+            path_policy: (path_policy != PathPolicy::default()).then_some(path_policy),
+            // open options are structured too
+            open: None,
+            repair: builder
+                .parse_var(prefix, &vars, "SALVAGE_MANIFESTS")
+                .map(|value| RepairOptions::default().with_salvage_manifests(value)),
+            // coordinate arrays and frozen arrays are structured too
+            coordinate_arrays: None,
+            frozen_arrays: None,
+        };
+        builder.with_file(file);
+        builder
+    }
+
+    fn parse_var<T: FromStr>(
+        &mut self,
+        prefix: &str,
+        vars: &HashMap<String, String>,
+        name: &str,
+    ) -> Option<T>
+    where
+        T::Err: fmt::Display,
+    {
+        let value = vars.get(name)?;
+        match value.trim().parse() {
+            Ok(parsed) => Some(parsed),
+            Err(err) => {
+                self.issues.push(ConfigIssue::InvalidValue {
+                    setting: format!("{prefix}{name}"),
+                    value: value.clone(),
+                    message: err.to_string(),
+                });
+                None
+            }
+        }
+    }
+
+    /// Apply the settings present in `file`
+    pub fn with_file(&mut self, file: RepositoryConfigFile) -> &mut Self {
+        if let Some(threshold) = file.inline_chunk_threshold_bytes {
+            self.with_inline_threshold_bytes(threshold);
+        }
+        if let Some(value) = file.unsafe_overwrite_refs {
+            self.with_unsafe_overwrite_refs(value);
+        }
+        if let Some(value) = file.cleanup_on_drop {
+            self.with_cleanup_on_drop(value);
+        }
+        if let Some(policy) = file.manifest_split_policy {
+            self.with_manifest_split_policy(policy);
+        }
+        if let Some(value) = file.compute_chunk_statistics {
+            self.with_compute_chunk_statistics(value);
+        }
+        if let Some(value) = file.manifest_fetch_concurrency {
+            self.with_manifest_fetch_concurrency(value);
+        }
+        for (path, constraints) in file.array_constraints.into_iter().flatten() {
+            self.with_array_constraints(path, constraints);
+        }
+        if let Some(value) = file.compute_chunk_checksums {
+            self.with_compute_chunk_checksums(value);
+        }
+        if let Some(policy) = file.verification {
+            self.with_verification_policy(policy);
+        }
+        if let Some(value) = file.skip_noop_writes {
+            self.with_skip_noop_writes(value);
+        }
+        if let Some(policy) = file.empty_commits {
+            self.with_empty_commit_policy(policy);
+        }
+        if let Some(config) = file.notifications {
+            self.with_notifications(config);
+        }
+        if let Some(value) = file.protected_tags {
+            self.with_protected_tags(value);
+        }
+        for rule in file.branch_protection.into_iter().flatten() {
+            self.with_branch_rule(rule);
+        }
+        if let Some(value) = file.validate_virtual_refs {
+            self.with_validate_virtual_refs(value);
+        }
+        if let Some(policy) = file.path_policy {
+            self.with_path_policy(policy);
+        }
+        if let Some(options) = file.open {
+            self.with_open_options(options);
+        }
+        if let Some(options) = file.repair {
+            self.with_repair_options(options);
+        }
+        for (group, dimensions) in file.coordinate_arrays.into_iter().flatten() {
+            for (dimension, coordinates) in dimensions {
+                self.with_coordinate_array(group.clone(), dimension, coordinates);
+            }
+        }
+        for path in file.frozen_arrays.into_iter().flatten() {
+            self.with_frozen_array(path);
+        }
+        self
+    }
+
+    pub fn with_inline_threshold_bytes(&mut self, threshold: u16) -> &mut Self {
+        self.config.inline_chunk_threshold_bytes = threshold;
+        self
+    }
+
+    pub fn with_unsafe_overwrite_refs(&mut self, value: bool) -> &mut Self {
+        self.config.unsafe_overwrite_refs = value;
+        self
+    }
+
+    pub fn with_cleanup_on_drop(&mut self, value: bool) -> &mut Self {
+        self.config.cleanup_on_drop = value;
+        self
+    }
+
+    pub fn with_manifest_split_policy(
+        &mut self,
+        policy: ManifestSplitPolicy,
+    ) -> &mut Self {
+        self.config.manifest_split_policy = policy;
+        self
+    }
+
+    pub fn with_compute_chunk_statistics(&mut self, value: bool) -> &mut Self {
+        self.config.compute_chunk_statistics = value;
+        self
+    }
+
+    pub fn with_manifest_fetch_concurrency(&mut self, value: u16) -> &mut Self {
+        self.config.manifest_fetch_concurrency = value;
+        self
+    }
+
+    pub fn with_array_constraints(
+        &mut self,
+        path: Path,
+        constraints: ArrayConstraints,
+    ) -> &mut Self {
+        self.config.array_constraints.insert(path, constraints);
+        self
+    }
+
+    pub fn with_compute_chunk_checksums(&mut self, value: bool) -> &mut Self {
+        self.config.compute_chunk_checksums = value;
+        self
+    }
+
+    pub fn with_verification_policy(&mut self, policy: VerificationPolicy) -> &mut Self {
+        self.config.verification = policy;
+        self
+    }
+
+    pub fn with_skip_noop_writes(&mut self, value: bool) -> &mut Self {
+        self.config.skip_noop_writes = value;
+        self
+    }
+
+    pub fn with_empty_commit_policy(&mut self, policy: EmptyCommitPolicy) -> &mut Self {
+        self.config.empty_commits = policy;
+        self
+    }
+
+    pub fn with_notifications(&mut self, config: NotificationConfig) -> &mut Self {
+        self.config.notifications = Some(config);
+        self
+    }
+
+    pub fn with_protected_tags(&mut self, value: bool) -> &mut Self {
+        self.config.protected_tags = value;
+        self
+    }
+
+    pub fn with_branch_rule(&mut self, rule: BranchRule) -> &mut Self {
+        self.config.branch_protection.push(rule);
+        self
+    }
+
+    pub fn with_validate_virtual_refs(&mut self, value: bool) -> &mut Self {
+        self.config.validate_virtual_refs = value;
+        self
+    }
+
+    pub fn with_path_policy(&mut self, policy: PathPolicy) -> &mut Self {
+        self.config.path_policy = policy;
+        self
+    }
+
+    pub fn with_open_options(&mut self, options: OpenOptions) -> &mut Self {
+        self.config.open = options;
+        self
+    }
+
+    pub fn with_repair_options(&mut self, options: RepairOptions) -> &mut Self {
+        self.config.repair = options;
+        self
+    }
+
+    /// Label the elements along `dimension`, in the arrays of `group`, with the values of
+    /// the 1-dimensional array at `coordinates`
+    pub fn with_coordinate_array(
+        &mut self,
+        group: Path,
+        dimension: impl Into<String>,
+        coordinates: Path,
+    ) -> &mut Self {
+        self.config
+            .coordinate_arrays
+            .entry(group)
+            .or_default()
+            .insert(dimension.into(), coordinates);
+        self
+    }
+
+    pub fn with_frozen_array(&mut self, path: Path) -> &mut Self {
+        self.config.frozen_arrays.insert(path);
+        self
+    }
+
+    /// Validate the settings, returning all the problems found
+    pub fn build(&self) -> ConfigResult<RepositoryConfig> {
+        let mut issues = self.issues.clone();
+        let policy = &self.config.manifest_split_policy;
+        if policy.max_rows == Some(0) {
+            issues.push(ConfigIssue::EmptyManifestLimit { setting: "manifest max_rows" });
+        }
+        match policy.max_bytes {
+            Some(0) => {
+                issues.push(ConfigIssue::EmptyManifestLimit {
+                    setting: "manifest max_bytes",
+                });
+            }
+            Some(max_bytes)
+                if u64::from(self.config.inline_chunk_threshold_bytes) > max_bytes =>
+            {
+                issues.push(ConfigIssue::InlineThresholdAboveManifestSize {
+                    threshold: self.config.inline_chunk_threshold_bytes,
+                    max_manifest_bytes: max_bytes,
+                });
+            }
+            _ => {}
+        }
+        if self.config.notifications.is_some() && !cfg!(feature = "notifications") {
+            issues.push(ConfigIssue::MissingFeature {
+                setting: "notifications",
+                feature: "notifications",
+            });
+        }
+        if !self.config.coordinate_arrays.is_empty() && !cfg!(feature = "ndarray") {
+            issues.push(ConfigIssue::MissingFeature {
+                setting: "coordinate_arrays",
+                feature: "ndarray",
+            });
+        }
+        if issues.is_empty() {
+            Ok(self.config.clone())
+        } else {
+            Err(ConfigError { issues })
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::manifest::CoordinateOrder, naming::CaseHandling,
+        verification::VerificationMode,
+    };
+
+    #[test]
+    fn test_config_builder_validation() {
+        let config = RepositoryConfigBuilder::new()
+            .with_inline_threshold_bytes(100)
+            .with_compute_chunk_statistics(true)
+            .build()
+            .unwrap();
+        assert_eq!(config.inline_chunk_threshold_bytes, 100);
+        assert!(config.compute_chunk_statistics);
+
+        let err = RepositoryConfigBuilder::new()
+            .with_inline_threshold_bytes(4096)
+            .with_manifest_split_policy(ManifestSplitPolicy {
+                max_rows: Some(0),
+                max_bytes: Some(1024),
+                ..Default::default()
+            })
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err.issues,
+            vec![
+                ConfigIssue::EmptyManifestLimit { setting: "manifest max_rows" },
+                ConfigIssue::InlineThresholdAboveManifestSize {
+                    threshold: 4096,
+                    max_manifest_bytes: 1024
+                },
+            ]
+        );
+        assert!(err
+            .to_string()
+            .contains("manifest max_rows must be greater than zero; "));
+    }
+
+    #[test]
+    fn test_repository_config_serialization() {
+        let config = RepositoryConfigBuilder::new()
+            .with_cleanup_on_drop(true)
+            .with_manifest_split_policy(ManifestSplitPolicy {
+                max_rows: Some(100),
+                ..Default::default()
+            })
+            .build()
+            .unwrap();
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(serde_json::from_str::<RepositoryConfig>(&json).unwrap(), config);
+
+        // missing fields take their default values
+        let config: RepositoryConfig =
+            serde_json::from_str(r#"{"unsafe_overwrite_refs": true}"#).unwrap();
+        assert!(config.unsafe_overwrite_refs);
+        assert_eq!(config.inline_chunk_threshold_bytes, 512);
+
+        let err = ConfigError {
+            issues: vec![ConfigIssue::EmptyManifestLimit {
+                setting: "manifest max_rows",
+            }],
+        };
+        assert_eq!(
+            serde_json::to_value(&err).unwrap(),
+            serde_json::json!({"issues": [{"EmptyManifestLimit": {"setting": "manifest max_rows"}}]})
+        );
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_config_from_json_and_env() {
+        let json = br#"{"inline_chunk_threshold_bytes": 8, "manifest_split_policy": {"max_rows": 10, "max_bytes": null, "split_axis": 0}}"#;
+        let config = RepositoryConfigBuilder::from_json(json).unwrap().build().unwrap();
+        assert_eq!(config.inline_chunk_threshold_bytes, 8);
+        assert_eq!(config.manifest_split_policy.max_rows, Some(10));
+        assert_eq!(config.manifest_split_policy.split_axis, Some(0));
+        assert!(
+            RepositoryConfigBuilder::from_json(br#"{"inline_threshold": 8}"#).is_err()
+        );
+        let json = br#"{"array_constraints": {"/temperature": {"data_type": "float32", "coordinate_domain": [{"start": 0, "end": 10}]}}}"#;
+        let config = RepositoryConfigBuilder::from_json(json).unwrap().build().unwrap();
+        let path: Path = "/temperature".try_into().unwrap();
+        assert_eq!(
+            config.array_constraints.get(&path),
+            Some(
+                &ArrayConstraints::default()
+                    .with_data_type(crate::metadata::DataType::Float32)
+                    .with_coordinate_domain(vec![0..10])
+            )
+        );
+        let json = br#"{"verification": {"chunk_checksums": "Always", "manifest_hashes": {"Sampled": 10}}}"#;
+        let config = RepositoryConfigBuilder::from_json(json).unwrap().build().unwrap();
+        assert_eq!(
+            config.verification,
+            VerificationPolicy::default()
+                .with_chunk_checksums(VerificationMode::Always)
+                .with_manifest_hashes(VerificationMode::Sampled(10))
+        );
+        let json = br#"{"notifications": {"repository": "weather", "target": {"type": "webhook", "url": "https://example.com/events"}}}"#;
+        let result = RepositoryConfigBuilder::from_json(json).unwrap().build();
+        if cfg!(feature = "notifications") {
+            assert_eq!(result.unwrap().notifications.unwrap().repository, "weather");
+        } else {
+            assert_eq!(
+                result.unwrap_err().issues,
+                vec![ConfigIssue::MissingFeature {
+                    setting: "notifications",
+                    feature: "notifications"
+                }]
+            );
+        }
+
+        let vars = [
+            ("ICECHUNK_CLEANUP_ON_DROP", "true"),
+            ("ICECHUNK_MANIFEST_MAX_BYTES", "65536"),
+            ("ICECHUNK_MANIFEST_COORDINATE_ORDER", "morton"),
+            ("ICECHUNK_COMPUTE_CHUNK_CHECKSUMS", "true"),
+            ("ICECHUNK_SKIP_NOOP_WRITES", "true"),
+            ("ICECHUNK_EMPTY_COMMITS", "return-head"),
+            ("ICECHUNK_PATH_CASE_HANDLING", "insensitive"),
+            ("OTHER_INLINE_CHUNK_THRESHOLD_BYTES", "not read"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let config =
+            RepositoryConfigBuilder::from_vars("ICECHUNK_", vars).build().unwrap();
+        assert!(config.cleanup_on_drop);
+        assert_eq!(config.manifest_split_policy.max_bytes, Some(65536));
+        assert_eq!(
+            config.manifest_split_policy.coordinate_order,
+            CoordinateOrder::Morton
+        );
+        assert!(config.compute_chunk_checksums);
+        assert!(config.skip_noop_writes);
+        assert_eq!(config.empty_commits, EmptyCommitPolicy::ReturnHead);
+        assert_eq!(config.path_policy.case, CaseHandling::Insensitive);
+        assert_eq!(config.inline_chunk_threshold_bytes, 512);
+
+        let vars = [
+            ("ICECHUNK_INLINE_CHUNK_THRESHOLD_BYTES", "1e6"),
+            ("ICECHUNK_UNSAFE_OVERWRITE_REFS", "yes"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let err =
+            RepositoryConfigBuilder::from_vars("ICECHUNK_", vars).build().unwrap_err();
+        let settings: Vec<_> = err
+            .issues
+            .iter()
+            .map(|issue| match issue {
+                ConfigIssue::InvalidValue { setting, .. } => setting.as_str(),
+                other => panic!("unexpected issue {other:?}"),
+            })
+            .collect();
+        assert_eq!(
+            settings,
+            vec![
+                "ICECHUNK_INLINE_CHUNK_THRESHOLD_BYTES",
+                "ICECHUNK_UNSAFE_OVERWRITE_REFS"
+            ]
+        );
+    }
+}
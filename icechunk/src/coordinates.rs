@@ -0,0 +1,201 @@
+//! Selecting array elements by label, with the `ndarray` feature.
+//!
+//! Like the indexes of xarray, a coordinate array holds a label for every element along
+//! a dimension, for example the days of a time axis encoded as integers. Coordinate
+//! arrays are registered in the [`crate::RepositoryConfig`] per group and dimension
+//! name, and label the arrays of the group that have a dimension with that name. Their
+//! labels must be sorted in ascending order.
+//!
+//! [`Repository::select_labels`] turns a range of labels into the elements and chunks it
+//! covers: the selection can be read with [`Repository::read_selection`], and the region
+//! deleted with [`Repository::delete_chunks_in_range`] or declared with
+//! [`Repository::declare_write_region`].
+use std::{ops::Range, slice};
+
+use crate::{
+    decoded::Element,
+    format::{
+        snapshot::{ChunkRegion, NodeData},
+        Path,
+    },
+    repository::{RepositoryError, RepositoryResult},
+    Repository,
+};
+
+/// The elements of an array with labels in a range, along one of its dimensions
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LabelSelection {
+    /// The position of the dimension in the shape of the array
+    pub axis: usize,
+    /// The elements along the dimension with labels in the range
+    pub elements: Range<u64>,
+    /// The chunks along the dimension that hold those elements
+    pub chunks: Range<u64>,
+    /// Those elements, and every element along the other dimensions
+    pub selection: Vec<Range<u64>>,
+    /// Those chunks, and every chunk along the other dimensions
+    pub region: ChunkRegion,
+}
+
+/// The coordinate array registered for `dimension` in the group of the array at `path`
+fn coordinate_array<'a>(
+    repository: &'a Repository,
+    path: &Path,
+    dimension: &str,
+) -> RepositoryResult<&'a Path> {
+    let group = path.ancestors().nth(1).unwrap_or_else(Path::root);
+    repository
+        .config()
+        .coordinate_arrays
+        .get(&group)
+        .and_then(|dimensions| dimensions.get(dimension))
+        .ok_or_else(|| RepositoryError::NoCoordinateArray {
+            path: path.clone(),
+            dimension: dimension.to_string(),
+        })
+}
+
+pub(crate) async fn select_labels<T: Element + PartialOrd>(
+    repository: &Repository,
+    path: &Path,
+    dimension: &str,
+    labels: Range<T>,
+) -> RepositoryResult<LabelSelection> {
+    let node = repository.get_array(path).await?;
+    let (NodeData::Array(metadata, _) | NodeData::Concatenated(metadata, _)) =
+        &node.node_data
+    else {
+        return Err(RepositoryError::NotAnArray {
+            node: Box::new(node),
+            message: "selecting labels".to_string(),
+        });
+    };
+    let axis = metadata.axis_index(dimension)?;
+    let size = metadata.shape[axis];
+
+    let coordinates = coordinate_array(repository, path, dimension)?;
+    let invalid = |message: String| RepositoryError::InvalidCoordinateArray {
+        path: coordinates.clone(),
+        message,
+    };
+    let coordinate_node = repository.get_array(coordinates).await?;
+    let (NodeData::Array(coordinate_metadata, _)
+    | NodeData::Concatenated(coordinate_metadata, _)) = &coordinate_node.node_data
+    else {
+        return Err(invalid("it is not an array".to_string()));
+    };
+    if coordinate_metadata.shape != [size] {
+        return Err(invalid(format!(
+            "its shape is {:?}, it must be [{size}] to label `{path}`",
+            coordinate_metadata.shape
+        )));
+    }
+    let all = 0..size;
+    let values =
+        repository.read_selection::<T>(coordinates, slice::from_ref(&all)).await?;
+    let values: Vec<T> = values.iter().copied().collect();
+    if !values.windows(2).all(|pair| pair[0] <= pair[1]) {
+        return Err(invalid("its labels are not sorted".to_string()));
+    }
+
+    let start = values.partition_point(|value| *value < labels.start) as u64;
+    let end = (values.partition_point(|value| *value < labels.end) as u64).max(start);
+    let spans = metadata.axis_chunk_spans(axis, &(start..end))?;
+    let chunks = match (spans.first(), spans.last()) {
+        (Some((first, _)), Some((last, _))) => *first..last + 1,
+        _ => 0..0,
+    };
+    let selection = metadata
+        .shape
+        .iter()
+        .enumerate()
+        .map(|(index, size)| if index == axis { start..end } else { 0..*size })
+        .collect();
+    let region = metadata.region_along(axis, chunks.clone())?;
+    Ok(LabelSelection { axis, elements: start..end, chunks, selection, region })
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64, sync::Arc};
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        error::ErrorKind,
+        format::{manifest::ChunkPayload, snapshot::ZarrArrayMetadata, ChunkIndices},
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        ObjectStorage, Storage,
+    };
+
+    #[tokio::test]
+    async fn test_select_labels() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let group: Path = "/weather".try_into().unwrap();
+        let time: Path = "/weather/time".try_into().unwrap();
+        let temperature: Path = "/weather/temperature".try_into().unwrap();
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_coordinate_array(group.clone(), "time", time.clone())
+            .build();
+        let metadata =
+            |shape: Vec<u64>, chunks: Vec<u64>, names: Vec<&str>| ZarrArrayMetadata {
+                shape,
+                data_type: DataType::Int64,
+                chunk_shape: ChunkShape(
+                    chunks
+                        .into_iter()
+                        .map(|size| NonZeroU64::new(size).unwrap())
+                        .collect(),
+                ),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::Int64(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: Some(
+                    names.into_iter().map(|name| Some(name.to_string())).collect(),
+                ),
+                rectilinear_grid: None,
+            };
+        repo.add_group(Path::root()).await?;
+        repo.add_group(group.clone()).await?;
+        repo.add_array(time.clone(), metadata(vec![6], vec![6], vec!["time"])).await?;
+        repo.add_array(
+            temperature.clone(),
+            metadata(vec![2, 6], vec![2, 2], vec!["station", "time"]),
+        )
+        .await?;
+        // days since the start of the year, with a gap
+        let days: [i64; 6] = [10, 11, 12, 20, 21, 22];
+        let bytes = Bytes::from_iter(days.iter().flat_map(|day| day.to_le_bytes()));
+        repo.set_chunk_ref(
+            time.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Inline(bytes)),
+        )
+        .await?;
+
+        let selected = repo.select_labels(&temperature, "time", 12i64..21).await?;
+        assert_eq!(
+            selected,
+            LabelSelection {
+                axis: 1,
+                elements: 2..4,
+                chunks: 1..2,
+                selection: vec![0..2, 2..4],
+                region: ChunkRegion(vec![0..u64::MAX, 1..2]),
+            }
+        );
+        // labels in the gap select nothing
+        let selected = repo.select_labels(&temperature, "time", 13i64..20).await?;
+        assert_eq!((selected.elements, selected.chunks), (3..3, 0..0));
+
+        let err = repo.select_labels(&temperature, "station", 0i64..1).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        Ok(())
+    }
+}
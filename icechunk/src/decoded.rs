@@ -0,0 +1,225 @@
+//! Reading array selections as decoded [`ndarray`] arrays, with the `ndarray` feature.
+//!
+//! [`crate::Repository::read_selection`] plans the read with [`crate::read_plan`],
+//! fetches the chunks concurrently, decodes them and copies the selected elements into a
+//! single array. Chunks that were never written hold the fill value. Chunks are decoded
+//! with the [`crate::codecs`] pipeline.
+use std::ops::Range;
+
+use futures::future::try_join_all;
+use ndarray::{ArrayD, ArrayViewD, AxisDescription, IxDyn, Slice};
+
+use crate::{
+    codecs::CodecPipeline,
+    format::{snapshot::NodeData, ByteRange, Path},
+    metadata::{DataType, FillValue},
+    private,
+    repository::{RepositoryError, RepositoryResult},
+    Repository,
+};
+
+/// The element types arrays can be decoded to
+pub trait Element: Copy + private::Sealed + 'static {
+    /// The name of the type in error messages
+    const NAME: &'static str;
+    /// The encoded size in bytes
+    const SIZE: usize;
+
+    fn is_data_type(data_type: &DataType) -> bool;
+    fn from_fill_value(fill_value: &FillValue) -> Option<Self>;
+    /// Decode one element from exactly [`Self::SIZE`] little-endian bytes
+    fn decode(bytes: &[u8]) -> Self;
+}
+
+impl private::Sealed for bool {}
+
+impl Element for bool {
+    const NAME: &'static str = "bool";
+    const SIZE: usize = 1;
+
+    fn is_data_type(data_type: &DataType) -> bool {
+        data_type == &DataType::Bool
+    }
+
+    fn from_fill_value(fill_value: &FillValue) -> Option<Self> {
+        match fill_value {
+            FillValue::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        bytes[0] != 0
+    }
+}
+
+macro_rules! numeric_element {
+    ($t:ty, $variant:ident) => {
+        impl private::Sealed for $t {}
+
+        impl Element for $t {
+            const NAME: &'static str = stringify!($t);
+            const SIZE: usize = size_of::<$t>();
+
+            fn is_data_type(data_type: &DataType) -> bool {
+                data_type == &DataType::$variant
+            }
+
+            fn from_fill_value(fill_value: &FillValue) -> Option<Self> {
+                match fill_value {
+                    FillValue::$variant(value) => Some(*value),
+                    _ => None,
+                }
+            }
+
+            fn decode(bytes: &[u8]) -> Self {
+                #[allow(clippy::unwrap_used)]
+                <$t>::from_le_bytes(bytes.try_into().unwrap())
+            }
+        }
+    };
+}
+
+numeric_element!(i8, Int8);
+numeric_element!(i16, Int16);
+numeric_element!(i32, Int32);
+numeric_element!(i64, Int64);
+numeric_element!(u8, UInt8);
+numeric_element!(u16, UInt16);
+numeric_element!(u32, UInt32);
+numeric_element!(u64, UInt64);
+numeric_element!(f32, Float32);
+numeric_element!(f64, Float64);
+
+fn to_usize(values: &[u64]) -> Vec<usize> {
+    values.iter().map(|value| *value as usize).collect()
+}
+
+fn slice_of(selection: &[Range<u64>]) -> impl Fn(AxisDescription) -> Slice + '_ {
+    |axis| {
+        let range = &selection[axis.axis.index()];
+        Slice::from(range.start as usize..range.end as usize)
+    }
+}
+
+pub(crate) async fn read_selection<T: Element>(
+    repository: &Repository,
+    path: &Path,
+    selection: &[Range<u64>],
+) -> RepositoryResult<ArrayD<T>> {
+    let node = repository.get_array(path).await?;
+    let (NodeData::Array(metadata, _) | NodeData::Concatenated(metadata, _)) =
+        &node.node_data
+    else {
+        return Err(RepositoryError::NotAnArray {
+            node: Box::new(node),
+            message: "reading a selection".to_string(),
+        });
+    };
+    let pipeline = CodecPipeline::new(metadata)?;
+    let fill_value = T::from_fill_value(&metadata.fill_value)
+        .filter(|_| T::is_data_type(&metadata.data_type))
+        .ok_or_else(|| RepositoryError::WrongElementType {
+            path: path.clone(),
+            data_type: metadata.data_type.clone(),
+            requested: T::NAME,
+        })?;
+
+    let plan = repository.plan_read(path, selection).await?;
+    let mut readers = Vec::new();
+    for chunk in plan.chunks.iter().filter(|chunk| chunk.stored_bytes.is_some()) {
+        if let Some(reader) =
+            repository.get_chunk_reader(path, &chunk.coords, &ByteRange::ALL).await?
+        {
+            readers.push(async move {
+                let bytes = reader.await?;
+                Ok::<_, RepositoryError>((chunk, bytes))
+            });
+        }
+    }
+
+    let mut output = ArrayD::from_elem(IxDyn(&to_usize(&plan.shape)), fill_value);
+    for (chunk, bytes) in try_join_all(readers).await? {
+        let extent = metadata.chunk_extent(&chunk.coords)?;
+        let values: Vec<T> = pipeline
+            .decode(&bytes, &extent)
+            .map_err(|err| RepositoryError::from(err).with_chunk(path, &chunk.coords))?
+            .chunks_exact(T::SIZE)
+            .map(T::decode)
+            .collect();
+        #[allow(clippy::expect_used)]
+        let decoded = ArrayViewD::from_shape(IxDyn(&to_usize(&extent)), &values)
+            .expect("the number of values matches the chunk extent");
+        output
+            .slice_each_axis_mut(slice_of(&chunk.output_selection))
+            .assign(&decoded.slice_each_axis(slice_of(&chunk.chunk_selection)));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{collections::HashMap, error::Error, num::NonZeroU64, sync::Arc};
+
+    use bytes::Bytes;
+    use ndarray::array;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        error::ErrorKind,
+        format::{manifest::ChunkPayload, snapshot::ZarrArrayMetadata, ChunkIndices},
+        metadata::{ChunkKeyEncoding, ChunkShape, Codec},
+        ObjectStorage, Storage,
+    };
+
+    #[tokio::test]
+    async fn test_read_selection() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        let path: Path = "/array".try_into().unwrap();
+        let metadata = ZarrArrayMetadata {
+            shape: vec![3, 3],
+            data_type: DataType::Int16,
+            chunk_shape: ChunkShape(vec![
+                NonZeroU64::new(2).unwrap(),
+                NonZeroU64::new(2).unwrap(),
+            ]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int16(-1),
+            codecs: vec![Codec {
+                name: "bytes".to_string(),
+                configuration: Some(HashMap::from([(
+                    "endian".to_string(),
+                    "big".into(),
+                )])),
+            }],
+            storage_transformers: None,
+            dimension_names: None,
+            rectilinear_grid: None,
+        };
+        repo.add_group(Path::root()).await?;
+        repo.add_array(path.clone(), metadata).await?;
+        let encode = |values: [i16; 4]| {
+            Some(ChunkPayload::Inline(Bytes::from_iter(
+                values.iter().flat_map(|value| value.to_be_bytes()),
+            )))
+        };
+        repo.set_chunk_ref(path.clone(), ChunkIndices(vec![0, 0]), encode([0, 1, 3, 4]))
+            .await?;
+        // the edge chunk stores a full chunk, past the end of the array
+        repo.set_chunk_ref(path.clone(), ChunkIndices(vec![1, 0]), encode([6, 7, 9, 9]))
+            .await?;
+
+        let data = repo.read_selection::<i16>(&path, &[0..3, 0..3]).await?;
+        assert_eq!(data, array![[0, 1, -1], [3, 4, -1], [6, 7, -1]].into_dyn());
+        let data = repo.read_selection::<i16>(&path, &[1..3, 1..2]).await?;
+        assert_eq!(data, array![[4], [7]].into_dyn());
+
+        let err = repo.read_selection::<f64>(&path, &[0..1, 0..1]).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidRequest);
+        Ok(())
+    }
+}
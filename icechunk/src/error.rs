@@ -0,0 +1,88 @@
+//! Classification of errors.
+//!
+//! Every error type returned by the public API has a `kind` method returning an
+//! [`ErrorKind`]. Applications use it to decide if a failed operation is worth retrying:
+//! throttling and transient network failures usually go away, missing objects,
+//! corruption or permission errors don't.
+use std::io;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// The storage service asked to slow down
+    Throttled,
+    /// Timeouts, dropped connections and server side failures
+    Transient,
+    /// The object, node or ref doesn't exist
+    NotFound,
+    /// Somebody else updated the same ref, or the object already exists
+    Conflict,
+    /// The credentials don't allow the operation
+    PermissionDenied,
+    /// Objects in storage can't be decoded or are inconsistent
+    Corruption,
+    /// The request is invalid for the current state of the repository
+    InvalidRequest,
+    /// The storage or the repository doesn't implement the operation
+    Unsupported,
+    Other,
+}
+
+impl ErrorKind {
+    /// True for the kinds of errors that can succeed if the operation is tried again
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ErrorKind::Throttled | ErrorKind::Transient)
+    }
+
+    /// The kind of errors answered with the HTTP `status` code
+    pub fn from_http_status(status: u16) -> Self {
+        match status {
+            429 | 503 => ErrorKind::Throttled,
+            408 | 500 | 502 | 504 => ErrorKind::Transient,
+            401 | 403 => ErrorKind::PermissionDenied,
+            404 => ErrorKind::NotFound,
+            409 | 412 => ErrorKind::Conflict,
+            400..=499 => ErrorKind::InvalidRequest,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl From<io::ErrorKind> for ErrorKind {
+    fn from(kind: io::ErrorKind) -> Self {
+        match kind {
+            io::ErrorKind::NotFound => ErrorKind::NotFound,
+            io::ErrorKind::PermissionDenied => ErrorKind::PermissionDenied,
+            io::ErrorKind::AlreadyExists => ErrorKind::Conflict,
+            io::ErrorKind::TimedOut
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof => ErrorKind::Transient,
+            io::ErrorKind::InvalidData => ErrorKind::Corruption,
+            io::ErrorKind::InvalidInput => ErrorKind::InvalidRequest,
+            io::ErrorKind::Unsupported => ErrorKind::Unsupported,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_status_kinds() {
+        assert_eq!(ErrorKind::from_http_status(503), ErrorKind::Throttled);
+        assert_eq!(ErrorKind::from_http_status(500), ErrorKind::Transient);
+        assert_eq!(ErrorKind::from_http_status(403), ErrorKind::PermissionDenied);
+        assert_eq!(ErrorKind::from_http_status(412), ErrorKind::Conflict);
+        assert_eq!(ErrorKind::from_http_status(416), ErrorKind::InvalidRequest);
+        assert!(ErrorKind::from_http_status(429).is_retryable());
+        assert!(!ErrorKind::from_http_status(404).is_retryable());
+    }
+}
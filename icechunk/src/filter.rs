@@ -0,0 +1,450 @@
+//! Removing a node from the whole history of a branch.
+//!
+//! Sometimes an array must disappear from every version, because it holds sensitive
+//! data or it was broken beyond repair. [`filter_path`] rewrites the history of a
+//! branch without the node at a path, and the nodes below it, and points a new branch
+//! to the result. Like `git filter-repo`, the original branch is not modified: the new
+//! history shares the unchanged snapshots, manifests and chunks with it.
+//!
+//! Every snapshot from the first one with the node is written again, under a new id.
+//! Manifests with chunks of the removed nodes, and attribute tables, are written again
+//! without them. The chunk objects of the removed nodes are only deleted by
+//! [`crate::gc`], once the original branch and the tags that reach them are deleted.
+//! Every rewrite is recorded in the [`crate::audit`] log.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    audit::{append_record, AuditError, AuditEvent},
+    clock::Clock,
+    error::ErrorKind,
+    format::{
+        manifest::{ChunkPayload, ManifestRef},
+        snapshot::{ManifestFileInfo, NodeData, Snapshot},
+        ChunkId, ManifestId, NodeId, Path, SnapshotId,
+    },
+    refs::{fetch_branch_tip, update_branch, RefError},
+    repository::{resolve_user_attributes, write_attributes_table, RepositoryError},
+    Storage, StorageError,
+};
+
+/// What a call to [`filter_path`] wrote, as recorded in the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilteredHistory {
+    pub path: Path,
+    pub source_branch: String,
+    pub branch: String,
+    /// The snapshots written again, as pairs of original and new ids, oldest first
+    pub snapshots_rewritten: Vec<(SnapshotId, SnapshotId)>,
+    /// Manifests with chunks of the removed nodes, they are not used by the new history
+    pub manifests_replaced: Vec<ManifestId>,
+}
+
+impl FilteredHistory {
+    /// The tip of the new branch
+    pub fn tip(&self) -> Option<&SnapshotId> {
+        self.snapshots_rewritten.last().map(|(_, new)| new)
+    }
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum FilterError {
+    #[error("error contacting storage {0}")]
+    Storage(#[from] StorageError),
+    #[error("error updating refs {0}")]
+    Ref(#[from] RefError),
+    #[error("error rewriting user attributes {0}")]
+    Repository(#[from] RepositoryError),
+    #[error("cannot record the rewrite {0}")]
+    Audit(#[from] AuditError),
+    #[error("cannot hash manifest {0}")]
+    Hash(#[from] rmp_serde::encode::Error),
+    #[error("the root group cannot be removed")]
+    RootPath,
+    #[error("branch `{branch}` never had a node at `{path}`")]
+    PathNotFound { path: Path, branch: String },
+    #[error("the history of snapshot `{0}` is incomplete, it cannot be rewritten")]
+    IncompleteHistory(SnapshotId),
+}
+
+impl FilterError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            FilterError::Storage(err) => err.kind(),
+            FilterError::Ref(err) => err.kind(),
+            FilterError::Repository(err) => err.kind(),
+            FilterError::Audit(err) => err.kind(),
+            FilterError::Hash(_) => ErrorKind::Other,
+            FilterError::RootPath | FilterError::PathNotFound { .. } => {
+                ErrorKind::InvalidRequest
+            }
+            FilterError::IncompleteHistory(_) => ErrorKind::Unsupported,
+        }
+    }
+}
+
+pub type FilterResult<A> = Result<A, FilterError>;
+
+/// Write the history of `source_branch` without the node at `path`, and the nodes
+/// below it, to the new branch `branch`.
+///
+/// See the [module documentation](self). Fails if `branch` already exists.
+pub async fn filter_path(
+    storage: &dyn Storage,
+    clock: &dyn Clock,
+    source_branch: &str,
+    path: &Path,
+    branch: &str,
+) -> FilterResult<FilteredHistory> {
+    if path == &Path::root() {
+        return Err(FilterError::RootPath);
+    }
+    let tip = fetch_branch_tip(storage, source_branch).await?;
+    let tip_snapshot = storage.fetch_snapshot(&tip.snapshot).await?;
+    if tip_snapshot.short_term_history.len() < tip_snapshot.total_parents as usize {
+        return Err(FilterError::IncompleteHistory(tip.snapshot));
+    }
+    let mut history: Vec<SnapshotId> =
+        tip_snapshot.short_term_history.iter().map(|meta| meta.id.clone()).collect();
+    history.reverse();
+    history.push(tip.snapshot);
+
+    let mut rewriter = Rewriter { storage, path, manifests: HashMap::new() };
+    let mut parent: Option<Arc<Snapshot>> = None;
+    let mut snapshots_rewritten = Vec::new();
+    for id in history {
+        let original = storage.fetch_snapshot(&id).await?;
+        // snapshots before the first one with the node are shared with the original
+        if snapshots_rewritten.is_empty()
+            && !original.iter().any(|node| node.path.starts_with(path))
+        {
+            parent = Some(original);
+            continue;
+        }
+        let snapshot = Arc::new(rewriter.rewrite(&original, parent.as_deref()).await?);
+        let new_id = snapshot.metadata.id.clone();
+        storage.write_snapshot(new_id.clone(), Arc::clone(&snapshot)).await?;
+        snapshots_rewritten.push((id, new_id));
+        parent = Some(snapshot);
+    }
+
+    let Some((_, new_tip)) = snapshots_rewritten.last() else {
+        return Err(FilterError::PathNotFound {
+            path: path.clone(),
+            branch: source_branch.to_string(),
+        });
+    };
+    update_branch(storage, branch, new_tip.clone(), None, false).await?;
+
+    let mut manifests_replaced: Vec<_> = rewriter
+        .manifests
+        .into_iter()
+        .filter(|(_, rewrite)| !matches!(rewrite, ManifestRewrite::Unchanged))
+        .map(|((id, _), _)| id)
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    manifests_replaced.sort();
+    let filtered = FilteredHistory {
+        path: path.clone(),
+        source_branch: source_branch.to_string(),
+        branch: branch.to_string(),
+        snapshots_rewritten,
+        manifests_replaced,
+    };
+    append_record(storage, clock, AuditEvent::HistoryFiltered(filtered.clone())).await?;
+    Ok(filtered)
+}
+
+#[derive(Debug, Clone)]
+enum ManifestRewrite {
+    Unchanged,
+    Replaced {
+        file: ManifestFileInfo,
+        hash: String,
+        removed: Vec<ChunkId>,
+    },
+    /// All the chunks of the manifest belonged to removed nodes
+    Removed {
+        removed: Vec<ChunkId>,
+    },
+}
+
+impl ManifestRewrite {
+    fn removed_chunks(&self) -> &[ChunkId] {
+        match self {
+            ManifestRewrite::Unchanged => &[],
+            ManifestRewrite::Replaced { removed, .. }
+            | ManifestRewrite::Removed { removed } => removed,
+        }
+    }
+}
+
+struct Rewriter<'a> {
+    storage: &'a dyn Storage,
+    path: &'a Path,
+    // node ids are only unique within a snapshot, so rewrites depend on the removed ids
+    manifests: HashMap<(ManifestId, Vec<NodeId>), ManifestRewrite>,
+}
+
+impl Rewriter<'_> {
+    async fn rewrite_manifest(
+        &mut self,
+        id: &ManifestId,
+        removed: &[NodeId],
+    ) -> FilterResult<ManifestRewrite> {
+        if removed.is_empty() {
+            return Ok(ManifestRewrite::Unchanged);
+        }
+        let key = (id.clone(), removed.to_vec());
+        if let Some(rewrite) = self.manifests.get(&key) {
+            return Ok(rewrite.clone());
+        }
+        let mut manifest = Arc::unwrap_or_clone(self.storage.fetch_manifests(id).await?);
+        let payloads = manifest.remove_nodes(removed);
+        let removed_chunks: Vec<ChunkId> = payloads
+            .iter()
+            .filter_map(|payload| match payload {
+                ChunkPayload::Ref(chunk_ref) => Some(chunk_ref.id.clone()),
+                _ => None,
+            })
+            .collect();
+        let rewrite = if payloads.is_empty() {
+            ManifestRewrite::Unchanged
+        } else if manifest.is_empty() {
+            ManifestRewrite::Removed { removed: removed_chunks }
+        } else {
+            let file = ManifestFileInfo {
+                id: ManifestId::random(),
+                format_version: manifest.icechunk_manifest_format_version,
+            };
+            let hash = manifest.content_hash()?;
+            self.storage.write_manifests(file.id.clone(), Arc::new(manifest)).await?;
+            ManifestRewrite::Replaced { file, hash, removed: removed_chunks }
+        };
+        self.manifests.insert(key, rewrite.clone());
+        Ok(rewrite)
+    }
+
+    /// `original` without the removed nodes, as a child of `parent`
+    async fn rewrite(
+        &mut self,
+        original: &Snapshot,
+        parent: Option<&Snapshot>,
+    ) -> FilterResult<Snapshot> {
+        let mut snapshot = original.clone();
+        let mut removed: Vec<NodeId> =
+            snapshot.remove_subtree(self.path).iter().map(|node| node.id).collect();
+        removed.sort();
+
+        let mut rewrites = HashMap::new();
+        for file in original.manifest_files.iter() {
+            let rewrite = self.rewrite_manifest(&file.id, &removed).await?;
+            rewrites.insert(file.id.clone(), rewrite);
+        }
+        let removed_chunks: HashSet<&ChunkId> =
+            rewrites.values().flat_map(|rewrite| rewrite.removed_chunks()).collect();
+        snapshot.manifest_files = original
+            .manifest_files
+            .iter()
+            .filter_map(|file| match rewrites.get(&file.id) {
+                Some(ManifestRewrite::Replaced { file, .. }) => Some(file.clone()),
+                Some(ManifestRewrite::Removed { .. }) => None,
+                _ => Some(file.clone()),
+            })
+            .collect();
+        snapshot.packed_manifests.retain(|packed| {
+            snapshot.manifest_files.iter().any(|file| file.id == packed.id)
+        });
+
+        // attribute tables may have the attributes of the removed nodes, they are
+        // written again with the attributes of the remaining ones
+        let mut nodes: Vec<_> = snapshot.iter().cloned().collect();
+        for node in nodes.iter_mut() {
+            node.user_attributes =
+                resolve_user_attributes(self.storage, node.user_attributes.take())
+                    .await?;
+            if let NodeData::Array(_, refs) = &mut node.node_data {
+                refs.retain_mut(|manifest| replace_ref(manifest, &rewrites));
+            }
+        }
+        let attributes =
+            write_attributes_table(self.storage, original, &mut nodes).await?;
+        snapshot.replace_nodes(nodes);
+        snapshot.attribute_files = attributes.into_iter().collect();
+
+        let (history, total_parents) = match parent {
+            Some(parent) => {
+                let mut history = parent.short_term_history.clone();
+                history.push_front(parent.metadata.clone());
+                (history, parent.total_parents + 1)
+            }
+            None => (VecDeque::new(), 0),
+        };
+        snapshot.short_term_parents = history.len() as u16;
+        snapshot.short_term_history = history;
+        snapshot.total_parents = total_parents;
+
+        snapshot.metadata.id = SnapshotId::random();
+        if let Some(regions) = snapshot.metadata.write_regions.as_mut() {
+            regions.retain(|path, _| !path.starts_with(self.path));
+        }
+        if let Some(summary) = snapshot.metadata.summary.as_mut() {
+            summary.arrays_added.retain(|path| !path.starts_with(self.path));
+            summary.arrays_deleted.retain(|path| !path.starts_with(self.path));
+        }
+        if let Some(objects) = snapshot.new_objects.as_mut() {
+            objects.manifests = objects
+                .manifests
+                .iter()
+                .filter_map(|id| match rewrites.get(id) {
+                    Some(ManifestRewrite::Replaced { file, .. }) => Some(file.id.clone()),
+                    Some(ManifestRewrite::Removed { .. }) => None,
+                    _ => Some(id.clone()),
+                })
+                .collect();
+            objects.chunks.retain(|id| !removed_chunks.contains(&id));
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Point `manifest` to its rewritten version, returns false if it was removed
+fn replace_ref(
+    manifest: &mut ManifestRef,
+    rewrites: &HashMap<ManifestId, ManifestRewrite>,
+) -> bool {
+    match rewrites.get(&manifest.object_id) {
+        Some(ManifestRewrite::Replaced { file, hash, .. }) => {
+            manifest.object_id = file.id.clone();
+            manifest.content_hash = Some(hash.clone());
+            true
+        }
+        Some(ManifestRewrite::Removed { .. }) => false,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use chrono::DateTime;
+    use futures::TryStreamExt;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        audit::audit_log,
+        clock::{Clock, FixedClock},
+        format::{snapshot::UserAttributesSnapshot, ChunkIndices},
+        metadata::{FillValue, UserAttributes},
+        repository::RepositoryError,
+        synthetic::vector_metadata,
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_filter_path() -> Result<(), Box<dyn Error>> {
+        let clock = FixedClock::new(DateTime::from_timestamp(1_700_000_000, 0).unwrap());
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        let initial = repo.snapshot_id().clone();
+        let keep = Path::try_from("/keep")?;
+        let group = Path::try_from("/data")?;
+        let secret = Path::try_from("/data/secret")?;
+        let chunk = |data: &'static str| Some(ChunkPayload::Inline(data.into()));
+        let atts = |json: &str| Some(UserAttributes::try_new(json.as_bytes()).unwrap());
+
+        repo.add_group(Path::root()).await?;
+        repo.add_group(group.clone()).await?;
+        repo.add_array(keep.clone(), vector_metadata(2, 1, FillValue::Int32(0))).await?;
+        repo.add_array(secret.clone(), vector_metadata(2, 1, FillValue::Int32(0)))
+            .await?;
+        repo.set_user_attributes(keep.clone(), atts(r#"{"public":true}"#)).await?;
+        repo.set_user_attributes(secret.clone(), atts(r#"{"pii":true}"#)).await?;
+        repo.set_chunk_ref(keep.clone(), ChunkIndices(vec![0]), chunk("kept")).await?;
+        repo.set_chunk_ref(secret.clone(), ChunkIndices(vec![0]), chunk("secret"))
+            .await?;
+        repo.commit("main", "first", None).await?;
+        repo.set_chunk_ref(keep.clone(), ChunkIndices(vec![1]), chunk("more")).await?;
+        let tip = repo.commit("main", "second", None).await?;
+
+        let filtered =
+            filter_path(storage.as_ref(), &clock, "main", &secret, "clean").await?;
+        assert_eq!(filtered.snapshots_rewritten.len(), 2);
+        assert_eq!(filtered.manifests_replaced.len(), 2);
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, tip);
+
+        let clean =
+            Repository::from_branch_tip(Arc::clone(&storage), "clean").await?.build();
+        assert_eq!(Some(clean.snapshot_id()), filtered.tip());
+        assert!(matches!(
+            clean.get_node(&secret).await,
+            Err(RepositoryError::NodeNotFound { .. })
+        ));
+        assert!(clean.get_group(&group).await.is_ok());
+        assert_eq!(
+            clean.get_chunk_ref(&keep, &ChunkIndices(vec![1])).await?,
+            chunk("more")
+        );
+        assert_eq!(
+            clean.get_node(&keep).await?.user_attributes,
+            atts(r#"{"public":true}"#).map(UserAttributesSnapshot::Inline)
+        );
+        let ancestry: Vec<_> = clean.ancestry().await?.try_collect().await?;
+        assert_eq!(
+            ancestry.iter().map(|meta| meta.message.as_str()).collect::<Vec<_>>(),
+            vec!["second", "first", Snapshot::INITIAL_COMMIT_MESSAGE]
+        );
+        // the history before the array existed is shared
+        assert_eq!(ancestry[2].id, initial);
+
+        for (_, id) in filtered.snapshots_rewritten.iter() {
+            let snapshot = storage.fetch_snapshot(id).await?;
+            assert!(snapshot.get_node(&secret).is_err());
+            for file in snapshot.manifest_files.iter() {
+                let manifest = storage.fetch_manifests(&file.id).await?;
+                assert!(!manifest
+                    .chunks()
+                    .values()
+                    .any(|p| p == &chunk("secret").unwrap()));
+            }
+            let original = storage.fetch_snapshot(&tip).await?;
+            assert!(snapshot
+                .attribute_files
+                .iter()
+                .all(|file| original.attribute_files.iter().all(|o| o.id != file.id)));
+        }
+
+        let log = audit_log(storage.as_ref()).await?;
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].event, AuditEvent::HistoryFiltered(filtered));
+        assert_eq!(log[0].written_at, clock.now());
+
+        assert!(matches!(
+            filter_path(storage.as_ref(), &clock, "main", &secret, "clean").await,
+            Err(FilterError::Ref(_))
+        ));
+        assert!(matches!(
+            filter_path(
+                storage.as_ref(),
+                &clock,
+                "main",
+                &Path::try_from("/missing")?,
+                "other"
+            )
+            .await,
+            Err(FilterError::PathNotFound { .. })
+        ));
+        Ok(())
+    }
+}
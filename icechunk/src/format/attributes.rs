@@ -1,2 +1,121 @@
-#[derive(Debug, PartialEq)]
-pub struct AttributesTable {}
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::metadata::UserAttributes;
+
+use super::{
+    format_constants, snapshot::UserAttributesRef, AttributesId, IcechunkFormatVersion,
+    TableOffset,
+};
+
+/// The user attributes of a group of nodes, stored outside of the snapshot
+///
+/// Tables are content addressed: their id is a hash of the attributes they hold, so
+/// the same attributes always produce the same table and are never written twice.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AttributesTable {
+    pub icechunk_attributes_format_version: IcechunkFormatVersion,
+    attributes: Vec<UserAttributes>,
+}
+
+impl AttributesTable {
+    pub fn get(&self, location: TableOffset) -> Option<&UserAttributes> {
+        usize::try_from(location).ok().and_then(|location| self.attributes.get(location))
+    }
+
+    pub fn len(&self) -> usize {
+        self.attributes.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The id of the table, a hash of its attributes
+    pub fn id(&self) -> AttributesId {
+        let mut hasher = Sha256::new();
+        for atts in self.attributes.iter() {
+            let bytes = atts.to_bytes();
+            hasher.update((bytes.len() as u64).to_le_bytes());
+            hasher.update(&bytes);
+        }
+        let digest = hasher.finalize();
+        let mut id = [0; 12];
+        id.copy_from_slice(&digest[..12]);
+        AttributesId::new(id)
+    }
+}
+
+/// Collects attributes into an [`AttributesTable`], each distinct value is stored once
+#[derive(Debug, Default)]
+pub struct AttributesTableBuilder {
+    attributes: Vec<UserAttributes>,
+    locations: HashMap<Vec<u8>, TableOffset>,
+}
+
+impl AttributesTableBuilder {
+    /// Add `atts` to the table and return their location in it
+    pub fn add(&mut self, atts: UserAttributes) -> TableOffset {
+        let key = atts.to_bytes().to_vec();
+        *self.locations.entry(key).or_insert_with(|| {
+            self.attributes.push(atts);
+            (self.attributes.len() - 1) as TableOffset
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.attributes.is_empty()
+    }
+
+    /// The table, and a function to build references to the locations returned by
+    /// [`AttributesTableBuilder::add`]
+    pub fn build(self) -> (AttributesTable, impl Fn(TableOffset) -> UserAttributesRef) {
+        let table = AttributesTable {
+            icechunk_attributes_format_version:
+                format_constants::LATEST_ICECHUNK_ATTRIBUTES_FORMAT,
+            attributes: self.attributes,
+        };
+        let object_id = table.id();
+        let to_ref =
+            move |location| UserAttributesRef { object_id: object_id.clone(), location };
+        (table, to_ref)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    fn atts(json: &str) -> UserAttributes {
+        UserAttributes::try_new(json.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_attributes_table_dedup() {
+        let mut builder = AttributesTableBuilder::default();
+        assert_eq!(builder.add(atts(r#"{"a": 1}"#)), 0);
+        assert_eq!(builder.add(atts(r#"{"b": 2}"#)), 1);
+        assert_eq!(builder.add(atts(r#"{"a": 1}"#)), 0);
+        let (table, to_ref) = builder.build();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.get(1), Some(&atts(r#"{"b": 2}"#)));
+        assert_eq!(table.get(2), None);
+        assert_eq!(to_ref(1).object_id, table.id());
+
+        // the id depends only on the contents
+        let mut builder = AttributesTableBuilder::default();
+        builder.add(atts(r#"{"a": 1}"#));
+        builder.add(atts(r#"{"b": 2}"#));
+        assert_eq!(builder.build().0.id(), table.id());
+        let mut builder = AttributesTableBuilder::default();
+        builder.add(atts(r#"{"b": 2}"#));
+        builder.add(atts(r#"{"a": 1}"#));
+        assert_ne!(builder.build().0.id(), table.id());
+    }
+}
@@ -1,23 +1,176 @@
 use futures::{pin_mut, Stream, TryStreamExt};
 use itertools::Itertools;
-use std::{collections::BTreeMap, ops::Bound, sync::Arc};
+use std::{
+    cmp,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    fmt,
+    hash::{BuildHasher, BuildHasherDefault},
+    iter,
+    ops::{Bound, Range},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+};
 use thiserror::Error;
 
 use bytes::Bytes;
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{DeserializeOwned, MapAccess, Visitor},
+    ser::{Error as _, SerializeStruct},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{error::ErrorKind, StorageError};
 
 use super::{
-    format_constants, ChunkId, ChunkIndices, ChunkLength, ChunkOffset,
-    IcechunkFormatError, IcechunkFormatVersion, IcechunkResult, ManifestId, NodeId,
+    format_constants, snapshot::ChunkRegion, ChunkId, ChunkIndices, ChunkLength,
+    ChunkOffset, IcechunkFormatError, IcechunkFormatVersion, IcechunkResult, ManifestId,
+    NodeId,
 };
 
+/// The lowest and highest chunk indices of a node in a manifest, both inclusive.
+///
+/// Empty extents are unknown, the manifest may contain any chunk of the node.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ManifestExtents(pub Vec<ChunkIndices>);
 
+impl ManifestExtents {
+    pub fn contains(&self, coord: &ChunkIndices) -> bool {
+        match self.0.as_slice() {
+            [from, to] => {
+                from.0.len() == coord.0.len()
+                    && to.0.len() == coord.0.len()
+                    && coord
+                        .0
+                        .iter()
+                        .zip(from.0.iter().zip(to.0.iter()))
+                        .all(|(index, (from, to))| from <= index && index <= to)
+            }
+            _ => true,
+        }
+    }
+
+    /// Returns true if the manifest may have chunks inside `region`
+    pub fn overlaps(&self, region: &ChunkRegion) -> bool {
+        match self.0.as_slice() {
+            [from, to]
+                if from.0.len() == region.0.len() && to.0.len() == region.0.len() =>
+            {
+                region
+                    .0
+                    .iter()
+                    .zip(from.0.iter().zip(to.0.iter()))
+                    .all(|(range, (from, to))| range.start <= *to && *from < range.end)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// How the chunk references of a snapshot are split into manifests on flush.
+///
+/// Manifests are filled in node order, and in the [`CoordinateOrder`] of the policy for
+/// the chunks of each node. A new one is started when adding a chunk reference would go
+/// over any of the limits. The default policy writes a single manifest.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ManifestSplitPolicy {
+    /// Maximum number of chunk references in a manifest
+    pub max_rows: Option<u64>,
+    /// Maximum size of a manifest, as estimated by [`Manifest::estimated_size_bytes`]
+    pub max_bytes: Option<u64>,
+    /// Sort the chunks of each array by their index along this axis before splitting, so
+    /// manifests hold slabs of the array. Arrays with fewer dimensions keep their order.
+    pub split_axis: Option<usize>,
+    /// The order of the chunks of each array, in manifests and when splitting them.
+    /// `split_axis` takes precedence, this orders the chunks with the same index.
+    #[serde(default)]
+    pub coordinate_order: CoordinateOrder,
+}
+
+/// The order of the chunk references of each array in manifests.
+///
+/// Chunks that are close in this order end up in the same manifest, next to each other.
+/// [`CoordinateOrder::Morton`] keeps together chunks that are close along every
+/// dimension, which helps queries over ranges of several dimensions, like regions of
+/// geospatial arrays, read fewer manifests and compress them better.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CoordinateOrder {
+    /// Lexicographic order of the indices, the last dimension varies the fastest
+    #[default]
+    RowMajor,
+    /// Z-order, the order of the indices with their bits interleaved
+    Morton,
+}
+
+impl CoordinateOrder {
+    // the manifest format flag recording the order, manifests without it are row-major
+    const FLAG: &'static str = "coordinate_order";
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CoordinateOrder::RowMajor => "row-major",
+            CoordinateOrder::Morton => "morton",
+        }
+    }
+
+    pub fn compare(&self, a: &ChunkIndices, b: &ChunkIndices) -> cmp::Ordering {
+        match self {
+            CoordinateOrder::RowMajor => a.cmp(b),
+            CoordinateOrder::Morton => morton_cmp(&a.0, &b.0),
+        }
+    }
+}
+
+impl fmt::Display for CoordinateOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for CoordinateOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "row-major" => Ok(CoordinateOrder::RowMajor),
+            "morton" => Ok(CoordinateOrder::Morton),
+            other => Err(format!(
+                "unknown coordinate order `{other}`, expected `row-major` or `morton`"
+            )),
+        }
+    }
+}
+
+/// Compares the interleaved bits of the indices without computing them: the dimension
+/// with the most significant differing bit decides, earlier dimensions win ties.
+fn morton_cmp(a: &[u64], b: &[u64]) -> cmp::Ordering {
+    if a.len() != b.len() {
+        return a.cmp(b);
+    }
+    let mut decisive = 0;
+    let mut highest_diff = 0u64;
+    for (dim, (x, y)) in a.iter().zip(b).enumerate() {
+        let diff = x ^ y;
+        // true if the highest bit of diff is above the highest bit of highest_diff
+        if highest_diff < diff && highest_diff < (highest_diff ^ diff) {
+            decisive = dim;
+            highest_diff = diff;
+        }
+    }
+    a.get(decisive).cmp(&b.get(decisive))
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ManifestRef {
     pub object_id: ManifestId,
     pub extents: ManifestExtents,
+    /// See [`Manifest::content_hash`], refs written before it existed have none
+    #[serde(default)]
+    pub content_hash: Option<String>,
 }
 
 #[derive(Debug, Error)]
@@ -35,6 +188,24 @@ pub enum VirtualReferenceError {
     FetchError(Box<dyn std::error::Error + Send + Sync>),
     #[error("error parsing virtual reference {0}")]
     OtherError(#[from] Box<dyn std::error::Error + Send + Sync>),
+    #[error(
+        "virtual reference past the end of `{location}`, {offset} + {length} > {size}"
+    )]
+    OutOfBounds { location: String, offset: u64, length: u64, size: u64 },
+}
+
+impl VirtualReferenceError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            VirtualReferenceError::FetchError(err) => err
+                .downcast_ref::<StorageError>()
+                .map(StorageError::kind)
+                .unwrap_or(ErrorKind::Other),
+            VirtualReferenceError::UnsupportedScheme(_) => ErrorKind::Unsupported,
+            VirtualReferenceError::OtherError(_) => ErrorKind::Other,
+            _ => ErrorKind::InvalidRequest,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
@@ -95,18 +266,520 @@ pub enum ChunkPayload {
     Ref(ChunkRef),
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct ChunkInfo {
     pub node: NodeId,
     pub coord: ChunkIndices,
     pub payload: ChunkPayload,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize, Default)]
+/// A summary of the values in a chunk, used to skip chunks that cannot match a query.
+///
+/// Values are converted to `f64`, so the bounds of large 64 bit integers are approximate.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct ChunkStatistics {
+    /// The smallest value that is not NaN, `None` if there are no such values
+    pub min: Option<f64>,
+    /// The largest value that is not NaN, `None` if there are no such values
+    pub max: Option<f64>,
+    pub nan_count: u64,
+}
+
+impl ChunkStatistics {
+    pub fn from_values(values: impl IntoIterator<Item = f64>) -> Self {
+        values.into_iter().fold(Self::default(), |mut stats, value| {
+            if value.is_nan() {
+                stats.nan_count += 1;
+            } else {
+                stats.min = Some(stats.min.map_or(value, |min| min.min(value)));
+                stats.max = Some(stats.max.map_or(value, |max| max.max(value)));
+            }
+            stats
+        })
+    }
+}
+
+/// A condition on the values of a chunk
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[non_exhaustive]
+pub enum ChunkPredicate {
+    /// Some value is smaller than the threshold
+    LessThan(f64),
+    /// Some value is larger than the threshold
+    GreaterThan(f64),
+    /// Some value is in the range, both ends inclusive
+    Between(f64, f64),
+    /// Some value is NaN
+    HasNan,
+}
+
+impl ChunkPredicate {
+    /// False only if no value summarized by `stats` can satisfy the predicate
+    pub fn may_match(&self, stats: &ChunkStatistics) -> bool {
+        match (self, stats.min, stats.max) {
+            (ChunkPredicate::HasNan, _, _) => stats.nan_count > 0,
+            (_, None, _) | (_, _, None) => false,
+            (ChunkPredicate::LessThan(threshold), Some(min), _) => min < *threshold,
+            (ChunkPredicate::GreaterThan(threshold), _, Some(max)) => max > *threshold,
+            (ChunkPredicate::Between(from, to), Some(min), Some(max)) => {
+                min <= *to && max >= *from
+            }
+        }
+    }
+}
+
+/// A type that can be stored in the [`ChunkExtra`] of chunk references.
+///
+/// Each schema is stored under its own key, extensions should prefix their keys with
+/// their name to avoid collisions. The version is stored with the value, so the schema
+/// can evolve while still reading what older versions wrote.
+pub trait ChunkExtraSchema: Serialize + DeserializeOwned {
+    const KEY: &'static str;
+    const VERSION: u32;
+
+    /// Read a value written with another version of the schema.
+    ///
+    /// By default values from other versions are not supported.
+    fn from_version(version: u32, value: rmpv::Value) -> Result<Self, ChunkExtraError> {
+        let _ = value;
+        Err(ChunkExtraError::UnsupportedVersion { key: Self::KEY.to_string(), version })
+    }
+}
+
+impl ChunkExtraSchema for ChunkStatistics {
+    const KEY: &'static str = "icechunk.statistics";
+    const VERSION: u32 = 1;
+}
+
+/// The sha256 of the bytes of a chunk, in hex
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkChecksum(pub String);
+
+impl ChunkChecksum {
+    pub fn of(data: &[u8]) -> Self {
+        Self(sha256_hex(data))
+    }
+}
+
+impl ChunkExtraSchema for ChunkChecksum {
+    const KEY: &'static str = "icechunk.checksum";
+    const VERSION: u32 = 1;
+}
+
+/// The etag of the file of a virtual chunk, when the reference was created
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VirtualChunkEtag(pub String);
+
+impl ChunkExtraSchema for VirtualChunkEtag {
+    const KEY: &'static str = "icechunk.etag";
+    const VERSION: u32 = 1;
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ChunkExtraError {
+    #[error("invalid chunk extra data for `{key}`: {source}")]
+    Invalid { key: String, source: rmpv::ext::Error },
+    #[error("unsupported version {version} of chunk extra data `{key}`")]
+    UnsupportedVersion { key: String, version: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VersionedExtra {
+    pub version: u32,
+    pub value: rmpv::Value,
+}
+
+/// Optional data stored with a chunk reference in a manifest, keyed by schema
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct ChunkExtra(BTreeMap<String, VersionedExtra>);
+
+impl ChunkExtra {
+    /// The value stored for the schema `T`, if any
+    pub fn get<T: ChunkExtraSchema>(&self) -> Result<Option<T>, ChunkExtraError> {
+        let Some(extra) = self.0.get(T::KEY) else { return Ok(None) };
+        if extra.version == T::VERSION {
+            rmpv::ext::from_value(extra.value.clone()).map(Some).map_err(|source| {
+                ChunkExtraError::Invalid { key: T::KEY.to_string(), source }
+            })
+        } else {
+            T::from_version(extra.version, extra.value.clone()).map(Some)
+        }
+    }
+
+    /// Store `value` with the current version of its schema, replacing any previous value
+    pub fn set<T: ChunkExtraSchema>(&mut self, value: &T) -> Result<(), ChunkExtraError> {
+        let value = rmpv::ext::to_value(value).map_err(|source| {
+            ChunkExtraError::Invalid { key: T::KEY.to_string(), source }
+        })?;
+        self.0.insert(T::KEY.to_string(), VersionedExtra { version: T::VERSION, value });
+        Ok(())
+    }
+
+    pub fn remove<T: ChunkExtraSchema>(&mut self) {
+        self.0.remove(T::KEY);
+    }
+
+    /// The raw values, including those of schemas unknown to this program
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &VersionedExtra)> {
+        self.0.iter().map(|(key, extra)| (key.as_str(), extra))
+    }
+
+    /// Add the values of `other`, replacing those with the same key
+    pub fn merge(&mut self, other: ChunkExtra) {
+        self.0.extend(other.0);
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
 pub struct Manifest {
     pub icechunk_manifest_format_version: IcechunkFormatVersion,
     pub icechunk_manifest_format_flags: BTreeMap<String, rmpv::Value>,
-    chunks: BTreeMap<(NodeId, ChunkIndices), ChunkPayload>,
+    chunks: ChunkTable,
+    /// Extra data for some of the chunks, manifests written before it existed have none
+    #[serde(default)]
+    extra: BTreeMap<(NodeId, ChunkIndices), ChunkExtra>,
+    #[serde(skip)]
+    lookup_index: LookupIndex,
+}
+
+// the manifest format flag with the tombstones of redacted chunks
+const REDACTED_FLAG: &str = "redacted_chunks";
+
+// the lookup indexes are derived from the chunks, they don't change the contents
+impl PartialEq for Manifest {
+    fn eq(&self, other: &Self) -> bool {
+        let Self {
+            icechunk_manifest_format_version,
+            icechunk_manifest_format_flags,
+            chunks,
+            extra,
+            lookup_index: _,
+        } = self;
+        *icechunk_manifest_format_version == other.icechunk_manifest_format_version
+            && *icechunk_manifest_format_flags == other.icechunk_manifest_format_flags
+            && *chunks == other.chunks
+            && *extra == other.extra
+    }
+}
+
+/// The chunk references of a [`Manifest`], sorted by node and coordinates.
+///
+/// A sorted vector instead of a map, so the lookup indexes can point into it.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ChunkTable(Vec<((NodeId, ChunkIndices), ChunkPayload)>);
+
+impl ChunkTable {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(
+        &self,
+    ) -> impl DoubleEndedIterator<Item = (&(NodeId, ChunkIndices), &ChunkPayload)> {
+        self.0.iter().map(|(key, payload)| (key, payload))
+    }
+
+    pub fn keys(&self) -> impl DoubleEndedIterator<Item = &(NodeId, ChunkIndices)> {
+        self.0.iter().map(|(key, _)| key)
+    }
+
+    pub fn values(&self) -> impl DoubleEndedIterator<Item = &ChunkPayload> {
+        self.0.iter().map(|(_, payload)| payload)
+    }
+
+    pub fn get(&self, (node, coord): &(NodeId, ChunkIndices)) -> Option<&ChunkPayload> {
+        let position = self.position(*node, coord).ok()?;
+        self.0.get(position).map(|(_, payload)| payload)
+    }
+
+    pub fn contains_key(&self, (node, coord): &(NodeId, ChunkIndices)) -> bool {
+        self.position(*node, coord).is_ok()
+    }
+
+    /// The position of the chunk, or where it would be inserted
+    fn position(&self, node: NodeId, coord: &ChunkIndices) -> Result<usize, usize> {
+        self.0.binary_search_by(|((n, c), _)| n.cmp(&node).then_with(|| c.cmp(coord)))
+    }
+
+    /// The positions of the chunks of `node`
+    fn node_range(&self, node: NodeId) -> Range<usize> {
+        let start = self.0.partition_point(|((n, _), _)| *n < node);
+        let end = self.0.partition_point(|((n, _), _)| *n <= node);
+        start..end
+    }
+
+    fn remove(&mut self, (node, coord): &(NodeId, ChunkIndices)) -> Option<ChunkPayload> {
+        let position = self.position(*node, coord).ok()?;
+        Some(self.0.remove(position).1)
+    }
+}
+
+impl FromIterator<((NodeId, ChunkIndices), ChunkPayload)> for ChunkTable {
+    // like collecting into a map, the last payload of each key is kept
+    fn from_iter<T: IntoIterator<Item = ((NodeId, ChunkIndices), ChunkPayload)>>(
+        iter: T,
+    ) -> Self {
+        let mut entries: Vec<_> = iter.into_iter().collect();
+        // reversed so the stable sort puts the last payload of each key first
+        entries.reverse();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+        entries.dedup_by(|(a, _), (b, _)| a == b);
+        Self(entries)
+    }
+}
+
+impl IntoIterator for ChunkTable {
+    type Item = ((NodeId, ChunkIndices), ChunkPayload);
+    type IntoIter = std::vec::IntoIter<Self::Item>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'de> Deserialize<'de> for ChunkTable {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ChunkTableVisitor;
+
+        impl<'de> Visitor<'de> for ChunkTableVisitor {
+            type Value = ChunkTable;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a map of chunk references")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(
+                self,
+                mut map: A,
+            ) -> Result<Self::Value, A::Error> {
+                // the length comes from the input, it may not be trustworthy
+                let mut entries =
+                    Vec::with_capacity(map.size_hint().unwrap_or(0).min(1 << 16));
+                while let Some(entry) = map.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(entries.into_iter().collect())
+            }
+        }
+
+        deserializer.deserialize_map(ChunkTableVisitor)
+    }
+}
+
+/// Nodes get a hash index after this many lookups of their chunks
+const INDEX_AFTER_LOOKUPS: u32 = 8;
+
+/// Upper bound of the memory a node's hash index takes for each of its chunks,
+/// counting the spare capacity of the table
+const INDEX_ENTRY_SIZE: u64 = 3 * size_of::<(u64, usize)>() as u64;
+
+// stands for the coordinates that share their hash with others in a node index
+const AMBIGUOUS_HASH: usize = usize::MAX;
+
+#[derive(Debug)]
+enum NodeLookups {
+    Counting(AtomicU32),
+    Indexed(Arc<NodeIndex>),
+}
+
+/// The hash of each coordinate of a node, to the position of its chunk in the
+/// [`ChunkTable`]
+#[derive(Debug)]
+struct NodeIndex {
+    positions: HashMap<u64, usize>,
+}
+
+impl NodeIndex {
+    fn build(chunks: &ChunkTable, node: NodeId) -> Self {
+        let range = chunks.node_range(node);
+        let mut positions = HashMap::with_capacity(range.len());
+        for position in range {
+            let ((_, coord), _) = &chunks.0[position];
+            positions
+                .entry(coord_hash(coord))
+                .and_modify(|found| *found = AMBIGUOUS_HASH)
+                .or_insert(position);
+        }
+        Self { positions }
+    }
+
+    fn position(
+        &self,
+        chunks: &ChunkTable,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> Option<usize> {
+        match self.positions.get(&coord_hash(coord)) {
+            Some(&AMBIGUOUS_HASH) => chunks.position(node, coord).ok(),
+            // another coordinate may have the same hash
+            Some(&position) => chunks
+                .0
+                .get(position)
+                .filter(|((n, c), _)| *n == node && c == coord)
+                .map(|_| position),
+            None => None,
+        }
+    }
+}
+
+fn coord_hash(coord: &ChunkIndices) -> u64 {
+    BuildHasherDefault::<DefaultHasher>::default().hash_one(coord)
+}
+
+/// Hash indexes for the nodes whose chunks are looked up repeatedly, built lazily
+#[derive(Debug, Default)]
+struct LookupIndex(RwLock<HashMap<NodeId, NodeLookups>>);
+
+impl Clone for LookupIndex {
+    // clones start without an index, it's rebuilt on demand
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+// written by hand so the chunk references follow the coordinate order of the manifest,
+// in memory they are always in row-major order
+impl Serialize for Manifest {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        for len in [self.chunks.len(), self.extra.len()] {
+            map_length(len).map_err(S::Error::custom)?;
+        }
+        let mut manifest = serializer.serialize_struct("Manifest", 4)?;
+        manifest.serialize_field(
+            "icechunk_manifest_format_version",
+            &self.icechunk_manifest_format_version,
+        )?;
+        manifest.serialize_field(
+            "icechunk_manifest_format_flags",
+            &self.icechunk_manifest_format_flags,
+        )?;
+        manifest.serialize_field("chunks", &OrderedChunks(self.ordered_chunks()))?;
+        manifest.serialize_field("extra", &self.extra)?;
+        manifest.end()
+    }
+}
+
+/// A piece of the serialized [`Manifest`], see [`Manifest::serialized_parts`]
+enum ManifestPiece<'a> {
+    /// The struct header and the fields before the chunks
+    Header(&'a Manifest),
+    MapLength(usize),
+    Chunk(&'a (NodeId, ChunkIndices), &'a ChunkPayload),
+    Extra(&'a (NodeId, ChunkIndices), &'a ChunkExtra),
+}
+
+impl ManifestPiece<'_> {
+    fn write(&self, out: &mut Vec<u8>) -> Result<(), rmp_serde::encode::Error> {
+        match self {
+            ManifestPiece::Header(manifest) => {
+                // structs are serialized as arrays of their 4 fields
+                out.push(0x94);
+                rmp_serde::encode::write(
+                    out,
+                    &manifest.icechunk_manifest_format_version,
+                )?;
+                rmp_serde::encode::write(out, &manifest.icechunk_manifest_format_flags)
+            }
+            ManifestPiece::MapLength(len) => {
+                let len = map_length(*len).map_err(rmp_serde::encode::Error::custom)?;
+                write_map_length(out, len);
+                Ok(())
+            }
+            ManifestPiece::Chunk(key, payload) => {
+                rmp_serde::encode::write(out, key)?;
+                rmp_serde::encode::write(out, payload)
+            }
+            ManifestPiece::Extra(key, extra) => {
+                rmp_serde::encode::write(out, key)?;
+                rmp_serde::encode::write(out, extra)
+            }
+        }
+    }
+}
+
+/// The msgpack header of a map with `len` entries
+fn write_map_length(out: &mut Vec<u8>, len: u32) {
+    if len < 16 {
+        out.push(0x80 | len as u8);
+    } else if let Ok(len) = u16::try_from(len) {
+        out.push(0xde);
+        out.extend_from_slice(&len.to_be_bytes());
+    } else {
+        out.push(0xdf);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+/// Msgpack maps hold at most `u32::MAX` entries, larger ones can't be serialized
+fn map_length(len: usize) -> IcechunkResult<u32> {
+    u32::try_from(len).map_err(|_| IcechunkFormatError::TooManyChunkReferences {
+        found: len as u64,
+        max: u32::MAX.into(),
+    })
+}
+
+/// Read the msgpack header of an array, or of a map if `map`, returning its length
+fn read_length(input: &mut &[u8], map: bool) -> Option<u32> {
+    let (fix, wide, wider) = if map { (0x80, 0xde, 0xdf) } else { (0x90, 0xdc, 0xdd) };
+    let (&marker, rest) = input.split_first()?;
+    let (len, rest) = match marker {
+        marker if marker & 0xf0 == fix => (u32::from(marker & 0x0f), rest),
+        marker if marker == wide => {
+            let len: [u8; 2] = rest.get(..2)?.try_into().ok()?;
+            (u32::from(u16::from_be_bytes(len)), &rest[2..])
+        }
+        marker if marker == wider => {
+            let len: [u8; 4] = rest.get(..4)?.try_into().ok()?;
+            (u32::from_be_bytes(len), &rest[4..])
+        }
+        _ => return None,
+    };
+    *input = rest;
+    Some(len)
+}
+
+/// The bytes of the next msgpack value of `input`, `None` if they are not valid msgpack
+fn next_value<'a>(input: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let start = *input;
+    rmpv::decode::read_value(input).ok()?;
+    Some(&start[..start.len() - input.len()])
+}
+
+/// A manifest read by [`Manifest::salvage`]
+#[derive(Debug, PartialEq)]
+pub struct SalvagedManifest {
+    /// The chunk references that could be read
+    pub manifest: Manifest,
+    /// The chunks whose references couldn't be read
+    pub lost: Vec<(NodeId, ChunkIndices)>,
+    /// Chunk references lost without knowing their chunks, the manifest is truncated or
+    /// its bytes are not msgpack from some point on
+    pub unidentified: u64,
+}
+
+type ChunkEntry<'a> = (&'a (NodeId, ChunkIndices), &'a ChunkPayload);
+
+struct OrderedChunks<'a>(Vec<ChunkEntry<'a>>);
+
+impl Serialize for OrderedChunks<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().copied())
+    }
 }
 
 impl Manifest {
@@ -114,23 +787,76 @@ impl Manifest {
         &self,
         node: NodeId,
         coord: ChunkIndices,
-    ) -> IcechunkResult<&ChunkPayload> {
-        self.chunks.get(&(node, coord)).ok_or_else(|| {
-            // FIXME: error
-            IcechunkFormatError::ChunkCoordinatesNotFound { coords: ChunkIndices(vec![]) }
-        })
+    ) -> IcechunkResult<ChunkPayload> {
+        let position = match self.node_index(node) {
+            Some(index) => index.position(&self.chunks, node, &coord),
+            None => self.chunks.position(node, &coord).ok(),
+        };
+        match position.and_then(|position| self.chunks.0.get(position)) {
+            Some((_, payload)) => Ok(payload.clone()),
+            None => Err(IcechunkFormatError::ChunkCoordinatesNotFound { coords: coord }),
+        }
     }
 
-    pub fn iter(
-        self: Arc<Self>,
-        node: &NodeId,
-    ) -> impl Iterator<Item = (ChunkIndices, ChunkPayload)> {
-        PayloadIterator { manifest: self, for_node: *node, last_key: None }
+    /// Returns the hash index for the node, if it's been looked up enough times to have one
+    fn node_index(&self, node: NodeId) -> Option<Arc<NodeIndex>> {
+        let lookups = {
+            let nodes =
+                self.lookup_index.0.read().unwrap_or_else(PoisonError::into_inner);
+            match nodes.get(&node) {
+                Some(NodeLookups::Indexed(index)) => return Some(Arc::clone(index)),
+                Some(NodeLookups::Counting(count)) => {
+                    count.fetch_add(1, Ordering::Relaxed) + 1
+                }
+                None => 0,
+            }
+        };
+        if lookups > 0 && lookups < INDEX_AFTER_LOOKUPS {
+            return None;
+        }
+
+        let mut nodes =
+            self.lookup_index.0.write().unwrap_or_else(PoisonError::into_inner);
+        match nodes.get(&node) {
+            Some(NodeLookups::Indexed(index)) => Some(Arc::clone(index)),
+            Some(NodeLookups::Counting(count))
+                if count.load(Ordering::Relaxed) >= INDEX_AFTER_LOOKUPS =>
+            {
+                let index = Arc::new(NodeIndex::build(&self.chunks, node));
+                nodes.insert(node, NodeLookups::Indexed(Arc::clone(&index)));
+                Some(index)
+            }
+            Some(NodeLookups::Counting(_)) => None,
+            None => {
+                nodes.insert(node, NodeLookups::Counting(AtomicU32::new(1)));
+                None
+            }
+        }
+    }
+
+    fn node_chunks(
+        &self,
+        node: NodeId,
+    ) -> impl Iterator<Item = (&(NodeId, ChunkIndices), &ChunkPayload)> {
+        self.chunks.0[self.chunks.node_range(node)]
+            .iter()
+            .map(|(key, payload)| (key, payload))
+    }
+
+    /// The chunk references of `node`, in row-major order of their coordinates.
+    ///
+    /// The order doesn't depend on the [`CoordinateOrder`] the manifest is written in,
+    /// so the chunks of several manifests can be merged as they are iterated.
+    pub fn iter(self: Arc<Self>, node: &NodeId) -> PayloadIterator {
+        let Range { start, end } = self.chunks.node_range(*node);
+        PayloadIterator { manifest: self, for_node: *node, front: start, back: end }
     }
 
     pub fn new(chunks: BTreeMap<(NodeId, ChunkIndices), ChunkPayload>) -> Self {
         Self {
-            chunks,
+            chunks: chunks.into_iter().collect(),
+            extra: BTreeMap::new(),
+            lookup_index: LookupIndex::default(),
             icechunk_manifest_format_version:
                 format_constants::LATEST_ICECHUNK_MANIFEST_FORMAT,
             icechunk_manifest_format_flags: Default::default(),
@@ -148,7 +874,7 @@ impl Manifest {
         Ok(Self::new(chunk_map))
     }
 
-    pub fn chunks(&self) -> &BTreeMap<(NodeId, ChunkIndices), ChunkPayload> {
+    pub fn chunks(&self) -> &ChunkTable {
         &self.chunks
     }
 
@@ -156,10 +882,364 @@ impl Manifest {
         self.chunks.len()
     }
 
+    /// The order of the chunk references when the manifest is written
+    pub fn coordinate_order(&self) -> CoordinateOrder {
+        match self.icechunk_manifest_format_flags.get(CoordinateOrder::FLAG) {
+            Some(rmpv::Value::String(order)) => {
+                order.as_str().and_then(|order| order.parse().ok()).unwrap_or_default()
+            }
+            _ => CoordinateOrder::RowMajor,
+        }
+    }
+
+    pub fn with_coordinate_order(mut self, order: CoordinateOrder) -> Self {
+        if order == CoordinateOrder::RowMajor {
+            self.icechunk_manifest_format_flags.remove(CoordinateOrder::FLAG);
+        } else {
+            self.icechunk_manifest_format_flags
+                .insert(CoordinateOrder::FLAG.to_string(), order.as_str().into());
+        }
+        self
+    }
+
+    /// Attach extra data to the chunks, entries for chunks not in the manifest are dropped
+    pub fn with_extras(
+        mut self,
+        extras: impl IntoIterator<Item = ((NodeId, ChunkIndices), ChunkExtra)>,
+    ) -> Self {
+        self.extra.extend(
+            extras.into_iter().filter(|(key, extra)| {
+                self.chunks.contains_key(key) && !extra.is_empty()
+            }),
+        );
+        self
+    }
+
+    /// Remove the reference to a chunk, leaving a tombstone, see [`crate::redaction`].
+    ///
+    /// Returns the removed payload, `None` if the manifest doesn't have the chunk.
+    pub fn redact_chunk(
+        &mut self,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> Option<ChunkPayload> {
+        let key = (node, coord.clone());
+        let payload = self.chunks.remove(&key)?;
+        self.extra.remove(&key);
+        // the index may have the removed chunk
+        self.lookup_index = LookupIndex::default();
+        let tombstone = rmpv::Value::Array(vec![
+            node.into(),
+            rmpv::Value::Array(coord.0.iter().map(|index| (*index).into()).collect()),
+        ]);
+        match self.icechunk_manifest_format_flags.get_mut(REDACTED_FLAG) {
+            Some(rmpv::Value::Array(tombstones)) => tombstones.push(tombstone),
+            _ => {
+                self.icechunk_manifest_format_flags.insert(
+                    REDACTED_FLAG.to_string(),
+                    rmpv::Value::Array(vec![tombstone]),
+                );
+            }
+        }
+        Some(payload)
+    }
+
+    /// Remove the chunk references of `nodes`, returning their payloads
+    pub fn remove_nodes(&mut self, nodes: &[NodeId]) -> Vec<ChunkPayload> {
+        if !self.chunks.keys().any(|(node, _)| nodes.contains(node)) {
+            return Vec::new();
+        }
+        self.extra.retain(|(node, _), _| !nodes.contains(node));
+        self.lookup_index = LookupIndex::default();
+        let (removed, kept): (Vec<_>, Vec<_>) = std::mem::take(&mut self.chunks.0)
+            .into_iter()
+            .partition(|((node, _), _)| nodes.contains(node));
+        self.chunks = ChunkTable(kept);
+        removed.into_iter().map(|(_, payload)| payload).collect()
+    }
+
+    /// The chunks removed by [`Manifest::redact_chunk`]
+    pub fn redacted_chunks(&self) -> Vec<(NodeId, ChunkIndices)> {
+        match self.icechunk_manifest_format_flags.get(REDACTED_FLAG) {
+            Some(rmpv::Value::Array(tombstones)) => tombstones
+                .iter()
+                .filter_map(|tombstone| rmpv::ext::from_value(tombstone.clone()).ok())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn extras(&self) -> &BTreeMap<(NodeId, ChunkIndices), ChunkExtra> {
+        &self.extra
+    }
+
+    pub fn get_chunk_extra(
+        &self,
+        node: NodeId,
+        coord: &ChunkIndices,
+    ) -> Option<&ChunkExtra> {
+        self.extra.get(&(node, coord.clone()))
+    }
+
+    /// The extra data of the chunks of `node` that have any
+    pub fn node_extras(
+        &self,
+        node: NodeId,
+    ) -> impl Iterator<Item = (&ChunkIndices, &ChunkExtra)> {
+        self.extra
+            .range((Bound::Included((node, ChunkIndices(vec![]))), Bound::Unbounded))
+            .take_while(move |((n, _), _)| *n == node)
+            .map(|((_, coord), extra)| (coord, extra))
+    }
+
     #[must_use]
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
+
+    /// An approximation of the memory used by the manifest, in bytes
+    pub fn estimated_size_bytes(&self) -> u64 {
+        let heap_size: u64 = self
+            .chunks
+            .iter()
+            .map(|((_, coord), payload)| estimated_entry_size(coord, payload))
+            .sum();
+        size_of::<Self>() as u64 + heap_size
+    }
+
+    /// An approximation of the memory the manifest can take while in use, in bytes.
+    ///
+    /// Unlike [`Manifest::estimated_size_bytes`] it counts the hash indexes built for
+    /// the nodes whose chunks are looked up repeatedly, assuming all of them get one.
+    pub fn estimated_memory_bytes(&self) -> u64 {
+        self.estimated_size_bytes() + self.len() as u64 * INDEX_ENTRY_SIZE
+    }
+
+    /// Decode a serialized manifest skipping the chunk references that can't be read,
+    /// instead of failing like [`rmp_serde::from_slice`].
+    ///
+    /// Fails only if the fields before the chunk references are unreadable.
+    pub fn salvage(bytes: &[u8]) -> IcechunkResult<SalvagedManifest> {
+        let unsalvageable = |message: &str| IcechunkFormatError::UnsalvageableManifest {
+            message: message.to_string(),
+        };
+        let mut input = bytes;
+        let fields = read_length(&mut input, false)
+            .filter(|fields| *fields >= 3)
+            .ok_or_else(|| unsalvageable("unreadable header"))?;
+        let version = next_value(&mut input)
+            .and_then(|raw| rmp_serde::from_slice(raw).ok())
+            .ok_or_else(|| unsalvageable("unreadable format version"))?;
+        let flags = next_value(&mut input)
+            .and_then(|raw| rmp_serde::from_slice(raw).ok())
+            .ok_or_else(|| unsalvageable("unreadable format flags"))?;
+        let declared = read_length(&mut input, true)
+            .ok_or_else(|| unsalvageable("unreadable chunks"))?;
+
+        let mut salvaged = SalvagedManifest {
+            manifest: Manifest::default(),
+            lost: Vec::new(),
+            unidentified: 0,
+        };
+        salvaged.manifest.icechunk_manifest_format_version = version;
+        salvaged.manifest.icechunk_manifest_format_flags = flags;
+        let mut chunks = Vec::new();
+        let mut truncated = false;
+        for index in 0..declared {
+            let Some(key) = next_value(&mut input).and_then(|raw| {
+                rmp_serde::from_slice::<(NodeId, ChunkIndices)>(raw).ok()
+            }) else {
+                // without the key we don't know where the next entry starts either
+                salvaged.unidentified = u64::from(declared - index);
+                truncated = true;
+                break;
+            };
+            match next_value(&mut input) {
+                Some(raw) => match rmp_serde::from_slice(raw) {
+                    Ok(payload) => chunks.push((key, payload)),
+                    Err(_) => salvaged.lost.push(key),
+                },
+                None => {
+                    salvaged.lost.push(key);
+                    salvaged.unidentified = u64::from(declared - index - 1);
+                    truncated = true;
+                    break;
+                }
+            }
+        }
+        salvaged.manifest.chunks = chunks.into_iter().collect();
+        if truncated {
+            return Ok(salvaged);
+        }
+
+        // extra data is optional, unreadable entries are dropped
+        let extra_entries =
+            if fields >= 4 { read_length(&mut input, true) } else { None };
+        let mut extras = Vec::new();
+        for _ in 0..extra_entries.unwrap_or(0) {
+            let (Some(key), Some(extra)) =
+                (next_value(&mut input), next_value(&mut input))
+            else {
+                break;
+            };
+            if let (Ok(key), Ok(extra)) =
+                (rmp_serde::from_slice(key), rmp_serde::from_slice(extra))
+            {
+                extras.push((key, extra));
+            }
+        }
+        salvaged.manifest = salvaged.manifest.with_extras(extras);
+        Ok(salvaged)
+    }
+
+    /// The sha256 of the serialized manifest, in hex, recorded in the [`ManifestRef`]s
+    pub fn content_hash(&self) -> Result<String, rmp_serde::encode::Error> {
+        Ok(sha256_hex(&rmp_serde::to_vec(self)?))
+    }
+
+    /// The serialized manifest, the same bytes as [`rmp_serde::to_vec`], in parts of at
+    /// least `part_size` bytes, except the last one.
+    ///
+    /// Parts are serialized as the iterator advances, so a large manifest can be uploaded
+    /// without holding all of its serialized bytes in memory.
+    pub fn serialized_parts(
+        &self,
+        part_size: usize,
+    ) -> impl Iterator<Item = Result<Bytes, rmp_serde::encode::Error>> + '_ {
+        let chunks = self.ordered_chunks();
+        let mut pieces =
+            [ManifestPiece::Header(self), ManifestPiece::MapLength(chunks.len())]
+                .into_iter()
+                .chain(
+                    chunks
+                        .into_iter()
+                        .map(|(key, payload)| ManifestPiece::Chunk(key, payload)),
+                )
+                .chain(iter::once(ManifestPiece::MapLength(self.extra.len())))
+                .chain(
+                    self.extra
+                        .iter()
+                        .map(|(key, extra)| ManifestPiece::Extra(key, extra)),
+                );
+        let mut failed = false;
+        iter::from_fn(move || {
+            if failed {
+                return None;
+            }
+            let mut part = Vec::with_capacity(part_size);
+            for piece in pieces.by_ref() {
+                if let Err(err) = piece.write(&mut part) {
+                    // nothing can follow a broken part
+                    failed = true;
+                    return Some(Err(err));
+                }
+                if part.len() >= part_size {
+                    return Some(Ok(Bytes::from(part)));
+                }
+            }
+            (!part.is_empty()).then(|| Ok(Bytes::from(part)))
+        })
+    }
+
+    /// The chunks in the order they are serialized, see [`CoordinateOrder`]
+    fn ordered_chunks(&self) -> Vec<ChunkEntry<'_>> {
+        let order = self.coordinate_order();
+        let mut chunks: Vec<_> = self.chunks.iter().collect();
+        if order != CoordinateOrder::RowMajor {
+            chunks.sort_by(|((node1, coord1), _), ((node2, coord2), _)| {
+                node1.cmp(node2).then_with(|| order.compare(coord1, coord2))
+            });
+        }
+        chunks
+    }
+
+    /// The nodes with chunks in this manifest, in order
+    pub fn nodes(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.chunks.keys().map(|(node, _)| *node).dedup()
+    }
+
+    /// The extents of the chunks of `node` in this manifest
+    pub fn extents(&self, node: NodeId) -> ManifestExtents {
+        let mut chunks = self.node_chunks(node).map(|((_, coord), _)| coord);
+        let Some(first) = chunks.next() else { return ManifestExtents(vec![]) };
+        let (mut from, mut to) = (first.clone(), first.clone());
+        for coord in chunks {
+            if coord.0.len() != from.0.len() {
+                return ManifestExtents(vec![]);
+            }
+            for (i, index) in coord.0.iter().enumerate() {
+                from.0[i] = from.0[i].min(*index);
+                to.0[i] = to.0[i].max(*index);
+            }
+        }
+        ManifestExtents(vec![from, to])
+    }
+
+    /// Split the chunk references into manifests following `policy`.
+    ///
+    /// Empty manifests produce no manifests.
+    pub fn split(self, policy: &ManifestSplitPolicy) -> Vec<Manifest> {
+        let order = policy.coordinate_order;
+        if policy.max_rows.is_none() && policy.max_bytes.is_none() {
+            return if self.is_empty() {
+                vec![]
+            } else {
+                vec![self.with_coordinate_order(order)]
+            };
+        }
+
+        let mut extras = self.extra;
+        let with_extras = |chunks: BTreeMap<_, _>, extras: &mut BTreeMap<_, _>| {
+            let manifest_extras: Vec<_> =
+                chunks.keys().filter_map(|key| extras.remove_entry(key)).collect();
+            Manifest::new(chunks)
+                .with_extras(manifest_extras)
+                .with_coordinate_order(order)
+        };
+        let base_size = size_of::<Self>() as u64;
+        let mut res = Vec::new();
+        let mut current = BTreeMap::new();
+        let mut current_size = base_size;
+        let nodes = self.chunks.into_iter().chunk_by(|((node, _), _)| *node);
+        for (_, node_chunks) in &nodes {
+            let mut node_chunks: Vec<_> = node_chunks.collect();
+            if order != CoordinateOrder::RowMajor {
+                node_chunks.sort_by(|((_, a), _), ((_, b), _)| order.compare(a, b));
+            }
+            if let Some(axis) = policy.split_axis {
+                // stable, so chunks with the same index keep their order
+                node_chunks.sort_by_key(|((_, coord), _)| coord.0.get(axis).copied());
+            }
+            for (key, payload) in node_chunks {
+                let size = estimated_entry_size(&key.1, &payload);
+                let full = policy.max_rows.is_some_and(|max| current.len() as u64 >= max)
+                    || policy.max_bytes.is_some_and(|max| current_size + size > max);
+                if full && !current.is_empty() {
+                    res.push(with_extras(std::mem::take(&mut current), &mut extras));
+                    current_size = base_size;
+                }
+                current.insert(key, payload);
+                current_size += size;
+            }
+        }
+        if !current.is_empty() {
+            res.push(with_extras(current, &mut extras));
+        }
+        res
+    }
+}
+
+fn estimated_entry_size(coord: &ChunkIndices, payload: &ChunkPayload) -> u64 {
+    let entry_size = size_of::<(NodeId, ChunkIndices)>() + size_of::<ChunkPayload>();
+    let payload_size = match payload {
+        ChunkPayload::Inline(bytes) => bytes.len(),
+        ChunkPayload::Virtual(VirtualChunkRef {
+            location: VirtualChunkLocation::Absolute(location),
+            ..
+        }) => location.len(),
+        ChunkPayload::Ref(_) => 0,
+    };
+    (entry_size + coord.0.len() * size_of::<u64>() + payload_size) as u64
 }
 
 impl FromIterator<ChunkInfo> for Manifest {
@@ -172,46 +1252,553 @@ impl FromIterator<ChunkInfo> for Manifest {
     }
 }
 
-struct PayloadIterator {
+/// The chunk references of a node, see [`Manifest::iter`].
+///
+/// It iterates from both ends, and [`PayloadIterator::seek`] skips to a coordinate
+/// without going through the chunks before it.
+#[derive(Debug, Clone)]
+pub struct PayloadIterator {
     manifest: Arc<Manifest>,
     for_node: NodeId,
-    last_key: Option<(NodeId, ChunkIndices)>,
+    // the positions in the chunk table not yielded yet, from each end
+    front: usize,
+    back: usize,
+}
+
+impl PayloadIterator {
+    /// Continue from the first chunk at or after `coord`
+    pub fn seek(&mut self, coord: &ChunkIndices) {
+        self.front = self
+            .manifest
+            .chunks
+            .position(self.for_node, coord)
+            .unwrap_or_else(|position| position);
+    }
+
+    fn entry(&self, position: usize) -> Option<(ChunkIndices, ChunkPayload)> {
+        let ((_, coord), payload) = self.manifest.chunks.0.get(position)?;
+        Some((coord.clone(), payload.clone()))
+    }
 }
 
 impl Iterator for PayloadIterator {
     type Item = (ChunkIndices, ChunkPayload);
 
     fn next(&mut self) -> Option<Self::Item> {
-        match &self.last_key {
-            None => {
-                if let Some((k @ (_, coord), payload)) = self
-                    .manifest
-                    .chunks
-                    .range((
-                        Bound::Included((self.for_node, ChunkIndices(vec![]))),
-                        Bound::Unbounded,
-                    ))
-                    .next()
-                {
-                    self.last_key = Some(k.clone());
-                    Some((coord.clone(), payload.clone()))
-                } else {
-                    None
-                }
+        if self.front >= self.back {
+            return None;
+        }
+        self.front += 1;
+        self.entry(self.front - 1)
+    }
+}
+
+impl DoubleEndedIterator for PayloadIterator {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front >= self.back {
+            return None;
+        }
+        self.back -= 1;
+        self.entry(self.back)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_manifest_iter_is_limited_to_node() {
+        let inline =
+            |s: &'static str| ChunkPayload::Inline(Bytes::from_static(s.as_bytes()));
+        let manifest: Arc<Manifest> = Arc::new(
+            vec![
+                // a scalar array only has the chunk with empty coordinates
+                ChunkInfo { node: 1, coord: ChunkIndices(vec![]), payload: inline("a") },
+                ChunkInfo { node: 2, coord: ChunkIndices(vec![0]), payload: inline("b") },
+                ChunkInfo { node: 2, coord: ChunkIndices(vec![1]), payload: inline("c") },
+                ChunkInfo { node: 3, coord: ChunkIndices(vec![]), payload: inline("d") },
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        assert_eq!(
+            Arc::clone(&manifest).iter(&1).collect::<Vec<_>>(),
+            vec![(ChunkIndices(vec![]), inline("a"))]
+        );
+        assert_eq!(
+            Arc::clone(&manifest).iter(&2).collect::<Vec<_>>(),
+            vec![
+                (ChunkIndices(vec![0]), inline("b")),
+                (ChunkIndices(vec![1]), inline("c"))
+            ]
+        );
+        assert_eq!(
+            Arc::clone(&manifest).iter(&3).collect::<Vec<_>>(),
+            vec![(ChunkIndices(vec![]), inline("d"))]
+        );
+        assert_eq!(Arc::clone(&manifest).iter(&4).count(), 0);
+
+        assert_eq!(manifest.get_chunk_payload(3, ChunkIndices(vec![])), Ok(inline("d")));
+        assert_eq!(
+            manifest.get_chunk_payload(2, ChunkIndices(vec![])),
+            Err(IcechunkFormatError::ChunkCoordinatesNotFound {
+                coords: ChunkIndices(vec![])
+            })
+        );
+    }
+
+    #[test]
+    fn test_manifest_iter_order() {
+        let coords: Vec<_> =
+            (0..3).flat_map(|i| (0..3).map(move |j| ChunkIndices(vec![i, j]))).collect();
+        // inserted out of order, and written in morton order
+        let manifest: Arc<Manifest> = Arc::new(
+            coords
+                .iter()
+                .rev()
+                .flat_map(|coord| {
+                    [1, NodeId::MAX].map(|node| ChunkInfo {
+                        node,
+                        coord: coord.clone(),
+                        payload: ChunkPayload::Inline(Bytes::new()),
+                    })
+                })
+                .collect::<Manifest>()
+                .with_coordinate_order(CoordinateOrder::Morton),
+        );
+        let keys = |iter: &mut dyn Iterator<Item = (ChunkIndices, ChunkPayload)>| {
+            iter.map(|(coord, _)| coord).collect::<Vec<_>>()
+        };
+
+        for node in [1, NodeId::MAX] {
+            assert_eq!(keys(&mut Arc::clone(&manifest).iter(&node)), coords);
+            let reversed: Vec<_> = coords.iter().rev().cloned().collect();
+            assert_eq!(keys(&mut Arc::clone(&manifest).iter(&node).rev()), reversed);
+        }
+
+        let mut iter = Arc::clone(&manifest).iter(&1);
+        iter.seek(&ChunkIndices(vec![1, 1]));
+        assert_eq!(iter.next().map(|(coord, _)| coord), Some(ChunkIndices(vec![1, 1])));
+        assert_eq!(iter.next_back().map(|(coord, _)| coord), Some(coords[8].clone()));
+        // both ends meet
+        assert_eq!(keys(&mut iter), coords[5..8].to_vec());
+        assert_eq!(iter.next_back(), None);
+        iter.seek(&ChunkIndices(vec![5]));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn test_repeated_lookups_use_index() {
+        let inline = |i: u64| ChunkPayload::Inline(Bytes::from(i.to_string()));
+        let manifest: Manifest = (0..3)
+            .flat_map(|node| {
+                (0..10).map(move |i| ChunkInfo {
+                    node,
+                    coord: ChunkIndices(vec![i]),
+                    payload: inline(i),
+                })
+            })
+            .collect();
+
+        let is_indexed = |node| {
+            let nodes = manifest.lookup_index.0.read().unwrap();
+            matches!(
+                nodes.get(&node),
+                Some(NodeLookups::Indexed(index)) if index.positions.len() == 10
+            )
+        };
+        let lookup = |i| manifest.get_chunk_payload(1, ChunkIndices(vec![i]));
+
+        for i in 0..INDEX_AFTER_LOOKUPS as u64 - 1 {
+            assert_eq!(lookup(i), Ok(inline(i)));
+        }
+        assert!(!is_indexed(1));
+        // missing chunks count as lookups too
+        assert_eq!(
+            lookup(10),
+            Err(IcechunkFormatError::ChunkCoordinatesNotFound {
+                coords: ChunkIndices(vec![10])
+            })
+        );
+        assert!(is_indexed(1));
+        for i in 0..10 {
+            assert_eq!(lookup(i), Ok(inline(i)));
+        }
+        assert_eq!(
+            lookup(10),
+            Err(IcechunkFormatError::ChunkCoordinatesNotFound {
+                coords: ChunkIndices(vec![10])
+            })
+        );
+        assert!(!is_indexed(0));
+        assert!(!is_indexed(2));
+
+        // the index only holds positions, within what the manifest accounts for
+        let nodes = manifest.lookup_index.0.read().unwrap();
+        let Some(NodeLookups::Indexed(index)) = nodes.get(&1) else { panic!() };
+        let index_size = index.positions.capacity() * (size_of::<(u64, usize)>() + 1);
+        assert!(index_size as u64 <= 10 * INDEX_ENTRY_SIZE);
+        assert!(
+            manifest.estimated_memory_bytes()
+                >= manifest.estimated_size_bytes() + 30 * INDEX_ENTRY_SIZE
+        );
+    }
+
+    #[test]
+    fn test_chunk_table_keeps_last_payload() {
+        let inline =
+            |s: &'static str| ChunkPayload::Inline(Bytes::from_static(s.as_bytes()));
+        let chunks: ChunkTable = [
+            ((2, ChunkIndices(vec![0])), inline("a")),
+            ((1, ChunkIndices(vec![1])), inline("b")),
+            ((2, ChunkIndices(vec![0])), inline("c")),
+            ((1, ChunkIndices(vec![0])), inline("d")),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            chunks.keys().cloned().collect::<Vec<_>>(),
+            vec![
+                (1, ChunkIndices(vec![0])),
+                (1, ChunkIndices(vec![1])),
+                (2, ChunkIndices(vec![0]))
+            ]
+        );
+        assert_eq!(chunks.get(&(2, ChunkIndices(vec![0]))), Some(&inline("c")));
+        assert_eq!(chunks.node_range(1), 0..2);
+        assert_eq!(chunks.node_range(3), 3..3);
+    }
+
+    #[test]
+    fn test_manifest_split() {
+        let inline = |i: u8| ChunkPayload::Inline(Bytes::from(vec![i]));
+        let mut chunks: Vec<ChunkInfo> = (0..3u64)
+            .cartesian_product(0..2u64)
+            .map(|(i, j)| ChunkInfo {
+                node: 1,
+                coord: ChunkIndices(vec![i, j]),
+                payload: inline(i as u8),
+            })
+            .collect();
+        chunks.push(ChunkInfo {
+            node: 2,
+            coord: ChunkIndices(vec![5]),
+            payload: inline(9),
+        });
+        let manifest: Manifest = chunks.into_iter().collect();
+
+        assert_eq!(manifest.clone().split(&ManifestSplitPolicy::default()).len(), 1);
+        assert!(Manifest::default().split(&ManifestSplitPolicy::default()).is_empty());
+
+        let policy = ManifestSplitPolicy {
+            max_rows: Some(3),
+            split_axis: Some(1),
+            ..ManifestSplitPolicy::default()
+        };
+        let split = manifest.clone().split(&policy);
+        assert_eq!(split.iter().map(|m| m.len()).collect::<Vec<_>>(), vec![3, 3, 1]);
+        // sorted along the second axis, each manifest holds a column
+        assert_eq!(
+            split[0].extents(1),
+            ManifestExtents(vec![ChunkIndices(vec![0, 0]), ChunkIndices(vec![2, 0])])
+        );
+        assert_eq!(
+            split[1].extents(1),
+            ManifestExtents(vec![ChunkIndices(vec![0, 1]), ChunkIndices(vec![2, 1])])
+        );
+        assert_eq!(split[1].nodes().collect::<Vec<_>>(), vec![1]);
+        assert_eq!(split[2].nodes().collect::<Vec<_>>(), vec![2]);
+        assert!(split[0].extents(1).contains(&ChunkIndices(vec![1, 0])));
+        assert!(!split[0].extents(1).contains(&ChunkIndices(vec![1, 1])));
+        assert!(!split[0].extents(1).contains(&ChunkIndices(vec![1])));
+        assert!(ManifestExtents(vec![]).contains(&ChunkIndices(vec![1, 1])));
+
+        let max_bytes = size_of::<Manifest>() as u64
+            + 2 * estimated_entry_size(&ChunkIndices(vec![0, 0]), &inline(0));
+        let policy = ManifestSplitPolicy { max_bytes: Some(max_bytes), ..policy };
+        let split = manifest.split(&policy);
+        assert_eq!(split.iter().map(|m| m.len()).collect::<Vec<_>>(), vec![2, 2, 2, 1]);
+    }
+
+    #[test]
+    fn test_morton_order() {
+        let coords: Vec<_> = (0..4u64)
+            .cartesian_product(0..4u64)
+            .map(|(i, j)| ChunkIndices(vec![i, j]))
+            .collect();
+        let mut sorted = coords.clone();
+        sorted.sort_by(|a, b| CoordinateOrder::Morton.compare(a, b));
+        assert_eq!(
+            sorted[..6],
+            [[0, 0], [0, 1], [1, 0], [1, 1], [0, 2], [0, 3]]
+                .map(|coord| ChunkIndices(coord.to_vec()))
+        );
+        assert_eq!(sorted.last(), Some(&ChunkIndices(vec![3, 3])));
+
+        let manifest: Manifest = coords
+            .into_iter()
+            .map(|coord| ChunkInfo {
+                node: 1,
+                payload: ChunkPayload::Inline(Bytes::from_static(b"x")),
+                coord,
+            })
+            .collect();
+        let policy = ManifestSplitPolicy {
+            max_rows: Some(4),
+            coordinate_order: CoordinateOrder::Morton,
+            ..ManifestSplitPolicy::default()
+        };
+        let split = manifest.clone().split(&policy);
+        // each manifest holds a 2x2 block of the array
+        assert_eq!(split.len(), 4);
+        assert_eq!(
+            split[1].extents(1),
+            ManifestExtents(vec![ChunkIndices(vec![0, 2]), ChunkIndices(vec![1, 3])])
+        );
+
+        assert_eq!(split[0].coordinate_order(), CoordinateOrder::Morton);
+
+        // manifests are written in the order, and read back the same
+        let manifest = manifest.with_coordinate_order(CoordinateOrder::Morton);
+        let bytes = rmp_serde::to_vec(&manifest).unwrap();
+        let written: rmpv::Value = rmp_serde::from_slice(&bytes).unwrap();
+        let written_coords: Vec<ChunkIndices> = written.as_array().unwrap()[2]
+            .as_map()
+            .unwrap()
+            .iter()
+            .map(|(key, _)| rmpv::ext::from_value::<(NodeId, ChunkIndices)>(key.clone()))
+            .map(|key| key.unwrap().1)
+            .collect();
+        assert_eq!(written_coords, sorted);
+        let read: Manifest = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(read, manifest);
+        assert_eq!(read.coordinate_order(), CoordinateOrder::Morton);
+    }
+
+    #[test]
+    fn test_serialized_parts() {
+        let manifest: Manifest = (0..8u64)
+            .cartesian_product(0..8u64)
+            .map(|(i, j)| ChunkInfo {
+                node: 1,
+                payload: ChunkPayload::Inline(Bytes::from(format!("chunk {i} {j}"))),
+                coord: ChunkIndices(vec![i, j]),
+            })
+            .collect();
+        let mut extra = ChunkExtra::default();
+        extra.set(&ChunkStatistics::from_values([1.0, 2.0])).unwrap();
+        let manifest = manifest
+            .with_coordinate_order(CoordinateOrder::Morton)
+            .with_extras([((1, ChunkIndices(vec![2, 3])), extra)]);
+        let expected = rmp_serde::to_vec(&manifest).unwrap();
+
+        for part_size in [1, 100, expected.len(), 10 * expected.len()] {
+            let parts: Vec<Bytes> =
+                manifest.serialized_parts(part_size).collect::<Result<_, _>>().unwrap();
+            assert_eq!(parts.concat(), expected);
+            assert!(parts[..parts.len() - 1].iter().all(|part| part.len() >= part_size));
+        }
+        assert_eq!(Manifest::default().serialized_parts(10).count(), 1);
+        // msgpack can't describe larger maps
+        assert_eq!(map_length(u32::MAX as usize), Ok(u32::MAX));
+        assert!(matches!(
+            map_length(u32::MAX as usize + 1),
+            Err(IcechunkFormatError::TooManyChunkReferences { .. })
+        ));
+    }
+
+    #[test]
+    fn test_salvage() {
+        let key = |i: u64| (1, ChunkIndices(vec![i]));
+        let payload = |i: u64| ChunkPayload::Inline(Bytes::from(format!("chunk {i}")));
+        let mut bytes = vec![0x94];
+        bytes.extend(
+            rmp_serde::to_vec(&format_constants::LATEST_ICECHUNK_MANIFEST_FORMAT)
+                .unwrap(),
+        );
+        bytes.extend(rmp_serde::to_vec(&BTreeMap::<String, rmpv::Value>::new()).unwrap());
+        write_map_length(&mut bytes, 3);
+        for i in 0..3 {
+            bytes.extend(rmp_serde::to_vec(&key(i)).unwrap());
+            if i == 1 {
+                bytes.extend(rmp_serde::to_vec("not a payload").unwrap());
+            } else {
+                bytes.extend(rmp_serde::to_vec(&payload(i)).unwrap());
             }
-            Some(last_key) => {
-                if let Some((k @ (_, coord), payload)) = self
-                    .manifest
-                    .chunks
-                    .range((Bound::Excluded(last_key), Bound::Unbounded))
-                    .next()
-                {
-                    self.last_key = Some(k.clone());
-                    Some((coord.clone(), payload.clone()))
-                } else {
-                    None
-                }
+        }
+        write_map_length(&mut bytes, 0);
+        assert!(rmp_serde::from_slice::<Manifest>(&bytes).is_err());
+
+        let salvaged = Manifest::salvage(&bytes).unwrap();
+        let expected: Manifest = [0, 2]
+            .into_iter()
+            .map(|i| ChunkInfo { node: 1, coord: key(i).1, payload: payload(i) })
+            .collect();
+        assert_eq!(salvaged.manifest, expected);
+        assert_eq!((salvaged.lost, salvaged.unidentified), (vec![key(1)], 0));
+
+        // a truncated manifest loses the entries after the cut, without their keys
+        let cut = bytes.len() - rmp_serde::to_vec(&payload(2)).unwrap().len() - 10;
+        let salvaged = Manifest::salvage(&bytes[..cut]).unwrap();
+        assert_eq!(salvaged.manifest.len(), 1);
+        assert_eq!((salvaged.lost, salvaged.unidentified), (vec![key(1)], 1));
+        assert!(matches!(
+            Manifest::salvage(&bytes[..2]),
+            Err(IcechunkFormatError::UnsalvageableManifest { .. })
+        ));
+    }
+
+    #[test]
+    fn test_chunk_info_serialization() {
+        let infos = vec![
+            ChunkInfo {
+                node: 1,
+                coord: ChunkIndices(vec![0, 3]),
+                payload: ChunkPayload::Inline(Bytes::from_static(b"abc")),
+            },
+            ChunkInfo {
+                node: 2,
+                coord: ChunkIndices(vec![]),
+                payload: ChunkPayload::Ref(ChunkRef {
+                    id: ChunkId::random(),
+                    offset: 10,
+                    length: 20,
+                }),
+            },
+        ];
+        let json = serde_json::to_string(&infos).unwrap();
+        let back: Vec<ChunkInfo> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, infos);
+    }
+
+    #[test]
+    fn test_chunk_statistics() {
+        let stats = ChunkStatistics::from_values([3.0, f64::NAN, -1.0, 7.5]);
+        assert_eq!(
+            stats,
+            ChunkStatistics { min: Some(-1.0), max: Some(7.5), nan_count: 1 }
+        );
+        assert!(ChunkPredicate::LessThan(0.0).may_match(&stats));
+        assert!(!ChunkPredicate::LessThan(-1.0).may_match(&stats));
+        assert!(ChunkPredicate::GreaterThan(7.0).may_match(&stats));
+        assert!(!ChunkPredicate::GreaterThan(7.5).may_match(&stats));
+        assert!(ChunkPredicate::Between(7.5, 10.0).may_match(&stats));
+        assert!(!ChunkPredicate::Between(8.0, 10.0).may_match(&stats));
+        assert!(ChunkPredicate::HasNan.may_match(&stats));
+
+        let nans = ChunkStatistics::from_values([f64::NAN]);
+        assert!(!ChunkPredicate::LessThan(f64::INFINITY).may_match(&nans));
+
+        let extra = |value: f64| {
+            let mut extra = ChunkExtra::default();
+            extra.set(&ChunkStatistics::from_values([value])).unwrap();
+            extra
+        };
+        let manifest: Manifest = (0..4u64)
+            .map(|i| ChunkInfo {
+                node: 1,
+                coord: ChunkIndices(vec![i]),
+                payload: ChunkPayload::Inline(Bytes::from(vec![i as u8])),
+            })
+            .collect();
+        let manifest = manifest.with_extras([
+            ((1, ChunkIndices(vec![0])), extra(0.0)),
+            ((1, ChunkIndices(vec![3])), extra(3.0)),
+            // not in the manifest
+            ((2, ChunkIndices(vec![0])), extra(5.0)),
+        ]);
+        assert_eq!(manifest.node_extras(1).count(), 2);
+        assert_eq!(manifest.node_extras(2).count(), 0);
+
+        let policy = ManifestSplitPolicy { max_rows: Some(2), ..Default::default() };
+        let split = manifest.clone().split(&policy);
+        assert_eq!(
+            split[0].get_chunk_extra(1, &ChunkIndices(vec![0])),
+            Some(&extra(0.0))
+        );
+        assert_eq!(split[0].get_chunk_extra(1, &ChunkIndices(vec![3])), None);
+        assert_eq!(
+            split[1].get_chunk_extra(1, &ChunkIndices(vec![3])),
+            Some(&extra(3.0))
+        );
+
+        let bytes = rmp_serde::to_vec(&manifest).unwrap();
+        let read: Manifest = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(read, manifest);
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Provenance {
+        source: String,
+    }
+
+    impl ChunkExtraSchema for Provenance {
+        const KEY: &'static str = "test.provenance";
+        const VERSION: u32 = 2;
+
+        fn from_version(
+            version: u32,
+            value: rmpv::Value,
+        ) -> Result<Self, ChunkExtraError> {
+            match (version, value.as_str()) {
+                // version 1 stored the source as a plain string
+                (1, Some(source)) => Ok(Provenance { source: source.to_string() }),
+                _ => Err(ChunkExtraError::UnsupportedVersion {
+                    key: Self::KEY.to_string(),
+                    version,
+                }),
             }
         }
     }
+
+    #[test]
+    fn test_chunk_extra_schemas() {
+        let stats = ChunkStatistics::from_values([1.0, 2.0]);
+        let provenance = Provenance { source: "model run 3".to_string() };
+        let mut extra = ChunkExtra::default();
+        assert_eq!(extra.get::<ChunkStatistics>().unwrap(), None);
+        extra.set(&stats).unwrap();
+        extra.set(&provenance).unwrap();
+        assert_eq!(extra.get::<ChunkStatistics>().unwrap(), Some(stats));
+        assert_eq!(extra.get::<Provenance>().unwrap(), Some(provenance));
+        assert_eq!(
+            extra.entries().map(|(key, extra)| (key, extra.version)).collect::<Vec<_>>(),
+            vec![("icechunk.statistics", 1), ("test.provenance", 2)]
+        );
+
+        let bytes = rmp_serde::to_vec(&extra).unwrap();
+        let read: ChunkExtra = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(read, extra);
+
+        let mut old = ChunkExtra::default();
+        old.0.insert(
+            Provenance::KEY.to_string(),
+            VersionedExtra { version: 1, value: "old run".into() },
+        );
+        assert_eq!(
+            old.get::<Provenance>().unwrap(),
+            Some(Provenance { source: "old run".to_string() })
+        );
+        old.0.insert(
+            ChunkStatistics::KEY.to_string(),
+            VersionedExtra { version: 7, value: rmpv::Value::Nil },
+        );
+        assert!(matches!(
+            old.get::<ChunkStatistics>(),
+            Err(ChunkExtraError::UnsupportedVersion { version: 7, .. })
+        ));
+        old.remove::<ChunkStatistics>();
+        assert_eq!(old.get::<ChunkStatistics>().unwrap(), None);
+
+        extra.merge(old);
+        assert_eq!(
+            extra.get::<Provenance>().unwrap(),
+            Some(Provenance { source: "old run".to_string() })
+        );
+        assert_eq!(extra.get::<ChunkStatistics>().unwrap(), Some(stats));
+    }
 }
@@ -3,7 +3,7 @@ use std::{
     fmt::{Debug, Display},
     hash::Hash,
     marker::PhantomData,
-    ops::Bound,
+    ops::{Bound, Range},
 };
 
 use bytes::Bytes;
@@ -14,10 +14,14 @@ use serde_with::{serde_as, TryFromInto};
 use thiserror::Error;
 use typed_path::Utf8UnixPathBuf;
 
-use crate::{metadata::DataType, private};
+use crate::{
+    metadata::{ArrayShape, DataType, DimensionNames},
+    private,
+};
 
 pub mod attributes;
 pub mod manifest;
+pub mod schema;
 pub mod snapshot;
 
 #[serde_as]
@@ -43,14 +47,19 @@ pub struct ChunkTag;
 #[derive(Hash, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct AttributesTag;
 
+#[derive(Hash, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AuditRecordTag;
+
 impl private::Sealed for SnapshotTag {}
 impl private::Sealed for ManifestTag {}
 impl private::Sealed for ChunkTag {}
 impl private::Sealed for AttributesTag {}
+impl private::Sealed for AuditRecordTag {}
 impl FileTypeTag for SnapshotTag {}
 impl FileTypeTag for ManifestTag {}
 impl FileTypeTag for ChunkTag {}
 impl FileTypeTag for AttributesTag {}
+impl FileTypeTag for AuditRecordTag {}
 
 // A 1e-9 conflict probability requires 2^33 ~ 8.5 bn chunks
 // using this site for the calculations: https://www.bdayprob.com/
@@ -58,6 +67,7 @@ pub type SnapshotId = ObjectId<12, SnapshotTag>;
 pub type ManifestId = ObjectId<12, ManifestTag>;
 pub type ChunkId = ObjectId<12, ChunkTag>;
 pub type AttributesId = ObjectId<12, AttributesTag>;
+pub type AuditRecordId = ObjectId<12, AuditRecordTag>;
 
 impl<const SIZE: usize, T: FileTypeTag> ObjectId<SIZE, T> {
     pub fn random() -> Self {
@@ -132,12 +142,12 @@ pub type NodeId = u32;
 
 #[derive(Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 /// An ND index to an element in a chunk grid.
-pub struct ChunkIndices(pub Vec<u32>);
+pub struct ChunkIndices(pub Vec<u64>);
 
 pub type ChunkOffset = u64;
 pub type ChunkLength = u64;
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct ByteRange(pub Bound<ChunkOffset>, pub Bound<ChunkOffset>);
 
 impl ByteRange {
@@ -149,6 +159,19 @@ impl ByteRange {
         Self(Bound::Included(offset), Bound::Excluded(offset + length))
     }
 
+    /// Like [`ByteRange::from_offset_with_length`], failing with
+    /// [`IcechunkFormatError::ByteRangeOverflow`] if the end doesn't fit in a `u64`, use
+    /// it for offsets and lengths read from storage
+    pub fn checked_from_offset_with_length(
+        offset: ChunkOffset,
+        length: ChunkLength,
+    ) -> IcechunkResult<Self> {
+        let end = offset
+            .checked_add(length)
+            .ok_or(IcechunkFormatError::ByteRangeOverflow { offset, length })?;
+        Ok(Self(Bound::Included(offset), Bound::Excluded(end)))
+    }
+
     pub fn to_offset(offset: ChunkOffset) -> Self {
         Self(Bound::Unbounded, Bound::Excluded(offset))
     }
@@ -171,27 +194,29 @@ impl ByteRange {
 
     pub const ALL: Self = Self(Bound::Unbounded, Bound::Unbounded);
 
-    pub fn slice(&self, bytes: Bytes) -> Bytes {
-        match (self.0, self.1) {
-            (Bound::Included(start), Bound::Excluded(end)) => {
-                bytes.slice(start as usize..end as usize)
-            }
-            (Bound::Included(start), Bound::Unbounded) => bytes.slice(start as usize..),
-            (Bound::Unbounded, Bound::Excluded(end)) => bytes.slice(..end as usize),
-            (Bound::Excluded(start), Bound::Excluded(end)) => {
-                bytes.slice(start as usize + 1..end as usize)
-            }
-            (Bound::Excluded(start), Bound::Unbounded) => {
-                bytes.slice(start as usize + 1..)
-            }
-            (Bound::Unbounded, Bound::Included(end)) => bytes.slice(..=end as usize),
-            (Bound::Included(start), Bound::Included(end)) => {
-                bytes.slice(start as usize..=end as usize)
-            }
-            (Bound::Excluded(start), Bound::Included(end)) => {
-                bytes.slice(start as usize + 1..=end as usize)
+    /// The bytes of `bytes` in this range, fails with
+    /// [`IcechunkFormatError::ByteRangeOutOfBounds`] if the range doesn't fit in them
+    pub fn slice(&self, bytes: Bytes) -> IcechunkResult<Bytes> {
+        let size = bytes.len() as u64;
+        let start = match self.0 {
+            Bound::Included(start) => Some(start),
+            Bound::Excluded(start) => start.checked_add(1),
+            Bound::Unbounded => Some(0),
+        };
+        let end = match self.1 {
+            Bound::Included(end) => end.checked_add(1),
+            Bound::Excluded(end) => Some(end),
+            Bound::Unbounded => Some(size),
+        };
+        match (start, end) {
+            // both fit in `usize`, they are not larger than the length of `bytes`
+            (Some(start), Some(end)) if start <= end && end <= size => {
+                Ok(bytes.slice(start as usize..end as usize))
             }
-            (Bound::Unbounded, Bound::Unbounded) => bytes,
+            _ => Err(IcechunkFormatError::ByteRangeOutOfBounds {
+                range: self.clone(),
+                size,
+            }),
         }
     }
 }
@@ -209,7 +234,8 @@ impl From<(Option<ChunkOffset>, Option<ChunkOffset>)> for ByteRange {
     }
 }
 
-pub type TableOffset = u32;
+/// The location of a row in a table, 64 bits so tables can grow past 4 billion rows
+pub type TableOffset = u64;
 
 #[derive(Debug, Clone, Error, PartialEq, Eq)]
 #[non_exhaustive]
@@ -222,11 +248,81 @@ pub enum IcechunkFormatError {
     NodeNotFound { path: Path },
     #[error("chunk coordinates not found `{coords:?}`")]
     ChunkCoordinatesNotFound { coords: ChunkIndices },
+    #[error("chunk coordinates `{coords:?}` are outside the chunk grid")]
+    ChunkCoordinatesOutOfBounds { coords: ChunkIndices },
+    #[error("chunk grid arithmetic overflows for array shape `{shape:?}`")]
+    ChunkGridOverflow { shape: ArrayShape },
+    #[error("axis {axis} is out of bounds for an array with {ndim} dimensions")]
+    AxisOutOfBounds { axis: usize, ndim: usize },
+    #[error(
+        "the array has no dimension named `{name}`, its names are {dimension_names:?}"
+    )]
+    DimensionNotFound { name: String, dimension_names: Option<DimensionNames> },
+    #[error("more than one dimension of the array is named `{name}`")]
+    AmbiguousDimension { name: String },
+    #[error("selection `{range:?}` of axis {axis} is outside the array, of size {size}")]
+    SelectionOutOfBounds { axis: usize, range: Range<u64>, size: u64 },
+    #[error("selection has {found} dimensions, the array has {ndim}")]
+    SelectionRank { found: usize, ndim: usize },
+    #[error("invalid chunk grid: {message}")]
+    InvalidChunkGrid { message: String },
+    #[error("attributes not found at location {location} of table `{id}`")]
+    AttributesNotFound { id: AttributesId, location: TableOffset },
+    #[error("the range of {length} bytes at offset {offset} overflows")]
+    ByteRangeOverflow { offset: u64, length: u64 },
+    #[error("byte range `{range:?}` is outside an object of {size} bytes")]
+    ByteRangeOutOfBounds { range: ByteRange, size: u64 },
+    #[error("a manifest can't hold more than {max} chunk references, found {found}")]
+    TooManyChunkReferences { found: u64, max: u64 },
+    #[error("the manifest can't be salvaged: {message}")]
+    UnsalvageableManifest { message: String },
 }
 
 pub type IcechunkResult<T> = Result<T, IcechunkFormatError>;
 
-type IcechunkFormatVersion = u16;
+/// A broken invariant found in a table before writing it, see [`Snapshot::validate`]
+///
+/// [`Snapshot::validate`]: snapshot::Snapshot::validate
+#[derive(Debug, Clone, Error, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TableViolation {
+    #[error("table format version {found} is not the latest one, {expected}")]
+    FormatVersion { found: IcechunkFormatVersion, expected: IcechunkFormatVersion },
+    #[error("node `{path}` is stored under the key `{key}`")]
+    MisplacedNode { key: Path, path: Path },
+    #[error("node id {id} is used by `{first}` and `{second}`")]
+    DuplicateNodeId { id: NodeId, first: Path, second: Path },
+    #[error("invalid metadata for array `{path}`: {message}")]
+    InvalidArrayMetadata { path: Path, message: String },
+    #[error(
+        "array `{path}` references manifest {manifest}, which is not in the snapshot"
+    )]
+    UnknownManifest { path: Path, manifest: ManifestId },
+    #[error(
+        "extents of array `{path}` in manifest {manifest} don't have {ndim} dimensions"
+    )]
+    ExtentsRank { path: Path, manifest: ManifestId, ndim: usize },
+    #[error(
+        "node `{path}` references attribute table {id}, which is not in the snapshot"
+    )]
+    UnknownAttributes { path: Path, id: AttributesId },
+    #[error("manifest {manifest} has chunks of node {node}, which is not an array")]
+    DanglingNode { manifest: ManifestId, node: NodeId },
+    #[error(
+        "array `{path}` doesn't reference manifest {manifest}, which has its chunks"
+    )]
+    UnreferencedManifest { path: Path, manifest: ManifestId },
+    #[error("chunk `{coords:?}` of array `{path}` doesn't have {ndim} dimensions")]
+    ChunkRank { path: Path, coords: ChunkIndices, ndim: usize },
+    #[error(
+        "chunk `{coords:?}` of array `{path}` is outside the extents of its manifest"
+    )]
+    OutsideExtents { path: Path, coords: ChunkIndices },
+    #[error("manifest {manifest} has extra data for missing chunk `{coords:?}` of node {node}")]
+    DanglingExtra { manifest: ManifestId, node: NodeId, coords: ChunkIndices },
+}
+
+pub type IcechunkFormatVersion = u16;
 
 pub mod format_constants {
     use super::IcechunkFormatVersion;
@@ -238,6 +334,10 @@ pub mod format_constants {
     pub const LATEST_ICECHUNK_SNAPSHOT_FORMAT: IcechunkFormatVersion = 0;
     pub const LATEST_ICECHUNK_SNAPSHOT_CONTENT_TYPE: &str = "application/msgpack";
     pub const LATEST_ICECHUNK_SNAPSHOT_VERSION_METADATA_KEY: &str = "ic-sna-fmt-ver";
+
+    pub const LATEST_ICECHUNK_ATTRIBUTES_FORMAT: IcechunkFormatVersion = 0;
+    pub const LATEST_ICECHUNK_ATTRIBUTES_CONTENT_TYPE: &str = "application/msgpack";
+    pub const LATEST_ICECHUNK_ATTRIBUTES_VERSION_METADATA_KEY: &str = "ic-att-fmt-ver";
 }
 
 impl Display for Path {
@@ -272,6 +372,14 @@ impl Path {
         Ok(Path(buf))
     }
 
+    /// The path of the child of this node named `name`
+    pub fn child(&self, name: &str) -> Result<Path, PathError> {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+            return Err(PathError::NotCanonic);
+        }
+        Path::new(self.0.join(name).as_str())
+    }
+
     pub fn starts_with(&self, other: &Path) -> bool {
         self.0.starts_with(&other.0)
     }
@@ -326,4 +434,34 @@ mod tests {
             sid,
         );
     }
+
+    #[test]
+    fn test_byte_range_bounds() {
+        let bytes = Bytes::from_static(b"0123456789");
+        assert_eq!(ByteRange::bounded(2, 5).slice(bytes.clone()), Ok("234".into()));
+        assert_eq!(ByteRange::from_offset(10).slice(bytes.clone()), Ok("".into()));
+        assert_eq!(
+            ByteRange(Bound::Excluded(7), Bound::Included(9)).slice(bytes.clone()),
+            Ok("89".into())
+        );
+        assert_eq!(
+            ByteRange::bounded(8, 11).slice(bytes.clone()),
+            Err(IcechunkFormatError::ByteRangeOutOfBounds {
+                range: ByteRange::bounded(8, 11),
+                size: 10
+            })
+        );
+        assert!(ByteRange::bounded(5, 2).slice(bytes.clone()).is_err());
+        // offsets past 4 GiB are not truncated
+        let far = 1u64 << 32;
+        assert!(ByteRange::bounded(far, far + 2).slice(bytes).is_err());
+        assert_eq!(
+            ByteRange::checked_from_offset_with_length(far, far),
+            Ok(ByteRange::bounded(far, 2 * far))
+        );
+        assert_eq!(
+            ByteRange::checked_from_offset_with_length(u64::MAX, 1),
+            Err(IcechunkFormatError::ByteRangeOverflow { offset: u64::MAX, length: 1 })
+        );
+    }
 }
@@ -0,0 +1,208 @@
+//! The layout of the serialized tables, for tools that write them without this crate.
+//!
+//! Snapshots, manifests and attribute tables are msgpack arrays of their fields, in the
+//! order of [`TableSchema::fields`]. Optional fields were added after the first version
+//! of the format, they can be missing from the end of the array and readers use their
+//! default. [`validate_schema`] checks serialized tables against a schema, the contents
+//! of the fields, like the nodes of a snapshot, are checked by reading the table.
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use thiserror::Error;
+
+use super::{format_constants, IcechunkFormatVersion};
+
+/// The msgpack type of a field
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum FieldType {
+    UInt,
+    /// An RFC 3339 string
+    Timestamp,
+    Array,
+    Map,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct FieldSchema {
+    pub name: &'static str,
+    pub field_type: FieldType,
+    /// `nil` is a valid value
+    pub nullable: bool,
+    /// The field can be missing
+    pub optional: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TableSchema {
+    pub table: &'static str,
+    /// The format version the schema describes, the first field of every table
+    pub version: IcechunkFormatVersion,
+    pub fields: &'static [FieldSchema],
+}
+
+const fn field(name: &'static str, field_type: FieldType) -> FieldSchema {
+    FieldSchema { name, field_type, nullable: false, optional: false }
+}
+
+const fn optional(name: &'static str, field_type: FieldType) -> FieldSchema {
+    FieldSchema { name, field_type, nullable: false, optional: true }
+}
+
+pub const SNAPSHOT_SCHEMA: TableSchema = TableSchema {
+    table: "snapshot",
+    version: format_constants::LATEST_ICECHUNK_SNAPSHOT_FORMAT,
+    fields: &[
+        field("icechunk_snapshot_format_version", FieldType::UInt),
+        field("icechunk_snapshot_format_flags", FieldType::Map),
+        field("manifest_files", FieldType::Array),
+        field("attribute_files", FieldType::Array),
+        field("total_parents", FieldType::UInt),
+        field("short_term_parents", FieldType::UInt),
+        field("short_term_history", FieldType::Array),
+        field("metadata", FieldType::Array),
+        field("started_at", FieldType::Timestamp),
+        field("properties", FieldType::Map),
+        // only the updated nodes if the snapshot has a structure delta
+        field("nodes", FieldType::Map),
+        FieldSchema {
+            name: "new_objects",
+            field_type: FieldType::Array,
+            nullable: true,
+            optional: true,
+        },
+        optional("packed_manifests", FieldType::Array),
+        FieldSchema {
+            name: "structure_delta",
+            field_type: FieldType::Array,
+            nullable: true,
+            optional: true,
+        },
+    ],
+};
+
+pub const MANIFEST_SCHEMA: TableSchema = TableSchema {
+    table: "manifest",
+    version: format_constants::LATEST_ICECHUNK_MANIFEST_FORMAT,
+    fields: &[
+        field("icechunk_manifest_format_version", FieldType::UInt),
+        field("icechunk_manifest_format_flags", FieldType::Map),
+        field("chunks", FieldType::Map),
+        optional("extra", FieldType::Map),
+    ],
+};
+
+pub const ATTRIBUTES_SCHEMA: TableSchema = TableSchema {
+    table: "attributes",
+    version: format_constants::LATEST_ICECHUNK_ATTRIBUTES_FORMAT,
+    fields: &[
+        field("icechunk_attributes_format_version", FieldType::UInt),
+        field("attributes", FieldType::Array),
+    ],
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[non_exhaustive]
+pub enum SchemaViolation {
+    #[error("not a msgpack document: {0}")]
+    Decode(String),
+    #[error("the {table} table is not a msgpack array")]
+    NotAnArray { table: &'static str },
+    #[error("format version {found} is newer than {supported}")]
+    UnsupportedVersion { found: u64, supported: IcechunkFormatVersion },
+    #[error("missing field `{field}`")]
+    MissingField { field: &'static str },
+    #[error("field `{field}` is not a valid {expected:?}")]
+    WrongType { field: &'static str, expected: FieldType },
+    #[error("{count} fields after the last one of the schema")]
+    ExtraFields { count: usize },
+}
+
+fn has_type(value: &rmpv::Value, field_type: FieldType) -> bool {
+    match field_type {
+        FieldType::UInt => value.as_u64().is_some(),
+        FieldType::Timestamp => {
+            value.as_str().is_some_and(|s| s.parse::<DateTime<Utc>>().is_ok())
+        }
+        FieldType::Array => value.is_array(),
+        FieldType::Map => value.is_map(),
+    }
+}
+
+/// Check the serialized table `bytes` against `schema`, returning all the problems found
+pub fn validate_schema(schema: &TableSchema, bytes: &[u8]) -> Vec<SchemaViolation> {
+    let value: rmpv::Value = match rmp_serde::from_slice(bytes) {
+        Ok(value) => value,
+        Err(err) => return vec![SchemaViolation::Decode(err.to_string())],
+    };
+    let Some(values) = value.as_array() else {
+        return vec![SchemaViolation::NotAnArray { table: schema.table }];
+    };
+    let mut violations = Vec::new();
+    for (index, field) in schema.fields.iter().enumerate() {
+        match values.get(index) {
+            None if field.optional => {}
+            None => violations.push(SchemaViolation::MissingField { field: field.name }),
+            Some(value) if value.is_nil() && field.nullable => {}
+            Some(value) if !has_type(value, field.field_type) => {
+                violations.push(SchemaViolation::WrongType {
+                    field: field.name,
+                    expected: field.field_type,
+                })
+            }
+            Some(_) => {}
+        }
+    }
+    if let Some(found) = values.first().and_then(rmpv::Value::as_u64) {
+        if found > u64::from(schema.version) {
+            violations.push(SchemaViolation::UnsupportedVersion {
+                found,
+                supported: schema.version,
+            });
+        }
+    }
+    if values.len() > schema.fields.len() {
+        violations.push(SchemaViolation::ExtraFields {
+            count: values.len() - schema.fields.len(),
+        });
+    }
+    violations
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::format::{manifest::Manifest, snapshot::Snapshot};
+
+    #[test]
+    fn test_validate_schema() {
+        let snapshot = rmp_serde::to_vec(&Snapshot::empty()).unwrap();
+        assert_eq!(validate_schema(&SNAPSHOT_SCHEMA, &snapshot), vec![]);
+        let manifest = rmp_serde::to_vec(&Manifest::default()).unwrap();
+        assert_eq!(validate_schema(&MANIFEST_SCHEMA, &manifest), vec![]);
+
+        // a newer manifest, with chunks of the wrong type and an extra field
+        let table = rmpv::Value::Array(vec![
+            1.into(),
+            rmpv::Value::Map(vec![]),
+            "chunks".into(),
+            rmpv::Value::Map(vec![]),
+            rmpv::Value::Nil,
+        ]);
+        let bytes = rmp_serde::to_vec(&table).unwrap();
+        assert_eq!(
+            validate_schema(&MANIFEST_SCHEMA, &bytes),
+            vec![
+                SchemaViolation::WrongType { field: "chunks", expected: FieldType::Map },
+                SchemaViolation::UnsupportedVersion { found: 1, supported: 0 },
+                SchemaViolation::ExtraFields { count: 1 },
+            ]
+        );
+        let bytes = rmp_serde::to_vec(&rmpv::Value::Array(vec![0.into()])).unwrap();
+        assert_eq!(
+            validate_schema(&ATTRIBUTES_SCHEMA, &bytes),
+            vec![SchemaViolation::MissingField { field: "attributes" }]
+        );
+    }
+}
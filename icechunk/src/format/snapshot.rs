@@ -1,22 +1,28 @@
 use std::{
-    collections::{BTreeMap, HashMap, VecDeque},
-    ops::Bound,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    iter,
+    num::NonZeroU64,
+    ops::{Bound, Range},
     sync::Arc,
 };
 
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize, Serializer};
 use serde_json::Value;
 
 use crate::metadata::{
-    ArrayShape, ChunkKeyEncoding, ChunkShape, Codec, DataType, DimensionNames, FillValue,
-    StorageTransformer, UserAttributes,
+    ArrayShape, Axis, ChunkGrid, ChunkKeyEncoding, ChunkShape, Codec, DataType,
+    DimensionChunks, DimensionNames, FillValue, RectilinearGrid, StorageTransformer,
+    UserAttributes,
 };
 
 use super::{
-    format_constants, manifest::ManifestRef, AttributesId, IcechunkFormatError,
-    IcechunkFormatVersion, IcechunkResult, ManifestId, NodeId, ObjectId, Path,
-    SnapshotId, TableOffset,
+    format_constants,
+    manifest::{Manifest, ManifestRef, ManifestSplitPolicy},
+    AttributesId, ChunkId, ChunkIndices, IcechunkFormatError, IcechunkFormatVersion,
+    IcechunkResult, ManifestId, NodeId, ObjectId, Path, SnapshotId, TableOffset,
+    TableViolation,
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -31,7 +37,7 @@ pub enum UserAttributesSnapshot {
     Ref(UserAttributesRef),
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub enum NodeType {
     Group,
     Array,
@@ -47,12 +53,421 @@ pub struct ZarrArrayMetadata {
     pub codecs: Vec<Codec>,
     pub storage_transformers: Option<Vec<StorageTransformer>>,
     pub dimension_names: Option<DimensionNames>,
+    /// The chunk sizes of irregular chunk grids, `chunk_shape` holds the largest chunk
+    /// of each dimension then
+    #[serde(default)]
+    pub rectilinear_grid: Option<RectilinearGrid>,
+}
+
+// the chunks along one axis of the grid
+enum AxisChunks<'a> {
+    Regular(u64),
+    Sizes(&'a [NonZeroU64]),
+}
+
+impl AxisChunks<'_> {
+    fn count(&self, size: u64) -> u64 {
+        match self {
+            AxisChunks::Regular(chunk_size) => size.div_ceil(*chunk_size),
+            AxisChunks::Sizes(sizes) => {
+                let mut end = 0u64;
+                sizes
+                    .iter()
+                    .take_while(|chunk_size| {
+                        let covered = end >= size;
+                        end = end.saturating_add(chunk_size.get());
+                        !covered
+                    })
+                    .count() as u64
+            }
+        }
+    }
+
+    fn origin(&self, index: u64) -> Option<u64> {
+        match self {
+            AxisChunks::Regular(chunk_size) => index.checked_mul(*chunk_size),
+            AxisChunks::Sizes(sizes) => sizes
+                .iter()
+                .take(usize::try_from(index).ok()?)
+                .try_fold(0u64, |acc, chunk_size| acc.checked_add(chunk_size.get())),
+        }
+    }
+
+    fn containing(&self, element: u64) -> u64 {
+        match self {
+            AxisChunks::Regular(chunk_size) => element / chunk_size,
+            AxisChunks::Sizes(sizes) => {
+                let mut end = 0u64;
+                sizes
+                    .iter()
+                    .position(|chunk_size| {
+                        end = end.saturating_add(chunk_size.get());
+                        end > element
+                    })
+                    .unwrap_or(sizes.len()) as u64
+            }
+        }
+    }
+}
+
+impl ZarrArrayMetadata {
+    fn axis_chunks(&self) -> impl Iterator<Item = AxisChunks<'_>> {
+        self.chunk_shape.0.iter().enumerate().map(|(axis, chunk_size)| {
+            match self.rectilinear_grid.as_ref().and_then(|grid| grid.0.get(axis)) {
+                Some(DimensionChunks::Sizes(sizes)) => AxisChunks::Sizes(sizes),
+                Some(DimensionChunks::Regular(size)) => AxisChunks::Regular(size.get()),
+                None => AxisChunks::Regular(chunk_size.get()),
+            }
+        })
+    }
+
+    /// The chunk grid of the array, as written in its zarr metadata
+    pub fn chunk_grid(&self) -> ChunkGrid {
+        match &self.rectilinear_grid {
+            Some(grid) => ChunkGrid::Rectilinear(grid.clone()),
+            None => ChunkGrid::Regular(self.chunk_shape.clone()),
+        }
+    }
+
+    /// Replace the chunk grid, `chunk_shape` becomes the largest chunk of each dimension
+    pub fn set_chunk_grid(&mut self, grid: ChunkGrid) {
+        self.chunk_shape = grid.chunk_shape();
+        self.rectilinear_grid = match grid {
+            ChunkGrid::Regular(_) => None,
+            ChunkGrid::Rectilinear(grid) => Some(grid),
+        };
+    }
+
+    /// Check that the rectilinear grid, if any, matches the shape of the array
+    pub fn validate_chunk_grid(&self) -> IcechunkResult<()> {
+        let Some(grid) = &self.rectilinear_grid else { return Ok(()) };
+        let invalid = |message: String| IcechunkFormatError::InvalidChunkGrid { message };
+        if grid.0.len() != self.ndim() {
+            return Err(invalid(format!(
+                "the grid has {} dimensions, the array has {}",
+                grid.0.len(),
+                self.ndim()
+            )));
+        }
+        if grid.max_chunk_shape() != self.chunk_shape {
+            return Err(invalid("the chunk shape is not the largest chunk".to_string()));
+        }
+        for (axis, (dim, size)) in grid.0.iter().zip(self.shape.iter()).enumerate() {
+            if let DimensionChunks::Sizes(sizes) = dim {
+                let total = sizes.iter().map(|s| u128::from(s.get())).sum::<u128>();
+                if total != u128::from(*size) {
+                    return Err(invalid(format!(
+                        "the chunks of axis {axis} add up to {total}, the size is {size}"
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The position of `axis`, an index or one of the `dimension_names`
+    pub fn axis_index(&self, axis: impl Into<Axis>) -> IcechunkResult<usize> {
+        let index = match axis.into() {
+            Axis::Index(index) => index,
+            Axis::Name(name) => {
+                let names = self.dimension_names.as_deref().unwrap_or_default();
+                let mut matching =
+                    names.iter().positions(|dim| dim.as_deref() == Some(name.as_str()));
+                match (matching.next(), matching.next()) {
+                    (Some(index), None) => index,
+                    (Some(_), Some(_)) => {
+                        return Err(IcechunkFormatError::AmbiguousDimension { name })
+                    }
+                    (None, _) => {
+                        return Err(IcechunkFormatError::DimensionNotFound {
+                            name,
+                            dimension_names: self.dimension_names.clone(),
+                        })
+                    }
+                }
+            }
+        };
+        if index >= self.ndim() {
+            return Err(IcechunkFormatError::AxisOutOfBounds {
+                axis: index,
+                ndim: self.ndim(),
+            });
+        }
+        Ok(index)
+    }
+
+    /// The chunks in `chunks` along `axis`, and every chunk along the other dimensions
+    pub fn region_along(
+        &self,
+        axis: impl Into<Axis>,
+        chunks: Range<u64>,
+    ) -> IcechunkResult<ChunkRegion> {
+        let axis = self.axis_index(axis)?;
+        Ok(ChunkRegion(
+            (0..self.ndim())
+                .map(|index| if index == axis { chunks.clone() } else { 0..u64::MAX })
+                .collect(),
+        ))
+    }
+
+    /// The number of dimensions of the array, 0 for scalar arrays
+    pub fn ndim(&self) -> usize {
+        self.shape.len()
+    }
+
+    /// Rank-0 arrays hold a single element, stored in the chunk with coordinates `[]`
+    pub fn is_scalar(&self) -> bool {
+        self.ndim() == 0
+    }
+
+    /// The number of chunks along each dimension of the array.
+    ///
+    /// The result is empty for scalar arrays.
+    pub fn chunk_grid_shape(&self) -> Vec<u64> {
+        self.shape
+            .iter()
+            .zip(self.axis_chunks())
+            .map(|(size, axis)| axis.count(*size))
+            .collect()
+    }
+
+    /// The total number of chunks in the chunk grid.
+    ///
+    /// Scalar arrays have exactly one chunk. Fails with
+    /// [`IcechunkFormatError::ChunkGridOverflow`] if the count doesn't fit in a `u64`.
+    pub fn num_chunks(&self) -> IcechunkResult<u64> {
+        self.chunk_grid_shape()
+            .iter()
+            .try_fold(1u64, |acc, n| acc.checked_mul(*n))
+            .ok_or_else(|| IcechunkFormatError::ChunkGridOverflow {
+                shape: self.shape.clone(),
+            })
+    }
+
+    /// Returns true if `coord` points to a chunk inside the chunk grid.
+    ///
+    /// The only valid coordinates for scalar arrays are the empty ones.
+    pub fn valid_chunk_coord(&self, coord: &ChunkIndices) -> bool {
+        self.ndim() == self.chunk_shape.0.len()
+            && self
+                .rectilinear_grid
+                .as_ref()
+                .is_none_or(|grid| grid.0.len() == self.ndim())
+            && coord.0.len() == self.ndim()
+            && coord
+                .0
+                .iter()
+                .zip(self.chunk_grid_shape())
+                .all(|(index, num_chunks)| *index < num_chunks)
+    }
+
+    /// The coordinates of the first array element stored in the chunk at `coord`
+    pub fn chunk_origin(&self, coord: &ChunkIndices) -> IcechunkResult<Vec<u64>> {
+        if !self.valid_chunk_coord(coord) {
+            return Err(IcechunkFormatError::ChunkCoordinatesOutOfBounds {
+                coords: coord.clone(),
+            });
+        }
+        coord
+            .0
+            .iter()
+            .zip(self.axis_chunks())
+            .map(|(index, axis)| {
+                axis.origin(*index).ok_or_else(|| {
+                    IcechunkFormatError::ChunkGridOverflow { shape: self.shape.clone() }
+                })
+            })
+            .collect()
+    }
+
+    /// The number of elements stored along each dimension by the chunk at `coord`.
+    ///
+    /// Chunks of regular grids all have the same shape, even if they extend past the
+    /// end of the array.
+    pub fn chunk_extent(&self, coord: &ChunkIndices) -> IcechunkResult<Vec<u64>> {
+        if !self.valid_chunk_coord(coord) {
+            return Err(IcechunkFormatError::ChunkCoordinatesOutOfBounds {
+                coords: coord.clone(),
+            });
+        }
+        Ok(coord
+            .0
+            .iter()
+            .zip(self.axis_chunks())
+            .map(|(index, axis)| match axis {
+                AxisChunks::Regular(chunk_size) => chunk_size,
+                // valid coordinates index into the sizes
+                AxisChunks::Sizes(sizes) => {
+                    sizes.get(*index as usize).map_or(0, |size| size.get())
+                }
+            })
+            .collect())
+    }
+
+    /// The coordinates of the chunk that holds the array element at `element`
+    pub fn chunk_containing(&self, element: &[u64]) -> IcechunkResult<ChunkIndices> {
+        let in_bounds = element.len() == self.ndim()
+            && element.iter().zip(self.shape.iter()).all(|(i, size)| i < size);
+        let coords = element
+            .iter()
+            .zip(self.axis_chunks())
+            .map(|(i, axis)| axis.containing(*i))
+            .collect();
+        if in_bounds {
+            Ok(ChunkIndices(coords))
+        } else {
+            Err(IcechunkFormatError::ChunkCoordinatesOutOfBounds {
+                coords: ChunkIndices(coords),
+            })
+        }
+    }
+
+    /// The chunks along `axis` that hold the elements in `range`, with their index and
+    /// the elements of the array they hold, clipped to the shape of the array
+    pub fn axis_chunk_spans(
+        &self,
+        axis: impl Into<Axis>,
+        range: &Range<u64>,
+    ) -> IcechunkResult<Vec<(u64, Range<u64>)>> {
+        let axis = self.axis_index(axis)?;
+        let (Some(size), Some(chunks)) =
+            (self.shape.get(axis), self.axis_chunks().nth(axis))
+        else {
+            return Err(IcechunkFormatError::AxisOutOfBounds { axis, ndim: self.ndim() });
+        };
+        if range.start > range.end || range.end > *size {
+            return Err(IcechunkFormatError::SelectionOutOfBounds {
+                axis,
+                range: range.clone(),
+                size: *size,
+            });
+        }
+        if range.is_empty() {
+            return Ok(Vec::new());
+        }
+        let overflow =
+            || IcechunkFormatError::ChunkGridOverflow { shape: self.shape.clone() };
+        (chunks.containing(range.start)..=chunks.containing(range.end - 1))
+            .map(|index| {
+                let start = chunks.origin(index).ok_or_else(overflow)?;
+                let end = index
+                    .checked_add(1)
+                    .and_then(|next| chunks.origin(next))
+                    .map_or(*size, |end| end.min(*size));
+                Ok((index, start..end))
+            })
+            .collect()
+    }
+
+    /// The array shape that results from appending `len` elements along `axis`
+    pub fn shape_after_append(
+        &self,
+        axis: impl Into<Axis>,
+        len: u64,
+    ) -> IcechunkResult<ArrayShape> {
+        let axis = self.axis_index(axis)?;
+        let mut shape = self.shape.clone();
+        let size = shape
+            .get_mut(axis)
+            .ok_or(IcechunkFormatError::AxisOutOfBounds { axis, ndim: self.ndim() })?;
+        *size = size.checked_add(len).ok_or_else(|| {
+            IcechunkFormatError::ChunkGridOverflow { shape: self.shape.clone() }
+        })?;
+        Ok(shape)
+    }
+
+    /// The metadata that results from appending `len` elements along `axis`.
+    ///
+    /// If the rectilinear grid lists the chunk sizes of `axis`, the new elements go in a
+    /// new chunk.
+    pub fn after_append(&self, axis: impl Into<Axis>, len: u64) -> IcechunkResult<Self> {
+        let axis = self.axis_index(axis)?;
+        let mut metadata = self.clone();
+        metadata.shape = self.shape_after_append(axis, len)?;
+        if let (Some(grid), Some(len)) =
+            (metadata.rectilinear_grid.as_mut(), NonZeroU64::new(len))
+        {
+            if let Some(DimensionChunks::Sizes(sizes)) = grid.0.get_mut(axis) {
+                sizes.push(len);
+                metadata.chunk_shape = grid.max_chunk_shape();
+            }
+        }
+        Ok(metadata)
+    }
+
+    /// The metadata that results from dropping the chunks along `axis` from index
+    /// `chunk` on, the array ends where that chunk starts
+    pub fn truncated(&self, axis: impl Into<Axis>, chunk: u64) -> IcechunkResult<Self> {
+        let axis = self.axis_index(axis)?;
+        let chunks = self
+            .axis_chunks()
+            .nth(axis)
+            .ok_or(IcechunkFormatError::AxisOutOfBounds { axis, ndim: self.ndim() })?;
+        let origin = chunks.origin(chunk).ok_or_else(|| {
+            IcechunkFormatError::ChunkGridOverflow { shape: self.shape.clone() }
+        })?;
+        let mut metadata = self.clone();
+        if let Some(size) = metadata.shape.get_mut(axis) {
+            *size = origin.min(*size);
+        }
+        if let Some(grid) = metadata.rectilinear_grid.as_mut() {
+            if let Some(DimensionChunks::Sizes(sizes)) = grid.0.get_mut(axis) {
+                sizes.truncate(usize::try_from(chunk).unwrap_or(usize::MAX));
+                metadata.chunk_shape = grid.max_chunk_shape();
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+/// An array of a snapshot, placed at some position of a concatenated array
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConcatenationSource {
+    pub snapshot: SnapshotId,
+    pub path: Path,
+    /// The index, along the concatenation axis, of the first chunk of the source
+    pub chunk_offset: u64,
+    /// The number of chunks of the source along the concatenation axis
+    pub chunk_count: u64,
+}
+
+/// The layout of an array that presents other arrays one after the other.
+///
+/// Chunks are not copied, the chunk at some coordinates is the chunk of the source
+/// that covers them, with the coordinate along `axis` shifted by the source offset.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Concatenation {
+    pub axis: usize,
+    /// Sorted by offset, without gaps
+    pub sources: Vec<ConcatenationSource>,
+}
+
+impl Concatenation {
+    /// The source holding the chunk at `coords`, and the coordinates of the chunk in it
+    pub fn locate(
+        &self,
+        coords: &ChunkIndices,
+    ) -> Option<(&ConcatenationSource, ChunkIndices)> {
+        let index = *coords.0.get(self.axis)?;
+        let position = self
+            .sources
+            .partition_point(|source| source.chunk_offset + source.chunk_count <= index);
+        let source = self.sources.get(position)?;
+        if index < source.chunk_offset {
+            return None;
+        }
+        let mut source_coords = coords.clone();
+        source_coords.0[self.axis] = index - source.chunk_offset;
+        Some((source, source_coords))
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum NodeData {
     Array(ZarrArrayMetadata, Vec<ManifestRef>),
     Group,
+    /// A read-only array whose chunks are the chunks of other arrays
+    Concatenated(ZarrArrayMetadata, Concatenation),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -67,7 +482,15 @@ impl NodeSnapshot {
     pub fn node_type(&self) -> NodeType {
         match &self.node_data {
             NodeData::Group => NodeType::Group,
-            NodeData::Array(_, _) => NodeType::Array,
+            NodeData::Array(_, _) | NodeData::Concatenated(_, _) => NodeType::Array,
+        }
+    }
+
+    /// The zarr metadata of arrays, `None` for groups
+    pub fn zarr_metadata(&self) -> Option<&ZarrArrayMetadata> {
+        match &self.node_data {
+            NodeData::Array(meta, _) | NodeData::Concatenated(meta, _) => Some(meta),
+            NodeData::Group => None,
         }
     }
 }
@@ -77,6 +500,79 @@ pub struct SnapshotMetadata {
     pub id: SnapshotId,
     pub written_at: DateTime<Utc>,
     pub message: String,
+    /// The regions declared by the writer of the snapshot, if it only wrote chunks
+    /// inside them. `None` means the changes are unknown.
+    #[serde(default)]
+    pub write_regions: Option<WriteRegions>,
+    /// The policy used to split the chunk references of the snapshot into manifests,
+    /// `None` if unknown
+    #[serde(default)]
+    pub manifest_split_policy: Option<ManifestSplitPolicy>,
+    /// What the commit changed, `None` for snapshots written before summaries existed
+    #[serde(default)]
+    pub summary: Option<CommitSummary>,
+    /// The upstream dataset versions the commit was derived from
+    #[serde(default)]
+    pub dependencies: Vec<SnapshotDependency>,
+}
+
+/// A version of another dataset a commit was derived from, see [`crate::provenance`]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct SnapshotDependency {
+    /// The upstream repository, by a name or URL known to the readers of the provenance
+    pub repository: String,
+    pub snapshot: SnapshotId,
+    /// How the upstream data was used, like `regridded to 0.25 degrees`
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+/// A compact description of the changes in a snapshot, compared to its parent
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct CommitSummary {
+    pub arrays_added: Vec<Path>,
+    pub arrays_deleted: Vec<Path>,
+    /// The number of chunks in the snapshot minus the number in its parent
+    pub chunk_count_delta: i64,
+    /// The size of the chunks written, virtual chunks are not included
+    pub bytes_added: u64,
+}
+
+/// A block of chunk coordinates, with one half-open range per dimension
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub struct ChunkRegion(pub Vec<Range<u64>>);
+
+impl ChunkRegion {
+    pub fn contains(&self, coord: &ChunkIndices) -> bool {
+        self.0.len() == coord.0.len()
+            && self
+                .0
+                .iter()
+                .zip(coord.0.iter())
+                .all(|(range, index)| range.contains(index))
+    }
+
+    /// Regions with different number of dimensions are considered to overlap
+    pub fn overlaps(&self, other: &ChunkRegion) -> bool {
+        self.0.len() != other.0.len()
+            || self
+                .0
+                .iter()
+                .zip(other.0.iter())
+                .all(|(a, b)| a.start < b.end && b.start < a.end)
+    }
+}
+
+/// The chunk regions, per array, a writer declared it would modify
+pub type WriteRegions = BTreeMap<Path, Vec<ChunkRegion>>;
+
+/// Returns true if any region of `a` overlaps with a region of `b` for the same array
+pub fn write_regions_overlap(a: &WriteRegions, b: &WriteRegions) -> bool {
+    a.iter().any(|(path, regions)| {
+        b.get(path).is_some_and(|others| {
+            regions.iter().any(|region| others.iter().any(|other| region.overlaps(other)))
+        })
+    })
 }
 
 pub type SnapshotProperties = HashMap<String, Value>;
@@ -93,7 +589,9 @@ pub struct AttributeFileInfo {
     pub format_version: IcechunkFormatVersion,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// A commit of the repository. A snapshot deserialized from its stored bytes can hold
+/// only the nodes that changed since an ancestor, see [`Snapshot::with_base`].
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Snapshot {
     pub icechunk_snapshot_format_version: IcechunkFormatVersion,
     pub icechunk_snapshot_format_flags: BTreeMap<String, rmpv::Value>,
@@ -110,6 +608,128 @@ pub struct Snapshot {
     pub started_at: DateTime<Utc>,
     pub properties: SnapshotProperties,
     nodes: BTreeMap<Path, NodeSnapshot>,
+    /// The objects written for this snapshot, `None` for snapshots written before they
+    /// were recorded
+    #[serde(default)]
+    pub new_objects: Option<CommitObjects>,
+    /// Where to find the manifests of the snapshot that are stored in packfiles, see
+    /// [`crate::storage::packing`]
+    #[serde(default)]
+    pub packed_manifests: Vec<PackedManifest>,
+    /// Set if only the nodes that changed since an ancestor are stored, see
+    /// [`StructureDelta`]
+    #[serde(default)]
+    structure_delta: Option<StructureDelta>,
+}
+
+/// How a snapshot with few changes to the node table of an ancestor is stored: only the
+/// nodes that differ from the ancestor are written, the rest are read from it.
+///
+/// Changing the nodes of a snapshot after building it drops its delta, it's then stored
+/// with its whole node table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StructureDelta {
+    /// The ancestor, stored with its whole node table
+    pub base: SnapshotId,
+    /// The paths of the nodes that are new or changed since the base
+    pub updated: BTreeSet<Path>,
+    /// The paths of the nodes of the base that were deleted
+    pub deleted: BTreeSet<Path>,
+}
+
+// The snapshot as it's stored, with only the updated nodes if it has a structure delta
+#[derive(Serialize)]
+#[serde(rename = "Snapshot")]
+struct StoredSnapshot<'a> {
+    icechunk_snapshot_format_version: &'a IcechunkFormatVersion,
+    icechunk_snapshot_format_flags: &'a BTreeMap<String, rmpv::Value>,
+    manifest_files: &'a Vec<ManifestFileInfo>,
+    attribute_files: &'a Vec<AttributeFileInfo>,
+    total_parents: &'a u32,
+    short_term_parents: &'a u16,
+    short_term_history: &'a VecDeque<SnapshotMetadata>,
+    metadata: &'a SnapshotMetadata,
+    started_at: &'a DateTime<Utc>,
+    properties: &'a SnapshotProperties,
+    nodes: StoredNodes<'a>,
+    new_objects: &'a Option<CommitObjects>,
+    packed_manifests: &'a Vec<PackedManifest>,
+    structure_delta: &'a Option<StructureDelta>,
+}
+
+struct StoredNodes<'a> {
+    nodes: &'a BTreeMap<Path, NodeSnapshot>,
+    delta: Option<&'a StructureDelta>,
+}
+
+impl Serialize for StoredNodes<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.delta {
+            None => self.nodes.serialize(serializer),
+            Some(delta) => serializer.collect_map(
+                delta.updated.iter().filter_map(|path| self.nodes.get_key_value(path)),
+            ),
+        }
+    }
+}
+
+impl Serialize for Snapshot {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Snapshot {
+            icechunk_snapshot_format_version,
+            icechunk_snapshot_format_flags,
+            manifest_files,
+            attribute_files,
+            total_parents,
+            short_term_parents,
+            short_term_history,
+            metadata,
+            started_at,
+            properties,
+            nodes,
+            new_objects,
+            packed_manifests,
+            structure_delta,
+        } = self;
+        StoredSnapshot {
+            icechunk_snapshot_format_version,
+            icechunk_snapshot_format_flags,
+            manifest_files,
+            attribute_files,
+            total_parents,
+            short_term_parents,
+            short_term_history,
+            metadata,
+            started_at,
+            properties,
+            nodes: StoredNodes { nodes, delta: structure_delta.as_ref() },
+            new_objects,
+            packed_manifests,
+            structure_delta,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// A manifest stored as a byte range of a packfile
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackedManifest {
+    pub id: ManifestId,
+    /// Packfiles are stored as chunks
+    pub pack: ChunkId,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// The objects a commit added to the repository, besides the snapshot itself.
+///
+/// Objects that are not in this list for any snapshot were written by interrupted or
+/// abandoned sessions.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitObjects {
+    pub manifests: Vec<ManifestId>,
+    /// Inline and virtual chunks have no objects, so they are not included
+    pub chunks: Vec<ChunkId>,
 }
 
 impl Default for SnapshotMetadata {
@@ -118,6 +738,10 @@ impl Default for SnapshotMetadata {
             id: ObjectId::random(),
             written_at: Utc::now(),
             message: Default::default(),
+            write_regions: None,
+            manifest_split_policy: None,
+            summary: None,
+            dependencies: Vec::new(),
         }
     }
 }
@@ -130,6 +754,9 @@ impl SnapshotMetadata {
 
 impl Snapshot {
     pub const INITIAL_COMMIT_MESSAGE: &'static str = "Repository initialized";
+    /// Snapshots are stored as a [`StructureDelta`] while less than one in this many of
+    /// their nodes changed since the base, past that the whole table is written
+    pub const STRUCTURE_DELTA_RATIO: usize = 4;
 
     fn new(
         short_term_history: VecDeque<SnapshotMetadata>,
@@ -156,6 +783,9 @@ impl Snapshot {
             started_at,
             properties,
             nodes,
+            new_objects: None,
+            packed_manifests: Vec::new(),
+            structure_delta: None,
         }
     }
 
@@ -180,6 +810,82 @@ impl Snapshot {
         )
     }
 
+    /// A child of `parent`, stored at `parent_id`, that reuses its node table
+    ///
+    /// The `updated` nodes are inserted, replacing the nodes at the same paths, and the
+    /// nodes at the `deleted` paths are removed. Every other node is kept as it is.
+    /// While few nodes changed since the last snapshot stored with its whole node table,
+    /// the child is stored as a [`StructureDelta`] of it.
+    pub fn from_parent<U, D>(
+        parent_id: &SnapshotId,
+        parent: &Snapshot,
+        properties: Option<SnapshotProperties>,
+        manifest_files: Vec<ManifestFileInfo>,
+        attribute_files: Vec<AttributeFileInfo>,
+        updated: U,
+        deleted: D,
+    ) -> Self
+    where
+        U: IntoIterator<Item = NodeSnapshot>,
+        D: IntoIterator<Item = Path>,
+    {
+        let mut nodes = parent.nodes.clone();
+        let mut delta =
+            parent.structure_delta.clone().unwrap_or_else(|| StructureDelta {
+                base: parent_id.clone(),
+                updated: BTreeSet::new(),
+                deleted: BTreeSet::new(),
+            });
+        for path in deleted {
+            nodes.remove(&path);
+            delta.updated.remove(&path);
+            delta.deleted.insert(path);
+        }
+        for node in updated {
+            delta.deleted.remove(&node.path);
+            delta.updated.insert(node.path.clone());
+            nodes.insert(node.path.clone(), node);
+        }
+        let changes = delta.updated.len() + delta.deleted.len();
+        let mut history = parent.short_term_history.clone();
+        history.push_front(parent.metadata.clone());
+
+        let mut snapshot = Self::new(
+            history,
+            parent.total_parents + 1,
+            properties,
+            nodes,
+            manifest_files,
+            attribute_files,
+        );
+        if changes * Self::STRUCTURE_DELTA_RATIO < snapshot.nodes.len() {
+            snapshot.structure_delta = Some(delta);
+        }
+        snapshot
+    }
+
+    /// The snapshot whose node table this one is stored as changes to, see
+    /// [`StructureDelta`]
+    pub fn structure_base(&self) -> Option<&SnapshotId> {
+        self.structure_delta.as_ref().map(|delta| &delta.base)
+    }
+
+    /// Complete the node table of a snapshot read from storage with the nodes of its
+    /// structure base, that it didn't store
+    pub fn with_base(mut self, base: &Snapshot) -> Self {
+        if let Some(delta) = &self.structure_delta {
+            let mut nodes: BTreeMap<Path, NodeSnapshot> = base
+                .nodes
+                .iter()
+                .filter(|(path, _)| !delta.deleted.contains(*path))
+                .map(|(path, node)| (path.clone(), node.clone()))
+                .collect();
+            nodes.append(&mut self.nodes);
+            self.nodes = nodes;
+        }
+        self
+    }
+
     pub fn empty() -> Self {
         let metadata =
             SnapshotMetadata::with_message(Self::INITIAL_COMMIT_MESSAGE.to_string());
@@ -199,6 +905,18 @@ impl Snapshot {
         self.nodes.values()
     }
 
+    /// The node at `prefix` and its descendants, in path order, without scanning the
+    /// rest of the table
+    pub fn iter_subtree<'a>(
+        &'a self,
+        prefix: &'a Path,
+    ) -> impl Iterator<Item = &'a NodeSnapshot> + 'a {
+        self.nodes
+            .range(prefix..)
+            .take_while(move |(path, _)| path.starts_with(prefix))
+            .map(|(_, node)| node)
+    }
+
     pub fn iter_arc(self: Arc<Self>) -> impl Iterator<Item = NodeSnapshot> {
         NodeIterator { table: self, last_key: None }
     }
@@ -212,6 +930,222 @@ impl Snapshot {
         self.nodes.len()
     }
 
+    /// Record the new hash of a manifest rewritten in place, returns false if no node
+    /// uses the manifest
+    pub fn set_manifest_hash(&mut self, id: &ManifestId, hash: Option<String>) -> bool {
+        let mut found = false;
+        for node in self.nodes.values_mut() {
+            if let NodeData::Array(_, refs) = &mut node.node_data {
+                for manifest in refs.iter_mut().filter(|m| &m.object_id == id) {
+                    manifest.content_hash = hash.clone();
+                    found = true;
+                }
+            }
+        }
+        if found {
+            self.structure_delta = None;
+        }
+        found
+    }
+
+    /// Remove the node at `path` and all the nodes below it, returning them
+    pub fn remove_subtree(&mut self, path: &Path) -> Vec<NodeSnapshot> {
+        self.structure_delta = None;
+        let paths: Vec<Path> =
+            self.nodes.keys().filter(|node| node.starts_with(path)).cloned().collect();
+        paths.iter().filter_map(|path| self.nodes.remove(path)).collect()
+    }
+
+    /// Insert `nodes`, replacing the nodes at the same paths
+    pub fn replace_nodes(&mut self, nodes: impl IntoIterator<Item = NodeSnapshot>) {
+        self.structure_delta = None;
+        for node in nodes {
+            self.nodes.insert(node.path.clone(), node);
+        }
+    }
+
+    /// Check the invariants of the snapshot before writing it.
+    ///
+    /// Nodes must be stored under their path, with unique ids and valid array metadata,
+    /// and reference only manifests and attribute tables listed in the snapshot. Returns
+    /// every broken invariant, an empty list for a valid snapshot.
+    pub fn validate(&self) -> Vec<TableViolation> {
+        let mut violations = Vec::new();
+        if self.icechunk_snapshot_format_version
+            != format_constants::LATEST_ICECHUNK_SNAPSHOT_FORMAT
+        {
+            violations.push(TableViolation::FormatVersion {
+                found: self.icechunk_snapshot_format_version,
+                expected: format_constants::LATEST_ICECHUNK_SNAPSHOT_FORMAT,
+            });
+        }
+        let manifests: HashSet<&ManifestId> = self
+            .manifest_files
+            .iter()
+            .map(|file| &file.id)
+            .chain(self.packed_manifests.iter().map(|packed| &packed.id))
+            .collect();
+        let attributes: HashSet<&AttributesId> =
+            self.attribute_files.iter().map(|file| &file.id).collect();
+        let mut ids: HashMap<NodeId, &Path> = HashMap::new();
+        for (key, node) in self.nodes.iter() {
+            if key != &node.path {
+                violations.push(TableViolation::MisplacedNode {
+                    key: key.clone(),
+                    path: node.path.clone(),
+                });
+            }
+            if let Some(first) = ids.insert(node.id, &node.path) {
+                violations.push(TableViolation::DuplicateNodeId {
+                    id: node.id,
+                    first: first.clone(),
+                    second: node.path.clone(),
+                });
+            }
+            if let Some(UserAttributesSnapshot::Ref(atts)) = &node.user_attributes {
+                if !attributes.contains(&atts.object_id) {
+                    violations.push(TableViolation::UnknownAttributes {
+                        path: node.path.clone(),
+                        id: atts.object_id.clone(),
+                    });
+                }
+            }
+            let metadata = match &node.node_data {
+                NodeData::Array(metadata, _) | NodeData::Concatenated(metadata, _) => {
+                    metadata
+                }
+                NodeData::Group => continue,
+            };
+            if let Err(err) = metadata.validate_chunk_grid() {
+                violations.push(TableViolation::InvalidArrayMetadata {
+                    path: node.path.clone(),
+                    message: err.to_string(),
+                });
+            }
+            let NodeData::Array(_, refs) = &node.node_data else { continue };
+            for manifest in refs {
+                if !manifests.contains(&manifest.object_id) {
+                    violations.push(TableViolation::UnknownManifest {
+                        path: node.path.clone(),
+                        manifest: manifest.object_id.clone(),
+                    });
+                }
+                let ranks_match = manifest
+                    .extents
+                    .0
+                    .iter()
+                    .all(|bound| bound.0.len() == metadata.ndim());
+                if !ranks_match {
+                    violations.push(TableViolation::ExtentsRank {
+                        path: node.path.clone(),
+                        manifest: manifest.object_id.clone(),
+                        ndim: metadata.ndim(),
+                    });
+                }
+            }
+        }
+        violations
+    }
+
+    /// Check the invariants of a manifest of the snapshot, stored with `id`, before
+    /// writing it.
+    ///
+    /// Every chunk must belong to an array of the snapshot that references the manifest,
+    /// have as many coordinates as the array has dimensions, and fall inside the extents
+    /// recorded for the array. Extra data must belong to chunks of the manifest.
+    pub fn validate_manifest(
+        &self,
+        id: &ManifestId,
+        manifest: &Manifest,
+    ) -> Vec<TableViolation> {
+        let mut violations = Vec::new();
+        if manifest.icechunk_manifest_format_version
+            != format_constants::LATEST_ICECHUNK_MANIFEST_FORMAT
+        {
+            violations.push(TableViolation::FormatVersion {
+                found: manifest.icechunk_manifest_format_version,
+                expected: format_constants::LATEST_ICECHUNK_MANIFEST_FORMAT,
+            });
+        }
+        let arrays: HashMap<NodeId, (&Path, &ZarrArrayMetadata, &Vec<ManifestRef>)> =
+            self.nodes
+                .values()
+                .filter_map(|node| match &node.node_data {
+                    NodeData::Array(metadata, refs) => {
+                        Some((node.id, (&node.path, metadata, refs)))
+                    }
+                    _ => None,
+                })
+                .collect();
+        for (node, chunks) in &manifest.chunks().keys().chunk_by(|(node, _)| *node) {
+            let Some((path, metadata, refs)) = arrays.get(&node) else {
+                violations
+                    .push(TableViolation::DanglingNode { manifest: id.clone(), node });
+                continue;
+            };
+            let Some(manifest_ref) = refs.iter().find(|r| &r.object_id == id) else {
+                violations.push(TableViolation::UnreferencedManifest {
+                    path: (*path).clone(),
+                    manifest: id.clone(),
+                });
+                continue;
+            };
+            for (_, coords) in chunks {
+                if coords.0.len() != metadata.ndim() {
+                    violations.push(TableViolation::ChunkRank {
+                        path: (*path).clone(),
+                        coords: coords.clone(),
+                        ndim: metadata.ndim(),
+                    });
+                } else if !manifest_ref.extents.contains(coords) {
+                    violations.push(TableViolation::OutsideExtents {
+                        path: (*path).clone(),
+                        coords: coords.clone(),
+                    });
+                }
+            }
+        }
+        for (node, coords) in manifest.extras().keys() {
+            if !manifest.chunks().contains_key(&(*node, coords.clone())) {
+                violations.push(TableViolation::DanglingExtra {
+                    manifest: id.clone(),
+                    node: *node,
+                    coords: coords.clone(),
+                });
+            }
+        }
+        violations
+    }
+
+    /// An approximation of the memory used by the snapshot, in bytes
+    pub fn estimated_size_bytes(&self) -> u64 {
+        let nodes: usize = self
+            .nodes
+            .values()
+            .map(|node| {
+                let data = match &node.node_data {
+                    NodeData::Array(meta, manifests) => {
+                        meta.ndim() * 2 * size_of::<u64>()
+                            + manifests.len() * size_of::<ManifestRef>()
+                    }
+                    NodeData::Concatenated(meta, concatenation) => {
+                        meta.ndim() * 2 * size_of::<u64>()
+                            + concatenation.sources.len()
+                                * size_of::<ConcatenationSource>()
+                    }
+                    NodeData::Group => 0,
+                };
+                // paths are stored twice, as the key and in the node
+                size_of::<NodeSnapshot>() + 2 * node.path.0.as_str().len() + data
+            })
+            .sum();
+        let history: usize = iter::once(&self.metadata)
+            .chain(self.short_term_history.iter())
+            .map(|meta| size_of::<SnapshotMetadata>() + meta.message.len())
+            .sum();
+        (size_of::<Self>() + nodes + history) as u64
+    }
+
     #[must_use]
     pub fn is_empty(&self) -> bool {
         self.len() == 0
@@ -258,15 +1192,23 @@ impl Iterator for NodeIterator {
 #[cfg(test)]
 #[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
 mod tests {
-    use crate::format::{manifest::ManifestExtents, IcechunkFormatError};
+    use crate::{
+        format::{
+            manifest::{ChunkPayload, ManifestExtents},
+            IcechunkFormatError,
+        },
+        strategies::large_zarr_array_metadata,
+    };
 
     use super::*;
     use pretty_assertions::assert_eq;
+    use proptest::prelude::{prop_assert, prop_assert_eq};
     use std::{
         collections::HashMap,
         iter::{self},
         num::NonZeroU64,
     };
+    use test_strategy::proptest;
 
     #[test]
     fn test_get_node() -> Result<(), Box<dyn std::error::Error>> {
@@ -300,6 +1242,7 @@ mod tests {
                 Some("y".to_string()),
                 Some("t".to_string()),
             ]),
+            rectilinear_grid: None,
         };
         let zarr_meta2 = ZarrArrayMetadata {
             storage_transformers: None,
@@ -313,10 +1256,12 @@ mod tests {
         let man_ref1 = ManifestRef {
             object_id: ObjectId::random(),
             extents: ManifestExtents(vec![]),
+            content_hash: None,
         };
         let man_ref2 = ManifestRef {
             object_id: ObjectId::random(),
             extents: ManifestExtents(vec![]),
+            content_hash: None,
         };
 
         let oid = ObjectId::random();
@@ -449,4 +1394,368 @@ mod tests {
         );
         Ok(())
     }
+
+    #[test]
+    fn test_validate() {
+        let meta = ZarrArrayMetadata {
+            shape: vec![4, 4],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![
+                NonZeroU64::new(2).unwrap(),
+                NonZeroU64::new(2).unwrap(),
+            ]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            rectilinear_grid: None,
+        };
+        let manifest_id = ObjectId::random();
+        let manifest_ref = |extents: Vec<ChunkIndices>| ManifestRef {
+            object_id: manifest_id.clone(),
+            extents: ManifestExtents(extents),
+            content_hash: None,
+        };
+        let node = |path: &str, id: NodeId, node_data: NodeData| NodeSnapshot {
+            path: path.try_into().unwrap(),
+            id,
+            user_attributes: None,
+            node_data,
+        };
+        let unknown_manifest =
+            ManifestRef { object_id: ObjectId::random(), ..manifest_ref(vec![]) };
+        let unknown_attributes =
+            UserAttributesRef { object_id: ObjectId::random(), location: 0 };
+        let nodes = vec![
+            node(
+                "/a",
+                1,
+                NodeData::Array(
+                    meta.clone(),
+                    vec![manifest_ref(vec![
+                        ChunkIndices(vec![0, 0]),
+                        ChunkIndices(vec![0, 1]),
+                    ])],
+                ),
+            ),
+            node("/b", 2, NodeData::Array(meta.clone(), vec![manifest_ref(vec![])])),
+            NodeSnapshot {
+                user_attributes: Some(UserAttributesSnapshot::Ref(
+                    unknown_attributes.clone(),
+                )),
+                ..node(
+                    "/c",
+                    3,
+                    NodeData::Array(meta.clone(), vec![unknown_manifest.clone()]),
+                )
+            },
+            node("/d", 4, NodeData::Array(meta.clone(), vec![])),
+            node("/g", 1, NodeData::Group),
+        ];
+        let snapshot = Snapshot::from_iter(
+            &Snapshot::empty(),
+            None,
+            vec![ManifestFileInfo {
+                id: manifest_id.clone(),
+                format_version: format_constants::LATEST_ICECHUNK_MANIFEST_FORMAT,
+            }],
+            vec![],
+            nodes,
+        );
+        assert_eq!(
+            snapshot.validate(),
+            vec![
+                TableViolation::UnknownAttributes {
+                    path: "/c".try_into().unwrap(),
+                    id: unknown_attributes.object_id,
+                },
+                TableViolation::UnknownManifest {
+                    path: "/c".try_into().unwrap(),
+                    manifest: unknown_manifest.object_id,
+                },
+                TableViolation::DuplicateNodeId {
+                    id: 1,
+                    first: "/a".try_into().unwrap(),
+                    second: "/g".try_into().unwrap(),
+                },
+            ]
+        );
+
+        let payload = ChunkPayload::Inline("hello".into());
+        let manifest = Manifest::new(
+            [
+                ((1, ChunkIndices(vec![0, 0])), payload.clone()),
+                ((1, ChunkIndices(vec![1, 1])), payload.clone()),
+                ((2, ChunkIndices(vec![0])), payload.clone()),
+                ((4, ChunkIndices(vec![0, 0])), payload.clone()),
+                ((9, ChunkIndices(vec![0, 0])), payload),
+            ]
+            .into(),
+        );
+        assert_eq!(
+            snapshot.validate_manifest(&manifest_id, &manifest),
+            vec![
+                TableViolation::OutsideExtents {
+                    path: "/a".try_into().unwrap(),
+                    coords: ChunkIndices(vec![1, 1]),
+                },
+                TableViolation::ChunkRank {
+                    path: "/b".try_into().unwrap(),
+                    coords: ChunkIndices(vec![0]),
+                    ndim: 2,
+                },
+                TableViolation::UnreferencedManifest {
+                    path: "/d".try_into().unwrap(),
+                    manifest: manifest_id.clone(),
+                },
+                TableViolation::DanglingNode { manifest: manifest_id.clone(), node: 9 },
+            ]
+        );
+        let valid = Manifest::new(
+            [((1, ChunkIndices(vec![0, 1])), ChunkPayload::Inline("hello".into()))]
+                .into(),
+        );
+        assert_eq!(snapshot.validate_manifest(&manifest_id, &valid), vec![]);
+    }
+
+    #[test]
+    fn test_chunk_grid() {
+        let meta = ZarrArrayMetadata {
+            shape: vec![10, 0, 3],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![
+                NonZeroU64::new(3).unwrap(),
+                NonZeroU64::new(2).unwrap(),
+                NonZeroU64::new(3).unwrap(),
+            ]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            rectilinear_grid: None,
+        };
+        assert_eq!(meta.ndim(), 3);
+        assert!(!meta.is_scalar());
+        assert_eq!(meta.chunk_grid_shape(), vec![4, 0, 1]);
+        assert_eq!(meta.num_chunks().unwrap(), 0);
+        assert!(!meta.valid_chunk_coord(&ChunkIndices(vec![0, 0, 0])));
+
+        let meta = ZarrArrayMetadata { shape: vec![10, 1, 3], ..meta };
+        assert_eq!(meta.num_chunks().unwrap(), 4);
+        assert!(meta.valid_chunk_coord(&ChunkIndices(vec![3, 0, 0])));
+        assert!(!meta.valid_chunk_coord(&ChunkIndices(vec![4, 0, 0])));
+        assert!(!meta.valid_chunk_coord(&ChunkIndices(vec![0, 0])));
+        assert!(!meta.valid_chunk_coord(&ChunkIndices(vec![])));
+
+        let scalar = ZarrArrayMetadata {
+            shape: vec![],
+            chunk_shape: ChunkShape(vec![]),
+            dimension_names: None,
+            ..meta
+        };
+        assert_eq!(scalar.ndim(), 0);
+        assert!(scalar.is_scalar());
+        assert_eq!(scalar.chunk_grid_shape(), Vec::<u64>::new());
+        assert_eq!(scalar.num_chunks().unwrap(), 1);
+        assert!(scalar.valid_chunk_coord(&ChunkIndices(vec![])));
+        assert!(!scalar.valid_chunk_coord(&ChunkIndices(vec![0])));
+    }
+
+    #[test]
+    fn test_axis_by_dimension_name() {
+        let meta = ZarrArrayMetadata {
+            shape: vec![10, 4, 4],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![NonZeroU64::new(2).unwrap(); 3]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: Some(vec![
+                Some("time".to_string()),
+                None,
+                Some("lon".to_string()),
+            ]),
+            rectilinear_grid: None,
+        };
+        assert_eq!(meta.axis_index("time").unwrap(), 0);
+        assert_eq!(meta.axis_index("lon").unwrap(), 2);
+        assert_eq!(meta.axis_index(1).unwrap(), 1);
+        assert!(matches!(
+            meta.axis_index("lat"),
+            Err(IcechunkFormatError::DimensionNotFound { name, .. }) if name == "lat"
+        ));
+        assert!(matches!(
+            meta.axis_index(3),
+            Err(IcechunkFormatError::AxisOutOfBounds { axis: 3, ndim: 3 })
+        ));
+
+        assert_eq!(meta.after_append("time", 4).unwrap().shape, vec![14, 4, 4]);
+        assert_eq!(meta.truncated("time", 2).unwrap().shape, vec![4, 4, 4]);
+        assert_eq!(
+            meta.axis_chunk_spans("lon", &(1..3)).unwrap(),
+            vec![(0, 0..2), (1, 2..4)]
+        );
+        assert_eq!(
+            meta.region_along("time", 1..3).unwrap(),
+            ChunkRegion(vec![1..3, 0..u64::MAX, 0..u64::MAX])
+        );
+
+        let ambiguous = ZarrArrayMetadata {
+            dimension_names: Some(vec![Some("x".to_string()); 3]),
+            ..meta
+        };
+        assert!(matches!(
+            ambiguous.axis_index("x"),
+            Err(IcechunkFormatError::AmbiguousDimension { .. })
+        ));
+    }
+
+    #[test]
+    fn test_rectilinear_chunk_grid() {
+        let size = |n| NonZeroU64::new(n).unwrap();
+        let grid = RectilinearGrid(vec![
+            DimensionChunks::Sizes(vec![size(2), size(5), size(3)]),
+            DimensionChunks::Regular(size(4)),
+        ]);
+        let meta = ZarrArrayMetadata {
+            shape: vec![10, 6],
+            data_type: DataType::Int32,
+            chunk_shape: grid.max_chunk_shape(),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            rectilinear_grid: Some(grid),
+        };
+        assert!(meta.validate_chunk_grid().is_ok());
+        assert_eq!(meta.chunk_shape, ChunkShape(vec![size(5), size(4)]));
+        assert_eq!(meta.chunk_grid_shape(), vec![3, 2]);
+        assert_eq!(meta.num_chunks().unwrap(), 6);
+        assert!(meta.valid_chunk_coord(&ChunkIndices(vec![2, 1])));
+        assert!(!meta.valid_chunk_coord(&ChunkIndices(vec![3, 0])));
+        assert_eq!(meta.chunk_origin(&ChunkIndices(vec![2, 1])).unwrap(), vec![7, 4]);
+        assert_eq!(meta.chunk_containing(&[1, 3]).unwrap(), ChunkIndices(vec![0, 0]));
+        assert_eq!(meta.chunk_containing(&[6, 5]).unwrap(), ChunkIndices(vec![1, 1]));
+        assert_eq!(meta.chunk_containing(&[7, 0]).unwrap(), ChunkIndices(vec![2, 0]));
+        assert!(meta.chunk_containing(&[10, 0]).is_err());
+
+        // appends along irregular dimensions add a chunk, regular ones grow the grid
+        let appended = meta.after_append(0, 8).unwrap();
+        assert!(appended.validate_chunk_grid().is_ok());
+        assert_eq!(appended.chunk_grid_shape(), vec![4, 2]);
+        assert_eq!(appended.chunk_shape, ChunkShape(vec![size(8), size(4)]));
+        assert_eq!(
+            appended.chunk_containing(&[17, 0]).unwrap(),
+            ChunkIndices(vec![3, 0])
+        );
+        let appended = meta.after_append(1, 3).unwrap();
+        assert_eq!(appended.chunk_grid_shape(), vec![3, 3]);
+
+        let wrong_total = ZarrArrayMetadata { shape: vec![11, 6], ..meta.clone() };
+        assert!(matches!(
+            wrong_total.validate_chunk_grid(),
+            Err(IcechunkFormatError::InvalidChunkGrid { .. })
+        ));
+        let wrong_shape =
+            ZarrArrayMetadata { chunk_shape: ChunkShape(vec![size(2), size(4)]), ..meta };
+        assert!(wrong_shape.validate_chunk_grid().is_err());
+    }
+
+    #[test]
+    fn test_chunk_grid_large_extents() {
+        let meta = ZarrArrayMetadata {
+            shape: vec![u64::MAX, 1 << 40],
+            data_type: DataType::Int8,
+            chunk_shape: ChunkShape(vec![
+                NonZeroU64::new(1).unwrap(),
+                NonZeroU64::new(2).unwrap(),
+            ]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int8(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            rectilinear_grid: None,
+        };
+        assert_eq!(meta.chunk_grid_shape(), vec![u64::MAX, 1 << 39]);
+        assert!(matches!(
+            meta.num_chunks(),
+            Err(IcechunkFormatError::ChunkGridOverflow { .. })
+        ));
+
+        let last = ChunkIndices(vec![u64::MAX - 1, (1 << 39) - 1]);
+        assert!(meta.valid_chunk_coord(&last));
+        assert!(!meta.valid_chunk_coord(&ChunkIndices(vec![0, 1 << 39])));
+        assert_eq!(meta.chunk_origin(&last).unwrap(), vec![u64::MAX - 1, (1 << 40) - 2]);
+        assert_eq!(meta.chunk_containing(&[u64::MAX - 1, (1 << 40) - 1]).unwrap(), last);
+        assert!(matches!(
+            meta.chunk_containing(&[0, 1 << 40]),
+            Err(IcechunkFormatError::ChunkCoordinatesOutOfBounds { .. })
+        ));
+
+        assert_eq!(
+            meta.shape_after_append(1, 10).unwrap(),
+            vec![u64::MAX, (1 << 40) + 10]
+        );
+        assert!(matches!(
+            meta.shape_after_append(0, 1),
+            Err(IcechunkFormatError::ChunkGridOverflow { .. })
+        ));
+        assert!(matches!(
+            meta.shape_after_append(2, 1),
+            Err(IcechunkFormatError::AxisOutOfBounds { axis: 2, ndim: 2 })
+        ));
+    }
+
+    #[proptest]
+    fn test_chunk_grid_properties(
+        #[strategy(large_zarr_array_metadata())] meta: ZarrArrayMetadata,
+    ) {
+        let grid = meta.chunk_grid_shape();
+        prop_assert_eq!(grid.len(), meta.ndim());
+
+        // the grid covers the whole array, and not a single chunk more
+        for ((size, chunk_size), n) in
+            meta.shape.iter().zip(meta.chunk_shape.0.iter()).zip(&grid)
+        {
+            let (size, chunk_size, n) =
+                (*size as u128, chunk_size.get() as u128, *n as u128);
+            prop_assert!(n * chunk_size >= size);
+            prop_assert!(n == 0 || (n - 1) * chunk_size < size);
+        }
+
+        let expected_total =
+            grid.iter().try_fold(1u128, |acc, n| acc.checked_mul(*n as u128));
+        match meta.num_chunks() {
+            Ok(total) => prop_assert_eq!(Some(total as u128), expected_total),
+            Err(IcechunkFormatError::ChunkGridOverflow { .. }) => {
+                prop_assert!(expected_total.is_none_or(|t| t > u64::MAX as u128))
+            }
+            Err(err) => prop_assert!(false, "unexpected error {err}"),
+        }
+
+        if grid.iter().all(|n| *n > 0) {
+            let last = ChunkIndices(grid.iter().map(|n| n - 1).collect());
+            prop_assert!(meta.valid_chunk_coord(&last));
+            let origin = meta.chunk_origin(&last).unwrap();
+            prop_assert!(origin.iter().zip(meta.shape.iter()).all(|(o, size)| o < size));
+            prop_assert_eq!(meta.chunk_containing(&origin).unwrap(), last.clone());
+
+            for axis in 0..meta.ndim() {
+                let mut past_end = last.clone();
+                past_end.0[axis] += 1;
+                prop_assert!(!meta.valid_chunk_coord(&past_end));
+                prop_assert!(meta.chunk_origin(&past_end).is_err());
+            }
+        }
+
+        for axis in 0..meta.ndim() {
+            let appended = meta.shape_after_append(axis, 1);
+            prop_assert_eq!(appended.is_ok(), meta.shape[axis] < u64::MAX);
+        }
+    }
 }
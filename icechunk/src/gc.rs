@@ -0,0 +1,604 @@
+//! Garbage collection of the objects no longer reachable from any ref.
+//!
+//! Snapshots are reachable if a branch tip or a tag points to them, or to one of their
+//! descendants. Manifests, attribute tables and chunks are reachable if a reachable
+//! snapshot uses them.
+//! Objects written after [`GcConfig::older_than`] are never collected, they can belong to
+//! sessions that haven't committed yet.
+//!
+//! With [`GcMode::Trash`] collected objects are moved to the trash of the storage
+//! instead of deleted. Repositories can't see them there, but [`restore_trash`] brings
+//! them back, until [`purge_trash`] deletes the ones trashed before its cutoff.
+//!
+//! The set of reachable chunks can be too large for memory in huge repositories. With
+//! [`GcConfig::with_chunk_filter`] it is replaced by a bloom filter, of a fixed size
+//! decided by the expected number of chunks and the false positive rate. The filter
+//! never misses a reachable chunk, so the collection stays safe, but a fraction of the
+//! unreachable chunks are kept, until a later collection.
+use std::{
+    collections::HashSet,
+    hash::{BuildHasher, BuildHasherDefault, DefaultHasher, Hash},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    format::{
+        manifest::ChunkPayload, snapshot::NodeData, AttributesId, ChunkId, FileTypeTag,
+        ManifestId, ObjectId, SnapshotId,
+    },
+    progress::{NoProgress, ProgressListener, ProgressOperation, ProgressTracker},
+    refs::{fetch_branch_tip, fetch_tag, list_refs, Ref, RefError},
+    storage::{ObjectKind, ObjectLocation},
+    Storage, StorageError,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GcMode {
+    /// Delete collected objects permanently
+    #[default]
+    Delete,
+    /// Move collected objects to the trash, see [`purge_trash`]
+    Trash,
+}
+
+/// The size of the bloom filter of reachable chunks
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChunkFilterConfig {
+    /// The number of reachable chunks the filter is sized for, with more the false
+    /// positive rate grows
+    pub expected_chunks: u64,
+    /// The fraction of unreachable chunks the filter reports as reachable, and kept
+    pub false_positive_rate: f64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GcConfig {
+    /// Only objects written before this time are collected
+    pub older_than: DateTime<Utc>,
+    pub mode: GcMode,
+    /// How many manifests are read at the same time
+    pub manifest_fetch_concurrency: u16,
+    /// Track reachable chunks with a bloom filter instead of a set
+    pub chunk_filter: Option<ChunkFilterConfig>,
+}
+
+impl GcConfig {
+    pub fn new(older_than: DateTime<Utc>) -> Self {
+        Self {
+            older_than,
+            mode: GcMode::default(),
+            manifest_fetch_concurrency: 4,
+            chunk_filter: None,
+        }
+    }
+
+    pub fn with_mode(self, mode: GcMode) -> Self {
+        Self { mode, ..self }
+    }
+
+    pub fn with_manifest_fetch_concurrency(
+        self,
+        manifest_fetch_concurrency: u16,
+    ) -> Self {
+        Self { manifest_fetch_concurrency, ..self }
+    }
+
+    pub fn with_chunk_filter(self, chunk_filter: ChunkFilterConfig) -> Self {
+        Self { chunk_filter: Some(chunk_filter), ..self }
+    }
+}
+
+/// A bloom filter of object ids, it can report ids that were never inserted, but never
+/// misses one that was
+#[derive(Clone, Debug)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    pub fn new(config: &ChunkFilterConfig) -> Self {
+        let items = config.expected_chunks.max(1) as f64;
+        let rate = config.false_positive_rate.clamp(f64::MIN_POSITIVE, 0.5);
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-items * rate.ln() / (ln2 * ln2)).ceil().max(64.0);
+        let hashes = ((bits / items) * ln2).round().clamp(1.0, 32.0) as u32;
+        Self { bits: vec![0; (bits as usize).div_ceil(64)], hashes }
+    }
+
+    /// The size of the filter in bytes
+    pub fn size_bytes(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    pub fn insert<T: Hash>(&mut self, item: &T) {
+        for bit in self.bit_indices(item) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// False only if `item` was never inserted
+    pub fn contains<T: Hash>(&self, item: &T) -> bool {
+        self.bit_indices(item).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn bit_indices<T: Hash>(&self, item: &T) -> impl Iterator<Item = usize> {
+        // double hashing, the hasher has fixed keys so filters are reproducible
+        let hasher = BuildHasherDefault::<DefaultHasher>::default();
+        let first = hasher.hash_one(item);
+        let second = hasher.hash_one((item, 0x9e37_79b9_7f4a_7c15_u64)) | 1;
+        let len = self.bits.len() as u64 * 64;
+        (0..u64::from(self.hashes))
+            .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % len) as usize)
+    }
+}
+
+/// The chunks used by reachable manifests
+enum ReachableChunks {
+    Exact(HashSet<ChunkId>),
+    Filter(BloomFilter),
+}
+
+impl ReachableChunks {
+    fn insert(&mut self, id: ChunkId) {
+        match self {
+            ReachableChunks::Exact(set) => {
+                set.insert(id);
+            }
+            ReachableChunks::Filter(filter) => filter.insert(&id),
+        }
+    }
+
+    fn contains(&self, id: &ChunkId) -> bool {
+        match self {
+            ReachableChunks::Exact(set) => set.contains(id),
+            ReachableChunks::Filter(filter) => filter.contains(id),
+        }
+    }
+}
+
+/// The number of objects affected, per kind
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GcSummary {
+    pub snapshots: u64,
+    pub manifests: u64,
+    pub chunks: u64,
+    pub attributes: u64,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum GcError {
+    #[error("storage error {0}")]
+    Storage(#[from] StorageError),
+    #[error("error reading refs {0}")]
+    Ref(#[from] RefError),
+}
+
+pub type GcResult<A> = Result<A, GcError>;
+
+/// Delete, or move to the trash, the objects not reachable from any ref
+pub async fn garbage_collect(
+    storage: &dyn Storage,
+    config: &GcConfig,
+) -> GcResult<GcSummary> {
+    garbage_collect_with_progress(storage, config, Arc::new(NoProgress)).await
+}
+
+/// Like [`garbage_collect`], reporting each collected object to `progress` as a
+/// [`ProgressOperation::GarbageCollection`]
+pub async fn garbage_collect_with_progress(
+    storage: &dyn Storage,
+    config: &GcConfig,
+    progress: Arc<dyn ProgressListener>,
+) -> GcResult<GcSummary> {
+    let progress =
+        ProgressTracker::new(progress, ProgressOperation::GarbageCollection, None);
+    let Reachable { snapshots, manifests, attributes, packs } =
+        reachable_objects(storage, reachable_snapshots(storage).await?).await?;
+    let mut chunks = match &config.chunk_filter {
+        Some(filter) => ReachableChunks::Filter(BloomFilter::new(filter)),
+        None => ReachableChunks::Exact(HashSet::new()),
+    };
+    for id in packs {
+        chunks.insert(id);
+    }
+    // manifests are fetched concurrently, and dropped once their chunks are recorded
+    let mut fetched = stream::iter(manifests.iter())
+        .map(|id| storage.fetch_manifests(id))
+        .buffer_unordered(usize::from(config.manifest_fetch_concurrency.max(1)));
+    while let Some(manifest) = fetched.try_next().await? {
+        for payload in manifest.chunks().values() {
+            if let ChunkPayload::Ref(chunk_ref) = payload {
+                chunks.insert(chunk_ref.id.clone());
+            }
+        }
+    }
+
+    // snapshots go last, so an interrupted collection leaves no snapshot without objects
+    Ok(GcSummary {
+        chunks: sweep(
+            storage,
+            ObjectKind::Chunk,
+            |id| chunks.contains(id),
+            config,
+            &progress,
+        )
+        .await?,
+        manifests: sweep(
+            storage,
+            ObjectKind::Manifest,
+            |id| manifests.contains(id),
+            config,
+            &progress,
+        )
+        .await?,
+        attributes: sweep(
+            storage,
+            ObjectKind::Attributes,
+            |id| attributes.contains(id),
+            config,
+            &progress,
+        )
+        .await?,
+        snapshots: sweep(
+            storage,
+            ObjectKind::Snapshot,
+            |id| snapshots.contains(id),
+            config,
+            &progress,
+        )
+        .await?,
+    })
+}
+
+/// Permanently delete the objects moved to the trash before `older_than`
+pub async fn purge_trash(
+    storage: &dyn Storage,
+    older_than: DateTime<Utc>,
+) -> GcResult<GcSummary> {
+    Ok(GcSummary {
+        snapshots: purge(storage, ObjectKind::Snapshot, older_than).await?,
+        manifests: purge(storage, ObjectKind::Manifest, older_than).await?,
+        attributes: purge(storage, ObjectKind::Attributes, older_than).await?,
+        chunks: purge(storage, ObjectKind::Chunk, older_than).await?,
+    })
+}
+
+/// Move every object in the trash back to the repository
+pub async fn restore_trash(storage: &dyn Storage) -> GcResult<GcSummary> {
+    // chunks first, so restored snapshots are readable as soon as they appear
+    Ok(GcSummary {
+        chunks: restore(storage, ObjectKind::Chunk).await?,
+        manifests: restore(storage, ObjectKind::Manifest).await?,
+        attributes: restore(storage, ObjectKind::Attributes).await?,
+        snapshots: restore(storage, ObjectKind::Snapshot).await?,
+    })
+}
+
+/// The snapshots, manifests and attribute tables used by some snapshots
+pub(crate) struct Reachable {
+    pub snapshots: HashSet<SnapshotId>,
+    pub manifests: HashSet<ManifestId>,
+    pub attributes: HashSet<AttributesId>,
+    /// Packfiles are stored as chunks
+    pub packs: HashSet<ChunkId>,
+}
+
+/// The objects used by `snapshots`, including the snapshots concatenated arrays read from
+pub(crate) async fn reachable_objects(
+    storage: &dyn Storage,
+    mut snapshots: HashSet<SnapshotId>,
+) -> GcResult<Reachable> {
+    let mut manifests = HashSet::new();
+    let mut attributes = HashSet::new();
+    let mut packs = HashSet::new();
+    let mut pending: Vec<SnapshotId> = snapshots.iter().cloned().collect();
+    while let Some(id) = pending.pop() {
+        let snapshot = storage.fetch_snapshot(&id).await?;
+        manifests.extend(snapshot.manifest_files.iter().map(|info| info.id.clone()));
+        attributes.extend(snapshot.attribute_files.iter().map(|info| info.id.clone()));
+        packs.extend(snapshot.packed_manifests.iter().map(|packed| packed.pack.clone()));
+        // the snapshot can't be read without the base of its node table
+        if let Some(base) = snapshot.structure_base() {
+            if snapshots.insert(base.clone()) {
+                pending.push(base.clone());
+            }
+        }
+        for node in snapshot.iter() {
+            match &node.node_data {
+                NodeData::Array(_, refs) => {
+                    manifests.extend(refs.iter().map(|mref| mref.object_id.clone()));
+                }
+                // the sources of concatenated arrays are reachable too
+                NodeData::Concatenated(_, concatenation) => {
+                    for source in concatenation.sources.iter() {
+                        if snapshots.insert(source.snapshot.clone()) {
+                            pending.push(source.snapshot.clone());
+                        }
+                    }
+                }
+                NodeData::Group => {}
+            }
+        }
+    }
+    Ok(Reachable { snapshots, manifests, attributes, packs })
+}
+
+/// The snapshot `r` points to and its ancestors
+pub(crate) async fn ref_snapshots(
+    storage: &dyn Storage,
+    r: &Ref,
+) -> GcResult<HashSet<SnapshotId>> {
+    let tip = match r {
+        Ref::Tag(name) => fetch_tag(storage, name).await?,
+        Ref::Branch(name) => fetch_branch_tip(storage, name).await?,
+    };
+    let snapshot = storage.fetch_snapshot(&tip.snapshot).await?;
+    let mut res: HashSet<_> =
+        snapshot.short_term_history.iter().map(|meta| meta.id.clone()).collect();
+    res.insert(tip.snapshot);
+    Ok(res)
+}
+
+async fn reachable_snapshots(storage: &dyn Storage) -> GcResult<HashSet<SnapshotId>> {
+    let mut res = HashSet::new();
+    for r in list_refs(storage).await? {
+        res.extend(ref_snapshots(storage, &r).await?);
+    }
+    Ok(res)
+}
+
+async fn sweep<const SIZE: usize, T: FileTypeTag>(
+    storage: &dyn Storage,
+    kind: ObjectKind,
+    reachable: impl Fn(&ObjectId<SIZE, T>) -> bool,
+    config: &GcConfig,
+    progress: &ProgressTracker,
+) -> GcResult<u64> {
+    let garbage: Vec<_> = storage
+        .list_objects(kind, ObjectLocation::Live)
+        .await?
+        .try_filter(|object| {
+            // keys that are not object ids were not written by icechunk, they are kept
+            let unreachable = ObjectId::<SIZE, T>::try_from(object.id.as_str())
+                .is_ok_and(|id| !reachable(&id));
+            futures::future::ready(unreachable && object.modified_at < config.older_than)
+        })
+        .try_collect()
+        .await?;
+    for object in garbage.iter() {
+        match config.mode {
+            GcMode::Delete => {
+                storage.delete_object(kind, &object.id, ObjectLocation::Live).await?
+            }
+            GcMode::Trash => {
+                storage.move_object(kind, &object.id, ObjectLocation::Trash).await?
+            }
+        }
+        progress.advance(1, 0);
+    }
+    Ok(garbage.len() as u64)
+}
+
+async fn purge(
+    storage: &dyn Storage,
+    kind: ObjectKind,
+    older_than: DateTime<Utc>,
+) -> GcResult<u64> {
+    let expired: Vec<_> = storage
+        .list_objects(kind, ObjectLocation::Trash)
+        .await?
+        .try_filter(|object| futures::future::ready(object.modified_at < older_than))
+        .try_collect()
+        .await?;
+    for object in expired.iter() {
+        storage.delete_object(kind, &object.id, ObjectLocation::Trash).await?;
+    }
+    Ok(expired.len() as u64)
+}
+
+async fn restore(storage: &dyn Storage, kind: ObjectKind) -> GcResult<u64> {
+    let trashed: Vec<_> =
+        storage.list_objects(kind, ObjectLocation::Trash).await?.try_collect().await?;
+    for object in trashed.iter() {
+        storage.move_object(kind, &object.id, ObjectLocation::Live).await?;
+    }
+    Ok(trashed.len() as u64)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use bytes::Bytes;
+    use chrono::TimeDelta;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{
+            attributes::AttributesTableBuilder, snapshot::Snapshot, ByteRange, ChunkId,
+            ChunkIndices, Path,
+        },
+        metadata::{FillValue, UserAttributes},
+        progress::{Progress, RecordingProgress},
+        repository::{get_chunk, resolve_user_attributes},
+        synthetic::{generate, vector_metadata, SyntheticRepoConfig},
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_garbage_collect_to_trash() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array: Path = "/array".try_into()?;
+        repo.add_group(Path::root()).await?;
+        repo.add_array(array.clone(), vector_metadata(1, 1, FillValue::UInt8(0))).await?;
+        let data = Bytes::from(vec![1; 1024]);
+        let payload = repo.get_chunk_writer()(data.clone()).await?;
+        repo.set_chunk_ref(array.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+        let atts = UserAttributes::try_new(br#"{"units": "m"}"#)?;
+        repo.set_user_attributes(array.clone(), Some(atts)).await?;
+        repo.commit("main", "first", None).await?;
+
+        // objects left behind by a session that never committed
+        let orphan_chunk = ChunkId::random();
+        storage.write_chunk(orphan_chunk.clone(), Bytes::from_static(b"orphan")).await?;
+        let orphan_snapshot = Arc::new(Snapshot::empty());
+        let orphan_snapshot_id = orphan_snapshot.metadata.id.clone();
+        storage.write_snapshot(orphan_snapshot_id.clone(), orphan_snapshot).await?;
+        let mut builder = AttributesTableBuilder::default();
+        builder.add(UserAttributes::try_new(br#"{"orphan": true}"#)?);
+        let (orphan_table, _) = builder.build();
+        let orphan_table_id = orphan_table.id();
+        storage.write_attributes(orphan_table_id.clone(), Arc::new(orphan_table)).await?;
+
+        let recent =
+            GcConfig::new(Utc::now() - TimeDelta::hours(1)).with_mode(GcMode::Trash);
+        assert_eq!(
+            garbage_collect(storage.as_ref(), &recent).await?,
+            GcSummary::default()
+        );
+
+        let config =
+            GcConfig::new(Utc::now() + TimeDelta::seconds(1)).with_mode(GcMode::Trash);
+        let expected = GcSummary { snapshots: 1, manifests: 0, chunks: 1, attributes: 1 };
+        let progress = Arc::new(RecordingProgress::default());
+        assert_eq!(
+            garbage_collect_with_progress(storage.as_ref(), &config, progress.clone())
+                .await?,
+            expected
+        );
+        assert_eq!(
+            progress.updates().last(),
+            Some(&(
+                ProgressOperation::GarbageCollection,
+                Progress { done: 3, total: None, bytes: 0 }
+            ))
+        );
+        assert!(storage.fetch_chunk(&orphan_chunk, &ByteRange::ALL).await.is_err());
+        assert!(storage.fetch_snapshot(&orphan_snapshot_id).await.is_err());
+        assert!(storage.fetch_attributes(&orphan_table_id).await.is_err());
+
+        // reachable objects are untouched
+        let repo =
+            Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        let reader = repo
+            .get_chunk_reader(&array, &ChunkIndices(vec![0]), &ByteRange::ALL)
+            .await?;
+        assert_eq!(get_chunk(reader).await?, Some(data));
+        let atts = repo.get_node(&array).await?.user_attributes;
+        assert!(resolve_user_attributes(storage.as_ref(), atts).await?.is_some());
+
+        assert_eq!(restore_trash(storage.as_ref()).await?, expected);
+        assert_eq!(
+            storage.fetch_chunk(&orphan_chunk, &ByteRange::ALL).await?,
+            Bytes::from_static(b"orphan")
+        );
+        assert!(storage.fetch_snapshot(&orphan_snapshot_id).await.is_ok());
+        assert!(storage.fetch_attributes(&orphan_table_id).await.is_ok());
+
+        assert_eq!(garbage_collect(storage.as_ref(), &config).await?, expected);
+        // the retention window starts when objects are trashed
+        assert_eq!(
+            purge_trash(storage.as_ref(), Utc::now() - TimeDelta::hours(1)).await?,
+            GcSummary::default()
+        );
+        assert_eq!(
+            purge_trash(storage.as_ref(), Utc::now() + TimeDelta::seconds(1)).await?,
+            expected
+        );
+        assert_eq!(restore_trash(storage.as_ref()).await?, GcSummary::default());
+        assert!(storage.fetch_chunk(&orphan_chunk, &ByteRange::ALL).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_garbage_collect_delete() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        let orphan_chunk = ChunkId::random();
+        storage.write_chunk(orphan_chunk.clone(), Bytes::from_static(b"orphan")).await?;
+
+        let config = GcConfig::new(Utc::now() + TimeDelta::seconds(1));
+        assert_eq!(
+            garbage_collect(storage.as_ref(), &config).await?,
+            GcSummary { snapshots: 0, manifests: 0, chunks: 1, attributes: 0 }
+        );
+        assert!(storage.fetch_chunk(&orphan_chunk, &ByteRange::ALL).await.is_err());
+        assert_eq!(restore_trash(storage.as_ref()).await?, GcSummary::default());
+        assert!(storage.fetch_snapshot(repo.snapshot_id()).await.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_garbage_collect_synthetic_history() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let config =
+            SyntheticRepoConfig { commits: 5, dangling_chunks: 3, ..Default::default() };
+        let synthetic = generate(Arc::clone(&storage), &config).await?;
+
+        // the whole history is reachable from the branch
+        let config = GcConfig::new(Utc::now() + TimeDelta::seconds(1));
+        assert_eq!(
+            garbage_collect(storage.as_ref(), &config).await?,
+            GcSummary { snapshots: 0, manifests: 0, chunks: 3, attributes: 0 }
+        );
+        for id in synthetic.dangling_chunks.iter() {
+            assert!(storage.fetch_chunk(id, &ByteRange::ALL).await.is_err());
+        }
+        for id in synthetic.committed_chunks.iter() {
+            storage.fetch_chunk(id, &ByteRange::ALL).await?;
+        }
+        for id in synthetic.snapshots.iter() {
+            storage.fetch_snapshot(id).await?;
+        }
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_garbage_collect_with_chunk_filter() -> Result<(), Box<dyn Error>> {
+        let config =
+            ChunkFilterConfig { expected_chunks: 10_000, false_positive_rate: 0.01 };
+        let mut filter = BloomFilter::new(&config);
+        // about 9.6 bits per item
+        assert_eq!(filter.size_bytes(), 11_984);
+        let inserted: Vec<_> = (0..10_000).map(|_| ChunkId::random()).collect();
+        for id in inserted.iter() {
+            filter.insert(id);
+        }
+        assert!(inserted.iter().all(|id| filter.contains(id)));
+        let false_positives =
+            (0..10_000).filter(|_| filter.contains(&ChunkId::random())).count();
+        assert!(false_positives < 300, "{false_positives} false positives");
+
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let synthetic =
+            SyntheticRepoConfig { commits: 5, dangling_chunks: 3, ..Default::default() };
+        let synthetic = generate(Arc::clone(&storage), &synthetic).await?;
+        let config = GcConfig::new(Utc::now() + TimeDelta::seconds(1))
+            .with_manifest_fetch_concurrency(2)
+            .with_chunk_filter(ChunkFilterConfig {
+                expected_chunks: 1_000,
+                false_positive_rate: 1e-12,
+            });
+        assert_eq!(
+            garbage_collect(storage.as_ref(), &config).await?,
+            GcSummary { snapshots: 0, manifests: 0, chunks: 3, attributes: 0 }
+        );
+        for id in synthetic.committed_chunks.iter() {
+            storage.fetch_chunk(id, &ByteRange::ALL).await?;
+        }
+        Ok(())
+    }
+}
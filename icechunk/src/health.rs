@@ -0,0 +1,192 @@
+//! Checks run when opening a repository.
+//!
+//! Misconfigured credentials, a wrong prefix or a repository written by a newer version
+//! of the library usually surface as an obscure error deep inside the first real
+//! operation. [`crate::Repository::health_check`] runs a few cheap checks up front and
+//! reports each of them, so applications can fail early with a clear message.
+use std::fmt;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::ErrorKind,
+    format::{format_constants, ChunkId},
+    refs::{fetch_branch_tip, Ref, RefData},
+    Storage,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CheckStatus {
+    Passed,
+    Failed {
+        kind: ErrorKind,
+        message: String,
+    },
+    /// Not requested, or not possible because an earlier check failed
+    Skipped,
+}
+
+impl CheckStatus {
+    fn failed(kind: ErrorKind, message: impl fmt::Display) -> Self {
+        CheckStatus::Failed { kind, message: message.to_string() }
+    }
+
+    pub fn is_failed(&self) -> bool {
+        matches!(self, CheckStatus::Failed { .. })
+    }
+}
+
+/// The outcome of every check of [`crate::Repository::health_check`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthReport {
+    /// Refs can be listed
+    pub storage_reachable: CheckStatus,
+    /// The main branch exists, so there is a repository under the prefix
+    pub main_branch: CheckStatus,
+    /// The tip of the main branch was written with format versions this library reads
+    pub format_version: CheckStatus,
+    /// A probe object can be written and deleted, skipped unless requested
+    pub writable: CheckStatus,
+}
+
+impl HealthReport {
+    pub fn is_healthy(&self) -> bool {
+        self.checks().all(|(_, status)| !status.is_failed())
+    }
+
+    /// Every check by name, in the order they run
+    pub fn checks(&self) -> impl Iterator<Item = (&'static str, &CheckStatus)> {
+        [
+            ("storage_reachable", &self.storage_reachable),
+            ("main_branch", &self.main_branch),
+            ("format_version", &self.format_version),
+            ("writable", &self.writable),
+        ]
+        .into_iter()
+    }
+}
+
+pub(crate) async fn health_check(
+    storage: &dyn Storage,
+    probe_writes: bool,
+) -> HealthReport {
+    let mut report = HealthReport {
+        storage_reachable: CheckStatus::Skipped,
+        main_branch: CheckStatus::Skipped,
+        format_version: CheckStatus::Skipped,
+        writable: CheckStatus::Skipped,
+    };
+
+    report.storage_reachable = match storage.ref_names().await {
+        Ok(_) => CheckStatus::Passed,
+        Err(err) => CheckStatus::failed(err.kind(), err),
+    };
+    if report.storage_reachable.is_failed() {
+        return report;
+    }
+
+    if probe_writes {
+        report.writable = probe_write(storage).await;
+    }
+
+    let tip = match fetch_branch_tip(storage, Ref::DEFAULT_BRANCH).await {
+        Ok(tip) => tip,
+        Err(err) => {
+            report.main_branch = CheckStatus::failed(err.kind(), err);
+            return report;
+        }
+    };
+    report.main_branch = CheckStatus::Passed;
+    report.format_version = check_format_version(storage, &tip).await;
+    report
+}
+
+async fn probe_write(storage: &dyn Storage) -> CheckStatus {
+    let id = ChunkId::random();
+    if let Err(err) = storage
+        .write_chunk(id.clone(), Bytes::from_static(b"icechunk health check"))
+        .await
+    {
+        return CheckStatus::failed(err.kind(), err);
+    }
+    match storage.delete_chunk(&id).await {
+        Ok(()) => CheckStatus::Passed,
+        Err(err) => {
+            CheckStatus::failed(err.kind(), format!("cannot delete probe: {err}"))
+        }
+    }
+}
+
+async fn check_format_version(storage: &dyn Storage, tip: &RefData) -> CheckStatus {
+    let snapshot = match storage.fetch_snapshot(&tip.snapshot).await {
+        Ok(snapshot) => snapshot,
+        Err(err) => return CheckStatus::failed(err.kind(), err),
+    };
+    let snapshot_version = snapshot.icechunk_snapshot_format_version;
+    if snapshot_version > format_constants::LATEST_ICECHUNK_SNAPSHOT_FORMAT {
+        return CheckStatus::failed(
+            ErrorKind::Unsupported,
+            format!(
+                "snapshot {} has format version {snapshot_version}, newer than {}",
+                tip.snapshot,
+                format_constants::LATEST_ICECHUNK_SNAPSHOT_FORMAT
+            ),
+        );
+    }
+    match snapshot.manifest_files.iter().find(|file| {
+        file.format_version > format_constants::LATEST_ICECHUNK_MANIFEST_FORMAT
+    }) {
+        Some(file) => CheckStatus::failed(
+            ErrorKind::Unsupported,
+            format!(
+                "manifest {} has format version {}, newer than {}",
+                file.id,
+                file.format_version,
+                format_constants::LATEST_ICECHUNK_MANIFEST_FORMAT
+            ),
+        ),
+        None => CheckStatus::Passed,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{ObjectStorage, Repository};
+
+    #[tokio::test]
+    async fn test_health_check() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+
+        let report = Repository::health_check(storage.as_ref(), true).await;
+        assert!(!report.is_healthy());
+        assert_eq!(report.storage_reachable, CheckStatus::Passed);
+        assert_eq!(report.writable, CheckStatus::Passed);
+        assert!(matches!(
+            report.main_branch,
+            CheckStatus::Failed { kind: ErrorKind::NotFound, .. }
+        ));
+        assert_eq!(report.format_version, CheckStatus::Skipped);
+
+        Repository::init(Arc::clone(&storage), false).await?;
+        let report = Repository::health_check(storage.as_ref(), false).await;
+        assert!(report.is_healthy());
+        assert_eq!(
+            report.checks().map(|(_, status)| status.clone()).collect::<Vec<_>>(),
+            vec![
+                CheckStatus::Passed,
+                CheckStatus::Passed,
+                CheckStatus::Passed,
+                CheckStatus::Skipped
+            ]
+        );
+        Ok(())
+    }
+}
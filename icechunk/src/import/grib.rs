@@ -0,0 +1,344 @@
+//! Import of GRIB2 files from their `.idx` inventories.
+//!
+//! The `.idx` files written by wgrib2, and published next to weather archives like
+//! GFS or ERA5, list the byte offset of every message in a GRIB2 file. This scanner
+//! creates one array per variable and level, with a message per chunk, without reading
+//! the GRIB2 files themselves.
+//!
+//! Arrays have shape `[files, ..grid]`, the first axis follows the order in which index
+//! files are added to the [`GribIndexScanner`]. Chunks are whole GRIB2 messages, decoded
+//! with the `grib` codec, as used by kerchunk.
+use std::{collections::HashMap, num::NonZeroU64};
+
+use super::{ImportError, ImportResult, Scan, ScannedArray};
+use crate::{
+    format::{
+        manifest::{ChunkPayload, VirtualChunkLocation, VirtualChunkRef},
+        snapshot::ZarrArrayMetadata,
+        ChunkIndices, Path,
+    },
+    metadata::{ChunkKeyEncoding, ChunkShape, Codec, DataType, FillValue},
+};
+
+/// A line of a GRIB2 `.idx` file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GribIndexEntry {
+    pub message: u32,
+    /// Messages with several fields have an entry for each of them
+    pub submessage: Option<u32>,
+    pub offset: u64,
+    /// Length of the whole message, `None` for the last message if the file size is
+    /// not known
+    pub length: Option<u64>,
+    /// The reference time, usually formatted as `YYYYMMDDHH`
+    pub reference_time: String,
+    pub variable: String,
+    pub level: String,
+    pub forecast: String,
+}
+
+/// Parse the contents of a `.idx` file.
+///
+/// `file_size` is the size of the GRIB2 file, it's needed to know the length of its
+/// last message.
+pub fn parse_index(
+    index: &str,
+    file_size: Option<u64>,
+) -> ImportResult<Vec<GribIndexEntry>> {
+    let invalid =
+        |line: &str| ImportError::InvalidFile(format!("invalid index line {line:?}"));
+    let mut entries = Vec::new();
+    for line in index.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 6 {
+            return Err(invalid(line));
+        }
+        let (message, submessage) = match fields[0].split_once('.') {
+            Some((message, sub)) => {
+                (message, Some(sub.parse().map_err(|_| invalid(line))?))
+            }
+            None => (fields[0], None),
+        };
+        entries.push(GribIndexEntry {
+            message: message.parse().map_err(|_| invalid(line))?,
+            submessage,
+            offset: fields[1].parse().map_err(|_| invalid(line))?,
+            length: None,
+            reference_time: fields[2].strip_prefix("d=").unwrap_or(fields[2]).to_string(),
+            variable: fields[3].to_string(),
+            level: fields[4].to_string(),
+            forecast: fields[5].to_string(),
+        });
+    }
+
+    // submessages share the offset of their message, a message ends where the next starts
+    let mut offsets: Vec<u64> = entries.iter().map(|e| e.offset).collect();
+    offsets.sort_unstable();
+    offsets.dedup();
+    for entry in entries.iter_mut() {
+        let next = offsets.partition_point(|offset| *offset <= entry.offset);
+        let end = offsets.get(next).copied().or(file_size);
+        entry.length = match end {
+            Some(end) if end > entry.offset => Some(end - entry.offset),
+            Some(_) => {
+                return Err(ImportError::InvalidFile(format!(
+                    "message {} starts past the end of the file",
+                    entry.message
+                )))
+            }
+            None => None,
+        };
+    }
+    Ok(entries)
+}
+
+#[derive(Debug)]
+pub struct GribIndexScanner {
+    grid_shape: Vec<NonZeroU64>,
+    data_type: DataType,
+    files: Vec<(VirtualChunkLocation, Vec<GribIndexEntry>)>,
+}
+
+impl GribIndexScanner {
+    /// A scanner for GRIB2 files where every message holds a field of `grid_shape`,
+    /// decoded as `float32` by default
+    pub fn new(grid_shape: Vec<NonZeroU64>) -> Self {
+        Self { grid_shape, data_type: DataType::Float32, files: Vec::new() }
+    }
+
+    pub fn with_data_type(mut self, data_type: DataType) -> Self {
+        self.data_type = data_type;
+        self
+    }
+
+    /// Add the GRIB2 file at `url`, described by the contents of its `.idx` file
+    pub fn add_index(
+        &mut self,
+        url: &str,
+        index: &str,
+        file_size: Option<u64>,
+    ) -> ImportResult<&mut Self> {
+        let location = VirtualChunkLocation::from_absolute_path(url)?;
+        self.files.push((location, parse_index(index, file_size)?));
+        Ok(self)
+    }
+
+    /// Describe the arrays of all the added files, under `root`.
+    ///
+    /// Arrays are placed at `root/<variable>/<level>`, with spaces and slashes in the
+    /// names replaced by underscores.
+    pub fn scan(&self, root: &Path) -> ImportResult<Scan> {
+        let mut scan = Scan { groups: vec![root.clone()], ..Scan::default() };
+        let mut arrays: Vec<ScannedArray> = Vec::new();
+        let mut by_key: HashMap<(String, String), usize> = HashMap::new();
+
+        for (file_index, (location, entries)) in self.files.iter().enumerate() {
+            let mut seen_in_file = HashMap::new();
+            for entry in entries {
+                let key = (entry.variable.clone(), entry.level.clone());
+                let idx = match by_key.get(&key) {
+                    Some(idx) => *idx,
+                    None => {
+                        let group =
+                            root.child(sanitize(entry.variable.as_str()).as_str())?;
+                        let path =
+                            group.child(sanitize(entry.level.as_str()).as_str())?;
+                        if !scan.groups.contains(&group) {
+                            scan.groups.push(group);
+                        }
+                        arrays.push(ScannedArray {
+                            path,
+                            metadata: self.metadata(entry.variable.as_str()),
+                            chunks: Vec::new(),
+                        });
+                        by_key.insert(key.clone(), arrays.len() - 1);
+                        arrays.len() - 1
+                    }
+                };
+                let array = &mut arrays[idx];
+
+                if let Some(previous) = seen_in_file.insert(key, entry.message) {
+                    scan.skipped.push((
+                        array.path.clone(),
+                        format!(
+                            "message {} duplicates message {previous} in file {file_index}",
+                            entry.message
+                        ),
+                    ));
+                    continue;
+                }
+                let Some(length) = entry.length else {
+                    scan.skipped.push((
+                        array.path.clone(),
+                        format!(
+                            "the length of message {} in file {file_index} is not known",
+                            entry.message
+                        ),
+                    ));
+                    continue;
+                };
+                let mut coord = vec![file_index as u64];
+                coord.extend(self.grid_shape.iter().map(|_| 0));
+                array.chunks.push((
+                    ChunkIndices(coord),
+                    ChunkPayload::Virtual(VirtualChunkRef {
+                        location: location.clone(),
+                        offset: entry.offset,
+                        length,
+                    }),
+                ));
+            }
+        }
+
+        for array in arrays.iter_mut() {
+            array.metadata.shape[0] = self.files.len() as u64;
+        }
+        scan.arrays = arrays;
+        Ok(scan)
+    }
+
+    fn metadata(&self, variable: &str) -> ZarrArrayMetadata {
+        let mut shape = vec![0];
+        shape.extend(self.grid_shape.iter().map(|d| d.get()));
+        let mut chunk_shape = vec![NonZeroU64::MIN];
+        chunk_shape.extend(self.grid_shape.iter().copied());
+        let fill_value = match self.data_type {
+            DataType::Float64 => FillValue::Float64(f64::NAN),
+            DataType::Float32 => FillValue::Float32(f32::NAN),
+            _ => FillValue::Int32(0),
+        };
+        ZarrArrayMetadata {
+            shape,
+            data_type: self.data_type.clone(),
+            chunk_shape: ChunkShape(chunk_shape),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value,
+            codecs: vec![Codec {
+                name: "grib".to_string(),
+                configuration: Some(HashMap::from([(
+                    "var".to_string(),
+                    variable.into(),
+                )])),
+            }],
+            storage_transformers: None,
+            dimension_names: None,
+            rectilinear_grid: None,
+        }
+    }
+}
+
+fn sanitize(name: &str) -> String {
+    name.replace([' ', '/'], "_")
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{ObjectStorage, Repository};
+
+    const INDEX_00: &str = "\
+1:0:d=2024010100:PRMSL:mean sea level:anl:
+2:1000:d=2024010100:TMP:2 m above ground:anl:
+3:2500:d=2024010100:UGRD:10 m above ground:anl:
+3.2:2500:d=2024010100:VGRD:10 m above ground:anl:
+4:4000:d=2024010100:TMP:2 m above ground:anl:
+";
+
+    const INDEX_06: &str = "\
+1:0:d=2024010106:PRMSL:mean sea level:anl:
+2:1200:d=2024010106:TMP:2 m above ground:anl:
+";
+
+    #[test]
+    fn test_parse_index() -> Result<(), Box<dyn Error>> {
+        let entries = parse_index(INDEX_00, Some(5000))?;
+        assert_eq!(entries.len(), 5);
+        assert_eq!(
+            entries[3],
+            GribIndexEntry {
+                message: 3,
+                submessage: Some(2),
+                offset: 2500,
+                length: Some(1500),
+                reference_time: "2024010100".to_string(),
+                variable: "VGRD".to_string(),
+                level: "10 m above ground".to_string(),
+                forecast: "anl".to_string(),
+            }
+        );
+        let lengths: Vec<_> = entries.iter().map(|e| e.length).collect();
+        assert_eq!(
+            lengths,
+            vec![Some(1000), Some(1500), Some(1500), Some(1500), Some(1000)]
+        );
+
+        let entries = parse_index(INDEX_00, None)?;
+        assert_eq!(entries[4].length, None);
+
+        assert!(parse_index("1:zero:d=2024010100:TMP:surface:anl:", None).is_err());
+        assert!(parse_index("1:0:d=2024010100", None).is_err());
+        assert!(parse_index(INDEX_00, Some(10)).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scan_grib_indexes() -> Result<(), Box<dyn Error>> {
+        let grid = vec![NonZeroU64::new(181).unwrap(), NonZeroU64::new(360).unwrap()];
+        let mut scanner = GribIndexScanner::new(grid);
+        scanner
+            .add_index("s3://noaa-gfs/gfs.t00z.grib2", INDEX_00, Some(5000))?
+            .add_index("s3://noaa-gfs/gfs.t06z.grib2", INDEX_06, None)?;
+        let scan = scanner.scan(&"/gfs".try_into()?)?;
+
+        let paths: Vec<String> = scan.arrays.iter().map(|a| a.path.to_string()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "/gfs/PRMSL/mean_sea_level",
+                "/gfs/TMP/2_m_above_ground",
+                "/gfs/UGRD/10_m_above_ground",
+                "/gfs/VGRD/10_m_above_ground"
+            ]
+        );
+        assert_eq!(scan.groups.len(), 5);
+        assert_eq!(
+            scan.skipped.iter().map(|(p, _)| p.to_string()).collect::<Vec<_>>(),
+            // TMP is twice in the first file, and the last message of the second file
+            // has unknown length
+            vec!["/gfs/TMP/2_m_above_ground", "/gfs/TMP/2_m_above_ground"]
+        );
+
+        let tmp = &scan.arrays[1];
+        assert_eq!(tmp.metadata.shape, vec![2, 181, 360]);
+        assert_eq!(tmp.metadata.codecs[0].name, "grib");
+        assert_eq!(tmp.chunks.len(), 1);
+        let prmsl = &scan.arrays[0];
+        let ranges: Vec<_> = prmsl
+            .chunks
+            .iter()
+            .map(|(coord, payload)| match payload {
+                ChunkPayload::Virtual(r) => (coord.0.clone(), r.offset, r.length),
+                _ => panic!("expected a virtual ref"),
+            })
+            .collect();
+        assert_eq!(ranges, vec![(vec![0, 0, 0], 0, 1000), (vec![1, 0, 0], 0, 1200)]);
+
+        let storage = Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(storage, false).await?.build();
+        ds.add_group(Path::root()).await?;
+        scan.add_to(&mut ds).await?;
+        let chunk = ds
+            .get_chunk_ref(
+                &"/gfs/PRMSL/mean_sea_level".try_into()?,
+                &ChunkIndices(vec![1, 0, 0]),
+            )
+            .await?;
+        assert!(matches!(chunk, Some(ChunkPayload::Virtual(r)) if r.length == 1200));
+        Ok(())
+    }
+}
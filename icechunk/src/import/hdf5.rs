@@ -0,0 +1,1378 @@
+//! A scanner for HDF5 files, including NetCDF4 files.
+//!
+//! This is a pure Rust reader for the parts of the HDF5 format that describe where
+//! chunks are stored, it doesn't need the HDF5 library. It supports:
+//!
+//! - superblocks versions 0 to 3, and version 1 and 2 object headers
+//! - groups with symbol tables, or with compact link storage
+//! - fixed-point and floating-point datatypes
+//! - compact, contiguous and chunked layouts, for chunked datasets only the version 1
+//!   B-tree, single chunk and implicit chunk indexes
+//! - the deflate, shuffle, fletcher32 and zstd filters
+//!
+//! Datasets and groups using other features are reported in [`Scan::skipped`].
+//! Attributes are not imported yet, and checksums are not verified.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    num::NonZeroU64,
+};
+
+use bytes::{Buf, Bytes};
+
+use super::{ImportError, ImportResult, ReadAt, Scan, ScannedArray};
+use crate::{
+    format::{
+        manifest::{ChunkPayload, VirtualChunkLocation, VirtualChunkRef},
+        snapshot::ZarrArrayMetadata,
+        ChunkIndices, Path,
+    },
+    metadata::{ChunkKeyEncoding, ChunkShape, Codec, DataType, FillValue},
+};
+
+const SIGNATURE: &[u8; 8] = b"\x89HDF\r\n\x1a\n";
+
+const MSG_DATASPACE: u16 = 0x01;
+const MSG_LINK_INFO: u16 = 0x02;
+const MSG_DATATYPE: u16 = 0x03;
+const MSG_FILL_VALUE_OLD: u16 = 0x04;
+const MSG_FILL_VALUE: u16 = 0x05;
+const MSG_LINK: u16 = 0x06;
+const MSG_LAYOUT: u16 = 0x08;
+const MSG_FILTER_PIPELINE: u16 = 0x0b;
+const MSG_CONTINUATION: u16 = 0x10;
+const MSG_SYMBOL_TABLE: u16 = 0x11;
+
+const FILTER_DEFLATE: u16 = 1;
+const FILTER_SHUFFLE: u16 = 2;
+const FILTER_FLETCHER32: u16 = 3;
+const FILTER_ZSTD: u16 = 32015;
+
+/// Scan the HDF5 file read by `reader`, placing its root group at `root`.
+///
+/// `url` is the location of the file, it's used for the virtual chunk references.
+pub fn scan<R: ReadAt + ?Sized>(
+    reader: &R,
+    url: &str,
+    root: &Path,
+) -> ImportResult<Scan> {
+    let location = VirtualChunkLocation::from_absolute_path(url)?;
+    let (file, root_header) = Hdf5File::open(reader)?;
+    let mut scanner =
+        Scanner { file, location, scan: Scan::default(), visited: HashSet::new() };
+    scanner.visit(root_header, root.clone())?;
+    Ok(scanner.scan)
+}
+
+fn invalid(msg: impl Into<String>) -> ImportError {
+    ImportError::InvalidFile(msg.into())
+}
+
+fn unsupported(msg: impl Into<String>) -> ImportError {
+    ImportError::Unsupported(msg.into())
+}
+
+/// Little endian decoding of the fields in a metadata block
+struct Cursor {
+    buf: Bytes,
+    offset_size: u8,
+    length_size: u8,
+}
+
+impl Cursor {
+    fn need(&self, n: usize) -> ImportResult<()> {
+        if self.buf.remaining() < n {
+            Err(invalid("truncated metadata block"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.remaining()
+    }
+
+    fn skip(&mut self, n: usize) -> ImportResult<()> {
+        self.need(n)?;
+        self.buf.advance(n);
+        Ok(())
+    }
+
+    fn bytes(&mut self, n: usize) -> ImportResult<Bytes> {
+        self.need(n)?;
+        Ok(self.buf.split_to(n))
+    }
+
+    fn u8(&mut self) -> ImportResult<u8> {
+        self.need(1)?;
+        Ok(self.buf.get_u8())
+    }
+
+    fn u16(&mut self) -> ImportResult<u16> {
+        self.need(2)?;
+        Ok(self.buf.get_u16_le())
+    }
+
+    fn u32(&mut self) -> ImportResult<u32> {
+        self.need(4)?;
+        Ok(self.buf.get_u32_le())
+    }
+
+    fn uint(&mut self, size: u8) -> ImportResult<u64> {
+        if size == 0 || size > 8 {
+            return Err(invalid(format!("invalid field size {size}")));
+        }
+        self.need(size as usize)?;
+        Ok(self.buf.get_uint_le(size as usize))
+    }
+
+    fn length(&mut self) -> ImportResult<u64> {
+        self.uint(self.length_size)
+    }
+
+    /// An address, `None` for the undefined address
+    fn address(&mut self) -> ImportResult<Option<u64>> {
+        let addr = self.uint(self.offset_size)?;
+        let undefined = u64::MAX >> (64 - 8 * self.offset_size as u32);
+        Ok((addr != undefined).then_some(addr))
+    }
+
+    fn required_address(&mut self) -> ImportResult<u64> {
+        self.address()?.ok_or_else(|| invalid("undefined address"))
+    }
+
+    fn signature(&mut self, expected: &[u8; 4]) -> ImportResult<()> {
+        if self.bytes(4)?.as_ref() != expected {
+            return Err(invalid(format!(
+                "expected {} signature",
+                String::from_utf8_lossy(expected)
+            )));
+        }
+        Ok(())
+    }
+}
+
+struct Hdf5File<'a, R: ?Sized> {
+    reader: &'a R,
+    size: u64,
+    base: u64,
+    offset_size: u8,
+    length_size: u8,
+}
+
+struct Message {
+    kind: u16,
+    data: Bytes,
+}
+
+impl<'a, R: ReadAt + ?Sized> Hdf5File<'a, R> {
+    /// Find the superblock, returns the file and the address of the root group
+    fn open(reader: &'a R) -> ImportResult<(Self, u64)> {
+        let size = reader.size()?;
+        // the superblock can be after a user block of 512 * 2^n bytes
+        let mut position = 0;
+        while position + 8 <= size {
+            if reader.read_at(position, 8)?.as_ref() == SIGNATURE {
+                return Self::superblock(reader, size, position);
+            }
+            position = if position == 0 { 512 } else { position * 2 };
+        }
+        Err(invalid("HDF5 signature not found"))
+    }
+
+    fn superblock(reader: &'a R, size: u64, position: u64) -> ImportResult<(Self, u64)> {
+        let mut file =
+            Self { reader, size, base: position, offset_size: 8, length_size: 8 };
+        let mut c = file.cursor_abs(position + 8, 16)?;
+        let version = c.u8()?;
+        match version {
+            0 | 1 => {
+                c.skip(4)?;
+                file.offset_size = c.u8()?;
+                file.length_size = c.u8()?;
+                // reserved, group K values and flags, plus the indexed storage K in v1
+                let skip = if version == 0 { 9 } else { 13 };
+                let start = position + 8 + 7 + skip;
+                let mut c = file.cursor_abs(start, 4 * file.offset_size as u64 + 40)?;
+                file.base = c.required_address()?;
+                // free space, end of file and driver info addresses
+                c.skip(3 * file.offset_size as usize)?;
+                // root group symbol table entry: link name offset and header address
+                c.skip(file.offset_size as usize)?;
+                let root = c.required_address()?;
+                Ok((file, root))
+            }
+            2 | 3 => {
+                file.offset_size = c.u8()?;
+                file.length_size = c.u8()?;
+                let mut c =
+                    file.cursor_abs(position + 12, 4 * file.offset_size as u64)?;
+                file.base = c.required_address()?;
+                // superblock extension and end of file addresses
+                c.skip(2 * file.offset_size as usize)?;
+                let root = c.required_address()?;
+                Ok((file, root))
+            }
+            _ => Err(unsupported(format!("HDF5 superblock version {version}"))),
+        }
+    }
+
+    fn cursor_of(&self, buf: Bytes) -> Cursor {
+        Cursor { buf, offset_size: self.offset_size, length_size: self.length_size }
+    }
+
+    fn cursor_abs(&self, position: u64, len: u64) -> ImportResult<Cursor> {
+        Ok(self.cursor_of(self.reader.read_at(position, len)?))
+    }
+
+    /// The position in the file of `addr`, relative to the base address
+    fn absolute(&self, addr: u64) -> ImportResult<u64> {
+        self.base
+            .checked_add(addr)
+            .ok_or_else(|| invalid(format!("address {addr} is out of range")))
+    }
+
+    /// A cursor over `len` bytes at `addr`, relative to the base address
+    fn cursor(&self, addr: u64, len: u64) -> ImportResult<Cursor> {
+        self.cursor_abs(self.absolute(addr)?, len)
+    }
+
+    /// Like [`Self::cursor`] but shorter if the block is at the end of the file
+    fn cursor_up_to(&self, addr: u64, len: u64) -> ImportResult<Cursor> {
+        let available = self.size.saturating_sub(self.absolute(addr)?);
+        self.cursor(addr, len.min(available))
+    }
+
+    fn object_header(&self, addr: u64) -> ImportResult<Vec<Message>> {
+        let mut c = self.cursor_up_to(addr, 34)?;
+        if c.remaining() >= 4 && c.buf[..4] == *b"OHDR" {
+            c.skip(4)?;
+            self.object_header_v2(addr, c)
+        } else {
+            self.object_header_v1(addr, c)
+        }
+    }
+
+    fn object_header_v1(&self, addr: u64, mut c: Cursor) -> ImportResult<Vec<Message>> {
+        let version = c.u8()?;
+        if version != 1 {
+            return Err(invalid(format!("object header version {version}")));
+        }
+        c.skip(1)?;
+        let num_messages = c.u16()? as usize;
+        c.skip(4)?;
+        let size = c.u32()? as u64;
+
+        let mut blocks = VecDeque::from([(addr + 16, size)]);
+        let mut messages = Vec::new();
+        let mut parsed = 0;
+        while let Some((start, len)) = blocks.pop_front() {
+            let mut c = self.cursor(start, len)?;
+            while c.remaining() >= 8 && parsed < num_messages {
+                let kind = c.u16()?;
+                let size = c.u16()? as usize;
+                let flags = c.u8()?;
+                c.skip(3)?;
+                let data = c.bytes(size)?;
+                parsed += 1;
+                self.add_message(kind, flags, data, &mut messages, &mut blocks)?;
+            }
+        }
+        Ok(messages)
+    }
+
+    fn object_header_v2(&self, addr: u64, mut c: Cursor) -> ImportResult<Vec<Message>> {
+        let version = c.u8()?;
+        if version != 2 {
+            return Err(invalid(format!("object header version {version}")));
+        }
+        let flags = c.u8()?;
+        let mut prefix = 6;
+        if flags & 0x20 != 0 {
+            c.skip(16)?;
+            prefix += 16;
+        }
+        if flags & 0x10 != 0 {
+            c.skip(4)?;
+            prefix += 4;
+        }
+        let width = 1 << (flags & 0x03);
+        let size = c.uint(width)?;
+        prefix += width as u64;
+        let creation_order = flags & 0x04 != 0;
+        let header_size = if creation_order { 6 } else { 4 };
+
+        let mut blocks = VecDeque::from([(addr + prefix, size)]);
+        let mut messages = Vec::new();
+        let mut first = true;
+        while let Some((start, len)) = blocks.pop_front() {
+            let mut c = if first {
+                self.cursor(start, len)?
+            } else {
+                // continuation blocks have a signature and a checksum
+                let mut c = self.cursor(start, len)?;
+                c.signature(b"OCHK")?;
+                let data = c.bytes(c.remaining().saturating_sub(4))?;
+                self.cursor_of(data)
+            };
+            first = false;
+            while c.remaining() >= header_size {
+                let kind = c.u8()? as u16;
+                let size = c.u16()? as usize;
+                let msg_flags = c.u8()?;
+                if creation_order {
+                    c.skip(2)?;
+                }
+                let data = c.bytes(size)?;
+                self.add_message(kind, msg_flags, data, &mut messages, &mut blocks)?;
+            }
+        }
+        Ok(messages)
+    }
+
+    fn add_message(
+        &self,
+        kind: u16,
+        flags: u8,
+        data: Bytes,
+        messages: &mut Vec<Message>,
+        blocks: &mut VecDeque<(u64, u64)>,
+    ) -> ImportResult<()> {
+        match kind {
+            0 => {}
+            MSG_CONTINUATION => {
+                let mut c = self.cursor_of(data);
+                blocks.push_back((c.required_address()?, c.length()?));
+            }
+            _ => {
+                // shared messages are stored in another object, we don't follow them
+                if flags & 0x02 != 0 {
+                    return Err(unsupported(format!("shared header message {kind}")));
+                }
+                messages.push(Message { kind, data })
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the nodes of a version 1 B-tree, returning the leaf entries as
+    /// `(key, child address)` pairs
+    fn btree_v1(
+        &self,
+        root: u64,
+        node_type: u8,
+        key_size: u64,
+    ) -> ImportResult<Vec<(Bytes, u64)>> {
+        let offset_size = self.offset_size as u64;
+        let mut entries = Vec::new();
+        let mut pending = vec![root];
+        let mut visited = HashSet::new();
+        while let Some(addr) = pending.pop() {
+            if !visited.insert(addr) {
+                return Err(invalid("cycle in B-tree"));
+            }
+            let mut c = self.cursor(addr, 8 + 2 * offset_size)?;
+            c.signature(b"TREE")?;
+            if c.u8()? != node_type {
+                return Err(invalid("unexpected B-tree node type"));
+            }
+            let level = c.u8()?;
+            let used = c.u16()? as u64;
+            let mut c = self.cursor(
+                addr + 8 + 2 * offset_size,
+                (used + 1) * key_size + used * offset_size,
+            )?;
+            for _ in 0..used {
+                let key = c.bytes(key_size as usize)?;
+                let child = c.required_address()?;
+                if level == 0 {
+                    entries.push((key, child));
+                } else {
+                    pending.push(child);
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn symbol_table_links(&self, data: Bytes) -> ImportResult<Vec<(String, u64)>> {
+        let mut c = self.cursor_of(data);
+        let btree = c.required_address()?;
+        let heap = c.required_address()?;
+
+        let mut c =
+            self.cursor(heap, 8 + 2 * self.length_size as u64 + self.offset_size as u64)?;
+        c.signature(b"HEAP")?;
+        c.skip(4)?;
+        let heap_size = c.length()?;
+        c.length()?;
+        let heap_data = self.cursor(c.required_address()?, heap_size)?.buf;
+
+        let mut links = Vec::new();
+        let entry_size = 2 * self.offset_size as u64 + 24;
+        for (_, snod) in self.btree_v1(btree, 0, self.length_size as u64)? {
+            let mut c = self.cursor(snod, 8)?;
+            c.signature(b"SNOD")?;
+            c.skip(2)?;
+            let symbols = c.u16()? as u64;
+            let mut c = self.cursor(snod + 8, symbols * entry_size)?;
+            for _ in 0..symbols {
+                let name_offset = c.length()? as usize;
+                let header = c.required_address()?;
+                c.skip(24)?;
+                let name = heap_data
+                    .get(name_offset..)
+                    .and_then(|name| name.split(|b| *b == 0).next())
+                    .ok_or_else(|| invalid("link name outside of local heap"))?;
+                let name = String::from_utf8(name.to_vec())
+                    .map_err(|_| invalid("link name is not valid UTF-8"))?;
+                links.push((name, header));
+            }
+        }
+        Ok(links)
+    }
+
+    /// A hard link from a link message, `None` for soft and external links
+    fn link(&self, data: Bytes) -> ImportResult<Option<(String, u64)>> {
+        let mut c = self.cursor_of(data);
+        let version = c.u8()?;
+        if version != 1 {
+            return Err(unsupported(format!("link message version {version}")));
+        }
+        let flags = c.u8()?;
+        let link_type = if flags & 0x08 != 0 { c.u8()? } else { 0 };
+        if flags & 0x04 != 0 {
+            c.skip(8)?;
+        }
+        if flags & 0x10 != 0 {
+            c.skip(1)?;
+        }
+        let name_len = c.uint(1 << (flags & 0x03))? as usize;
+        let name = String::from_utf8(c.bytes(name_len)?.to_vec())
+            .map_err(|_| invalid("link name is not valid UTF-8"))?;
+        if link_type != 0 {
+            return Ok(None);
+        }
+        Ok(Some((name, c.required_address()?)))
+    }
+}
+
+struct Scanner<'a, R: ?Sized> {
+    file: Hdf5File<'a, R>,
+    location: VirtualChunkLocation,
+    scan: Scan,
+    visited: HashSet<u64>,
+}
+
+impl<R: ReadAt + ?Sized> Scanner<'_, R> {
+    fn visit(&mut self, addr: u64, path: Path) -> ImportResult<()> {
+        if !self.visited.insert(addr) {
+            return Ok(());
+        }
+        let messages = match self.file.object_header(addr) {
+            Ok(messages) => messages,
+            Err(ImportError::Unsupported(reason)) => {
+                self.scan.skipped.push((path, reason));
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        let kinds: HashMap<u16, &Message> =
+            messages.iter().rev().map(|msg| (msg.kind, msg)).collect();
+
+        if kinds.contains_key(&MSG_LAYOUT) {
+            match self.dataset(&path, &kinds) {
+                Ok(array) => self.scan.arrays.push(array),
+                Err(ImportError::Unsupported(reason)) => {
+                    self.scan.skipped.push((path, reason))
+                }
+                Err(err) => return Err(err),
+            }
+            return Ok(());
+        }
+        if kinds.contains_key(&MSG_DATATYPE) {
+            self.scan.skipped.push((path, "named datatype".to_string()));
+            return Ok(());
+        }
+
+        let links = match self.group_links(&messages) {
+            Ok(links) => links,
+            Err(ImportError::Unsupported(reason)) => {
+                self.scan.skipped.push((path, reason));
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+        self.scan.groups.push(path.clone());
+        for (name, child) in links {
+            match path.child(name.as_str()) {
+                Ok(child_path) => self.visit(child, child_path)?,
+                Err(_) => {
+                    self.scan.skipped.push((path.clone(), format!("link name {name:?}")))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn group_links(&self, messages: &[Message]) -> ImportResult<Vec<(String, u64)>> {
+        let mut links = Vec::new();
+        for msg in messages {
+            match msg.kind {
+                MSG_SYMBOL_TABLE => {
+                    links.extend(self.file.symbol_table_links(msg.data.clone())?)
+                }
+                MSG_LINK => links.extend(self.file.link(msg.data.clone())?),
+                MSG_LINK_INFO => {
+                    let mut c = self.file.cursor_of(msg.data.clone());
+                    c.skip(1)?;
+                    let flags = c.u8()?;
+                    if flags & 0x01 != 0 {
+                        c.skip(8)?;
+                    }
+                    if c.address()?.is_some() {
+                        return Err(unsupported("groups with dense link storage"));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(links)
+    }
+
+    fn dataset(
+        &self,
+        path: &Path,
+        messages: &HashMap<u16, &Message>,
+    ) -> ImportResult<ScannedArray> {
+        let message = |kind| {
+            messages
+                .get(&kind)
+                .map(|msg| self.file.cursor_of(msg.data.clone()))
+                .ok_or_else(|| invalid(format!("dataset without message {kind}")))
+        };
+
+        let shape = dataspace(message(MSG_DATASPACE)?)?
+            .ok_or_else(|| unsupported("datasets with null dataspace"))?;
+        let dtype = datatype(message(MSG_DATATYPE)?)?;
+        let fill = match message(MSG_FILL_VALUE) {
+            Ok(c) => fill_value_bytes(c)?,
+            Err(_) => message(MSG_FILL_VALUE_OLD)
+                .ok()
+                .map(old_fill_value_bytes)
+                .transpose()?
+                .flatten(),
+        };
+        let filters = match message(MSG_FILTER_PIPELINE) {
+            Ok(c) => filter_pipeline(c)?,
+            Err(_) => Vec::new(),
+        };
+        let layout = layout(message(MSG_LAYOUT)?, &shape, dtype.size)?;
+
+        let mut codecs = vec![dtype.bytes_codec()];
+        for filter in filters.iter() {
+            codecs.push(filter.codec(dtype.size)?);
+        }
+
+        let (chunk_shape, chunks) = match layout {
+            Layout::Compact(data) => {
+                let chunks = if shape.iter().all(|d| *d > 0) {
+                    vec![(ChunkIndices(vec![0; shape.len()]), ChunkPayload::Inline(data))]
+                } else {
+                    vec![]
+                };
+                (whole_array_chunk(&shape), chunks)
+            }
+            Layout::Contiguous { address, size } => {
+                let chunks = match address {
+                    Some(address) if size > 0 => vec![(
+                        ChunkIndices(vec![0; shape.len()]),
+                        self.virtual_payload(address, size)?,
+                    )],
+                    _ => vec![],
+                };
+                (whole_array_chunk(&shape), chunks)
+            }
+            Layout::Chunked { chunk_shape, index } => {
+                if chunk_shape.len() != shape.len() || chunk_shape.contains(&0) {
+                    return Err(invalid("chunk shape doesn't match the dataspace"));
+                }
+                let chunks =
+                    self.chunks(&shape, &chunk_shape, dtype.size, index, &filters)?;
+                (chunk_shape, chunks)
+            }
+        };
+
+        let metadata = ZarrArrayMetadata {
+            shape,
+            data_type: dtype.data_type.clone(),
+            chunk_shape: ChunkShape(
+                chunk_shape.into_iter().filter_map(NonZeroU64::new).collect(),
+            ),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: dtype.fill_value(fill.as_deref()),
+            codecs,
+            storage_transformers: None,
+            dimension_names: None,
+            rectilinear_grid: None,
+        };
+        Ok(ScannedArray { path: path.clone(), metadata, chunks })
+    }
+
+    fn chunks(
+        &self,
+        shape: &[u64],
+        chunk_shape: &[u64],
+        element_size: u64,
+        index: ChunkIndex,
+        filters: &[Filter],
+    ) -> ImportResult<Vec<(ChunkIndices, ChunkPayload)>> {
+        let chunk_bytes = chunk_shape.iter().product::<u64>() * element_size;
+        match index {
+            ChunkIndex::BTreeV1(None)
+            | ChunkIndex::Implicit(None)
+            | ChunkIndex::Single { address: None, .. } => Ok(vec![]),
+            ChunkIndex::BTreeV1(Some(root)) => {
+                let rank = shape.len() as u64;
+                let entries = self.file.btree_v1(root, 1, 8 + 8 * (rank + 1))?;
+                let mut chunks = Vec::with_capacity(entries.len());
+                for (key, address) in entries {
+                    let mut c = self.file.cursor_of(key);
+                    let size = c.u32()? as u64;
+                    let filter_mask = c.u32()?;
+                    if filter_mask != 0 {
+                        return Err(unsupported("chunks with skipped filters"));
+                    }
+                    let coord = chunk_shape
+                        .iter()
+                        .map(|chunk| Ok(c.uint(8)? / chunk))
+                        .collect::<ImportResult<Vec<_>>>()?;
+                    chunks.push((
+                        ChunkIndices(coord),
+                        self.virtual_payload(address, size)?,
+                    ));
+                }
+                Ok(chunks)
+            }
+            ChunkIndex::Single { address: Some(address), filtered_size, filter_mask } => {
+                if filter_mask != 0 {
+                    return Err(unsupported("chunks with skipped filters"));
+                }
+                let size = filtered_size.unwrap_or(chunk_bytes);
+                Ok(vec![(
+                    ChunkIndices(vec![0; shape.len()]),
+                    self.virtual_payload(address, size)?,
+                )])
+            }
+            ChunkIndex::Implicit(Some(address)) => {
+                if !filters.is_empty() {
+                    return Err(invalid("implicit chunk index with filters"));
+                }
+                let grid: Vec<u64> =
+                    shape.iter().zip(chunk_shape).map(|(s, c)| s.div_ceil(*c)).collect();
+                let mut chunks = Vec::new();
+                let mut coord = vec![0; grid.len()];
+                let mut n = 0u64;
+                if grid.iter().all(|g| *g > 0) {
+                    loop {
+                        let offset = n
+                            .checked_mul(chunk_bytes)
+                            .and_then(|position| position.checked_add(address))
+                            .ok_or_else(|| invalid("chunk address is out of range"))?;
+                        chunks.push((
+                            ChunkIndices(coord.clone()),
+                            self.virtual_payload(offset, chunk_bytes)?,
+                        ));
+                        n += 1;
+                        // row major increment of the chunk coordinates
+                        let mut axis = grid.len();
+                        loop {
+                            if axis == 0 {
+                                return Ok(chunks);
+                            }
+                            axis -= 1;
+                            coord[axis] += 1;
+                            if coord[axis] < grid[axis] {
+                                break;
+                            }
+                            coord[axis] = 0;
+                        }
+                    }
+                }
+                Ok(chunks)
+            }
+        }
+    }
+
+    fn virtual_payload(&self, address: u64, size: u64) -> ImportResult<ChunkPayload> {
+        Ok(ChunkPayload::Virtual(VirtualChunkRef {
+            location: self.location.clone(),
+            offset: self.file.absolute(address)?,
+            length: size,
+        }))
+    }
+}
+
+fn whole_array_chunk(shape: &[u64]) -> Vec<u64> {
+    shape.iter().map(|d| (*d).max(1)).collect()
+}
+
+/// The dimensions, `None` for a null dataspace
+fn dataspace(mut c: Cursor) -> ImportResult<Option<Vec<u64>>> {
+    let version = c.u8()?;
+    let ndim = c.u8()?;
+    c.skip(1)?;
+    match version {
+        1 => c.skip(5)?,
+        2 => {
+            if c.u8()? == 2 {
+                return Ok(None);
+            }
+        }
+        _ => return Err(unsupported(format!("dataspace version {version}"))),
+    }
+    (0..ndim).map(|_| c.length()).collect::<ImportResult<_>>().map(Some)
+}
+
+struct Datatype {
+    data_type: DataType,
+    size: u64,
+    big_endian: bool,
+}
+
+fn datatype(mut c: Cursor) -> ImportResult<Datatype> {
+    let class = c.u8()? & 0x0f;
+    let bits = c.u8()?;
+    c.skip(2)?;
+    let size = c.u32()? as u64;
+    let data_type = match class {
+        0 => {
+            let signed = bits & 0x08 != 0;
+            match (size, signed) {
+                (1, true) => DataType::Int8,
+                (2, true) => DataType::Int16,
+                (4, true) => DataType::Int32,
+                (8, true) => DataType::Int64,
+                (1, false) => DataType::UInt8,
+                (2, false) => DataType::UInt16,
+                (4, false) => DataType::UInt32,
+                (8, false) => DataType::UInt64,
+                _ => return Err(unsupported(format!("{size} bytes integers"))),
+            }
+        }
+        1 => {
+            if bits & 0x40 != 0 {
+                return Err(unsupported("VAX floating point"));
+            }
+            match size {
+                2 => DataType::Float16,
+                4 => DataType::Float32,
+                8 => DataType::Float64,
+                _ => return Err(unsupported(format!("{size} bytes floating point"))),
+            }
+        }
+        _ => return Err(unsupported(format!("datatype class {class}"))),
+    };
+    Ok(Datatype { data_type, size, big_endian: bits & 0x01 != 0 })
+}
+
+impl Datatype {
+    fn bytes_codec(&self) -> Codec {
+        let configuration = (self.size > 1).then(|| {
+            let endian = if self.big_endian { "big" } else { "little" };
+            HashMap::from([("endian".to_string(), endian.into())])
+        });
+        Codec { name: "bytes".to_string(), configuration }
+    }
+
+    fn fill_value(&self, bytes: Option<&[u8]>) -> FillValue {
+        let bytes = bytes.filter(|b| b.len() as u64 == self.size).map(|b| {
+            let mut b = b.to_vec();
+            if self.big_endian {
+                b.reverse();
+            }
+            let mut le = [0u8; 8];
+            le[..b.len()].copy_from_slice(&b);
+            le
+        });
+        let raw = bytes.unwrap_or_default();
+        let raw = u64::from_le_bytes(raw);
+        match self.data_type {
+            DataType::Int8 => FillValue::Int8(raw as u8 as i8),
+            DataType::Int16 => FillValue::Int16(raw as u16 as i16),
+            DataType::Int32 => FillValue::Int32(raw as u32 as i32),
+            DataType::Int64 => FillValue::Int64(raw as i64),
+            DataType::UInt8 => FillValue::UInt8(raw as u8),
+            DataType::UInt16 => FillValue::UInt16(raw as u16),
+            DataType::UInt32 => FillValue::UInt32(raw as u32),
+            DataType::Float16 => FillValue::Float16(f16_to_f32(raw as u16)),
+            DataType::Float32 => FillValue::Float32(f32::from_bits(raw as u32)),
+            DataType::Float64 => FillValue::Float64(f64::from_bits(raw)),
+            _ => FillValue::UInt64(raw),
+        }
+    }
+}
+
+fn f16_to_f32(bits: u16) -> f32 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((bits >> 10) & 0x1f) as i32;
+    let mantissa = (bits & 0x03ff) as f32;
+    match exponent {
+        0 => sign * mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0.0 => sign * f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => sign * (1.0 + mantissa / 1024.0) * 2f32.powi(exponent - 15),
+    }
+}
+
+/// The fill value, `None` if it's not defined
+fn fill_value_bytes(mut c: Cursor) -> ImportResult<Option<Bytes>> {
+    let version = c.u8()?;
+    let defined = match version {
+        1 | 2 => {
+            c.skip(2)?;
+            version == 1 || c.u8()? != 0
+        }
+        3 => c.u8()? & 0x20 != 0,
+        _ => return Err(unsupported(format!("fill value version {version}"))),
+    };
+    if version == 1 {
+        c.skip(1)?;
+    }
+    if !defined {
+        return Ok(None);
+    }
+    let size = c.u32()? as usize;
+    (size > 0).then(|| c.bytes(size)).transpose()
+}
+
+fn old_fill_value_bytes(mut c: Cursor) -> ImportResult<Option<Bytes>> {
+    let size = c.u32()? as usize;
+    (size > 0).then(|| c.bytes(size)).transpose()
+}
+
+struct Filter {
+    id: u16,
+    name: String,
+    values: Vec<u32>,
+}
+
+impl Filter {
+    fn codec(&self, element_size: u64) -> ImportResult<Codec> {
+        let codec = |name: &str, configuration: Vec<(&str, serde_json::Value)>| Codec {
+            name: name.to_string(),
+            configuration: Some(
+                configuration.into_iter().map(|(k, v)| (k.to_string(), v)).collect(),
+            ),
+        };
+        match self.id {
+            FILTER_DEFLATE => Ok(codec(
+                "numcodecs.zlib",
+                vec![("level", self.values.first().copied().unwrap_or(6).into())],
+            )),
+            FILTER_SHUFFLE => {
+                Ok(codec("numcodecs.shuffle", vec![("elementsize", element_size.into())]))
+            }
+            FILTER_FLETCHER32 => Ok(codec("numcodecs.fletcher32", vec![])),
+            FILTER_ZSTD => Ok(codec(
+                "numcodecs.zstd",
+                vec![("level", self.values.first().copied().unwrap_or(3).into())],
+            )),
+            id => Err(unsupported(format!("filter {id} {}", self.name))),
+        }
+    }
+}
+
+fn filter_pipeline(mut c: Cursor) -> ImportResult<Vec<Filter>> {
+    let version = c.u8()?;
+    let count = c.u8()?;
+    if version == 1 {
+        c.skip(6)?;
+    } else if version != 2 {
+        return Err(unsupported(format!("filter pipeline version {version}")));
+    }
+    let mut filters = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let id = c.u16()?;
+        let name_len = if version == 1 || id >= 256 { c.u16()? as usize } else { 0 };
+        c.skip(2)?;
+        let num_values = c.u16()? as usize;
+        let name = c.bytes(name_len)?;
+        let name = String::from_utf8_lossy(name.split(|b| *b == 0).next().unwrap_or(&[]))
+            .into_owned();
+        let values = (0..num_values).map(|_| c.u32()).collect::<ImportResult<_>>()?;
+        if version == 1 && num_values % 2 == 1 {
+            c.skip(4)?;
+        }
+        filters.push(Filter { id, name, values });
+    }
+    Ok(filters)
+}
+
+enum ChunkIndex {
+    BTreeV1(Option<u64>),
+    Single { address: Option<u64>, filtered_size: Option<u64>, filter_mask: u32 },
+    Implicit(Option<u64>),
+}
+
+enum Layout {
+    Compact(Bytes),
+    Contiguous { address: Option<u64>, size: u64 },
+    Chunked { chunk_shape: Vec<u64>, index: ChunkIndex },
+}
+
+/// Drop the last chunk dimension, it's the datatype size
+fn chunk_dims(mut dims: Vec<u64>) -> ImportResult<Vec<u64>> {
+    dims.pop().ok_or_else(|| invalid("chunked layout without dimensions"))?;
+    Ok(dims)
+}
+
+fn layout(mut c: Cursor, shape: &[u64], element_size: u64) -> ImportResult<Layout> {
+    let version = c.u8()?;
+    match version {
+        1 | 2 => {
+            let ndim = c.u8()?;
+            let class = c.u8()?;
+            c.skip(5)?;
+            let address = if class != 0 { c.address()? } else { None };
+            let dims = (0..ndim)
+                .map(|_| Ok(c.u32()? as u64))
+                .collect::<ImportResult<Vec<_>>>()?;
+            match class {
+                0 => {
+                    let size = c.u32()? as usize;
+                    Ok(Layout::Compact(c.bytes(size)?))
+                }
+                1 => Ok(Layout::Contiguous {
+                    address,
+                    size: shape.iter().product::<u64>() * element_size,
+                }),
+                2 => Ok(Layout::Chunked {
+                    chunk_shape: chunk_dims(dims)?,
+                    index: ChunkIndex::BTreeV1(address),
+                }),
+                _ => Err(unsupported(format!("layout class {class}"))),
+            }
+        }
+        3 | 4 => {
+            let class = c.u8()?;
+            match class {
+                0 => {
+                    let size = c.u16()? as usize;
+                    Ok(Layout::Compact(c.bytes(size)?))
+                }
+                1 => Ok(Layout::Contiguous { address: c.address()?, size: c.length()? }),
+                2 if version == 3 => {
+                    let ndim = c.u8()?;
+                    let address = c.address()?;
+                    let dims = (0..ndim)
+                        .map(|_| Ok(c.u32()? as u64))
+                        .collect::<ImportResult<Vec<_>>>()?;
+                    Ok(Layout::Chunked {
+                        chunk_shape: chunk_dims(dims)?,
+                        index: ChunkIndex::BTreeV1(address),
+                    })
+                }
+                2 => {
+                    let flags = c.u8()?;
+                    let ndim = c.u8()?;
+                    let dim_size = c.u8()?;
+                    let dims = (0..ndim)
+                        .map(|_| c.uint(dim_size))
+                        .collect::<ImportResult<Vec<_>>>()?;
+                    let index = match c.u8()? {
+                        1 => {
+                            let (filtered_size, filter_mask) = if flags & 0x02 != 0 {
+                                (Some(c.length()?), c.u32()?)
+                            } else {
+                                (None, 0)
+                            };
+                            ChunkIndex::Single {
+                                address: c.address()?,
+                                filtered_size,
+                                filter_mask,
+                            }
+                        }
+                        2 => ChunkIndex::Implicit(c.address()?),
+                        index => {
+                            return Err(unsupported(format!("chunk index type {index}")))
+                        }
+                    };
+                    Ok(Layout::Chunked { chunk_shape: chunk_dims(dims)?, index })
+                }
+                _ => Err(unsupported(format!("layout class {class}"))),
+            }
+        }
+        _ => Err(unsupported(format!("layout version {version}"))),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{format::ByteRange, ObjectStorage, Repository};
+
+    const UNDEFINED: u64 = u64::MAX;
+
+    /// Builds a small HDF5 file, the way libhdf5 lays out the default (v0 superblock)
+    /// format, with 8 byte offsets and lengths
+    #[derive(Default)]
+    struct FileBuilder {
+        buf: Vec<u8>,
+    }
+
+    impl FileBuilder {
+        fn append(&mut self, bytes: &[u8]) -> u64 {
+            while !self.buf.len().is_multiple_of(8) {
+                self.buf.push(0);
+            }
+            let addr = self.buf.len() as u64;
+            self.buf.extend_from_slice(bytes);
+            addr
+        }
+
+        fn header_v1(&mut self, messages: &[(u16, Vec<u8>)]) -> u64 {
+            let mut body = Vec::new();
+            for (kind, data) in messages {
+                let mut data = data.clone();
+                data.resize(data.len().div_ceil(8) * 8, 0);
+                body.extend(kind.to_le_bytes());
+                body.extend((data.len() as u16).to_le_bytes());
+                body.extend([0; 4]);
+                body.extend(data);
+            }
+            let mut header = vec![1, 0];
+            header.extend((messages.len() as u16).to_le_bytes());
+            header.extend(1u32.to_le_bytes());
+            header.extend((body.len() as u32).to_le_bytes());
+            header.extend([0; 4]);
+            header.extend(body);
+            self.append(&header)
+        }
+
+        fn header_v2(&mut self, messages: &[(u8, Vec<u8>)]) -> u64 {
+            let mut body = Vec::new();
+            for (kind, data) in messages {
+                body.push(*kind);
+                body.extend((data.len() as u16).to_le_bytes());
+                body.push(0);
+                body.extend(data);
+            }
+            let mut header = b"OHDR".to_vec();
+            header.extend([2, 0, body.len() as u8]);
+            header.extend(body);
+            // checksum, not verified by the scanner
+            header.extend([0; 4]);
+            self.append(&header)
+        }
+
+        fn finish(mut self, root_header: u64) -> Bytes {
+            let mut sb = SIGNATURE.to_vec();
+            sb.extend([0, 0, 0, 0, 0, 8, 8, 0]);
+            sb.extend(4u16.to_le_bytes());
+            sb.extend(16u16.to_le_bytes());
+            sb.extend(0u32.to_le_bytes());
+            let eof = self.buf.len() as u64;
+            for addr in [0, UNDEFINED, eof, UNDEFINED, 0, root_header] {
+                sb.extend(addr.to_le_bytes());
+            }
+            sb.extend([0; 24]);
+            self.buf[..sb.len()].copy_from_slice(&sb);
+            self.buf.into()
+        }
+    }
+
+    fn le(values: &[u64]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn dataspace_v1(dims: &[u64]) -> (u16, Vec<u8>) {
+        let mut data = vec![1, dims.len() as u8, 0, 0, 0, 0, 0, 0];
+        data.extend(le(dims));
+        (MSG_DATASPACE, data)
+    }
+
+    fn int16_le() -> (u16, Vec<u8>) {
+        let mut data = vec![0x10, 0x08, 0, 0];
+        data.extend(2u32.to_le_bytes());
+        data.extend([0, 0, 16, 0]);
+        (MSG_DATATYPE, data)
+    }
+
+    fn float64_be() -> (u16, Vec<u8>) {
+        let mut data = vec![0x11, 0x21, 63, 0];
+        data.extend(8u32.to_le_bytes());
+        data.extend([0, 0, 64, 0, 52, 11, 0, 52]);
+        data.extend(1023u32.to_le_bytes());
+        (MSG_DATATYPE, data)
+    }
+
+    fn filters(filters: &[(u16, &str, &[u32])]) -> (u16, Vec<u8>) {
+        let mut data = vec![1, filters.len() as u8, 0, 0, 0, 0, 0, 0];
+        for (id, name, values) in filters {
+            let mut name = name.as_bytes().to_vec();
+            if !name.is_empty() {
+                name.push(0);
+                name.resize(name.len().div_ceil(8) * 8, 0);
+            }
+            data.extend(id.to_le_bytes());
+            data.extend((name.len() as u16).to_le_bytes());
+            data.extend(0u16.to_le_bytes());
+            data.extend((values.len() as u16).to_le_bytes());
+            data.extend(name);
+            for v in values.iter() {
+                data.extend(v.to_le_bytes());
+            }
+            if values.len() % 2 == 1 {
+                data.extend([0; 4]);
+            }
+        }
+        (MSG_FILTER_PIPELINE, data)
+    }
+
+    struct TestFile {
+        bytes: Bytes,
+        chunks: Vec<(u64, u64)>,
+        pressure: u64,
+    }
+
+    /// A root group with a chunked and compressed int16 dataset, a big-endian contiguous
+    /// float64 dataset, a dataset with an unsupported filter, and a subgroup with a
+    /// version 2 object header holding a compact uint8 dataset
+    fn test_file() -> TestFile {
+        let mut f = FileBuilder::default();
+        // space for the superblock
+        f.append(&[0; 96]);
+
+        // a 4x6 array in 2x3 chunks
+        let chunks: Vec<(u64, u64)> = (0..4u8)
+            .map(|i| {
+                let data = vec![i; 5 + i as usize];
+                (f.append(&data), data.len() as u64)
+            })
+            .collect();
+        let mut btree = b"TREE".to_vec();
+        btree.extend([1, 0]);
+        btree.extend(4u16.to_le_bytes());
+        btree.extend(le(&[UNDEFINED, UNDEFINED]));
+        let offsets = [[0, 0], [0, 3], [2, 0], [2, 3], [4, 0]];
+        for (i, [row, col]) in offsets.iter().enumerate() {
+            let size = chunks.get(i).map(|(_, len)| *len).unwrap_or(0);
+            btree.extend((size as u32).to_le_bytes());
+            btree.extend(0u32.to_le_bytes());
+            btree.extend(le(&[*row, *col, 0]));
+            if let Some((addr, _)) = chunks.get(i) {
+                btree.extend(addr.to_le_bytes());
+            }
+        }
+        let btree = f.append(&btree);
+
+        let chunked_layout = {
+            let mut data = vec![3, 2, 3];
+            data.extend(btree.to_le_bytes());
+            data.extend([2u32, 3, 2].iter().flat_map(|d| d.to_le_bytes()));
+            (MSG_LAYOUT, data)
+        };
+        let fill = (MSG_FILL_VALUE, vec![2, 2, 2, 1, 2, 0, 0, 0, 0xff, 0xff]);
+        let temperature = f.header_v1(&[
+            dataspace_v1(&[4, 6]),
+            int16_le(),
+            fill.clone(),
+            chunked_layout.clone(),
+            filters(&[(2, "", &[2]), (1, "deflate", &[4])]),
+        ]);
+        let blosc = f.header_v1(&[
+            dataspace_v1(&[4, 6]),
+            int16_le(),
+            chunked_layout,
+            filters(&[(32001, "blosc", &[2, 2, 2, 0])]),
+        ]);
+
+        let pressure_data = f.append(&[0; 24]);
+        let mut contiguous = vec![3, 1];
+        contiguous.extend(le(&[pressure_data, 24]));
+        let pressure =
+            f.header_v1(&[dataspace_v1(&[3]), float64_be(), (MSG_LAYOUT, contiguous)]);
+
+        let compact = f.header_v2(&[
+            (MSG_DATASPACE as u8, [vec![2, 1, 0, 1], le(&[2])].concat()),
+            (MSG_DATATYPE as u8, vec![0x10, 0, 0, 0, 1, 0, 0, 0, 0, 0, 8, 0]),
+            (MSG_LAYOUT as u8, vec![3, 0, 2, 0, 7, 9]),
+        ]);
+        let mut link = vec![1, 0, 7];
+        link.extend(b"compact");
+        link.extend(compact.to_le_bytes());
+        let sub = f.header_v2(&[(MSG_LINK as u8, link)]);
+
+        // the root group symbol table
+        let names = b"\0blosc\0pressure\0sub\0temperature\0";
+        let name_offsets = [1u64, 7, 16, 20];
+        let heap_data = f.append(names);
+        let mut heap = b"HEAP".to_vec();
+        heap.extend([0; 4]);
+        heap.extend(le(&[names.len() as u64, UNDEFINED, heap_data]));
+        let heap = f.append(&heap);
+
+        let mut snod = b"SNOD".to_vec();
+        snod.extend([1, 0]);
+        snod.extend(4u16.to_le_bytes());
+        for (name, header) in name_offsets.iter().zip([blosc, pressure, sub, temperature])
+        {
+            snod.extend(le(&[*name, header]));
+            snod.extend([0; 24]);
+        }
+        let snod = f.append(&snod);
+
+        let mut group_btree = b"TREE".to_vec();
+        group_btree.extend([0, 0]);
+        group_btree.extend(1u16.to_le_bytes());
+        group_btree.extend(le(&[UNDEFINED, UNDEFINED, 0, snod, 20]));
+        let group_btree = f.append(&group_btree);
+
+        let root = f.header_v1(&[(MSG_SYMBOL_TABLE, le(&[group_btree, heap]))]);
+        TestFile { bytes: f.finish(root), chunks, pressure: pressure_data }
+    }
+
+    fn codec(name: &str, configuration: &[(&str, serde_json::Value)]) -> Codec {
+        Codec {
+            name: name.to_string(),
+            configuration: Some(
+                configuration.iter().map(|(k, v)| (k.to_string(), v.clone())).collect(),
+            ),
+        }
+    }
+
+    #[test]
+    fn test_scan_hdf5() -> Result<(), Box<dyn Error>> {
+        let file = test_file();
+        let url = "s3://bucket/data/file.nc";
+        let location = VirtualChunkLocation::from_absolute_path(url)?;
+        let root: Path = "/imported".try_into()?;
+        let scan = scan(&file.bytes, url, &root)?;
+
+        assert_eq!(scan.groups, vec![root.clone(), "/imported/sub".try_into()?]);
+        assert_eq!(
+            scan.skipped,
+            vec![("/imported/blosc".try_into()?, "filter 32001 blosc".to_string())]
+        );
+        let arrays: HashMap<_, _> =
+            scan.arrays.iter().map(|a| (a.path.to_string(), a)).collect();
+
+        let temperature = arrays["/imported/temperature"];
+        assert_eq!(temperature.metadata.shape, vec![4, 6]);
+        assert_eq!(temperature.metadata.data_type, DataType::Int16);
+        assert_eq!(
+            temperature.metadata.chunk_shape,
+            ChunkShape(vec![NonZeroU64::new(2).unwrap(), NonZeroU64::new(3).unwrap()])
+        );
+        assert_eq!(temperature.metadata.fill_value, FillValue::Int16(-1));
+        assert_eq!(
+            temperature.metadata.codecs,
+            vec![
+                codec("bytes", &[("endian", "little".into())]),
+                codec("numcodecs.shuffle", &[("elementsize", 2.into())]),
+                codec("numcodecs.zlib", &[("level", 4.into())]),
+            ]
+        );
+        let expected: Vec<_> = [[0, 0], [0, 1], [1, 0], [1, 1]]
+            .iter()
+            .zip(file.chunks.iter())
+            .map(|(coord, (offset, length))| {
+                (
+                    ChunkIndices(coord.to_vec()),
+                    ChunkPayload::Virtual(VirtualChunkRef {
+                        location: location.clone(),
+                        offset: *offset,
+                        length: *length,
+                    }),
+                )
+            })
+            .collect();
+        assert_eq!(temperature.chunks, expected);
+
+        let pressure = arrays["/imported/pressure"];
+        assert_eq!(pressure.metadata.data_type, DataType::Float64);
+        assert_eq!(pressure.metadata.fill_value, FillValue::Float64(0.0));
+        assert_eq!(
+            pressure.metadata.codecs,
+            vec![codec("bytes", &[("endian", "big".into())])]
+        );
+        assert_eq!(
+            pressure.chunks,
+            vec![(
+                ChunkIndices(vec![0]),
+                ChunkPayload::Virtual(VirtualChunkRef {
+                    location: location.clone(),
+                    offset: file.pressure,
+                    length: 24
+                })
+            )]
+        );
+
+        let compact = arrays["/imported/sub/compact"];
+        assert_eq!(compact.metadata.shape, vec![2]);
+        assert_eq!(compact.metadata.data_type, DataType::UInt8);
+        assert_eq!(
+            compact.metadata.codecs,
+            vec![Codec { name: "bytes".to_string(), configuration: None }]
+        );
+        assert_eq!(
+            compact.chunks,
+            vec![(
+                ChunkIndices(vec![0]),
+                ChunkPayload::Inline(Bytes::from_static(&[7, 9]))
+            )]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_invalid_files() {
+        assert!(matches!(
+            scan(
+                &Bytes::from_static(b"not an hdf5 file"),
+                "s3://bucket/f.h5",
+                &Path::root()
+            ),
+            Err(ImportError::InvalidFile(_))
+        ));
+        // a truncated file
+        let file = test_file().bytes;
+        let truncated = file.slice(..file.len() - 100);
+        assert!(scan(&truncated, "s3://bucket/f.h5", &Path::root()).is_err());
+        // a base address that overflows the addresses relative to it
+        let mut file = file.to_vec();
+        file[24..32].copy_from_slice(&(u64::MAX - 1).to_le_bytes());
+        assert!(matches!(
+            scan(&Bytes::from(file), "s3://bucket/f.h5", &Path::root()),
+            Err(ImportError::InvalidFile(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_import_into_repository() -> Result<(), Box<dyn Error>> {
+        let file = test_file();
+        let storage = Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(storage, false).await?.build();
+
+        scan(&file.bytes, "s3://bucket/file.h5", &Path::root())?.add_to(&mut ds).await?;
+        let path: Path = "/temperature".try_into()?;
+        let Some(ChunkPayload::Virtual(chunk)) =
+            ds.get_chunk_ref(&path, &ChunkIndices(vec![1, 1])).await?
+        else {
+            panic!("expected a virtual chunk");
+        };
+        assert_eq!((chunk.offset, chunk.length), file.chunks[3]);
+
+        let path: Path = "/sub/compact".try_into()?;
+        let data =
+            ds.get_chunk_reader(&path, &ChunkIndices(vec![0]), &ByteRange::ALL).await?;
+        assert_eq!(data.unwrap().await?, Bytes::from_static(&[7, 9]));
+        assert_eq!(ds.list_nodes().await?.count(), 5);
+        Ok(())
+    }
+}
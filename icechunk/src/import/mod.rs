@@ -0,0 +1,134 @@
+//! Import of existing files as virtual arrays.
+//!
+//! Scanners read the metadata of files in other formats, and describe their groups and
+//! arrays in a [`Scan`], with chunks pointing to byte ranges inside the original file.
+//! Chunk data is never read or copied, [`Scan::add_to`] only records the references in
+//! a repository.
+use std::{fs::File, io};
+
+use bytes::Bytes;
+use thiserror::Error;
+
+use crate::{
+    format::{
+        manifest::{ChunkPayload, VirtualReferenceError},
+        snapshot::ZarrArrayMetadata,
+        ChunkIndices, Path, PathError,
+    },
+    repository::RepositoryError,
+    Repository,
+};
+
+pub mod grib;
+pub mod hdf5;
+pub mod tiff;
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ImportError {
+    #[error("error reading file {0}")]
+    Io(#[from] io::Error),
+    #[error("invalid file: {0}")]
+    InvalidFile(String),
+    #[error("unsupported feature: {0}")]
+    Unsupported(String),
+    #[error("invalid path {0}")]
+    InvalidPath(#[from] PathError),
+    #[error("invalid file location {0}")]
+    InvalidLocation(#[from] VirtualReferenceError),
+    #[error("repository error {0}")]
+    Repository(#[from] RepositoryError),
+}
+
+pub type ImportResult<T> = Result<T, ImportError>;
+
+/// Random access to the bytes of the file being scanned
+pub trait ReadAt {
+    fn size(&self) -> io::Result<u64>;
+
+    /// Read exactly `len` bytes starting at `offset`
+    fn read_at(&self, offset: u64, len: u64) -> io::Result<Bytes>;
+}
+
+impl ReadAt for Bytes {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.len() as u64)
+    }
+
+    fn read_at(&self, offset: u64, len: u64) -> io::Result<Bytes> {
+        let end = offset.checked_add(len).filter(|end| *end <= self.len() as u64);
+        match end {
+            Some(end) => Ok(self.slice(offset as usize..end as usize)),
+            None => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("read of {len} bytes at {offset} is past the end of the file"),
+            )),
+        }
+    }
+}
+
+impl ReadAt for File {
+    fn size(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn read_at(&self, offset: u64, len: u64) -> io::Result<Bytes> {
+        let mut buf = vec![0; len as usize];
+        #[cfg(unix)]
+        std::os::unix::fs::FileExt::read_exact_at(self, &mut buf, offset)?;
+        #[cfg(windows)]
+        {
+            let mut done = 0;
+            while done < buf.len() {
+                let read = std::os::windows::fs::FileExt::seek_read(
+                    self,
+                    &mut buf[done..],
+                    offset + done as u64,
+                )?;
+                if read == 0 {
+                    return Err(io::ErrorKind::UnexpectedEof.into());
+                }
+                done += read;
+            }
+        }
+        Ok(buf.into())
+    }
+}
+
+/// An array found by a scanner
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScannedArray {
+    pub path: Path,
+    pub metadata: ZarrArrayMetadata,
+    /// Virtual references for chunks stored in the file, or inline data for chunks
+    /// stored in its metadata
+    pub chunks: Vec<(ChunkIndices, ChunkPayload)>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Scan {
+    pub groups: Vec<Path>,
+    pub arrays: Vec<ScannedArray>,
+    /// Objects found in the file that cannot be imported, with the reason
+    pub skipped: Vec<(Path, String)>,
+}
+
+impl Scan {
+    /// Add the scanned groups and arrays to `repo`.
+    ///
+    /// Groups that already exist are kept, the parents of the scanned root must exist.
+    pub async fn add_to(self, repo: &mut Repository) -> ImportResult<()> {
+        for group in self.groups {
+            if repo.get_group(&group).await.is_err() {
+                repo.add_group(group).await?;
+            }
+        }
+        for array in self.arrays {
+            repo.add_array(array.path.clone(), array.metadata).await?;
+            for (coord, payload) in array.chunks {
+                repo.set_chunk_ref(array.path.clone(), coord, Some(payload)).await?;
+            }
+        }
+        Ok(())
+    }
+}
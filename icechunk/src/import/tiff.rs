@@ -0,0 +1,630 @@
+//! A scanner for TIFF files, including Cloud-Optimized GeoTIFFs.
+//!
+//! Every image of the file becomes an array, named after its position in the file, so
+//! for COGs `0` is the full resolution image and `1`, `2`, ... are its overviews. With
+//! chunky planar configuration, arrays have shape `[height, width, samples]`, or
+//! `[height, width]` for single sample images, and `[samples, height, width]` with
+//! separate planes. Tiles, or strips, are chunks.
+//!
+//! Classic and BigTIFF files in both byte orders are supported, with no compression,
+//! deflate, zstd, LZW or JPEG compression and no predictor. Masks and images using
+//! other features are reported in [`Scan::skipped`]. GeoTIFF tags other than the
+//! GDAL nodata value are not imported yet.
+use std::{collections::HashMap, num::NonZeroU64};
+
+use bytes::Bytes;
+
+use super::{ImportError, ImportResult, ReadAt, Scan, ScannedArray};
+use crate::{
+    format::{
+        manifest::{ChunkPayload, VirtualChunkLocation, VirtualChunkRef},
+        snapshot::ZarrArrayMetadata,
+        ChunkIndices, Path,
+    },
+    metadata::{ChunkKeyEncoding, ChunkShape, Codec, DataType, FillValue},
+};
+
+const TAG_NEW_SUBFILE_TYPE: u16 = 254;
+const TAG_IMAGE_WIDTH: u16 = 256;
+const TAG_IMAGE_LENGTH: u16 = 257;
+const TAG_BITS_PER_SAMPLE: u16 = 258;
+const TAG_COMPRESSION: u16 = 259;
+const TAG_STRIP_OFFSETS: u16 = 273;
+const TAG_SAMPLES_PER_PIXEL: u16 = 277;
+const TAG_ROWS_PER_STRIP: u16 = 278;
+const TAG_STRIP_BYTE_COUNTS: u16 = 279;
+const TAG_PLANAR_CONFIGURATION: u16 = 284;
+const TAG_PREDICTOR: u16 = 317;
+const TAG_TILE_WIDTH: u16 = 322;
+const TAG_TILE_LENGTH: u16 = 323;
+const TAG_TILE_OFFSETS: u16 = 324;
+const TAG_TILE_BYTE_COUNTS: u16 = 325;
+const TAG_SAMPLE_FORMAT: u16 = 339;
+const TAG_GDAL_NODATA: u16 = 42113;
+
+/// Images with this bit in their subfile type are transparency masks
+const SUBFILE_MASK: u64 = 0x4;
+
+/// Scan the TIFF file read by `reader`, placing its images under `root`.
+///
+/// `url` is the location of the file, it's used for the virtual chunk references.
+pub fn scan<R: ReadAt + ?Sized>(
+    reader: &R,
+    url: &str,
+    root: &Path,
+) -> ImportResult<Scan> {
+    let location = VirtualChunkLocation::from_absolute_path(url)?;
+    let file = TiffFile::open(reader)?;
+    let mut scan = Scan { groups: vec![root.clone()], ..Scan::default() };
+
+    let mut next = Some(file.first_ifd);
+    let mut seen = Vec::new();
+    while let Some(offset) = next {
+        if seen.contains(&offset) {
+            return Err(invalid("cycle in the image file directories"));
+        }
+        seen.push(offset);
+        let (tags, next_ifd) = file.ifd(offset)?;
+        next = next_ifd;
+
+        let path = root.child((seen.len() - 1).to_string().as_str())?;
+        if file.value(&tags, TAG_NEW_SUBFILE_TYPE)?.unwrap_or(0) & SUBFILE_MASK != 0 {
+            scan.skipped.push((path, format!("mask image at offset {offset}")));
+            continue;
+        }
+        match file.image(&tags, &location, path.clone()) {
+            Ok(array) => scan.arrays.push(array),
+            Err(ImportError::Unsupported(reason)) => scan.skipped.push((path, reason)),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(scan)
+}
+
+fn invalid(msg: impl Into<String>) -> ImportError {
+    ImportError::InvalidFile(msg.into())
+}
+
+fn unsupported(msg: impl Into<String>) -> ImportError {
+    ImportError::Unsupported(msg.into())
+}
+
+struct Entry {
+    field_type: u16,
+    count: u64,
+    /// The value bytes if they fit in the entry, otherwise the offset to them
+    value: Bytes,
+}
+
+struct TiffFile<'a, R: ?Sized> {
+    reader: &'a R,
+    big_endian: bool,
+    big_tiff: bool,
+    first_ifd: u64,
+}
+
+impl<'a, R: ReadAt + ?Sized> TiffFile<'a, R> {
+    fn open(reader: &'a R) -> ImportResult<Self> {
+        let header = reader.read_at(0, 8.min(reader.size()?))?;
+        if header.len() < 8 {
+            return Err(invalid("file too short for a TIFF header"));
+        }
+        let big_endian = match &header[..2] {
+            b"II" => false,
+            b"MM" => true,
+            _ => return Err(invalid("TIFF byte order mark not found")),
+        };
+        let mut file = Self { reader, big_endian, big_tiff: false, first_ifd: 0 };
+        match file.uint(&header[2..4]) {
+            42 => file.first_ifd = file.uint(&header[4..8]),
+            43 => {
+                file.big_tiff = true;
+                let header = reader.read_at(8, 8)?;
+                file.first_ifd = file.uint(&header);
+            }
+            magic => return Err(invalid(format!("unexpected TIFF version {magic}"))),
+        }
+        Ok(file)
+    }
+
+    fn uint(&self, bytes: &[u8]) -> u64 {
+        let mut le = [0u8; 8];
+        le[..bytes.len()].copy_from_slice(bytes);
+        if self.big_endian {
+            le[..bytes.len()].reverse();
+        }
+        u64::from_le_bytes(le)
+    }
+
+    /// The entries of the image file directory at `offset`, and the offset of the next
+    fn ifd(&self, offset: u64) -> ImportResult<(HashMap<u16, Entry>, Option<u64>)> {
+        let (count_size, entry_size, offset_size) =
+            if self.big_tiff { (8, 20, 8) } else { (2, 12, 4) };
+        let count = self.uint(&self.reader.read_at(offset, count_size)?);
+        let data =
+            self.reader.read_at(offset + count_size, count * entry_size + offset_size)?;
+        let mut tags = HashMap::new();
+        for raw in data.chunks_exact(entry_size as usize).take(count as usize) {
+            let tag = self.uint(&raw[0..2]) as u16;
+            let field_type = self.uint(&raw[2..4]) as u16;
+            let (count, value) = if self.big_tiff {
+                (self.uint(&raw[4..12]), &raw[12..20])
+            } else {
+                (self.uint(&raw[4..8]), &raw[8..12])
+            };
+            tags.insert(
+                tag,
+                Entry { field_type, count, value: Bytes::copy_from_slice(value) },
+            );
+        }
+        let next = self.uint(&data[(count * entry_size) as usize..]);
+        Ok((tags, (next != 0).then_some(next)))
+    }
+
+    /// The raw bytes of an entry's values
+    fn entry_bytes(&self, entry: &Entry) -> ImportResult<(Bytes, usize)> {
+        let size = match entry.field_type {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 | 13 => 4,
+            5 | 10 | 12 | 16 | 17 | 18 => 8,
+            t => return Err(invalid(format!("unknown TIFF field type {t}"))),
+        };
+        let len = entry.count * size as u64;
+        if len <= entry.value.len() as u64 {
+            Ok((entry.value.slice(..len as usize), size))
+        } else {
+            Ok((self.reader.read_at(self.uint(&entry.value), len)?, size))
+        }
+    }
+
+    fn values(
+        &self,
+        tags: &HashMap<u16, Entry>,
+        tag: u16,
+    ) -> ImportResult<Option<Vec<u64>>> {
+        let Some(entry) = tags.get(&tag) else { return Ok(None) };
+        if !matches!(entry.field_type, 1 | 3 | 4 | 16) {
+            return Err(invalid(format!("tag {tag} doesn't hold unsigned integers")));
+        }
+        let (bytes, size) = self.entry_bytes(entry)?;
+        Ok(Some(bytes.chunks_exact(size).map(|v| self.uint(v)).collect()))
+    }
+
+    fn value(&self, tags: &HashMap<u16, Entry>, tag: u16) -> ImportResult<Option<u64>> {
+        Ok(self.values(tags, tag)?.and_then(|v| v.first().copied()))
+    }
+
+    fn required(&self, tags: &HashMap<u16, Entry>, tag: u16) -> ImportResult<u64> {
+        self.value(tags, tag)?.ok_or_else(|| invalid(format!("missing TIFF tag {tag}")))
+    }
+
+    fn ascii(
+        &self,
+        tags: &HashMap<u16, Entry>,
+        tag: u16,
+    ) -> ImportResult<Option<String>> {
+        let Some(entry) = tags.get(&tag) else { return Ok(None) };
+        let (bytes, _) = self.entry_bytes(entry)?;
+        let text = bytes.split(|b| *b == 0).next().unwrap_or_default();
+        Ok(Some(String::from_utf8_lossy(text).trim().to_string()))
+    }
+
+    fn image(
+        &self,
+        tags: &HashMap<u16, Entry>,
+        location: &VirtualChunkLocation,
+        path: Path,
+    ) -> ImportResult<ScannedArray> {
+        let width = self.required(tags, TAG_IMAGE_WIDTH)?;
+        let height = self.required(tags, TAG_IMAGE_LENGTH)?;
+        let samples = self.value(tags, TAG_SAMPLES_PER_PIXEL)?.unwrap_or(1);
+        let separate_planes = match self
+            .value(tags, TAG_PLANAR_CONFIGURATION)?
+            .unwrap_or(1)
+        {
+            1 => false,
+            2 => true,
+            config => return Err(unsupported(format!("planar configuration {config}"))),
+        };
+        if self.value(tags, TAG_PREDICTOR)?.unwrap_or(1) != 1 {
+            return Err(unsupported("predictors"));
+        }
+
+        let bits = self.values(tags, TAG_BITS_PER_SAMPLE)?.unwrap_or_else(|| vec![1]);
+        if bits.iter().any(|b| *b != bits[0]) {
+            return Err(unsupported("samples of different sizes"));
+        }
+        let formats = self.values(tags, TAG_SAMPLE_FORMAT)?.unwrap_or_else(|| vec![1]);
+        let data_type = match (formats[0], bits[0]) {
+            (1, 8) => DataType::UInt8,
+            (1, 16) => DataType::UInt16,
+            (1, 32) => DataType::UInt32,
+            (1, 64) => DataType::UInt64,
+            (2, 8) => DataType::Int8,
+            (2, 16) => DataType::Int16,
+            (2, 32) => DataType::Int32,
+            (2, 64) => DataType::Int64,
+            (3, 16) => DataType::Float16,
+            (3, 32) => DataType::Float32,
+            (3, 64) => DataType::Float64,
+            (format, bits) => {
+                return Err(unsupported(format!(
+                    "{bits} bits samples of format {format}"
+                )))
+            }
+        };
+
+        // tiles, or strips spanning the whole width
+        let (chunk_height, chunk_width, offsets, byte_counts) =
+            match self.values(tags, TAG_TILE_OFFSETS)? {
+                Some(offsets) => (
+                    self.required(tags, TAG_TILE_LENGTH)?,
+                    self.required(tags, TAG_TILE_WIDTH)?,
+                    offsets,
+                    self.values(tags, TAG_TILE_BYTE_COUNTS)?
+                        .ok_or_else(|| invalid("tiles without byte counts"))?,
+                ),
+                None => {
+                    let rows = self.value(tags, TAG_ROWS_PER_STRIP)?.unwrap_or(height);
+                    let rows = rows.min(height);
+                    if rows == 0 || height % rows != 0 {
+                        // the last strip is shorter than the others, not a full chunk
+                        return Err(unsupported("strips that don't divide the image"));
+                    }
+                    (
+                        rows,
+                        width,
+                        self.values(tags, TAG_STRIP_OFFSETS)?
+                            .ok_or_else(|| invalid("image without tiles or strips"))?,
+                        self.values(tags, TAG_STRIP_BYTE_COUNTS)?
+                            .ok_or_else(|| invalid("strips without byte counts"))?,
+                    )
+                }
+            };
+        if chunk_height == 0 || chunk_width == 0 || offsets.len() != byte_counts.len() {
+            return Err(invalid("inconsistent tile layout"));
+        }
+
+        let down = height.div_ceil(chunk_height);
+        let across = width.div_ceil(chunk_width);
+        let planes = if separate_planes { samples } else { 1 };
+        if offsets.len() as u64 != down * across * planes {
+            return Err(invalid("wrong number of tiles"));
+        }
+        let (shape, chunk_shape) = match (separate_planes, samples) {
+            (true, _) => {
+                (vec![samples, height, width], vec![1, chunk_height, chunk_width])
+            }
+            (false, 1) => (vec![height, width], vec![chunk_height, chunk_width]),
+            (false, _) => {
+                (vec![height, width, samples], vec![chunk_height, chunk_width, samples])
+            }
+        };
+
+        let mut chunks = Vec::new();
+        for (i, (offset, length)) in offsets.iter().zip(byte_counts.iter()).enumerate() {
+            // sparse files don't store empty tiles
+            if *length == 0 {
+                continue;
+            }
+            let i = i as u64;
+            let (plane, rest) = (i / (down * across), i % (down * across));
+            let (row, col) = (rest / across, rest % across);
+            let coord = match (separate_planes, samples) {
+                (true, _) => vec![plane, row, col],
+                (false, 1) => vec![row, col],
+                (false, _) => vec![row, col, 0],
+            };
+            chunks.push((
+                ChunkIndices(coord),
+                ChunkPayload::Virtual(VirtualChunkRef {
+                    location: location.clone(),
+                    offset: *offset,
+                    length: *length,
+                }),
+            ));
+        }
+
+        let mut codecs = vec![self.bytes_codec(bits[0])];
+        codecs
+            .extend(compression_codec(self.value(tags, TAG_COMPRESSION)?.unwrap_or(1))?);
+        let nodata = self.ascii(tags, TAG_GDAL_NODATA)?;
+        let metadata = ZarrArrayMetadata {
+            shape,
+            fill_value: fill_value(&data_type, nodata.as_deref()),
+            data_type,
+            chunk_shape: ChunkShape(
+                chunk_shape.into_iter().filter_map(NonZeroU64::new).collect(),
+            ),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            codecs,
+            storage_transformers: None,
+            dimension_names: None,
+            rectilinear_grid: None,
+        };
+        Ok(ScannedArray { path, metadata, chunks })
+    }
+
+    fn bytes_codec(&self, bits: u64) -> Codec {
+        let configuration = (bits > 8).then(|| {
+            let endian = if self.big_endian { "big" } else { "little" };
+            HashMap::from([("endian".to_string(), endian.into())])
+        });
+        Codec { name: "bytes".to_string(), configuration }
+    }
+}
+
+fn compression_codec(compression: u64) -> ImportResult<Option<Codec>> {
+    let name = match compression {
+        1 => return Ok(None),
+        5 => "imagecodecs_lzw",
+        // old and new style JPEG
+        6 | 7 => "imagecodecs_jpeg",
+        8 | 32946 => "numcodecs.zlib",
+        50000 => "numcodecs.zstd",
+        c => return Err(unsupported(format!("compression {c}"))),
+    };
+    Ok(Some(Codec { name: name.to_string(), configuration: Some(HashMap::new()) }))
+}
+
+/// The GDAL nodata value if it's valid for the data type, otherwise zero
+fn fill_value(data_type: &DataType, nodata: Option<&str>) -> FillValue {
+    let int = nodata.and_then(|n| n.parse::<i64>().ok()).unwrap_or(0);
+    let float = nodata.and_then(|n| n.parse::<f64>().ok()).unwrap_or(0.0);
+    match data_type {
+        DataType::UInt8 => FillValue::UInt8(int.try_into().unwrap_or(0)),
+        DataType::UInt16 => FillValue::UInt16(int.try_into().unwrap_or(0)),
+        DataType::UInt32 => FillValue::UInt32(int.try_into().unwrap_or(0)),
+        DataType::UInt64 => FillValue::UInt64(int.try_into().unwrap_or(0)),
+        DataType::Int8 => FillValue::Int8(int.try_into().unwrap_or(0)),
+        DataType::Int16 => FillValue::Int16(int.try_into().unwrap_or(0)),
+        DataType::Int32 => FillValue::Int32(int.try_into().unwrap_or(0)),
+        DataType::Float16 => FillValue::Float16(float as f32),
+        DataType::Float32 => FillValue::Float32(float as f32),
+        DataType::Float64 => FillValue::Float64(float),
+        _ => FillValue::Int64(int),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    /// Writes TIFF files with the given byte order and variant
+    struct TiffBuilder {
+        big_endian: bool,
+        big_tiff: bool,
+        buf: Vec<u8>,
+        /// position of the last "next IFD" offset, to link the next directory
+        next_ifd_at: usize,
+    }
+
+    enum Value {
+        Short(Vec<u16>),
+        Long(Vec<u32>),
+        Ascii(&'static str),
+    }
+
+    impl TiffBuilder {
+        fn new(big_endian: bool, big_tiff: bool) -> Self {
+            let mut b = Self { big_endian, big_tiff, buf: Vec::new(), next_ifd_at: 4 };
+            b.buf.extend(if big_endian { b"MM" } else { b"II" });
+            if big_tiff {
+                b.uint(43, 2);
+                b.uint(8, 2);
+                b.uint(0, 2);
+                b.next_ifd_at = 8;
+                b.uint(0, 8);
+            } else {
+                b.uint(42, 2);
+                b.uint(0, 4);
+            }
+            b
+        }
+
+        fn uint(&mut self, v: u64, size: usize) {
+            let bytes = v.to_le_bytes();
+            let mut bytes = bytes[..size].to_vec();
+            if self.big_endian {
+                bytes.reverse();
+            }
+            self.buf.extend(bytes);
+        }
+
+        fn patch(&mut self, at: usize, v: u64, size: usize) {
+            let end = self.buf.len();
+            self.uint(v, size);
+            let bytes: Vec<u8> = self.buf.drain(end..).collect();
+            self.buf[at..at + size].copy_from_slice(&bytes);
+        }
+
+        fn data(&mut self, bytes: &[u8]) -> u64 {
+            let at = self.buf.len() as u64;
+            self.buf.extend(bytes);
+            at
+        }
+
+        fn ifd(&mut self, mut entries: Vec<(u16, Value)>) {
+            entries.sort_by_key(|(tag, _)| *tag);
+            let (count_size, offset_size) = if self.big_tiff { (8, 8) } else { (2, 4) };
+            // values that don't fit in the entries go before the directory
+            let mut encoded = Vec::new();
+            for (tag, value) in entries {
+                let (field_type, size, raw): (u16, usize, Vec<u64>) = match value {
+                    Value::Short(v) => (3, 2, v.into_iter().map(u64::from).collect()),
+                    Value::Long(v) => (4, 4, v.into_iter().map(u64::from).collect()),
+                    Value::Ascii(s) => {
+                        (2, 1, s.bytes().chain([0]).map(u64::from).collect())
+                    }
+                };
+                let count = raw.len();
+                let inline = count * size <= offset_size;
+                let offset = if inline {
+                    None
+                } else {
+                    let at = self.buf.len() as u64;
+                    for v in raw.iter() {
+                        self.uint(*v, size);
+                    }
+                    Some(at)
+                };
+                encoded.push((tag, field_type, count, size, raw, offset));
+            }
+            let ifd = self.buf.len() as u64;
+            self.patch(self.next_ifd_at, ifd, offset_size);
+            self.uint(encoded.len() as u64, count_size);
+            for (tag, field_type, count, size, raw, offset) in encoded {
+                self.uint(tag as u64, 2);
+                self.uint(field_type as u64, 2);
+                self.uint(count as u64, offset_size);
+                match offset {
+                    Some(offset) => self.uint(offset, offset_size),
+                    None => {
+                        for v in raw.iter() {
+                            self.uint(*v, size);
+                        }
+                        self.buf.extend(vec![0; offset_size - count * size]);
+                    }
+                }
+            }
+            self.next_ifd_at = self.buf.len();
+            self.uint(0, offset_size);
+        }
+    }
+
+    fn virtual_chunks(array: &ScannedArray) -> Vec<(Vec<u64>, u64, u64)> {
+        array
+            .chunks
+            .iter()
+            .map(|(coord, payload)| match payload {
+                ChunkPayload::Virtual(r) => (coord.0.clone(), r.offset, r.length),
+                _ => panic!("expected a virtual ref"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_scan_cog() -> Result<(), Box<dyn Error>> {
+        let mut b = TiffBuilder::new(false, false);
+        // a 40x20 image in 16x16 tiles, 3 across and 2 down, with an empty tile
+        let tiles: Vec<u64> = (0..5).map(|i| b.data(&vec![i; 10 + i as usize])).collect();
+        let mut offsets: Vec<u32> = tiles.iter().map(|t| *t as u32).collect();
+        offsets.insert(2, 0);
+        let mut counts: Vec<u32> = (0..5).map(|i| 10 + i).collect();
+        counts.insert(2, 0);
+        b.ifd(vec![
+            (TAG_IMAGE_WIDTH, Value::Short(vec![40])),
+            (TAG_IMAGE_LENGTH, Value::Short(vec![20])),
+            (TAG_BITS_PER_SAMPLE, Value::Short(vec![16])),
+            (TAG_SAMPLE_FORMAT, Value::Short(vec![2])),
+            (TAG_COMPRESSION, Value::Short(vec![8])),
+            (TAG_TILE_WIDTH, Value::Short(vec![16])),
+            (TAG_TILE_LENGTH, Value::Short(vec![16])),
+            (TAG_TILE_OFFSETS, Value::Long(offsets)),
+            (TAG_TILE_BYTE_COUNTS, Value::Long(counts)),
+            (TAG_GDAL_NODATA, Value::Ascii("-9999")),
+        ]);
+        // an overview of a single tile
+        let overview = b.data(&[7; 12]);
+        b.ifd(vec![
+            (TAG_NEW_SUBFILE_TYPE, Value::Long(vec![1])),
+            (TAG_IMAGE_WIDTH, Value::Short(vec![20])),
+            (TAG_IMAGE_LENGTH, Value::Short(vec![10])),
+            (TAG_BITS_PER_SAMPLE, Value::Short(vec![16])),
+            (TAG_SAMPLE_FORMAT, Value::Short(vec![2])),
+            (TAG_COMPRESSION, Value::Short(vec![8])),
+            (TAG_TILE_WIDTH, Value::Short(vec![32])),
+            (TAG_TILE_LENGTH, Value::Short(vec![16])),
+            (TAG_TILE_OFFSETS, Value::Long(vec![overview as u32])),
+            (TAG_TILE_BYTE_COUNTS, Value::Long(vec![12])),
+        ]);
+        // a mask
+        b.ifd(vec![
+            (TAG_NEW_SUBFILE_TYPE, Value::Long(vec![4])),
+            (TAG_IMAGE_WIDTH, Value::Short(vec![40])),
+            (TAG_IMAGE_LENGTH, Value::Short(vec![20])),
+        ]);
+        let file = Bytes::from(b.buf);
+
+        let scan = scan(&file, "s3://bucket/image.tif", &"/image".try_into()?)?;
+        assert_eq!(scan.groups, vec!["/image".try_into()?]);
+        assert_eq!(scan.arrays.len(), 2);
+        assert_eq!(scan.skipped.len(), 1);
+        assert!(scan.skipped[0].1.starts_with("mask"));
+
+        let full = &scan.arrays[0];
+        assert_eq!(full.path, "/image/0".try_into()?);
+        assert_eq!(full.metadata.shape, vec![20, 40]);
+        assert_eq!(full.metadata.data_type, DataType::Int16);
+        assert_eq!(full.metadata.fill_value, FillValue::Int16(-9999));
+        assert_eq!(
+            full.metadata.codecs.iter().map(|c| c.name.as_str()).collect::<Vec<_>>(),
+            vec!["bytes", "numcodecs.zlib"]
+        );
+        assert_eq!(
+            virtual_chunks(full),
+            vec![
+                (vec![0, 0], tiles[0], 10),
+                (vec![0, 1], tiles[1], 11),
+                (vec![1, 0], tiles[2], 12),
+                (vec![1, 1], tiles[3], 13),
+                (vec![1, 2], tiles[4], 14),
+            ]
+        );
+
+        let overview_array = &scan.arrays[1];
+        assert_eq!(overview_array.path, "/image/1".try_into()?);
+        assert_eq!(overview_array.metadata.shape, vec![10, 20]);
+        assert_eq!(virtual_chunks(overview_array), vec![(vec![0, 0], overview, 12)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scan_big_tiff_strips() -> Result<(), Box<dyn Error>> {
+        let mut b = TiffBuilder::new(true, true);
+        let strips: Vec<u64> = (0..2).map(|i| b.data(&[i; 24])).collect();
+        b.ifd(vec![
+            (TAG_IMAGE_WIDTH, Value::Short(vec![4])),
+            (TAG_IMAGE_LENGTH, Value::Short(vec![4])),
+            (TAG_SAMPLES_PER_PIXEL, Value::Short(vec![3])),
+            (TAG_BITS_PER_SAMPLE, Value::Short(vec![8, 8, 8])),
+            (TAG_ROWS_PER_STRIP, Value::Short(vec![2])),
+            (TAG_STRIP_OFFSETS, Value::Long(strips.iter().map(|s| *s as u32).collect())),
+            (TAG_STRIP_BYTE_COUNTS, Value::Long(vec![24, 24])),
+        ]);
+        let file = Bytes::from(b.buf);
+
+        let scan = scan(&file, "s3://bucket/image.tif", &Path::root())?;
+        let rgb = &scan.arrays[0];
+        assert_eq!(rgb.metadata.shape, vec![4, 4, 3]);
+        assert_eq!(
+            rgb.metadata.chunk_shape.0.iter().map(|d| d.get()).collect::<Vec<_>>(),
+            vec![2, 4, 3]
+        );
+        assert_eq!(rgb.metadata.data_type, DataType::UInt8);
+        assert_eq!(
+            rgb.metadata.codecs,
+            vec![Codec { name: "bytes".to_string(), configuration: None }]
+        );
+        assert_eq!(
+            virtual_chunks(rgb),
+            vec![(vec![0, 0, 0], strips[0], 24), (vec![1, 0, 0], strips[1], 24)]
+        );
+
+        assert!(matches!(
+            super::scan(
+                &Bytes::from_static(b"PK\x03\x04"),
+                "s3://b/f.tif",
+                &Path::root()
+            ),
+            Err(ImportError::InvalidFile(_))
+        ));
+        Ok(())
+    }
+}
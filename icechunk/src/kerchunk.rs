@@ -0,0 +1,220 @@
+//! Export a snapshot as a kerchunk reference set.
+//!
+//! Kerchunk references let `fsspec` open a zarr hierarchy without icechunk: every key of
+//! the store maps to inline data, or to a byte range of some object. [`export`] maps the
+//! `zarr.json` documents and inline chunks to inline data, chunks stored by icechunk to
+//! byte ranges of its chunk objects, and virtual chunks to their own location.
+//!
+//! The export is a read-only view of one snapshot, later commits are not visible
+//! through it, and it breaks if garbage collection deletes the chunks it points to.
+use std::{collections::BTreeMap, sync::Arc};
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use futures::{StreamExt, TryStreamExt};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    format::{
+        manifest::{ChunkPayload, VirtualChunkLocation},
+        snapshot::NodeData,
+        ChunkIndices,
+    },
+    progress::{ProgressOperation, ProgressTracker},
+    repository::RepositoryResult,
+    zarr::{chunk_key, metadata_key, node_metadata_bytes},
+    Repository,
+};
+
+/// The target of one key
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Reference {
+    /// Inline data, text or `base64:` followed by the encoded bytes
+    Inline(String),
+    /// `length` bytes at `offset` of the object at `url`
+    Range(String, u64, u64),
+}
+
+/// A kerchunk reference set, version 1
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReferenceSet {
+    pub version: u32,
+    pub refs: BTreeMap<String, Reference>,
+}
+
+impl ReferenceSet {
+    /// The JSON document `fsspec` reads
+    pub fn to_json(&self) -> serde_json::Result<Vec<u8>> {
+        serde_json::to_vec(self)
+    }
+}
+
+fn inline(bytes: &[u8]) -> Reference {
+    match std::str::from_utf8(bytes) {
+        Ok(text) if !text.starts_with("base64:") => Reference::Inline(text.to_string()),
+        _ => Reference::Inline(format!("base64:{}", STANDARD.encode(bytes))),
+    }
+}
+
+fn reference(chunk_root: &str, payload: ChunkPayload) -> Reference {
+    match payload {
+        ChunkPayload::Inline(bytes) => inline(&bytes),
+        ChunkPayload::Ref(chunk) => Reference::Range(
+            format!("{chunk_root}/chunks/{}", chunk.id),
+            chunk.offset,
+            chunk.length,
+        ),
+        ChunkPayload::Virtual(chunk) => match chunk.location {
+            VirtualChunkLocation::Absolute(url) => {
+                Reference::Range(url, chunk.offset, chunk.length)
+            }
+        },
+    }
+}
+
+/// The reference set of the snapshot `repository` points to, including its
+/// uncommitted changes.
+///
+/// `chunk_root` is the URL of the repository prefix, like `s3://bucket/prefix`, chunk
+/// objects are under `{chunk_root}/chunks/`, as in the default
+/// [`KeyLayout`](crate::storage::KeyLayout). The chunks of concatenated arrays are
+/// looked up one coordinate at a time, so exporting large ones is slow.
+///
+/// Every reference built is reported to the progress listener of `repository`, as a
+/// [`ProgressOperation::KerchunkExport`].
+pub async fn export(
+    repository: &Repository,
+    chunk_root: &str,
+) -> RepositoryResult<ReferenceSet> {
+    let chunk_root = chunk_root.trim_end_matches('/');
+    let progress = ProgressTracker::new(
+        Arc::clone(repository.progress_listener()),
+        ProgressOperation::KerchunkExport,
+        None,
+    );
+    let mut refs = BTreeMap::new();
+    let paths: Vec<_> = repository.list_nodes().await?.map(|node| node.path).collect();
+    for path in paths {
+        // get_node resolves the user attributes stored out of the snapshot
+        let node = repository.get_node(&path).await?;
+        if let NodeData::Concatenated(metadata, _) = &node.node_data {
+            let grid = metadata.chunk_grid_shape().into_iter().map(|size| 0..size);
+            for coords in grid.multi_cartesian_product() {
+                let coords = ChunkIndices(coords);
+                if let Some(payload) = repository.get_chunk_ref(&path, &coords).await? {
+                    refs.insert(
+                        chunk_key(&path, &coords),
+                        reference(chunk_root, payload),
+                    );
+                    progress.advance(1, 0);
+                }
+            }
+        }
+        let metadata = node_metadata_bytes(repository.storage().as_ref(), node).await?;
+        refs.insert(metadata_key(&path), inline(&metadata));
+        progress.advance(1, 0);
+    }
+
+    let mut chunks = repository.all_chunks().await?.boxed();
+    while let Some((path, chunk)) = chunks.try_next().await? {
+        refs.insert(chunk_key(&path, &chunk.coord), reference(chunk_root, chunk.payload));
+        progress.advance(1, 0);
+    }
+    Ok(ReferenceSet { version: 1, refs })
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{
+            manifest::{ChunkRef, VirtualChunkRef},
+            ChunkId, Path,
+        },
+        metadata::FillValue,
+        progress::{Progress, RecordingProgress},
+        synthetic::vector_metadata,
+        ObjectStorage, Storage,
+    };
+
+    #[tokio::test]
+    async fn test_export() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let progress = Arc::new(RecordingProgress::default());
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_progress_listener(progress.clone())
+            .build();
+        let path: Path = "/group/array".try_into().unwrap();
+        repo.add_group(Path::root()).await?;
+        repo.add_group("/group".try_into().unwrap()).await?;
+        repo.add_array(path.clone(), vector_metadata(4, 2, FillValue::UInt8(0))).await?;
+        let id = ChunkId::random();
+        repo.set_chunk_ref(
+            path.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Ref(ChunkRef { id: id.clone(), offset: 10, length: 2 })),
+        )
+        .await?;
+        repo.set_chunk_ref(
+            path.clone(),
+            ChunkIndices(vec![1]),
+            Some(ChunkPayload::Virtual(VirtualChunkRef {
+                location: VirtualChunkLocation::Absolute(
+                    "s3://other/file.nc".to_string(),
+                ),
+                offset: 100,
+                length: 2,
+            })),
+        )
+        .await?;
+        repo.commit("main", "export", None).await?;
+
+        let set = export(&repo, "s3://bucket/prefix/").await?;
+        assert_eq!(
+            progress.updates().last(),
+            Some(&(
+                ProgressOperation::KerchunkExport,
+                Progress { done: 5, total: None, bytes: 0 }
+            ))
+        );
+        assert_eq!(
+            set.refs.keys().collect::<Vec<_>>(),
+            vec![
+                "group/array/c/0",
+                "group/array/c/1",
+                "group/array/zarr.json",
+                "group/zarr.json",
+                "zarr.json"
+            ]
+        );
+        assert_eq!(
+            set.refs["group/array/c/0"],
+            Reference::Range(format!("s3://bucket/prefix/chunks/{id}"), 10, 2)
+        );
+        assert_eq!(
+            set.refs["group/array/c/1"],
+            Reference::Range("s3://other/file.nc".to_string(), 100, 2)
+        );
+        let Reference::Inline(metadata) = &set.refs["group/array/zarr.json"] else {
+            panic!("metadata should be inline")
+        };
+        assert!(metadata.contains(r#""node_type":"array""#));
+
+        let json: serde_json::Value = serde_json::from_slice(&set.to_json()?)?;
+        assert_eq!(json["version"], 1);
+        assert_eq!(
+            json["refs"]["group/array/c/1"],
+            serde_json::json!(["s3://other/file.nc", 100, 2])
+        );
+        assert_eq!(inline(&[0xff, 0]), Reference::Inline("base64:/wA=".to_string()));
+        Ok(())
+    }
+}
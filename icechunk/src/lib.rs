@@ -9,24 +9,74 @@
 //! - There is a translation language between low and high levels. When user writes to a zarr key,
 //!   we need to convert that key to the language of arrays and groups. This is implemented it the
 //!   [`zarr`] module
-//! - There is an abstract type for loading and saving of the Arrow datastructures.
-//!   This is the [`Storage`] trait. It knows how to fetch and write arrow.
+//! - There is an abstract type for loading and saving of the datastructures.
+//!   This is the [`Storage`] trait. It knows how to fetch and write them.
 //!   We have:
-//!     - an in memory implementation
-//!     - an s3 implementation that writes to parquet
+//!     - an in memory and local filesystem implementation, using `object_store`
+//!     - an s3 implementation
 //!     - a caching wrapper implementation
 //! - The datastructures are represented by concrete types in the [`mod@format`] modules.
-//!   These datastructures use Arrow RecordBatches for representation.
+//!   These are plain Rust types, serialized with messagepack only inside the storage
+//!   implementations, so the public API doesn't depend on any serialization library.
+pub mod access;
+pub mod archive;
+pub mod audit;
+#[cfg(feature = "tokio-runtime")]
+pub mod blocking;
+pub mod catalog;
 pub mod change_set;
+pub mod clock;
+pub mod codecs;
+pub mod config;
+#[cfg(feature = "ndarray")]
+pub mod coordinates;
+#[cfg(feature = "ndarray")]
+pub mod decoded;
+pub mod error;
+pub mod filter;
 pub mod format;
+pub mod gc;
+pub mod health;
+pub mod import;
+pub mod kerchunk;
+pub mod memory;
 pub mod metadata;
+pub mod migrate;
+pub mod mirror;
+pub mod naming;
+pub mod notify;
+pub mod open;
+pub mod partition;
+pub mod pool;
+pub mod postprocess;
+pub mod progress;
+pub mod protection;
+pub mod provenance;
+pub mod read_plan;
+pub mod redaction;
 pub mod refs;
+pub mod repair;
 pub mod repository;
+pub mod revision;
+pub mod rolling;
+pub mod runtime;
+pub mod session;
+pub mod sink;
 pub mod storage;
 #[cfg(test)]
 pub mod strategies;
+pub mod structure;
+#[cfg(any(test, feature = "test-support"))]
+pub mod synthetic;
+pub mod telemetry;
+pub mod transaction;
+pub mod usage;
+pub mod validation;
+pub mod verification;
 pub mod zarr;
 
+pub use config::RepositoryConfigBuilder;
+pub use error::ErrorKind;
 pub use repository::{Repository, RepositoryBuilder, RepositoryConfig, SnapshotMetadata};
 pub use storage::{MemCachingStorage, ObjectStorage, Storage, StorageError};
 pub use zarr::Store;
@@ -0,0 +1,232 @@
+//! Accounting of the memory used by icechunk.
+//!
+//! A [`MemoryBudget`] can be shared by the caching storage and the repositories of an
+//! application, to bound the memory used by cached snapshots, manifests and chunks, and
+//! by chunks buffered while they are uploaded. Every tracked allocation holds a
+//! [`MemoryPermit`], memory is returned to the budget when the permit is dropped.
+//!
+//! Caches don't use more than what's available in the budget, and they are evicted when
+//! a chunk upload needs their memory. Uploads wait for memory to be freed if the budget
+//! is exhausted by other uploads.
+use std::{
+    collections::HashMap,
+    fmt,
+    pin::pin,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use tokio::sync::Notify;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum MemoryCategory {
+    CachedSnapshots,
+    CachedManifests,
+    CachedChunks,
+    /// Chunk payloads held in memory while they are uploaded
+    BufferedChunks,
+}
+
+type Evictor = Box<dyn Fn() -> bool + Send + Sync>;
+
+#[derive(Debug, Default)]
+struct Usage {
+    total: u64,
+    by_category: HashMap<MemoryCategory, u64>,
+}
+
+pub struct MemoryBudget {
+    limit: u64,
+    usage: Mutex<Usage>,
+    released: Notify,
+    // each evictor frees what it can, and returns false once its cache is gone
+    evictors: Mutex<Vec<Evictor>>,
+}
+
+impl fmt::Debug for MemoryBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MemoryBudget")
+            .field("limit", &self.limit)
+            .field("used", &self.used())
+            .finish()
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl MemoryBudget {
+    /// Create a budget of `limit` bytes
+    pub fn new(limit: u64) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            usage: Mutex::new(Usage::default()),
+            released: Notify::new(),
+            evictors: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    /// Total bytes currently held by permits
+    pub fn used(&self) -> u64 {
+        lock(&self.usage).total
+    }
+
+    pub fn used_by(&self, category: MemoryCategory) -> u64 {
+        lock(&self.usage).by_category.get(&category).copied().unwrap_or(0)
+    }
+
+    /// Get a permit for `bytes` only if it fits in the remaining budget.
+    ///
+    /// A request bigger than the whole budget is granted only if nothing else is using
+    /// memory, so it can always eventually succeed.
+    pub fn try_acquire(
+        self: &Arc<Self>,
+        category: MemoryCategory,
+        bytes: u64,
+    ) -> Option<MemoryPermit> {
+        let mut usage = lock(&self.usage);
+        let fits = usage.total.saturating_add(bytes) <= self.limit || usage.total == 0;
+        if !fits {
+            return None;
+        }
+        usage.total += bytes;
+        *usage.by_category.entry(category).or_default() += bytes;
+        Some(MemoryPermit { budget: Arc::clone(self), category, bytes })
+    }
+
+    /// Get a permit for `bytes`, evicting caches and then waiting for other permits to be
+    /// released if needed
+    pub async fn acquire(
+        self: &Arc<Self>,
+        category: MemoryCategory,
+        bytes: u64,
+    ) -> MemoryPermit {
+        loop {
+            // register for notifications before checking, to not miss a release
+            let mut released = pin!(self.released.notified());
+            released.as_mut().enable();
+
+            if let Some(permit) = self.try_acquire(category, bytes) {
+                return permit;
+            }
+            self.evict();
+            if let Some(permit) = self.try_acquire(category, bytes) {
+                return permit;
+            }
+            released.await;
+        }
+    }
+
+    /// Free all the memory used by registered caches
+    pub fn evict(&self) {
+        lock(&self.evictors).retain(|evict| evict());
+    }
+
+    pub(crate) fn register_evictor(&self, evictor: Evictor) {
+        lock(&self.evictors).push(evictor);
+    }
+
+    fn release(&self, category: MemoryCategory, bytes: u64) {
+        {
+            let mut usage = lock(&self.usage);
+            usage.total -= bytes;
+            if let Some(used) = usage.by_category.get_mut(&category) {
+                *used -= bytes;
+            }
+        }
+        self.released.notify_waiters();
+    }
+}
+
+/// Memory reserved in a [`MemoryBudget`], released on drop
+#[derive(Debug)]
+pub struct MemoryPermit {
+    budget: Arc<MemoryBudget>,
+    category: MemoryCategory,
+    bytes: u64,
+}
+
+impl MemoryPermit {
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+impl Drop for MemoryPermit {
+    fn drop(&mut self) {
+        self.budget.release(self.category, self.bytes);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_permits_accounting() {
+        let budget = MemoryBudget::new(100);
+        let a = budget.try_acquire(MemoryCategory::CachedManifests, 60).unwrap();
+        let b = budget.try_acquire(MemoryCategory::BufferedChunks, 40).unwrap();
+        assert_eq!(budget.used(), 100);
+        assert_eq!(budget.used_by(MemoryCategory::CachedManifests), 60);
+        assert_eq!(budget.used_by(MemoryCategory::BufferedChunks), 40);
+        assert_eq!(budget.used_by(MemoryCategory::CachedChunks), 0);
+        assert!(budget.try_acquire(MemoryCategory::CachedChunks, 1).is_none());
+
+        drop(a);
+        assert_eq!(budget.used(), 40);
+        assert_eq!(budget.used_by(MemoryCategory::CachedManifests), 0);
+        assert!(budget.try_acquire(MemoryCategory::CachedChunks, 61).is_none());
+        drop(b);
+
+        // oversized requests are granted when nothing else holds memory
+        let big = budget.try_acquire(MemoryCategory::BufferedChunks, 1000).unwrap();
+        assert_eq!(big.bytes(), 1000);
+        assert!(budget.try_acquire(MemoryCategory::BufferedChunks, 1).is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_acquire_evicts_then_waits() {
+        let budget = MemoryBudget::new(100);
+
+        let cached = Arc::new(Mutex::new(Some(
+            budget.try_acquire(MemoryCategory::CachedChunks, 50).unwrap(),
+        )));
+        let evictions = Arc::new(AtomicUsize::new(0));
+        let (cached_c, evictions_c) = (Arc::clone(&cached), Arc::clone(&evictions));
+        budget.register_evictor(Box::new(move || {
+            evictions_c.fetch_add(1, Ordering::Relaxed);
+            cached_c.lock().unwrap().take();
+            true
+        }));
+
+        let upload = budget.acquire(MemoryCategory::BufferedChunks, 60).await;
+        assert_eq!(evictions.load(Ordering::Relaxed), 1);
+        assert_eq!(budget.used_by(MemoryCategory::CachedChunks), 0);
+
+        // nothing left to evict, the second upload must wait for the first
+        let task = {
+            let budget = Arc::clone(&budget);
+            tokio::spawn(async move {
+                budget.acquire(MemoryCategory::BufferedChunks, 60).await.bytes()
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!task.is_finished());
+        drop(upload);
+        assert_eq!(task.await.unwrap(), 60);
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -36,6 +36,19 @@ impl DataType {
         }
     }
 
+    /// The size in bytes of an element, `None` for variable size types
+    pub fn size(&self) -> Option<usize> {
+        use DataType::*;
+        match self {
+            Bool | Int8 | UInt8 => Some(1),
+            Int16 | UInt16 | Float16 => Some(2),
+            Int32 | UInt32 | Float32 => Some(4),
+            Int64 | UInt64 | Float64 | Complex64 => Some(8),
+            Complex128 => Some(16),
+            String | Bytes => None,
+        }
+    }
+
     pub fn fits_u64(&self, n: u64) -> bool {
         use DataType::*;
         match self {
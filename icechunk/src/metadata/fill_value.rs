@@ -1,4 +1,3 @@
-use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use test_strategy::Arbitrary;
 
@@ -32,184 +31,105 @@ impl FillValue {
     pub const INF_STR: &'static str = "Infinity";
     pub const NEG_INF_STR: &'static str = "-Infinity";
 
-    pub fn from_data_type_and_json(
+    /// The fill value of an array of type `dt` that `document` represents
+    pub fn from_document(
         dt: &DataType,
-        value: &serde_json::Value,
+        document: &FillValueDocument,
     ) -> Result<Self, IcechunkFormatError> {
-        #![allow(clippy::expect_used)] // before calling `as_foo` we check with `fits_foo`
-        match (dt, value) {
-            (DataType::Bool, serde_json::Value::Bool(b)) => Ok(FillValue::Bool(*b)),
-            (DataType::Int8, serde_json::Value::Number(n))
-                if n.as_i64().map(|n| dt.fits_i64(n)) == Some(true) =>
-            {
-                Ok(FillValue::Int8(
-                    n.as_i64().expect("bug in from_data_type_and_json") as i8
-                ))
+        let integer = document.as_integer();
+        let float = document.as_float();
+        let value = match (dt, document) {
+            (DataType::Bool, FillValueDocument::Bool(b)) => Some(FillValue::Bool(*b)),
+            (DataType::Int8, _) => {
+                integer.and_then(|n| n.try_into().ok()).map(FillValue::Int8)
             }
-            (DataType::Int16, serde_json::Value::Number(n))
-                if n.as_i64().map(|n| dt.fits_i64(n)) == Some(true) =>
-            {
-                Ok(FillValue::Int16(
-                    n.as_i64().expect("bug in from_data_type_and_json") as i16
-                ))
+            (DataType::Int16, _) => {
+                integer.and_then(|n| n.try_into().ok()).map(FillValue::Int16)
             }
-            (DataType::Int32, serde_json::Value::Number(n))
-                if n.as_i64().map(|n| dt.fits_i64(n)) == Some(true) =>
-            {
-                Ok(FillValue::Int32(
-                    n.as_i64().expect("bug in from_data_type_and_json") as i32
-                ))
+            (DataType::Int32, _) => {
+                integer.and_then(|n| n.try_into().ok()).map(FillValue::Int32)
             }
-            (DataType::Int64, serde_json::Value::Number(n))
-                if n.as_i64().map(|n| dt.fits_i64(n)) == Some(true) =>
-            {
-                Ok(FillValue::Int64(n.as_i64().expect("bug in from_data_type_and_json")))
+            (DataType::Int64, _) => {
+                integer.and_then(|n| n.try_into().ok()).map(FillValue::Int64)
             }
-            (DataType::UInt8, serde_json::Value::Number(n))
-                if n.as_u64().map(|n| dt.fits_u64(n)) == Some(true) =>
-            {
-                Ok(FillValue::UInt8(
-                    n.as_u64().expect("bug in from_data_type_and_json") as u8
-                ))
+            (DataType::UInt8, _) => {
+                integer.and_then(|n| n.try_into().ok()).map(FillValue::UInt8)
             }
-            (DataType::UInt16, serde_json::Value::Number(n))
-                if n.as_u64().map(|n| dt.fits_u64(n)) == Some(true) =>
-            {
-                Ok(FillValue::UInt16(
-                    n.as_u64().expect("bug in from_data_type_and_json") as u16
-                ))
+            (DataType::UInt16, _) => {
+                integer.and_then(|n| n.try_into().ok()).map(FillValue::UInt16)
             }
-            (DataType::UInt32, serde_json::Value::Number(n))
-                if n.as_u64().map(|n| dt.fits_u64(n)) == Some(true) =>
-            {
-                Ok(FillValue::UInt32(
-                    n.as_u64().expect("bug in from_data_type_and_json") as u32
-                ))
+            (DataType::UInt32, _) => {
+                integer.and_then(|n| n.try_into().ok()).map(FillValue::UInt32)
             }
-            (DataType::UInt64, serde_json::Value::Number(n))
-                if n.as_u64().map(|n| dt.fits_u64(n)) == Some(true) =>
-            {
-                Ok(FillValue::UInt64(n.as_u64().expect("bug in from_data_type_and_json")))
+            (DataType::UInt64, _) => {
+                integer.and_then(|n| n.try_into().ok()).map(FillValue::UInt64)
             }
-            (DataType::Float16, serde_json::Value::Number(n)) if n.as_f64().is_some() => {
-                // FIXME: limits logic
-                Ok(FillValue::Float16(
-                    n.as_f64().expect("bug in from_data_type_and_json") as f32,
-                ))
-            }
-            (DataType::Float16, serde_json::Value::String(s))
-                if s.as_str() == FillValue::NAN_STR =>
-            {
-                Ok(FillValue::Float16(f32::NAN))
-            }
-            (DataType::Float16, serde_json::Value::String(s))
-                if s.as_str() == FillValue::INF_STR =>
-            {
-                Ok(FillValue::Float16(f32::INFINITY))
-            }
-            (DataType::Float16, serde_json::Value::String(s))
-                if s.as_str() == FillValue::NEG_INF_STR =>
-            {
-                Ok(FillValue::Float16(f32::NEG_INFINITY))
-            }
-
-            (DataType::Float32, serde_json::Value::Number(n)) if n.as_f64().is_some() => {
-                // FIXME: limits logic
-                Ok(FillValue::Float32(
-                    n.as_f64().expect("bug in from_data_type_and_json") as f32,
-                ))
-            }
-            (DataType::Float32, serde_json::Value::String(s))
-                if s.as_str() == FillValue::NAN_STR =>
-            {
-                Ok(FillValue::Float32(f32::NAN))
-            }
-            (DataType::Float32, serde_json::Value::String(s))
-                if s.as_str() == FillValue::INF_STR =>
-            {
-                Ok(FillValue::Float32(f32::INFINITY))
-            }
-            (DataType::Float32, serde_json::Value::String(s))
-                if s.as_str() == FillValue::NEG_INF_STR =>
-            {
-                Ok(FillValue::Float32(f32::NEG_INFINITY))
-            }
-
-            (DataType::Float64, serde_json::Value::Number(n)) if n.as_f64().is_some() => {
-                // FIXME: limits logic
-                Ok(FillValue::Float64(
-                    n.as_f64().expect("bug in from_data_type_and_json"),
-                ))
-            }
-            (DataType::Float64, serde_json::Value::String(s))
-                if s.as_str() == FillValue::NAN_STR =>
-            {
-                Ok(FillValue::Float64(f64::NAN))
-            }
-            (DataType::Float64, serde_json::Value::String(s))
-                if s.as_str() == FillValue::INF_STR =>
-            {
-                Ok(FillValue::Float64(f64::INFINITY))
-            }
-            (DataType::Float64, serde_json::Value::String(s))
-                if s.as_str() == FillValue::NEG_INF_STR =>
-            {
-                Ok(FillValue::Float64(f64::NEG_INFINITY))
-            }
-            (DataType::Complex64, serde_json::Value::Array(arr)) if arr.len() == 2 => {
-                let r = FillValue::from_data_type_and_json(&DataType::Float32, &arr[0])?;
-                let i = FillValue::from_data_type_and_json(&DataType::Float32, &arr[1])?;
-                match (r, i) {
-                    (FillValue::Float32(r), FillValue::Float32(i)) => {
-                        Ok(FillValue::Complex64(r, i))
-                    }
-                    _ => Err(IcechunkFormatError::FillValueParse {
-                        data_type: dt.clone(),
-                        value: value.clone(),
-                    }),
+            // FIXME: limits logic
+            (DataType::Float16, _) => float.map(|f| FillValue::Float16(f as f32)),
+            (DataType::Float32, _) => float.map(|f| FillValue::Float32(f as f32)),
+            (DataType::Float64, _) => float.map(FillValue::Float64),
+            (DataType::Complex64, FillValueDocument::Array(parts)) => {
+                match parts.as_slice() {
+                    [r, i] => r
+                        .as_float()
+                        .zip(i.as_float())
+                        .map(|(r, i)| FillValue::Complex64(r as f32, i as f32)),
+                    _ => None,
                 }
             }
-            (DataType::Complex128, serde_json::Value::Array(arr)) if arr.len() == 2 => {
-                let r = FillValue::from_data_type_and_json(&DataType::Float64, &arr[0])?;
-                let i = FillValue::from_data_type_and_json(&DataType::Float64, &arr[1])?;
-                match (r, i) {
-                    (FillValue::Float64(r), FillValue::Float64(i)) => {
-                        Ok(FillValue::Complex128(r, i))
-                    }
-                    _ => Err(IcechunkFormatError::FillValueParse {
-                        data_type: dt.clone(),
-                        value: value.clone(),
-                    }),
+            (DataType::Complex128, FillValueDocument::Array(parts)) => {
+                match parts.as_slice() {
+                    [r, i] => r
+                        .as_float()
+                        .zip(i.as_float())
+                        .map(|(r, i)| FillValue::Complex128(r, i)),
+                    _ => None,
                 }
             }
-
-            (DataType::String, serde_json::Value::String(s)) => {
-                Ok(FillValue::String(s.clone()))
+            (DataType::String, FillValueDocument::String(s)) => {
+                Some(FillValue::String(s.clone()))
             }
+            (DataType::Bytes, FillValueDocument::Array(bytes)) => bytes
+                .iter()
+                .map(|b| b.as_integer().and_then(|n| n.try_into().ok()))
+                .collect::<Option<_>>()
+                .map(FillValue::Bytes),
+            _ => None,
+        };
+        value.ok_or_else(|| IcechunkFormatError::FillValueParse {
+            data_type: dt.clone(),
+            value: serde_json::to_value(document).unwrap_or_default(),
+        })
+    }
 
-            (DataType::Bytes, serde_json::Value::Array(arr)) => {
-                let bytes = arr
-                    .iter()
-                    .map(|b| FillValue::from_data_type_and_json(&DataType::UInt8, b))
-                    .collect::<Result<Vec<_>, _>>()?;
-                Ok(FillValue::Bytes(
-                    bytes
-                        .iter()
-                        .map(|b| match b {
-                            FillValue::UInt8(n) => Ok(*n),
-                            _ => Err(IcechunkFormatError::FillValueParse {
-                                data_type: dt.clone(),
-                                value: value.clone(),
-                            }),
-                        })
-                        .try_collect()?,
-                ))
+    /// The document representing this fill value in zarr metadata
+    pub fn to_document(&self) -> FillValueDocument {
+        match self {
+            FillValue::Bool(b) => FillValueDocument::Bool(*b),
+            FillValue::Int8(n) => FillValueDocument::Int((*n).into()),
+            FillValue::Int16(n) => FillValueDocument::Int((*n).into()),
+            FillValue::Int32(n) => FillValueDocument::Int((*n).into()),
+            FillValue::Int64(n) => FillValueDocument::Int(*n),
+            FillValue::UInt8(n) => FillValueDocument::UInt((*n).into()),
+            FillValue::UInt16(n) => FillValueDocument::UInt((*n).into()),
+            FillValue::UInt32(n) => FillValueDocument::UInt((*n).into()),
+            FillValue::UInt64(n) => FillValueDocument::UInt(*n),
+            FillValue::Float16(f) | FillValue::Float32(f) => {
+                FillValueDocument::from_float((*f).into())
             }
-
-            _ => Err(IcechunkFormatError::FillValueParse {
-                data_type: dt.clone(),
-                value: value.clone(),
-            }),
+            FillValue::Float64(f) => FillValueDocument::from_float(*f),
+            FillValue::Complex64(r, i) => FillValueDocument::Array(vec![
+                FillValueDocument::from_float((*r).into()),
+                FillValueDocument::from_float((*i).into()),
+            ]),
+            FillValue::Complex128(r, i) => FillValueDocument::Array(vec![
+                FillValueDocument::from_float(*r),
+                FillValueDocument::from_float(*i),
+            ]),
+            FillValue::String(s) => FillValueDocument::String(s.clone()),
+            FillValue::Bytes(b) => FillValueDocument::Array(
+                b.iter().map(|b| FillValueDocument::UInt((*b).into())).collect(),
+            ),
         }
     }
 
@@ -235,46 +155,112 @@ impl FillValue {
     }
 }
 
+/// The `fill_value` of zarr array metadata, what it means depends on the data type of
+/// the array, see [`FillValue::from_document`]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FillValueDocument {
+    Bool(bool),
+    UInt(u64),
+    Int(i64),
+    Float(f64),
+    /// A string, or `NaN`, `Infinity` and `-Infinity` for floats
+    String(String),
+    /// The real and imaginary parts of a complex number, or bytes
+    Array(Vec<FillValueDocument>),
+}
+
+impl FillValueDocument {
+    fn from_float(f: f64) -> Self {
+        if f.is_nan() {
+            FillValueDocument::String(FillValue::NAN_STR.to_string())
+        } else if f == f64::INFINITY {
+            FillValueDocument::String(FillValue::INF_STR.to_string())
+        } else if f == f64::NEG_INFINITY {
+            FillValueDocument::String(FillValue::NEG_INF_STR.to_string())
+        } else {
+            FillValueDocument::Float(f)
+        }
+    }
+
+    fn as_integer(&self) -> Option<i128> {
+        match self {
+            FillValueDocument::UInt(n) => Some((*n).into()),
+            FillValueDocument::Int(n) => Some((*n).into()),
+            _ => None,
+        }
+    }
+
+    fn as_float(&self) -> Option<f64> {
+        match self {
+            FillValueDocument::UInt(n) => Some(*n as f64),
+            FillValueDocument::Int(n) => Some(*n as f64),
+            FillValueDocument::Float(f) => Some(*f),
+            FillValueDocument::String(s) => match s.as_str() {
+                FillValue::NAN_STR => Some(f64::NAN),
+                FillValue::INF_STR => Some(f64::INFINITY),
+                FillValue::NEG_INF_STR => Some(f64::NEG_INFINITY),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
 #[cfg(test)]
 #[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
 mod tests {
     use super::*;
 
+    fn parse(dt: DataType, json: &str) -> Result<FillValue, IcechunkFormatError> {
+        FillValue::from_document(&dt, &serde_json::from_str(json).unwrap())
+    }
+
     #[test]
     fn test_nan_inf_parsing() {
-        assert_eq!(
-            FillValue::from_data_type_and_json(&DataType::Float64, &55f64.into())
-                .unwrap(),
-            FillValue::Float64(55.0)
-        );
-
-        assert_eq!(
-            FillValue::from_data_type_and_json(&DataType::Float64, &55.into()).unwrap(),
-            FillValue::Float64(55.0)
-        );
-
-        assert!(matches!(FillValue::from_data_type_and_json(
-            &DataType::Float64,
-            &"NaN".into()
-        )
-        .unwrap(),
+        assert_eq!(parse(DataType::Float64, "55.0").unwrap(), FillValue::Float64(55.0));
+        assert_eq!(parse(DataType::Float64, "55").unwrap(), FillValue::Float64(55.0));
+        assert!(matches!(
+            parse(DataType::Float64, r#""NaN""#).unwrap(),
             FillValue::Float64(n) if n.is_nan()
         ));
-
-        assert!(matches!(FillValue::from_data_type_and_json(
-            &DataType::Float64,
-            &"Infinity".into()
-        )
-        .unwrap(),
+        assert!(matches!(
+            parse(DataType::Float64, r#""Infinity""#).unwrap(),
             FillValue::Float64(n) if n == f64::INFINITY
         ));
-
-        assert!(matches!(FillValue::from_data_type_and_json(
-            &DataType::Float64,
-            &"-Infinity".into()
-        )
-        .unwrap(),
+        assert!(matches!(
+            parse(DataType::Float64, r#""-Infinity""#).unwrap(),
             FillValue::Float64(n) if n == f64::NEG_INFINITY
         ));
     }
+
+    #[test]
+    fn test_document_round_trip() {
+        let values = [
+            FillValue::Bool(true),
+            FillValue::Int8(-3),
+            FillValue::Int64(i64::MIN),
+            FillValue::UInt64(u64::MAX),
+            FillValue::Float32(1.5),
+            FillValue::Float64(f64::NEG_INFINITY),
+            FillValue::Complex128(2.0, -1.0),
+            FillValue::String("missing".to_string()),
+            FillValue::Bytes(vec![0, 255]),
+        ];
+        for value in values {
+            let json = serde_json::to_string(&value.to_document()).unwrap();
+            assert_eq!(parse(value.get_data_type(), &json).unwrap(), value);
+        }
+        assert_eq!(
+            serde_json::to_string(&FillValue::Complex64(f32::NAN, 0.0).to_document())
+                .unwrap(),
+            r#"["NaN",0.0]"#
+        );
+
+        assert!(parse(DataType::Int8, "128").is_err());
+        assert!(parse(DataType::UInt8, "-1").is_err());
+        assert!(parse(DataType::Int32, "1.5").is_err());
+        assert!(parse(DataType::Bytes, "[1, 256]").is_err());
+        assert!(parse(DataType::Complex64, "[1]").is_err());
+    }
 }
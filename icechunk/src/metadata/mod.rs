@@ -8,7 +8,7 @@ pub mod data_type;
 pub mod fill_value;
 
 pub use data_type::DataType;
-pub use fill_value::FillValue;
+pub use fill_value::{FillValue, FillValueDocument};
 
 /// The shape of an array.
 /// 0 is a valid shape member
@@ -17,6 +17,31 @@ pub type ArrayShape = Vec<u64>;
 pub type DimensionName = Option<String>;
 pub type DimensionNames = Vec<DimensionName>;
 
+/// An axis of an array, by position or by dimension name
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Axis {
+    Index(usize),
+    Name(String),
+}
+
+impl From<usize> for Axis {
+    fn from(value: usize) -> Self {
+        Axis::Index(value)
+    }
+}
+
+impl From<&str> for Axis {
+    fn from(value: &str) -> Self {
+        Axis::Name(value.to_string())
+    }
+}
+
+impl From<String> for Axis {
+    fn from(value: String) -> Self {
+        Axis::Name(value)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Codec {
     pub name: String,
@@ -32,6 +57,133 @@ pub struct StorageTransformer {
 #[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub struct ChunkShape(pub Vec<NonZeroU64>);
 
+/// The chunks of one dimension of a [`RectilinearGrid`]
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum DimensionChunks {
+    /// All chunks have this size, the last one can extend past the end of the array
+    Regular(NonZeroU64),
+    /// The size of each chunk, they add up to the size of the dimension
+    Sizes(Vec<NonZeroU64>),
+}
+
+/// A chunk grid with a list of chunk sizes for some dimensions, as in the zarr proposal
+/// for variable chunking
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct RectilinearGrid(pub Vec<DimensionChunks>);
+
+impl RectilinearGrid {
+    /// The largest chunk along each dimension
+    pub fn max_chunk_shape(&self) -> ChunkShape {
+        ChunkShape(
+            self.0
+                .iter()
+                .map(|dim| match dim {
+                    DimensionChunks::Regular(size) => *size,
+                    DimensionChunks::Sizes(sizes) => {
+                        sizes.iter().max().copied().unwrap_or(NonZeroU64::MIN)
+                    }
+                })
+                .collect(),
+        )
+    }
+}
+
+/// The chunk grid of an array, serialized as the `chunk_grid` of zarr v3 metadata
+#[derive(Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(into = "ChunkGridDocument", from = "ChunkGridDocument")]
+pub enum ChunkGrid {
+    Regular(ChunkShape),
+    Rectilinear(RectilinearGrid),
+}
+
+impl ChunkGrid {
+    /// The shape of the chunks of regular grids, the largest chunk along each dimension
+    /// of rectilinear ones
+    pub fn chunk_shape(&self) -> ChunkShape {
+        match self {
+            ChunkGrid::Regular(shape) => shape.clone(),
+            ChunkGrid::Rectilinear(grid) => grid.max_chunk_shape(),
+        }
+    }
+
+    pub fn ndim(&self) -> usize {
+        match self {
+            ChunkGrid::Regular(shape) => shape.0.len(),
+            ChunkGrid::Rectilinear(grid) => grid.0.len(),
+        }
+    }
+}
+
+// `{"name": "regular", "configuration": {"chunk_shape": [...]}}`, rectilinear grids
+// have `{"kind": "inline", "chunk_shapes": [...]}` as configuration, with a chunk size
+// or a list of chunk sizes for each dimension
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "name", content = "configuration", rename_all = "lowercase")]
+enum ChunkGridDocument {
+    Regular { chunk_shape: ChunkShape },
+    Rectilinear { kind: RectilinearKind, chunk_shapes: Vec<DimensionChunksDocument> },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RectilinearKind {
+    Inline,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum DimensionChunksDocument {
+    Regular(NonZeroU64),
+    Sizes(Vec<NonZeroU64>),
+}
+
+impl From<ChunkGrid> for ChunkGridDocument {
+    fn from(value: ChunkGrid) -> Self {
+        match value {
+            ChunkGrid::Regular(chunk_shape) => ChunkGridDocument::Regular { chunk_shape },
+            ChunkGrid::Rectilinear(grid) => ChunkGridDocument::Rectilinear {
+                kind: RectilinearKind::Inline,
+                chunk_shapes: grid
+                    .0
+                    .into_iter()
+                    .map(|dim| match dim {
+                        DimensionChunks::Regular(size) => {
+                            DimensionChunksDocument::Regular(size)
+                        }
+                        DimensionChunks::Sizes(sizes) => {
+                            DimensionChunksDocument::Sizes(sizes)
+                        }
+                    })
+                    .collect(),
+            },
+        }
+    }
+}
+
+impl From<ChunkGridDocument> for ChunkGrid {
+    fn from(value: ChunkGridDocument) -> Self {
+        match value {
+            ChunkGridDocument::Regular { chunk_shape } => ChunkGrid::Regular(chunk_shape),
+            ChunkGridDocument::Rectilinear {
+                kind: RectilinearKind::Inline,
+                chunk_shapes,
+            } => ChunkGrid::Rectilinear(RectilinearGrid(
+                chunk_shapes
+                    .into_iter()
+                    .map(|dim| match dim {
+                        DimensionChunksDocument::Regular(size) => {
+                            DimensionChunks::Regular(size)
+                        }
+                        DimensionChunksDocument::Sizes(sizes) => {
+                            DimensionChunks::Sizes(sizes)
+                        }
+                    })
+                    .collect(),
+            )),
+        }
+    }
+}
+
 #[derive(Arbitrary, Copy, Clone, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub enum ChunkKeyEncoding {
     Slash,
@@ -46,6 +198,10 @@ pub struct UserAttributes {
 }
 
 impl UserAttributes {
+    /// The attribute that marks an array as frozen when it's `true`, see
+    /// [`crate::RepositoryConfig::frozen_arrays`]
+    pub const FROZEN: &'static str = "icechunk.frozen";
+
     pub fn try_new(json: &[u8]) -> Result<UserAttributes, serde_json::Error> {
         serde_json::from_slice(json).map(|json| UserAttributes { parsed: json })
     }
@@ -57,6 +213,10 @@ impl UserAttributes {
             .expect("Bug in UserAttributes serialization")
             .into()
     }
+
+    pub fn is_frozen(&self) -> bool {
+        self.parsed.get(Self::FROZEN).and_then(serde_json::Value::as_bool) == Some(true)
+    }
 }
 
 impl TryFrom<u8> for ChunkKeyEncoding {
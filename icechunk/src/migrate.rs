@@ -0,0 +1,469 @@
+//! Migration of repositories between on-storage format versions.
+//!
+//! A [`Migrator`] walks every snapshot reachable from any version of any ref, and
+//! rewrites snapshots and manifests with a format version older than the target by
+//! applying a chain of [`Migration`]s, one per format version. Chunks are never
+//! rewritten. Object ids are preserved, so history and refs stay valid.
+//!
+//! Migrations operate on the current in-memory types, after the old objects are
+//! deserialized, which covers format changes that only add fields.
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt,
+    sync::Arc,
+};
+
+use bytes::Bytes;
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    format::{
+        format_constants,
+        manifest::{ChunkPayload, Manifest},
+        snapshot::{NodeData, Snapshot},
+        AttributesId, ChunkId, IcechunkFormatVersion, ManifestId, SnapshotId,
+    },
+    refs::RefData,
+    storage::copy_chunk,
+    Storage, StorageError,
+};
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum MigrationError {
+    #[error("error contacting storage {0}")]
+    StorageError(#[from] StorageError),
+    #[error("invalid ref data {0}")]
+    InvalidRef(#[from] serde_json::Error),
+    #[error("no migration from format version {from} to {target}")]
+    NoMigrationPath { from: IcechunkFormatVersion, target: IcechunkFormatVersion },
+    #[error(
+        "{object} has format version {version}, newer than the target version {target}"
+    )]
+    FormatVersionTooNew {
+        object: String,
+        version: IcechunkFormatVersion,
+        target: IcechunkFormatVersion,
+    },
+    #[error("migration failed: {0}")]
+    Other(String),
+}
+
+pub type MigrationResult<T> = Result<T, MigrationError>;
+
+/// Upgrades objects from format version `source_version` to `source_version + 1`
+pub trait Migration: fmt::Debug + Send + Sync {
+    fn source_version(&self) -> IcechunkFormatVersion;
+
+    fn migrate_snapshot(&self, snapshot: Snapshot) -> MigrationResult<Snapshot> {
+        Ok(snapshot)
+    }
+
+    fn migrate_manifest(&self, manifest: Manifest) -> MigrationResult<Manifest> {
+        Ok(manifest)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MigrationReport {
+    pub snapshots_rewritten: usize,
+    pub manifests_rewritten: usize,
+    /// Chunks copied to the target storage, always 0 for in place migrations
+    pub chunks_copied: usize,
+    /// Of the chunks copied, those copied by the storage service without downloading them
+    pub chunks_copied_server_side: usize,
+    /// Ref versions copied to the target storage, always 0 for in place migrations
+    pub refs_copied: usize,
+}
+
+#[derive(Debug)]
+pub struct Migrator {
+    migrations: Vec<Arc<dyn Migration>>,
+    target_version: IcechunkFormatVersion,
+}
+
+impl Default for Migrator {
+    /// Migrate to the latest format, using the migrations shipped with this library
+    fn default() -> Self {
+        // there is a single format version for now, new migrations get registered here
+        Self::new(format_constants::LATEST_ICECHUNK_SNAPSHOT_FORMAT)
+    }
+}
+
+impl Migrator {
+    /// A migrator to `target_version` with no migrations registered
+    pub fn new(target_version: IcechunkFormatVersion) -> Self {
+        Self { migrations: Vec::new(), target_version }
+    }
+
+    pub fn with_migration(mut self, migration: Arc<dyn Migration>) -> Self {
+        self.migrations.push(migration);
+        self
+    }
+
+    pub fn target_version(&self) -> IcechunkFormatVersion {
+        self.target_version
+    }
+
+    /// Rewrite the outdated metadata objects of the repository, under the same ids.
+    ///
+    /// Caches in front of `storage` may keep serving the old objects.
+    pub async fn migrate_in_place(
+        &self,
+        storage: &dyn Storage,
+    ) -> MigrationResult<MigrationReport> {
+        self.run(storage, None).await
+    }
+
+    /// Write the migrated repository to `target`, which must be empty.
+    ///
+    /// All refs, snapshots and manifests are written, up to date or not, and chunks are
+    /// copied unchanged.
+    pub async fn migrate_to(
+        &self,
+        source: &dyn Storage,
+        target: &dyn Storage,
+    ) -> MigrationResult<MigrationReport> {
+        self.run(source, Some(target)).await
+    }
+
+    /// Check there is a migration for every version in `from..target_version`
+    fn check_path(&self, from: IcechunkFormatVersion) -> MigrationResult<()> {
+        for version in from..self.target_version {
+            self.migration_from(version)?;
+        }
+        Ok(())
+    }
+
+    fn migration_from(
+        &self,
+        version: IcechunkFormatVersion,
+    ) -> MigrationResult<&Arc<dyn Migration>> {
+        self.migrations.iter().find(|m| m.source_version() == version).ok_or(
+            MigrationError::NoMigrationPath {
+                from: version,
+                target: self.target_version,
+            },
+        )
+    }
+
+    fn check_version(
+        &self,
+        object: impl FnOnce() -> String,
+        version: IcechunkFormatVersion,
+    ) -> MigrationResult<bool> {
+        if version > self.target_version {
+            return Err(MigrationError::FormatVersionTooNew {
+                object: object(),
+                version,
+                target: self.target_version,
+            });
+        }
+        self.check_path(version)?;
+        Ok(version < self.target_version)
+    }
+
+    fn migrate_snapshot(&self, mut snapshot: Snapshot) -> MigrationResult<Snapshot> {
+        for version in snapshot.icechunk_snapshot_format_version..self.target_version {
+            snapshot = self.migration_from(version)?.migrate_snapshot(snapshot)?;
+        }
+        snapshot.icechunk_snapshot_format_version = self.target_version;
+        Ok(snapshot)
+    }
+
+    fn migrate_manifest(&self, mut manifest: Manifest) -> MigrationResult<Manifest> {
+        for version in manifest.icechunk_manifest_format_version..self.target_version {
+            manifest = self.migration_from(version)?.migrate_manifest(manifest)?;
+        }
+        manifest.icechunk_manifest_format_version = self.target_version;
+        Ok(manifest)
+    }
+
+    async fn run(
+        &self,
+        source: &dyn Storage,
+        target: Option<&dyn Storage>,
+    ) -> MigrationResult<MigrationReport> {
+        let mut report = MigrationReport::default();
+        let refs = all_ref_versions(source).await?;
+
+        let mut pending: VecDeque<SnapshotId> =
+            refs.iter().map(|(_, _, data)| data.snapshot.clone()).collect();
+        let mut seen_snapshots = HashSet::new();
+        let mut seen_manifests: HashSet<ManifestId> = HashSet::new();
+        let mut seen_chunks: HashSet<ChunkId> = HashSet::new();
+        let mut seen_attributes: HashSet<AttributesId> = HashSet::new();
+
+        while let Some(snapshot_id) = pending.pop_front() {
+            if !seen_snapshots.insert(snapshot_id.clone()) {
+                continue;
+            }
+            let snapshot = source.fetch_snapshot(&snapshot_id).await?;
+            pending
+                .extend(snapshot.short_term_history.front().map(|meta| meta.id.clone()));
+
+            let manifest_ids = snapshot.iter().flat_map(|node| match &node.node_data {
+                NodeData::Array(_, manifests) => {
+                    manifests.iter().map(|m| m.object_id.clone()).collect()
+                }
+                NodeData::Group | NodeData::Concatenated(..) => Vec::new(),
+            });
+            for manifest_id in manifest_ids.collect::<Vec<_>>() {
+                if !seen_manifests.insert(manifest_id.clone()) {
+                    continue;
+                }
+                let manifest = source.fetch_manifests(&manifest_id).await?;
+                let outdated = self.check_version(
+                    || format!("manifest {manifest_id}"),
+                    manifest.icechunk_manifest_format_version,
+                )?;
+
+                if let Some(target) = target {
+                    for payload in manifest.chunks().values() {
+                        if let ChunkPayload::Ref(chunk_ref) = payload {
+                            if seen_chunks.insert(chunk_ref.id.clone()) {
+                                if copy_chunk(source, target, &chunk_ref.id).await? {
+                                    report.chunks_copied_server_side += 1;
+                                }
+                                report.chunks_copied += 1;
+                            }
+                        }
+                    }
+                }
+
+                if outdated || target.is_some() {
+                    let migrated =
+                        self.migrate_manifest(Arc::unwrap_or_clone(manifest))?;
+                    target
+                        .unwrap_or(source)
+                        .write_manifests(manifest_id, Arc::new(migrated))
+                        .await?;
+                    if outdated {
+                        report.manifests_rewritten += 1;
+                    }
+                }
+            }
+
+            if let Some(target) = target {
+                for file in snapshot.attribute_files.iter() {
+                    if seen_attributes.insert(file.id.clone()) {
+                        let table = source.fetch_attributes(&file.id).await?;
+                        target.write_attributes(file.id.clone(), table).await?;
+                    }
+                }
+            }
+
+            let outdated = self.check_version(
+                || format!("snapshot {snapshot_id}"),
+                snapshot.icechunk_snapshot_format_version,
+            )?;
+            if outdated || target.is_some() {
+                let migrated = self.migrate_snapshot(Arc::unwrap_or_clone(snapshot))?;
+                target
+                    .unwrap_or(source)
+                    .write_snapshot(snapshot_id, Arc::new(migrated))
+                    .await?;
+                if outdated {
+                    report.snapshots_rewritten += 1;
+                }
+            }
+        }
+
+        // refs go last, so they only point to snapshots that already exist in the target
+        if let Some(target) = target {
+            for (key, bytes, _) in refs {
+                target.write_ref(key.as_str(), false, bytes).await?;
+                report.refs_copied += 1;
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// Every version of every ref in the repository, with its key and raw contents
+async fn all_ref_versions(
+    storage: &dyn Storage,
+) -> MigrationResult<Vec<(String, Bytes, RefData)>> {
+    let mut res = Vec::new();
+    for ref_name in storage.ref_names().await? {
+        let versions: Vec<String> =
+            storage.ref_versions(ref_name.as_str()).await?.try_collect().await?;
+        for version in versions {
+            let key = format!("{ref_name}/{version}");
+            let bytes = storage.get_ref(key.as_str()).await?;
+            let data: RefData = serde_json::from_slice(bytes.as_ref())?;
+            res.push((key, bytes, data));
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{ByteRange, ChunkIndices, Path},
+        metadata::FillValue,
+        refs::{fetch_branch_tip, fetch_tag, list_refs},
+        synthetic::{generate, vector_metadata, SyntheticRepoConfig},
+        ObjectStorage, Repository,
+    };
+
+    /// A migration that marks every object with a format flag
+    #[derive(Debug)]
+    struct MarkMigrated;
+
+    impl Migration for MarkMigrated {
+        fn source_version(&self) -> IcechunkFormatVersion {
+            0
+        }
+
+        fn migrate_snapshot(&self, mut snapshot: Snapshot) -> MigrationResult<Snapshot> {
+            snapshot
+                .icechunk_snapshot_format_flags
+                .insert("migrated".to_string(), rmpv::Value::Boolean(true));
+            Ok(snapshot)
+        }
+
+        fn migrate_manifest(&self, mut manifest: Manifest) -> MigrationResult<Manifest> {
+            manifest
+                .icechunk_manifest_format_flags
+                .insert("migrated".to_string(), rmpv::Value::Boolean(true));
+            Ok(manifest)
+        }
+    }
+
+    async fn make_repo() -> Result<Arc<dyn Storage>, Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(2, 1, FillValue::Int32(0))).await?;
+        let payload = ds.get_chunk_writer()(Bytes::from_static(b"hello")).await?;
+        ds.set_chunk_ref(array.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+        ds.commit("main", "first", None).await?;
+        let payload = ds.get_chunk_writer()(Bytes::from_static(b"world")).await?;
+        ds.set_chunk_ref(array, ChunkIndices(vec![1]), Some(payload)).await?;
+        let second = ds.commit("main", "second", None).await?;
+        ds.tag("v1", &second).await?;
+        Ok(storage)
+    }
+
+    #[tokio::test]
+    async fn test_migrate_to_new_storage() -> Result<(), Box<dyn Error>> {
+        let source = make_repo().await?;
+        let target: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("migrated".into())));
+
+        let migrator = Migrator::new(1).with_migration(Arc::new(MarkMigrated));
+        let report = migrator.migrate_to(source.as_ref(), target.as_ref()).await?;
+        assert_eq!(
+            report,
+            MigrationReport {
+                // init, first and second commits
+                snapshots_rewritten: 3,
+                manifests_rewritten: 2,
+                chunks_copied: 2,
+                chunks_copied_server_side: 0,
+                // three versions of main and the tag
+                refs_copied: 4,
+            }
+        );
+
+        let mut source_refs = list_refs(source.as_ref()).await?;
+        let mut target_refs = list_refs(target.as_ref()).await?;
+        source_refs.sort_by_key(|r| format!("{r:?}"));
+        target_refs.sort_by_key(|r| format!("{r:?}"));
+        assert_eq!(source_refs, target_refs);
+
+        let tip = fetch_branch_tip(target.as_ref(), "main").await?.snapshot;
+        assert_eq!(tip, fetch_branch_tip(source.as_ref(), "main").await?.snapshot);
+        assert_eq!(tip, fetch_tag(target.as_ref(), "v1").await?.snapshot);
+
+        let snapshot = target.fetch_snapshot(&tip).await?;
+        assert_eq!(snapshot.icechunk_snapshot_format_version, 1);
+        assert_eq!(
+            snapshot.icechunk_snapshot_format_flags.get("migrated"),
+            Some(&rmpv::Value::Boolean(true))
+        );
+
+        // history and chunks are readable from the new storage
+        let ds = Repository::update(Arc::clone(&target), tip).build();
+        let parents: Vec<_> = ds.ancestry().await?.try_collect().await?;
+        assert_eq!(parents.len(), 3);
+        let chunks: Vec<_> = ds.all_chunks().await?.try_collect().await?;
+        assert_eq!(chunks.len(), 2);
+        for (_, chunk) in chunks {
+            let ChunkPayload::Ref(chunk_ref) = chunk.payload else { panic!() };
+            target.fetch_chunk(&chunk_ref.id, &ByteRange::ALL).await?;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_migrate_in_place() -> Result<(), Box<dyn Error>> {
+        let storage = make_repo().await?;
+
+        // everything is already in the latest format
+        let report = Migrator::default().migrate_in_place(storage.as_ref()).await?;
+        assert_eq!(report, MigrationReport::default());
+
+        // no migration registered past version 1
+        let migrator = Migrator::new(2).with_migration(Arc::new(MarkMigrated));
+        assert!(matches!(
+            migrator.migrate_in_place(storage.as_ref()).await,
+            Err(MigrationError::NoMigrationPath { from: 1, target: 2 })
+        ));
+
+        let migrator = Migrator::new(1).with_migration(Arc::new(MarkMigrated));
+        let report = migrator.migrate_in_place(storage.as_ref()).await?;
+        assert_eq!(report.snapshots_rewritten, 3);
+        assert_eq!(report.manifests_rewritten, 2);
+        assert_eq!(report.chunks_copied + report.refs_copied, 0);
+
+        // migrating again is a no-op, and going back is not possible
+        let report = migrator.migrate_in_place(storage.as_ref()).await?;
+        assert_eq!(report, MigrationReport::default());
+        assert!(matches!(
+            Migrator::default().migrate_in_place(storage.as_ref()).await,
+            Err(MigrationError::FormatVersionTooNew { version: 1, target: 0, .. })
+        ));
+        Ok(())
+    }
+    #[tokio::test]
+    async fn test_migrate_synthetic_history() -> Result<(), Box<dyn Error>> {
+        let source: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let config = SyntheticRepoConfig { commits: 6, ..Default::default() };
+        let synthetic = generate(Arc::clone(&source), &config).await?;
+        // in the same store, chunks are copied without fetching them
+        let target = source.sub_storage("migrated")?;
+
+        let migrator = Migrator::new(1).with_migration(Arc::new(MarkMigrated));
+        let report = migrator.migrate_to(source.as_ref(), target.as_ref()).await?;
+        assert_eq!(report.snapshots_rewritten, synthetic.snapshots.len());
+        assert_eq!(report.chunks_copied, synthetic.committed_chunks.len());
+        assert_eq!(report.chunks_copied_server_side, report.chunks_copied);
+        // a version of main per snapshot
+        assert_eq!(report.refs_copied, synthetic.snapshots.len());
+
+        let ds = Repository::update(Arc::clone(&target), synthetic.tip().clone()).build();
+        let parents: Vec<_> = ds.ancestry().await?.try_collect().await?;
+        assert_eq!(parents.len(), synthetic.snapshots.len());
+        for id in synthetic.committed_chunks.iter() {
+            target.fetch_chunk(id, &ByteRange::ALL).await?;
+        }
+        Ok(())
+    }
+}
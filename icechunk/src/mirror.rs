@@ -0,0 +1,414 @@
+//! Read-only mirrors of branches.
+//!
+//! A [`Mirror`] keeps a copy of some branches of a repository in another storage, a local
+//! directory or a bucket in another region, for example next to a compute cluster.
+//! Every [`Mirror::refresh`] reads the tips of the branches in the source, copies the
+//! snapshots the target doesn't have yet, with their new manifests, attribute files and
+//! chunks, and then moves the branches of the target. Objects already in the target are
+//! never copied again, so refreshes cost about the size of the new commits.
+//!
+//! Snapshots are copied oldest first and refs last, an interrupted refresh leaves the
+//! target readable, and the next one continues where it stopped. Nothing else must write
+//! to the target, readers open it like any other repository.
+//!
+//! [`Mirror::spawn`] refreshes in the background every [`Mirror::with_interval`],
+//! reporting to a [`MirrorListener`].
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    error::ErrorKind,
+    format::{
+        manifest::ChunkPayload,
+        snapshot::{NodeData, Snapshot},
+        AttributesId, ChunkId, ManifestId, SnapshotId,
+    },
+    progress::{NoProgress, ProgressListener, ProgressOperation, ProgressTracker},
+    refs::{fetch_branch_tip, update_branch, RefError},
+    runtime::{DefaultRuntime, Runtime},
+    storage::copy_chunk,
+    Storage, StorageError,
+};
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum MirrorError {
+    #[error("error contacting storage {0}")]
+    StorageError(#[from] StorageError),
+    #[error("error reading or updating a branch {0}")]
+    RefError(#[from] RefError),
+}
+
+impl MirrorError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            MirrorError::StorageError(err) => err.kind(),
+            MirrorError::RefError(err) => err.kind(),
+        }
+    }
+}
+
+pub type MirrorResult<T> = Result<T, MirrorError>;
+
+/// What a refresh copied to the target
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MirrorReport {
+    pub branches_updated: Vec<String>,
+    pub snapshots_copied: usize,
+    pub manifests_copied: usize,
+    pub attribute_files_copied: usize,
+    pub chunks_copied: usize,
+}
+
+/// Receives the outcome of background refreshes, see [`Mirror::spawn`]
+pub trait MirrorListener: fmt::Debug + Send + Sync {
+    fn on_refresh(&self, _report: &MirrorReport) {}
+
+    /// Refreshes are retried after the interval, the error doesn't stop the mirror
+    fn on_error(&self, error: &MirrorError);
+}
+
+pub struct Mirror {
+    source: Arc<dyn Storage>,
+    target: Arc<dyn Storage>,
+    branches: Vec<String>,
+    interval: Duration,
+    runtime: Arc<dyn Runtime>,
+    listener: Option<Arc<dyn MirrorListener>>,
+    progress: Arc<dyn ProgressListener>,
+    // objects known to be in the target
+    snapshots: HashSet<SnapshotId>,
+    manifests: HashSet<ManifestId>,
+    attributes: HashSet<AttributesId>,
+    chunks: HashSet<ChunkId>,
+}
+
+impl fmt::Debug for Mirror {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mirror")
+            .field("branches", &self.branches)
+            .field("interval", &self.interval)
+            .field("snapshots", &self.snapshots.len())
+            .field("chunks", &self.chunks.len())
+            .finish()
+    }
+}
+
+impl Mirror {
+    /// Mirror `branches` of the repository in `source` to `target`, refreshing every
+    /// minute when spawned
+    pub fn new(
+        source: Arc<dyn Storage>,
+        target: Arc<dyn Storage>,
+        branches: impl IntoIterator<Item = impl Into<String>>,
+    ) -> Self {
+        Self {
+            source,
+            target,
+            branches: branches.into_iter().map(Into::into).collect(),
+            interval: Duration::from_secs(60),
+            runtime: Arc::new(DefaultRuntime::default()),
+            listener: None,
+            progress: Arc::new(NoProgress),
+            snapshots: HashSet::new(),
+            manifests: HashSet::new(),
+            attributes: HashSet::new(),
+            chunks: HashSet::new(),
+        }
+    }
+
+    /// Time between the end of a background refresh and the start of the next
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn with_runtime(mut self, runtime: Arc<dyn Runtime>) -> Self {
+        self.runtime = runtime;
+        self
+    }
+
+    pub fn with_listener(mut self, listener: Arc<dyn MirrorListener>) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Receive every object copied by a refresh, as a [`ProgressOperation::Replication`]
+    pub fn with_progress_listener(mut self, listener: Arc<dyn ProgressListener>) -> Self {
+        self.progress = listener;
+        self
+    }
+
+    pub fn branches(&self) -> &[String] {
+        &self.branches
+    }
+
+    /// Copy the new commits of every branch to the target, and move its branches
+    pub async fn refresh(&mut self) -> MirrorResult<MirrorReport> {
+        let mut report = MirrorReport::default();
+        let progress = ProgressTracker::new(
+            Arc::clone(&self.progress),
+            ProgressOperation::Replication,
+            None,
+        );
+        for branch in self.branches.clone() {
+            let tip = fetch_branch_tip(self.source.as_ref(), &branch).await?.snapshot;
+            let current = match fetch_branch_tip(self.target.as_ref(), &branch).await {
+                Ok(data) => Some(data.snapshot),
+                Err(RefError::RefNotFound(_)) => None,
+                Err(err) => return Err(err.into()),
+            };
+            if current.as_ref() == Some(&tip) {
+                continue;
+            }
+            self.copy_history(&tip, &mut report, &progress).await?;
+            update_branch(self.target.as_ref(), &branch, tip, current.as_ref(), false)
+                .await?;
+            report.branches_updated.push(branch);
+        }
+        Ok(report)
+    }
+
+    /// Refresh in the background until the returned handle is dropped
+    pub fn spawn(mut self) -> MirrorHandle {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let handle = MirrorHandle { stopped: Arc::clone(&stopped) };
+        let runtime = Arc::clone(&self.runtime);
+        runtime.spawn(
+            async move {
+                while !stopped.load(Ordering::Relaxed) {
+                    let result = self.refresh().await;
+                    if let Some(listener) = &self.listener {
+                        match &result {
+                            Ok(report) => listener.on_refresh(report),
+                            Err(err) => listener.on_error(err),
+                        }
+                    }
+                    self.runtime.sleep(self.interval).await;
+                }
+            }
+            .boxed(),
+        );
+        handle
+    }
+
+    /// Copy `tip` and its ancestors that are not in the target yet
+    async fn copy_history(
+        &mut self,
+        tip: &SnapshotId,
+        report: &mut MirrorReport,
+        progress: &ProgressTracker,
+    ) -> MirrorResult<()> {
+        let mut missing = Vec::new();
+        let mut next = Some(tip.clone());
+        while let Some(id) = next {
+            if self.snapshots.contains(&id) {
+                break;
+            }
+            match self.target.fetch_snapshot(&id).await {
+                Ok(snapshot) => {
+                    self.remember(&snapshot).await?;
+                    break;
+                }
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+            let snapshot = self.source.fetch_snapshot(&id).await?;
+            next = snapshot.short_term_history.front().map(|meta| meta.id.clone());
+            missing.push(snapshot);
+        }
+
+        // oldest first, every snapshot in the target has all its ancestors
+        for snapshot in missing.into_iter().rev() {
+            for manifest_id in manifest_ids(&snapshot) {
+                if self.manifests.contains(&manifest_id) {
+                    continue;
+                }
+                let manifest = self.source.fetch_manifests(&manifest_id).await?;
+                for payload in manifest.chunks().values() {
+                    if let ChunkPayload::Ref(chunk_ref) = payload {
+                        if !self.chunks.contains(&chunk_ref.id) {
+                            copy_chunk(
+                                self.source.as_ref(),
+                                self.target.as_ref(),
+                                &chunk_ref.id,
+                            )
+                            .await?;
+                            self.chunks.insert(chunk_ref.id.clone());
+                            report.chunks_copied += 1;
+                            progress.advance(1, chunk_ref.length);
+                        }
+                    }
+                }
+                self.target.write_manifests(manifest_id.clone(), manifest).await?;
+                self.manifests.insert(manifest_id);
+                report.manifests_copied += 1;
+                progress.advance(1, 0);
+            }
+            for file in snapshot.attribute_files.iter() {
+                if self.attributes.insert(file.id.clone()) {
+                    let table = self.source.fetch_attributes(&file.id).await?;
+                    self.target.write_attributes(file.id.clone(), table).await?;
+                    report.attribute_files_copied += 1;
+                    progress.advance(1, 0);
+                }
+            }
+            self.snapshots.insert(snapshot.metadata.id.clone());
+            self.target.write_snapshot(snapshot.metadata.id.clone(), snapshot).await?;
+            report.snapshots_copied += 1;
+            progress.advance(1, 0);
+        }
+        Ok(())
+    }
+
+    /// Record the objects of a snapshot found in the target, so they are not copied
+    async fn remember(&mut self, snapshot: &Snapshot) -> MirrorResult<()> {
+        for manifest_id in manifest_ids(snapshot) {
+            if self.manifests.insert(manifest_id.clone()) {
+                let manifest = self.target.fetch_manifests(&manifest_id).await?;
+                self.chunks.extend(manifest.chunks().values().filter_map(|payload| {
+                    match payload {
+                        ChunkPayload::Ref(chunk_ref) => Some(chunk_ref.id.clone()),
+                        _ => None,
+                    }
+                }));
+            }
+        }
+        self.attributes.extend(snapshot.attribute_files.iter().map(|f| f.id.clone()));
+        self.snapshots.insert(snapshot.metadata.id.clone());
+        Ok(())
+    }
+}
+
+fn manifest_ids(snapshot: &Snapshot) -> Vec<ManifestId> {
+    snapshot
+        .iter()
+        .flat_map(|node| match &node.node_data {
+            NodeData::Array(_, manifests) => {
+                manifests.iter().map(|m| m.object_id.clone()).collect()
+            }
+            NodeData::Group | NodeData::Concatenated(..) => Vec::new(),
+        })
+        .collect()
+}
+
+/// Stops the background refreshes of a [`Mirror`] when dropped, a refresh in progress
+/// runs to completion
+#[derive(Debug)]
+pub struct MirrorHandle {
+    stopped: Arc<AtomicBool>,
+}
+
+impl MirrorHandle {
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+impl Drop for MirrorHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{ByteRange, ChunkIndices, Path},
+        metadata::FillValue,
+        progress::{Progress, RecordingProgress},
+        repository::RepositoryResult,
+        synthetic::vector_metadata,
+        ObjectStorage, Repository,
+    };
+
+    async fn write(
+        repo: &mut Repository,
+        array: &Path,
+        index: u64,
+    ) -> RepositoryResult<()> {
+        let payload = repo.get_chunk_writer()(Bytes::from(vec![index as u8; 10])).await?;
+        repo.set_chunk_ref(array.clone(), ChunkIndices(vec![index]), Some(payload))
+            .await?;
+        repo.commit("main", &format!("chunk {index}"), None).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mirror_refresh() -> Result<(), Box<dyn Error>> {
+        let source: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let target: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&source), false)
+            .await?
+            .with_inline_threshold_bytes(1)
+            .build();
+        let array: Path = "/array".try_into()?;
+        repo.add_group(Path::root()).await?;
+        repo.add_array(array.clone(), vector_metadata(4, 1, FillValue::UInt8(0))).await?;
+        write(&mut repo, &array, 0).await?;
+        write(&mut repo, &array, 1).await?;
+
+        let progress = Arc::new(RecordingProgress::default());
+        let mut mirror = Mirror::new(Arc::clone(&source), Arc::clone(&target), ["main"])
+            .with_progress_listener(progress.clone());
+        let report = mirror.refresh().await?;
+        assert_eq!(report.branches_updated, vec!["main".to_string()]);
+        // the initial commit and the two writes
+        assert_eq!((report.snapshots_copied, report.chunks_copied), (3, 2));
+        let copied = report.snapshots_copied
+            + report.manifests_copied
+            + report.attribute_files_copied
+            + report.chunks_copied;
+        assert_eq!(
+            progress.updates().last(),
+            Some(&(
+                ProgressOperation::Replication,
+                Progress { done: copied as u64, total: None, bytes: 20 }
+            ))
+        );
+        assert_eq!(mirror.refresh().await?, MirrorReport::default());
+
+        write(&mut repo, &array, 2).await?;
+        let report = mirror.refresh().await?;
+        assert_eq!((report.snapshots_copied, report.chunks_copied), (1, 1));
+
+        // a new mirror learns what the target has from its branch tips
+        write(&mut repo, &array, 3).await?;
+        let mut mirror = Mirror::new(Arc::clone(&source), Arc::clone(&target), ["main"]);
+        let report = mirror.refresh().await?;
+        assert_eq!((report.snapshots_copied, report.chunks_copied), (1, 1));
+
+        let mirrored =
+            Repository::from_branch_tip(Arc::clone(&target), "main").await?.build();
+        assert_eq!(mirrored.snapshot_id(), repo.snapshot_id());
+        for index in 0..4 {
+            let chunk = mirrored
+                .get_chunk_reader(&array, &ChunkIndices(vec![index]), &ByteRange::ALL)
+                .await?
+                .unwrap()
+                .await?;
+            assert_eq!(chunk, Bytes::from(vec![index as u8; 10]));
+        }
+        Ok(())
+    }
+}
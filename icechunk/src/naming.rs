@@ -0,0 +1,163 @@
+//! How new node paths are normalized and checked against the existing ones.
+//!
+//! Two paths that look the same can differ in their unicode form, `é` written as one
+//! code point or as `e` followed by a combining accent, and object stores and file
+//! systems disagree on whether `Data` and `data` are the same key. Repositories with a
+//! [`PathPolicy`] rewrite new paths to NFC and refuse to add a node whose path, or the
+//! path of one of its ancestors, matches an existing node under the policy. Only new
+//! nodes are checked: existing nodes and reads use paths as they are.
+use std::{collections::HashMap, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::format::Path;
+
+/// The unicode form of new paths
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnicodeForm {
+    /// Paths are stored as they are written
+    #[default]
+    AsIs,
+    /// Paths are rewritten to Normalization Form C, the form of most input methods
+    Nfc,
+}
+
+/// How paths that differ only by case are treated
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaseHandling {
+    /// They are different nodes
+    #[default]
+    Sensitive,
+    /// A node can't be added if another one has the same path ignoring case
+    Insensitive,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PathPolicy {
+    pub unicode: UnicodeForm,
+    pub case: CaseHandling,
+}
+
+impl PathPolicy {
+    /// True if new paths are checked for collisions, there is a cost proportional to
+    /// the number of nodes on every node added
+    pub fn detects_collisions(&self) -> bool {
+        *self != Self::default()
+    }
+
+    /// `path` in the unicode form of the policy
+    pub fn normalize(&self, path: Path) -> Path {
+        match self.unicode {
+            UnicodeForm::AsIs => path,
+            UnicodeForm::Nfc => {
+                let nfc: String = path.to_string().nfc().collect();
+                // normalization doesn't add or remove separators or dots
+                Path::new(&nfc).unwrap_or(path)
+            }
+        }
+    }
+
+    fn key(&self, path: &Path) -> String {
+        let key = match self.unicode {
+            UnicodeForm::AsIs => path.to_string(),
+            UnicodeForm::Nfc => path.to_string().nfc().collect(),
+        };
+        match self.case {
+            CaseHandling::Sensitive => key,
+            CaseHandling::Insensitive => key.to_lowercase(),
+        }
+    }
+
+    /// An existing path that `path`, or one of its ancestors, collides with
+    pub fn find_collision<'a>(
+        &self,
+        path: &Path,
+        existing: impl IntoIterator<Item = &'a Path>,
+    ) -> Option<Path> {
+        if !self.detects_collisions() {
+            return None;
+        }
+        let existing: HashMap<String, &Path> =
+            existing.into_iter().map(|path| (self.key(path), path)).collect();
+        path.ancestors().find_map(|ancestor| {
+            existing
+                .get(&self.key(&ancestor))
+                .filter(|other| ***other != ancestor)
+                .map(|other| (*other).clone())
+        })
+    }
+}
+
+impl FromStr for UnicodeForm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "as-is" => Ok(UnicodeForm::AsIs),
+            "nfc" => Ok(UnicodeForm::Nfc),
+            other => {
+                Err(format!("unknown unicode form `{other}`, expected `as-is` or `nfc`"))
+            }
+        }
+    }
+}
+
+impl FromStr for CaseHandling {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sensitive" => Ok(CaseHandling::Sensitive),
+            "insensitive" => Ok(CaseHandling::Insensitive),
+            other => Err(format!(
+                "unknown case handling `{other}`, expected `sensitive` or `insensitive`"
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{repository::RepositoryError, ObjectStorage, Repository, Storage};
+
+    #[tokio::test]
+    async fn test_path_policy() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_path_policy(PathPolicy {
+                unicode: UnicodeForm::Nfc,
+                case: CaseHandling::Insensitive,
+            })
+            .build();
+        repo.add_group(Path::root()).await?;
+        repo.add_group("/Data".try_into().unwrap()).await?;
+
+        // `e` and a combining accent
+        repo.add_group("/cafe\u{301}".try_into().unwrap()).await?;
+        let nfc: Path = "/caf\u{e9}".try_into().unwrap();
+        assert_eq!(repo.get_group(&nfc).await?.path, nfc);
+
+        for path in ["/data", "/DATA/array", "/CAF\u{c9}"] {
+            let result = repo.add_group(path.try_into().unwrap()).await;
+            assert!(
+                matches!(result, Err(RepositoryError::PathCollision { .. })),
+                "{path}"
+            );
+        }
+        repo.add_group("/Data/array".try_into().unwrap()).await?;
+        assert_eq!(PathPolicy::default().find_collision(&nfc, [&nfc]), None);
+        Ok(())
+    }
+}
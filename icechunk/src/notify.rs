@@ -0,0 +1,251 @@
+//! Commit notifications, with the `notifications` feature.
+//!
+//! When [`RepositoryConfig::notifications`](crate::RepositoryConfig) is set, every
+//! successful commit publishes a [`CommitEvent`] in the background, as JSON, to a webhook
+//! or an SNS topic. Downstream caches and services use them to invalidate what they
+//! derived from the previous tip of the branch. Delivery is best effort: a lost event
+//! doesn't fail the commit, and readers should still check the branch tip from time to
+//! time.
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    error::ErrorKind,
+    format::{
+        snapshot::{CommitSummary, SnapshotMetadata},
+        SnapshotId,
+    },
+    storage::s3::S3Credentials,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotificationTarget {
+    /// `POST` the event to this URL
+    Webhook { url: String },
+    /// Publish the event to an SNS topic
+    Sns {
+        topic_arn: String,
+        region: Option<String>,
+        /// Send requests to this URL instead of AWS
+        #[serde(default)]
+        endpoint: Option<String>,
+        #[serde(default)]
+        credentials: S3Credentials,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotificationConfig {
+    /// The name of the repository in the events, to tell them apart if a target
+    /// receives events from many repositories
+    pub repository: String,
+    pub target: NotificationTarget,
+}
+
+/// What a commit publishes
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitEvent {
+    pub repository: String,
+    pub branch: String,
+    pub snapshot_id: SnapshotId,
+    pub message: String,
+    pub written_at: DateTime<Utc>,
+    pub summary: Option<CommitSummary>,
+}
+
+impl CommitEvent {
+    pub fn new(repository: &str, branch: &str, metadata: &SnapshotMetadata) -> Self {
+        Self {
+            repository: repository.to_string(),
+            branch: branch.to_string(),
+            snapshot_id: metadata.id.clone(),
+            message: metadata.message.clone(),
+            written_at: metadata.written_at,
+            summary: metadata.summary.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum NotifyError {
+    #[error("invalid notification target: {0}")]
+    InvalidTarget(String),
+    #[error("error sending notification: {0}")]
+    Transport(String),
+    #[error("notification rejected ({status}): {body}")]
+    Rejected { status: u16, body: String },
+}
+
+impl NotifyError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            NotifyError::InvalidTarget(_) => ErrorKind::InvalidRequest,
+            NotifyError::Transport(_) => ErrorKind::Transient,
+            NotifyError::Rejected { status, .. } => ErrorKind::from_http_status(*status),
+        }
+    }
+}
+
+pub type NotifyResult<A> = Result<A, NotifyError>;
+
+#[cfg(feature = "notifications")]
+pub use publish::Notifier;
+
+#[cfg(feature = "notifications")]
+mod publish {
+    use aws_config::SdkConfig;
+    use tokio::sync::OnceCell;
+
+    use super::*;
+    use crate::storage::aws_http::{self, AwsHttpError};
+
+    impl From<AwsHttpError> for NotifyError {
+        fn from(err: AwsHttpError) -> Self {
+            match err {
+                AwsHttpError::Config(message) => NotifyError::InvalidTarget(message),
+                AwsHttpError::Transport(message) => NotifyError::Transport(message),
+            }
+        }
+    }
+
+    /// Sends events to a target, the AWS configuration of SNS targets is loaded once
+    #[derive(Debug)]
+    pub struct Notifier {
+        target: NotificationTarget,
+        aws_config: OnceCell<SdkConfig>,
+    }
+
+    impl Notifier {
+        pub fn new(target: NotificationTarget) -> Self {
+            Self { target, aws_config: OnceCell::new() }
+        }
+
+        pub async fn publish(&self, event: &CommitEvent) -> NotifyResult<()> {
+            let event = serde_json::to_string(event)
+                .map_err(|err| NotifyError::Transport(err.to_string()))?;
+            let (status, body) = match &self.target {
+                NotificationTarget::Webhook { url } => {
+                    let headers = vec![(
+                        "content-type".to_string(),
+                        "application/json".to_string(),
+                    )];
+                    aws_http::send(url, headers, event.into_bytes()).await?
+                }
+                NotificationTarget::Sns { topic_arn, region, endpoint, credentials } => {
+                    let aws_config = self
+                        .aws_config
+                        .get_or_init(|| {
+                            aws_http::load_config(region.as_deref(), credentials)
+                        })
+                        .await;
+                    let region = aws_config.region().ok_or_else(|| {
+                        NotifyError::InvalidTarget(
+                            "no region configured for SNS".to_string(),
+                        )
+                    })?;
+                    let url = endpoint
+                        .clone()
+                        .unwrap_or_else(|| format!("https://sns.{region}.amazonaws.com"));
+                    let body = url::form_urlencoded::Serializer::new(String::new())
+                        .append_pair("Action", "Publish")
+                        .append_pair("Version", "2010-03-31")
+                        .append_pair("TopicArn", topic_arn)
+                        .append_pair("Message", &event)
+                        .finish()
+                        .into_bytes();
+                    let headers = vec![(
+                        "content-type".to_string(),
+                        "application/x-www-form-urlencoded".to_string(),
+                    )];
+                    aws_http::post(aws_config, "sns", &url, headers, body).await?
+                }
+            };
+            if (200..300).contains(&status) {
+                return Ok(());
+            }
+            Err(NotifyError::Rejected {
+                status,
+                body: String::from_utf8_lossy(body.as_ref()).to_string(),
+            })
+        }
+    }
+}
+
+#[cfg(all(test, feature = "notifications"))]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{
+        error::Error,
+        io::{Read, Write},
+        net::TcpListener,
+        sync::{mpsc, Arc},
+        time::Duration,
+    };
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{format::Path, ObjectStorage, Repository, Storage};
+
+    /// Answer one HTTP request with 200, sending its body to the returned channel
+    fn webhook() -> (String, mpsc::Receiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/events", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            let body_start = loop {
+                let read = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..read]);
+                if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                    break end + 4;
+                }
+            };
+            let head = String::from_utf8_lossy(&request[..body_start]).to_lowercase();
+            let length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length:"))
+                .unwrap()
+                .trim()
+                .parse()
+                .unwrap();
+            while request.len() < body_start + length {
+                let read = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..read]);
+            }
+            stream.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n").unwrap();
+            sender.send(request[body_start..].to_vec()).unwrap();
+        });
+        (url, receiver)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_commit_notification() -> Result<(), Box<dyn Error>> {
+        let (url, events) = webhook();
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut builder = Repository::init(Arc::clone(&storage), false).await?;
+        builder.with_notifications(NotificationConfig {
+            repository: "weather".to_string(),
+            target: NotificationTarget::Webhook { url },
+        });
+        let mut repo = builder.build();
+        repo.add_group(Path::root()).await?;
+        let snapshot_id = repo.commit("main", "first", None).await?;
+
+        let event = events.recv_timeout(Duration::from_secs(10))?;
+        let event: CommitEvent = serde_json::from_slice(&event)?;
+        assert_eq!(
+            (event.repository.as_str(), event.branch.as_str(), event.message.as_str()),
+            ("weather", "main", "first")
+        );
+        assert_eq!(event.snapshot_id, snapshot_id);
+        assert!(event.summary.is_some());
+        Ok(())
+    }
+}
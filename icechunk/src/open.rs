@@ -0,0 +1,191 @@
+//! Opening repositories with only the objects a reader needs.
+//!
+//! Opening a repository reads its ref, everything else is fetched as it's used: the
+//! snapshot with the first node read, the user attributes stored out of the snapshot with
+//! every node read, and the chunk statistics with [`Repository::chunks_matching`].
+//! [`OpenOptions`] choose what is loaded up front and what is never loaded, services
+//! that must answer quickly can use [`OpenOptions::structure_only`] to open a branch with
+//! a request for its ref and a single `GET` for the snapshot.
+use std::sync::Arc;
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::ErrorKind,
+    postprocess::ConsolidatedMetadata,
+    repository::{RepositoryBuilder, RepositoryResult},
+    revision,
+    storage::layout::check_key_layout,
+    Repository, Storage, StorageError,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OpenOptions {
+    /// Fetch the snapshot while opening, into the cache of the storage if it has one,
+    /// instead of with the first read
+    pub load_structure: bool,
+    /// Replace the references to user attributes stored out of the snapshot with the
+    /// attributes when nodes are read. Without it nodes keep the references, and the
+    /// attributes are only fetched to write their Zarr metadata documents.
+    pub resolve_attributes: bool,
+    /// Skip chunks using their statistics in [`Repository::chunks_matching`], without it
+    /// every chunk is returned
+    pub use_statistics: bool,
+    /// Fetch the [`ConsolidatedMetadata`] artifact of the snapshot while opening
+    pub load_consolidated_metadata: bool,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        Self {
+            load_structure: false,
+            resolve_attributes: true,
+            use_statistics: true,
+            load_consolidated_metadata: false,
+        }
+    }
+}
+
+impl OpenOptions {
+    /// Load the snapshot while opening and nothing else, ever
+    pub fn structure_only() -> Self {
+        Self {
+            load_structure: true,
+            resolve_attributes: false,
+            use_statistics: false,
+            load_consolidated_metadata: false,
+        }
+    }
+
+    pub fn with_load_structure(mut self, value: bool) -> Self {
+        self.load_structure = value;
+        self
+    }
+
+    pub fn with_resolve_attributes(mut self, value: bool) -> Self {
+        self.resolve_attributes = value;
+        self
+    }
+
+    pub fn with_use_statistics(mut self, value: bool) -> Self {
+        self.use_statistics = value;
+        self
+    }
+
+    pub fn with_load_consolidated_metadata(mut self, value: bool) -> Self {
+        self.load_consolidated_metadata = value;
+        self
+    }
+}
+
+#[derive(Debug)]
+pub struct OpenedRepository {
+    /// Configured with the options the repository was opened with
+    pub builder: RepositoryBuilder,
+    /// The branch of the ref expression, if it names the tip of one
+    pub branch: Option<String>,
+    /// `None` if it wasn't requested or the snapshot doesn't have it
+    pub consolidated_metadata: Option<Bytes>,
+}
+
+/// Open the snapshot named by a ref expression, see [`crate::revision`], loading what
+/// `options` asks for
+pub async fn open(
+    storage: Arc<dyn Storage>,
+    expression: &str,
+    options: OpenOptions,
+) -> RepositoryResult<OpenedRepository> {
+    check_key_layout(storage.as_ref()).await?;
+    let resolved = revision::resolve(storage.as_ref(), expression).await?;
+    let snapshot = &resolved.snapshot;
+    let structure = async {
+        if options.load_structure {
+            storage.fetch_snapshot(snapshot).await?;
+        }
+        Ok::<_, StorageError>(())
+    };
+    let consolidated = async {
+        if !options.load_consolidated_metadata {
+            return Ok(None);
+        }
+        match storage.fetch_snapshot_artifact(snapshot, ConsolidatedMetadata::NAME).await
+        {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    };
+    let ((), consolidated_metadata) = futures::try_join!(structure, consolidated)?;
+
+    let mut builder = Repository::update(Arc::clone(&storage), resolved.snapshot);
+    builder.with_open_options(options);
+    Ok(OpenedRepository { builder, branch: resolved.branch, consolidated_metadata })
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{snapshot::UserAttributesSnapshot, Path},
+        postprocess::{process_snapshot, SnapshotProcessor},
+        repository::UserAttributes,
+        storage::logging::LoggingStorage,
+        zarr::node_metadata_bytes,
+        ObjectStorage,
+    };
+
+    #[tokio::test]
+    async fn test_open_structure_only() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&backend), false).await?.build();
+        let group: Path = "/group".try_into().unwrap();
+        repo.add_group(Path::root()).await?;
+        repo.add_group(group.clone()).await?;
+        let atts = UserAttributes::try_new(br#"{"units": "m"}"#).unwrap();
+        repo.set_user_attributes(group.clone(), Some(atts.clone())).await?;
+        let snapshot = repo.commit("main", "attributes", None).await?;
+        let processors: Vec<Arc<dyn SnapshotProcessor>> =
+            vec![Arc::new(ConsolidatedMetadata)];
+        process_snapshot(Arc::clone(&backend), &snapshot, &processors).await?;
+
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let storage: Arc<dyn Storage> = logging.clone();
+        let opened =
+            open(Arc::clone(&storage), "main", OpenOptions::structure_only()).await?;
+        assert_eq!(opened.branch.as_deref(), Some("main"));
+        assert_eq!(opened.consolidated_metadata, None);
+        let fetched = |name: &str| {
+            logging.fetch_operations().iter().filter(|(op, _)| op == name).count()
+        };
+        assert_eq!(fetched("fetch_snapshot"), 1);
+
+        let repo = opened.builder.build();
+        let node = repo.get_node(&group).await?;
+        assert!(matches!(node.user_attributes, Some(UserAttributesSnapshot::Ref(_))));
+        assert_eq!(fetched("fetch_attributes"), 0);
+        // the zarr.json document still has the attributes
+        let metadata = node_metadata_bytes(storage.as_ref(), node).await?;
+        let metadata: serde_json::Value = serde_json::from_slice(&metadata)?;
+        assert_eq!(metadata["attributes"], serde_json::json!({"units": "m"}));
+        assert_eq!(fetched("fetch_attributes"), 1);
+
+        let opened = open(
+            storage,
+            &snapshot.to_string(),
+            OpenOptions::default().with_load_consolidated_metadata(true),
+        )
+        .await?;
+        assert!(opened.consolidated_metadata.is_some());
+        let node = opened.builder.build().get_node(&group).await?;
+        assert_eq!(node.user_attributes, Some(UserAttributesSnapshot::Inline(atts)));
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+//! Splitting the chunks of an array between parallel writers.
+//!
+//! Writers that declare disjoint write regions, with
+//! [`Repository::declare_write_region`], can commit concurrently without conflicts. The
+//! helpers here compute those regions: [`partition_array`] splits the chunk grid of an
+//! array in one region per worker, and [`check_partition`] verifies, before a
+//! distributed commit, that the change set submitted by a worker only writes chunks in
+//! the regions it claimed.
+use std::collections::HashMap;
+
+use crate::{
+    change_set::ChangeSet,
+    format::{
+        snapshot::{ChunkRegion, WriteRegions, ZarrArrayMetadata},
+        NodeId, Path,
+    },
+    repository::{RepositoryError, RepositoryResult},
+    Repository,
+};
+
+/// Split a chunk grid, with `grid[i]` chunks along dimension `i`, in at most `workers`
+/// disjoint regions that together cover it.
+///
+/// The largest dimensions are split first, in contiguous slabs of nearly the same
+/// size, so regions are as compact as possible. There are fewer regions than workers
+/// if the grid doesn't have enough chunks, or the number of workers doesn't factor over
+/// the dimensions. Regions are returned in row-major order.
+pub fn partition_grid(grid: &[u64], workers: usize) -> Vec<ChunkRegion> {
+    if workers == 0 || grid.contains(&0) {
+        return Vec::new();
+    }
+    let mut by_size: Vec<usize> = (0..grid.len()).collect();
+    by_size.sort_by_key(|axis| std::cmp::Reverse(grid[*axis]));
+    let mut parts = vec![1u64; grid.len()];
+    let mut remaining = workers as u64;
+    for axis in by_size {
+        parts[axis] = grid[axis].min(remaining);
+        remaining /= parts[axis];
+    }
+
+    let mut regions = vec![ChunkRegion(Vec::with_capacity(grid.len()))];
+    for (size, parts) in grid.iter().zip(parts) {
+        let slabs = (0..parts).map(|part| part * size / parts..(part + 1) * size / parts);
+        regions = regions
+            .into_iter()
+            .flat_map(|region| {
+                slabs.clone().map(move |slab| {
+                    let mut ranges = region.0.clone();
+                    ranges.push(slab);
+                    ChunkRegion(ranges)
+                })
+            })
+            .collect();
+    }
+    regions
+}
+
+/// The write regions each worker declares to write the array at `path`, see
+/// [`partition_grid`]
+pub fn partition_array(
+    path: &Path,
+    metadata: &ZarrArrayMetadata,
+    workers: usize,
+) -> Vec<WriteRegions> {
+    partition_grid(&metadata.chunk_grid_shape(), workers)
+        .into_iter()
+        .map(|region| WriteRegions::from([(path.clone(), vec![region])]))
+        .collect()
+}
+
+/// Fail with [`RepositoryError::OutsideWriteRegions`] if `change_set` writes or deletes
+/// chunks outside the `claimed` regions.
+///
+/// Nodes are resolved in `repository`, and in the arrays created by `change_set`. Only
+/// chunk changes are checked, commits that also change metadata or attributes can't be
+/// rebased, so they already fail if other writers committed first.
+pub async fn check_partition(
+    repository: &Repository,
+    change_set: &ChangeSet,
+    claimed: &WriteRegions,
+) -> RepositoryResult<()> {
+    let mut paths: HashMap<NodeId, Path> =
+        repository.list_nodes().await?.map(|node| (node.id, node.path)).collect();
+    for path in change_set.new_nodes() {
+        if let Some((id, _)) = change_set.get_array(path) {
+            paths.insert(*id, path.clone());
+        }
+    }
+    for (node, coords) in change_set.chunk_changes_iterator() {
+        // chunks of unknown nodes are dropped by the flush, they are never committed
+        let Some(path) = paths.get(&node) else { continue };
+        let inside = claimed
+            .get(path)
+            .is_some_and(|regions| regions.iter().any(|region| region.contains(coords)));
+        if !inside {
+            return Err(RepositoryError::OutsideWriteRegions {
+                path: path.clone(),
+                coords: coords.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64, ops::Range, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{manifest::ChunkPayload, ChunkIndices},
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        ObjectStorage, Storage,
+    };
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_partition_grid() {
+        let region = |ranges: &[Range<u64>]| ChunkRegion(ranges.to_vec());
+        assert_eq!(
+            partition_grid(&[10, 3], 4),
+            vec![
+                region(&[0..2, 0..3]),
+                region(&[2..5, 0..3]),
+                region(&[5..7, 0..3]),
+                region(&[7..10, 0..3]),
+            ]
+        );
+        // the largest dimension is split first, then the next one
+        assert_eq!(partition_grid(&[2, 3], 6).len(), 6);
+        assert_eq!(partition_grid(&[2, 2], 5).len(), 4);
+        assert_eq!(
+            partition_grid(&[3], 10),
+            vec![region(&[0..1]), region(&[1..2]), region(&[2..3])]
+        );
+        assert_eq!(partition_grid(&[], 3), vec![region(&[])]);
+        assert_eq!(partition_grid(&[4, 0], 3), vec![]);
+
+        let grid = [7, 5, 3];
+        let regions = partition_grid(&grid, 12);
+        for (i, a) in regions.iter().enumerate() {
+            assert!(regions[i + 1..].iter().all(|b| !a.overlaps(b)));
+        }
+        // every chunk is in exactly one region
+        for x in 0..grid[0] {
+            for y in 0..grid[1] {
+                for z in 0..grid[2] {
+                    let coord = ChunkIndices(vec![x, y, z]);
+                    assert_eq!(regions.iter().filter(|r| r.contains(&coord)).count(), 1);
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_partition() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        let path: Path = "/array".try_into().unwrap();
+        let metadata = ZarrArrayMetadata {
+            shape: vec![4, 4],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![
+                NonZeroU64::new(1).unwrap(),
+                NonZeroU64::new(2).unwrap(),
+            ]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            rectilinear_grid: None,
+        };
+        repo.add_group(Path::root()).await?;
+        repo.add_array(path.clone(), metadata.clone()).await?;
+        let base = repo.commit("main", "create array", None).await?;
+
+        let claims = partition_array(&path, &metadata, 2);
+        assert_eq!(claims.len(), 2);
+        let mut change_sets = Vec::new();
+        for claim in &claims {
+            let mut worker =
+                Repository::update(Arc::clone(&storage), base.clone()).build();
+            worker.declare_write_regions(claim);
+            let region = &claim[&path][0];
+            let coord = ChunkIndices(vec![region.0[0].start, 0]);
+            worker
+                .set_chunk_ref(
+                    path.clone(),
+                    coord,
+                    Some(ChunkPayload::Inline("x".into())),
+                )
+                .await?;
+            change_sets.push(ChangeSet::import_from_bytes(&worker.change_set_bytes()?)?);
+        }
+        for (change_set, claim) in change_sets.iter().zip(&claims) {
+            check_partition(&repo, change_set, claim).await?;
+        }
+        assert!(matches!(
+            check_partition(&repo, &change_sets[1], &claims[0]).await,
+            Err(RepositoryError::OutsideWriteRegions { path: p, .. }) if p == path
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,143 @@
+//! A repository handle shared by many concurrent requests.
+//!
+//! Opening a [`Repository`] per request rebuilds its configuration and object store
+//! clients, and fetches the branch tip again. A [`RepositoryPool`] is built once from a
+//! [`RepositoryBuilder`] and shared between threads, every session it hands out is a
+//! [`Repository`] that reuses its storage, with any caches, and its virtual chunk
+//! resolver. Branch tips are remembered for [`RepositoryPool::with_tip_ttl`], so busy
+//! services don't read the ref of every request.
+//!
+//! Use [`Repository::add_in_mem_asset_caching`] on the storage of the builder to share
+//! fetched snapshots and manifests between sessions too.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::{
+    format::SnapshotId,
+    refs::{fetch_branch_tip, fetch_tag},
+    repository::{RepositoryBuilder, RepositoryResult},
+    Repository, Storage,
+};
+
+#[derive(Debug)]
+pub struct RepositoryPool {
+    builder: RepositoryBuilder,
+    tip_ttl: Duration,
+    tips: Mutex<HashMap<String, (SnapshotId, DateTime<Utc>)>>,
+}
+
+impl RepositoryPool {
+    /// Hand out sessions configured like `builder`, branch tips are read for every
+    /// session until [`RepositoryPool::with_tip_ttl`] is set
+    pub fn new(mut builder: RepositoryBuilder) -> Self {
+        builder.share_virtual_resolver();
+        Self { builder, tip_ttl: Duration::ZERO, tips: Mutex::new(HashMap::new()) }
+    }
+
+    /// Reuse the tip of a branch for `ttl` after reading it, sessions can miss the
+    /// commits made in that time
+    pub fn with_tip_ttl(mut self, ttl: Duration) -> Self {
+        self.tip_ttl = ttl;
+        self
+    }
+
+    pub fn storage(&self) -> &Arc<dyn Storage> {
+        self.builder.storage()
+    }
+
+    /// A session reading `snapshot_id`, without any requests to storage
+    pub fn session(&self, snapshot_id: &SnapshotId) -> Repository {
+        self.builder.build_at(snapshot_id)
+    }
+
+    /// A session on the tip of `branch`, the session can commit to it
+    pub async fn branch_session(&self, branch: &str) -> RepositoryResult<Repository> {
+        let now = self.builder.clock().now();
+        let cached = self
+            .tips
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(branch)
+            .filter(|(_, read_at)| {
+                // too long to represent means forever
+                TimeDelta::from_std(self.tip_ttl)
+                    .map_or(true, |ttl| now.signed_duration_since(*read_at) < ttl)
+            })
+            .map(|(snapshot_id, _)| snapshot_id.clone());
+        let snapshot_id = match cached {
+            Some(snapshot_id) => snapshot_id,
+            None => {
+                let tip = fetch_branch_tip(self.storage().as_ref(), branch).await?;
+                self.tips
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(branch.to_string(), (tip.snapshot.clone(), now));
+                tip.snapshot
+            }
+        };
+        Ok(self.session(&snapshot_id))
+    }
+
+    /// A session on `tag`, tags don't move so they are never cached
+    pub async fn tag_session(&self, tag: &str) -> RepositoryResult<Repository> {
+        let tag = fetch_tag(self.storage().as_ref(), tag).await?;
+        Ok(self.session(&tag.snapshot))
+    }
+
+    /// Forget the cached tip of `branch`, after committing to it for example
+    pub fn invalidate(&self, branch: &str) {
+        self.tips.lock().unwrap_or_else(PoisonError::into_inner).remove(branch);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{format::Path, ObjectStorage};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_repository_pool() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut builder = Repository::init(Arc::clone(&storage), false).await?;
+        builder.with_inline_threshold_bytes(1);
+        let pool = Arc::new(
+            RepositoryPool::new(builder).with_tip_ttl(Duration::from_secs(3600)),
+        );
+
+        let mut repo = pool.branch_session("main").await?;
+        assert_eq!(repo.config().inline_chunk_threshold_bytes, 1);
+        repo.add_group(Path::root()).await?;
+        let first = repo.commit("main", "first", None).await?;
+
+        // the tip read by the first session is still cached
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let pool = Arc::clone(&pool);
+                tokio::spawn(async move {
+                    let repo = pool.branch_session("main").await?;
+                    RepositoryResult::Ok(repo.snapshot_id().clone())
+                })
+            })
+            .collect();
+        for reader in readers {
+            assert_ne!(reader.await??, first);
+        }
+
+        pool.invalidate("main");
+        let repo = pool.branch_session("main").await?;
+        assert_eq!(repo.snapshot_id(), &first);
+        assert!(repo.get_group(&Path::root()).await.is_ok());
+        Ok(())
+    }
+}
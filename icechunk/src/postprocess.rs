@@ -0,0 +1,315 @@
+//! Artifacts derived from snapshots after they are committed.
+//!
+//! Consolidated metadata, per-array statistics or search indexes are expensive to
+//! compute for large repositories, so they are kept out of the commit critical path.
+//! Each [`SnapshotProcessor`] computes one artifact from a snapshot, and
+//! [`process_snapshot`] stores them next to it, with
+//! [`Storage::write_snapshot_artifact`], where readers find them with
+//! [`fetch_artifact`].
+//!
+//! Processors registered with [`crate::RepositoryBuilder::with_snapshot_processor`] run
+//! in the background, on the repository [`crate::runtime::Runtime`], after every
+//! successful commit. Background processing is best effort, artifacts that are missing
+//! can be computed again with [`process_snapshot`] at any time.
+use std::{collections::BTreeMap, fmt::Debug, pin::pin, sync::Arc};
+
+use bytes::Bytes;
+use futures::{
+    future::{try_join_all, BoxFuture},
+    TryStreamExt,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    error::ErrorKind,
+    format::{manifest::ChunkPayload, snapshot::NodeData, SnapshotId},
+    repository::RepositoryError,
+    zarr::node_metadata_value,
+    Repository, Storage, StorageError,
+};
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum PostProcessError {
+    #[error("error contacting storage {0}")]
+    Storage(#[from] StorageError),
+    #[error("repository error {0}")]
+    Repository(#[from] RepositoryError),
+    #[error("invalid artifact name `{0}`")]
+    InvalidName(String),
+    #[error("cannot serialize artifact {0}")]
+    Serialization(#[from] serde_json::Error),
+    #[error("processor `{name}` failed: {message}")]
+    Processor { name: String, message: String },
+}
+
+impl PostProcessError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            PostProcessError::Storage(err) => err.kind(),
+            PostProcessError::Repository(err) => err.kind(),
+            PostProcessError::InvalidName(_) => ErrorKind::InvalidRequest,
+            PostProcessError::Serialization(_) | PostProcessError::Processor { .. } => {
+                ErrorKind::Other
+            }
+        }
+    }
+}
+
+pub type PostProcessResult<A> = Result<A, PostProcessError>;
+
+/// Computes one artifact from the snapshot a repository points to
+pub trait SnapshotProcessor: Debug + Send + Sync {
+    /// The name of the artifact, unique per snapshot, like `stats.json`
+    fn name(&self) -> &str;
+
+    fn process<'a>(
+        &'a self,
+        repository: &'a Repository,
+    ) -> BoxFuture<'a, PostProcessResult<Bytes>>;
+}
+
+fn validate_name(name: &str) -> PostProcessResult<()> {
+    let valid = !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\'])
+        && !name.chars().any(char::is_control);
+    if valid {
+        Ok(())
+    } else {
+        Err(PostProcessError::InvalidName(name.to_string()))
+    }
+}
+
+/// Compute the artifacts of `processors` for `snapshot` and store them.
+///
+/// Processors run concurrently, artifacts are only written if all of them succeed.
+/// Existing artifacts with the same names are replaced.
+pub async fn process_snapshot(
+    storage: Arc<dyn Storage>,
+    snapshot: &SnapshotId,
+    processors: &[Arc<dyn SnapshotProcessor>],
+) -> PostProcessResult<()> {
+    for processor in processors {
+        validate_name(processor.name())?;
+    }
+    let repository = Repository::update(Arc::clone(&storage), snapshot.clone()).build();
+    let artifacts = try_join_all(processors.iter().map(|processor| async {
+        let bytes = processor.process(&repository).await?;
+        Ok::<_, PostProcessError>((processor.name(), bytes))
+    }))
+    .await?;
+    for (name, bytes) in artifacts {
+        storage.write_snapshot_artifact(snapshot, name, bytes).await?;
+    }
+    Ok(())
+}
+
+/// The artifact `name` of `snapshot`, `StorageError::NotFound` if it wasn't computed
+pub async fn fetch_artifact(
+    storage: &dyn Storage,
+    snapshot: &SnapshotId,
+    name: &str,
+) -> PostProcessResult<Bytes> {
+    validate_name(name)?;
+    Ok(storage.fetch_snapshot_artifact(snapshot, name).await?)
+}
+
+/// The `zarr.json` documents of every node, in a single document.
+///
+/// Uses the layout of Zarr consolidated metadata, keyed by the node path without the
+/// leading slash. Readers can open the whole hierarchy with a single request.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConsolidatedMetadata;
+
+impl ConsolidatedMetadata {
+    pub const NAME: &'static str = "consolidated_metadata.json";
+
+    async fn consolidate(repository: &Repository) -> PostProcessResult<Bytes> {
+        let paths: Vec<_> =
+            repository.list_nodes().await?.map(|node| node.path).collect();
+        let mut metadata = BTreeMap::new();
+        for path in paths {
+            let key = path.to_string().trim_start_matches('/').to_string();
+            if key.is_empty() {
+                continue;
+            }
+            // list_nodes doesn't resolve attribute references, get_node does
+            let node = repository.get_node(&path).await?;
+            let value = node_metadata_value(repository.storage().as_ref(), node).await?;
+            metadata.insert(key, value);
+        }
+        let document = serde_json::json!({
+            "kind": "inline",
+            "must_understand": false,
+            "metadata": metadata,
+        });
+        Ok(Bytes::from(serde_json::to_vec(&document)?))
+    }
+}
+
+impl SnapshotProcessor for ConsolidatedMetadata {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn process<'a>(
+        &'a self,
+        repository: &'a Repository,
+    ) -> BoxFuture<'a, PostProcessResult<Bytes>> {
+        Box::pin(Self::consolidate(repository))
+    }
+}
+
+/// The chunks of an array, as counted by [`ArrayStatistics`]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArrayStats {
+    pub chunks: u64,
+    pub inline_chunks: u64,
+    pub virtual_chunks: u64,
+    /// The length of all chunks, including inline and virtual ones
+    pub total_bytes: u64,
+}
+
+/// A document with the [`ArrayStats`] of every array, keyed by path
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ArrayStatistics;
+
+impl ArrayStatistics {
+    pub const NAME: &'static str = "array_statistics.json";
+
+    async fn compute(repository: &Repository) -> PostProcessResult<Bytes> {
+        // arrays without chunks are reported too
+        let mut stats: BTreeMap<String, ArrayStats> = repository
+            .list_nodes()
+            .await?
+            .filter(|node| !matches!(node.node_data, NodeData::Group))
+            .map(|node| (node.path.to_string(), ArrayStats::default()))
+            .collect();
+        let mut chunks = pin!(repository.all_chunks().await?);
+        while let Some((path, chunk)) = chunks.try_next().await? {
+            let array = stats.entry(path.to_string()).or_default();
+            array.chunks += 1;
+            array.total_bytes += match &chunk.payload {
+                ChunkPayload::Inline(bytes) => {
+                    array.inline_chunks += 1;
+                    bytes.len() as u64
+                }
+                ChunkPayload::Virtual(chunk_ref) => {
+                    array.virtual_chunks += 1;
+                    chunk_ref.length
+                }
+                ChunkPayload::Ref(chunk_ref) => chunk_ref.length,
+            };
+        }
+        Ok(Bytes::from(serde_json::to_vec(&stats)?))
+    }
+}
+
+impl SnapshotProcessor for ArrayStatistics {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn process<'a>(
+        &'a self,
+        repository: &'a Repository,
+    ) -> BoxFuture<'a, PostProcessResult<Bytes>> {
+        Box::pin(Self::compute(repository))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{collections::HashMap, error::Error};
+
+    use pretty_assertions::assert_eq;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::{
+        format::{snapshot::ZarrArrayMetadata, ChunkIndices, Path},
+        metadata::{
+            ChunkKeyEncoding, ChunkShape, Codec, DataType, FillValue, StorageTransformer,
+        },
+        ObjectStorage,
+    };
+
+    #[tokio::test]
+    async fn test_process_snapshot() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        repo.add_group(Path::root()).await?;
+        let array = Path::try_from("/group/array")?;
+        repo.add_group(Path::try_from("/group")?).await?;
+        repo.add_array(
+            array.clone(),
+            ZarrArrayMetadata {
+                shape: vec![4],
+                data_type: DataType::Int32,
+                chunk_shape: ChunkShape(vec![std::num::NonZeroU64::new(2).unwrap()]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::Int32(0),
+                codecs: vec![Codec { name: "mycodec".to_string(), configuration: None }],
+                storage_transformers: Some(vec![StorageTransformer {
+                    name: "mytransformer".to_string(),
+                    configuration: None,
+                }]),
+                dimension_names: None,
+                rectilinear_grid: None,
+            },
+        )
+        .await?;
+        repo.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Inline(Bytes::from_static(b"12345678"))),
+        )
+        .await?;
+        let snapshot = repo.commit("main", "commit", None).await?;
+
+        let processors: Vec<Arc<dyn SnapshotProcessor>> =
+            vec![Arc::new(ConsolidatedMetadata), Arc::new(ArrayStatistics)];
+        process_snapshot(Arc::clone(&storage), &snapshot, &processors).await?;
+
+        let consolidated: Value = serde_json::from_slice(
+            &fetch_artifact(storage.as_ref(), &snapshot, ConsolidatedMetadata::NAME)
+                .await?,
+        )?;
+        let metadata = consolidated["metadata"].as_object().unwrap();
+        let mut keys: Vec<_> = metadata.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["group", "group/array"]);
+        assert_eq!(metadata["group"]["node_type"], "group");
+        assert_eq!(metadata["group/array"]["shape"], serde_json::json!([4]));
+
+        let stats: HashMap<String, ArrayStats> = serde_json::from_slice(
+            &fetch_artifact(storage.as_ref(), &snapshot, ArrayStatistics::NAME).await?,
+        )?;
+        assert_eq!(
+            stats,
+            HashMap::from([(
+                "/group/array".to_string(),
+                ArrayStats {
+                    chunks: 1,
+                    inline_chunks: 1,
+                    virtual_chunks: 0,
+                    total_bytes: 8
+                }
+            )])
+        );
+
+        // nothing is computed for artifacts that don't exist
+        let missing = fetch_artifact(storage.as_ref(), &snapshot, "search_index").await;
+        assert_eq!(missing.unwrap_err().kind(), ErrorKind::NotFound);
+        assert!(matches!(
+            fetch_artifact(storage.as_ref(), &snapshot, "../refs").await,
+            Err(PostProcessError::InvalidName(_))
+        ));
+        Ok(())
+    }
+}
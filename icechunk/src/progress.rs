@@ -0,0 +1,111 @@
+//! Progress reporting for long running operations.
+//!
+//! Users register a [`ProgressListener`] with the [`crate::RepositoryBuilder`] to receive
+//! updates while chunks are uploaded and new versions are flushed, for example to
+//! render a progress bar. Garbage collection, exports and mirrors take a listener too.
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// The operation a [`Progress`] update refers to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ProgressOperation {
+    /// Chunks uploaded by the writers obtained with
+    /// [`crate::Repository::get_chunk_writer`] since the last flush
+    ChunkUpload,
+    /// Manifests and snapshot written while flushing or committing
+    Flush,
+    /// Objects deleted, or moved to the trash, by
+    /// [`crate::gc::garbage_collect_with_progress`]
+    GarbageCollection,
+    /// Objects written by [`crate::archive::export_archive_with_progress`]
+    ArchiveExport,
+    /// References built by [`crate::kerchunk::export`]
+    KerchunkExport,
+    /// Objects copied to the target by [`crate::mirror::Mirror::refresh`]
+    Replication,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct Progress {
+    /// Number of items processed so far
+    pub done: u64,
+    /// Total number of items, if known in advance
+    pub total: Option<u64>,
+    /// Bytes of chunk data uploaded, archived or copied so far, metadata files are not
+    /// counted
+    pub bytes: u64,
+}
+
+/// Receives progress updates, implementations must be cheap and must not block
+pub trait ProgressListener: fmt::Debug + Send + Sync {
+    fn on_progress(&self, operation: ProgressOperation, progress: Progress);
+}
+
+/// A [`ProgressListener`] that ignores all updates, this is the default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoProgress;
+
+impl ProgressListener for NoProgress {
+    fn on_progress(&self, _operation: ProgressOperation, _progress: Progress) {}
+}
+
+/// Accumulates progress for a single operation and forwards it to the listener
+#[derive(Debug)]
+pub(crate) struct ProgressTracker {
+    listener: Arc<dyn ProgressListener>,
+    operation: ProgressOperation,
+    total: Option<u64>,
+    done: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl ProgressTracker {
+    pub(crate) fn new(
+        listener: Arc<dyn ProgressListener>,
+        operation: ProgressOperation,
+        total: Option<u64>,
+    ) -> Self {
+        Self {
+            listener,
+            operation,
+            total,
+            done: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn advance(&self, items: u64, bytes: u64) {
+        let done = self.done.fetch_add(items, Ordering::Relaxed) + items;
+        let bytes = self.bytes.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        self.listener
+            .on_progress(self.operation, Progress { done, total: self.total, bytes });
+    }
+}
+
+/// A [`ProgressListener`] that keeps every update, for tests
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct RecordingProgress(std::sync::Mutex<Vec<(ProgressOperation, Progress)>>);
+
+#[cfg(test)]
+impl RecordingProgress {
+    pub(crate) fn updates(&self) -> Vec<(ProgressOperation, Progress)> {
+        self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner).clone()
+    }
+}
+
+#[cfg(test)]
+impl ProgressListener for RecordingProgress {
+    fn on_progress(&self, operation: ProgressOperation, progress: Progress) {
+        self.0
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .push((operation, progress));
+    }
+}
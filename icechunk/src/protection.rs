@@ -0,0 +1,211 @@
+//! Protection rules for branches.
+//!
+//! [`BranchRule`]s are part of the [`RepositoryConfig`], and apply to the branches whose
+//! name matches their pattern. They are checked by the repository every time it moves a
+//! branch: on commit, and on [`Repository::reset_branch`]. Like every setting of the
+//! configuration, they only bind writers that use it.
+//!
+//! [`RepositoryConfig`]: crate::RepositoryConfig
+//! [`Repository::reset_branch`]: crate::Repository::reset_branch
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    format::{snapshot::SnapshotProperties, SnapshotId},
+    repository::{RepositoryError, RepositoryResult},
+    Storage,
+};
+
+/// What can happen to the branches matching `pattern`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BranchRule {
+    /// Branch names, `*` matches any sequence of characters
+    pub pattern: String,
+    /// Forbid resets, and writing the ref unconditionally even with
+    /// `unsafe_overwrite_refs`
+    pub forbid_force_updates: bool,
+    /// Only allow resets to snapshots that descend from the tip of the branch
+    pub linear_history: bool,
+    /// Commit properties every commit must set
+    pub required_properties: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[non_exhaustive]
+pub enum ProtectionViolation {
+    #[error("branches matching `{pattern}` can't be reset or force updated")]
+    ForceUpdate { pattern: String },
+    #[error(
+        "`{target}` does not descend from `{tip}`, `{pattern}` needs linear history"
+    )]
+    NonLinear { pattern: String, tip: SnapshotId, target: SnapshotId },
+    #[error(
+        "commits to branches matching `{pattern}` must set the property `{property}`"
+    )]
+    MissingProperty { pattern: String, property: String },
+}
+
+impl BranchRule {
+    pub fn new(pattern: impl Into<String>) -> Self {
+        Self { pattern: pattern.into(), ..Self::default() }
+    }
+
+    pub fn with_forbid_force_updates(mut self, value: bool) -> Self {
+        self.forbid_force_updates = value;
+        self
+    }
+
+    pub fn with_linear_history(mut self, value: bool) -> Self {
+        self.linear_history = value;
+        self
+    }
+
+    pub fn with_required_property(mut self, property: impl Into<String>) -> Self {
+        self.required_properties.push(property.into());
+        self
+    }
+
+    pub fn matches(&self, branch: &str) -> bool {
+        glob_matches(self.pattern.as_bytes(), branch.as_bytes())
+    }
+
+    /// Check the properties of a commit to a matching branch
+    pub fn check_commit(
+        &self,
+        properties: &SnapshotProperties,
+    ) -> Result<(), ProtectionViolation> {
+        match self.required_properties.iter().find(|key| !properties.contains_key(*key)) {
+            Some(property) => Err(ProtectionViolation::MissingProperty {
+                pattern: self.pattern.clone(),
+                property: property.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+fn glob_matches(pattern: &[u8], name: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((b'*', rest)) => {
+            (0..=name.len()).any(|skip| glob_matches(rest, &name[skip..]))
+        }
+        Some((c, rest)) => name.first() == Some(c) && glob_matches(rest, &name[1..]),
+    }
+}
+
+/// The rules that apply to `branch`
+pub(crate) fn rules_for<'a>(
+    rules: &'a [BranchRule],
+    branch: &'a str,
+) -> impl Iterator<Item = &'a BranchRule> {
+    rules.iter().filter(move |rule| rule.matches(branch))
+}
+
+/// Check that `branch` can move from `tip` to `target`, a snapshot that doesn't need to
+/// descend from it
+pub(crate) async fn check_reset(
+    storage: &dyn Storage,
+    rules: &[BranchRule],
+    branch: &str,
+    tip: &SnapshotId,
+    target: &SnapshotId,
+) -> RepositoryResult<()> {
+    let protected = |violation| RepositoryError::BranchProtected {
+        branch: branch.to_string(),
+        violation,
+    };
+    for rule in rules_for(rules, branch) {
+        if rule.forbid_force_updates {
+            return Err(protected(ProtectionViolation::ForceUpdate {
+                pattern: rule.pattern.clone(),
+            }));
+        }
+        if rule.linear_history && tip != target {
+            let snapshot = storage.fetch_snapshot(target).await?;
+            if !snapshot.short_term_history.iter().any(|meta| &meta.id == tip) {
+                return Err(protected(ProtectionViolation::NonLinear {
+                    pattern: rule.pattern.clone(),
+                    tip: tip.clone(),
+                    target: target.clone(),
+                }));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{format::Path, refs::fetch_branch_tip, ObjectStorage, Repository};
+
+    #[tokio::test]
+    async fn test_branch_protection() -> Result<(), Box<dyn Error>> {
+        assert!(BranchRule::new("release-*").matches("release-2024"));
+        assert!(BranchRule::new("*").matches(""));
+        assert!(!BranchRule::new("release-*").matches("main"));
+
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut builder = Repository::init(Arc::clone(&storage), false).await?;
+        builder
+            .with_branch_rule(BranchRule::new("main").with_linear_history(true))
+            .with_branch_rule(
+                BranchRule::new("release-*")
+                    .with_forbid_force_updates(true)
+                    .with_required_property("approved_by"),
+            );
+        let mut repo = builder.build();
+        let initial = repo.snapshot_id().clone();
+        repo.add_group(Path::root()).await?;
+        let first = repo.commit("main", "first", None).await?;
+
+        // resets of main can only fast forward
+        repo.new_branch("release-1").await?;
+        repo.add_group("/a".try_into().unwrap()).await?;
+        let properties = [("approved_by".to_string(), "someone".into())].into();
+        let second = repo.commit("release-1", "second", Some(properties)).await?;
+        repo.reset_branch("main", &second).await?;
+        assert!(matches!(
+            repo.reset_branch("main", &initial).await,
+            Err(RepositoryError::BranchProtected {
+                violation: ProtectionViolation::NonLinear { .. },
+                ..
+            })
+        ));
+
+        assert!(matches!(
+            repo.reset_branch("release-1", &first).await,
+            Err(RepositoryError::BranchProtected {
+                violation: ProtectionViolation::ForceUpdate { .. },
+                ..
+            })
+        ));
+        repo.add_group("/b".try_into().unwrap()).await?;
+        match repo.commit("release-1", "unapproved", None).await {
+            Err(RepositoryError::BranchProtected { branch, violation }) => {
+                assert_eq!(branch, "release-1");
+                assert_eq!(
+                    violation,
+                    ProtectionViolation::MissingProperty {
+                        pattern: "release-*".to_string(),
+                        property: "approved_by".to_string(),
+                    }
+                );
+            }
+            other => panic!("unexpected result {other:?}"),
+        }
+        assert_eq!(
+            fetch_branch_tip(storage.as_ref(), "release-1").await?.snapshot,
+            second
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+//! Provenance across repositories.
+//!
+//! A commit can record the versions of other datasets it was derived from with
+//! [`crate::Repository::add_dependency`]; they are stored in the snapshot metadata as
+//! [`SnapshotDependency`]. Repositories only know their upstreams by name, so walking the
+//! graph takes a map from those names to storage: [`dependency_graph`] follows the
+//! dependencies of a version upstream, and [`dependents`] finds the versions derived
+//! from one.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    iter,
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    format::{
+        snapshot::{Snapshot, SnapshotDependency},
+        SnapshotId,
+    },
+    refs::{fetch_branch_tip, fetch_tag, list_refs, Ref},
+    repository::RepositoryResult,
+    Storage,
+};
+
+/// A snapshot of a named repository
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct DatasetVersion {
+    pub repository: String,
+    pub snapshot: SnapshotId,
+}
+
+/// `version` depends on `dependency`, recorded by the commit of `recorded_by`, the
+/// snapshot itself or one of its ancestors
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DependencyEdge {
+    pub version: DatasetVersion,
+    pub recorded_by: SnapshotId,
+    pub dependency: SnapshotDependency,
+}
+
+/// The dependencies recorded by `snapshot` and its ancestors, latest first
+pub(crate) fn recorded_dependencies(
+    snapshot: &Snapshot,
+) -> impl Iterator<Item = (SnapshotId, SnapshotDependency)> + '_ {
+    iter::once(&snapshot.metadata).chain(snapshot.short_term_history.iter()).flat_map(
+        |meta| meta.dependencies.iter().map(|dep| (meta.id.clone(), dep.clone())),
+    )
+}
+
+/// Every dependency edge upstream of `root`, breadth first.
+///
+/// Dependencies on repositories missing from `repositories` are returned, but not
+/// followed further.
+pub async fn dependency_graph(
+    repositories: &HashMap<String, Arc<dyn Storage>>,
+    root: DatasetVersion,
+) -> RepositoryResult<Vec<DependencyEdge>> {
+    let mut res = Vec::new();
+    let mut seen = HashSet::from([root.clone()]);
+    let mut pending = VecDeque::from([root]);
+    while let Some(version) = pending.pop_front() {
+        let Some(storage) = repositories.get(&version.repository) else {
+            continue;
+        };
+        let snapshot = storage.fetch_snapshot(&version.snapshot).await?;
+        for (recorded_by, dependency) in recorded_dependencies(&snapshot) {
+            let upstream = DatasetVersion {
+                repository: dependency.repository.clone(),
+                snapshot: dependency.snapshot.clone(),
+            };
+            if seen.insert(upstream.clone()) {
+                pending.push_back(upstream);
+            }
+            res.push(DependencyEdge {
+                version: version.clone(),
+                recorded_by,
+                dependency,
+            });
+        }
+    }
+    Ok(res)
+}
+
+/// The refs of `repositories` whose history depends directly on `target`, with the
+/// edge that records it
+pub async fn dependents(
+    repositories: &HashMap<String, Arc<dyn Storage>>,
+    target: &DatasetVersion,
+) -> RepositoryResult<Vec<(String, Ref, DependencyEdge)>> {
+    let mut res = Vec::new();
+    for (name, storage) in repositories {
+        for r in list_refs(storage.as_ref()).await? {
+            let tip = match &r {
+                Ref::Tag(tag) => fetch_tag(storage.as_ref(), tag).await?,
+                Ref::Branch(branch) => fetch_branch_tip(storage.as_ref(), branch).await?,
+            };
+            let snapshot = storage.fetch_snapshot(&tip.snapshot).await?;
+            for (recorded_by, dependency) in recorded_dependencies(&snapshot) {
+                if dependency.repository == target.repository
+                    && dependency.snapshot == target.snapshot
+                {
+                    let version = DatasetVersion {
+                        repository: name.clone(),
+                        snapshot: tip.snapshot.clone(),
+                    };
+                    let edge = DependencyEdge { version, recorded_by, dependency };
+                    res.push((name.clone(), r.clone(), edge));
+                }
+            }
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{format::Path, ObjectStorage, Repository};
+
+    #[tokio::test]
+    async fn test_dependency_graph() -> Result<(), Box<dyn Error>> {
+        let raw: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("raw".into())));
+        let derived: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("derived".into())));
+
+        let mut raw_repo = Repository::init(Arc::clone(&raw), false).await?.build();
+        raw_repo.add_group(Path::root()).await?;
+        let raw_snapshot = raw_repo.commit("main", "observations", None).await?;
+
+        let mut repo = Repository::init(Arc::clone(&derived), false).await?.build();
+        repo.add_group(Path::root()).await?;
+        let dependency = SnapshotDependency {
+            repository: "raw".to_string(),
+            snapshot: raw_snapshot.clone(),
+            description: Some("regridded".to_string()),
+        };
+        repo.add_dependency(dependency.clone());
+        let recorded_by = repo.commit("main", "regrid", None).await?;
+        repo.add_group("/other".try_into().unwrap()).await?;
+        let tip = repo.commit("main", "more", None).await?;
+
+        assert_eq!(
+            repo.dependencies().await?,
+            vec![(recorded_by.clone(), dependency.clone())]
+        );
+
+        let repositories = HashMap::from([
+            ("raw".to_string(), Arc::clone(&raw)),
+            ("derived".to_string(), Arc::clone(&derived)),
+        ]);
+        let derived_tip =
+            DatasetVersion { repository: "derived".to_string(), snapshot: tip };
+        let edge = DependencyEdge {
+            version: derived_tip.clone(),
+            recorded_by,
+            dependency: dependency.clone(),
+        };
+        assert_eq!(
+            dependency_graph(&repositories, derived_tip).await?,
+            vec![edge.clone()]
+        );
+
+        let raw_version =
+            DatasetVersion { repository: "raw".to_string(), snapshot: raw_snapshot };
+        assert_eq!(
+            dependents(&repositories, &raw_version).await?,
+            vec![("derived".to_string(), Ref::Branch("main".to_string()), edge)]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Mapping a selection of array elements onto the chunks that hold them.
+//!
+//! [`Repository::plan_read`] resolves the chunk grid of an array, rectilinear or
+//! regular, and the chunk references of the repository, so compute layers can schedule
+//! fetching and decoding chunks without reimplementing the chunk grid math.
+use std::ops::Range;
+
+use crate::{
+    format::{
+        manifest::ChunkPayload, snapshot::NodeData, ChunkIndices, IcechunkFormatError,
+        Path,
+    },
+    metadata::ArrayShape,
+    repository::{RepositoryError, RepositoryResult},
+    Repository,
+};
+
+/// A chunk that holds part of a selection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkRead {
+    pub coords: ChunkIndices,
+    /// The selected elements, relative to the origin of the chunk
+    pub chunk_selection: Vec<Range<u64>>,
+    /// Where the selected elements go, relative to the start of the selection
+    pub output_selection: Vec<Range<u64>>,
+    /// The stored size of the chunk, `None` if it was never written, so it only holds
+    /// the fill value
+    pub stored_bytes: Option<u64>,
+}
+
+/// The chunks to read for a selection, in row-major order
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadPlan {
+    pub path: Path,
+    /// The shape of the selection
+    pub shape: ArrayShape,
+    pub chunks: Vec<ChunkRead>,
+}
+
+impl ReadPlan {
+    /// The bytes to fetch to read the selection, whole chunks are fetched even if only
+    /// some of their elements are selected
+    pub fn estimated_bytes(&self) -> u64 {
+        self.chunks.iter().filter_map(|chunk| chunk.stored_bytes).sum()
+    }
+}
+
+fn stored_bytes(payload: &ChunkPayload) -> u64 {
+    match payload {
+        ChunkPayload::Inline(bytes) => bytes.len() as u64,
+        ChunkPayload::Virtual(reference) => reference.length,
+        ChunkPayload::Ref(reference) => reference.length,
+    }
+}
+
+pub(crate) async fn plan_read(
+    repository: &Repository,
+    path: &Path,
+    selection: &[Range<u64>],
+) -> RepositoryResult<ReadPlan> {
+    let node = repository.get_array(path).await?;
+    let (NodeData::Array(metadata, _) | NodeData::Concatenated(metadata, _)) =
+        &node.node_data
+    else {
+        return Err(RepositoryError::NotAnArray {
+            node: Box::new(node),
+            message: "planning a read".to_string(),
+        });
+    };
+    if selection.len() != metadata.ndim() {
+        return Err(IcechunkFormatError::SelectionRank {
+            found: selection.len(),
+            ndim: metadata.ndim(),
+        }
+        .into());
+    }
+
+    let mut chunks = vec![ChunkRead {
+        coords: ChunkIndices(Vec::with_capacity(selection.len())),
+        chunk_selection: Vec::with_capacity(selection.len()),
+        output_selection: Vec::with_capacity(selection.len()),
+        stored_bytes: None,
+    }];
+    for (axis, range) in selection.iter().enumerate() {
+        let spans = metadata.axis_chunk_spans(axis, range)?;
+        chunks = chunks
+            .into_iter()
+            .flat_map(|chunk| {
+                spans.iter().map(move |(index, span)| {
+                    let start = span.start.max(range.start);
+                    let end = span.end.min(range.end);
+                    let mut chunk = chunk.clone();
+                    chunk.coords.0.push(*index);
+                    chunk.chunk_selection.push(start - span.start..end - span.start);
+                    chunk.output_selection.push(start - range.start..end - range.start);
+                    chunk
+                })
+            })
+            .collect();
+    }
+
+    for chunk in chunks.iter_mut() {
+        chunk.stored_bytes = repository
+            .get_chunk_ref(path, &chunk.coords)
+            .await?
+            .as_ref()
+            .map(stored_bytes);
+    }
+    Ok(ReadPlan {
+        path: path.clone(),
+        shape: selection.iter().map(|range| range.end - range.start).collect(),
+        chunks,
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        error::ErrorKind,
+        format::snapshot::ZarrArrayMetadata,
+        metadata::{
+            ChunkKeyEncoding, ChunkShape, DataType, DimensionChunks, FillValue,
+            RectilinearGrid,
+        },
+        ObjectStorage, Storage,
+    };
+
+    #[tokio::test]
+    #[allow(clippy::single_range_in_vec_init)]
+    async fn test_plan_read() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        let path: Path = "/array".try_into().unwrap();
+        // rows in chunks of 2, columns in chunks of 1, 3 and 2
+        let metadata = ZarrArrayMetadata {
+            shape: vec![5, 6],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![
+                NonZeroU64::new(2).unwrap(),
+                NonZeroU64::new(3).unwrap(),
+            ]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            rectilinear_grid: Some(RectilinearGrid(vec![
+                DimensionChunks::Regular(NonZeroU64::new(2).unwrap()),
+                DimensionChunks::Sizes(
+                    [1, 3, 2].map(|size| NonZeroU64::new(size).unwrap()).to_vec(),
+                ),
+            ])),
+        };
+        repo.add_group(Path::root()).await?;
+        repo.add_array(path.clone(), metadata).await?;
+        repo.set_chunk_ref(
+            path.clone(),
+            ChunkIndices(vec![2, 1]),
+            Some(ChunkPayload::Inline("hello".into())),
+        )
+        .await?;
+
+        let plan = repo.plan_read(&path, &[3..5, 2..5]).await?;
+        assert_eq!(plan.shape, vec![2, 3]);
+        assert_eq!(
+            plan.chunks,
+            vec![
+                ChunkRead {
+                    coords: ChunkIndices(vec![1, 1]),
+                    chunk_selection: vec![1..2, 1..3],
+                    output_selection: vec![0..1, 0..2],
+                    stored_bytes: None,
+                },
+                ChunkRead {
+                    coords: ChunkIndices(vec![1, 2]),
+                    chunk_selection: vec![1..2, 0..1],
+                    output_selection: vec![0..1, 2..3],
+                    stored_bytes: None,
+                },
+                ChunkRead {
+                    coords: ChunkIndices(vec![2, 1]),
+                    chunk_selection: vec![0..1, 1..3],
+                    output_selection: vec![1..2, 0..2],
+                    stored_bytes: Some(5),
+                },
+                ChunkRead {
+                    coords: ChunkIndices(vec![2, 2]),
+                    chunk_selection: vec![0..1, 0..1],
+                    output_selection: vec![1..2, 2..3],
+                    stored_bytes: None,
+                },
+            ]
+        );
+        assert_eq!(plan.estimated_bytes(), 5);
+
+        assert!(repo.plan_read(&path, &[0..0, 0..6]).await?.chunks.is_empty());
+        for selection in [vec![0..1], vec![0..6, 0..1], vec![0..1, 0..7]] {
+            let err = repo.plan_read(&path, &selection).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidRequest);
+        }
+        Ok(())
+    }
+}
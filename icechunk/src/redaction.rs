@@ -0,0 +1,322 @@
+//! Experimental: erasing chunks from the history of a repository.
+//!
+//! Snapshots are immutable, but bad data or personal information sometimes has to be
+//! removed from every version that has it. [`redact_chunk`] removes the reference to a
+//! chunk from the manifests of the given snapshots, leaving a tombstone in them, see
+//! [`Manifest::redacted_chunks`], and deletes the chunk object once no manifest
+//! references it. Reads of the chunk then return the fill value. Every redaction is
+//! recorded in the [`crate::audit`] log.
+//!
+//! Manifests are rewritten in place, under the same ids, so every snapshot using them
+//! loses the chunk, including snapshots that were not listed, and the manifest hashes
+//! recorded in those snapshots are updated. Caches in front of the storage may keep
+//! serving the old objects. Manifests stored in packfiles cannot be redacted.
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    audit::{append_record, AuditError, AuditEvent},
+    clock::Clock,
+    error::ErrorKind,
+    format::{
+        manifest::{ChunkPayload, Manifest},
+        snapshot::{NodeData, Snapshot},
+        ChunkId, ChunkIndices, ManifestId, NodeId, Path, SnapshotId,
+    },
+    refs::{fetch_branch_tip, fetch_tag, list_refs, Ref, RefError},
+    Storage, StorageError,
+};
+
+/// What a call to [`redact_chunk`] changed, as recorded in the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Redaction {
+    pub path: Path,
+    pub coords: ChunkIndices,
+    /// The snapshots the redaction was requested for
+    pub snapshots: Vec<SnapshotId>,
+    pub manifests_rewritten: Vec<ManifestId>,
+    /// Snapshots whose manifest hashes were updated
+    pub snapshots_rewritten: Vec<SnapshotId>,
+    pub chunks_deleted: Vec<ChunkId>,
+    /// Chunk objects kept because manifests of other snapshots still reference them,
+    /// they are deleted when the chunk is redacted from those snapshots too
+    pub chunks_retained: Vec<ChunkId>,
+}
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum RedactionError {
+    #[error("error contacting storage {0}")]
+    Storage(#[from] StorageError),
+    #[error("error reading refs {0}")]
+    Ref(#[from] RefError),
+    #[error("cannot record the redaction {0}")]
+    Audit(#[from] AuditError),
+    #[error("cannot hash manifest {0}")]
+    Hash(#[from] rmp_serde::encode::Error),
+    #[error("snapshot `{snapshot}` has no array at `{path}`")]
+    NotAnArray { path: Path, snapshot: SnapshotId },
+    #[error("manifest `{0}` is stored in a packfile, it cannot be redacted")]
+    PackedManifest(ManifestId),
+}
+
+impl RedactionError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            RedactionError::Storage(err) => err.kind(),
+            RedactionError::Ref(err) => err.kind(),
+            RedactionError::Audit(err) => err.kind(),
+            RedactionError::Hash(_) => ErrorKind::Other,
+            RedactionError::NotAnArray { .. } => ErrorKind::InvalidRequest,
+            RedactionError::PackedManifest(_) => ErrorKind::Unsupported,
+        }
+    }
+}
+
+pub type RedactionResult<A> = Result<A, RedactionError>;
+
+/// Remove the chunk at `coords` of the array at `path` from `snapshots`.
+///
+/// See the [module documentation](self), the removal cannot be undone.
+pub async fn redact_chunk(
+    storage: &dyn Storage,
+    clock: &dyn Clock,
+    path: &Path,
+    coords: &ChunkIndices,
+    snapshots: &[SnapshotId],
+) -> RedactionResult<Redaction> {
+    // the manifests that may have the chunk, with the id of the array in them
+    let mut targets: BTreeSet<(ManifestId, NodeId)> = BTreeSet::new();
+    for id in snapshots {
+        let snapshot = storage.fetch_snapshot(id).await?;
+        let not_an_array =
+            || RedactionError::NotAnArray { path: path.clone(), snapshot: id.clone() };
+        let node = snapshot.get_node(path).map_err(|_| not_an_array())?;
+        let NodeData::Array(_, refs) = &node.node_data else {
+            return Err(not_an_array());
+        };
+        for manifest in refs.iter().filter(|m| m.extents.contains(coords)) {
+            if snapshot.packed_manifests.iter().any(|p| p.id == manifest.object_id) {
+                return Err(RedactionError::PackedManifest(manifest.object_id.clone()));
+            }
+            targets.insert((manifest.object_id.clone(), node.id));
+        }
+    }
+
+    let mut hashes = HashMap::new();
+    let mut removed_chunks = HashSet::new();
+    for (manifest_id, node) in targets {
+        let mut manifest =
+            Arc::unwrap_or_clone(storage.fetch_manifests(&manifest_id).await?);
+        let Some(payload) = manifest.redact_chunk(node, coords) else { continue };
+        if let ChunkPayload::Ref(chunk_ref) = payload {
+            removed_chunks.insert(chunk_ref.id);
+        }
+        hashes.insert(manifest_id.clone(), manifest.content_hash()?);
+        storage.write_manifests(manifest_id, Arc::new(manifest)).await?;
+    }
+
+    // every snapshot using the rewritten manifests gets their new hashes, and the removed
+    // chunk objects are only deleted if no other manifest references them
+    let mut all_snapshots = reachable_snapshots(storage).await?;
+    all_snapshots.extend(snapshots.iter().cloned());
+    let mut snapshots_rewritten = Vec::new();
+    let mut seen_manifests = HashSet::new();
+    let mut retained = HashSet::new();
+    for id in all_snapshots {
+        let snapshot = storage.fetch_snapshot(&id).await?;
+        let mut updated = Arc::unwrap_or_clone(Arc::clone(&snapshot));
+        let mut changed = false;
+        for (manifest_id, hash) in hashes.iter() {
+            changed |= updated.set_manifest_hash(manifest_id, Some(hash.clone()));
+        }
+        if changed {
+            storage.write_snapshot(id.clone(), Arc::new(updated)).await?;
+            snapshots_rewritten.push(id);
+        }
+
+        if removed_chunks.is_empty() {
+            continue;
+        }
+        for manifest_id in snapshot_manifests(&snapshot) {
+            if !seen_manifests.insert(manifest_id.clone()) {
+                continue;
+            }
+            let manifest = storage.fetch_manifests(&manifest_id).await?;
+            retained.extend(referenced_chunks(&manifest, &removed_chunks));
+        }
+    }
+
+    let mut chunks_deleted = Vec::new();
+    for chunk in removed_chunks.iter().filter(|chunk| !retained.contains(*chunk)) {
+        storage.delete_chunk(chunk).await?;
+        chunks_deleted.push(chunk.clone());
+    }
+
+    let mut manifests_rewritten: Vec<_> = hashes.into_keys().collect();
+    manifests_rewritten.sort();
+    snapshots_rewritten.sort();
+    chunks_deleted.sort();
+    let mut chunks_retained: Vec<_> = retained.into_iter().collect();
+    chunks_retained.sort();
+    let redaction = Redaction {
+        path: path.clone(),
+        coords: coords.clone(),
+        snapshots: snapshots.to_vec(),
+        manifests_rewritten,
+        snapshots_rewritten,
+        chunks_deleted,
+        chunks_retained,
+    };
+    append_record(storage, clock, AuditEvent::ChunkRedacted(redaction.clone())).await?;
+    Ok(redaction)
+}
+
+async fn reachable_snapshots(
+    storage: &dyn Storage,
+) -> RedactionResult<HashSet<SnapshotId>> {
+    let mut res = HashSet::new();
+    for r in list_refs(storage).await? {
+        let tip = match r {
+            Ref::Tag(name) => fetch_tag(storage, &name).await?,
+            Ref::Branch(name) => fetch_branch_tip(storage, &name).await?,
+        };
+        if res.contains(&tip.snapshot) {
+            continue;
+        }
+        let snapshot = storage.fetch_snapshot(&tip.snapshot).await?;
+        res.extend(snapshot.short_term_history.iter().map(|meta| meta.id.clone()));
+        res.insert(tip.snapshot);
+    }
+    Ok(res)
+}
+
+fn snapshot_manifests(snapshot: &Snapshot) -> HashSet<ManifestId> {
+    let mut res: HashSet<_> =
+        snapshot.manifest_files.iter().map(|info| info.id.clone()).collect();
+    for node in snapshot.iter() {
+        if let NodeData::Array(_, refs) = &node.node_data {
+            res.extend(refs.iter().map(|manifest| manifest.object_id.clone()));
+        }
+    }
+    res
+}
+
+fn referenced_chunks<'a>(
+    manifest: &'a Manifest,
+    chunks: &'a HashSet<ChunkId>,
+) -> impl Iterator<Item = ChunkId> + 'a {
+    manifest.chunks().values().filter_map(move |payload| match payload {
+        ChunkPayload::Ref(chunk_ref) if chunks.contains(&chunk_ref.id) => {
+            Some(chunk_ref.id.clone())
+        }
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, slice};
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        audit::audit_log,
+        clock::SystemClock,
+        format::{manifest::ChunkRef, ByteRange},
+        metadata::FillValue,
+        synthetic::vector_metadata,
+        ObjectStorage, Repository,
+    };
+
+    async fn write_chunk(
+        repo: &mut Repository,
+        storage: &dyn Storage,
+        path: &Path,
+        index: u64,
+    ) -> Result<ChunkId, Box<dyn Error>> {
+        let id = ChunkId::random();
+        storage.write_chunk(id.clone(), Bytes::from_static(b"data")).await?;
+        let payload =
+            ChunkPayload::Ref(ChunkRef { id: id.clone(), offset: 0, length: 4 });
+        repo.set_chunk_ref(path.clone(), ChunkIndices(vec![index]), Some(payload))
+            .await?;
+        Ok(id)
+    }
+
+    #[tokio::test]
+    async fn test_redact_chunk() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        let path = Path::try_from("/array")?;
+        repo.add_array(path.clone(), vector_metadata(4, 1, FillValue::Int32(0))).await?;
+        let redacted = write_chunk(&mut repo, storage.as_ref(), &path, 0).await?;
+        write_chunk(&mut repo, storage.as_ref(), &path, 1).await?;
+        let first = repo.commit("main", "first", None).await?;
+        write_chunk(&mut repo, storage.as_ref(), &path, 1).await?;
+        let second = repo.commit("main", "second", None).await?;
+
+        let coords = ChunkIndices(vec![0]);
+        let redaction = redact_chunk(
+            storage.as_ref(),
+            &SystemClock,
+            &path,
+            &coords,
+            slice::from_ref(&first),
+        )
+        .await?;
+        assert_eq!(redaction.manifests_rewritten.len(), 1);
+        assert_eq!(redaction.snapshots_rewritten, vec![first.clone()]);
+        // the second snapshot has the chunk in its own manifest
+        assert_eq!(redaction.chunks_deleted, vec![]);
+        assert_eq!(redaction.chunks_retained, vec![redacted.clone()]);
+
+        let old = Repository::update(Arc::clone(&storage), first.clone()).build();
+        assert_eq!(old.get_chunk_ref(&path, &coords).await?, None);
+        assert!(old.get_chunk_ref(&path, &ChunkIndices(vec![1])).await?.is_some());
+        let snapshot = storage.fetch_snapshot(&first).await?;
+        let node = snapshot.get_node(&path)?;
+        let NodeData::Array(_, refs) = &node.node_data else { panic!("not an array") };
+        let manifest = storage.fetch_manifests(&refs[0].object_id).await?;
+        assert_eq!(manifest.redacted_chunks(), vec![(node.id, coords.clone())]);
+        assert_eq!(refs[0].content_hash, Some(manifest.content_hash()?));
+
+        let redaction = redact_chunk(
+            storage.as_ref(),
+            &SystemClock,
+            &path,
+            &coords,
+            slice::from_ref(&second),
+        )
+        .await?;
+        assert_eq!(redaction.chunks_deleted, vec![redacted.clone()]);
+        assert!(storage.fetch_chunk(&redacted, &ByteRange::ALL).await.is_err());
+
+        let log = audit_log(storage.as_ref()).await?;
+        assert_eq!(log.len(), 2);
+        let event = AuditEvent::ChunkRedacted(redaction);
+        assert!(log.iter().any(|record| record.event == event));
+
+        assert!(matches!(
+            redact_chunk(
+                storage.as_ref(),
+                &SystemClock,
+                &Path::try_from("/missing")?,
+                &coords,
+                &[second]
+            )
+            .await,
+            Err(RedactionError::NotAnArray { .. })
+        ));
+        Ok(())
+    }
+}
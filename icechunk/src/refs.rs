@@ -5,7 +5,7 @@ use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::{format::SnapshotId, Storage, StorageError};
+use crate::{error::ErrorKind, format::SnapshotId, Storage, StorageError};
 
 fn crock_encode_int(n: u64) -> String {
     // skip the first 3 bytes (zeroes)
@@ -46,9 +46,29 @@ pub enum RefError {
     Conflict { expected_parent: Option<SnapshotId>, actual_parent: Option<SnapshotId> },
 }
 
+impl RefError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            RefError::Storage(err) => err.kind(),
+            RefError::RefNotFound(_) => ErrorKind::NotFound,
+            RefError::TagAlreadyExists(_) | RefError::Conflict { .. } => {
+                ErrorKind::Conflict
+            }
+            RefError::InvalidRefName(_) => ErrorKind::InvalidRequest,
+            RefError::InvalidRefType(_)
+            | RefError::InvalidBranchVersion(_)
+            | RefError::Serialization(_) => ErrorKind::Corruption,
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
+    }
+}
+
 pub type RefResult<A> = Result<A, RefError>;
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize)]
 pub enum Ref {
     Tag(String),
     Branch(String),
@@ -68,7 +88,7 @@ impl Ref {
     }
 }
 
-#[derive(Debug, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct BranchVersion(pub u64);
 
 impl BranchVersion {
@@ -124,7 +144,7 @@ fn branch_key(branch_name: &str, version_id: &str) -> RefResult<String> {
 }
 
 pub async fn create_tag(
-    storage: &(dyn Storage + Send + Sync),
+    storage: &dyn Storage,
     name: &str,
     snapshot: SnapshotId,
     overwrite_refs: bool,
@@ -144,9 +164,16 @@ pub async fn create_tag(
     Ok(())
 }
 
+/// Delete the tag `name`, failing with [`RefError::RefNotFound`] if it doesn't exist
+pub async fn delete_tag(storage: &dyn Storage, name: &str) -> RefResult<()> {
+    fetch_tag(storage, name).await?;
+    storage.delete_ref(tag_key(name)?.as_str()).await?;
+    Ok(())
+}
+
 #[async_recursion]
 pub async fn update_branch(
-    storage: &(dyn Storage + Send + Sync),
+    storage: &dyn Storage,
     name: &str,
     new_snapshot: SnapshotId,
     current_snapshot: Option<&SnapshotId>,
@@ -190,14 +217,33 @@ pub async fn update_branch(
     }
 }
 
-pub async fn list_refs(storage: &(dyn Storage + Send + Sync)) -> RefResult<Vec<Ref>> {
+/// Point `name` to `snapshot` with a new version, whatever the last version points to.
+///
+/// Used to repair branches whose tip can't be read, see [`crate::repair`].
+pub async fn reset_branch(
+    storage: &dyn Storage,
+    name: &str,
+    snapshot: SnapshotId,
+) -> RefResult<BranchVersion> {
+    let new_version = match last_branch_version(storage, name).await {
+        Ok(version) => version.inc(),
+        Err(RefError::RefNotFound(_)) => BranchVersion::initial(),
+        Err(err) => return Err(err),
+    };
+    let key = new_version.to_path(name)?;
+    let content = serde_json::to_vec(&RefData { snapshot })?;
+    storage.write_ref(key.as_str(), false, Bytes::copy_from_slice(&content)).await?;
+    Ok(new_version)
+}
+
+pub async fn list_refs(storage: &dyn Storage) -> RefResult<Vec<Ref>> {
     let all = storage.ref_names().await?;
     all.iter().map(|path| Ref::from_path(path.as_str())).try_collect()
 }
 
-async fn branch_history<'a, 'b>(
-    storage: &'a (dyn Storage + Send + Sync),
-    branch: &'b str,
+async fn branch_history<'a>(
+    storage: &'a dyn Storage,
+    branch: &str,
 ) -> RefResult<impl Stream<Item = RefResult<BranchVersion>> + 'a> {
     let key = branch_root(branch)?;
     let all = storage.ref_versions(key.as_str()).await?;
@@ -209,8 +255,16 @@ async fn branch_history<'a, 'b>(
     }))
 }
 
+/// The versions of branch `name`, newest first
+pub async fn list_branch_versions(
+    storage: &dyn Storage,
+    name: &str,
+) -> RefResult<Vec<BranchVersion>> {
+    branch_history(storage, name).await?.try_collect().await
+}
+
 async fn last_branch_version(
-    storage: &(dyn Storage + Send + Sync),
+    storage: &dyn Storage,
     branch: &str,
 ) -> RefResult<BranchVersion> {
     // TODO! optimize
@@ -218,10 +272,7 @@ async fn last_branch_version(
     all.try_next().await?.ok_or(RefError::RefNotFound(branch.to_string()))
 }
 
-pub async fn fetch_tag(
-    storage: &(dyn Storage + Send + Sync),
-    name: &str,
-) -> RefResult<RefData> {
+pub async fn fetch_tag(storage: &dyn Storage, name: &str) -> RefResult<RefData> {
     let path = tag_key(name)?;
     match storage.get_ref(path.as_str()).await {
         Ok(data) => Ok(serde_json::from_slice(data.as_ref())?),
@@ -232,8 +283,9 @@ pub async fn fetch_tag(
     }
 }
 
-async fn fetch_branch(
-    storage: &(dyn Storage + Send + Sync),
+/// The ref data of a version of branch `name`, not necessarily its tip
+pub async fn fetch_branch(
+    storage: &dyn Storage,
     name: &str,
     version: &BranchVersion,
 ) -> RefResult<RefData> {
@@ -247,16 +299,22 @@ async fn fetch_branch(
     }
 }
 
-pub async fn fetch_branch_tip(
-    storage: &(dyn Storage + Send + Sync),
+pub async fn fetch_branch_tip(storage: &dyn Storage, name: &str) -> RefResult<RefData> {
+    Ok(fetch_branch_tip_version(storage, name).await?.1)
+}
+
+/// The tip of a branch, with the version that points to it
+pub async fn fetch_branch_tip_version(
+    storage: &dyn Storage,
     name: &str,
-) -> RefResult<RefData> {
+) -> RefResult<(BranchVersion, RefData)> {
     let version = last_branch_version(storage, name).await?;
-    fetch_branch(storage, name, &version).await
+    let data = fetch_branch(storage, name, &version).await?;
+    Ok((version, data))
 }
 
 pub async fn fetch_ref(
-    storage: &(dyn Storage + Send + Sync),
+    storage: &dyn Storage,
     ref_name: &str,
 ) -> RefResult<(Ref, RefData)> {
     match fetch_tag(storage, ref_name).await {
@@ -315,13 +373,13 @@ mod tests {
     async fn with_test_storages<
         R,
         Fut: Future<Output = R>,
-        F: FnMut(Arc<dyn Storage + Send + Sync>) -> Fut,
+        F: FnMut(Arc<dyn Storage>) -> Fut,
     >(
         mut f: F,
     ) -> ((Arc<ObjectStorage>, R), (Arc<ObjectStorage>, R, TempDir)) {
         let prefix: String = Alphanumeric.sample_string(&mut rand::thread_rng(), 10);
         let mem_storage = Arc::new(ObjectStorage::new_in_memory_store(Some(prefix)));
-        let res1 = f(Arc::clone(&mem_storage) as Arc<dyn Storage + Send + Sync>).await;
+        let res1 = f(Arc::clone(&mem_storage) as Arc<dyn Storage>).await;
 
         let dir = tempdir().expect("cannot create temp dir");
         let local_storage = Arc::new(
@@ -329,7 +387,7 @@ mod tests {
                 .expect("Cannot create local Storage"),
         );
 
-        let res2 = f(Arc::clone(&local_storage) as Arc<dyn Storage + Send + Sync>).await;
+        let res2 = f(Arc::clone(&local_storage) as Arc<dyn Storage>).await;
         ((mem_storage, res1), (local_storage, res2, dir))
     }
 
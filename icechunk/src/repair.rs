@@ -0,0 +1,411 @@
+//! Recovering from corrupted manifests.
+//!
+//! A manifest with an unreadable chunk reference fails to decode, and every read of the
+//! array fails with it. With [`RepairOptions::salvage_manifests`] in the
+//! [`RepositoryConfig`](crate::RepositoryConfig), sessions read such manifests with
+//! [`SalvagingStorage`]: the unreadable references are skipped, those chunks read as
+//! missing, and [`Repository::salvaged_manifests`] reports what was lost.
+//!
+//! [`repair_manifests`] makes the loss permanent, so readers without the option can read
+//! the branch again: it commits the tip of the branch with the lost chunks deleted and
+//! every manifest written again, without the corrupted ones.
+//!
+//! [`Repository::repair`] recovers a branch from lost objects: if its tip can't be read
+//! the branch is moved back to its newest version that can, the references to chunk
+//! objects missing from storage are replaced with tombstones in a recovery commit, and
+//! the artifacts of the snapshot processors are computed again. The returned
+//! [`RecoveryReport`] has everything that was done.
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    sync::Arc,
+};
+
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::ErrorKind,
+    format::{
+        manifest::ChunkPayload, snapshot::NodeData, ChunkId, ChunkIndices, ManifestId,
+        NodeId, Path, SnapshotId,
+    },
+    refs::{
+        fetch_branch, fetch_branch_tip, list_branch_versions, BranchVersion, RefError,
+    },
+    repository::RepositoryResult,
+    storage::{salvage::SalvagingStorage, ObjectKind, ObjectLocation},
+    Repository, Storage,
+};
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RepairOptions {
+    /// Skip the chunk references that can't be decoded when reading manifests, instead
+    /// of failing
+    pub salvage_manifests: bool,
+}
+
+impl RepairOptions {
+    pub fn with_salvage_manifests(mut self, value: bool) -> Self {
+        self.salvage_manifests = value;
+        self
+    }
+}
+
+/// A chunk whose reference was lost from a corrupted manifest
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LostChunk {
+    pub manifest: ManifestId,
+    pub node: NodeId,
+    /// `None` if the node is not in the snapshot
+    pub path: Option<Path>,
+    pub coords: ChunkIndices,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RepairReport {
+    pub lost: Vec<LostChunk>,
+    /// Chunk references lost without knowing their chunks, because the manifest is
+    /// truncated or unreadable from some point on
+    pub unidentified: u64,
+    /// The commit with the cleaned manifests, `None` if nothing needed repair
+    pub snapshot: Option<SnapshotId>,
+}
+
+/// Commit the tip of `branch` again, without the chunk references of its manifests that
+/// can't be decoded.
+///
+/// Manifests that can't be salvaged at all fail the repair. If a corrupted manifest
+/// doesn't have a single readable reference, and the lost ones are unidentified, there
+/// is nothing to commit and the report has no snapshot.
+pub async fn repair_manifests(
+    storage: Arc<dyn Storage>,
+    branch: &str,
+    message: &str,
+) -> RepositoryResult<RepairReport> {
+    let tip = fetch_branch_tip(storage.as_ref(), branch).await?.snapshot;
+    let salvage = Arc::new(SalvagingStorage::new(storage));
+    let snapshot = salvage.fetch_snapshot(&tip).await?;
+    for file in snapshot.manifest_files.iter() {
+        salvage.fetch_manifests(&file.id).await?;
+    }
+    let salvaged = salvage.salvaged();
+    if salvaged.is_empty() {
+        return Ok(RepairReport::default());
+    }
+
+    let paths: HashMap<NodeId, Path> =
+        snapshot.iter().map(|node| (node.id, node.path.clone())).collect();
+    let mut report = RepairReport::default();
+    for (manifest, salvaged) in salvaged.iter() {
+        report.unidentified += salvaged.unidentified;
+        report.lost.extend(salvaged.lost.iter().map(|(node, coords)| LostChunk {
+            manifest: manifest.clone(),
+            node: *node,
+            path: paths.get(node).cloned(),
+            coords: coords.clone(),
+        }));
+    }
+
+    let mut repo =
+        Repository::update(Arc::clone(&salvage) as Arc<dyn Storage>, tip).build();
+    for lost in report.lost.iter() {
+        if let Some(path) = &lost.path {
+            repo.set_chunk_ref(path.clone(), lost.coords.clone(), None).await?;
+        }
+    }
+    if !repo.has_uncommitted_changes() {
+        // only unidentified references were lost, any change rewrites every manifest
+        for manifest in salvaged.keys() {
+            let manifest = salvage.fetch_manifests(manifest).await?;
+            let readable =
+                manifest.chunks().iter().find_map(|((node, coords), payload)| {
+                    paths
+                        .get(node)
+                        .map(|path| (path.clone(), coords.clone(), payload.clone()))
+                });
+            if let Some((path, coords, payload)) = readable {
+                repo.set_chunk_ref(path, coords, Some(payload)).await?;
+                break;
+            }
+        }
+    }
+    if repo.has_uncommitted_changes() {
+        report.snapshot = Some(repo.commit(branch, message, None).await?);
+    }
+    Ok(report)
+}
+
+/// A chunk reference dropped because its chunk object is missing from storage
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MissingChunk {
+    pub path: Path,
+    pub coords: ChunkIndices,
+    pub chunk: ChunkId,
+    /// The manifest of the recovery commit with the tombstone of the chunk, `None` if
+    /// the manifest is packed and can't be rewritten
+    pub tombstone: Option<ManifestId>,
+}
+
+/// What [`Repository::repair`] did
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RecoveryReport {
+    /// The snapshot the branch was moved back to, if its tip couldn't be read
+    pub rebuilt_ref: Option<SnapshotId>,
+    /// The versions of the branch whose ref or snapshot couldn't be read, newest first
+    pub skipped_versions: Vec<BranchVersion>,
+    pub missing_chunks: Vec<MissingChunk>,
+    /// The recovery commit, `None` if no chunk was missing
+    pub snapshot: Option<SnapshotId>,
+    /// The artifacts computed again, for the recovery commit or the tip of the branch
+    pub artifacts: Vec<String>,
+    /// The artifacts that couldn't be computed, with the error
+    pub failed_artifacts: Vec<(String, String)>,
+}
+
+fn is_lost(kind: ErrorKind) -> bool {
+    matches!(kind, ErrorKind::NotFound | ErrorKind::Corruption)
+}
+
+/// The newest snapshot of `branch` that can be read, with the newer versions of the
+/// branch that can't.
+///
+/// Only missing and corrupted objects are skipped, other errors are returned, so the
+/// branch is never moved back because of a failed request.
+pub(crate) async fn newest_readable_tip(
+    storage: &dyn Storage,
+    branch: &str,
+) -> RepositoryResult<(SnapshotId, Vec<BranchVersion>)> {
+    let mut skipped = Vec::new();
+    for version in list_branch_versions(storage, branch).await? {
+        match fetch_branch(storage, branch, &version).await {
+            Ok(data) => match storage.fetch_snapshot(&data.snapshot).await {
+                Ok(_) => return Ok((data.snapshot, skipped)),
+                Err(err) if is_lost(err.kind()) => {}
+                Err(err) => return Err(err.into()),
+            },
+            Err(err) if is_lost(err.kind()) => {}
+            Err(err) => return Err(err.into()),
+        }
+        skipped.push(version);
+    }
+    Err(RefError::RefNotFound(branch.to_string()).into())
+}
+
+/// The chunks of `repository` whose objects are missing from its storage, with their
+/// payloads
+pub(crate) async fn missing_chunks(
+    repository: &Repository,
+) -> RepositoryResult<Vec<(MissingChunk, ChunkPayload)>> {
+    let stored: HashSet<ChunkId> = repository
+        .storage()
+        .list_objects(ObjectKind::Chunk, ObjectLocation::Live)
+        .await?
+        .try_filter_map(
+            |object| async move { Ok(ChunkId::try_from(object.id.as_str()).ok()) },
+        )
+        .try_collect()
+        .await?;
+    let mut res = Vec::new();
+    let mut chunks = Box::pin(repository.all_chunks().await?);
+    while let Some((path, info)) = chunks.try_next().await? {
+        if let ChunkPayload::Ref(chunk_ref) = &info.payload {
+            if !stored.contains(&chunk_ref.id) {
+                let missing = MissingChunk {
+                    path,
+                    coords: info.coord,
+                    chunk: chunk_ref.id.clone(),
+                    tombstone: None,
+                };
+                res.push((missing, info.payload));
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// Find the manifests of `snapshot_id` with the tombstones of `missing`
+pub(crate) async fn find_tombstones(
+    storage: &dyn Storage,
+    snapshot_id: &SnapshotId,
+    missing: &mut [MissingChunk],
+) -> RepositoryResult<()> {
+    let snapshot = storage.fetch_snapshot(snapshot_id).await?;
+    let mut tombstones: HashMap<ManifestId, Vec<(NodeId, ChunkIndices)>> = HashMap::new();
+    for chunk in missing.iter_mut() {
+        let node = snapshot.get_node(&chunk.path)?;
+        let NodeData::Array(_, refs) = &node.node_data else { continue };
+        for manifest_ref in refs.iter().filter(|m| m.extents.contains(&chunk.coords)) {
+            let id = &manifest_ref.object_id;
+            let redacted = match tombstones.entry(id.clone()) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    entry.insert(storage.fetch_manifests(id).await?.redacted_chunks())
+                }
+            };
+            if redacted.contains(&(node.id, chunk.coords.clone())) {
+                chunk.tombstone = Some(id.clone());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        error::ErrorKind,
+        format::manifest::ChunkPayload,
+        metadata::FillValue,
+        postprocess::{fetch_artifact, ConsolidatedMetadata},
+        refs::update_branch,
+        synthetic::vector_metadata,
+        ObjectStorage,
+    };
+
+    #[tokio::test]
+    async fn test_repair_manifests() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_local_store(dir.path())?);
+        let mut repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array: Path = "/array".try_into()?;
+        repo.add_group(Path::root()).await?;
+        repo.add_array(array.clone(), vector_metadata(3, 1, FillValue::UInt8(0))).await?;
+        let payload =
+            |i: u64| Some(ChunkPayload::Inline(Bytes::from(format!("chunk {i}"))));
+        for i in 0..3 {
+            repo.set_chunk_ref(array.clone(), ChunkIndices(vec![i]), payload(i)).await?;
+        }
+        let broken = repo.commit("main", "chunks", None).await?;
+
+        // the reference to the second chunk names a variant that doesn't exist
+        let snapshot = storage.fetch_snapshot(&broken).await?;
+        let manifest = snapshot.manifest_files[0].id.clone();
+        let file = dir.path().join("manifests").join(manifest.to_string());
+        let mut bytes = std::fs::read(&file)?;
+        let second = bytes.windows(6).enumerate().filter(|(_, w)| w == b"Inline").nth(1);
+        let offset = second.unwrap().0;
+        bytes[offset..offset + 6].copy_from_slice(b"Broken");
+        std::fs::write(&file, bytes)?;
+
+        let plain =
+            Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        let err = plain.get_chunk_ref(&array, &ChunkIndices(vec![0])).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Corruption);
+
+        let mut builder =
+            Repository::from_branch_tip(Arc::clone(&storage), "main").await?;
+        builder
+            .with_repair_options(RepairOptions::default().with_salvage_manifests(true));
+        let salvaging = builder.build();
+        assert_eq!(
+            salvaging.get_chunk_ref(&array, &ChunkIndices(vec![0])).await?,
+            payload(0)
+        );
+        assert_eq!(salvaging.get_chunk_ref(&array, &ChunkIndices(vec![1])).await?, None);
+        let node = salvaging.get_array(&array).await?.id;
+        assert_eq!(
+            salvaging
+                .salvaged_manifests()
+                .get(&manifest)
+                .map(|report| report.lost.clone()),
+            Some(vec![(node, ChunkIndices(vec![1]))])
+        );
+
+        let report = repair_manifests(Arc::clone(&storage), "main", "repair").await?;
+        assert_eq!(
+            report.lost,
+            vec![LostChunk {
+                manifest,
+                node,
+                path: Some(array.clone()),
+                coords: ChunkIndices(vec![1]),
+            }]
+        );
+        assert!(report.snapshot.is_some());
+        let repaired =
+            Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        assert_eq!(
+            repaired.get_chunk_ref(&array, &ChunkIndices(vec![2])).await?,
+            payload(2)
+        );
+        assert_eq!(repaired.get_chunk_ref(&array, &ChunkIndices(vec![1])).await?, None);
+        assert_eq!(
+            repair_manifests(storage, "main", "again").await?,
+            RepairReport::default()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_repository_repair() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(1)
+            .build();
+        let array: Path = "/array".try_into()?;
+        repo.add_group(Path::root()).await?;
+        repo.add_array(array.clone(), vector_metadata(3, 1, FillValue::UInt8(0))).await?;
+        for i in 0..3 {
+            let payload = repo.get_chunk_writer()(Bytes::from(vec![i as u8; 8])).await?;
+            repo.set_chunk_ref(array.clone(), ChunkIndices(vec![i]), Some(payload))
+                .await?;
+        }
+        let good = repo.commit("main", "chunks", None).await?;
+
+        // the object of the second chunk is lost, and the branch points to a snapshot
+        // that doesn't exist
+        let Some(ChunkPayload::Ref(lost)) =
+            repo.get_chunk_ref(&array, &ChunkIndices(vec![1])).await?
+        else {
+            panic!("the chunk is not a reference");
+        };
+        storage.delete_chunk(&lost.id).await?;
+        update_branch(storage.as_ref(), "main", SnapshotId::random(), Some(&good), false)
+            .await?;
+
+        let mut repo = Repository::update(Arc::clone(&storage), good.clone())
+            .with_snapshot_processor(Arc::new(ConsolidatedMetadata))
+            .build();
+        let report = repo.repair("main", "repair").await?;
+        assert_eq!(report.rebuilt_ref, Some(good.clone()));
+        assert_eq!(report.skipped_versions.len(), 1);
+        assert_eq!(report.artifacts, vec![ConsolidatedMetadata::NAME.to_string()]);
+        assert!(report.failed_artifacts.is_empty());
+        let recovery = report.snapshot.clone().unwrap();
+        assert_eq!(repo.snapshot_id(), &recovery);
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, recovery);
+        fetch_artifact(storage.as_ref(), &recovery, ConsolidatedMetadata::NAME).await?;
+
+        let [missing] = report.missing_chunks.as_slice() else {
+            panic!("expected a missing chunk: {:?}", report.missing_chunks);
+        };
+        assert_eq!(missing.coords, ChunkIndices(vec![1]));
+        assert_eq!(missing.chunk, lost.id);
+        let manifest =
+            storage.fetch_manifests(missing.tombstone.as_ref().unwrap()).await?;
+        let node = repo.get_array(&array).await?.id;
+        assert_eq!(manifest.redacted_chunks(), vec![(node, ChunkIndices(vec![1]))]);
+
+        let repaired =
+            Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        assert_eq!(repaired.get_chunk_ref(&array, &ChunkIndices(vec![1])).await?, None);
+        assert!(repaired.get_chunk_ref(&array, &ChunkIndices(vec![2])).await?.is_some());
+
+        // nothing is lost anymore
+        let report = repo.repair("main", "again").await?;
+        assert_eq!(report.snapshot, None);
+        assert_eq!(report.rebuilt_ref, None);
+        Ok(())
+    }
+}
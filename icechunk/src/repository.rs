@@ -1,58 +1,100 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    io,
     iter::{self},
+    mem,
+    ops::Range,
     pin::Pin,
-    sync::Arc,
+    slice,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
 };
 
-pub use crate::{
-    change_set::ChangeSet,
+use crate::{
+    access::{ChunkAccess, ChunkAccessListener, ChunkSource},
+    clock::{Clock, SystemClock},
+    codecs::{CodecError, CodecPipeline},
+    error::ErrorKind,
     format::{
-        manifest::{ChunkPayload, VirtualChunkLocation},
-        snapshot::{SnapshotMetadata, ZarrArrayMetadata},
-        ChunkIndices, Path,
+        manifest::VirtualReferenceError, snapshot::ManifestFileInfo, ChunkId, ManifestId,
+        SnapshotId,
     },
-    metadata::{
-        ArrayShape, ChunkKeyEncoding, ChunkShape, Codec, DataType, DimensionName,
-        DimensionNames, FillValue, StorageTransformer, UserAttributes,
+    health::{self, HealthReport},
+    memory::{MemoryBudget, MemoryCategory},
+    naming::PathPolicy,
+    notify::NotificationConfig,
+    open::OpenOptions,
+    postprocess::{process_snapshot, SnapshotProcessor},
+    progress::{NoProgress, ProgressListener, ProgressOperation, ProgressTracker},
+    protection::{self, BranchRule, ProtectionViolation},
+    provenance,
+    read_plan::{self, ReadPlan},
+    repair::{self, RecoveryReport, RepairOptions},
+    runtime::{DefaultRuntime, Runtime},
+    storage::{
+        copy_chunk,
+        layout::{check_key_layout, write_key_layout},
+        salvage::{SalvageReport, SalvagingStorage},
+        virtual_ref::{
+            construct_valid_byte_range, FetchedRange,
+            ObjectStoreVirtualChunkResolverConfig, VirtualChunkResolver,
+        },
+        CacheStatus,
     },
+    transaction::PreparedCommit,
 };
-use crate::{
+pub use crate::{
+    change_set::ChangeSet,
     format::{
-        manifest::VirtualReferenceError, snapshot::ManifestFileInfo, ManifestId,
-        SnapshotId,
+        manifest::{
+            ChunkChecksum, ChunkExtra, ChunkExtraSchema, ChunkPayload, ChunkPredicate,
+            ChunkStatistics, ManifestSplitPolicy, VirtualChunkEtag, VirtualChunkLocation,
+        },
+        snapshot::{
+            ChunkRegion, CommitObjects, CommitSummary, Concatenation,
+            ConcatenationSource, SnapshotDependency, SnapshotMetadata, WriteRegions,
+            ZarrArrayMetadata,
+        },
+        ChunkIndices, Path,
     },
-    storage::virtual_ref::{
-        construct_valid_byte_range, ObjectStoreVirtualChunkResolverConfig,
-        VirtualChunkResolver,
+    metadata::{
+        ArrayShape, Axis, ChunkGrid, ChunkKeyEncoding, ChunkShape, Codec, DataType,
+        DimensionName, DimensionNames, FillValue, StorageTransformer, UserAttributes,
     },
 };
 use bytes::Bytes;
-use chrono::Utc;
 use futures::{future::ready, Future, FutureExt, Stream, StreamExt, TryStreamExt};
-use itertools::Either;
+use itertools::{Either, Itertools};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::{
     format::{
+        attributes::AttributesTableBuilder,
         manifest::{
-            ChunkInfo, ChunkRef, Manifest, ManifestExtents, ManifestRef, VirtualChunkRef,
+            ChunkExtraError, ChunkInfo, ChunkRef, Manifest, ManifestRef, VirtualChunkRef,
         },
         snapshot::{
-            NodeData, NodeSnapshot, NodeType, Snapshot, SnapshotProperties,
-            UserAttributesSnapshot,
+            write_regions_overlap, AttributeFileInfo, NodeData, NodeSnapshot, NodeType,
+            Snapshot, SnapshotProperties, UserAttributesRef, UserAttributesSnapshot,
         },
-        ByteRange, IcechunkFormatError, NodeId, ObjectId,
+        AttributesId, ByteRange, IcechunkFormatError, NodeId, ObjectId, TableViolation,
     },
     refs::{
-        create_tag, fetch_branch_tip, fetch_tag, update_branch, BranchVersion, Ref,
-        RefError,
+        create_tag, delete_tag, fetch_branch_tip, fetch_branch_tip_version, fetch_tag,
+        list_refs, reset_branch, update_branch, BranchVersion, Ref, RefError,
     },
-    storage::virtual_ref::ObjectStoreVirtualChunkResolver,
+    revision::{self, RevisionError},
+    storage::{range_cache::RangeCache, virtual_ref::ObjectStoreVirtualChunkResolver},
+    telemetry::{CommitStats, CommitTelemetry, IoSummary, IoTelemetry},
+    validation::{ArrayConstraints, ConstraintViolation},
+    verification::{VerificationError, VerificationPolicy},
     MemCachingStorage, Storage, StorageError,
 };
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct RepositoryConfig {
     // Chunks smaller than this will be stored inline in the manifst
     pub inline_chunk_threshold_bytes: u16,
@@ -61,41 +103,272 @@ pub struct RepositoryConfig {
     // the possibility of race conditions if this variable is set to true and there are concurrent
     // commit attempts.
     pub unsafe_overwrite_refs: bool,
+    // Delete, on a best effort basis, objects uploaded by the repository that are not part of
+    // a flushed snapshot when the repository is dropped. The deletion is spawned in the
+    // repository runtime.
+    pub cleanup_on_drop: bool,
+    // How flushes split the chunk references into manifests
+    pub manifest_split_policy: ManifestSplitPolicy,
+    // Compute statistics for the chunks written through the Store, for arrays without
+    // compression. They are used by `Repository::chunks_matching` to skip chunks.
+    pub compute_chunk_statistics: bool,
+    // How many of the manifests of a node are fetched at the same time, while looking
+    // for a chunk reference. Manifests are still searched in order, 0 behaves like 1.
+    pub manifest_fetch_concurrency: u16,
+    // Constraints checked when chunks are set and on commit, for the arrays at these paths
+    pub array_constraints: BTreeMap<Path, ArrayConstraints>,
+    // Record a checksum for the chunks written through the Store, so reads can be checked
+    pub compute_chunk_checksums: bool,
+    // Which reads are checked against the recorded checksums, etags and manifest hashes
+    pub verification: VerificationPolicy,
+    // Drop writes of chunks, user attributes and array metadata identical to the committed
+    // ones, so re-running a pipeline doesn't produce changes. Chunks stored as objects are
+    // only recognized if a checksum was recorded for them.
+    pub skip_noop_writes: bool,
+    // What commits do when there are no changes
+    pub empty_commits: EmptyCommitPolicy,
+    // Where successful commits publish an event, needs the `notifications` feature
+    pub notifications: Option<NotificationConfig>,
+    // Forbid deleting tags, and overwriting them even with `unsafe_overwrite_refs`, so
+    // published versions can't be replaced
+    pub protected_tags: bool,
+    // What writers can do to the branches matching each rule, see `crate::protection`
+    pub branch_protection: Vec<BranchRule>,
+    // Check that the file of every virtual reference set exists and covers the range of
+    // the reference, with a `HEAD` request per reference
+    pub validate_virtual_refs: bool,
+    // How the paths of new nodes are normalized and checked for collisions with the
+    // existing ones, see `crate::naming`
+    pub path_policy: PathPolicy,
+    // What is read when nodes and chunks are read, see `crate::open`
+    pub open: OpenOptions,
+    // How corrupted objects are read, see `crate::repair`
+    pub repair: RepairOptions,
+    // The coordinate array holding the labels of each dimension name, for the arrays of
+    // each group, see `crate::coordinates`. Needs the `ndarray` feature.
+    pub coordinate_arrays: BTreeMap<Path, BTreeMap<String, Path>>,
+    // Arrays whose metadata, attributes and chunks cannot be changed, and that cannot be
+    // deleted. Arrays with the `icechunk.frozen` attribute set to `true` are frozen too,
+    // for good, since the attribute cannot be removed either.
+    pub frozen_arrays: BTreeSet<Path>,
 }
 
 impl Default for RepositoryConfig {
     fn default() -> Self {
-        Self { inline_chunk_threshold_bytes: 512, unsafe_overwrite_refs: false }
+        Self {
+            inline_chunk_threshold_bytes: 512,
+            unsafe_overwrite_refs: false,
+            cleanup_on_drop: false,
+            manifest_split_policy: ManifestSplitPolicy::default(),
+            compute_chunk_statistics: false,
+            manifest_fetch_concurrency: 4,
+            array_constraints: BTreeMap::new(),
+            compute_chunk_checksums: false,
+            verification: VerificationPolicy::default(),
+            skip_noop_writes: false,
+            empty_commits: EmptyCommitPolicy::default(),
+            notifications: None,
+            protected_tags: false,
+            branch_protection: Vec::new(),
+            validate_virtual_refs: false,
+            path_policy: PathPolicy::default(),
+            open: OpenOptions::default(),
+            repair: RepairOptions::default(),
+            coordinate_arrays: BTreeMap::new(),
+            frozen_arrays: BTreeSet::new(),
+        }
+    }
+}
+
+/// What a commit does when there are no changes to commit
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EmptyCommitPolicy {
+    /// Fail with [`RepositoryError::NoChangesToCommit`]
+    #[default]
+    Error,
+    /// Write nothing and return the snapshot at the tip of the branch. Commits to a branch
+    /// that doesn't exist still fail.
+    ReturnHead,
+    /// Write a snapshot with the same content as its parent, to mark an event in the
+    /// history. It shares the manifests of the parent.
+    CreateEmpty,
+}
+
+impl FromStr for EmptyCommitPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(EmptyCommitPolicy::Error),
+            "return-head" => Ok(EmptyCommitPolicy::ReturnHead),
+            "create-empty" => Ok(EmptyCommitPolicy::CreateEmpty),
+            other => Err(format!(
+                "unknown empty commit policy `{other}`, expected `error`, `return-head` or `create-empty`"
+            )),
+        }
+    }
+}
+
+/// Virtual chunks copied at the same time by [`Repository::materialize_virtual_refs`]
+const MATERIALIZE_CONCURRENCY: usize = 16;
+
+/// Chunks moved at the same time by [`Repository::move_scratch_chunks`]
+const SCRATCH_MOVE_CONCURRENCY: usize = 16;
+
+/// Identifies a commit, to read it back from storage that is eventually consistent.
+///
+/// See [`Repository::consistency_token`] and [`Repository::from_consistency_token`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsistencyToken {
+    pub branch: String,
+    /// The branch version created by the commit
+    pub version: BranchVersion,
+    pub snapshot: SnapshotId,
+}
+
+/// How [`Repository::from_consistency_token`] polls storage
+#[derive(Clone, Debug)]
+pub struct ConsistencyWait {
+    pub max_attempts: u32,
+    pub interval: Duration,
+    /// Provides the timer used between attempts
+    pub runtime: Arc<dyn Runtime>,
+}
+
+impl Default for ConsistencyWait {
+    fn default() -> Self {
+        Self {
+            max_attempts: 20,
+            interval: Duration::from_millis(250),
+            runtime: Arc::new(DefaultRuntime::default()),
+        }
+    }
+}
+
+/// Objects written to storage by a [`Repository`] that are not referenced by a flushed snapshot.
+///
+/// Ids are recorded before the write starts, so objects from interrupted uploads are
+/// tracked too.
+#[derive(Debug, Default)]
+struct StagedUploads {
+    chunks: Vec<ChunkId>,
+    manifests: Vec<ManifestId>,
+    snapshots: Vec<SnapshotId>,
+}
+
+impl StagedUploads {
+    fn is_empty(&self) -> bool {
+        self.chunks.is_empty() && self.manifests.is_empty() && self.snapshots.is_empty()
+    }
+
+    fn append(&mut self, mut other: StagedUploads) {
+        self.chunks.append(&mut other.chunks);
+        self.manifests.append(&mut other.manifests);
+        self.snapshots.append(&mut other.snapshots);
+    }
+
+    /// Deletes the objects, on error the ones not yet deleted remain in `self`
+    async fn delete(&mut self, storage: &dyn Storage) -> RepositoryResult<()> {
+        while let Some(id) = self.snapshots.last() {
+            storage.delete_snapshot(id).await?;
+            self.snapshots.pop();
+        }
+        while let Some(id) = self.manifests.last() {
+            storage.delete_manifests(id).await?;
+            self.manifests.pop();
+        }
+        while let Some(id) = self.chunks.last() {
+            storage.delete_chunk(id).await?;
+            self.chunks.pop();
+        }
+        Ok(())
     }
 }
 
+fn lock_staged(staged: &Mutex<StagedUploads>) -> MutexGuard<'_, StagedUploads> {
+    // the lock is never held while panicking in the middle of an update
+    staged.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn lock_scratch(chunks: &Mutex<HashSet<ChunkId>>) -> MutexGuard<'_, HashSet<ChunkId>> {
+    chunks.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
 #[derive(Debug)]
 pub struct Repository {
     config: RepositoryConfig,
-    storage: Arc<dyn Storage + Send + Sync>,
+    storage: Arc<dyn Storage>,
+    // `storage` itself, with `RepairOptions::salvage_manifests`
+    salvage: Option<Arc<SalvagingStorage>>,
     snapshot_id: SnapshotId,
     last_node_id: Option<NodeId>,
+    // reserved node ids are `last_node_id + node_id_step`, a session and the writers
+    // split from it reserve ids that don't collide
+    node_id_step: NodeId,
     change_set: ChangeSet,
+    // the changes of the session a writer was split from, at the time of the split
+    split_base: Option<Arc<ChangeSet>>,
     virtual_resolver: Arc<dyn VirtualChunkResolver + Send + Sync>,
+    progress: Arc<dyn ProgressListener>,
+    chunk_uploads: Arc<ProgressTracker>,
+    staged: Arc<Mutex<StagedUploads>>,
+    write_regions: Option<WriteRegions>,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    clock: Arc<dyn Clock>,
+    runtime: Arc<dyn Runtime>,
+    consistency_token: Option<ConsistencyToken>,
+    commit_telemetry: Arc<CommitTelemetry>,
+    io_telemetry: Arc<IoTelemetry>,
+    // checked once per session
+    verified_manifests: Arc<Mutex<HashSet<ManifestId>>>,
+    snapshot_processors: Vec<Arc<dyn SnapshotProcessor>>,
+    access_listener: Option<Arc<dyn ChunkAccessListener>>,
+    // chunks are written here, and moved to `storage` before flushing
+    scratch: Option<Arc<dyn Storage>>,
+    // the chunks in the scratch storage not moved yet
+    scratch_chunks: Arc<Mutex<HashSet<ChunkId>>>,
+    #[cfg(feature = "notifications")]
+    notifier: Option<Arc<crate::notify::Notifier>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct RepositoryBuilder {
     config: RepositoryConfig,
-    storage: Arc<dyn Storage + Send + Sync>,
+    storage: Arc<dyn Storage>,
     snapshot_id: SnapshotId,
     change_set: Option<ChangeSet>,
     virtual_ref_config: Option<ObjectStoreVirtualChunkResolverConfig>,
+    virtual_range_cache: Option<Arc<dyn RangeCache>>,
+    progress: Arc<dyn ProgressListener>,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    clock: Arc<dyn Clock>,
+    runtime: Arc<dyn Runtime>,
+    commit_telemetry: Option<Arc<CommitTelemetry>>,
+    snapshot_processors: Vec<Arc<dyn SnapshotProcessor>>,
+    access_listener: Option<Arc<dyn ChunkAccessListener>>,
+    virtual_resolver: Option<Arc<dyn VirtualChunkResolver + Send + Sync>>,
+    scratch: Option<Arc<dyn Storage>>,
 }
 
 impl RepositoryBuilder {
-    fn new(storage: Arc<dyn Storage + Send + Sync>, snapshot_id: SnapshotId) -> Self {
+    fn new(storage: Arc<dyn Storage>, snapshot_id: SnapshotId) -> Self {
         Self {
             config: RepositoryConfig::default(),
             snapshot_id,
             storage,
             change_set: None,
             virtual_ref_config: None,
+            virtual_range_cache: None,
+            progress: Arc::new(NoProgress),
+            memory_budget: None,
+            clock: Arc::new(SystemClock),
+            runtime: Arc::new(DefaultRuntime::default()),
+            commit_telemetry: None,
+            snapshot_processors: Vec::new(),
+            access_listener: None,
+            virtual_resolver: None,
+            scratch: None,
         }
     }
 
@@ -109,6 +382,80 @@ impl RepositoryBuilder {
         self
     }
 
+    pub fn with_cleanup_on_drop(&mut self, value: bool) -> &mut Self {
+        self.config.cleanup_on_drop = value;
+        self
+    }
+
+    pub fn with_manifest_split_policy(
+        &mut self,
+        policy: ManifestSplitPolicy,
+    ) -> &mut Self {
+        self.config.manifest_split_policy = policy;
+        self
+    }
+
+    pub fn with_compute_chunk_statistics(&mut self, value: bool) -> &mut Self {
+        self.config.compute_chunk_statistics = value;
+        self
+    }
+
+    pub fn with_manifest_fetch_concurrency(&mut self, value: u16) -> &mut Self {
+        self.config.manifest_fetch_concurrency = value;
+        self
+    }
+
+    pub fn with_array_constraints(
+        &mut self,
+        path: Path,
+        constraints: ArrayConstraints,
+    ) -> &mut Self {
+        self.config.array_constraints.insert(path, constraints);
+        self
+    }
+
+    /// Label the elements along `dimension`, in the arrays of `group`, with the values of
+    /// the 1-dimensional array at `coordinates`
+    pub fn with_coordinate_array(
+        &mut self,
+        group: Path,
+        dimension: impl Into<String>,
+        coordinates: Path,
+    ) -> &mut Self {
+        self.config
+            .coordinate_arrays
+            .entry(group)
+            .or_default()
+            .insert(dimension.into(), coordinates);
+        self
+    }
+
+    /// Refuse changes to the array at `path`, see [`RepositoryError::FrozenArray`]
+    pub fn with_frozen_array(&mut self, path: Path) -> &mut Self {
+        self.config.frozen_arrays.insert(path);
+        self
+    }
+
+    pub fn with_compute_chunk_checksums(&mut self, value: bool) -> &mut Self {
+        self.config.compute_chunk_checksums = value;
+        self
+    }
+
+    pub fn with_verification_policy(&mut self, policy: VerificationPolicy) -> &mut Self {
+        self.config.verification = policy;
+        self
+    }
+
+    pub fn with_skip_noop_writes(&mut self, value: bool) -> &mut Self {
+        self.config.skip_noop_writes = value;
+        self
+    }
+
+    pub fn with_empty_commit_policy(&mut self, policy: EmptyCommitPolicy) -> &mut Self {
+        self.config.empty_commits = policy;
+        self
+    }
+
     pub fn with_config(&mut self, config: RepositoryConfig) -> &mut Self {
         self.config = config;
         self
@@ -122,19 +469,176 @@ impl RepositoryBuilder {
         self
     }
 
+    /// Keep the byte ranges read through virtual chunk references in `cache`, that can
+    /// be shared with other repositories
+    pub fn with_virtual_range_cache(&mut self, cache: Arc<dyn RangeCache>) -> &mut Self {
+        self.virtual_range_cache = Some(cache);
+        self
+    }
+
     pub fn with_change_set(&mut self, change_set_bytes: ChangeSet) -> &mut Self {
         self.change_set = Some(change_set_bytes);
         self
     }
 
+    /// Receive progress updates for chunk uploads, flushes and kerchunk exports
+    pub fn with_progress_listener(
+        &mut self,
+        listener: Arc<dyn ProgressListener>,
+    ) -> &mut Self {
+        self.progress = listener;
+        self
+    }
+
+    /// Account chunks held in memory while uploading in `budget`.
+    ///
+    /// Chunk writers wait for memory to be available before uploading. Use the same
+    /// budget in [`MemCachingStorage::with_memory_budget`] to bound caches too.
+    pub fn with_memory_budget(&mut self, budget: Arc<MemoryBudget>) -> &mut Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    /// Use `clock` for the timestamps of new snapshots, instead of the system clock
+    pub fn with_clock(&mut self, clock: Arc<dyn Clock>) -> &mut Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Use `runtime` for background work, instead of the default runtime
+    pub fn with_runtime(&mut self, runtime: Arc<dyn Runtime>) -> &mut Self {
+        self.runtime = runtime;
+        self
+    }
+
+    /// Record commit contention in `telemetry`, shared with other repositories, instead
+    /// of in counters of its own
+    pub fn with_commit_telemetry(
+        &mut self,
+        telemetry: Arc<CommitTelemetry>,
+    ) -> &mut Self {
+        self.commit_telemetry = Some(telemetry);
+        self
+    }
+
+    /// Publish an event after every successful commit, see [`crate::notify`]
+    pub fn with_notifications(&mut self, config: NotificationConfig) -> &mut Self {
+        self.config.notifications = Some(config);
+        self
+    }
+
+    pub fn with_protected_tags(&mut self, value: bool) -> &mut Self {
+        self.config.protected_tags = value;
+        self
+    }
+
+    pub fn with_branch_rule(&mut self, rule: BranchRule) -> &mut Self {
+        self.config.branch_protection.push(rule);
+        self
+    }
+
+    pub fn with_path_policy(&mut self, policy: PathPolicy) -> &mut Self {
+        self.config.path_policy = policy;
+        self
+    }
+
+    pub fn with_open_options(&mut self, options: OpenOptions) -> &mut Self {
+        self.config.open = options;
+        self
+    }
+
+    pub fn with_repair_options(&mut self, options: RepairOptions) -> &mut Self {
+        self.config.repair = options;
+        self
+    }
+
+    pub fn with_validate_virtual_refs(&mut self, value: bool) -> &mut Self {
+        self.config.validate_virtual_refs = value;
+        self
+    }
+
+    /// Compute the artifact of `processor` in the background after every commit.
+    ///
+    /// See [`crate::postprocess`], commits don't wait for processors to complete.
+    pub fn with_snapshot_processor(
+        &mut self,
+        processor: Arc<dyn SnapshotProcessor>,
+    ) -> &mut Self {
+        self.snapshot_processors.push(processor);
+        self
+    }
+
+    /// Report every chunk read to `listener`, see [`crate::access`]
+    pub fn with_chunk_access_listener(
+        &mut self,
+        listener: Arc<dyn ChunkAccessListener>,
+    ) -> &mut Self {
+        self.access_listener = Some(listener);
+        self
+    }
+
+    /// Write chunks to `scratch`, a faster storage, and move them to the repository
+    /// storage on flush, see [`Repository::move_scratch_chunks`]
+    pub fn with_scratch_storage(&mut self, scratch: Arc<dyn Storage>) -> &mut Self {
+        self.scratch = Some(scratch);
+        self
+    }
+
+    /// Make every repository built from now on share one virtual chunk resolver, with
+    /// its object store clients, instead of creating its own
+    pub(crate) fn share_virtual_resolver(&mut self) -> &mut Self {
+        if self.virtual_resolver.is_none() {
+            self.virtual_resolver = Some(Arc::new(self.new_virtual_resolver()));
+        }
+        self
+    }
+
+    fn new_virtual_resolver(&self) -> ObjectStoreVirtualChunkResolver {
+        let resolver =
+            ObjectStoreVirtualChunkResolver::new(self.virtual_ref_config.clone());
+        match &self.virtual_range_cache {
+            Some(cache) => resolver.with_range_cache(Arc::clone(cache)),
+            None => resolver,
+        }
+    }
+
     pub fn build(&self) -> Repository {
-        Repository::new(
+        self.build_at(&self.snapshot_id)
+    }
+
+    /// Like [`RepositoryBuilder::build`], reading `snapshot_id` instead
+    pub(crate) fn build_at(&self, snapshot_id: &SnapshotId) -> Repository {
+        let mut repo = Repository::new(
             self.config.clone(),
             self.storage.clone(),
-            self.snapshot_id.clone(),
+            snapshot_id.clone(),
             self.change_set.clone(),
             self.virtual_ref_config.clone(),
-        )
+            Arc::clone(&self.progress),
+            self.memory_budget.clone(),
+        );
+        repo.clock = Arc::clone(&self.clock);
+        repo.runtime = Arc::clone(&self.runtime);
+        repo.snapshot_processors = self.snapshot_processors.clone();
+        repo.access_listener = self.access_listener.clone();
+        repo.scratch = self.scratch.clone();
+        if let Some(telemetry) = &self.commit_telemetry {
+            repo.commit_telemetry = Arc::clone(telemetry);
+        }
+        if let Some(resolver) = &self.virtual_resolver {
+            repo.virtual_resolver = Arc::clone(resolver);
+        } else if self.virtual_range_cache.is_some() {
+            repo.virtual_resolver = Arc::new(self.new_virtual_resolver());
+        }
+        repo
+    }
+
+    pub(crate) fn storage(&self) -> &Arc<dyn Storage> {
+        &self.storage
+    }
+
+    pub(crate) fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
     }
 }
 
@@ -148,29 +652,222 @@ pub enum RepositoryError {
     #[error("node not found at `{path}`: {message}")]
     NodeNotFound { path: Path, message: String },
     #[error("there is not an array at `{node:?}`: {message}")]
-    NotAnArray { node: NodeSnapshot, message: String },
+    NotAnArray { node: Box<NodeSnapshot>, message: String },
     #[error("there is not a group at `{node:?}`: {message}")]
-    NotAGroup { node: NodeSnapshot, message: String },
+    NotAGroup { node: Box<NodeSnapshot>, message: String },
     #[error("node already exists at `{node:?}`: {message}")]
-    AlreadyExists { node: NodeSnapshot, message: String },
+    AlreadyExists { node: Box<NodeSnapshot>, message: String },
+    #[error("`{path}` collides with the existing node `{existing}`")]
+    PathCollision { path: Path, existing: Path },
     #[error("cannot commit, no changes made to the repository")]
     NoChangesToCommit,
     #[error("unknown flush error")]
     OtherFlushError,
     #[error("ref error: `{0}`")]
     Ref(#[from] RefError),
+    #[error("ref expression error: `{0}`")]
+    Revision(#[from] RevisionError),
     #[error("tag error: `{0}`")]
     Tag(String),
+    #[error("tag `{0}` is protected, it cannot be deleted or replaced")]
+    ProtectedTag(String),
+    #[error("branch `{branch}` is protected: {violation}")]
+    BranchProtected {
+        branch: String,
+        #[source]
+        violation: ProtectionViolation,
+    },
     #[error("branch update conflict: `({expected_parent:?}) != ({actual_parent:?})`")]
     Conflict { expected_parent: Option<SnapshotId>, actual_parent: Option<SnapshotId> },
     #[error("the repository has been initialized already (default branch exists)")]
     AlreadyInitialized,
     #[error("error when handling virtual reference {0}")]
     VirtualReferenceError(#[from] VirtualReferenceError),
+    #[error(
+        "chunk `{coords:?}` of array `{path}` is outside the declared write regions"
+    )]
+    OutsideWriteRegions { path: Path, coords: ChunkIndices },
+    #[error("error writing output `{0}`")]
+    IoError(#[from] io::Error),
     #[error("error in repository serialization `{0}`")]
     SerializationError(#[from] rmp_serde::encode::Error),
     #[error("error in repository deserialization `{0}`")]
     DeserializationError(#[from] rmp_serde::decode::Error),
+    #[error(
+        "uncommitted changes in repository, commit changes or abort them and try again"
+    )]
+    UncommittedChanges,
+    #[error("error in chunk extra data {0}")]
+    ChunkExtra(#[from] ChunkExtraError),
+    #[error("integrity check failed: {0}")]
+    Verification(#[from] VerificationError),
+    #[error("read {found} bytes of chunk data, the manifest expects {expected}")]
+    ChunkLengthMismatch { expected: u64, found: u64 },
+    #[error("commit to branch `{branch}` not visible after {attempts} attempts")]
+    ConsistencyNotReached { branch: String, attempts: u32 },
+    #[error("cannot concatenate arrays: {message}")]
+    InvalidConcatenation { message: String },
+    #[error(
+        "the array at `{path}` is a concatenation of other arrays, it cannot be modified"
+    )]
+    ConcatenatedArrayIsReadOnly { path: Path },
+    #[error("the array at `{path}` is frozen, it cannot be modified or deleted")]
+    FrozenArray { path: Path },
+    #[error("invalid changes to array `{path}`: {violation}")]
+    ConstraintViolation {
+        path: Path,
+        #[source]
+        violation: ConstraintViolation,
+    },
+    #[error(
+        "refusing to write invalid tables for snapshot `{snapshot_id}`: {}",
+        violations.iter().join("; ")
+    )]
+    InvalidTables { snapshot_id: SnapshotId, violations: Vec<TableViolation> },
+    #[error("cannot read array `{path}`, of type {data_type}, as `{requested}`")]
+    WrongElementType { path: Path, data_type: DataType, requested: &'static str },
+    #[error("no coordinate array is registered for dimension `{dimension}` of `{path}`")]
+    NoCoordinateArray { path: Path, dimension: String },
+    #[error("invalid coordinate array `{path}`: {message}")]
+    InvalidCoordinateArray { path: Path, message: String },
+    #[error("error in chunk codecs: {0}")]
+    CodecError(#[from] CodecError),
+    #[error("error reading snapshot `{snapshot_id}`: {source}")]
+    InSnapshot {
+        snapshot_id: SnapshotId,
+        #[source]
+        source: Box<RepositoryError>,
+    },
+    #[error("cannot merge writer: {message}")]
+    InvalidWriter { message: String },
+    #[error("error reading chunk `{coords:?}` of array `{path}`: {source}")]
+    InChunk {
+        path: Path,
+        coords: ChunkIndices,
+        #[source]
+        source: Box<RepositoryError>,
+    },
+}
+
+impl RepositoryError {
+    /// Record the snapshot the failed operation was reading
+    pub fn with_snapshot(self, snapshot_id: &SnapshotId) -> Self {
+        RepositoryError::InSnapshot {
+            snapshot_id: snapshot_id.clone(),
+            source: Box::new(self),
+        }
+    }
+
+    /// Record the array and chunk coordinates the failed operation was reading
+    pub fn with_chunk(self, path: &Path, coords: &ChunkIndices) -> Self {
+        RepositoryError::InChunk {
+            path: path.clone(),
+            coords: coords.clone(),
+            source: Box::new(self),
+        }
+    }
+
+    /// The innermost error, without the context added by [`RepositoryError::with_snapshot`]
+    /// and [`RepositoryError::with_chunk`]
+    pub fn root_cause(&self) -> &RepositoryError {
+        match self {
+            RepositoryError::InSnapshot { source, .. }
+            | RepositoryError::InChunk { source, .. } => source.root_cause(),
+            err => err,
+        }
+    }
+
+    /// The snapshot involved in the failure, if known
+    pub fn snapshot_id(&self) -> Option<&SnapshotId> {
+        match self {
+            RepositoryError::InSnapshot { snapshot_id, .. } => Some(snapshot_id),
+            RepositoryError::InChunk { source, .. } => source.snapshot_id(),
+            _ => None,
+        }
+    }
+
+    /// The array path and chunk coordinates involved in the failure, if known
+    pub fn chunk(&self) -> Option<(&Path, &ChunkIndices)> {
+        match self {
+            RepositoryError::InChunk { path, coords, .. } => Some((path, coords)),
+            RepositoryError::InSnapshot { source, .. } => source.chunk(),
+            _ => None,
+        }
+    }
+
+    /// The key of the object in storage involved in the failure, if known
+    pub fn object_key(&self) -> Option<&str> {
+        match self.root_cause() {
+            RepositoryError::StorageError(err) => err.object_key(),
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            RepositoryError::StorageError(err) => err.kind(),
+            RepositoryError::Ref(err) => err.kind(),
+            RepositoryError::Revision(err) => err.kind(),
+            RepositoryError::VirtualReferenceError(err) => err.kind(),
+            RepositoryError::IoError(err) => err.kind().into(),
+            RepositoryError::InSnapshot { source, .. }
+            | RepositoryError::InChunk { source, .. } => source.kind(),
+            RepositoryError::FormatError(err) => match err {
+                IcechunkFormatError::NodeNotFound { .. }
+                | IcechunkFormatError::ChunkCoordinatesNotFound { .. } => {
+                    ErrorKind::NotFound
+                }
+                IcechunkFormatError::ChunkCoordinatesOutOfBounds { .. }
+                | IcechunkFormatError::AxisOutOfBounds { .. }
+                | IcechunkFormatError::DimensionNotFound { .. }
+                | IcechunkFormatError::AmbiguousDimension { .. }
+                | IcechunkFormatError::ByteRangeOutOfBounds { .. }
+                | IcechunkFormatError::SelectionOutOfBounds { .. }
+                | IcechunkFormatError::SelectionRank { .. } => ErrorKind::InvalidRequest,
+                IcechunkFormatError::TooManyChunkReferences { .. } => {
+                    ErrorKind::Unsupported
+                }
+                _ => ErrorKind::Corruption,
+            },
+            RepositoryError::NodeNotFound { .. }
+            | RepositoryError::NoCoordinateArray { .. } => ErrorKind::NotFound,
+            RepositoryError::DeserializationError(_)
+            | RepositoryError::ChunkExtra(_)
+            | RepositoryError::Verification(_)
+            | RepositoryError::ChunkLengthMismatch { .. } => ErrorKind::Corruption,
+            RepositoryError::CodecError(err) => err.kind(),
+            RepositoryError::Conflict { .. }
+            | RepositoryError::AlreadyExists { .. }
+            | RepositoryError::PathCollision { .. }
+            | RepositoryError::AlreadyInitialized => ErrorKind::Conflict,
+            // the commit can become visible later
+            RepositoryError::ConsistencyNotReached { .. } => ErrorKind::Transient,
+            RepositoryError::NotAnArray { .. }
+            | RepositoryError::NotAGroup { .. }
+            | RepositoryError::NoChangesToCommit
+            | RepositoryError::Tag(_)
+            | RepositoryError::ProtectedTag(_)
+            | RepositoryError::BranchProtected { .. }
+            | RepositoryError::OutsideWriteRegions { .. }
+            | RepositoryError::ConstraintViolation { .. }
+            | RepositoryError::UncommittedChanges
+            | RepositoryError::InvalidConcatenation { .. }
+            | RepositoryError::ConcatenatedArrayIsReadOnly { .. }
+            | RepositoryError::FrozenArray { .. }
+            | RepositoryError::InvalidWriter { .. }
+            | RepositoryError::WrongElementType { .. }
+            | RepositoryError::InvalidCoordinateArray { .. } => ErrorKind::InvalidRequest,
+            RepositoryError::OtherFlushError
+            | RepositoryError::SerializationError(_)
+            | RepositoryError::InvalidTables { .. } => ErrorKind::Other,
+        }
+    }
+
+    /// True if the operation can succeed if tried again, throttling and transient
+    /// storage failures are retryable, missing objects or corruption are not
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
+    }
 }
 
 pub type RepositoryResult<T> = Result<T, RepositoryError>;
@@ -179,41 +876,83 @@ pub type RepositoryResult<T> = Result<T, RepositoryError>;
 ///
 impl Repository {
     pub fn update(
-        storage: Arc<dyn Storage + Send + Sync>,
+        storage: Arc<dyn Storage>,
         previous_version_snapshot_id: SnapshotId,
     ) -> RepositoryBuilder {
         RepositoryBuilder::new(storage, previous_version_snapshot_id)
     }
 
     pub async fn from_branch_tip(
-        storage: Arc<dyn Storage + Send + Sync>,
+        storage: Arc<dyn Storage>,
         branch_name: &str,
     ) -> RepositoryResult<RepositoryBuilder> {
+        check_key_layout(storage.as_ref()).await?;
         let snapshot_id = fetch_branch_tip(storage.as_ref(), branch_name).await?.snapshot;
         Ok(Self::update(storage, snapshot_id))
     }
 
     pub async fn from_tag(
-        storage: Arc<dyn Storage + Send + Sync>,
+        storage: Arc<dyn Storage>,
         tag_name: &str,
     ) -> RepositoryResult<RepositoryBuilder> {
+        check_key_layout(storage.as_ref()).await?;
         let ref_data = fetch_tag(storage.as_ref(), tag_name).await?;
         Ok(Self::update(storage, ref_data.snapshot))
     }
 
+    /// Open the snapshot named by a ref expression like `main~2` or `tag:v1`, see
+    /// [`crate::revision`]
+    pub async fn from_ref_expression(
+        storage: Arc<dyn Storage>,
+        expression: &str,
+    ) -> RepositoryResult<RepositoryBuilder> {
+        check_key_layout(storage.as_ref()).await?;
+        let resolved = revision::resolve(storage.as_ref(), expression).await?;
+        Ok(Self::update(storage, resolved.snapshot))
+    }
+
+    /// Open the branch of `token` once its commit is visible in `storage`.
+    ///
+    /// Eventually consistent stores, mirrors and caches can serve an older version of the
+    /// branch, or miss the objects of the commit, for a while after it is made. The branch
+    /// and the snapshot of the token, with its manifests, are read again every
+    /// `wait.interval` until they are visible, the repository is opened at the branch tip,
+    /// which can be newer than the token.
+    pub async fn from_consistency_token(
+        storage: Arc<dyn Storage>,
+        token: &ConsistencyToken,
+        wait: &ConsistencyWait,
+    ) -> RepositoryResult<RepositoryBuilder> {
+        let mut attempts = 1;
+        loop {
+            if let Some(tip) = visible_tip(storage.as_ref(), token).await? {
+                return Ok(Self::update(storage, tip));
+            }
+            if attempts >= wait.max_attempts {
+                return Err(RepositoryError::ConsistencyNotReached {
+                    branch: token.branch.clone(),
+                    attempts,
+                });
+            }
+            wait.runtime.sleep(wait.interval).await;
+            attempts += 1;
+        }
+    }
+
     /// Initialize a new repository with a single empty commit to the main branch.
     ///
     /// This is the default way to create a new repository to avoid race conditions
     /// when creating repositories.
     pub async fn init(
-        storage: Arc<dyn Storage + Send + Sync>,
+        storage: Arc<dyn Storage>,
         unsafe_overwrite_refs: bool,
     ) -> RepositoryResult<RepositoryBuilder> {
         if Self::exists(storage.as_ref()).await? {
             return Err(RepositoryError::AlreadyInitialized);
         }
+        write_key_layout(storage.as_ref(), unsafe_overwrite_refs).await?;
         let new_snapshot = Snapshot::empty();
-        let new_snapshot_id = ObjectId::random();
+        let new_snapshot_id = new_snapshot.metadata.id.clone();
         storage.write_snapshot(new_snapshot_id.clone(), Arc::new(new_snapshot)).await?;
         update_branch(
             storage.as_ref(),
@@ -229,7 +968,7 @@ impl Repository {
         Ok(RepositoryBuilder::new(storage, new_snapshot_id))
     }
 
-    pub async fn exists(storage: &(dyn Storage + Send + Sync)) -> RepositoryResult<bool> {
+    pub async fn exists(storage: &dyn Storage) -> RepositoryResult<bool> {
         match fetch_branch_tip(storage, Ref::DEFAULT_BRANCH).await {
             Ok(_) => Ok(true),
             Err(RefError::RefNotFound(_)) => Ok(false),
@@ -237,28 +976,71 @@ impl Repository {
         }
     }
 
+    /// Check that `storage` holds a repository this library can use, see
+    /// [`crate::health`].
+    ///
+    /// With `probe_writes` a small object is written and deleted, to check the
+    /// credentials allow writing. Failures are reported, never returned.
+    pub async fn health_check(storage: &dyn Storage, probe_writes: bool) -> HealthReport {
+        health::health_check(storage, probe_writes).await
+    }
+
     /// Provide a reasonable amount of caching for snapshots, manifests and other assets.
     /// We recommend always using some level of asset caching.
-    pub fn add_in_mem_asset_caching(
-        storage: Arc<dyn Storage + Send + Sync>,
-    ) -> Arc<dyn Storage + Send + Sync> {
+    pub fn add_in_mem_asset_caching(storage: Arc<dyn Storage>) -> Arc<dyn Storage> {
         // TODO: allow tuning once we experiment with different configurations
         Arc::new(MemCachingStorage::new(storage, 2, 2, 2, 0))
     }
 
     fn new(
         config: RepositoryConfig,
-        storage: Arc<dyn Storage + Send + Sync>,
+        storage: Arc<dyn Storage>,
         snapshot_id: SnapshotId,
         change_set: Option<ChangeSet>,
         virtual_ref_config: Option<ObjectStoreVirtualChunkResolverConfig>,
+        progress: Arc<dyn ProgressListener>,
+        memory_budget: Option<Arc<MemoryBudget>>,
     ) -> Self {
+        let salvage = config
+            .repair
+            .salvage_manifests
+            .then(|| Arc::new(SalvagingStorage::new(Arc::clone(&storage))));
+        let storage = match &salvage {
+            Some(salvage) => Arc::clone(salvage) as Arc<dyn Storage>,
+            None => storage,
+        };
         Repository {
+            chunk_uploads: Arc::new(ProgressTracker::new(
+                Arc::clone(&progress),
+                ProgressOperation::ChunkUpload,
+                None,
+            )),
+            progress,
+            staged: Arc::new(Mutex::new(StagedUploads::default())),
+            write_regions: None,
+            memory_budget,
+            clock: Arc::new(SystemClock),
+            runtime: Arc::new(DefaultRuntime::default()),
+            consistency_token: None,
+            commit_telemetry: Arc::new(CommitTelemetry::new()),
+            io_telemetry: Arc::new(IoTelemetry::default()),
+            verified_manifests: Arc::new(Mutex::new(HashSet::new())),
+            snapshot_processors: Vec::new(),
+            access_listener: None,
+            scratch: None,
+            scratch_chunks: Arc::new(Mutex::new(HashSet::new())),
+            #[cfg(feature = "notifications")]
+            notifier: config.notifications.as_ref().map(|notifications| {
+                Arc::new(crate::notify::Notifier::new(notifications.target.clone()))
+            }),
             snapshot_id,
             config,
             storage,
+            salvage,
             last_node_id: None,
+            node_id_step: 1,
             change_set: change_set.unwrap_or_default(),
+            split_base: None,
             virtual_resolver: Arc::new(ObjectStoreVirtualChunkResolver::new(
                 virtual_ref_config,
             )),
@@ -287,8 +1069,46 @@ impl Repository {
         Ok(())
     }
 
+    pub(crate) fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    pub(crate) fn progress_listener(&self) -> &Arc<dyn ProgressListener> {
+        &self.progress
+    }
+
+    pub fn config(&self) -> &RepositoryConfig {
+        &self.config
+    }
+
+    /// The token of the last commit made by this repository, to read the commit in other
+    /// processes with [`Repository::from_consistency_token`]
+    pub fn consistency_token(&self) -> Option<&ConsistencyToken> {
+        self.consistency_token.as_ref()
+    }
+
+    /// The commit attempts, conflicts and rebases recorded so far
+    pub fn commit_stats(&self) -> CommitStats {
+        self.commit_telemetry.stats()
+    }
+
+    pub fn commit_telemetry(&self) -> &Arc<CommitTelemetry> {
+        &self.commit_telemetry
+    }
+
+    /// The reads done so far and how many of them were checked
+    pub fn io_summary(&self) -> IoSummary {
+        self.io_telemetry.summary(self.config.verification)
+    }
+
+    /// The manifests read so far that lost chunk references, always empty without
+    /// [`RepairOptions::salvage_manifests`]
+    pub fn salvaged_manifests(&self) -> BTreeMap<ManifestId, SalvageReport> {
+        self.salvage.as_ref().map(|salvage| salvage.salvaged()).unwrap_or_default()
+    }
+
     /// Returns a pointer to the storage for the repository
-    pub fn storage(&self) -> &Arc<dyn Storage + Send + Sync> {
+    pub fn storage(&self) -> &Arc<dyn Storage> {
         &self.storage
     }
 
@@ -303,6 +1123,27 @@ impl Repository {
         !self.change_set.is_empty()
     }
 
+    /// Discard all uncommitted changes and delete the objects this repository uploaded
+    /// that are not part of a flushed snapshot.
+    ///
+    /// This includes chunks written since the last flush and the files of flushes that
+    /// were interrupted, for example because their future was dropped.
+    pub async fn abort(&mut self) -> RepositoryResult<()> {
+        self.change_set = ChangeSet::default();
+        if let Some(scratch) = &self.scratch {
+            let ids: Vec<_> =
+                lock_scratch(&self.scratch_chunks).iter().cloned().collect();
+            for id in ids {
+                scratch.delete_chunk(&id).await?;
+                lock_scratch(&self.scratch_chunks).remove(&id);
+            }
+        }
+        let mut staged = mem::take(&mut *lock_staged(&self.staged));
+        let res = staged.delete(self.storage.as_ref()).await;
+        lock_staged(&self.staged).append(staged);
+        res
+    }
+
     /// Returns the sequence of parents of the current session, in order of latest first.
     pub async fn ancestry(
         &self,
@@ -319,10 +1160,51 @@ impl Repository {
         Ok(futures::stream::iter(iter::once(Ok(last)).chain(it.map(Ok))))
     }
 
-    /// Add a group to the store.
-    ///
-    /// Calling this only records the operation in memory, doesn't have any consequence on the storage
-    pub async fn add_group(&mut self, path: Path) -> RepositoryResult<()> {
+    /// Record that the next commit is derived from `dependency`, a version of another
+    /// dataset, see [`crate::provenance`]
+    pub fn add_dependency(&mut self, dependency: SnapshotDependency) {
+        self.change_set.add_dependency(dependency);
+    }
+
+    /// The dependencies recorded by the current snapshot and its ancestors, with the
+    /// snapshot that recorded them, latest first
+    pub async fn dependencies(
+        &self,
+    ) -> RepositoryResult<Vec<(SnapshotId, SnapshotDependency)>> {
+        let snapshot = self.storage.fetch_snapshot(self.snapshot_id()).await?;
+        Ok(provenance::recorded_dependencies(&snapshot).collect())
+    }
+
+    /// The objects written by the commit of `snapshot_id`.
+    ///
+    /// Returns `None` for snapshots written before the objects were recorded.
+    pub async fn commit_objects(
+        &self,
+        snapshot_id: &SnapshotId,
+    ) -> RepositoryResult<Option<CommitObjects>> {
+        Ok(self.storage.fetch_snapshot(snapshot_id).await?.new_objects.clone())
+    }
+
+    // the path of a new node, normalized and checked against the existing nodes with
+    // the path policy
+    async fn check_new_path(&self, path: Path) -> RepositoryResult<Path> {
+        let policy = &self.config.path_policy;
+        let path = policy.normalize(path);
+        if policy.detects_collisions() {
+            let existing: Vec<_> =
+                self.list_nodes().await?.map(|node| node.path).collect();
+            if let Some(existing) = policy.find_collision(&path, &existing) {
+                return Err(RepositoryError::PathCollision { path, existing });
+            }
+        }
+        Ok(path)
+    }
+
+    /// Add a group to the store.
+    ///
+    /// Calling this only records the operation in memory, doesn't have any consequence on the storage
+    pub async fn add_group(&mut self, path: Path) -> RepositoryResult<()> {
+        let path = self.check_new_path(path).await?;
         match self.get_node(&path).await {
             Err(RepositoryError::NodeNotFound { .. }) => {
                 let id = self.reserve_node_id().await?;
@@ -330,7 +1212,7 @@ impl Repository {
                 Ok(())
             }
             Ok(node) => Err(RepositoryError::AlreadyExists {
-                node,
+                node: Box::new(node),
                 message: "trying to add group".to_string(),
             }),
             Err(err) => Err(err),
@@ -343,6 +1225,18 @@ impl Repository {
     pub async fn delete_group(&mut self, path: Path) -> RepositoryResult<()> {
         match self.get_group(&path).await {
             Ok(node) => {
+                // deleting the group deletes the arrays below it
+                let arrays: Vec<_> = self
+                    .list_nodes()
+                    .await?
+                    .filter(|array| {
+                        array.path.starts_with(&node.path)
+                            && !matches!(array.node_data, NodeData::Group)
+                    })
+                    .collect();
+                for array in arrays.iter() {
+                    self.check_not_frozen(array).await?;
+                }
                 self.change_set.delete_group(node.path, node.id);
             }
             Err(RepositoryError::NodeNotFound { .. }) => {}
@@ -359,6 +1253,8 @@ impl Repository {
         path: Path,
         metadata: ZarrArrayMetadata,
     ) -> RepositoryResult<()> {
+        metadata.validate_chunk_grid()?;
+        let path = self.check_new_path(path).await?;
         match self.get_node(&path).await {
             Err(RepositoryError::NodeNotFound { .. }) => {
                 let id = self.reserve_node_id().await?;
@@ -366,13 +1262,148 @@ impl Repository {
                 Ok(())
             }
             Ok(node) => Err(RepositoryError::AlreadyExists {
-                node,
+                node: Box::new(node),
                 message: "trying to add array".to_string(),
             }),
             Err(err) => Err(err),
         }
     }
 
+    /// Add an array that presents the arrays at `sources` one after the other, along
+    /// `axis`, an index or a dimension name.
+    ///
+    /// Sources are arrays of committed snapshots. They need the same metadata, except
+    /// for their size along `axis`, and all of them but the last must end at a chunk
+    /// boundary along `axis`. No chunk references are copied, reads are served from the
+    /// manifests of the sources, and the new array cannot be written.
+    pub async fn add_concatenated_array(
+        &mut self,
+        path: Path,
+        axis: impl Into<Axis>,
+        sources: Vec<(SnapshotId, Path)>,
+    ) -> RepositoryResult<()> {
+        let axis = axis.into();
+        let path = self.check_new_path(path).await?;
+        match self.get_node(&path).await {
+            Err(RepositoryError::NodeNotFound { .. }) => {}
+            Ok(node) => {
+                return Err(RepositoryError::AlreadyExists {
+                    node: Box::new(node),
+                    message: "trying to add concatenated array".to_string(),
+                })
+            }
+            Err(err) => return Err(err),
+        }
+        let invalid = |message: String| RepositoryError::InvalidConcatenation { message };
+
+        let mut metadata: Option<ZarrArrayMetadata> = None;
+        let mut concatenation_axis: Option<usize> = None;
+        let mut concatenation_sources = Vec::new();
+        let mut chunk_offset = 0;
+        let last = sources.len().saturating_sub(1);
+        for (index, (snapshot_id, source_path)) in sources.into_iter().enumerate() {
+            let snapshot = self.storage.fetch_snapshot(&snapshot_id).await?;
+            let node = snapshot.get_node(&source_path).map_err(|_| {
+                invalid(format!("no node at `{source_path}` in snapshot {snapshot_id}"))
+            })?;
+            let NodeData::Array(meta, _) = &node.node_data else {
+                return Err(invalid(format!(
+                    "`{source_path}` in snapshot {snapshot_id} is not a regular array"
+                )));
+            };
+            if meta.rectilinear_grid.is_some() {
+                return Err(invalid(format!(
+                    "`{source_path}` has a rectilinear chunk grid, which is not supported"
+                )));
+            }
+            let position = meta
+                .axis_index(axis.clone())
+                .map_err(|err| invalid(format!("`{source_path}`: {err}")))?;
+            let axis = *concatenation_axis.get_or_insert(position);
+            if position != axis {
+                return Err(invalid(format!(
+                    "`{source_path}` has the axis at position {position}, not {axis}"
+                )));
+            }
+            let (Some(size), Some(chunk_size)) =
+                (meta.shape.get(axis), meta.chunk_shape.0.get(axis))
+            else {
+                return Err(invalid(format!(
+                    "`{source_path}` has {} dimensions, concatenating along axis {axis}",
+                    meta.ndim()
+                )));
+            };
+            let chunk_size = chunk_size.get();
+            if index != last && size % chunk_size != 0 {
+                return Err(invalid(format!(
+                    "`{source_path}` doesn't end at a chunk boundary along axis {axis}"
+                )));
+            }
+            match metadata.as_mut() {
+                None => metadata = Some(meta.clone()),
+                Some(metadata) => {
+                    let mut aligned = meta.clone();
+                    aligned.shape[axis] = metadata.shape[axis];
+                    aligned.dimension_names.clone_from(&metadata.dimension_names);
+                    if &aligned != metadata {
+                        return Err(invalid(format!(
+                            "the metadata of `{source_path}` doesn't match the first array"
+                        )));
+                    }
+                    metadata.shape[axis] += size;
+                }
+            }
+            let chunk_count = size.div_ceil(chunk_size);
+            concatenation_sources.push(ConcatenationSource {
+                snapshot: snapshot_id,
+                path: source_path,
+                chunk_offset,
+                chunk_count,
+            });
+            chunk_offset += chunk_count;
+        }
+        let (Some(metadata), Some(axis)) = (metadata, concatenation_axis) else {
+            return Err(invalid("no source arrays".to_string()));
+        };
+        let concatenation = Concatenation { axis, sources: concatenation_sources };
+
+        let id = self.reserve_node_id().await?;
+        self.change_set.add_concatenated_array(path, id, metadata, concatenation);
+        Ok(())
+    }
+
+    /// The array at `path`, failing for concatenated arrays, which are read-only
+    async fn get_writable_array(&self, path: &Path) -> RepositoryResult<NodeSnapshot> {
+        let node = self.get_array(path).await?;
+        match node.node_data {
+            NodeData::Concatenated(..) => {
+                Err(RepositoryError::ConcatenatedArrayIsReadOnly { path: path.clone() })
+            }
+            _ => {
+                self.check_not_frozen(&node).await?;
+                Ok(node)
+            }
+        }
+    }
+
+    /// Fail if the array is frozen by the configuration or by its attributes
+    async fn check_not_frozen(&self, node: &NodeSnapshot) -> RepositoryResult<()> {
+        let frozen = self.config.frozen_arrays.contains(&node.path)
+            || match &node.user_attributes {
+                Some(UserAttributesSnapshot::Inline(atts)) => atts.is_frozen(),
+                // only fetched if the node was read without resolving its attributes
+                atts @ Some(UserAttributesSnapshot::Ref(_)) => matches!(
+                    resolve_user_attributes(self.storage.as_ref(), atts.clone()).await?,
+                    Some(UserAttributesSnapshot::Inline(atts)) if atts.is_frozen()
+                ),
+                None => false,
+            };
+        if frozen {
+            return Err(RepositoryError::FrozenArray { path: node.path.clone() });
+        }
+        Ok(())
+    }
+
     // Updates an array Zarr metadata
     ///
     /// Calling this only records the operation in memory, doesn't have any consequence on the storage
@@ -381,9 +1412,21 @@ impl Repository {
         path: Path,
         metadata: ZarrArrayMetadata,
     ) -> RepositoryResult<()> {
-        self.get_array(&path)
-            .await
-            .map(|node| self.change_set.update_array(node.id, metadata))
+        metadata.validate_chunk_grid()?;
+        let node = self.get_writable_array(&path).await?;
+        if self.config.skip_noop_writes {
+            if let Some(NodeSnapshot {
+                node_data: NodeData::Array(committed, _), ..
+            }) = self.committed_node(&node).await?
+            {
+                if committed == metadata {
+                    self.change_set.unset_updated_array(node.id);
+                    return Ok(());
+                }
+            }
+        }
+        self.change_set.update_array(node.id, metadata);
+        Ok(())
     }
 
     /// Delete an array in the hierarchy
@@ -392,6 +1435,7 @@ impl Repository {
     pub async fn delete_array(&mut self, path: Path) -> RepositoryResult<()> {
         match self.get_array(&path).await {
             Ok(node) => {
+                self.check_not_frozen(&node).await?;
                 self.change_set.delete_array(node.path, node.id);
             }
             Err(RepositoryError::NodeNotFound { .. }) => {}
@@ -407,10 +1451,88 @@ impl Repository {
         atts: Option<UserAttributes>,
     ) -> RepositoryResult<()> {
         let node = self.get_node(&path).await?;
+        if let NodeData::Array(..) | NodeData::Concatenated(..) = node.node_data {
+            self.check_not_frozen(&node).await?;
+        }
+        if self.config.skip_noop_writes {
+            if let Some(committed) = self.committed_node(&node).await? {
+                let committed = resolve_user_attributes(
+                    self.storage.as_ref(),
+                    committed.user_attributes,
+                )
+                .await?;
+                if committed == atts.clone().map(UserAttributesSnapshot::Inline) {
+                    self.change_set.unset_user_attributes(node.id);
+                    return Ok(());
+                }
+            }
+        }
         self.change_set.update_user_attributes(node.id, atts);
         Ok(())
     }
 
+    /// The version of `node` in the snapshot of this repository, without the changes of
+    /// this session. `None` for nodes created in this session.
+    async fn committed_node(
+        &self,
+        node: &NodeSnapshot,
+    ) -> RepositoryResult<Option<NodeSnapshot>> {
+        let snapshot =
+            self.storage.fetch_snapshot(&self.snapshot_id).await.map_err(|err| {
+                RepositoryError::from(err).with_snapshot(&self.snapshot_id)
+            })?;
+        Ok(snapshot.get_node(&node.path).ok().filter(|n| n.id == node.id).cloned())
+    }
+
+    /// True if `data` is the committed content of the chunk, so writing it is a no-op.
+    ///
+    /// Always false unless [`RepositoryConfig::skip_noop_writes`] is set. Inline chunks
+    /// are compared byte by byte, chunks stored as objects by their recorded checksum.
+    /// Virtual chunks are never considered equal.
+    pub async fn is_noop_chunk_write(
+        &self,
+        path: &Path,
+        coords: &ChunkIndices,
+        data: &[u8],
+    ) -> RepositoryResult<bool> {
+        if !self.config.skip_noop_writes {
+            return Ok(false);
+        }
+        let node = self.get_array(path).await?;
+        let Some(NodeSnapshot { id, node_data: NodeData::Array(_, manifests), .. }) =
+            self.committed_node(&node).await?
+        else {
+            return Ok(false);
+        };
+        match self.get_old_chunk(id, manifests.as_slice(), coords).await? {
+            Some(ChunkPayload::Inline(bytes)) => Ok(bytes.as_ref() == data),
+            Some(ChunkPayload::Ref(_)) => {
+                for manifest in manifests.iter().filter(|m| m.extents.contains(coords)) {
+                    let manifest =
+                        self.storage.fetch_manifests(&manifest.object_id).await?;
+                    if let Some(extra) = manifest.get_chunk_extra(id, coords) {
+                        return Ok(extra.get::<ChunkChecksum>()?.is_some_and(
+                            |checksum| checksum == ChunkChecksum::of(data),
+                        ));
+                    }
+                }
+                Ok(false)
+            }
+            Some(ChunkPayload::Virtual(_)) | None => Ok(false),
+        }
+    }
+
+    /// Forget the changes made to a chunk in this session, the committed chunk stays
+    pub async fn unset_chunk_ref(
+        &mut self,
+        path: &Path,
+        coords: &ChunkIndices,
+    ) -> RepositoryResult<()> {
+        let node = self.get_writable_array(path).await?;
+        self.change_set.unset_chunk_ref(node.id, coords);
+        Ok(())
+    }
+
     // Record the write, referenceing or delete of a chunk
     //
     // Caller has to write the chunk before calling this.
@@ -420,9 +1542,278 @@ impl Repository {
         coord: ChunkIndices,
         data: Option<ChunkPayload>,
     ) -> RepositoryResult<()> {
-        self.get_array(&path)
-            .await
-            .map(|node| self.change_set.set_chunk_ref(node.id, coord, data))
+        if let Some(regions) = &self.write_regions {
+            let inside = regions
+                .get(&path)
+                .is_some_and(|regions| regions.iter().any(|r| r.contains(&coord)));
+            if !inside {
+                return Err(RepositoryError::OutsideWriteRegions { path, coords: coord });
+            }
+        }
+        let node = self.get_writable_array(&path).await?;
+        if let Some(constraints) = self.config.array_constraints.get(&path) {
+            let checked = match &node.node_data {
+                NodeData::Array(metadata, _) => constraints.check_metadata(metadata),
+                _ => Ok(()),
+            };
+            if let Err(violation) =
+                checked.and_then(|_| constraints.check_chunk(&coord, data.as_ref()))
+            {
+                return Err(RepositoryError::ConstraintViolation { path, violation });
+            }
+        }
+        if let Some(ChunkPayload::Virtual(reference)) = &data {
+            if self.config.validate_virtual_refs {
+                self.check_virtual_ref(reference).await?;
+            }
+        }
+        self.change_set.set_chunk_ref(node.id, coord, data);
+        Ok(())
+    }
+
+    /// Delete all the chunks of the array at `path` inside `region`, returning how many.
+    ///
+    /// Only the manifests whose extents overlap `region` are read, and the deletes are
+    /// recorded all at once: if any of the chunks is outside the declared write regions,
+    /// nothing is deleted. With `shrink`, a region that covers the end of one axis, and
+    /// the whole chunk grid along the others, also cuts the array where the region
+    /// starts. Chunks are never moved, deleting the start of an axis leaves fill values.
+    pub async fn delete_chunks_in_range(
+        &mut self,
+        path: &Path,
+        region: &ChunkRegion,
+        shrink: bool,
+    ) -> RepositoryResult<usize> {
+        let node = self.get_writable_array(path).await?;
+        let NodeData::Array(metadata, manifests) = &node.node_data else {
+            return Err(RepositoryError::NotAnArray {
+                node: Box::new(node),
+                message: "deleting chunks".to_string(),
+            });
+        };
+        if region.0.len() != metadata.ndim() {
+            return Err(IcechunkFormatError::SelectionRank {
+                found: region.0.len(),
+                ndim: metadata.ndim(),
+            }
+            .into());
+        }
+
+        // the chunks written in this session, and the committed ones it didn't change
+        let mut coords: HashSet<ChunkIndices> = self
+            .change_set
+            .array_chunks_iterator(node.id, &node.path)
+            .filter(|(coord, payload)| payload.is_some() && region.contains(coord))
+            .map(|(coord, _)| coord.clone())
+            .collect();
+        for manifest_ref in manifests.iter().filter(|m| m.extents.overlaps(region)) {
+            let manifest = self.storage.fetch_manifests(&manifest_ref.object_id).await?;
+            coords.extend(manifest.iter(&node.id).map(|(coord, _)| coord).filter(
+                |coord| {
+                    region.contains(coord)
+                        && self.change_set.get_chunk_ref(node.id, coord).is_none()
+                },
+            ));
+        }
+        if let Some(regions) = &self.write_regions {
+            let declared = regions.get(path).map(Vec::as_slice).unwrap_or_default();
+            if let Some(coord) =
+                coords.iter().find(|coord| !declared.iter().any(|r| r.contains(coord)))
+            {
+                return Err(RepositoryError::OutsideWriteRegions {
+                    path: path.clone(),
+                    coords: coord.clone(),
+                });
+            }
+        }
+        let shrunk = match trailing_slab(metadata, region) {
+            Some((axis, chunk)) if shrink => Some(metadata.truncated(axis, chunk)?),
+            _ => None,
+        };
+        if let (Some(constraints), Some(shrunk)) =
+            (self.config.array_constraints.get(path), &shrunk)
+        {
+            constraints.check_metadata(shrunk).map_err(|violation| {
+                RepositoryError::ConstraintViolation { path: path.clone(), violation }
+            })?;
+        }
+
+        let deleted = coords.len();
+        for coord in coords {
+            self.change_set.set_chunk_ref(node.id, coord, None);
+        }
+        if let Some(shrunk) = shrunk {
+            self.change_set.update_array(node.id, shrunk);
+        }
+        Ok(deleted)
+    }
+
+    /// Check that the file of `reference` exists and covers its range, without setting
+    /// it. [`Repository::set_chunk_ref`] does it for every virtual reference with
+    /// [`RepositoryConfig::validate_virtual_refs`].
+    pub async fn check_virtual_ref(
+        &self,
+        reference: &VirtualChunkRef,
+    ) -> RepositoryResult<()> {
+        let info = self.virtual_resolver.fetch_file_info(&reference.location).await?;
+        let end = reference.offset.checked_add(reference.length);
+        if end.is_none_or(|end| end > info.size) {
+            let VirtualChunkLocation::Absolute(location) = &reference.location;
+            return Err(VirtualReferenceError::OutOfBounds {
+                location: location.clone(),
+                offset: reference.offset,
+                length: reference.length,
+                size: info.size,
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Store `value` in the extra data of a chunk, it's written to the manifest on flush.
+    ///
+    /// Extra data is dropped if the chunk is set again. It can be set for chunks that were
+    /// committed before, but it's ignored for chunks that don't exist.
+    pub async fn set_chunk_extra<T: ChunkExtraSchema>(
+        &mut self,
+        path: &Path,
+        coord: ChunkIndices,
+        value: &T,
+    ) -> RepositoryResult<()> {
+        let node = self.get_writable_array(path).await?;
+        self.change_set.chunk_extra_mut(node.id, coord).set(value)?;
+        Ok(())
+    }
+
+    /// The value stored for the schema `T` in the extra data of a chunk
+    pub async fn get_chunk_extra<T: ChunkExtraSchema>(
+        &self,
+        path: &Path,
+        coords: &ChunkIndices,
+    ) -> RepositoryResult<Option<T>> {
+        let node = self.get_array(path).await?;
+        if let Some(value) = self
+            .change_set
+            .get_chunk_extra(node.id, coords)
+            .map(|extra| extra.get::<T>())
+            .transpose()?
+            .flatten()
+        {
+            return Ok(Some(value));
+        }
+        // committed extra data is stale for chunks written in this session
+        if self.change_set.get_chunk_ref(node.id, coords).is_some() {
+            return Ok(None);
+        }
+        let NodeData::Array(_, manifests) = node.node_data else { return Ok(None) };
+        for manifest in manifests.iter().filter(|m| m.extents.contains(coords)) {
+            let manifest = self.storage.fetch_manifests(&manifest.object_id).await?;
+            if let Some(extra) = manifest.get_chunk_extra(node.id, coords) {
+                return Ok(extra.get::<T>()?);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Record statistics for the values of a chunk, see [`Repository::set_chunk_extra`]
+    pub async fn set_chunk_statistics(
+        &mut self,
+        path: &Path,
+        coord: ChunkIndices,
+        statistics: ChunkStatistics,
+    ) -> RepositoryResult<()> {
+        self.set_chunk_extra(path, coord, &statistics).await
+    }
+
+    /// The statistics of `data`, the bytes of a chunk of the array at `path`.
+    ///
+    /// Returns `None` if [`RepositoryConfig::compute_chunk_statistics`] is not set, or the
+    /// values cannot be decoded because the array has codecs the [`CodecPipeline`]
+    /// doesn't support, or a non numeric data type.
+    pub async fn compute_chunk_statistics(
+        &self,
+        path: &Path,
+        data: &[u8],
+    ) -> RepositoryResult<Option<ChunkStatistics>> {
+        if !self.config.compute_chunk_statistics {
+            return Ok(None);
+        }
+        match self.get_array(path).await?.node_data {
+            NodeData::Array(metadata, _) => {
+                let Ok(pipeline) = CodecPipeline::new(&metadata) else { return Ok(None) };
+                let data = pipeline.decode_elements(data)?;
+                Ok(decoded_chunk_statistics(&metadata.data_type, &data))
+            }
+            NodeData::Group | NodeData::Concatenated(..) => Ok(None),
+        }
+    }
+
+    /// The checksum of `data`, the bytes of a chunk, to store with
+    /// [`Repository::set_chunk_extra`].
+    ///
+    /// Returns `None` if [`RepositoryConfig::compute_chunk_checksums`] is not set.
+    pub fn compute_chunk_checksum(&self, data: &[u8]) -> Option<ChunkChecksum> {
+        self.config.compute_chunk_checksums.then(|| ChunkChecksum::of(data))
+    }
+
+    /// Declare a region of chunks of the array at `path` that this repository will write.
+    ///
+    /// Once a region is declared, writing chunks outside the declared regions fails. If
+    /// the only changes are chunk writes inside the declared regions, commits record the
+    /// regions, and a commit can proceed even if the branch moved, as long as all the new
+    /// commits in the branch declared regions that don't overlap with these ones.
+    ///
+    /// This makes it cheap for many writers to commit to disjoint regions of the same
+    /// arrays concurrently. Declared regions persist across commits.
+    pub fn declare_write_region(&mut self, path: Path, region: ChunkRegion) {
+        self.write_regions
+            .get_or_insert_with(Default::default)
+            .entry(path)
+            .or_default()
+            .push(region);
+    }
+
+    /// Declare all the `regions`, see [`Repository::declare_write_region`]
+    pub fn declare_write_regions(&mut self, regions: &WriteRegions) {
+        for (path, regions) in regions {
+            for region in regions {
+                self.declare_write_region(path.clone(), region.clone());
+            }
+        }
+    }
+
+    pub fn write_regions(&self) -> Option<&WriteRegions> {
+        self.write_regions.as_ref()
+    }
+
+    /// True if the changes in `change_sets` can be committed on top of `tip`, because all
+    /// the commits between our snapshot and `tip` wrote to different regions
+    async fn can_commit_regions_on(
+        &self,
+        tip: &SnapshotId,
+        change_sets: &[ChangeSet],
+    ) -> RepositoryResult<bool> {
+        let regions = match &self.write_regions {
+            Some(regions)
+                if self.change_set.has_only_chunk_changes()
+                    && change_sets.iter().all(|cs| cs.has_only_chunk_changes()) =>
+            {
+                regions
+            }
+            _ => return Ok(false),
+        };
+        let tip = self.storage.fetch_snapshot(tip).await?;
+        for snap in iter::once(&tip.metadata).chain(tip.short_term_history.iter()) {
+            if &snap.id == self.snapshot_id() {
+                return Ok(true);
+            }
+            match &snap.write_regions {
+                Some(theirs) if !write_regions_overlap(regions, theirs) => {}
+                _ => return Ok(false),
+            }
+        }
+        // our snapshot is not in the branch history
+        Ok(false)
     }
 
     async fn compute_last_node_id(&self) -> RepositoryResult<NodeId> {
@@ -438,37 +1829,85 @@ impl Repository {
 
     async fn reserve_node_id(&mut self) -> RepositoryResult<NodeId> {
         let last = self.last_node_id.unwrap_or(self.compute_last_node_id().await?);
-        let new = last + 1;
+        let new = last + self.node_id_step;
         self.last_node_id = Some(new);
         Ok(new)
     }
 
     pub async fn get_node(&self, path: &Path) -> RepositoryResult<NodeSnapshot> {
-        get_node(self.storage.as_ref(), &self.change_set, self.snapshot_id(), path).await
+        get_node(
+            self.storage.as_ref(),
+            &self.change_set,
+            self.snapshot_id(),
+            path,
+            self.config.open.resolve_attributes,
+        )
+        .await
     }
 
     pub async fn get_array(&self, path: &Path) -> RepositoryResult<NodeSnapshot> {
         match self.get_node(path).await {
-            res @ Ok(NodeSnapshot { node_data: NodeData::Array(..), .. }) => res,
+            res @ Ok(NodeSnapshot {
+                node_data: NodeData::Array(..) | NodeData::Concatenated(..),
+                ..
+            }) => res,
             Ok(node @ NodeSnapshot { .. }) => Err(RepositoryError::NotAnArray {
-                node,
+                node: Box::new(node),
                 message: "getting an array".to_string(),
             }),
             other => other,
         }
     }
 
+    /// The chunks to read, and the part of each chunk to decode, for the array elements
+    /// in `selection`, see [`crate::read_plan`]
+    pub async fn plan_read(
+        &self,
+        path: &Path,
+        selection: &[Range<u64>],
+    ) -> RepositoryResult<ReadPlan> {
+        read_plan::plan_read(self, path, selection).await
+    }
+
+    /// Fetch and decode the array elements in `selection`, see [`crate::decoded`]
+    #[cfg(feature = "ndarray")]
+    pub async fn read_selection<T: crate::decoded::Element>(
+        &self,
+        path: &Path,
+        selection: &[Range<u64>],
+    ) -> RepositoryResult<ndarray::ArrayD<T>> {
+        crate::decoded::read_selection(self, path, selection).await
+    }
+
+    /// The elements and chunks of the array at `path` with labels in `labels` along
+    /// `dimension`, see [`crate::coordinates`]
+    #[cfg(feature = "ndarray")]
+    pub async fn select_labels<T: crate::decoded::Element + PartialOrd>(
+        &self,
+        path: &Path,
+        dimension: &str,
+        labels: Range<T>,
+    ) -> RepositoryResult<crate::coordinates::LabelSelection> {
+        crate::coordinates::select_labels(self, path, dimension, labels).await
+    }
+
     pub async fn get_group(&self, path: &Path) -> RepositoryResult<NodeSnapshot> {
         match self.get_node(path).await {
             res @ Ok(NodeSnapshot { node_data: NodeData::Group, .. }) => res,
             Ok(node @ NodeSnapshot { .. }) => Err(RepositoryError::NotAGroup {
-                node,
+                node: Box::new(node),
                 message: "getting a group".to_string(),
             }),
             other => other,
         }
     }
 
+    /// Where the chunk at `coords` is stored, without fetching its bytes.
+    ///
+    /// The payload is the object id and byte range of chunks stored by the repository,
+    /// the location and byte range of virtual chunks, or the bytes of inline chunks, as
+    /// written to the manifest. Only snapshots and manifests are read, so catalogs and
+    /// read planners can locate chunks cheaply. Returns `None` for chunks never written.
     pub async fn get_chunk_ref(
         &self,
         path: &Path,
@@ -479,7 +1918,7 @@ impl Repository {
         // get_array should return the array data, not a node
         match node.node_data {
             NodeData::Group => Err(RepositoryError::NotAnArray {
-                node,
+                node: Box::new(node),
                 message: "getting chunk reference".to_string(),
             }),
             NodeData::Array(_, manifests) => {
@@ -499,9 +1938,61 @@ impl Repository {
                     }
                 }
             }
+            NodeData::Concatenated(_, concatenation) => {
+                let Some((source, coords)) = concatenation.locate(coords) else {
+                    return Ok(None);
+                };
+                let snapshot =
+                    self.storage.fetch_snapshot(&source.snapshot).await.map_err(
+                        |err| RepositoryError::from(err).with_snapshot(&source.snapshot),
+                    )?;
+                let source_node = snapshot.get_node(&source.path)?;
+                match &source_node.node_data {
+                    NodeData::Array(_, manifests) => {
+                        self.get_old_chunk(source_node.id, manifests, &coords).await
+                    }
+                    _ => Err(RepositoryError::InvalidConcatenation {
+                        message: format!("`{}` is not a regular array", source.path),
+                    }),
+                }
+            }
+        }
+    }
+
+    /// The storage holding the chunk object `id`
+    fn chunk_storage(&self, id: &ChunkId) -> Arc<dyn Storage> {
+        match &self.scratch {
+            Some(scratch) if lock_scratch(&self.scratch_chunks).contains(id) => {
+                Arc::clone(scratch)
+            }
+            _ => Arc::clone(&self.storage),
         }
     }
 
+    /// Move the chunks written to the scratch storage to the repository storage, and
+    /// return how many were moved.
+    ///
+    /// Chunks keep their ids, so their references don't change. Flushes move the
+    /// chunks first, calling this while the session is still writing gets the slow
+    /// uploads out of the way, but reads of the chunks started before they move can fail.
+    /// Change sets of other sessions, given to [`Repository::distributed_flush`], must
+    /// have their chunks moved by their writers.
+    pub async fn move_scratch_chunks(&self) -> RepositoryResult<usize> {
+        let Some(scratch) = &self.scratch else { return Ok(0) };
+        let ids: Vec<_> = lock_scratch(&self.scratch_chunks).iter().cloned().collect();
+        futures::stream::iter(ids)
+            .map(|id| async move {
+                lock_staged(&self.staged).chunks.push(id.clone());
+                copy_chunk(scratch.as_ref(), self.storage.as_ref(), &id).await?;
+                lock_scratch(&self.scratch_chunks).remove(&id);
+                scratch.delete_chunk(&id).await?;
+                Ok::<_, RepositoryError>(())
+            })
+            .buffer_unordered(SCRATCH_MOVE_CONCURRENCY)
+            .try_fold(0, |moved, _| ready(Ok(moved + 1)))
+            .await
+    }
+
     /// Get a future that reads the the payload of a chunk from object store
     ///
     /// This function doesn't return [`Bytes`] directly to avoid locking the ref to self longer
@@ -534,30 +2025,98 @@ impl Repository {
     ) -> RepositoryResult<
         Option<Pin<Box<dyn Future<Output = RepositoryResult<Bytes>> + Send>>>,
     > {
-        match self.get_chunk_ref(path, coords).await? {
-            Some(ChunkPayload::Ref(ChunkRef { id, .. })) => {
-                let storage = Arc::clone(&self.storage);
+        let payload =
+            self.get_chunk_ref(path, coords).await.map_err(|err| match err {
+                err @ RepositoryError::StorageError(_) => err.with_chunk(path, coords),
+                err => err,
+            })?;
+        match payload {
+            Some(ChunkPayload::Ref(ChunkRef { id, offset, length })) => {
+                // chunk objects hold one chunk, the manifest knows its size, so reads
+                // don't need to ask storage for it
+                let expected = construct_valid_byte_range(byte_range, offset, length)?
+                    .length()
+                    .unwrap_or_default();
+                let checksum = if self.config.verification.chunk_checksums.should_verify()
+                {
+                    self.get_chunk_extra::<ChunkChecksum>(path, coords).await?
+                } else {
+                    None
+                };
+                self.io_telemetry.record_chunk_read(checksum.is_some());
+                let storage = self.chunk_storage(&id);
                 let byte_range = byte_range.clone();
+                let path = path.clone();
+                let coords = coords.clone();
+                let listener = self.access_listener.clone();
                 Ok(Some(
                     async move {
-                        // TODO: we don't have a way to distinguish if we want to pass a range or not
-                        storage.fetch_chunk(&id, &byte_range).await.map_err(|e| e.into())
+                        let (bytes, cache) = fetch_verified_chunk(
+                            storage.as_ref(),
+                            &id,
+                            &byte_range,
+                            length,
+                            expected,
+                            checksum,
+                        )
+                        .await
+                        .map_err(|e| e.with_chunk(&path, &coords))?;
+                        report_access(
+                            listener,
+                            path,
+                            coords,
+                            &bytes,
+                            ChunkSource::Stored,
+                            cache,
+                        );
+                        Ok(bytes)
                     }
                     .boxed(),
                 ))
             }
             Some(ChunkPayload::Inline(bytes)) => {
-                Ok(Some(ready(Ok(byte_range.slice(bytes))).boxed()))
+                let bytes = byte_range.slice(bytes)?;
+                report_access(
+                    self.access_listener.clone(),
+                    path.clone(),
+                    coords.clone(),
+                    &bytes,
+                    ChunkSource::Inline,
+                    CacheStatus::Uncached,
+                );
+                Ok(Some(ready(Ok(bytes)).boxed()))
             }
             Some(ChunkPayload::Virtual(VirtualChunkRef { location, offset, length })) => {
-                let byte_range = construct_valid_byte_range(byte_range, offset, length);
+                let etag = if self.config.verification.virtual_etags.should_verify() {
+                    self.get_chunk_extra::<VirtualChunkEtag>(path, coords).await?
+                } else {
+                    None
+                };
+                self.io_telemetry.record_virtual_chunk_read(etag.is_some());
+                let byte_range = construct_valid_byte_range(byte_range, offset, length)?;
                 let resolver = Arc::clone(&self.virtual_resolver);
+                let path = path.clone();
+                let coords = coords.clone();
+                let listener = self.access_listener.clone();
                 Ok(Some(
                     async move {
-                        resolver
-                            .fetch_chunk(&location, &byte_range)
-                            .await
-                            .map_err(|e| e.into())
+                        let (bytes, cache) = fetch_verified_virtual_chunk(
+                            resolver.as_ref(),
+                            &location,
+                            &byte_range,
+                            etag,
+                        )
+                        .await
+                        .map_err(|e| e.with_chunk(&path, &coords))?;
+                        report_access(
+                            listener,
+                            path,
+                            coords,
+                            &bytes,
+                            ChunkSource::Virtual,
+                            cache,
+                        );
+                        Ok(bytes)
                     }
                     .boxed(),
                 ))
@@ -579,19 +2138,50 @@ impl Repository {
     /// ```
     ///
     /// As shown, the result of the returned function must be awaited to finish the upload.
+    /// The function can be called many times, to upload many chunks concurrently.
     pub fn get_chunk_writer(
         &self,
-    ) -> impl FnOnce(
+    ) -> impl Fn(
         Bytes,
-    ) -> Pin<
-        Box<dyn Future<Output = RepositoryResult<ChunkPayload>> + Send>,
-    > {
+    ) -> Pin<Box<dyn Future<Output = RepositoryResult<ChunkPayload>> + Send>>
+           + Send
+           + Sync {
         let threshold = self.config.inline_chunk_threshold_bytes as usize;
         let storage = Arc::clone(&self.storage);
+        let uploads = Arc::clone(&self.chunk_uploads);
+        let staged = Arc::clone(&self.staged);
+        let budget = self.memory_budget.clone();
+        let scratch = self.scratch.clone();
+        let scratch_chunks = Arc::clone(&self.scratch_chunks);
         move |data: Bytes| {
+            let storage = Arc::clone(&storage);
+            let uploads = Arc::clone(&uploads);
+            let staged = Arc::clone(&staged);
+            let budget = budget.clone();
+            let scratch = scratch.clone();
+            let scratch_chunks = Arc::clone(&scratch_chunks);
             async move {
                 let payload = if data.len() > threshold {
-                    new_materialized_chunk(storage.as_ref(), data).await?
+                    let len = data.len() as u64;
+                    let _permit = match &budget {
+                        Some(budget) => Some(
+                            budget.acquire(MemoryCategory::BufferedChunks, len).await,
+                        ),
+                        None => None,
+                    };
+                    let id = ObjectId::random();
+                    let payload = match scratch {
+                        Some(scratch) => {
+                            lock_scratch(&scratch_chunks).insert(id.clone());
+                            new_materialized_chunk(scratch.as_ref(), id, data).await?
+                        }
+                        None => {
+                            lock_staged(&staged).chunks.push(id.clone());
+                            new_materialized_chunk(storage.as_ref(), id, data).await?
+                        }
+                    };
+                    uploads.advance(1, len);
+                    payload
                 } else {
                     new_inline_chunk(data)
                 };
@@ -620,13 +2210,26 @@ impl Repository {
         manifests: &[ManifestRef],
         coords: &ChunkIndices,
     ) -> RepositoryResult<Option<ChunkPayload>> {
-        // FIXME: use manifest extents
-        for manifest in manifests {
-            let manifest_structure =
-                self.storage.fetch_manifests(&manifest.object_id).await?;
+        // candidate manifests are fetched concurrently, but searched in order, the
+        // remaining fetches are dropped once the chunk is found
+        let concurrency = usize::from(self.config.manifest_fetch_concurrency.max(1));
+        let candidates: Vec<(ManifestId, Option<String>)> = manifests
+            .iter()
+            .filter(|m| m.extents.contains(coords))
+            .map(|m| (m.object_id.clone(), m.content_hash.clone()))
+            .collect();
+        let storage = self.storage.as_ref();
+        let mut fetches = futures::stream::iter(candidates)
+            .map(|(id, hash)| async move {
+                let manifest = storage.fetch_manifests(&id).await?;
+                self.verify_manifest(&id, hash.as_ref(), manifest.as_ref())?;
+                Ok::<_, RepositoryError>(manifest)
+            })
+            .buffered(concurrency);
+        while let Some(manifest_structure) = fetches.try_next().await? {
             match manifest_structure.get_chunk_payload(node, coords.clone()) {
                 Ok(payload) => {
-                    return Ok(Some(payload.clone()));
+                    return Ok(Some(payload));
                 }
                 Err(IcechunkFormatError::ChunkCoordinatesNotFound { .. }) => {}
                 Err(err) => return Err(err.into()),
@@ -635,6 +2238,47 @@ impl Repository {
         Ok(None)
     }
 
+    /// Check `manifest` against its recorded hash, if the verification policy selects it
+    fn verify_manifest(
+        &self,
+        id: &ManifestId,
+        expected: Option<&String>,
+        manifest: &Manifest,
+    ) -> RepositoryResult<()> {
+        let verified = self
+            .verified_manifests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .contains(id);
+        let expected = match expected {
+            Some(expected)
+                if !verified
+                    && self.config.verification.manifest_hashes.should_verify() =>
+            {
+                expected
+            }
+            _ => {
+                self.io_telemetry.record_manifest_read(false);
+                return Ok(());
+            }
+        };
+        self.io_telemetry.record_manifest_read(true);
+        let found = manifest.content_hash()?;
+        if &found != expected {
+            return Err(VerificationError::ManifestHash {
+                id: id.clone(),
+                expected: expected.clone(),
+                found,
+            }
+            .into());
+        }
+        self.verified_manifests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(id.clone());
+        Ok(())
+    }
+
     pub async fn list_nodes(
         &self,
     ) -> RepositoryResult<impl Iterator<Item = NodeSnapshot> + '_> {
@@ -642,6 +2286,81 @@ impl Repository {
             .await
     }
 
+    /// Returns true if the array has a chunk at `coords`.
+    ///
+    /// The answer comes from the manifests and the uncommitted changes, chunk payloads are
+    /// not fetched.
+    pub async fn chunk_exists(
+        &self,
+        path: &Path,
+        coords: &ChunkIndices,
+    ) -> RepositoryResult<bool> {
+        Ok(self.get_chunk_ref(path, coords).await?.is_some())
+    }
+
+    /// The coordinates of all the chunks of the array, in no particular order.
+    ///
+    /// Like [`Repository::chunk_exists`], this only reads manifests.
+    pub async fn chunk_coords_iter(
+        &self,
+        path: &Path,
+    ) -> RepositoryResult<impl Stream<Item = RepositoryResult<ChunkIndices>> + '_> {
+        let node = self.get_array(path).await?;
+        Ok(verified_node_chunk_iterator(self.storage.as_ref(), &self.change_set, node)
+            .await
+            .map_ok(|chunk| chunk.coord))
+    }
+
+    /// The coordinates of the chunks of the array that may have values satisfying
+    /// `predicate`, in no particular order.
+    ///
+    /// Chunks are skipped using their statistics, chunks without statistics are always
+    /// returned. Like [`Repository::chunk_exists`], this only reads manifests.
+    pub async fn chunks_matching(
+        &self,
+        path: &Path,
+        predicate: ChunkPredicate,
+    ) -> RepositoryResult<impl Stream<Item = RepositoryResult<ChunkIndices>> + '_> {
+        let node = self.get_array(path).await?;
+        let node_id = node.id;
+        let use_statistics = self.config.open.use_statistics;
+        let mut committed = HashMap::new();
+        if let (true, NodeData::Array(_, manifests)) = (use_statistics, &node.node_data) {
+            for manifest in manifests {
+                let manifest = self.storage.fetch_manifests(&manifest.object_id).await?;
+                // statistics that cannot be read are unknown, they don't skip chunks
+                committed.extend(manifest.node_extras(node_id).filter_map(
+                    |(coord, extra)| {
+                        extra
+                            .get::<ChunkStatistics>()
+                            .ok()
+                            .flatten()
+                            .map(|s| (coord.clone(), s))
+                    },
+                ));
+            }
+        }
+        let change_set = &self.change_set;
+        Ok(verified_node_chunk_iterator(self.storage.as_ref(), change_set, node)
+            .await
+            .map_ok(|chunk| chunk.coord)
+            .try_filter(move |coord| {
+                if !use_statistics {
+                    return ready(true);
+                }
+                let session = change_set
+                    .get_chunk_extra(node_id, coord)
+                    .and_then(|extra| extra.get::<ChunkStatistics>().ok().flatten());
+                let stats = match session.as_ref() {
+                    Some(stats) => Some(stats),
+                    // committed statistics are stale for chunks written in this session
+                    None if change_set.get_chunk_ref(node_id, coord).is_some() => None,
+                    None => committed.get(coord),
+                };
+                ready(stats.is_none_or(|stats| predicate.may_match(stats)))
+            }))
+    }
+
     pub async fn all_chunks(
         &self,
     ) -> RepositoryResult<impl Stream<Item = RepositoryResult<(Path, ChunkInfo)>> + '_>
@@ -649,25 +2368,108 @@ impl Repository {
         all_chunks(self.storage.as_ref(), &self.change_set, self.snapshot_id()).await
     }
 
-    pub async fn distributed_flush<I: IntoIterator<Item = ChangeSet>>(
+    /// Copy the bytes of the virtual chunks selected by `filter` into chunks managed by
+    /// the repository, and replace the virtual references with them.
+    ///
+    /// This only stages the changes, like [`Repository::set_chunk_ref`], they are written
+    /// by the next commit. Repositories of virtual references can be converted gradually,
+    /// by selecting a few arrays or files each time. Returns the number of chunks copied.
+    pub async fn materialize_virtual_refs<F>(
+        &mut self,
+        filter: F,
+    ) -> RepositoryResult<usize>
+    where
+        F: Fn(&Path, &VirtualChunkRef) -> bool,
+    {
+        let selected: Vec<_> = self
+            .all_chunks()
+            .await?
+            .try_filter_map(|(path, chunk)| {
+                ready(Ok(match chunk.payload {
+                    ChunkPayload::Virtual(chunk_ref) if filter(&path, &chunk_ref) => {
+                        Some((path, chunk.coord, chunk_ref))
+                    }
+                    _ => None,
+                }))
+            })
+            .try_collect()
+            .await?;
+
+        let materialized: Vec<_> = futures::stream::iter(selected)
+            .map(|(path, coords, VirtualChunkRef { location, offset, length })| {
+                let resolver = Arc::clone(&self.virtual_resolver);
+                let write = self.get_chunk_writer();
+                async move {
+                    let range =
+                        ByteRange::checked_from_offset_with_length(offset, length)?;
+                    let bytes =
+                        resolver.fetch_chunk(&location, &range).await.map_err(|e| {
+                            RepositoryError::from(e).with_chunk(&path, &coords)
+                        })?;
+                    let payload = write(bytes).await?;
+                    Ok::<_, RepositoryError>((path, coords, payload))
+                }
+            })
+            .buffer_unordered(MATERIALIZE_CONCURRENCY)
+            .try_collect()
+            .await?;
+
+        let copied = materialized.len();
+        for (path, coords, payload) in materialized {
+            self.set_chunk_ref(path, coords, Some(payload)).await?;
+        }
+        Ok(copied)
+    }
+
+    pub async fn distributed_flush<I: IntoIterator<Item = ChangeSet>>(
+        &mut self,
+        other_change_sets: I,
+        message: &str,
+        properties: SnapshotProperties,
+    ) -> RepositoryResult<SnapshotId> {
+        let parent = self.snapshot_id.clone();
+        self.distributed_flush_on(&parent, other_change_sets, message, properties).await
+    }
+
+    /// Flush the changes as a child of `parent`, our snapshot or a tip we rebased on. The
+    /// repository moves to the new snapshot only if the flush succeeds.
+    async fn distributed_flush_on<I: IntoIterator<Item = ChangeSet>>(
         &mut self,
+        parent: &SnapshotId,
         other_change_sets: I,
         message: &str,
         properties: SnapshotProperties,
     ) -> RepositoryResult<SnapshotId> {
+        self.move_scratch_chunks().await?;
         // FIXME: this clone can be avoided
         let change_sets = iter::once(self.change_set.clone()).chain(other_change_sets);
+        let staged_chunks = lock_staged(&self.staged).chunks.len();
         let new_snapshot_id = distributed_flush(
             self.storage.as_ref(),
             change_sets,
-            self.snapshot_id(),
+            parent,
             message,
             properties,
+            self.config.empty_commits == EmptyCommitPolicy::CreateEmpty,
+            self.write_regions.as_ref(),
+            &self.config.manifest_split_policy,
+            &self.config.array_constraints,
+            self.clock.as_ref(),
+            Arc::clone(&self.progress),
+            &self.staged,
         )
         .await?;
 
+        // chunks uploaded before the flush are now part of the snapshot
+        lock_staged(&self.staged).chunks.drain(..staged_chunks);
+
         self.snapshot_id = new_snapshot_id.clone();
         self.change_set = ChangeSet::default();
+        self.chunk_uploads = Arc::new(ProgressTracker::new(
+            Arc::clone(&self.progress),
+            ProgressOperation::ChunkUpload,
+            None,
+        ));
         Ok(new_snapshot_id)
     }
 
@@ -696,6 +2498,66 @@ impl Repository {
             .await
     }
 
+    /// Recover `branch` from lost objects, see [`crate::repair`].
+    ///
+    /// The repository must not have uncommitted changes, it reads the repaired tip of the
+    /// branch after.
+    pub async fn repair(
+        &mut self,
+        branch: &str,
+        message: &str,
+    ) -> RepositoryResult<RecoveryReport> {
+        if self.has_uncommitted_changes() {
+            return Err(RepositoryError::UncommittedChanges);
+        }
+        let mut report = RecoveryReport::default();
+        let (tip, skipped) =
+            repair::newest_readable_tip(self.storage.as_ref(), branch).await?;
+        if !skipped.is_empty() {
+            reset_branch(self.storage.as_ref(), branch, tip.clone()).await?;
+            report.rebuilt_ref = Some(tip.clone());
+            report.skipped_versions = skipped;
+        }
+        self.snapshot_id = tip;
+
+        let missing = repair::missing_chunks(self).await?;
+        if !missing.is_empty() {
+            for (chunk, _) in missing.iter() {
+                let node = self.get_array(&chunk.path).await?;
+                self.change_set.tombstone_chunk(node.id, chunk.coords.clone());
+            }
+            // artifacts are computed below, for the tip of the branch in every case
+            let processors = mem::take(&mut self.snapshot_processors);
+            let committed = self.commit(branch, message, None).await;
+            self.snapshot_processors = processors;
+            let snapshot = committed?;
+            report.missing_chunks = missing.into_iter().map(|(chunk, _)| chunk).collect();
+            repair::find_tombstones(
+                self.storage.as_ref(),
+                &snapshot,
+                &mut report.missing_chunks,
+            )
+            .await?;
+            report.snapshot = Some(snapshot);
+        }
+
+        for processor in self.snapshot_processors.iter() {
+            let processors = slice::from_ref(processor);
+            let name = processor.name().to_string();
+            match process_snapshot(
+                Arc::clone(&self.storage),
+                &self.snapshot_id,
+                processors,
+            )
+            .await
+            {
+                Ok(()) => report.artifacts.push(name),
+                Err(err) => report.failed_artifacts.push((name, err.to_string())),
+            }
+        }
+        Ok(report)
+    }
+
     pub async fn distributed_commit<I: IntoIterator<Item = ChangeSet>>(
         &mut self,
         update_branch_name: &str,
@@ -703,10 +2565,22 @@ impl Repository {
         message: &str,
         properties: Option<SnapshotProperties>,
     ) -> RepositoryResult<SnapshotId> {
+        let other_change_sets: Vec<_> = other_change_sets.into_iter().collect();
+        self.commit_telemetry.record_attempt();
         let current = fetch_branch_tip(self.storage.as_ref(), update_branch_name).await;
-        match current {
+        if self.config.empty_commits == EmptyCommitPolicy::ReturnHead
+            && !self.has_uncommitted_changes()
+            && other_change_sets.iter().all(ChangeSet::is_empty)
+        {
+            if let Ok(ref_data) = &current {
+                return Ok(ref_data.snapshot.clone());
+            }
+        }
+        let result = match current {
             Err(RefError::RefNotFound(_)) => {
+                let parent = self.snapshot_id.clone();
                 self.do_distributed_commit(
+                    &parent,
                     update_branch_name,
                     other_change_sets,
                     message,
@@ -715,48 +2589,124 @@ impl Repository {
                 .await
             }
             Err(err) => Err(err.into()),
+            Ok(ref_data) if ref_data.snapshot == self.snapshot_id => {
+                let parent = self.snapshot_id.clone();
+                self.do_distributed_commit(
+                    &parent,
+                    update_branch_name,
+                    other_change_sets,
+                    message,
+                    properties,
+                )
+                .await
+            }
             Ok(ref_data) => {
                 // we can detect there will be a conflict before generating the new snapshot
-                if ref_data.snapshot != self.snapshot_id {
-                    Err(RepositoryError::Conflict {
-                        expected_parent: Some(self.snapshot_id.clone()),
-                        actual_parent: Some(ref_data.snapshot.clone()),
-                    })
-                } else {
+                let rebased = self
+                    .can_commit_regions_on(&ref_data.snapshot, &other_change_sets)
+                    .await?;
+                if self.write_regions.is_some() {
+                    self.commit_telemetry.record_rebase(rebased);
+                }
+                if rebased {
+                    // the branch moved, but only with writes to other regions
                     self.do_distributed_commit(
+                        &ref_data.snapshot,
                         update_branch_name,
                         other_change_sets,
                         message,
                         properties,
                     )
                     .await
+                } else {
+                    Err(RepositoryError::Conflict {
+                        expected_parent: Some(self.snapshot_id.clone()),
+                        actual_parent: Some(ref_data.snapshot.clone()),
+                    })
                 }
             }
+        };
+        match &result {
+            Ok(snapshot) => {
+                self.commit_telemetry.record_commit();
+                self.spawn_snapshot_processors(snapshot);
+                #[cfg(feature = "notifications")]
+                self.spawn_commit_notification(update_branch_name, snapshot);
+            }
+            Err(RepositoryError::Conflict { .. }) => {
+                self.commit_telemetry.record_conflict()
+            }
+            Err(_) => {}
+        }
+        result
+    }
+
+    fn spawn_snapshot_processors(&self, snapshot: &SnapshotId) {
+        if self.snapshot_processors.is_empty() {
+            return;
         }
+        let storage = Arc::clone(&self.storage);
+        let snapshot = snapshot.clone();
+        let processors = self.snapshot_processors.clone();
+        self.runtime.spawn(Box::pin(async move {
+            // best effort, missing artifacts can be computed again with process_snapshot
+            let _ = process_snapshot(storage, &snapshot, &processors).await;
+        }));
+    }
+
+    #[cfg(feature = "notifications")]
+    fn spawn_commit_notification(&self, branch: &str, snapshot: &SnapshotId) {
+        let (Some(config), Some(notifier)) =
+            (self.config.notifications.clone(), self.notifier.clone())
+        else {
+            return;
+        };
+        let storage = Arc::clone(&self.storage);
+        let branch = branch.to_string();
+        let snapshot = snapshot.clone();
+        self.runtime.spawn(Box::pin(async move {
+            let Ok(snapshot) = storage.fetch_snapshot(&snapshot).await else { return };
+            let event = crate::notify::CommitEvent::new(
+                &config.repository,
+                &branch,
+                &snapshot.metadata,
+            );
+            // best effort, consumers can't rely on receiving every event
+            let _ = notifier.publish(&event).await;
+        }));
     }
 
     async fn do_distributed_commit<I: IntoIterator<Item = ChangeSet>>(
         &mut self,
+        parent_snapshot: &SnapshotId,
         update_branch_name: &str,
         other_change_sets: I,
         message: &str,
         properties: Option<SnapshotProperties>,
     ) -> RepositoryResult<SnapshotId> {
-        let parent_snapshot = self.snapshot_id.clone();
         let properties = properties.unwrap_or_default();
-        let new_snapshot =
-            self.distributed_flush(other_change_sets, message, properties).await?;
+        self.check_commit_rules(update_branch_name, &properties)?;
+        let new_snapshot = self
+            .distributed_flush_on(parent_snapshot, other_change_sets, message, properties)
+            .await?;
 
         match update_branch(
             self.storage.as_ref(),
             update_branch_name,
             new_snapshot.clone(),
-            Some(&parent_snapshot),
-            self.config.unsafe_overwrite_refs,
+            Some(parent_snapshot),
+            self.overwrite_branch_refs(update_branch_name),
         )
         .await
         {
-            Ok(_) => Ok(new_snapshot),
+            Ok(version) => {
+                self.consistency_token = Some(ConsistencyToken {
+                    branch: update_branch_name.to_string(),
+                    version,
+                    snapshot: new_snapshot.clone(),
+                });
+                Ok(new_snapshot)
+            }
             Err(RefError::Conflict { expected_parent, actual_parent }) => {
                 Err(RepositoryError::Conflict { expected_parent, actual_parent })
             }
@@ -764,8 +2714,201 @@ impl Repository {
         }
     }
 
+    /// Fail if committing with `properties` breaks a protection rule of `branch`
+    fn check_commit_rules(
+        &self,
+        branch: &str,
+        properties: &SnapshotProperties,
+    ) -> RepositoryResult<()> {
+        for rule in protection::rules_for(&self.config.branch_protection, branch) {
+            rule.check_commit(properties).map_err(|violation| {
+                RepositoryError::BranchProtected { branch: branch.to_string(), violation }
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Flush the changes for a commit to `branch`, without moving the branch, see
+    /// [`crate::transaction`]
+    pub(crate) async fn prepare_commit(
+        &mut self,
+        branch: &str,
+        message: &str,
+        properties: Option<SnapshotProperties>,
+    ) -> RepositoryResult<PreparedCommit> {
+        let properties = properties.unwrap_or_default();
+        self.check_commit_rules(branch, &properties)?;
+        let tip = fetch_branch_tip(self.storage.as_ref(), branch).await?.snapshot;
+        if tip != self.snapshot_id {
+            return Err(RepositoryError::Conflict {
+                expected_parent: Some(self.snapshot_id.clone()),
+                actual_parent: Some(tip),
+            });
+        }
+        let change_set = self.change_set.clone();
+        let snapshot = self.flush(message, properties).await?;
+        Ok(PreparedCommit {
+            branch: branch.to_string(),
+            parent: tip,
+            snapshot,
+            change_set,
+        })
+    }
+
+    /// Move the branch of a prepared commit to its snapshot
+    pub(crate) async fn finalize_commit(
+        &mut self,
+        prepared: &PreparedCommit,
+    ) -> RepositoryResult<BranchVersion> {
+        let version = update_branch(
+            self.storage.as_ref(),
+            &prepared.branch,
+            prepared.snapshot.clone(),
+            Some(&prepared.parent),
+            self.overwrite_branch_refs(&prepared.branch),
+        )
+        .await
+        .map_err(|err| match err {
+            RefError::Conflict { expected_parent, actual_parent } => {
+                RepositoryError::Conflict { expected_parent, actual_parent }
+            }
+            err => err.into(),
+        })?;
+        self.consistency_token = Some(ConsistencyToken {
+            branch: prepared.branch.clone(),
+            version: version.clone(),
+            snapshot: prepared.snapshot.clone(),
+        });
+        Ok(version)
+    }
+
+    /// Undo [`Repository::prepare_commit`], restoring the snapshot and the uncommitted
+    /// changes. Deleting the prepared snapshot is best effort, if it fails the snapshot
+    /// is left unreachable, for garbage collection.
+    pub(crate) async fn discard_commit(&mut self, prepared: PreparedCommit) {
+        self.snapshot_id = prepared.parent;
+        self.change_set = prepared.change_set;
+        let _ = self.storage.delete_snapshot(&prepared.snapshot).await;
+    }
+
+    /// Undo [`Repository::finalize_commit`], moving the branch back, and then
+    /// [`Repository::discard_commit`]. Fails with a conflict if the branch moved after
+    /// it was finalized, the commit stays then.
+    pub(crate) async fn rollback_commit(
+        &mut self,
+        prepared: PreparedCommit,
+    ) -> RepositoryResult<()> {
+        update_branch(
+            self.storage.as_ref(),
+            &prepared.branch,
+            prepared.parent.clone(),
+            Some(&prepared.snapshot),
+            false,
+        )
+        .await
+        .map_err(|err| match err {
+            RefError::Conflict { expected_parent, actual_parent } => {
+                RepositoryError::Conflict { expected_parent, actual_parent }
+            }
+            err => err.into(),
+        })?;
+        self.consistency_token = None;
+        self.discard_commit(prepared).await;
+        Ok(())
+    }
+
+    /// Split the session into `n` writers, that can be moved to other tasks and write
+    /// concurrently with `self`, without locking.
+    ///
+    /// Writers read the snapshot of `self` with its changes so far, the changes they
+    /// make are added to `self` by [`Repository::merge_writers`], before committing.
+    /// Writers must change different chunks and nodes, the changes of later writers
+    /// replace the earlier ones. Deleting nodes created before the split, and
+    /// committing, must be done from `self`.
+    pub async fn split_writers(&mut self, n: usize) -> RepositoryResult<Vec<Repository>> {
+        let last = match self.last_node_id {
+            Some(last) => last,
+            None => self.compute_last_node_id().await?,
+        };
+        // every writer, and `self`, reserves a different residue of the ids after `last`
+        let step = NodeId::try_from(n.saturating_add(1)).map_err(|_| {
+            RepositoryError::InvalidWriter {
+                message: format!("cannot split into {n} writers"),
+            }
+        })?;
+        self.last_node_id = Some(last);
+        self.node_id_step = step;
+        let base = Arc::new(self.change_set.clone());
+        Ok((1..step)
+            .map(|offset| Repository {
+                config: self.config.clone(),
+                storage: Arc::clone(&self.storage),
+                salvage: self.salvage.clone(),
+                snapshot_id: self.snapshot_id.clone(),
+                last_node_id: Some(last + offset),
+                node_id_step: step,
+                change_set: base.as_ref().clone(),
+                split_base: Some(Arc::clone(&base)),
+                virtual_resolver: Arc::clone(&self.virtual_resolver),
+                progress: Arc::clone(&self.progress),
+                chunk_uploads: Arc::clone(&self.chunk_uploads),
+                // handed over to `self` when merged
+                staged: Arc::new(Mutex::new(StagedUploads::default())),
+                write_regions: self.write_regions.clone(),
+                memory_budget: self.memory_budget.clone(),
+                clock: Arc::clone(&self.clock),
+                runtime: Arc::clone(&self.runtime),
+                consistency_token: None,
+                commit_telemetry: Arc::clone(&self.commit_telemetry),
+                io_telemetry: Arc::clone(&self.io_telemetry),
+                verified_manifests: Arc::clone(&self.verified_manifests),
+                snapshot_processors: self.snapshot_processors.clone(),
+                access_listener: self.access_listener.clone(),
+                scratch: self.scratch.clone(),
+                scratch_chunks: Arc::clone(&self.scratch_chunks),
+                #[cfg(feature = "notifications")]
+                notifier: self.notifier.clone(),
+            })
+            .collect())
+    }
+
+    /// Add the changes of `writers`, split from `self` by [`Repository::split_writers`],
+    /// to the changes of `self`. The chunks they uploaded are handed over too.
+    pub fn merge_writers(
+        &mut self,
+        writers: impl IntoIterator<Item = Repository>,
+    ) -> RepositoryResult<()> {
+        let writers: Vec<_> = writers.into_iter().collect();
+        for writer in writers.iter() {
+            if writer.split_base.is_none() || writer.snapshot_id != self.snapshot_id {
+                return Err(RepositoryError::InvalidWriter {
+                    message: format!(
+                        "the writer was not split from a session on snapshot {}",
+                        self.snapshot_id
+                    ),
+                });
+            }
+        }
+        for writer in writers {
+            if let Some(base) = &writer.split_base {
+                self.change_set.merge(writer.change_set.changes_since(base));
+            }
+            self.last_node_id = self.last_node_id.max(writer.last_node_id);
+            let staged = mem::take(&mut *lock_staged(&writer.staged));
+            lock_staged(&self.staged).append(staged);
+        }
+        self.node_id_step = 1;
+        Ok(())
+    }
+
+    /// Serialize the uncommitted changes, to be merged by a different repository.
+    ///
+    /// The chunks uploaded so far are handed over with the changes, they won't be deleted
+    /// by [`Repository::abort`] or on drop.
     pub fn change_set_bytes(&self) -> RepositoryResult<Vec<u8>> {
-        self.change_set.export_to_bytes()
+        let bytes = self.change_set.export_to_bytes()?;
+        lock_staged(&self.staged).chunks.clear();
+        Ok(bytes)
     }
 
     pub async fn new_branch(&self, branch_name: &str) -> RepositoryResult<BranchVersion> {
@@ -789,6 +2932,47 @@ impl Repository {
         Ok(version)
     }
 
+    /// Move `branch` to `snapshot_id`, that doesn't need to descend from its tip, if
+    /// the protection rules of the branch allow it
+    pub async fn reset_branch(
+        &self,
+        branch: &str,
+        snapshot_id: &SnapshotId,
+    ) -> RepositoryResult<BranchVersion> {
+        let tip = fetch_branch_tip(self.storage.as_ref(), branch).await?.snapshot;
+        protection::check_reset(
+            self.storage.as_ref(),
+            &self.config.branch_protection,
+            branch,
+            &tip,
+            snapshot_id,
+        )
+        .await?;
+        match update_branch(
+            self.storage.as_ref(),
+            branch,
+            snapshot_id.clone(),
+            Some(&tip),
+            self.overwrite_branch_refs(branch),
+        )
+        .await
+        {
+            Ok(version) => Ok(version),
+            Err(RefError::Conflict { expected_parent, actual_parent }) => {
+                Err(RepositoryError::Conflict { expected_parent, actual_parent })
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Branch refs are written unconditionally only with `unsafe_overwrite_refs`, and
+    /// if no protection rule forbids it
+    fn overwrite_branch_refs(&self, branch: &str) -> bool {
+        self.config.unsafe_overwrite_refs
+            && !protection::rules_for(&self.config.branch_protection, branch)
+                .any(|rule| rule.forbid_force_updates)
+    }
+
     pub async fn tag(
         &self,
         tag_name: &str,
@@ -798,32 +2982,351 @@ impl Repository {
             self.storage.as_ref(),
             tag_name,
             snapshot_id.clone(),
-            self.config.unsafe_overwrite_refs,
+            self.config.unsafe_overwrite_refs && !self.config.protected_tags,
         )
         .await?;
         Ok(())
     }
+
+    /// Delete the tag `tag_name`, forbidden if [`RepositoryConfig::protected_tags`] is
+    /// set. The snapshot of the tag is kept until garbage collection.
+    pub async fn delete_tag(&self, tag_name: &str) -> RepositoryResult<()> {
+        if self.config.protected_tags {
+            return Err(RepositoryError::ProtectedTag(tag_name.to_string()));
+        }
+        delete_tag(self.storage.as_ref(), tag_name).await?;
+        Ok(())
+    }
+
+    /// Compare two snapshots by content.
+    ///
+    /// Snapshots are equivalent if they have the same nodes, with the same metadata,
+    /// user attributes and chunk payloads, no matter how chunks are split in manifests
+    /// or which node ids are used. Commit metadata and history are not compared.
+    pub async fn snapshots_equivalent(
+        &self,
+        a: &SnapshotId,
+        b: &SnapshotId,
+    ) -> RepositoryResult<bool> {
+        if a == b {
+            return Ok(true);
+        }
+        let storage = self.storage.as_ref();
+        let (a, b) =
+            futures::try_join!(storage.fetch_snapshot(a), storage.fetch_snapshot(b))?;
+        if a.len() != b.len() {
+            return Ok(false);
+        }
+
+        // nodes are iterated in path order
+        for (node_a, node_b) in a.iter().zip(b.iter()) {
+            if node_a.path != node_b.path
+                || node_a.user_attributes != node_b.user_attributes
+            {
+                return Ok(false);
+            }
+            match (&node_a.node_data, &node_b.node_data) {
+                (NodeData::Group, NodeData::Group) => {}
+                (
+                    NodeData::Array(meta_a, manifests_a),
+                    NodeData::Array(meta_b, manifests_b),
+                ) => {
+                    if meta_a != meta_b {
+                        return Ok(false);
+                    }
+                    let (chunks_a, chunks_b) = futures::try_join!(
+                        node_chunk_payloads(storage, node_a.id, manifests_a),
+                        node_chunk_payloads(storage, node_b.id, manifests_b),
+                    )?;
+                    if chunks_a != chunks_b {
+                        return Ok(false);
+                    }
+                }
+                (
+                    NodeData::Concatenated(meta_a, concatenation_a),
+                    NodeData::Concatenated(meta_b, concatenation_b),
+                ) => {
+                    if meta_a != meta_b || concatenation_a != concatenation_b {
+                        return Ok(false);
+                    }
+                }
+                _ => return Ok(false),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Write the history of all branches and tags in Graphviz DOT format.
+    ///
+    /// Snapshots are labeled with their commit messages, edges point from each snapshot
+    /// to its parent. The output is sorted, so it's stable for a given repository.
+    pub async fn export_history_dot<W: io::Write>(
+        &self,
+        writer: &mut W,
+    ) -> RepositoryResult<()> {
+        let storage = self.storage.as_ref();
+        let mut refs = list_refs(storage).await?;
+        refs.sort_by_key(|r| match r {
+            Ref::Branch(name) => (0, name.clone()),
+            Ref::Tag(name) => (1, name.clone()),
+        });
+
+        let mut snapshots = BTreeMap::new();
+        let mut edges = BTreeSet::new();
+        let mut ref_edges = Vec::with_capacity(refs.len());
+        for r in refs {
+            let (node, shape, tip) = match &r {
+                Ref::Branch(name) => (
+                    format!("branch:{name}"),
+                    "ellipse",
+                    fetch_branch_tip(storage, name).await?,
+                ),
+                Ref::Tag(name) => {
+                    (format!("tag:{name}"), "note", fetch_tag(storage, name).await?)
+                }
+            };
+            ref_edges.push((node, shape, tip.snapshot.to_string()));
+            if snapshots.contains_key(&tip.snapshot.to_string()) {
+                continue;
+            }
+
+            let snapshot = storage.fetch_snapshot(&tip.snapshot).await?;
+            let history: Vec<_> = iter::once(&snapshot.metadata)
+                .chain(snapshot.short_term_history.iter())
+                .collect();
+            for (child, parent) in history.iter().tuple_windows() {
+                edges.insert((child.id.to_string(), parent.id.to_string()));
+            }
+            for meta in history {
+                snapshots.insert(meta.id.to_string(), meta.message.clone());
+            }
+        }
+
+        writeln!(writer, "digraph history {{")?;
+        writeln!(writer, "  node [shape=box];")?;
+        for (id, message) in snapshots.iter() {
+            writeln!(
+                writer,
+                "  \"{id}\" [label=\"{}\"];",
+                dot_escape(&format!("{id}\n{message}"))
+            )?;
+        }
+        for (child, parent) in edges.iter() {
+            writeln!(writer, "  \"{child}\" -> \"{parent}\";")?;
+        }
+        for (node, shape, snapshot) in ref_edges {
+            let label = node.split_once(':').map_or(node.as_str(), |(_, name)| name);
+            writeln!(
+                writer,
+                "  \"{}\" [shape={shape}, label=\"{}\"];",
+                dot_escape(&node),
+                dot_escape(label)
+            )?;
+            writeln!(
+                writer,
+                "  \"{}\" -> \"{snapshot}\" [style=dashed];",
+                dot_escape(&node)
+            )?;
+        }
+        writeln!(writer, "}}")?;
+        Ok(())
+    }
+}
+
+fn dot_escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
 }
 
 impl From<Repository> for ChangeSet {
-    fn from(val: Repository) -> Self {
-        val.change_set
+    /// The chunks uploaded by the repository are handed over with the changes
+    fn from(mut val: Repository) -> Self {
+        lock_staged(&val.staged).chunks.clear();
+        mem::take(&mut val.change_set)
+    }
+}
+
+impl Drop for Repository {
+    fn drop(&mut self) {
+        if !self.config.cleanup_on_drop {
+            return;
+        }
+        let mut staged = mem::take(&mut *lock_staged(&self.staged));
+        if staged.is_empty() {
+            return;
+        }
+        let storage = Arc::clone(&self.storage);
+        self.runtime.spawn(Box::pin(async move {
+            // best effort, whatever is left will be collected by garbage collection
+            let _ = staged.delete(storage.as_ref()).await;
+        }));
     }
 }
 
 async fn new_materialized_chunk(
-    storage: &(dyn Storage + Send + Sync),
+    storage: &dyn Storage,
+    new_id: ChunkId,
     data: Bytes,
 ) -> RepositoryResult<ChunkPayload> {
-    let new_id = ObjectId::random();
     storage.write_chunk(new_id.clone(), data.clone()).await?;
     Ok(ChunkPayload::Ref(ChunkRef { id: new_id, offset: 0, length: data.len() as u64 }))
 }
 
+/// Fetch a materialized chunk, checking it against `checksum` if any
+async fn fetch_verified_chunk(
+    storage: &dyn Storage,
+    id: &ChunkId,
+    byte_range: &ByteRange,
+    chunk_length: u64,
+    range_length: u64,
+    checksum: Option<ChunkChecksum>,
+) -> RepositoryResult<(Bytes, CacheStatus)> {
+    let Some(expected) = checksum else {
+        if range_length == 0 {
+            return Ok((Bytes::new(), CacheStatus::Uncached));
+        }
+        let (bytes, cache) = storage.fetch_chunk_with_status(id, byte_range).await?;
+        return Ok((check_length(bytes, range_length)?, cache));
+    };
+    // the checksum covers the whole chunk
+    let (bytes, cache) = storage.fetch_chunk_with_status(id, &ByteRange::ALL).await?;
+    let bytes = check_length(bytes, chunk_length)?;
+    let found = ChunkChecksum::of(&bytes);
+    if found != expected {
+        return Err(VerificationError::ChunkChecksum {
+            id: id.clone(),
+            expected: expected.0,
+            found: found.0,
+        }
+        .into());
+    }
+    Ok((byte_range.slice(bytes)?, cache))
+}
+
+/// Fetch a virtual chunk, checking the etag of its file against `etag` if any
+async fn fetch_verified_virtual_chunk(
+    resolver: &(dyn VirtualChunkResolver + Send + Sync),
+    location: &VirtualChunkLocation,
+    byte_range: &ByteRange,
+    etag: Option<VirtualChunkEtag>,
+) -> RepositoryResult<(Bytes, CacheStatus)> {
+    let length = byte_range.length().unwrap_or_default();
+    if length == 0 {
+        return Ok((Bytes::new(), CacheStatus::Uncached));
+    }
+    // the etag comes with the data, checking it doesn't cost another request
+    let FetchedRange { bytes, etag: found, cache } =
+        resolver.fetch_range(location, byte_range).await?;
+    if let Some(VirtualChunkEtag(expected)) = etag {
+        if found.as_ref() != Some(&expected) {
+            let VirtualChunkLocation::Absolute(location) = location;
+            return Err(VerificationError::VirtualEtag {
+                location: location.clone(),
+                expected,
+                found,
+            }
+            .into());
+        }
+    }
+    Ok((check_length(bytes, length)?, cache))
+}
+
+/// Tell `listener`, if any, about a successful chunk read
+fn report_access(
+    listener: Option<Arc<dyn ChunkAccessListener>>,
+    path: Path,
+    coords: ChunkIndices,
+    bytes: &Bytes,
+    source: ChunkSource,
+    cache: CacheStatus,
+) {
+    if let Some(listener) = listener {
+        listener.on_chunk_read(&ChunkAccess {
+            path,
+            coords,
+            bytes: bytes.len() as u64,
+            source,
+            cache,
+        });
+    }
+}
+
+/// Fail with [`RepositoryError::ChunkLengthMismatch`] if storage returned more or less
+/// than the manifest says
+fn check_length(bytes: Bytes, expected: u64) -> RepositoryResult<Bytes> {
+    let found = bytes.len() as u64;
+    if found != expected {
+        return Err(RepositoryError::ChunkLengthMismatch { expected, found });
+    }
+    Ok(bytes)
+}
+
+async fn node_chunk_payloads(
+    storage: &dyn Storage,
+    node: NodeId,
+    manifests: &[ManifestRef],
+) -> RepositoryResult<HashMap<ChunkIndices, ChunkPayload>> {
+    let mut res = HashMap::new();
+    for manifest_ref in manifests {
+        let manifest = storage.fetch_manifests(&manifest_ref.object_id).await?;
+        res.extend(manifest.iter(&node));
+    }
+    Ok(res)
+}
+
+/// The axis and first chunk of `region` if it covers the chunk grid of the array from
+/// that chunk to the end of the axis, and from end to end along the other axes
+fn trailing_slab(
+    metadata: &ZarrArrayMetadata,
+    region: &ChunkRegion,
+) -> Option<(usize, u64)> {
+    let grid = metadata.chunk_grid_shape();
+    if region.0.is_empty()
+        || region.0.len() != grid.len()
+        || region.0.iter().zip(grid.iter()).any(|(range, count)| range.end < *count)
+    {
+        return None;
+    }
+    let mut partial = region.0.iter().enumerate().filter(|(_, range)| range.start > 0);
+    match (partial.next(), partial.next()) {
+        (None, _) => Some((0, 0)),
+        (Some((axis, range)), None) => Some((axis, range.start)),
+        (Some(_), Some(_)) => None,
+    }
+}
+
 fn new_inline_chunk(data: Bytes) -> ChunkPayload {
     ChunkPayload::Inline(data)
 }
 
+/// The tip of the branch of `token`, if it includes the commit and its objects are readable
+async fn visible_tip(
+    storage: &dyn Storage,
+    token: &ConsistencyToken,
+) -> RepositoryResult<Option<SnapshotId>> {
+    // objects not visible yet are missing, or fail with transient errors
+    let not_visible =
+        |kind: ErrorKind| kind == ErrorKind::NotFound || kind.is_retryable();
+    let tip = match fetch_branch_tip_version(storage, &token.branch).await {
+        Ok((version, data)) if version >= token.version => data.snapshot,
+        Ok(_) => return Ok(None),
+        Err(err) if not_visible(err.kind()) => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let snapshot = match storage.fetch_snapshot(&token.snapshot).await {
+        Ok(snapshot) => snapshot,
+        Err(err) if not_visible(err.kind()) => return Ok(None),
+        Err(err) => return Err(RepositoryError::from(err).with_snapshot(&token.snapshot)),
+    };
+    for manifest in snapshot.manifest_files.iter() {
+        match storage.fetch_manifests(&manifest.id).await {
+            Ok(_) => {}
+            Err(err) if not_visible(err.kind()) => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(Some(tip))
+}
+
 pub async fn get_chunk(
     reader: Option<Pin<Box<dyn Future<Output = RepositoryResult<Bytes>> + Send>>>,
 ) -> RepositoryResult<Option<Bytes>> {
@@ -834,19 +3337,15 @@ pub async fn get_chunk(
 }
 
 async fn updated_existing_nodes<'a>(
-    storage: &(dyn Storage + Send + Sync),
+    storage: &dyn Storage,
     change_set: &'a ChangeSet,
     parent_id: &SnapshotId,
-    manifest_id: Option<&'a ManifestId>,
+    manifest_refs: Option<&'a HashMap<NodeId, Vec<ManifestRef>>>,
 ) -> RepositoryResult<impl Iterator<Item = NodeSnapshot> + 'a> {
-    let manifest_refs = manifest_id.map(|mid| {
-        vec![ManifestRef { object_id: mid.clone(), extents: ManifestExtents(vec![]) }]
-    });
     let updated_nodes =
         storage.fetch_snapshot(parent_id).await?.iter_arc().filter_map(move |node| {
             let new_manifests = if node.node_type() == NodeType::Array {
-                //FIXME: it could be none for empty arrays
-                manifest_refs.clone()
+                manifest_refs.and_then(|refs| refs.get(&node.id).cloned())
             } else {
                 None
             };
@@ -857,21 +3356,22 @@ async fn updated_existing_nodes<'a>(
 }
 
 async fn updated_nodes<'a>(
-    storage: &(dyn Storage + Send + Sync),
+    storage: &dyn Storage,
     change_set: &'a ChangeSet,
     parent_id: &SnapshotId,
-    manifest_id: Option<&'a ManifestId>,
+    manifest_refs: Option<&'a HashMap<NodeId, Vec<ManifestRef>>>,
 ) -> RepositoryResult<impl Iterator<Item = NodeSnapshot> + 'a> {
-    Ok(updated_existing_nodes(storage, change_set, parent_id, manifest_id)
+    Ok(updated_existing_nodes(storage, change_set, parent_id, manifest_refs)
         .await?
-        .chain(change_set.new_nodes_iterator(manifest_id)))
+        .chain(change_set.new_nodes_iterator(manifest_refs)))
 }
 
-async fn get_node<'a>(
-    storage: &(dyn Storage + Send + Sync),
-    change_set: &'a ChangeSet,
+async fn get_node(
+    storage: &dyn Storage,
+    change_set: &ChangeSet,
     snapshot_id: &SnapshotId,
     path: &Path,
+    resolve_attributes: bool,
 ) -> RepositoryResult<NodeSnapshot> {
     // We need to look for nodes in self.change_set and the snapshot file
     if change_set.is_deleted(path) {
@@ -883,7 +3383,14 @@ async fn get_node<'a>(
     match change_set.get_new_node(path) {
         Some(node) => Ok(node),
         None => {
-            let node = get_existing_node(storage, change_set, snapshot_id, path).await?;
+            let node = get_existing_node(
+                storage,
+                change_set,
+                snapshot_id,
+                path,
+                resolve_attributes,
+            )
+            .await?;
             if change_set.is_deleted(&node.path) {
                 Err(RepositoryError::NodeNotFound {
                     path: path.clone(),
@@ -896,14 +3403,18 @@ async fn get_node<'a>(
     }
 }
 
-async fn get_existing_node<'a>(
-    storage: &(dyn Storage + Send + Sync),
-    change_set: &'a ChangeSet,
+async fn get_existing_node(
+    storage: &dyn Storage,
+    change_set: &ChangeSet,
     snapshot_id: &SnapshotId,
     path: &Path,
+    resolve_attributes: bool,
 ) -> RepositoryResult<NodeSnapshot> {
     // An existing node is one that is present in a Snapshot file on storage
-    let snapshot = storage.fetch_snapshot(snapshot_id).await?;
+    let snapshot = storage
+        .fetch_snapshot(snapshot_id)
+        .await
+        .map_err(|err| RepositoryError::from(err).with_snapshot(snapshot_id))?;
 
     let node = snapshot.get_node(path).map_err(|err| match err {
         // A missing node here is not really a format error, so we need to
@@ -918,10 +3429,12 @@ async fn get_existing_node<'a>(
         .get_user_attributes(node.id)
         .cloned()
         .map(|a| a.map(UserAttributesSnapshot::Inline));
-    let res = NodeSnapshot {
-        user_attributes: session_atts.unwrap_or_else(|| node.user_attributes.clone()),
-        ..node.clone()
+    let user_attributes = match session_atts {
+        Some(atts) => atts,
+        None if !resolve_attributes => node.user_attributes.clone(),
+        None => resolve_user_attributes(storage, node.user_attributes.clone()).await?,
     };
+    let res = NodeSnapshot { user_attributes, ..node.clone() };
     if let Some(session_meta) = change_set.get_updated_zarr_metadata(node.id).cloned() {
         if let NodeData::Array(_, manifests) = res.node_data {
             Ok(NodeSnapshot {
@@ -936,64 +3449,502 @@ async fn get_existing_node<'a>(
     }
 }
 
+/// Fail if the changes to the arrays in `constraints` violate them
+///
+/// The metadata is checked only for new arrays and arrays with updated metadata.
+async fn check_constraints(
+    storage: &dyn Storage,
+    change_set: &ChangeSet,
+    parent_id: &SnapshotId,
+    constraints: &BTreeMap<Path, ArrayConstraints>,
+) -> RepositoryResult<()> {
+    for (path, constraints) in constraints {
+        let node = match get_node(storage, change_set, parent_id, path, true).await {
+            Ok(node) => node,
+            Err(RepositoryError::NodeNotFound { .. }) => continue,
+            Err(err) => return Err(err),
+        };
+        let NodeData::Array(metadata, _) = &node.node_data else { continue };
+        let violation = |violation| RepositoryError::ConstraintViolation {
+            path: path.clone(),
+            violation,
+        };
+        if change_set.get_array(path).is_some()
+            || change_set.get_updated_zarr_metadata(node.id).is_some()
+        {
+            constraints.check_metadata(metadata).map_err(violation)?;
+        }
+        for (coords, payload) in change_set.array_chunks_iterator(node.id, path) {
+            constraints.check_chunk(coords, payload.as_ref()).map_err(violation)?;
+        }
+    }
+    Ok(())
+}
+
+/// The attributes a reference to an attributes table points to
+pub(crate) async fn resolve_user_attributes(
+    storage: &dyn Storage,
+    atts: Option<UserAttributesSnapshot>,
+) -> RepositoryResult<Option<UserAttributesSnapshot>> {
+    match atts {
+        Some(UserAttributesSnapshot::Ref(UserAttributesRef { object_id, location })) => {
+            let table = storage.fetch_attributes(&object_id).await?;
+            let atts = table.get(location).cloned().ok_or(
+                IcechunkFormatError::AttributesNotFound { id: object_id, location },
+            )?;
+            Ok(Some(UserAttributesSnapshot::Inline(atts)))
+        }
+        atts => Ok(atts),
+    }
+}
+
+/// Move the inline attributes of `nodes` to a new attributes table
+///
+/// The table is content addressed, so it's only written if `parent` doesn't reference
+/// it already. Returns the table, unless no node had inline attributes.
+pub(crate) async fn write_attributes_table(
+    storage: &dyn Storage,
+    parent: &Snapshot,
+    nodes: &mut [NodeSnapshot],
+) -> RepositoryResult<Option<AttributeFileInfo>> {
+    let mut builder = AttributesTableBuilder::default();
+    let locations: Vec<_> = nodes
+        .iter()
+        .map(|node| match &node.user_attributes {
+            Some(UserAttributesSnapshot::Inline(atts)) => Some(builder.add(atts.clone())),
+            _ => None,
+        })
+        .collect();
+    if builder.is_empty() {
+        return Ok(None);
+    }
+
+    let (table, to_ref) = builder.build();
+    for (node, location) in nodes.iter_mut().zip(locations) {
+        if let Some(location) = location {
+            node.user_attributes = Some(UserAttributesSnapshot::Ref(to_ref(location)));
+        }
+    }
+    let info = AttributeFileInfo {
+        id: table.id(),
+        format_version: table.icechunk_attributes_format_version,
+    };
+    if !parent.attribute_files.iter().any(|file| file.id == info.id) {
+        storage.write_attributes(info.id.clone(), Arc::new(table)).await?;
+    }
+    Ok(Some(info))
+}
+
+/// The attribute tables referenced by the nodes of `snapshot`
+fn referenced_attribute_files(
+    snapshot: &Snapshot,
+    known: impl IntoIterator<Item = AttributeFileInfo>,
+) -> Vec<AttributeFileInfo> {
+    let referenced: HashSet<&AttributesId> = snapshot
+        .iter()
+        .filter_map(|node| match &node.user_attributes {
+            Some(UserAttributesSnapshot::Ref(atts_ref)) => Some(&atts_ref.object_id),
+            _ => None,
+        })
+        .collect();
+    known
+        .into_iter()
+        .filter(|file| referenced.contains(&file.id))
+        .unique_by(|file| file.id.clone())
+        .collect()
+}
+
+/// Fail if a chunk of the merged `change_set` was written or deleted outside the declared
+/// `regions`, including chunks set before the regions were declared
+fn check_write_regions(
+    change_set: &ChangeSet,
+    parent: &Snapshot,
+    regions: &WriteRegions,
+) -> RepositoryResult<()> {
+    let paths: HashMap<NodeId, &Path> =
+        parent.iter().map(|node| (node.id, &node.path)).collect();
+    let existing = change_set
+        .chunk_changes_iterator()
+        .chain(change_set.tombstones_iterator())
+        .filter_map(|(node, coord)| {
+            paths.get(&node).map(|path| ((*path).clone(), coord.clone()))
+        });
+    let new =
+        change_set.new_arrays_chunk_iterator().map(|(path, info)| (path, info.coord));
+    for (path, coord) in existing.chain(new) {
+        let inside = regions
+            .get(&path)
+            .is_some_and(|regions| regions.iter().any(|r| r.contains(&coord)));
+        if !inside {
+            return Err(RepositoryError::OutsideWriteRegions { path, coords: coord });
+        }
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn distributed_flush<I: IntoIterator<Item = ChangeSet>>(
-    storage: &(dyn Storage + Send + Sync),
+    storage: &dyn Storage,
     change_sets: I,
     parent_id: &SnapshotId,
     message: &str,
     properties: SnapshotProperties,
+    allow_empty: bool,
+    write_regions: Option<&WriteRegions>,
+    split_policy: &ManifestSplitPolicy,
+    constraints: &BTreeMap<Path, ArrayConstraints>,
+    clock: &dyn Clock,
+    progress: Arc<dyn ProgressListener>,
+    staged: &Mutex<StagedUploads>,
 ) -> RepositoryResult<SnapshotId> {
     let mut change_set = ChangeSet::default();
     change_set.merge_many(change_sets);
     if change_set.is_empty() {
-        return Err(RepositoryError::NoChangesToCommit);
+        if !allow_empty {
+            return Err(RepositoryError::NoChangesToCommit);
+        }
+        return write_empty_snapshot(
+            storage, parent_id, message, properties, clock, staged,
+        )
+        .await;
+    }
+    check_constraints(storage, &change_set, parent_id, constraints).await?;
+    let old_snapshot = storage.fetch_snapshot(parent_id).await?;
+    if let Some(regions) = write_regions {
+        check_write_regions(&change_set, &old_snapshot, regions)?;
     }
 
     let chunks = all_chunks(storage, &change_set, parent_id)
         .await?
         .map_ok(|(_path, chunk_info)| chunk_info);
 
-    let new_manifest = Arc::new(Manifest::from_stream(chunks).await?);
-    let new_manifest_id = if new_manifest.len() > 0 {
-        let id = ObjectId::random();
-        storage.write_manifests(id.clone(), Arc::clone(&new_manifest)).await?;
-        Some(id)
-    } else {
-        None
-    };
-
-    let all_nodes =
-        updated_nodes(storage, &change_set, parent_id, new_manifest_id.as_ref()).await?;
-
-    let old_snapshot = storage.fetch_snapshot(parent_id).await?;
-    let mut new_snapshot = Snapshot::from_iter(
+    let new_manifest = Manifest::from_stream(chunks).await?;
+    let old_manifests = snapshot_manifest_ids(&old_snapshot);
+    let extras = chunk_extras(storage, &change_set, &old_manifests).await?;
+    let mut summary =
+        manifest_summary(storage, &change_set, &old_manifests, &new_manifest).await?;
+    let new_chunks: Vec<ChunkId> = written_chunks(&change_set, &new_manifest)
+        .filter_map(|payload| match payload {
+            ChunkPayload::Ref(ChunkRef { id, .. }) => Some(id.clone()),
+            _ => None,
+        })
+        .unique()
+        .collect();
+    let new_manifests = new_manifest.with_extras(extras).split(split_policy);
+    // the manifests plus the snapshot
+    let total_files = new_manifests.len() as u64 + 1;
+    let tracker =
+        ProgressTracker::new(progress, ProgressOperation::Flush, Some(total_files));
+    let mut manifest_refs: HashMap<NodeId, Vec<ManifestRef>> = HashMap::new();
+    let mut manifest_files = Vec::with_capacity(new_manifests.len());
+    let new_manifests: Vec<(ManifestId, Manifest)> = new_manifests
+        .into_iter()
+        .map(|mut new_manifest| {
+            let id = ObjectId::random();
+            // the extents still cover the tombstones, so readers find them
+            let extents: Vec<_> = new_manifest
+                .nodes()
+                .map(|node| (node, new_manifest.extents(node)))
+                .collect();
+            for (node, coord) in change_set.tombstones_iterator() {
+                new_manifest.redact_chunk(node, coord);
+            }
+            let content_hash = new_manifest.content_hash()?;
+            for (node, extents) in extents {
+                manifest_refs.entry(node).or_default().push(ManifestRef {
+                    object_id: id.clone(),
+                    extents,
+                    content_hash: Some(content_hash.clone()),
+                });
+            }
+            manifest_files.push(ManifestFileInfo {
+                id: id.clone(),
+                format_version: new_manifest.icechunk_manifest_format_version,
+            });
+            Ok::<_, RepositoryError>((id, new_manifest))
+        })
+        .collect::<RepositoryResult<_>>()?;
+
+    let (mut changed_nodes, deleted_paths) =
+        structure_changes(&old_snapshot, &change_set, &manifest_refs);
+    let new_attributes =
+        write_attributes_table(storage, &old_snapshot, &mut changed_nodes).await?;
+    let mut new_snapshot = Snapshot::from_parent(
+        parent_id,
         old_snapshot.as_ref(),
         Some(properties),
-        new_manifest_id
-            .as_ref()
-            .map(|mid| {
-                vec![ManifestFileInfo {
-                    id: mid.clone(),
-                    format_version: new_manifest.icechunk_manifest_format_version,
-                }]
-            })
-            .unwrap_or_default(),
+        manifest_files.clone(),
         vec![],
-        all_nodes,
+        changed_nodes,
+        deleted_paths,
+    );
+    new_snapshot.attribute_files = referenced_attribute_files(
+        &new_snapshot,
+        new_attributes.into_iter().chain(old_snapshot.attribute_files.iter().cloned()),
     );
     new_snapshot.metadata.message = message.to_string();
-    new_snapshot.metadata.written_at = Utc::now();
+    clock.observe(old_snapshot.metadata.written_at);
+    new_snapshot.metadata.written_at = clock.now();
+    new_snapshot.metadata.manifest_split_policy = Some(split_policy.clone());
+    let array_ids = |snapshot: &Snapshot| -> HashMap<NodeId, Path> {
+        snapshot
+            .iter()
+            .filter(|node| node.node_type() == NodeType::Array)
+            .map(|node| (node.id, node.path.clone()))
+            .collect()
+    };
+    let (old_arrays, new_arrays) = (array_ids(&old_snapshot), array_ids(&new_snapshot));
+    summary.arrays_added = new_arrays
+        .iter()
+        .filter(|(id, _)| !old_arrays.contains_key(id))
+        .map(|(_, path)| path.clone())
+        .sorted()
+        .collect();
+    summary.arrays_deleted = old_arrays
+        .iter()
+        .filter(|(id, _)| !new_arrays.contains_key(id))
+        .map(|(_, path)| path.clone())
+        .sorted()
+        .collect();
+    new_snapshot.metadata.summary = Some(summary);
+    new_snapshot.new_objects = Some(CommitObjects {
+        manifests: manifest_files.iter().map(|file| file.id.clone()).collect(),
+        chunks: new_chunks,
+    });
+    if change_set.has_only_chunk_changes() {
+        new_snapshot.metadata.write_regions = write_regions.cloned();
+    }
+    new_snapshot.metadata.dependencies = change_set.dependencies().to_vec();
+
+    // nothing is uploaded if the tables are broken, a bug in the writer must not leave
+    // objects in storage that readers can't make sense of
+    validate_tables(&new_snapshot, &new_manifests)?;
+    for (id, new_manifest) in new_manifests {
+        lock_staged(staged).manifests.push(id.clone());
+        storage.write_manifests(id, Arc::new(new_manifest)).await?;
+        tracker.advance(1, 0);
+    }
 
     let new_snapshot = Arc::new(new_snapshot);
     let new_snapshot_id = &new_snapshot.metadata.id;
+    lock_staged(staged).snapshots.push(new_snapshot_id.clone());
     storage.write_snapshot(new_snapshot_id.clone(), Arc::clone(&new_snapshot)).await?;
+    tracker.advance(1, 0);
+
+    let mut staged = lock_staged(staged);
+    staged.snapshots.retain(|id| id != new_snapshot_id);
+    staged.manifests.retain(|id| manifest_files.iter().all(|file| &file.id != id));
 
     Ok(new_snapshot_id.clone())
 }
 
+/// Fail with [`RepositoryError::InvalidTables`] if the snapshot or any of its new
+/// manifests break the invariants of the format
+fn validate_tables(
+    snapshot: &Snapshot,
+    manifests: &[(ManifestId, Manifest)],
+) -> RepositoryResult<()> {
+    let violations: Vec<TableViolation> = snapshot
+        .validate()
+        .into_iter()
+        .chain(
+            manifests
+                .iter()
+                .flat_map(|(id, manifest)| snapshot.validate_manifest(id, manifest)),
+        )
+        .collect();
+    if violations.is_empty() {
+        Ok(())
+    } else {
+        Err(RepositoryError::InvalidTables {
+            snapshot_id: snapshot.metadata.id.clone(),
+            violations,
+        })
+    }
+}
+
+/// Write a snapshot with the same nodes as `parent_id`, sharing its manifests and
+/// attribute tables
+async fn write_empty_snapshot(
+    storage: &dyn Storage,
+    parent_id: &SnapshotId,
+    message: &str,
+    properties: SnapshotProperties,
+    clock: &dyn Clock,
+    staged: &Mutex<StagedUploads>,
+) -> RepositoryResult<SnapshotId> {
+    let parent = storage.fetch_snapshot(parent_id).await?;
+    let mut new_snapshot = Snapshot::from_parent(
+        parent_id,
+        parent.as_ref(),
+        Some(properties),
+        parent.manifest_files.clone(),
+        parent.attribute_files.clone(),
+        iter::empty(),
+        iter::empty(),
+    );
+    new_snapshot.metadata.message = message.to_string();
+    clock.observe(parent.metadata.written_at);
+    new_snapshot.metadata.written_at = clock.now();
+    new_snapshot.metadata.manifest_split_policy =
+        parent.metadata.manifest_split_policy.clone();
+    new_snapshot.metadata.summary = Some(CommitSummary::default());
+    new_snapshot.new_objects = Some(CommitObjects::default());
+
+    let new_snapshot_id = new_snapshot.metadata.id.clone();
+    lock_staged(staged).snapshots.push(new_snapshot_id.clone());
+    storage.write_snapshot(new_snapshot_id.clone(), Arc::new(new_snapshot)).await?;
+    lock_staged(staged).snapshots.retain(|id| id != &new_snapshot_id);
+    Ok(new_snapshot_id)
+}
+
+/// The nodes that a flush changes in the `parent` structure, and the deleted paths
+///
+/// Arrays are always rebuilt, because every flush writes new manifests. Groups and
+/// concatenated arrays are rebuilt only if their attributes changed, the rest of the
+/// parent table is reused by [`Snapshot::from_parent`].
+fn structure_changes(
+    parent: &Snapshot,
+    change_set: &ChangeSet,
+    manifest_refs: &HashMap<NodeId, Vec<ManifestRef>>,
+) -> (Vec<NodeSnapshot>, Vec<Path>) {
+    let mut updated = Vec::new();
+    let mut deleted = Vec::new();
+    for node in parent.iter() {
+        if change_set.is_deleted(&node.path) {
+            deleted.push(node.path.clone());
+        } else if let NodeData::Array(..) = node.node_data {
+            let new_manifests = manifest_refs.get(&node.id).cloned();
+            updated.extend(change_set.update_existing_node(node.clone(), new_manifests));
+        } else if change_set.has_updated_attributes(&node.id) {
+            updated.extend(change_set.update_existing_node(node.clone(), None));
+        }
+    }
+    updated.extend(change_set.new_nodes_iterator(Some(manifest_refs)));
+    (updated, deleted)
+}
+
+/// The ids of the manifests referenced by the arrays of `snapshot`
+fn snapshot_manifest_ids(snapshot: &Snapshot) -> HashSet<ManifestId> {
+    snapshot
+        .iter()
+        .filter_map(|node| match &node.node_data {
+            NodeData::Array(_, manifests) => Some(manifests),
+            NodeData::Group | NodeData::Concatenated(..) => None,
+        })
+        .flatten()
+        .map(|manifest| manifest.object_id.clone())
+        .collect()
+}
+
+/// The chunk counts and sizes of a [`CommitSummary`], without the arrays
+async fn manifest_summary(
+    storage: &dyn Storage,
+    change_set: &ChangeSet,
+    old_manifests: &HashSet<ManifestId>,
+    new_manifest: &Manifest,
+) -> RepositoryResult<CommitSummary> {
+    let mut old_chunks = 0;
+    for id in old_manifests {
+        old_chunks += storage.fetch_manifests(id).await?.len() as i64;
+    }
+    let bytes_added = written_chunks(change_set, new_manifest)
+        .map(|payload| match payload {
+            ChunkPayload::Inline(bytes) => bytes.len() as u64,
+            ChunkPayload::Ref(ChunkRef { length, .. }) => *length,
+            ChunkPayload::Virtual(_) => 0,
+        })
+        .sum();
+    Ok(CommitSummary {
+        chunk_count_delta: new_manifest.len() as i64 - old_chunks,
+        bytes_added,
+        ..CommitSummary::default()
+    })
+}
+
+/// The payloads in `new_manifest` that were set by `change_set`
+fn written_chunks<'a>(
+    change_set: &'a ChangeSet,
+    new_manifest: &'a Manifest,
+) -> impl Iterator<Item = &'a ChunkPayload> + 'a {
+    new_manifest
+        .chunks()
+        .iter()
+        .filter(|((node, coord), payload)| {
+            matches!(change_set.get_chunk_ref(*node, coord), Some(Some(p)) if p == *payload)
+        })
+        .map(|(_, payload)| payload)
+}
+
+/// The extra data for the chunks of the new snapshot, the data in `change_set` is merged
+/// into the one in the manifests of the parent snapshot, for chunks that were not written
+/// again.
+async fn chunk_extras(
+    storage: &dyn Storage,
+    change_set: &ChangeSet,
+    manifest_ids: &HashSet<ManifestId>,
+) -> RepositoryResult<BTreeMap<(NodeId, ChunkIndices), ChunkExtra>> {
+    let mut res = BTreeMap::new();
+    for id in manifest_ids {
+        let manifest = storage.fetch_manifests(id).await?;
+        res.extend(
+            manifest
+                .extras()
+                .iter()
+                .filter(|((node, coord), _)| {
+                    change_set.get_chunk_ref(*node, coord).is_none()
+                })
+                .map(|(key, extra)| (key.clone(), extra.clone())),
+        );
+    }
+    for (node, coord, extra) in change_set.chunk_extras_iterator() {
+        let old: &mut ChunkExtra = res.entry((node, coord.clone())).or_default();
+        old.merge(extra.clone());
+    }
+    Ok(res)
+}
+
+/// Statistics for the decoded elements of a chunk, `None` for non numeric types
+fn decoded_chunk_statistics(
+    data_type: &DataType,
+    data: &[u8],
+) -> Option<ChunkStatistics> {
+    macro_rules! values {
+        ($t:ty) => {{
+            const SIZE: usize = size_of::<$t>();
+            if !data.len().is_multiple_of(SIZE) {
+                return None;
+            }
+            ChunkStatistics::from_values(data.chunks_exact(SIZE).map(|bytes| {
+                #[allow(clippy::unwrap_used)]
+                let bytes: [u8; SIZE] = bytes.try_into().unwrap();
+                <$t>::from_le_bytes(bytes) as f64
+            }))
+        }};
+    }
+
+    let stats = match data_type {
+        DataType::Bool => ChunkStatistics::from_values(
+            data.iter().map(|b| f64::from(u8::from(*b != 0))),
+        ),
+        DataType::Int8 => values!(i8),
+        DataType::Int16 => values!(i16),
+        DataType::Int32 => values!(i32),
+        DataType::Int64 => values!(i64),
+        DataType::UInt8 => values!(u8),
+        DataType::UInt16 => values!(u16),
+        DataType::UInt32 => values!(u32),
+        DataType::UInt64 => values!(u64),
+        DataType::Float32 => values!(f32),
+        DataType::Float64 => values!(f64),
+        _ => return None,
+    };
+    Some(stats)
+}
+
 /// Warning: The presence of a single error may mean multiple missing items
 async fn updated_chunk_iterator<'a>(
-    storage: &'a (dyn Storage + Send + Sync),
+    storage: &'a dyn Storage,
     change_set: &'a ChangeSet,
     snapshot_id: &'a SnapshotId,
 ) -> RepositoryResult<impl Stream<Item = RepositoryResult<(Path, ChunkInfo)>> + 'a> {
@@ -1010,12 +3961,13 @@ async fn updated_chunk_iterator<'a>(
 
 /// Warning: The presence of a single error may mean multiple missing items
 async fn node_chunk_iterator<'a>(
-    storage: &'a (dyn Storage + Send + Sync),
+    storage: &'a dyn Storage,
     change_set: &'a ChangeSet,
     snapshot_id: &SnapshotId,
     path: &Path,
 ) -> impl Stream<Item = RepositoryResult<ChunkInfo>> + 'a {
-    match get_node(storage, change_set, snapshot_id, path).await {
+    // chunks don't need the user attributes
+    match get_node(storage, change_set, snapshot_id, path, false).await {
         Ok(node) => futures::future::Either::Left(
             verified_node_chunk_iterator(storage, change_set, node).await,
         ),
@@ -1025,12 +3977,15 @@ async fn node_chunk_iterator<'a>(
 
 /// Warning: The presence of a single error may mean multiple missing items
 async fn verified_node_chunk_iterator<'a>(
-    storage: &'a (dyn Storage + Send + Sync),
+    storage: &'a dyn Storage,
     change_set: &'a ChangeSet,
     node: NodeSnapshot,
 ) -> impl Stream<Item = RepositoryResult<ChunkInfo>> + 'a {
     match node.node_data {
-        NodeData::Group => futures::future::Either::Left(futures::stream::empty()),
+        // the chunks of concatenated arrays belong to their sources
+        NodeData::Group | NodeData::Concatenated(..) => {
+            futures::future::Either::Left(futures::stream::empty())
+        }
         NodeData::Array(_, manifests) => {
             let new_chunk_indices: Box<HashSet<&ChunkIndices>> = Box::new(
                 change_set
@@ -1097,7 +4052,7 @@ async fn verified_node_chunk_iterator<'a>(
 }
 
 async fn all_chunks<'a>(
-    storage: &'a (dyn Storage + Send + Sync),
+    storage: &'a dyn Storage,
     change_set: &'a ChangeSet,
     snapshot_id: &'a SnapshotId,
 ) -> RepositoryResult<impl Stream<Item = RepositoryResult<(Path, ChunkInfo)>> + 'a> {
@@ -1115,13 +4070,15 @@ mod tests {
     use std::{error::Error, num::NonZeroU64};
 
     use crate::{
-        format::manifest::ChunkInfo,
+        format::manifest::{ChunkInfo, ManifestExtents},
         metadata::{
             ChunkKeyEncoding, ChunkShape, Codec, DataType, FillValue, StorageTransformer,
         },
         refs::{fetch_ref, Ref},
-        storage::{logging::LoggingStorage, ObjectStorage},
+        storage::{logging::LoggingStorage, ObjectStorage, StorageFuture, StorageResult},
         strategies::*,
+        synthetic::vector_metadata,
+        verification::VerificationMode,
     };
 
     use super::*;
@@ -1279,13 +4236,15 @@ mod tests {
                 Some("y".to_string()),
                 Some("t".to_string()),
             ]),
+            rectilinear_grid: None,
         };
         let manifest_ref = ManifestRef {
             object_id: manifest_id.clone(),
             extents: ManifestExtents(vec![]),
+            content_hash: None,
         };
         let array1_path: Path = "/array1".try_into().unwrap();
-        let nodes = vec![
+        let nodes = [
             NodeSnapshot {
                 path: Path::root(),
                 id: 1,
@@ -1346,6 +4305,7 @@ mod tests {
                 configuration: None,
             }]),
             dimension_names: Some(vec![Some("t".to_string())]),
+            rectilinear_grid: None,
         };
 
         let new_array_path: Path = "/group/array2".to_string().try_into().unwrap();
@@ -1479,6 +4439,7 @@ mod tests {
                 Some("y".to_string()),
                 Some("t".to_string()),
             ]),
+            rectilinear_grid: None,
         };
 
         change_set.add_array("/foo/bar".try_into().unwrap(), 1, zarr_meta.clone());
@@ -1555,11 +4516,11 @@ mod tests {
 
     #[tokio::test(flavor = "multi_thread")]
     async fn test_repository_with_updates_and_writes() -> Result<(), Box<dyn Error>> {
-        let backend: Arc<dyn Storage + Send + Sync> =
+        let backend: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
 
         let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
-        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let logging_c: Arc<dyn Storage> = logging.clone();
         let storage = Repository::add_in_mem_asset_caching(Arc::clone(&logging_c));
 
         let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
@@ -1610,6 +4571,7 @@ mod tests {
                 configuration: None,
             }]),
             dimension_names: Some(vec![Some("t".to_string())]),
+            rectilinear_grid: None,
         };
 
         let new_array_path: Path = "/group/array1".try_into().unwrap();
@@ -1743,7 +4705,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_basic_delete_and_flush() -> Result<(), Box<dyn Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
         let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
         ds.add_group(Path::root()).await?;
@@ -1759,7 +4721,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_basic_delete_after_flush() -> Result<(), Box<dyn Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
         let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
         ds.add_group(Path::root()).await?;
@@ -1775,7 +4737,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_commit_after_deleting_old_node() -> Result<(), Box<dyn Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
         let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
         ds.add_group(Path::root()).await?;
@@ -1788,7 +4750,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_delete_children() -> Result<(), Box<dyn Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
         let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
         ds.add_group(Path::root()).await?;
@@ -1803,7 +4765,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_delete_children_of_old_nodes() -> Result<(), Box<dyn Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
         let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
         ds.add_group(Path::root()).await?;
@@ -1822,7 +4784,7 @@ mod tests {
     async fn test_manifests_shrink() -> Result<(), Box<dyn Error>> {
         let in_mem_storage =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
-        let storage: Arc<dyn Storage + Send + Sync> = in_mem_storage.clone();
+        let storage: Arc<dyn Storage> = in_mem_storage.clone();
         let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
 
         // there should be no manifests yet
@@ -1856,6 +4818,7 @@ mod tests {
                 configuration: None,
             }]),
             dimension_names: Some(vec![Some("t".to_string())]),
+            rectilinear_grid: None,
         };
 
         let a1path: Path = "/array1".try_into()?;
@@ -1925,7 +4888,7 @@ mod tests {
             NodeData::Array(_, manifests) => {
                 manifests.first().as_ref().unwrap().object_id.clone()
             }
-            NodeData::Group => panic!("must be an array"),
+            _ => panic!("must be an array"),
         };
         let manifest = storage.fetch_manifests(&manifest_id).await?;
         let initial_size = manifest.len();
@@ -1948,7 +4911,7 @@ mod tests {
             NodeData::Array(_, manifests) => {
                 manifests.first().as_ref().unwrap().object_id.clone()
             }
-            NodeData::Group => panic!("must be an array"),
+            _ => panic!("must be an array"),
         };
         let manifest = storage.fetch_manifests(&manifest_id).await?;
         let size_after_delete = manifest.len();
@@ -1984,7 +4947,7 @@ mod tests {
             NodeData::Array(_, manifests) => {
                 manifests.first().as_ref().unwrap().object_id.clone()
             }
-            NodeData::Group => panic!("must be an array"),
+            _ => panic!("must be an array"),
         };
         let manifest = storage.fetch_manifests(&manifest_id).await?;
         let size_after_chunk_delete = manifest.len();
@@ -1995,7 +4958,7 @@ mod tests {
 
     #[tokio::test(flavor = "multi_thread")]
     async fn test_all_chunks_iterator() -> Result<(), Box<dyn Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
         let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
 
@@ -2013,6 +4976,7 @@ mod tests {
                 configuration: None,
             }]),
             dimension_names: Some(vec![Some("t".to_string())]),
+            rectilinear_grid: None,
         };
 
         let new_array_path: Path = "/array".try_into().unwrap();
@@ -2057,9 +5021,2017 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_scalar_arrays() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+
+        ds.add_group(Path::root()).await?;
+        let scalar_meta = ZarrArrayMetadata {
+            shape: vec![],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            rectilinear_grid: None,
+        };
+        assert!(scalar_meta.valid_chunk_coord(&ChunkIndices(vec![])));
+
+        let scalar1: Path = "/scalar1".try_into().unwrap();
+        let scalar2: Path = "/scalar2".try_into().unwrap();
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_array(scalar1.clone(), scalar_meta.clone()).await?;
+        ds.add_array(scalar2.clone(), scalar_meta.clone()).await?;
+        ds.add_array(
+            array.clone(),
+            ZarrArrayMetadata {
+                shape: vec![2],
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(1).unwrap()]),
+                ..scalar_meta.clone()
+            },
+        )
+        .await?;
+
+        let payload1 = ChunkPayload::Inline("1".into());
+        let payload2 = ChunkPayload::Inline("2".into());
+        ds.set_chunk_ref(scalar1.clone(), ChunkIndices(vec![]), Some(payload1.clone()))
+            .await?;
+        ds.set_chunk_ref(scalar2.clone(), ChunkIndices(vec![]), Some(payload2.clone()))
+            .await?;
+        ds.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![1]),
+            Some(ChunkPayload::Inline("hello".into())),
+        )
+        .await?;
+
+        let snapshot_id = ds.flush("commit", SnapshotProperties::default()).await?;
+        let ds = Repository::update(Arc::clone(&storage), snapshot_id).build();
+        assert_eq!(
+            ds.get_chunk_ref(&scalar1, &ChunkIndices(vec![])).await?,
+            Some(payload1.clone())
+        );
+        assert_eq!(
+            ds.get_chunk_ref(&scalar2, &ChunkIndices(vec![])).await?,
+            Some(payload2.clone())
+        );
+        assert_eq!(ds.get_chunk_ref(&array, &ChunkIndices(vec![])).await?, None);
+
+        // every chunk must be reported exactly once, for its own node
+        let chunks = ds
+            .all_chunks()
+            .await?
+            .map_ok(|(path, chunk)| (path, chunk.coord))
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(
+            chunks.into_iter().sorted().collect::<Vec<_>>(),
+            vec![
+                (array, ChunkIndices(vec![1])),
+                (scalar1, ChunkIndices(vec![])),
+                (scalar2, ChunkIndices(vec![])),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_progress_listener() -> Result<(), Box<dyn Error>> {
+        use crate::progress::Progress;
+        use std::sync::Mutex;
+
+        #[derive(Debug, Default)]
+        struct Recorder(Mutex<Vec<(ProgressOperation, Progress)>>);
+
+        impl ProgressListener for Recorder {
+            fn on_progress(&self, operation: ProgressOperation, progress: Progress) {
+                self.0.lock().unwrap().push((operation, progress));
+            }
+        }
+
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let recorder = Arc::new(Recorder::default());
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(2)
+            .with_progress_listener(recorder.clone())
+            .build();
+
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(3, 1, FillValue::Int32(0))).await?;
+
+        for (i, data) in ["hello", "a", "world!"].into_iter().enumerate() {
+            let payload = ds.get_chunk_writer()(Bytes::from(data)).await?;
+            ds.set_chunk_ref(array.clone(), ChunkIndices(vec![i as u64]), Some(payload))
+                .await?;
+        }
+        ds.flush("commit", SnapshotProperties::default()).await?;
+
+        // inline chunks are not uploaded, so they don't generate progress
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                (
+                    ProgressOperation::ChunkUpload,
+                    Progress { done: 1, total: None, bytes: 5 }
+                ),
+                (
+                    ProgressOperation::ChunkUpload,
+                    Progress { done: 2, total: None, bytes: 11 }
+                ),
+                (
+                    ProgressOperation::Flush,
+                    Progress { done: 1, total: Some(2), bytes: 0 }
+                ),
+                (
+                    ProgressOperation::Flush,
+                    Progress { done: 2, total: Some(2), bytes: 0 }
+                ),
+            ]
+        );
+
+        // upload counts start again after a flush
+        recorder.0.lock().unwrap().clear();
+        ds.get_chunk_writer()(Bytes::from_static(b"again")).await?;
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![(
+                ProgressOperation::ChunkUpload,
+                Progress { done: 1, total: None, bytes: 5 }
+            )]
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_commit_timestamps_use_clock() -> Result<(), Box<dyn Error>> {
+        use crate::clock::{FixedClock, MonotonicClock};
+        use chrono::{DateTime, TimeDelta};
+
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let t0 = DateTime::from_timestamp(1_700_000_000, 0).unwrap();
+        let frozen = Arc::new(FixedClock::new(t0));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_clock(frozen.clone())
+            .build();
+
+        ds.add_group(Path::root()).await?;
+        let first = ds.flush("first", SnapshotProperties::default()).await?;
+        assert_eq!(storage.fetch_snapshot(&first).await?.metadata.written_at, t0);
+
+        // a writer with its clock behind still produces timestamps after the parent's
+        let skewed = Arc::new(FixedClock::new(t0 - TimeDelta::hours(1)));
+        let mut ds = Repository::update(Arc::clone(&storage), first)
+            .with_clock(Arc::new(MonotonicClock::new(skewed)))
+            .build();
+        ds.add_group("/group".try_into().unwrap()).await?;
+        let second = ds.flush("second", SnapshotProperties::default()).await?;
+        assert_eq!(
+            storage.fetch_snapshot(&second).await?.metadata.written_at,
+            t0 + MonotonicClock::TICK
+        );
+
+        // a plain clock doesn't correct for skew
+        frozen.set(t0 - TimeDelta::hours(1));
+        let mut ds = Repository::update(Arc::clone(&storage), second)
+            .with_clock(frozen.clone())
+            .build();
+        ds.add_group("/other".try_into().unwrap()).await?;
+        let third = ds.flush("third", SnapshotProperties::default()).await?;
+        assert_eq!(
+            storage.fetch_snapshot(&third).await?.metadata.written_at,
+            t0 - TimeDelta::hours(1)
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_abort_deletes_staged_uploads() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(2, 1, FillValue::Int32(0))).await?;
+
+        let chunk_id = |payload: &ChunkPayload| match payload {
+            ChunkPayload::Ref(ChunkRef { id, .. }) => id.clone(),
+            _ => panic!("expected a materialized chunk"),
+        };
+
+        let committed = ds.get_chunk_writer()(Bytes::from_static(b"committed")).await?;
+        ds.set_chunk_ref(array.clone(), ChunkIndices(vec![0]), Some(committed.clone()))
+            .await?;
+        let snapshot_id = ds.flush("commit", SnapshotProperties::default()).await?;
+
+        let aborted = ds.get_chunk_writer()(Bytes::from_static(b"aborted")).await?;
+        ds.set_chunk_ref(array.clone(), ChunkIndices(vec![1]), Some(aborted.clone()))
+            .await?;
+        // an upload that was never recorded in the change set
+        let orphan = ds.get_chunk_writer()(Bytes::from_static(b"orphan")).await?;
+
+        ds.abort().await?;
+        assert!(!ds.has_uncommitted_changes());
+        assert_eq!(ds.snapshot_id(), &snapshot_id);
+        assert_eq!(ds.get_chunk_ref(&array, &ChunkIndices(vec![1])).await?, None);
+        for payload in [&aborted, &orphan] {
+            assert!(storage
+                .fetch_chunk(&chunk_id(payload), &ByteRange::ALL)
+                .await
+                .is_err());
+        }
+
+        // flushed data survives
+        assert!(storage.fetch_snapshot(&snapshot_id).await.is_ok());
+        assert_eq!(
+            storage.fetch_chunk(&chunk_id(&committed), &ByteRange::ALL).await?,
+            Bytes::from_static(b"committed")
+        );
+
+        // nothing left to clean up
+        ds.abort().await?;
+
+        // uploads handed over with the change set are not deleted
+        let mut ds = Repository::update(Arc::clone(&storage), snapshot_id)
+            .with_inline_threshold_bytes(0)
+            .with_cleanup_on_drop(true)
+            .build();
+        let exported = ds.get_chunk_writer()(Bytes::from_static(b"exported")).await?;
+        ds.set_chunk_ref(array.clone(), ChunkIndices(vec![1]), Some(exported.clone()))
+            .await?;
+        let change_set: ChangeSet = ds.into();
+        assert!(!change_set.is_empty());
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(storage.fetch_chunk(&chunk_id(&exported), &ByteRange::ALL).await.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_errors_carry_object_context() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(2, 1, FillValue::Int32(0))).await?;
+        let payload = ds.get_chunk_writer()(Bytes::from_static(b"hello")).await?;
+        let ChunkPayload::Ref(ChunkRef { id: chunk_id, .. }) = payload.clone() else {
+            panic!("expected a materialized chunk");
+        };
+        let coords = ChunkIndices(vec![1]);
+        ds.set_chunk_ref(array.clone(), coords.clone(), Some(payload)).await?;
+        let snapshot_id = ds.flush("commit", SnapshotProperties::default()).await?;
+
+        storage.delete_chunk(&chunk_id).await?;
+        let reader = ds.get_chunk_reader(&array, &coords, &ByteRange::ALL).await?;
+        let err = reader.expect("chunk should be referenced").await.unwrap_err();
+        assert_eq!(err.chunk(), Some((&array, &coords)));
+        assert!(matches!(err.root_cause(), RepositoryError::StorageError(_)));
+        let key = err.object_key().expect("missing object key");
+        assert!(key.ends_with(&chunk_id.to_string()));
+        assert!(err.to_string().contains(key));
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(!err.is_retryable());
+
+        storage.delete_snapshot(&snapshot_id).await?;
+        let ds = Repository::update(Arc::clone(&storage), snapshot_id.clone()).build();
+        let err = ds.get_node(&array).await.unwrap_err();
+        assert_eq!(err.snapshot_id(), Some(&snapshot_id));
+        assert!(err.object_key().is_some_and(|key| key.contains("snapshots/")));
+        assert!(err.to_string().contains(&snapshot_id.to_string()));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_commit_reuses_parent_structure() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+
+        let group = |n: usize| -> Path { format!("/group{n}").try_into().unwrap() };
+        let array: Path = "/group0/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        for n in 0..30 {
+            ds.add_group(group(n)).await?;
+        }
+        ds.add_array(array.clone(), vector_metadata(2, 1, FillValue::Int32(0))).await?;
+        ds.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Inline("hello".into())),
+        )
+        .await?;
+        let parent_id = ds.commit(Ref::DEFAULT_BRANCH, "first", None).await?;
+
+        let atts = UserAttributes::try_new(br#"{"updated": true}"#).unwrap();
+        ds.set_user_attributes(group(1), Some(atts.clone())).await?;
+        ds.delete_group(group(2)).await?;
+        ds.add_group(group(30)).await?;
+        ds.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![1]),
+            Some(ChunkPayload::Inline("world".into())),
+        )
+        .await?;
+        let child_id = ds.commit(Ref::DEFAULT_BRANCH, "second", None).await?;
+
+        let parent = storage.fetch_snapshot(&parent_id).await?;
+        let child = storage.fetch_snapshot(&child_id).await?;
+        assert_eq!(child.len(), parent.len());
+        for n in [0, 3, 4, 5, 6, 7, 8, 9] {
+            assert_eq!(child.get_node(&group(n))?, parent.get_node(&group(n))?);
+        }
+        assert_ne!(child.get_node(&group(1))?, parent.get_node(&group(1))?);
+        assert_eq!(
+            ds.get_node(&group(1)).await?.user_attributes,
+            Some(UserAttributesSnapshot::Inline(atts.clone()))
+        );
+        assert!(child.get_node(&group(2)).is_err());
+        assert!(child.get_node(&group(30)).is_ok());
+
+        let manifests =
+            |snapshot: &Snapshot| match &snapshot.get_node(&array).unwrap().node_data {
+                NodeData::Array(_, manifests) => manifests.clone(),
+                _ => panic!("expected an array"),
+            };
+        assert_ne!(manifests(&child), manifests(&parent));
+        let chunks: Vec<_> = ds.all_chunks().await?.try_collect().await?;
+        assert_eq!(chunks.len(), 2);
+
+        // only the changed nodes are stored, the rest are read from the parent
+        let stored = |snapshot: &Snapshot| -> Snapshot {
+            rmp_serde::from_slice(&rmp_serde::to_vec(snapshot).unwrap()).unwrap()
+        };
+        assert_eq!(parent.structure_base(), None);
+        assert_eq!(child.structure_base(), Some(&parent_id));
+        assert_eq!(stored(&child).len(), 3);
+        assert!(stored(&child).get_node(&group(3)).is_err());
+
+        // later commits keep the same base, with all the changes since it
+        ds.set_user_attributes(group(3), Some(atts.clone())).await?;
+        let grandchild_id = ds.commit(Ref::DEFAULT_BRANCH, "third", None).await?;
+        let grandchild = storage.fetch_snapshot(&grandchild_id).await?;
+        assert_eq!(grandchild.structure_base(), Some(&parent_id));
+        assert_eq!(stored(&grandchild).len(), 4);
+        assert!(grandchild.get_node(&group(2)).is_err());
+        assert_eq!(grandchild.get_node(&group(4))?, parent.get_node(&group(4))?);
+
+        // with too many changes the whole table is stored again
+        for n in 4..20 {
+            ds.delete_group(group(n)).await?;
+        }
+        let rebased_id = ds.commit(Ref::DEFAULT_BRANCH, "fourth", None).await?;
+        let rebased = storage.fetch_snapshot(&rebased_id).await?;
+        assert_eq!(rebased.structure_base(), None);
+        assert_eq!(stored(&rebased).len(), rebased.len());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_commits_reuse_attribute_tables() -> Result<(), Box<dyn Error>> {
+        let storage = Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage) as Arc<dyn Storage>, false)
+            .await?
+            .build();
+        let attribute_objects = || async {
+            let keys = storage.all_keys().await.unwrap();
+            keys.into_iter().filter(|key| key.contains("/attributes/")).count()
+        };
+
+        let array: Path = "/array".try_into().unwrap();
+        let atts = UserAttributes::try_new(br#"{"units": "m"}"#).unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(2, 1, FillValue::Int32(0))).await?;
+        ds.set_user_attributes(array.clone(), Some(atts.clone())).await?;
+        let first = ds.commit(Ref::DEFAULT_BRANCH, "first", None).await?;
+        assert_eq!(attribute_objects().await, 1);
+        let first = storage.fetch_snapshot(&first).await?;
+        assert_eq!(first.attribute_files.len(), 1);
+        assert!(matches!(
+            first.get_node(&array)?.user_attributes,
+            Some(UserAttributesSnapshot::Ref(_))
+        ));
+
+        // commits that don't change attributes keep referencing the same table
+        ds.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Inline("hello".into())),
+        )
+        .await?;
+        let second = ds.commit(Ref::DEFAULT_BRANCH, "chunks only", None).await?;
+        let second = storage.fetch_snapshot(&second).await?;
+        assert_eq!(second.attribute_files, first.attribute_files);
+        assert_eq!(attribute_objects().await, 1);
+
+        // setting the same attributes again produces the same table
+        ds.set_user_attributes(array.clone(), Some(atts.clone())).await?;
+        let third = ds.commit(Ref::DEFAULT_BRANCH, "same attributes", None).await?;
+        let third = storage.fetch_snapshot(&third).await?;
+        assert_eq!(third.attribute_files, first.attribute_files);
+        assert_eq!(attribute_objects().await, 1);
+        assert_eq!(
+            ds.get_node(&array).await?.user_attributes,
+            Some(UserAttributesSnapshot::Inline(atts))
+        );
+
+        let new_atts = UserAttributes::try_new(br#"{"units": "km"}"#).unwrap();
+        ds.set_user_attributes(array.clone(), Some(new_atts.clone())).await?;
+        let fourth = ds.commit(Ref::DEFAULT_BRANCH, "new attributes", None).await?;
+        let fourth = storage.fetch_snapshot(&fourth).await?;
+        assert_eq!(attribute_objects().await, 2);
+        assert_eq!(fourth.attribute_files.len(), 1);
+        assert_ne!(fourth.attribute_files, first.attribute_files);
+        let ds = Repository::update(
+            Arc::clone(&storage) as Arc<dyn Storage>,
+            fourth.metadata.id.clone(),
+        )
+        .build();
+        assert_eq!(
+            ds.get_node(&array).await?.user_attributes,
+            Some(UserAttributesSnapshot::Inline(new_atts))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_frozen_arrays() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let grid: Path = "/grid".try_into().unwrap();
+        let mask: Path = "/mask".try_into().unwrap();
+        let data: Path = "/data".try_into().unwrap();
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_frozen_array(grid.clone())
+            .build();
+        let zarr_meta = vector_metadata(2, 1, FillValue::Int32(0));
+        ds.add_group(Path::root()).await?;
+        for path in [&grid, &mask, &data] {
+            ds.add_array(path.clone(), zarr_meta.clone()).await?;
+        }
+        let frozen = UserAttributes::try_new(br#"{"icechunk.frozen": true}"#).unwrap();
+        ds.set_user_attributes(mask.clone(), Some(frozen)).await?;
+        let chunk = || Some(ChunkPayload::Inline("1234".into()));
+
+        for path in [&grid, &mask] {
+            let res =
+                ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), chunk()).await;
+            assert!(matches!(res, Err(RepositoryError::FrozenArray { .. })));
+            let res = ds.update_array(path.clone(), zarr_meta.clone()).await;
+            assert!(matches!(res, Err(RepositoryError::FrozenArray { .. })));
+            let res = ds.delete_array(path.clone()).await;
+            assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidRequest);
+            // the attributes, including the one freezing the array, can't change either
+            let res = ds.set_user_attributes(path.clone(), None).await;
+            assert!(matches!(res, Err(RepositoryError::FrozenArray { .. })));
+        }
+        ds.set_chunk_ref(data.clone(), ChunkIndices(vec![0]), chunk()).await?;
+        ds.set_user_attributes(data.clone(), None).await?;
+        assert!(ds.get_array(&mask).await?.user_attributes.is_some());
+
+        // nor through the groups above them
+        let group: Path = "/group".try_into().unwrap();
+        ds.add_group(group.clone()).await?;
+        let inner: Path = "/group/grid".try_into().unwrap();
+        ds.add_array(inner.clone(), zarr_meta.clone()).await?;
+        let frozen = UserAttributes::try_new(br#"{"icechunk.frozen": true}"#).unwrap();
+        ds.set_user_attributes(inner.clone(), Some(frozen)).await?;
+        let res = ds.delete_group(group.clone()).await;
+        assert!(
+            matches!(res, Err(RepositoryError::FrozenArray { path }) if path == inner)
+        );
+        assert!(ds.get_array(&inner).await.is_ok());
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[allow(clippy::single_range_in_vec_init)]
+    async fn test_array_constraints() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let array: Path = "/array".try_into().unwrap();
+        let constraints = ArrayConstraints::default()
+            .with_data_type(DataType::Int32)
+            .with_chunk_length_bytes(4)
+            .with_coordinate_domain(vec![0..2]);
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_array_constraints(array.clone(), constraints)
+            .build();
+
+        let zarr_meta = vector_metadata(2, 1, FillValue::Int32(0));
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), zarr_meta.clone()).await?;
+        let chunk = |bytes: &'static [u8]| Some(ChunkPayload::Inline(bytes.into()));
+        ds.set_chunk_ref(array.clone(), ChunkIndices(vec![0]), chunk(b"1234")).await?;
+        let res =
+            ds.set_chunk_ref(array.clone(), ChunkIndices(vec![2]), chunk(b"1234")).await;
+        assert!(matches!(
+            res,
+            Err(RepositoryError::ConstraintViolation {
+                violation: ConstraintViolation::OutsideDomain { .. },
+                ..
+            })
+        ));
+        let res =
+            ds.set_chunk_ref(array.clone(), ChunkIndices(vec![1]), chunk(b"12")).await;
+        assert!(matches!(
+            res,
+            Err(RepositoryError::ConstraintViolation {
+                violation: ConstraintViolation::ChunkLength { found: 2, .. },
+                ..
+            })
+        ));
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::InvalidRequest);
+        let tip = ds.commit(Ref::DEFAULT_BRANCH, "valid", None).await?;
+
+        // writers without the constraints can produce invalid changes, commit rejects them
+        let mut other = Repository::update(Arc::clone(&storage), tip.clone()).build();
+        other.set_chunk_ref(array.clone(), ChunkIndices(vec![1]), chunk(b"12")).await?;
+        let res = ds
+            .distributed_commit(
+                Ref::DEFAULT_BRANCH,
+                vec![other.change_set.clone()],
+                "invalid chunk",
+                None,
+            )
+            .await;
+        assert!(matches!(res, Err(RepositoryError::ConstraintViolation { .. })));
+
+        let mut ds = Repository::update(Arc::clone(&storage), tip.clone())
+            .with_config(ds.config().clone())
+            .build();
+        ds.update_array(
+            array.clone(),
+            ZarrArrayMetadata { data_type: DataType::Float32, ..zarr_meta },
+        )
+        .await?;
+        let res = ds.commit(Ref::DEFAULT_BRANCH, "invalid metadata", None).await;
+        assert!(matches!(
+            res,
+            Err(RepositoryError::ConstraintViolation {
+                violation: ConstraintViolation::DataType { .. },
+                ..
+            })
+        ));
+        assert_eq!(
+            fetch_branch_tip(storage.as_ref(), Ref::DEFAULT_BRANCH).await?.snapshot,
+            tip
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_commit_rejects_invalid_tables() -> Result<(), Box<dyn Error>> {
+        let in_mem_storage =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let storage: Arc<dyn Storage> = in_mem_storage.clone();
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(2, 1, FillValue::Int32(0))).await?;
+        let tip = ds.commit("main", "create array", None).await?;
+
+        // coordinates with the wrong number of dimensions
+        ds.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![0, 0]),
+            Some(ChunkPayload::Inline("hello".into())),
+        )
+        .await?;
+        let res = ds.commit("main", "broken", None).await;
+        match res {
+            Err(RepositoryError::InvalidTables { violations, .. }) => {
+                // the extents of the manifest have the rank of the chunk too
+                assert!(matches!(
+                    violations.as_slice(),
+                    [TableViolation::ExtentsRank { ndim: 1, .. }, _]
+                ));
+                assert_eq!(
+                    violations[1],
+                    TableViolation::ChunkRank {
+                        path: array.clone(),
+                        coords: ChunkIndices(vec![0, 0]),
+                        ndim: 1,
+                    }
+                );
+            }
+            other => panic!("expected invalid tables, got {other:?}"),
+        }
+        // nothing was uploaded
+        assert!(!in_mem_storage
+            .all_keys()
+            .await?
+            .iter()
+            .any(|key| key.contains("manifest")));
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, tip);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_region_commits() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(
+            array.clone(),
+            ZarrArrayMetadata {
+                shape: vec![4, 4],
+                data_type: DataType::Int32,
+                chunk_shape: ChunkShape(vec![
+                    NonZeroU64::new(1).unwrap(),
+                    NonZeroU64::new(1).unwrap(),
+                ]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::Int32(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                rectilinear_grid: None,
+            },
+        )
+        .await?;
+        let base = ds.commit(Ref::DEFAULT_BRANCH, "create array", None).await?;
+
+        let telemetry = Arc::new(CommitTelemetry::new());
+        let writer = |rows: std::ops::Range<u64>| {
+            let mut ds = Repository::update(Arc::clone(&storage), base.clone())
+                .with_commit_telemetry(Arc::clone(&telemetry))
+                .build();
+            ds.declare_write_region(array.clone(), ChunkRegion(vec![rows, 0..4]));
+            ds
+        };
+        let mut top = writer(0..2);
+        let mut bottom = writer(2..4);
+        let mut overlapping = writer(1..3);
+
+        assert!(matches!(
+            top.set_chunk_ref(array.clone(), ChunkIndices(vec![2, 0]), None).await,
+            Err(RepositoryError::OutsideWriteRegions { .. })
+        ));
+        let payload = |s: &'static str| Some(ChunkPayload::Inline(s.into()));
+        top.set_chunk_ref(array.clone(), ChunkIndices(vec![0, 0]), payload("top"))
+            .await?;
+        bottom
+            .set_chunk_ref(array.clone(), ChunkIndices(vec![3, 0]), payload("bottom"))
+            .await?;
+        overlapping
+            .set_chunk_ref(array.clone(), ChunkIndices(vec![1, 0]), payload("overlap"))
+            .await?;
+
+        let top_snap = top.commit(Ref::DEFAULT_BRANCH, "top", None).await?;
+        let top_meta = storage.fetch_snapshot(&top_snap).await?.metadata.clone();
+        assert_eq!(
+            top_meta.write_regions,
+            Some(WriteRegions::from([(
+                array.clone(),
+                vec![ChunkRegion(vec![0..2, 0..4])]
+            )]))
+        );
+
+        // the branch moved, but with a disjoint region
+        let bottom_snap = bottom.commit(Ref::DEFAULT_BRANCH, "bottom", None).await?;
+        let ds = Repository::from_branch_tip(Arc::clone(&storage), Ref::DEFAULT_BRANCH)
+            .await?
+            .build();
+        assert_eq!(ds.snapshot_id(), &bottom_snap);
+        assert_eq!(
+            ds.get_chunk_ref(&array, &ChunkIndices(vec![0, 0])).await?,
+            payload("top")
+        );
+        assert_eq!(
+            ds.get_chunk_ref(&array, &ChunkIndices(vec![3, 0])).await?,
+            payload("bottom")
+        );
+
+        assert!(matches!(
+            overlapping.commit(Ref::DEFAULT_BRANCH, "overlap", None).await,
+            Err(RepositoryError::Conflict { .. })
+        ));
+
+        // writers that don't declare regions conflict as usual
+        let mut undeclared =
+            Repository::update(Arc::clone(&storage), base.clone()).build();
+        undeclared
+            .set_chunk_ref(array.clone(), ChunkIndices(vec![3, 3]), payload("undeclared"))
+            .await?;
+        assert!(matches!(
+            undeclared.commit(Ref::DEFAULT_BRANCH, "undeclared", None).await,
+            Err(RepositoryError::Conflict { .. })
+        ));
+
+        // and so do writers that change more than chunks
+        let mut attrs = writer(3..4);
+        attrs.set_user_attributes(array.clone(), None).await?;
+        attrs
+            .set_chunk_ref(array.clone(), ChunkIndices(vec![3, 3]), payload("x"))
+            .await?;
+        assert!(matches!(
+            attrs.commit(Ref::DEFAULT_BRANCH, "attrs", None).await,
+            Err(RepositoryError::Conflict { .. })
+        ));
+
+        // the writers share their counters, the undeclared one has its own
+        assert_eq!(
+            telemetry.stats(),
+            CommitStats {
+                attempts: 4,
+                commits: 2,
+                conflicts: 2,
+                rebases: 1,
+                failed_rebases: 2
+            }
+        );
+        assert_eq!(top.commit_stats(), telemetry.stats());
+        assert_eq!(
+            undeclared.commit_stats(),
+            CommitStats { attempts: 1, conflicts: 1, ..Default::default() }
+        );
+        assert_eq!(telemetry.stats().conflict_rate(), 0.5);
+
+        // chunks set before declaring the regions are checked on commit, a failed rebase
+        // leaves the writer on its snapshot
+        let mut late = Repository::update(Arc::clone(&storage), top_snap.clone()).build();
+        late.set_chunk_ref(array.clone(), ChunkIndices(vec![3, 3]), payload("late"))
+            .await?;
+        late.declare_write_region(array.clone(), ChunkRegion(vec![0..1, 0..4]));
+        assert!(matches!(
+            late.commit(Ref::DEFAULT_BRANCH, "late", None).await,
+            Err(RepositoryError::OutsideWriteRegions { coords, .. })
+                if coords == ChunkIndices(vec![3, 3])
+        ));
+        assert_eq!(late.snapshot_id(), &top_snap);
+
+        // and so are the chunks of the merged change sets
+        let mut other =
+            Repository::update(Arc::clone(&storage), bottom_snap.clone()).build();
+        other
+            .set_chunk_ref(array.clone(), ChunkIndices(vec![2, 2]), payload("other"))
+            .await?;
+        let other = ChangeSet::import_from_bytes(&other.change_set_bytes()?)?;
+        let mut merging =
+            Repository::update(Arc::clone(&storage), bottom_snap.clone()).build();
+        merging.declare_write_region(array.clone(), ChunkRegion(vec![0..1, 0..4]));
+        merging
+            .set_chunk_ref(array.clone(), ChunkIndices(vec![0, 2]), payload("merging"))
+            .await?;
+        let res = merging
+            .distributed_commit(Ref::DEFAULT_BRANCH, vec![other], "merge", None)
+            .await;
+        assert!(matches!(
+            res,
+            Err(RepositoryError::OutsideWriteRegions { coords, .. })
+                if coords == ChunkIndices(vec![2, 2])
+        ));
+        assert_eq!(
+            fetch_branch_tip(storage.as_ref(), Ref::DEFAULT_BRANCH).await?.snapshot,
+            bottom_snap
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_chunk_inventory() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let logging = Arc::new(LoggingStorage::new(backend));
+        let storage: Arc<dyn Storage> = Arc::clone(&logging) as Arc<dyn Storage>;
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(4, 1, FillValue::Int32(0))).await?;
+        for i in 0..3 {
+            // big enough to be written as chunk objects
+            let payload = ds.get_chunk_writer()(Bytes::from(vec![i as u8; 1024])).await?;
+            ds.set_chunk_ref(array.clone(), ChunkIndices(vec![i]), Some(payload)).await?;
+        }
+        let snapshot_id = ds.commit("main", "chunks", None).await?;
+
+        let mut ds = Repository::update(Arc::clone(&storage), snapshot_id).build();
+        ds.set_chunk_ref(array.clone(), ChunkIndices(vec![1]), None).await?;
+        ds.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![3]),
+            Some(ChunkPayload::Inline("new".into())),
+        )
+        .await?;
+
+        assert!(ds.chunk_exists(&array, &ChunkIndices(vec![0])).await?);
+        assert!(!ds.chunk_exists(&array, &ChunkIndices(vec![1])).await?);
+        assert!(ds.chunk_exists(&array, &ChunkIndices(vec![3])).await?);
+        let coords: HashSet<_> =
+            ds.chunk_coords_iter(&array).await?.try_collect().await?;
+        assert_eq!(
+            coords,
+            [0, 2, 3].into_iter().map(|i| ChunkIndices(vec![i])).collect::<HashSet<_>>()
+        );
+        assert!(matches!(
+            ds.chunk_coords_iter(&Path::root()).await.err(),
+            Some(RepositoryError::NotAnArray { .. })
+        ));
+        assert!(logging.fetch_operations().iter().all(|(op, _)| op != "fetch_chunk"));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_chunk_statistics() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_compute_chunk_statistics(true)
+            .with_manifest_split_policy(ManifestSplitPolicy {
+                max_rows: Some(2),
+                ..ManifestSplitPolicy::default()
+            })
+            .build();
+
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(12, 4, FillValue::Int32(0))).await?;
+        let chunk = |first: i32| -> Bytes {
+            (first..first + 4).flat_map(i32::to_le_bytes).collect::<Vec<_>>().into()
+        };
+        for i in 0..3 {
+            let data = chunk(i as i32 * 10);
+            let stats = ds.compute_chunk_statistics(&array, &data).await?.unwrap();
+            let payload = ds.get_chunk_writer()(data).await?;
+            ds.set_chunk_ref(array.clone(), ChunkIndices(vec![i]), Some(payload)).await?;
+            ds.set_chunk_statistics(&array, ChunkIndices(vec![i]), stats).await?;
+        }
+        async fn matching(
+            ds: &Repository,
+            array: &Path,
+            predicate: ChunkPredicate,
+        ) -> RepositoryResult<Vec<u64>> {
+            let mut coords: Vec<_> = ds
+                .chunks_matching(array, predicate)
+                .await?
+                .map_ok(|coord| coord.0[0])
+                .try_collect()
+                .await?;
+            coords.sort();
+            Ok(coords)
+        }
+        assert_eq!(
+            matching(&ds, &array, ChunkPredicate::GreaterThan(11.0)).await?,
+            vec![1, 2]
+        );
+        let snapshot_id = ds.commit("main", "chunks", None).await?;
+
+        let mut ds = Repository::update(Arc::clone(&storage), snapshot_id).build();
+        assert_eq!(matching(&ds, &array, ChunkPredicate::LessThan(5.0)).await?, vec![0]);
+        assert_eq!(
+            matching(&ds, &array, ChunkPredicate::Between(3.0, 10.0)).await?,
+            vec![0, 1]
+        );
+        assert!(matching(&ds, &array, ChunkPredicate::HasNan).await?.is_empty());
+        assert_eq!(
+            ds.get_chunk_extra::<ChunkStatistics>(&array, &ChunkIndices(vec![2])).await?,
+            Some(ChunkStatistics { min: Some(20.0), max: Some(23.0), nan_count: 0 })
+        );
+        // without the configuration statistics are not computed
+        assert_eq!(ds.compute_chunk_statistics(&array, &chunk(0)).await?, None);
+
+        // rewritten chunks lose their statistics
+        ds.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![1]),
+            Some(ChunkPayload::Inline(chunk(100))),
+        )
+        .await?;
+        assert_eq!(
+            matching(&ds, &array, ChunkPredicate::LessThan(5.0)).await?,
+            vec![0, 1]
+        );
+        assert_eq!(
+            ds.get_chunk_extra::<ChunkStatistics>(&array, &ChunkIndices(vec![1])).await?,
+            None
+        );
+        let snapshot_id = ds.commit("main", "rewrite", None).await?;
+
+        let ds = Repository::update(Arc::clone(&storage), snapshot_id).build();
+        assert_eq!(
+            matching(&ds, &array, ChunkPredicate::LessThan(5.0)).await?,
+            vec![0, 1]
+        );
+        assert_eq!(
+            matching(&ds, &array, ChunkPredicate::GreaterThan(11.0)).await?,
+            vec![1, 2]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_verification() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(1)
+            .with_compute_chunk_checksums(true)
+            .build();
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(8, 4, FillValue::UInt8(0))).await?;
+        let mut ids = vec![];
+        for i in 0..2 {
+            let data = Bytes::from(vec![i as u8; 4]);
+            let checksum = ds.compute_chunk_checksum(&data).unwrap();
+            let payload = ds.get_chunk_writer()(data).await?;
+            if let ChunkPayload::Ref(ChunkRef { id, .. }) = &payload {
+                ids.push(id.clone());
+            }
+            ds.set_chunk_ref(array.clone(), ChunkIndices(vec![i]), Some(payload)).await?;
+            ds.set_chunk_extra(&array, ChunkIndices(vec![i]), &checksum).await?;
+        }
+        let snapshot_id = ds.commit("main", "chunks", None).await?;
+
+        async fn read(ds: &Repository, array: &Path, i: u64) -> RepositoryResult<Bytes> {
+            let reader = ds
+                .get_chunk_reader(array, &ChunkIndices(vec![i]), &ByteRange::ALL)
+                .await?
+                .unwrap();
+            reader.await
+        }
+
+        let policy = VerificationPolicy::default()
+            .with_chunk_checksums(VerificationMode::Always)
+            .with_manifest_hashes(VerificationMode::Always);
+        let ds = Repository::update(Arc::clone(&storage), snapshot_id.clone())
+            .with_verification_policy(policy)
+            .build();
+        assert_eq!(read(&ds, &array, 1).await?, Bytes::from(vec![1; 4]));
+        assert_eq!(read(&ds, &array, 1).await?, Bytes::from(vec![1; 4]));
+        // the manifest is only checked the first time
+        assert_eq!(
+            ds.io_summary(),
+            IoSummary {
+                policy,
+                chunk_reads: 2,
+                chunks_verified: 2,
+                manifest_reads: 2,
+                manifests_verified: 1,
+                ..IoSummary::default()
+            }
+        );
+
+        // a chunk that changed in storage fails the check
+        storage.write_chunk(ids[0].clone(), Bytes::from_static(b"oops")).await?;
+        let err = read(&ds, &array, 0).await.unwrap_err();
+        assert!(matches!(
+            err.root_cause(),
+            RepositoryError::Verification(VerificationError::ChunkChecksum { .. })
+        ));
+        assert_eq!(err.kind(), ErrorKind::Corruption);
+
+        // unless the policy skips the checks
+        let ds = Repository::update(Arc::clone(&storage), snapshot_id).build();
+        assert_eq!(read(&ds, &array, 0).await?, Bytes::from_static(b"oops"));
+        let summary = ds.io_summary();
+        assert_eq!((summary.chunk_reads, summary.chunks_verified), (1, 0));
+        assert_eq!(summary.policy, VerificationPolicy::default());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_chunk_length_mismatch() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(8, 4, FillValue::UInt8(0))).await?;
+        let id = ChunkId::random();
+        storage.write_chunk(id.clone(), Bytes::from_static(b"abcd")).await?;
+        // the manifest says the chunk is longer than the object
+        ds.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Ref(ChunkRef { id, offset: 0, length: 6 })),
+        )
+        .await?;
+
+        let read = |range: ByteRange| {
+            let ds = &ds;
+            let array = &array;
+            async move {
+                ds.get_chunk_reader(array, &ChunkIndices(vec![0]), &range)
+                    .await?
+                    .unwrap()
+                    .await
+            }
+        };
+        let err = read(ByteRange::ALL).await.unwrap_err();
+        assert!(matches!(
+            err.root_cause(),
+            RepositoryError::ChunkLengthMismatch { expected: 6, found: 4 }
+        ));
+        assert_eq!(err.kind(), ErrorKind::Corruption);
+        assert_eq!(
+            read(ByteRange::bounded(1u64, 3u64)).await?,
+            Bytes::from_static(b"bc")
+        );
+        assert_eq!(read(ByteRange::from_offset(6u64)).await?, Bytes::new());
+        assert!(matches!(
+            read(ByteRange::from_offset(7u64)).await.unwrap_err().root_cause(),
+            RepositoryError::FormatError(
+                IcechunkFormatError::ByteRangeOutOfBounds { .. }
+            )
+        ));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_commit_summary() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let meta = vector_metadata(3, 1, FillValue::UInt8(0));
+        let (a, b): (Path, Path) = ("/a".try_into().unwrap(), "/b".try_into().unwrap());
+        ds.add_group(Path::root()).await?;
+        ds.add_array(a.clone(), meta.clone()).await?;
+        let payload = ds.get_chunk_writer()(Bytes::from(vec![1; 1024])).await?;
+        ds.set_chunk_ref(a.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+        ds.set_chunk_ref(
+            a.clone(),
+            ChunkIndices(vec![1]),
+            Some(ChunkPayload::Inline("abc".into())),
+        )
+        .await?;
+        ds.set_chunk_ref(
+            a.clone(),
+            ChunkIndices(vec![2]),
+            Some(ChunkPayload::Virtual(VirtualChunkRef {
+                location: VirtualChunkLocation::from_absolute_path("s3://bucket/file")?,
+                offset: 0,
+                length: 10,
+            })),
+        )
+        .await?;
+        ds.commit("main", "add a", None).await?;
+
+        ds.delete_array(a.clone()).await?;
+        ds.add_array(b.clone(), meta).await?;
+        ds.set_chunk_ref(
+            b.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Inline("de".into())),
+        )
+        .await?;
+        ds.commit("main", "replace a with b", None).await?;
+
+        let summaries: Vec<_> =
+            ds.ancestry().await?.map_ok(|meta| meta.summary).try_collect().await?;
+        assert_eq!(
+            summaries,
+            vec![
+                Some(CommitSummary {
+                    arrays_added: vec![b],
+                    arrays_deleted: vec![a.clone()],
+                    chunk_count_delta: -2,
+                    bytes_added: 2,
+                }),
+                Some(CommitSummary {
+                    arrays_added: vec![a],
+                    arrays_deleted: vec![],
+                    chunk_count_delta: 3,
+                    bytes_added: 1027,
+                }),
+                None,
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_commit_objects() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        assert_eq!(ds.commit_objects(ds.snapshot_id()).await?, None);
+
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(2, 1, FillValue::UInt8(0))).await?;
+        let payload = ds.get_chunk_writer()(Bytes::from(vec![1; 1024])).await?;
+        let ChunkPayload::Ref(ChunkRef { id: chunk_id, .. }) = &payload else {
+            panic!("expected a chunk object");
+        };
+        let chunk_id = chunk_id.clone();
+        ds.set_chunk_ref(array.clone(), ChunkIndices(vec![0]), Some(payload)).await?;
+        let first = ds.commit("main", "first", None).await?;
+
+        ds.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![1]),
+            Some(ChunkPayload::Inline("x".into())),
+        )
+        .await?;
+        let second = ds.commit("main", "second", None).await?;
+
+        for (snapshot_id, chunks) in [(first, vec![chunk_id]), (second, vec![])] {
+            let snapshot = storage.fetch_snapshot(&snapshot_id).await?;
+            let manifests: Vec<_> =
+                snapshot.manifest_files.iter().map(|file| file.id.clone()).collect();
+            assert_eq!(
+                ds.commit_objects(&snapshot_id).await?,
+                Some(CommitObjects { manifests, chunks })
+            );
+        }
+        Ok(())
+    }
+
+    /// Serves the previous version of refs, and misses snapshots, for a number of reads
+    #[derive(Debug)]
+    struct StaleStorage {
+        backend: Arc<dyn Storage>,
+        stale_reads: std::sync::atomic::AtomicUsize,
+    }
+
+    impl StaleStorage {
+        fn is_stale(&self) -> bool {
+            use std::sync::atomic::Ordering;
+            self.stale_reads
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok()
+        }
+    }
+
+    impl Storage for StaleStorage {
+        fn fetch_snapshot<'a>(
+            &'a self,
+            id: &'a SnapshotId,
+        ) -> StorageFuture<'a, Arc<Snapshot>> {
+            if self.is_stale() {
+                return Box::pin(ready(Err(StorageError::ObjectStore(
+                    ::object_store::Error::NotFound {
+                        path: id.to_string(),
+                        source: "stale read".into(),
+                    },
+                ))));
+            }
+            self.backend.fetch_snapshot(id)
+        }
+
+        fn fetch_attributes<'a>(
+            &'a self,
+            id: &'a crate::format::AttributesId,
+        ) -> StorageFuture<'a, Arc<crate::format::attributes::AttributesTable>> {
+            self.backend.fetch_attributes(id)
+        }
+
+        fn write_attributes<'a>(
+            &'a self,
+            id: crate::format::AttributesId,
+            table: Arc<crate::format::attributes::AttributesTable>,
+        ) -> StorageFuture<'a, ()> {
+            self.backend.write_attributes(id, table)
+        }
+
+        fn ref_versions<'a, 'b>(
+            &'a self,
+            ref_name: &'b str,
+        ) -> StorageFuture<'b, futures::stream::BoxStream<'a, StorageResult<String>>>
+        where
+            'a: 'b,
+        {
+            let skip = usize::from(self.is_stale());
+            Box::pin(async move {
+                Ok(self.backend.ref_versions(ref_name).await?.skip(skip).boxed())
+            })
+        }
+
+        crate::forward_storage!(
+            backend =>
+                fetch_manifests, fetch_chunk, write_snapshot, write_manifests,
+                write_chunk, delete_snapshot, delete_manifests, delete_chunk, get_ref,
+                ref_names, write_ref,
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_consistency_token() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&backend), false).await?.build();
+        assert_eq!(ds.consistency_token(), None);
+        ds.add_group(Path::root()).await?;
+        let first = ds.commit("main", "first", None).await?;
+        let token = ds.consistency_token().cloned().unwrap();
+        assert_eq!(token.branch, "main");
+        assert_eq!(token.version, BranchVersion(1));
+        assert_eq!(token.snapshot, first);
+
+        let stale = Arc::new(StaleStorage {
+            backend: Arc::clone(&backend),
+            stale_reads: 4.into(),
+        });
+        let storage: Arc<dyn Storage> = Arc::clone(&stale) as Arc<dyn Storage>;
+        // a plain open sees the stale branch
+        let old =
+            Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        assert_ne!(old.snapshot_id(), &first);
+
+        let wait =
+            ConsistencyWait { interval: Duration::from_millis(1), ..Default::default() };
+        let repo =
+            Repository::from_consistency_token(Arc::clone(&storage), &token, &wait)
+                .await?
+                .build();
+        assert_eq!(repo.snapshot_id(), &first);
+
+        stale.stale_reads.store(10, std::sync::atomic::Ordering::Relaxed);
+        let wait = ConsistencyWait { max_attempts: 3, ..wait };
+        assert!(matches!(
+            Repository::from_consistency_token(storage, &token, &wait).await,
+            Err(RepositoryError::ConsistencyNotReached { branch, attempts: 3 }) if branch == "main"
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_commit_policy() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(
+            "/array".try_into().unwrap(),
+            vector_metadata(2, 1, FillValue::UInt8(0)),
+        )
+        .await?;
+        ds.set_chunk_ref(
+            "/array".try_into().unwrap(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Inline("hello".into())),
+        )
+        .await?;
+        let first = ds.commit("main", "first", None).await?;
+        assert!(matches!(
+            ds.commit("main", "nothing", None).await,
+            Err(RepositoryError::NoChangesToCommit)
+        ));
+
+        let mut ds = Repository::from_branch_tip(Arc::clone(&storage), "main")
+            .await?
+            .with_empty_commit_policy(EmptyCommitPolicy::ReturnHead)
+            .build();
+        assert_eq!(ds.commit("main", "nothing", None).await?, first);
+        assert!(matches!(
+            ds.commit("other", "nothing", None).await,
+            Err(RepositoryError::NoChangesToCommit)
+        ));
+
+        let mut ds = Repository::from_branch_tip(Arc::clone(&storage), "main")
+            .await?
+            .with_empty_commit_policy(EmptyCommitPolicy::CreateEmpty)
+            .build();
+        let marker = ds.commit("main", "data validated", None).await?;
+        assert_ne!(marker, first);
+        assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, marker);
+        let (parent, snapshot) = (
+            storage.fetch_snapshot(&first).await?,
+            storage.fetch_snapshot(&marker).await?,
+        );
+        assert_eq!(snapshot.manifest_files, parent.manifest_files);
+        assert_eq!(snapshot.metadata.message, "data validated");
+        assert!(ds.snapshots_equivalent(&first, &marker).await?);
+        let messages: Vec<_> =
+            ds.ancestry().await?.map_ok(|meta| meta.message).try_collect().await?;
+        assert_eq!(
+            messages,
+            vec!["data validated", "first", Snapshot::INITIAL_COMMIT_MESSAGE]
+        );
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_manifest_split_policy() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let policy =
+            ManifestSplitPolicy { max_rows: Some(2), ..ManifestSplitPolicy::default() };
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_manifest_split_policy(policy.clone())
+            .build();
+
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(5, 1, FillValue::Int32(0))).await?;
+        for i in 0..5 {
+            ds.set_chunk_ref(
+                array.clone(),
+                ChunkIndices(vec![i]),
+                Some(ChunkPayload::Inline(Bytes::from(vec![i as u8]))),
+            )
+            .await?;
+        }
+        let snapshot_id = ds.commit("main", "split", None).await?;
+
+        let snapshot = storage.fetch_snapshot(&snapshot_id).await?;
+        assert_eq!(snapshot.metadata.manifest_split_policy, Some(policy));
+        assert_eq!(snapshot.manifest_files.len(), 3);
+        let NodeData::Array(_, manifests) = snapshot.get_node(&array)?.node_data.clone()
+        else {
+            panic!()
+        };
+        assert_eq!(
+            manifests.iter().map(|m| m.extents.clone()).collect::<Vec<_>>(),
+            vec![
+                ManifestExtents(vec![ChunkIndices(vec![0]), ChunkIndices(vec![1])]),
+                ManifestExtents(vec![ChunkIndices(vec![2]), ChunkIndices(vec![3])]),
+                ManifestExtents(vec![ChunkIndices(vec![4]), ChunkIndices(vec![4])]),
+            ]
+        );
+
+        let ds = Repository::update(Arc::clone(&storage), snapshot_id).build();
+        for i in 0..5 {
+            assert_eq!(
+                ds.get_chunk_ref(&array, &ChunkIndices(vec![i])).await?,
+                Some(ChunkPayload::Inline(Bytes::from(vec![i as u8])))
+            );
+        }
+        assert_eq!(ds.get_chunk_ref(&array, &ChunkIndices(vec![5])).await?, None);
+        Ok(())
+    }
+
+    /// Delays manifest fetches, counting how many are in flight at the same time
+    #[derive(Debug, Default)]
+    struct SlowManifestStorage {
+        backend: Option<Arc<dyn Storage>>,
+        in_flight: std::sync::atomic::AtomicUsize,
+        max_in_flight: std::sync::atomic::AtomicUsize,
+    }
+
+    impl SlowManifestStorage {
+        fn backend(&self) -> &dyn Storage {
+            self.backend.as_deref().unwrap()
+        }
+    }
+
+    impl Storage for SlowManifestStorage {
+        fn fetch_attributes<'a>(
+            &'a self,
+            id: &'a crate::format::AttributesId,
+        ) -> StorageFuture<'a, Arc<crate::format::attributes::AttributesTable>> {
+            self.backend().fetch_attributes(id)
+        }
+
+        fn fetch_manifests<'a>(
+            &'a self,
+            id: &'a ManifestId,
+        ) -> StorageFuture<'a, Arc<Manifest>> {
+            use std::sync::atomic::Ordering;
+            Box::pin(async move {
+                let now = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_in_flight.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                self.in_flight.fetch_sub(1, Ordering::SeqCst);
+                self.backend().fetch_manifests(id).await
+            })
+        }
+
+        fn write_attributes<'a>(
+            &'a self,
+            id: crate::format::AttributesId,
+            table: Arc<crate::format::attributes::AttributesTable>,
+        ) -> StorageFuture<'a, ()> {
+            self.backend().write_attributes(id, table)
+        }
+
+        crate::forward_storage!(
+            backend() =>
+                fetch_snapshot, fetch_chunk, write_snapshot, write_manifests, write_chunk,
+                delete_snapshot, delete_manifests, delete_chunk, get_ref, ref_names,
+                ref_versions, write_ref,
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_manifest_fetch() -> Result<(), Box<dyn Error>> {
+        use std::sync::atomic::Ordering;
+
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_manifest_split_policy(ManifestSplitPolicy {
+                max_rows: Some(2),
+                ..ManifestSplitPolicy::default()
+            })
+            .build();
+
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(
+            array.clone(),
+            ZarrArrayMetadata {
+                shape: vec![2, 2],
+                data_type: DataType::Int32,
+                chunk_shape: ChunkShape(vec![
+                    NonZeroU64::new(1).unwrap(),
+                    NonZeroU64::new(1).unwrap(),
+                ]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::Int32(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: None,
+                rectilinear_grid: None,
+            },
+        )
+        .await?;
+        for (i, coords) in [vec![0, 1], vec![1, 0], vec![1, 1]].into_iter().enumerate() {
+            ds.set_chunk_ref(
+                array.clone(),
+                ChunkIndices(coords),
+                Some(ChunkPayload::Inline(Bytes::from(vec![i as u8]))),
+            )
+            .await?;
+        }
+        let snapshot_id = ds.commit("main", "fragmented", None).await?;
+
+        // the extents of both manifests include the last chunk
+        let last = ChunkIndices(vec![1, 1]);
+        let snapshot = storage.fetch_snapshot(&snapshot_id).await?;
+        let NodeData::Array(_, manifests) = snapshot.get_node(&array)?.node_data.clone()
+        else {
+            panic!()
+        };
+        assert_eq!(manifests.iter().filter(|m| m.extents.contains(&last)).count(), 2);
+
+        for (concurrency, expected) in [(4, 2), (1, 1)] {
+            let slow = Arc::new(SlowManifestStorage {
+                backend: Some(Arc::clone(&storage)),
+                ..Default::default()
+            });
+            let ds = Repository::update(
+                Arc::clone(&slow) as Arc<dyn Storage>,
+                snapshot_id.clone(),
+            )
+            .with_manifest_fetch_concurrency(concurrency)
+            .build();
+            assert_eq!(
+                ds.get_chunk_ref(&array, &last).await?,
+                Some(ChunkPayload::Inline(Bytes::from(vec![2])))
+            );
+            assert_eq!(slow.max_in_flight.load(Ordering::SeqCst), expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concatenated_array() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let metadata = |len: u64| ZarrArrayMetadata {
+            shape: vec![len, 2],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![
+                NonZeroU64::new(2).unwrap(),
+                NonZeroU64::new(2).unwrap(),
+            ]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            rectilinear_grid: None,
+        };
+
+        // one array per year, 2 and 1 chunks long
+        ds.add_group(Path::root()).await?;
+        let years: Vec<Path> =
+            vec!["/2000".try_into().unwrap(), "/2001".try_into().unwrap()];
+        ds.add_array(years[0].clone(), metadata(4)).await?;
+        ds.add_array(years[1].clone(), metadata(1)).await?;
+        for (year, chunks) in years.iter().zip([2u64, 1]) {
+            for i in 0..chunks {
+                ds.set_chunk_ref(
+                    year.clone(),
+                    ChunkIndices(vec![i, 0]),
+                    Some(ChunkPayload::Inline(format!("{year}/{i}").into())),
+                )
+                .await?;
+            }
+        }
+        let first = ds.commit("main", "years", None).await?;
+        ds.delete_array(years[0].clone()).await?;
+
+        let series: Path = "/series".try_into().unwrap();
+        let sources =
+            vec![(first.clone(), years[0].clone()), (first.clone(), years[1].clone())];
+        ds.add_concatenated_array(series.clone(), 0, sources.clone()).await?;
+        assert_eq!(ds.get_array(&series).await?.zarr_metadata(), Some(&metadata(5)));
+        ds.commit("main", "series", None).await?;
+
+        for (i, expected) in ["/2000/0", "/2000/1", "/2001/0"].into_iter().enumerate() {
+            assert_eq!(
+                ds.get_chunk_ref(&series, &ChunkIndices(vec![i as u64, 0])).await?,
+                Some(ChunkPayload::Inline(expected.into()))
+            );
+        }
+        assert_eq!(ds.get_chunk_ref(&series, &ChunkIndices(vec![3, 0])).await?, None);
+        assert_eq!(ds.get_chunk_ref(&series, &ChunkIndices(vec![0, 1])).await?, None);
+
+        assert!(matches!(
+            ds.set_chunk_ref(series.clone(), ChunkIndices(vec![0, 0]), None).await,
+            Err(RepositoryError::ConcatenatedArrayIsReadOnly { .. })
+        ));
+        assert!(matches!(
+            ds.update_array(series.clone(), metadata(5)).await,
+            Err(RepositoryError::ConcatenatedArrayIsReadOnly { .. })
+        ));
+
+        // sources must end at chunk boundaries, and have the same metadata
+        let other: Path = "/other".try_into().unwrap();
+        let reversed = sources.into_iter().rev().collect();
+        assert!(matches!(
+            ds.add_concatenated_array(other.clone(), 0, reversed).await,
+            Err(RepositoryError::InvalidConcatenation { .. })
+        ));
+        assert!(matches!(
+            ds.add_concatenated_array(
+                other.clone(),
+                1,
+                vec![
+                    (first.clone(), years[0].clone()),
+                    (first.clone(), years[1].clone())
+                ]
+            )
+            .await,
+            Err(RepositoryError::InvalidConcatenation { .. })
+        ));
+        assert!(matches!(
+            ds.add_concatenated_array(
+                other.clone(),
+                0,
+                vec![(first.clone(), Path::root())]
+            )
+            .await,
+            Err(RepositoryError::InvalidConcatenation { .. })
+        ));
+        ds.delete_array(series.clone()).await?;
+        assert!(matches!(
+            ds.get_node(&series).await,
+            Err(RepositoryError::NodeNotFound { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_snapshots_equivalent() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(4, 1, FillValue::Int32(0))).await?;
+        for i in 0..4 {
+            ds.set_chunk_ref(
+                array.clone(),
+                ChunkIndices(vec![i]),
+                Some(ChunkPayload::Inline(Bytes::from(vec![i as u8]))),
+            )
+            .await?;
+        }
+        let packed = ds.flush("packed", SnapshotProperties::default()).await?;
+
+        // write the same content split in two manifests, and with a different node id
+        let snapshot = storage.fetch_snapshot(&packed).await?;
+        let node = snapshot.get_node(&array)?.clone();
+        let NodeData::Array(meta, manifests) = node.node_data else { panic!() };
+        let manifest = storage.fetch_manifests(&manifests[0].object_id).await?;
+        let new_node_id = node.id + 100;
+        let mut refs = Vec::new();
+        for half in &manifest.clone().iter(&node.id).chunks(2) {
+            let chunks =
+                half.map(|(coord, payload)| ((new_node_id, coord), payload)).collect();
+            let id = ObjectId::random();
+            storage.write_manifests(id.clone(), Arc::new(Manifest::new(chunks))).await?;
+            refs.push(ManifestRef {
+                object_id: id,
+                extents: ManifestExtents(vec![]),
+                content_hash: None,
+            });
+        }
+        assert_eq!(refs.len(), 2);
+        let nodes = snapshot.iter().cloned().map(|n| {
+            if n.path == array {
+                NodeSnapshot {
+                    id: new_node_id,
+                    node_data: NodeData::Array(meta.clone(), refs.clone()),
+                    ..n
+                }
+            } else {
+                n
+            }
+        });
+        let split = Snapshot::from_iter(&snapshot, None, vec![], vec![], nodes);
+        let split_id = split.metadata.id.clone();
+        storage.write_snapshot(split_id.clone(), Arc::new(split)).await?;
+
+        assert!(ds.snapshots_equivalent(&packed, &split_id).await?);
+        assert!(ds.snapshots_equivalent(&split_id, &packed).await?);
+
+        // changing a single chunk breaks equivalence
+        ds.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![3]),
+            Some(ChunkPayload::Inline(Bytes::from_static(b"x"))),
+        )
+        .await?;
+        let changed = ds.flush("changed", SnapshotProperties::default()).await?;
+        assert!(!ds.snapshots_equivalent(&packed, &changed).await?);
+
+        // and so does changing attributes
+        ds.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![3]),
+            Some(ChunkPayload::Inline(Bytes::from(vec![3]))),
+        )
+        .await?;
+        ds.set_user_attributes(
+            Path::root(),
+            Some(UserAttributes::try_new(br#"{"a":1}"#)?),
+        )
+        .await?;
+        let attrs = ds.flush("attrs", SnapshotProperties::default()).await?;
+        assert!(!ds.snapshots_equivalent(&packed, &attrs).await?);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_export_history_dot() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let initial = ds.snapshot_id().clone();
+
+        ds.add_group(Path::root()).await?;
+        let first = ds.commit(Ref::DEFAULT_BRANCH, "add \"root\"", None).await?;
+        ds.tag("v1", &first).await?;
+        ds.new_branch("dev").await?;
+        ds.add_group("/a".try_into().unwrap()).await?;
+        let second = ds.commit("dev", "add a", None).await?;
+
+        let mut out = Vec::new();
+        ds.export_history_dot(&mut out).await?;
+        let dot = String::from_utf8(out)?;
+        let lines: Vec<_> = dot.lines().collect();
+
+        assert_eq!(lines.first(), Some(&"digraph history {"));
+        assert_eq!(lines.last(), Some(&"}"));
+        for expected in [
+            format!("  \"{initial}\" [label=\"{initial}\\nRepository initialized\"];"),
+            format!("  \"{first}\" [label=\"{first}\\nadd \\\"root\\\"\"];"),
+            format!("  \"{second}\" [label=\"{second}\\nadd a\"];"),
+            format!("  \"{first}\" -> \"{initial}\";"),
+            format!("  \"{second}\" -> \"{first}\";"),
+            "  \"branch:dev\" [shape=ellipse, label=\"dev\"];".to_string(),
+            format!("  \"branch:dev\" -> \"{second}\" [style=dashed];"),
+            format!("  \"branch:main\" -> \"{first}\" [style=dashed];"),
+            "  \"tag:v1\" [shape=note, label=\"v1\"];".to_string(),
+            format!("  \"tag:v1\" -> \"{first}\" [style=dashed];"),
+        ] {
+            assert!(lines.contains(&expected.as_str()), "missing {expected} in {dot}");
+        }
+        // shared history is written once
+        assert_eq!(
+            lines.iter().filter(|l| l.contains("->") && !l.contains("dashed")).count(),
+            2
+        );
+
+        let mut again = Vec::new();
+        ds.export_history_dot(&mut again).await?;
+        assert_eq!(String::from_utf8(again)?, dot);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tag_immutability() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        let first = ds.commit(Ref::DEFAULT_BRANCH, "first", None).await?;
+        ds.add_group("/a".try_into().unwrap()).await?;
+        let second = ds.commit(Ref::DEFAULT_BRANCH, "second", None).await?;
+
+        // only one of the concurrent creations succeeds
+        let attempts = [first.clone(), second.clone()].map(|snapshot| {
+            let ds = Repository::update(Arc::clone(&storage), snapshot.clone()).build();
+            tokio::spawn(async move { ds.tag("v1", &snapshot).await })
+        });
+        let mut created = 0;
+        for attempt in attempts {
+            match attempt.await? {
+                Ok(()) => created += 1,
+                Err(RepositoryError::Ref(RefError::TagAlreadyExists(name))) => {
+                    assert_eq!(name, "v1")
+                }
+                Err(err) => panic!("unexpected error {err}"),
+            }
+        }
+        assert_eq!(created, 1);
+
+        ds.delete_tag("v1").await?;
+        assert!(matches!(
+            ds.delete_tag("v1").await,
+            Err(RepositoryError::Ref(RefError::RefNotFound(_)))
+        ));
+        ds.tag("v1", &first).await?;
+
+        let mut builder = Repository::update(Arc::clone(&storage), second.clone());
+        builder.with_unsafe_overwrite_refs(true).with_protected_tags(true);
+        let protected = builder.build();
+        assert!(matches!(
+            protected.delete_tag("v1").await,
+            Err(RepositoryError::ProtectedTag(name)) if name == "v1"
+        ));
+        assert!(matches!(
+            protected.tag("v1", &second).await,
+            Err(RepositoryError::Ref(RefError::TagAlreadyExists(_)))
+        ));
+        assert_eq!(fetch_tag(storage.as_ref(), "v1").await?.snapshot, first);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[allow(clippy::single_range_in_vec_init)]
+    async fn test_delete_chunks_in_range() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array: Path = "/array".try_into().unwrap();
+        let metadata = ZarrArrayMetadata {
+            shape: vec![4, 2],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![
+                NonZeroU64::new(1).unwrap(),
+                NonZeroU64::new(1).unwrap(),
+            ]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            rectilinear_grid: None,
+        };
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), metadata.clone()).await?;
+        for (row, column) in (0..4).cartesian_product(0..2) {
+            let payload = Some(ChunkPayload::Inline("hello".into()));
+            ds.set_chunk_ref(array.clone(), ChunkIndices(vec![row, column]), payload)
+                .await?;
+        }
+        ds.commit(Ref::DEFAULT_BRANCH, "write", None).await?;
+
+        let payload = Some(ChunkPayload::Inline("again".into()));
+        ds.set_chunk_ref(array.clone(), ChunkIndices(vec![3, 0]), payload).await?;
+        assert!(matches!(
+            ds.delete_chunks_in_range(&array, &ChunkRegion(vec![0..1]), false).await,
+            Err(RepositoryError::FormatError(IcechunkFormatError::SelectionRank { .. }))
+        ));
+        // the last two rows, the array ends after the second one
+        let deleted = ds
+            .delete_chunks_in_range(&array, &ChunkRegion(vec![2..4, 0..2]), true)
+            .await?;
+        assert_eq!(deleted, 4);
+        // the first row is deleted, but the array keeps its shape
+        let deleted = ds
+            .delete_chunks_in_range(&array, &ChunkRegion(vec![0..1, 0..2]), true)
+            .await?;
+        assert_eq!(deleted, 2);
+        ds.commit(Ref::DEFAULT_BRANCH, "delete", None).await?;
+
+        let chunks: Vec<_> = ds.chunk_coords_iter(&array).await?.try_collect().await?;
+        assert_eq!(
+            chunks.into_iter().sorted().collect::<Vec<_>>(),
+            vec![ChunkIndices(vec![1, 0]), ChunkIndices(vec![1, 1])]
+        );
+        assert_eq!(
+            ds.get_array(&array).await?.zarr_metadata(),
+            Some(&ZarrArrayMetadata { shape: vec![2, 2], ..metadata })
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_chunk_ref_fetches_no_chunks() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let storage: Arc<dyn Storage> = logging.clone();
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(0)
+            .build();
+        let path: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(path.clone(), vector_metadata(4, 2, FillValue::UInt8(0))).await?;
+        let payload = ds.get_chunk_writer()(Bytes::from_static(b"ab")).await?;
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![0]), Some(payload.clone()))
+            .await?;
+        let virtual_ref = ChunkPayload::Virtual(VirtualChunkRef {
+            location: VirtualChunkLocation::from_absolute_path("s3://bucket/file")?,
+            offset: 4,
+            length: 2,
+        });
+        ds.set_chunk_ref(path.clone(), ChunkIndices(vec![1]), Some(virtual_ref.clone()))
+            .await?;
+        let snapshot = ds.commit("main", "chunks", None).await?;
+
+        let ds = Repository::update(Arc::clone(&storage), snapshot).build();
+        assert!(matches!(
+            ds.get_chunk_ref(&path, &ChunkIndices(vec![0])).await?,
+            Some(ChunkPayload::Ref(ChunkRef { length: 2, .. }))
+        ));
+        assert_eq!(ds.get_chunk_ref(&path, &ChunkIndices(vec![0])).await?, Some(payload));
+        assert_eq!(
+            ds.get_chunk_ref(&path, &ChunkIndices(vec![1])).await?,
+            Some(virtual_ref)
+        );
+        // only the write of the chunk reached storage
+        assert!(logging.fetch_operations().iter().all(|(op, _)| op != "fetch_chunk"));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_scratch_storage() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("primary".into())));
+        let scratch: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("scratch".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_scratch_storage(Arc::clone(&scratch))
+            .with_inline_threshold_bytes(0)
+            .build();
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(2, 1, FillValue::UInt8(0))).await?;
+        let data = Bytes::from(vec![7; 100]);
+        let mut ids = Vec::new();
+        for index in 0..2 {
+            let payload = ds.get_chunk_writer()(data.clone()).await?;
+            let ChunkPayload::Ref(ChunkRef { id, .. }) = &payload else {
+                panic!("chunk should not be inline")
+            };
+            ids.push(id.clone());
+            ds.set_chunk_ref(array.clone(), ChunkIndices(vec![index]), Some(payload))
+                .await?;
+        }
+        assert!(storage.fetch_chunk(&ids[0], &ByteRange::ALL).await.is_err());
+        let reader =
+            ds.get_chunk_reader(&array, &ChunkIndices(vec![0]), &ByteRange::ALL).await?;
+        assert_eq!(get_chunk(reader).await?, Some(data.clone()));
+
+        // the first chunks move early, the one written again moves on commit
+        assert_eq!(ds.move_scratch_chunks().await?, 2);
+        assert_eq!(ds.move_scratch_chunks().await?, 0);
+        let payload = ds.get_chunk_writer()(data.clone()).await?;
+        ds.set_chunk_ref(array.clone(), ChunkIndices(vec![1]), Some(payload.clone()))
+            .await?;
+        ds.commit(Ref::DEFAULT_BRANCH, "write", None).await?;
+        let ChunkPayload::Ref(ChunkRef { id, .. }) = payload else {
+            panic!("chunk should not be inline")
+        };
+        for id in ids.iter().chain([&id]) {
+            assert_eq!(storage.fetch_chunk(id, &ByteRange::ALL).await?, data);
+            assert!(scratch.fetch_chunk(id, &ByteRange::ALL).await.is_err());
+        }
+        let reader =
+            ds.get_chunk_reader(&array, &ChunkIndices(vec![1]), &ByteRange::ALL).await?;
+        assert_eq!(get_chunk(reader).await?, Some(data));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_split_writers() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(8, 1, FillValue::UInt8(0))).await?;
+        ds.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![0]),
+            Some(ChunkPayload::Inline("before".into())),
+        )
+        .await?;
+
+        let writers = ds.split_writers(2).await?;
+        let tasks = writers.into_iter().enumerate().map(|(index, mut writer)| {
+            let array = array.clone();
+            tokio::spawn(async move {
+                let group: Path = format!("/group{index}").try_into().unwrap();
+                writer.add_group(group).await?;
+                for coord in (1..8).skip(index).step_by(2) {
+                    let payload = writer.get_chunk_writer()(Bytes::from(vec![7; 100]));
+                    writer
+                        .set_chunk_ref(
+                            array.clone(),
+                            ChunkIndices(vec![coord]),
+                            Some(payload.await?),
+                        )
+                        .await?;
+                }
+                Ok::<_, RepositoryError>(writer)
+            })
+        });
+        let writers = futures::future::try_join_all(tasks)
+            .await?
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()?;
+        // `self` can keep writing while the writers do
+        ds.add_group("/group".try_into().unwrap()).await?;
+        ds.merge_writers(writers)?;
+        ds.commit(Ref::DEFAULT_BRANCH, "split", None).await?;
+
+        let nodes: Vec<_> = ds.list_nodes().await?.collect();
+        assert_eq!(nodes.len(), 5);
+        assert_eq!(nodes.iter().map(|node| node.id).unique().count(), 5);
+        let chunks: Vec<_> = ds.all_chunks().await?.try_collect().await?;
+        assert_eq!(chunks.len(), 8);
+        assert_eq!(
+            ds.get_chunk_ref(&array, &ChunkIndices(vec![0])).await?,
+            Some(ChunkPayload::Inline("before".into()))
+        );
+
+        let stranger =
+            Repository::update(Arc::clone(&storage), ds.snapshot_id().clone()).build();
+        assert!(matches!(
+            ds.merge_writers([stranger]),
+            Err(RepositoryError::InvalidWriter { .. })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_cleanup_on_drop() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        let snapshot_id = repo.snapshot_id().clone();
+
+        let upload = |cleanup_on_drop| {
+            let storage = Arc::clone(&storage);
+            let snapshot_id = snapshot_id.clone();
+            async move {
+                let ds = Repository::update(storage, snapshot_id)
+                    .with_inline_threshold_bytes(0)
+                    .with_cleanup_on_drop(cleanup_on_drop)
+                    .build();
+                match ds.get_chunk_writer()(Bytes::from_static(b"hello")).await {
+                    Ok(ChunkPayload::Ref(ChunkRef { id, .. })) => id,
+                    _ => panic!("expected a materialized chunk"),
+                }
+            }
+        };
+
+        let kept = upload(false).await;
+        let deleted = upload(true).await;
+
+        let mut attempts = 0;
+        while storage.fetch_chunk(&deleted, &ByteRange::ALL).await.is_ok() {
+            attempts += 1;
+            assert!(attempts < 100, "chunk was not deleted on drop");
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert!(storage.fetch_chunk(&kept, &ByteRange::ALL).await.is_ok());
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_commit_and_refs() -> Result<(), Box<dyn Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
         let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
 
@@ -2111,6 +7083,7 @@ mod tests {
                 configuration: None,
             }]),
             dimension_names: Some(vec![Some("t".to_string())]),
+            rectilinear_grid: None,
         };
 
         let new_array_path: Path = "/array1".try_into().unwrap();
@@ -2142,7 +7115,7 @@ mod tests {
 
     #[tokio::test(flavor = "multi_thread")]
     async fn test_no_double_commit() -> Result<(), Box<dyn Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
         let _ = Repository::init(Arc::clone(&storage), false).await?;
         let mut ds1 =
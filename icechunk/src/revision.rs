@@ -0,0 +1,362 @@
+//! Ref expressions, short names for snapshots.
+//!
+//! An expression names a snapshot starting from a branch, a tag or a snapshot id, and
+//! optionally walks back its history:
+//!
+//! - `main`, a tag or branch name, tags are tried first
+//! - `branch:main`, `tag:v1`, only the given kind of ref
+//! - `snapshot:4QZ8`, or just `4QZ8`, the snapshot whose id starts with the prefix
+//! - `main~3`, the third parent, `main~` and `main^` are the first parent, suffixes
+//!   can be repeated as in `v1^^`
+//!
+//! Short id prefixes are resolved listing the snapshots of the repository, full ids are
+//! used as they are.
+use std::{fmt, str::FromStr};
+
+use futures::TryStreamExt;
+use thiserror::Error;
+
+use crate::{
+    error::ErrorKind,
+    format::SnapshotId,
+    refs::{fetch_branch_tip, fetch_ref, fetch_tag, Ref, RefError},
+    storage::{ObjectKind, ObjectLocation},
+    Storage, StorageError,
+};
+
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum RevisionError {
+    #[error("invalid ref expression `{expression}`: {message}")]
+    Invalid { expression: String, message: &'static str },
+    #[error("no tag, branch or snapshot matches `{0}`")]
+    NotFound(String),
+    #[error("snapshot id prefix `{prefix}` is ambiguous, it matches {} snapshots", .matches.len())]
+    AmbiguousPrefix { prefix: String, matches: Vec<SnapshotId> },
+    #[error(
+        "`{expression}` has {ancestors} ancestors, the history only has {available}"
+    )]
+    HistoryTooShort { expression: String, ancestors: usize, available: usize },
+    #[error("ref error {0}")]
+    Ref(#[from] RefError),
+    #[error("error contacting storage {0}")]
+    Storage(#[from] StorageError),
+}
+
+impl RevisionError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            RevisionError::Invalid { .. } | RevisionError::AmbiguousPrefix { .. } => {
+                ErrorKind::InvalidRequest
+            }
+            RevisionError::NotFound(_) | RevisionError::HistoryTooShort { .. } => {
+                ErrorKind::NotFound
+            }
+            RevisionError::Ref(err) => err.kind(),
+            RevisionError::Storage(err) => err.kind(),
+        }
+    }
+}
+
+pub type RevisionResult<A> = Result<A, RevisionError>;
+
+/// Where an expression starts
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RevisionBase {
+    /// A tag, a branch or a snapshot id prefix, tried in that order
+    Name(String),
+    Branch(String),
+    Tag(String),
+    /// A snapshot id or a prefix of it
+    Snapshot(String),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RefExpression {
+    pub base: RevisionBase,
+    /// How many parents to walk back from the base
+    pub ancestors: usize,
+}
+
+/// The snapshot an expression points to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ResolvedRef {
+    pub snapshot: SnapshotId,
+    /// The branch, if the expression is the tip of a branch
+    pub branch: Option<String>,
+}
+
+// shorter prefixes would match too many snapshots to be useful
+const MIN_PREFIX_LEN: usize = 4;
+const CROCKFORD: &str = "0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+fn is_id_prefix(s: &str) -> bool {
+    s.len() >= MIN_PREFIX_LEN
+        && s.chars().all(|c| CROCKFORD.contains(c.to_ascii_uppercase()))
+}
+
+impl FromStr for RefExpression {
+    type Err = RevisionError;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let invalid = |message| RevisionError::Invalid {
+            expression: expression.to_string(),
+            message,
+        };
+        let split = expression.find(['~', '^']).unwrap_or(expression.len());
+        let (base, mut suffixes) = expression.split_at(split);
+
+        let base = match base.split_once(':') {
+            None => RevisionBase::Name(base.to_string()),
+            Some(("branch", name)) => RevisionBase::Branch(name.to_string()),
+            Some(("tag", name)) => RevisionBase::Tag(name.to_string()),
+            Some(("snapshot", id)) if is_id_prefix(id) => {
+                RevisionBase::Snapshot(id.to_ascii_uppercase())
+            }
+            Some(("snapshot", _)) => {
+                return Err(invalid(
+                    "snapshot ids need at least 4 Crockford base32 characters",
+                ))
+            }
+            Some(_) => {
+                return Err(invalid("the kind of ref must be branch, tag or snapshot"))
+            }
+        };
+        if let RevisionBase::Name(name)
+        | RevisionBase::Branch(name)
+        | RevisionBase::Tag(name) = &base
+        {
+            if name.is_empty() {
+                return Err(invalid("the ref name is empty"));
+            }
+        }
+
+        let mut ancestors = 0usize;
+        while let Some(c) = suffixes.chars().next() {
+            if !matches!(c, '~' | '^') {
+                return Err(invalid("only `~` and `^` can follow the ref"));
+            }
+            suffixes = &suffixes[1..];
+            // `~` can be followed by a number of ancestors, `^` is always one
+            let digits = if c == '~' {
+                suffixes.find(|c: char| !c.is_ascii_digit()).unwrap_or(suffixes.len())
+            } else {
+                0
+            };
+            let count = match &suffixes[..digits] {
+                "" => 1,
+                n => n
+                    .parse()
+                    .map_err(|_| invalid("the number of ancestors is too large"))?,
+            };
+            suffixes = &suffixes[digits..];
+            ancestors = ancestors
+                .checked_add(count)
+                .ok_or_else(|| invalid("the number of ancestors is too large"))?;
+        }
+        Ok(Self { base, ancestors })
+    }
+}
+
+impl fmt::Display for RefExpression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.base {
+            RevisionBase::Name(name) => write!(f, "{name}")?,
+            RevisionBase::Branch(name) => write!(f, "branch:{name}")?,
+            RevisionBase::Tag(name) => write!(f, "tag:{name}")?,
+            RevisionBase::Snapshot(id) => write!(f, "snapshot:{id}")?,
+        }
+        if self.ancestors > 0 {
+            write!(f, "~{}", self.ancestors)?;
+        }
+        Ok(())
+    }
+}
+
+impl RefExpression {
+    pub async fn resolve(&self, storage: &dyn Storage) -> RevisionResult<ResolvedRef> {
+        let (snapshot, branch) = match &self.base {
+            RevisionBase::Branch(name) => {
+                (fetch_branch_tip(storage, name).await?.snapshot, Some(name.clone()))
+            }
+            RevisionBase::Tag(name) => (fetch_tag(storage, name).await?.snapshot, None),
+            RevisionBase::Snapshot(prefix) => {
+                (find_snapshot(storage, prefix).await?, None)
+            }
+            RevisionBase::Name(name) => match fetch_ref(storage, name).await {
+                Ok((Ref::Branch(branch), data)) => (data.snapshot, Some(branch)),
+                Ok((_, data)) => (data.snapshot, None),
+                Err(RefError::RefNotFound(_)) if is_id_prefix(name) => {
+                    (find_snapshot(storage, &name.to_ascii_uppercase()).await?, None)
+                }
+                Err(RefError::RefNotFound(_)) => {
+                    return Err(RevisionError::NotFound(name.clone()))
+                }
+                Err(err) => return Err(err.into()),
+            },
+        };
+        if self.ancestors == 0 {
+            return Ok(ResolvedRef { snapshot, branch });
+        }
+
+        let history = &storage.fetch_snapshot(&snapshot).await?.short_term_history;
+        match history.get(self.ancestors - 1) {
+            Some(ancestor) => {
+                Ok(ResolvedRef { snapshot: ancestor.id.clone(), branch: None })
+            }
+            None => Err(RevisionError::HistoryTooShort {
+                expression: self.to_string(),
+                ancestors: self.ancestors,
+                available: history.len(),
+            }),
+        }
+    }
+}
+
+/// Resolve `expression` to a snapshot in `storage`
+pub async fn resolve(
+    storage: &dyn Storage,
+    expression: &str,
+) -> RevisionResult<ResolvedRef> {
+    expression.parse::<RefExpression>()?.resolve(storage).await
+}
+
+async fn find_snapshot(
+    storage: &dyn Storage,
+    prefix: &str,
+) -> RevisionResult<SnapshotId> {
+    let full_len = SnapshotId::random().to_string().len();
+    if prefix.len() == full_len {
+        if let Ok(id) = SnapshotId::try_from(prefix) {
+            return Ok(id);
+        }
+    }
+    let matches: Vec<SnapshotId> = storage
+        .list_objects(ObjectKind::Snapshot, ObjectLocation::Live)
+        .await?
+        .try_filter_map(|info| async move {
+            Ok(info
+                .id
+                .starts_with(prefix)
+                .then(|| SnapshotId::try_from(info.id.as_str()).ok())
+                .flatten())
+        })
+        .try_collect()
+        .await?;
+    match <[SnapshotId; 1]>::try_from(matches) {
+        Ok([id]) => Ok(id),
+        Err(matches) if matches.is_empty() => {
+            Err(RevisionError::NotFound(prefix.to_string()))
+        }
+        Err(matches) => {
+            Err(RevisionError::AmbiguousPrefix { prefix: prefix.to_string(), matches })
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::sync::Arc;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{format::Path, ObjectStorage, Repository};
+
+    #[test]
+    fn test_parse_ref_expressions() {
+        let parse = |s: &str| s.parse::<RefExpression>().unwrap();
+        let name = |n: &str| RevisionBase::Name(n.to_string());
+        assert_eq!(parse("main"), RefExpression { base: name("main"), ancestors: 0 });
+        assert_eq!(parse("main~3"), RefExpression { base: name("main"), ancestors: 3 });
+        assert_eq!(parse("main~"), RefExpression { base: name("main"), ancestors: 1 });
+        assert_eq!(parse("v1^^~2"), RefExpression { base: name("v1"), ancestors: 4 });
+        assert_eq!(
+            parse("tag:v1^"),
+            RefExpression { base: RevisionBase::Tag("v1".to_string()), ancestors: 1 }
+        );
+        assert_eq!(
+            parse("snapshot:4qz8"),
+            RefExpression {
+                base: RevisionBase::Snapshot("4QZ8".to_string()),
+                ancestors: 0
+            }
+        );
+        assert_eq!(parse("branch:dev~2").to_string(), "branch:dev~2");
+
+        for bad in
+            ["", "~1", "tag:", "remote:main", "snapshot:4Q", "snapshot:ABCU", "main~1x"]
+        {
+            assert!(
+                matches!(
+                    bad.parse::<RefExpression>(),
+                    Err(RevisionError::Invalid { .. })
+                ),
+                "{bad}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ref_expressions() {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let mut repo =
+            Repository::init(Arc::clone(&storage), false).await.unwrap().build();
+        let initial = repo.snapshot_id().clone();
+        let mut commits = vec![initial.clone()];
+        for ix in 0..3 {
+            repo.add_group(format!("/group{ix}").try_into().unwrap()).await.unwrap();
+            commits.push(repo.commit(Ref::DEFAULT_BRANCH, "commit", None).await.unwrap());
+        }
+        repo.tag("v1", &commits[2]).await.unwrap();
+
+        let storage = storage.as_ref();
+        assert_eq!(
+            resolve(storage, "main").await.unwrap(),
+            ResolvedRef {
+                snapshot: commits[3].clone(),
+                branch: Some("main".to_string())
+            }
+        );
+        assert_eq!(resolve(storage, "main~3").await.unwrap().snapshot, initial);
+        assert_eq!(resolve(storage, "main^").await.unwrap().branch, None);
+        assert_eq!(resolve(storage, "v1").await.unwrap().snapshot, commits[2]);
+        assert_eq!(resolve(storage, "tag:v1^").await.unwrap().snapshot, commits[1]);
+        assert!(matches!(
+            resolve(storage, "main~4").await,
+            Err(RevisionError::HistoryTooShort { ancestors: 4, available: 3, .. })
+        ));
+        assert!(matches!(
+            resolve(storage, "branch:v1").await,
+            Err(RevisionError::Ref(_))
+        ));
+        assert!(matches!(resolve(storage, "dev").await, Err(RevisionError::NotFound(_))));
+
+        let full = commits[1].to_string();
+        let mut prefix_len = MIN_PREFIX_LEN;
+        while commits
+            .iter()
+            .filter(|id| id.to_string().starts_with(&full[..prefix_len]))
+            .count()
+            > 1
+        {
+            prefix_len += 1;
+        }
+        let short = full[..prefix_len].to_lowercase();
+        assert_eq!(resolve(storage, &short).await.unwrap().snapshot, commits[1]);
+        let full = format!("snapshot:{full}~1");
+        assert_eq!(resolve(storage, &full).await.unwrap().snapshot, commits[0]);
+
+        let mut repo =
+            Repository::from_ref_expression(Arc::clone(repo.storage()), "main~2")
+                .await
+                .unwrap()
+                .build();
+        assert_eq!(repo.snapshot_id(), &commits[1]);
+        assert!(repo.get_node(&Path::try_from("/group1").unwrap()).await.is_err());
+        repo.add_group("/other".try_into().unwrap()).await.unwrap();
+    }
+}
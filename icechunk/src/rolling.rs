@@ -0,0 +1,198 @@
+//! Arrays that keep only their latest steps.
+//!
+//! Forecast archives and monitoring data grow along one axis, and only the latest steps
+//! are worth keeping. A [`RollingArray`] appends to such an array and, in the same
+//! commit, deletes the chunks that fall out of the retention window and records in an
+//! attribute of the array the first step still stored. Chunks are never moved: the array
+//! keeps growing, and readers start reading at the offset in the attribute.
+use crate::{
+    format::{
+        manifest::ChunkPayload,
+        snapshot::{NodeData, UserAttributesSnapshot},
+        ChunkIndices, Path, SnapshotId,
+    },
+    metadata::{Axis, UserAttributes},
+    repository::{resolve_user_attributes, RepositoryError, RepositoryResult},
+    Repository,
+};
+
+/// The attribute the offset is written to, unless set with
+/// [`RollingArray::with_offset_attribute`]
+pub const DEFAULT_OFFSET_ATTRIBUTE: &str = "rolling_offset";
+
+/// An array that keeps the last `keep` steps along `axis`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RollingArray {
+    pub path: Path,
+    pub axis: Axis,
+    pub keep: u64,
+    pub offset_attribute: String,
+}
+
+/// The result of a [`RollingArray::append`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RollingAppend {
+    pub snapshot: SnapshotId,
+    /// The first step still stored along the axis
+    pub offset: u64,
+    /// The size of the array along the axis
+    pub size: u64,
+    pub dropped_chunks: usize,
+}
+
+impl RollingArray {
+    /// `axis` is an index or a dimension name, like `"time"`
+    pub fn new(path: Path, axis: impl Into<Axis>, keep: u64) -> Self {
+        Self {
+            path,
+            axis: axis.into(),
+            keep,
+            offset_attribute: DEFAULT_OFFSET_ATTRIBUTE.to_string(),
+        }
+    }
+
+    pub fn with_offset_attribute(mut self, name: impl Into<String>) -> Self {
+        self.offset_attribute = name.into();
+        self
+    }
+
+    /// Append `len` steps, stored in the already written `chunks`, and commit to
+    /// `branch` together with the deletion of the chunks older than the retention window.
+    ///
+    /// Whole chunks are deleted, so at least `keep` steps are kept. The repository must
+    /// not have other uncommitted changes. On failure nothing is committed, and the
+    /// staged changes can be discarded with [`Repository::abort`].
+    pub async fn append(
+        &self,
+        repository: &mut Repository,
+        branch: &str,
+        len: u64,
+        chunks: impl IntoIterator<Item = (ChunkIndices, ChunkPayload)>,
+        message: &str,
+    ) -> RepositoryResult<RollingAppend> {
+        if repository.has_uncommitted_changes() {
+            return Err(RepositoryError::UncommittedChanges);
+        }
+        let node = repository.get_array(&self.path).await?;
+        let NodeData::Array(metadata, _) = &node.node_data else {
+            return Err(RepositoryError::NotAnArray {
+                node: Box::new(node),
+                message: "appending to a rolling array".to_string(),
+            });
+        };
+        let axis = metadata.axis_index(self.axis.clone())?;
+        let metadata = metadata.after_append(axis, len)?;
+        let size = metadata.shape[axis];
+        let spans =
+            metadata.axis_chunk_spans(axis, &(size.saturating_sub(self.keep)..size))?;
+        // keeping no steps drops every chunk
+        let (first_chunk, offset) = spans.first().map_or_else(
+            || (metadata.chunk_grid_shape()[axis], size),
+            |(index, span)| (*index, span.start),
+        );
+
+        let region = metadata.region_along(axis, 0..first_chunk)?;
+        repository.update_array(node.path.clone(), metadata).await?;
+        for (coords, payload) in chunks {
+            repository.set_chunk_ref(node.path.clone(), coords, Some(payload)).await?;
+        }
+        let dropped_chunks =
+            repository.delete_chunks_in_range(&node.path, &region, false).await?;
+
+        let node = repository.get_array(&self.path).await?;
+        let atts =
+            resolve_user_attributes(repository.storage().as_ref(), node.user_attributes)
+                .await?;
+        let mut atts = match atts {
+            Some(UserAttributesSnapshot::Inline(atts)) => atts.parsed,
+            _ => serde_json::Value::Null,
+        };
+        // zarr attributes are always objects
+        if !atts.is_object() {
+            atts = serde_json::Value::Object(Default::default());
+        }
+        if let Some(object) = atts.as_object_mut() {
+            object.insert(self.offset_attribute.clone(), offset.into());
+        }
+        repository
+            .set_user_attributes(self.path.clone(), Some(UserAttributes { parsed: atts }))
+            .await?;
+
+        let snapshot = repository.commit(branch, message, None).await?;
+        Ok(RollingAppend { snapshot, offset, size, dropped_chunks })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, num::NonZeroU64, sync::Arc};
+
+    use futures::TryStreamExt;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::snapshot::ZarrArrayMetadata,
+        metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
+        refs::Ref,
+        ObjectStorage, Storage,
+    };
+
+    #[tokio::test]
+    async fn test_rolling_append() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        let path: Path = "/forecast".try_into().unwrap();
+        repo.add_group(Path::root()).await?;
+        repo.add_array(
+            path.clone(),
+            ZarrArrayMetadata {
+                shape: vec![0],
+                data_type: DataType::UInt8,
+                chunk_shape: ChunkShape(vec![NonZeroU64::new(2).unwrap()]),
+                chunk_key_encoding: ChunkKeyEncoding::Slash,
+                fill_value: FillValue::UInt8(0),
+                codecs: vec![],
+                storage_transformers: None,
+                dimension_names: Some(vec![Some("time".to_string())]),
+                rectilinear_grid: None,
+            },
+        )
+        .await?;
+        repo.commit(Ref::DEFAULT_BRANCH, "create", None).await?;
+
+        let rolling = RollingArray::new(path.clone(), "time", 4);
+        let mut last = None;
+        for step in 0..3 {
+            let chunk = (ChunkIndices(vec![step]), ChunkPayload::Inline("ab".into()));
+            let append = rolling
+                .append(&mut repo, Ref::DEFAULT_BRANCH, 2, [chunk], "append")
+                .await?;
+            last = Some(append);
+        }
+        let last = last.unwrap();
+        assert_eq!((last.offset, last.size, last.dropped_chunks), (2, 6, 1));
+        assert_eq!(repo.snapshot_id(), &last.snapshot);
+
+        let chunks: Vec<_> = repo.chunk_coords_iter(&path).await?.try_collect().await?;
+        assert_eq!(chunks.len(), 2);
+        assert!(!chunks.contains(&ChunkIndices(vec![0])));
+        let node = repo.get_array(&path).await?;
+        assert_eq!(node.zarr_metadata().map(|meta| meta.shape.clone()), Some(vec![6]));
+        assert_eq!(
+            resolve_user_attributes(storage.as_ref(), node.user_attributes).await?,
+            Some(UserAttributesSnapshot::Inline(UserAttributes {
+                parsed: serde_json::json!({ "rolling_offset": 2 })
+            }))
+        );
+
+        repo.add_group("/other".try_into().unwrap()).await?;
+        assert!(matches!(
+            rolling.append(&mut repo, Ref::DEFAULT_BRANCH, 2, [], "append").await,
+            Err(RepositoryError::UncommittedChanges)
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,111 @@
+//! The async runtime used for background work.
+//!
+//! Icechunk futures don't depend on a specific executor, the few operations that need
+//! one, like spawning the cleanup of a dropped [`crate::Repository`] or timeouts, go
+//! through a [`Runtime`]. [`TokioRuntime`] is the default with the `tokio-runtime`
+//! feature, other executors, like async-std or the browser event loop in WASM, can be
+//! used by implementing the trait.
+use std::{fmt, future::Future, time::Duration};
+
+use futures::future::{self, BoxFuture, Either};
+use thiserror::Error;
+
+pub trait Runtime: fmt::Debug + Send + Sync {
+    /// Run `future` in the background, without waiting for it to complete
+    fn spawn(&self, future: BoxFuture<'static, ()>);
+
+    /// A future that completes once `duration` has passed
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// Runs background work in the current Tokio runtime.
+///
+/// Work spawned outside of a Tokio runtime is dropped.
+#[cfg(feature = "tokio-runtime")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioRuntime;
+
+#[cfg(feature = "tokio-runtime")]
+impl Runtime for TokioRuntime {
+    fn spawn(&self, future: BoxFuture<'static, ()>) {
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(future);
+        }
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// A runtime without an executor or timers.
+///
+/// Spawned work is dropped and timeouts never expire.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoRuntime;
+
+impl Runtime for NoRuntime {
+    fn spawn(&self, _future: BoxFuture<'static, ()>) {}
+
+    fn sleep(&self, _duration: Duration) -> BoxFuture<'static, ()> {
+        Box::pin(future::pending())
+    }
+}
+
+/// The runtime used when none is configured
+#[cfg(feature = "tokio-runtime")]
+pub type DefaultRuntime = TokioRuntime;
+#[cfg(not(feature = "tokio-runtime"))]
+pub type DefaultRuntime = NoRuntime;
+
+#[derive(Debug, Error, Clone, Copy, PartialEq, Eq)]
+#[error("operation timed out after {0:?}")]
+pub struct Elapsed(pub Duration);
+
+/// Wait for `future` at most `duration`, using the timers of `runtime`
+pub async fn timeout<F: Future>(
+    runtime: &dyn Runtime,
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    match future::select(std::pin::pin!(future), runtime.sleep(duration)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(((), _)) => Err(Elapsed(duration)),
+    }
+}
+
+#[cfg(all(test, feature = "tokio-runtime"))]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let runtime = TokioRuntime;
+        let slow = timeout(&runtime, Duration::from_millis(10), future::pending::<()>());
+        assert_eq!(slow.await, Err(Elapsed(Duration::from_millis(10))));
+        let fast = timeout(&runtime, Duration::from_secs(10), future::ready(42));
+        assert_eq!(fast.await, Ok(42));
+
+        // without timers the future always wins
+        let ready = timeout(&NoRuntime, Duration::ZERO, future::ready(1));
+        assert_eq!(ready.await, Ok(1));
+    }
+
+    #[tokio::test]
+    async fn test_spawn() {
+        let done = Arc::new(AtomicBool::new(false));
+        let done_c = Arc::clone(&done);
+        TokioRuntime
+            .spawn(Box::pin(async move { done_c.store(true, Ordering::Relaxed) }));
+        TokioRuntime.sleep(Duration::from_millis(50)).await;
+        assert!(done.load(Ordering::Relaxed));
+    }
+}
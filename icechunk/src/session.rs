@@ -0,0 +1,158 @@
+//! Read sessions that follow a branch.
+//!
+//! A [`Repository`] reads a fixed snapshot. Long-lived readers, like dashboards, usually
+//! want to see new commits to a branch, but not in the middle of a computation. A
+//! [`ReadSession`] wraps a repository opened on a branch, and its [`RefreshPolicy`]
+//! decides when it moves to the new tip of the branch.
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::{
+    format::SnapshotId,
+    repository::{RepositoryError, RepositoryResult},
+    Repository, Storage,
+};
+
+/// When a [`ReadSession`] moves to the tip of its branch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RefreshPolicy {
+    /// Never, the session always reads the snapshot it was opened on
+    Pinned,
+    /// On reads, if the last refresh is older than the interval
+    Poll(Duration),
+    /// Only when [`ReadSession::update`] is called
+    OnDemand,
+}
+
+#[derive(Debug)]
+pub struct ReadSession {
+    repository: Repository,
+    branch: String,
+    policy: RefreshPolicy,
+    refreshed_at: DateTime<Utc>,
+}
+
+impl ReadSession {
+    /// Follow `branch` with `repository`, that must not have uncommitted changes.
+    ///
+    /// The repository keeps reading its current snapshot until the first refresh.
+    pub fn new(
+        repository: Repository,
+        branch: impl Into<String>,
+        policy: RefreshPolicy,
+    ) -> RepositoryResult<Self> {
+        if repository.has_uncommitted_changes() {
+            return Err(RepositoryError::UncommittedChanges);
+        }
+        let refreshed_at = repository.clock().now();
+        Ok(Self { repository, branch: branch.into(), policy, refreshed_at })
+    }
+
+    /// Open a session on the tip of `branch`, with the default configuration
+    pub async fn open(
+        storage: Arc<dyn Storage>,
+        branch: &str,
+        policy: RefreshPolicy,
+    ) -> RepositoryResult<Self> {
+        let repository = Repository::from_branch_tip(storage, branch).await?.build();
+        Self::new(repository, branch, policy)
+    }
+
+    pub fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    pub fn policy(&self) -> RefreshPolicy {
+        self.policy
+    }
+
+    /// The snapshot the session reads now
+    pub fn snapshot_id(&self) -> &SnapshotId {
+        self.repository.snapshot_id()
+    }
+
+    /// The repository to read from, after moving to the tip of the branch if the
+    /// policy is [`RefreshPolicy::Poll`] and the interval elapsed
+    pub async fn repository(&mut self) -> RepositoryResult<&Repository> {
+        if let RefreshPolicy::Poll(interval) = self.policy {
+            let interval =
+                TimeDelta::from_std(interval).unwrap_or(TimeDelta::max_value());
+            if self.repository.clock().now() - self.refreshed_at >= interval {
+                self.refresh().await?;
+            }
+        }
+        Ok(&self.repository)
+    }
+
+    /// Move to the tip of the branch now, unless the session is
+    /// [`RefreshPolicy::Pinned`]. Returns true if the session now reads a different
+    /// snapshot.
+    pub async fn update(&mut self) -> RepositoryResult<bool> {
+        if self.policy == RefreshPolicy::Pinned {
+            return Ok(false);
+        }
+        let previous = self.snapshot_id().clone();
+        self.refresh().await?;
+        Ok(self.snapshot_id() != &previous)
+    }
+
+    async fn refresh(&mut self) -> RepositoryResult<()> {
+        self.repository.set_snapshot_from_branch(&self.branch).await?;
+        self.refreshed_at = self.repository.clock().now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{clock::FixedClock, format::Path, ObjectStorage};
+
+    #[tokio::test]
+    async fn test_refresh_policy() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut writer = Repository::init(Arc::clone(&storage), false).await?.build();
+        writer.add_group(Path::root()).await?;
+        let first = writer.commit("main", "first", None).await?;
+
+        let clock = Arc::new(FixedClock::new(Utc::now()));
+        let polling = Repository::from_branch_tip(Arc::clone(&storage), "main")
+            .await?
+            .with_clock(clock.clone())
+            .build();
+        let mut polling = ReadSession::new(
+            polling,
+            "main",
+            RefreshPolicy::Poll(Duration::from_secs(60)),
+        )?;
+        let mut pinned =
+            ReadSession::open(Arc::clone(&storage), "main", RefreshPolicy::Pinned)
+                .await?;
+        let mut on_demand =
+            ReadSession::open(Arc::clone(&storage), "main", RefreshPolicy::OnDemand)
+                .await?;
+
+        writer.add_group("/group".try_into().unwrap()).await?;
+        let second = writer.commit("main", "second", None).await?;
+
+        assert_eq!(polling.repository().await?.snapshot_id(), &first);
+        clock.advance(TimeDelta::seconds(60));
+        assert_eq!(polling.repository().await?.snapshot_id(), &second);
+
+        assert!(!pinned.update().await?);
+        assert_eq!(pinned.repository().await?.snapshot_id(), &first);
+
+        assert_eq!(on_demand.repository().await?.snapshot_id(), &first);
+        assert!(on_demand.update().await?);
+        assert!(!on_demand.update().await?);
+        assert_eq!(on_demand.snapshot_id(), &second);
+        Ok(())
+    }
+}
@@ -0,0 +1,209 @@
+//! Streaming chunks into a repository.
+//!
+//! A [`ChunkSink`] is a [`futures::Sink`] of `(path, coordinates, bytes)` that uploads
+//! the chunks it's sent and sets their references in the repository it borrows. At most
+//! `max_in_flight` chunks are uploaded at once, the sink isn't ready for more until one
+//! of them is done, so ingestion pipelines built on streams slow down to the speed of
+//! the object store without managing their own semaphores:
+//!
+//! ```ignore
+//! chunks.forward(ChunkSink::new(&mut repository, 16)).await?;
+//! repository.commit("main", "ingest", None).await?;
+//! ```
+//!
+//! Uploads still wait for the [`crate::memory::MemoryBudget`] of the repository, if it
+//! has one.
+use std::{
+    mem,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures::{
+    future::BoxFuture, stream::FuturesUnordered, FutureExt, Sink, StreamExt, TryFutureExt,
+};
+
+use crate::{
+    format::{manifest::ChunkPayload, ChunkIndices, Path},
+    repository::{RepositoryError, RepositoryResult},
+    Repository,
+};
+
+type Writer = Box<
+    dyn Fn(Bytes) -> BoxFuture<'static, RepositoryResult<ChunkPayload>> + Send + Sync,
+>;
+type Uploaded = (Path, ChunkIndices, ChunkPayload);
+
+pub struct ChunkSink<'a> {
+    // taken by `applying` while references are set
+    repository: Option<&'a mut Repository>,
+    writer: Writer,
+    max_in_flight: usize,
+    uploads: FuturesUnordered<BoxFuture<'static, RepositoryResult<Uploaded>>>,
+    uploaded: Vec<Uploaded>,
+    applying: Option<BoxFuture<'a, (&'a mut Repository, RepositoryResult<()>)>>,
+}
+
+impl<'a> ChunkSink<'a> {
+    /// Upload at most `max_in_flight` chunks concurrently, at least one
+    pub fn new(repository: &'a mut Repository, max_in_flight: usize) -> Self {
+        let writer = repository.get_chunk_writer();
+        Self {
+            repository: Some(repository),
+            writer: Box::new(writer),
+            max_in_flight: max_in_flight.max(1),
+            uploads: FuturesUnordered::new(),
+            uploaded: Vec::new(),
+            applying: None,
+        }
+    }
+
+    /// Chunks sent to the sink that are still uploading
+    pub fn in_flight(&self) -> usize {
+        self.uploads.len()
+    }
+
+    /// Drive uploads, and set the references of the finished ones, until everything sent
+    /// is in the repository
+    fn poll_progress(&mut self, cx: &mut Context<'_>) -> Poll<RepositoryResult<()>> {
+        loop {
+            while let Poll::Ready(Some(uploaded)) = self.uploads.poll_next_unpin(cx) {
+                self.uploaded.push(uploaded?);
+            }
+            if let Some(applying) = self.applying.as_mut() {
+                let (repository, result) = match applying.poll_unpin(cx) {
+                    Poll::Ready(done) => done,
+                    Poll::Pending => return Poll::Pending,
+                };
+                self.applying = None;
+                self.repository = Some(repository);
+                result?;
+            }
+            if self.uploaded.is_empty() {
+                return if self.uploads.is_empty() {
+                    Poll::Ready(Ok(()))
+                } else {
+                    Poll::Pending
+                };
+            }
+            // the repository is only missing while `applying` runs
+            let Some(repository) = self.repository.take() else {
+                return Poll::Ready(Ok(()));
+            };
+            let refs = mem::take(&mut self.uploaded);
+            self.applying = Some(
+                async move {
+                    let result = set_refs(repository, refs).await;
+                    (repository, result)
+                }
+                .boxed(),
+            );
+        }
+    }
+}
+
+async fn set_refs(
+    repository: &mut Repository,
+    refs: Vec<Uploaded>,
+) -> RepositoryResult<()> {
+    for (path, coords, payload) in refs {
+        repository.set_chunk_ref(path, coords, Some(payload)).await?;
+    }
+    Ok(())
+}
+
+impl Sink<(Path, ChunkIndices, Bytes)> for ChunkSink<'_> {
+    type Error = RepositoryError;
+
+    fn poll_ready(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        if let Poll::Ready(Err(err)) = this.poll_progress(cx) {
+            return Poll::Ready(Err(err));
+        }
+        // polling the uploads registered the task to be woken when one of them is done
+        if this.uploads.len() < this.max_in_flight {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn start_send(
+        self: Pin<&mut Self>,
+        (path, coords, bytes): (Path, ChunkIndices, Bytes),
+    ) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let upload = (this.writer)(bytes).map_ok(|payload| (path, coords, payload));
+        this.uploads.push(upload.boxed());
+        Ok(())
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_progress(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), Self::Error>> {
+        self.get_mut().poll_progress(cx)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use futures::{SinkExt, TryStreamExt};
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        metadata::FillValue, synthetic::vector_metadata, ErrorKind, ObjectStorage,
+        Storage,
+    };
+
+    #[tokio::test]
+    async fn test_chunk_sink() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(1)
+            .build();
+        let array: Path = "/array".try_into()?;
+        repo.add_group(Path::root()).await?;
+        repo.add_array(array.clone(), vector_metadata(20, 1, FillValue::UInt8(0)))
+            .await?;
+
+        let chunks = (0..20u64).map(|index| {
+            Ok((
+                array.clone(),
+                ChunkIndices(vec![index]),
+                Bytes::from(vec![index as u8; 8]),
+            ))
+        });
+        let mut sink = ChunkSink::new(&mut repo, 3);
+        futures::stream::iter(chunks).forward(&mut sink).await?;
+        assert_eq!(sink.in_flight(), 0);
+        drop(sink);
+        let written: Vec<_> = repo.all_chunks().await?.try_collect().await?;
+        assert_eq!(written.len(), 20);
+
+        // errors setting references are returned by the sink
+        let mut sink = ChunkSink::new(&mut repo, 3);
+        let missing: Path = "/missing".try_into()?;
+        sink.feed((missing, ChunkIndices(vec![0]), Bytes::from_static(b"data"))).await?;
+        let err = sink.flush().await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+//! Signed requests to the JSON and query APIs of AWS services, for the ones without an
+//! SDK client in this crate, like DynamoDB and SNS.
+//!
+//! Every caller shares one HTTPS client, and with it its pool of connections.
+use std::{sync::OnceLock, time::SystemTime};
+
+use aws_config::{meta::region::RegionProviderChain, BehaviorVersion, SdkConfig};
+use aws_credential_types::{provider::ProvideCredentials, Credentials};
+use aws_sdk_s3::config::Region;
+use aws_sigv4::{
+    http_request::{sign, SignableBody, SignableRequest, SigningSettings},
+    sign::v4,
+};
+use aws_smithy_runtime_api::client::identity::Identity;
+use bytes::Bytes;
+use hyper::{body::HttpBody, client::HttpConnector, Body, Client, Method, Request};
+use hyper_rustls::HttpsConnector;
+use thiserror::Error;
+
+use super::s3::{S3Credentials, StaticS3Credentials};
+
+#[derive(Debug, Error)]
+pub(crate) enum AwsHttpError {
+    #[error("{0}")]
+    Config(String),
+    #[error("{0}")]
+    Transport(String),
+}
+
+fn transport(err: impl std::fmt::Display) -> AwsHttpError {
+    AwsHttpError::Transport(err.to_string())
+}
+
+fn http_client() -> &'static Client<HttpsConnector<HttpConnector>> {
+    static CLIENT: OnceLock<Client<HttpsConnector<HttpConnector>>> = OnceLock::new();
+    CLIENT.get_or_init(|| {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_native_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Client::builder().build(connector)
+    })
+}
+
+/// The region and credentials of a service client, like [`super::s3::S3Config`] loads
+/// them
+pub(crate) async fn load_config(
+    region: Option<&str>,
+    credentials: &S3Credentials,
+) -> SdkConfig {
+    let region = region
+        .map(|r| RegionProviderChain::first_try(Some(Region::new(r.to_string()))))
+        .unwrap_or_else(RegionProviderChain::default_provider);
+    let mut config = aws_config::defaults(BehaviorVersion::v2024_03_28()).region(region);
+    match credentials {
+        S3Credentials::FromEnv => {}
+        S3Credentials::Anonymous => config = config.no_credentials(),
+        S3Credentials::Static(StaticS3Credentials {
+            access_key_id,
+            secret_access_key,
+            session_token,
+        }) => {
+            config = config.credentials_provider(Credentials::new(
+                access_key_id,
+                secret_access_key,
+                session_token.clone(),
+                None,
+                "user",
+            ));
+        }
+    }
+    config.load().await
+}
+
+/// `POST` `body` to `url`, signed for `service` with the credentials of `config` if it
+/// has any. Returns the status and the body of the response.
+pub(crate) async fn post(
+    config: &SdkConfig,
+    service: &str,
+    url: &str,
+    mut headers: Vec<(String, String)>,
+    body: Vec<u8>,
+) -> Result<(u16, Bytes), AwsHttpError> {
+    if let Some(provider) = config.credentials_provider() {
+        let region = config.region().ok_or_else(|| {
+            AwsHttpError::Config(format!("no region configured for {service}"))
+        })?;
+        let credentials = provider.provide_credentials().await.map_err(transport)?;
+        let identity = Identity::from(credentials);
+        let params = v4::SigningParams::builder()
+            .identity(&identity)
+            .region(region.as_ref())
+            .name(service)
+            .time(SystemTime::now())
+            .settings(SigningSettings::default())
+            .build()
+            .map_err(|err| AwsHttpError::Config(err.to_string()))?
+            .into();
+        let request = SignableRequest::new(
+            "POST",
+            url,
+            headers.iter().map(|(name, value)| (name.as_str(), value.as_str())),
+            SignableBody::Bytes(&body),
+        )
+        .map_err(|err| AwsHttpError::Config(err.to_string()))?;
+        let (instructions, _) = sign(request, &params)
+            .map_err(|err| AwsHttpError::Config(err.to_string()))?
+            .into_parts();
+        let signature: Vec<_> = instructions
+            .headers()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        headers.extend(signature);
+    }
+    send(url, headers, body).await
+}
+
+/// `POST` `body` to `url` without signing it
+pub(crate) async fn send(
+    url: &str,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+) -> Result<(u16, Bytes), AwsHttpError> {
+    let mut request = Request::builder().method(Method::POST).uri(url);
+    for (name, value) in headers {
+        request = request.header(name, value);
+    }
+    let request = request
+        .body(Body::from(body))
+        .map_err(|err| AwsHttpError::Config(err.to_string()))?;
+    let response = http_client().request(request).await.map_err(transport)?;
+    let status = response.status().as_u16();
+    let body = response.into_body().collect().await.map_err(transport)?;
+    Ok((status, body.to_bytes()))
+}
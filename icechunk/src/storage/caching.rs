@@ -1,8 +1,6 @@
 use std::sync::Arc;
 
-use async_trait::async_trait;
 use bytes::Bytes;
-use futures::stream::BoxStream;
 use quick_cache::sync::Cache;
 
 use crate::{
@@ -10,23 +8,31 @@ use crate::{
         attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
         AttributesId, ByteRange, ChunkId, ManifestId, SnapshotId,
     },
-    private,
+    memory::{MemoryBudget, MemoryCategory, MemoryPermit},
 };
 
-use super::{Storage, StorageError, StorageResult};
+use super::{CacheStatus, ObjectKind, ObjectLocation, Storage, StorageFuture};
+
+/// A cached value, with the memory it uses reserved in the budget
+#[derive(Debug, Clone)]
+struct Cached<T> {
+    value: T,
+    _permit: Option<Arc<MemoryPermit>>,
+}
 
 #[derive(Debug)]
 pub struct MemCachingStorage {
-    backend: Arc<dyn Storage + Send + Sync>,
-    snapshot_cache: Cache<SnapshotId, Arc<Snapshot>>,
-    manifest_cache: Cache<ManifestId, Arc<Manifest>>,
+    backend: Arc<dyn Storage>,
+    snapshot_cache: Arc<Cache<SnapshotId, Cached<Arc<Snapshot>>>>,
+    manifest_cache: Arc<Cache<ManifestId, Cached<Arc<Manifest>>>>,
     attributes_cache: Cache<AttributesId, Arc<AttributesTable>>,
-    chunk_cache: Cache<(ChunkId, ByteRange), Bytes>,
+    chunk_cache: Arc<Cache<(ChunkId, ByteRange), Cached<Bytes>>>,
+    memory_budget: Option<Arc<MemoryBudget>>,
 }
 
 impl MemCachingStorage {
     pub fn new(
-        backend: Arc<dyn Storage + Send + Sync>,
+        backend: Arc<dyn Storage>,
         num_snapshots: u16,
         num_manifests: u16,
         num_attributes: u16,
@@ -34,135 +40,294 @@ impl MemCachingStorage {
     ) -> Self {
         MemCachingStorage {
             backend,
-            snapshot_cache: Cache::new(num_snapshots as usize),
-            manifest_cache: Cache::new(num_manifests as usize),
+            snapshot_cache: Arc::new(Cache::new(num_snapshots as usize)),
+            manifest_cache: Arc::new(Cache::new(num_manifests as usize)),
             attributes_cache: Cache::new(num_attributes as usize),
-            chunk_cache: Cache::new(num_chunks as usize),
+            chunk_cache: Arc::new(Cache::new(num_chunks as usize)),
+            memory_budget: None,
         }
     }
-}
 
-impl private::Sealed for MemCachingStorage {}
+    /// Account the memory used by cached items in `budget`.
+    ///
+    /// Items are not cached if they don't fit in the budget, and all caches are cleared
+    /// when other users of the budget need the memory.
+    pub fn with_memory_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        fn evictor<K, V>(cache: &Arc<Cache<K, V>>) -> Box<dyn Fn() -> bool + Send + Sync>
+        where
+            K: Eq + std::hash::Hash + Send + Sync + 'static,
+            V: Clone + Send + Sync + 'static,
+        {
+            let cache = Arc::downgrade(cache);
+            Box::new(move || match cache.upgrade() {
+                Some(cache) => {
+                    cache.clear();
+                    true
+                }
+                None => false,
+            })
+        }
+        // chunks are the cheapest to fetch again, so they go first
+        budget.register_evictor(evictor(&self.chunk_cache));
+        budget.register_evictor(evictor(&self.manifest_cache));
+        budget.register_evictor(evictor(&self.snapshot_cache));
+        self.memory_budget = Some(budget);
+        self
+    }
 
-#[async_trait]
-impl Storage for MemCachingStorage {
-    async fn fetch_snapshot(
+    /// Wraps the value, or returns `None` if the budget doesn't allow caching it
+    fn cached<T>(
         &self,
-        id: &SnapshotId,
-    ) -> Result<Arc<Snapshot>, StorageError> {
-        match self.snapshot_cache.get_value_or_guard_async(id).await {
-            Ok(snapshot) => Ok(snapshot),
-            Err(guard) => {
-                let snapshot = self.backend.fetch_snapshot(id).await?;
-                let _fail_is_ok = guard.insert(Arc::clone(&snapshot));
-                Ok(snapshot)
-            }
+        value: T,
+        category: MemoryCategory,
+        bytes: u64,
+    ) -> Option<Cached<T>> {
+        match &self.memory_budget {
+            None => Some(Cached { value, _permit: None }),
+            Some(budget) => budget
+                .try_acquire(category, bytes)
+                .map(|permit| Cached { value, _permit: Some(Arc::new(permit)) }),
         }
     }
 
-    async fn fetch_attributes(
-        &self,
-        id: &AttributesId,
-    ) -> Result<Arc<AttributesTable>, StorageError> {
-        match self.attributes_cache.get_value_or_guard_async(id).await {
-            Ok(table) => Ok(table),
-            Err(guard) => {
-                let table = self.backend.fetch_attributes(id).await?;
-                let _fail_is_ok = guard.insert(Arc::clone(&table));
-                Ok(table)
+    /// Remove an object that is no longer live from the cache
+    fn evict(&self, kind: ObjectKind, id: &str) {
+        match kind {
+            ObjectKind::Snapshot => {
+                if let Ok(id) = SnapshotId::try_from(id) {
+                    self.snapshot_cache.remove(&id);
+                }
+            }
+            ObjectKind::Manifest => {
+                if let Ok(id) = ManifestId::try_from(id) {
+                    self.manifest_cache.remove(&id);
+                }
+            }
+            ObjectKind::Chunk => {
+                if let Ok(id) = ChunkId::try_from(id) {
+                    self.chunk_cache.remove(&(id, ByteRange::ALL));
+                }
+            }
+            ObjectKind::Attributes => {
+                if let Ok(id) = AttributesId::try_from(id) {
+                    self.attributes_cache.remove(&id);
+                }
             }
         }
     }
+}
 
-    async fn fetch_manifests(
-        &self,
-        id: &ManifestId,
-    ) -> Result<Arc<Manifest>, StorageError> {
-        match self.manifest_cache.get_value_or_guard_async(id).await {
-            Ok(manifest) => Ok(manifest),
-            Err(guard) => {
-                let manifest = self.backend.fetch_manifests(id).await?;
-                let _fail_is_ok = guard.insert(Arc::clone(&manifest));
-                Ok(manifest)
+impl Storage for MemCachingStorage {
+    fn fetch_snapshot<'a>(
+        &'a self,
+        id: &'a SnapshotId,
+    ) -> StorageFuture<'a, Arc<Snapshot>> {
+        Box::pin(async move {
+            match self.snapshot_cache.get_value_or_guard_async(id).await {
+                Ok(snapshot) => Ok(snapshot.value),
+                Err(guard) => {
+                    let snapshot = self.backend.fetch_snapshot(id).await?;
+                    let size = snapshot.estimated_size_bytes();
+                    if let Some(cached) = self.cached(
+                        Arc::clone(&snapshot),
+                        MemoryCategory::CachedSnapshots,
+                        size,
+                    ) {
+                        let _fail_is_ok = guard.insert(cached);
+                    }
+                    Ok(snapshot)
+                }
             }
-        }
+        })
     }
 
-    async fn fetch_chunk(
-        &self,
-        id: &ChunkId,
-        range: &ByteRange,
-    ) -> Result<Bytes, StorageError> {
-        let key = (id.clone(), range.clone());
-        match self.chunk_cache.get_value_or_guard_async(&key).await {
-            Ok(bytes) => Ok(bytes),
-            Err(guard) => {
-                let bytes = self.backend.fetch_chunk(id, range).await?;
-                let _fail_is_ok = guard.insert(bytes.clone());
-                Ok(bytes)
+    fn fetch_attributes<'a>(
+        &'a self,
+        id: &'a AttributesId,
+    ) -> StorageFuture<'a, Arc<AttributesTable>> {
+        Box::pin(async move {
+            match self.attributes_cache.get_value_or_guard_async(id).await {
+                Ok(table) => Ok(table),
+                Err(guard) => {
+                    let table = self.backend.fetch_attributes(id).await?;
+                    let _fail_is_ok = guard.insert(Arc::clone(&table));
+                    Ok(table)
+                }
             }
-        }
+        })
     }
 
-    async fn write_snapshot(
-        &self,
+    fn fetch_manifests<'a>(
+        &'a self,
+        id: &'a ManifestId,
+    ) -> StorageFuture<'a, Arc<Manifest>> {
+        Box::pin(async move {
+            match self.manifest_cache.get_value_or_guard_async(id).await {
+                Ok(manifest) => Ok(manifest.value),
+                Err(guard) => {
+                    let manifest = self.backend.fetch_manifests(id).await?;
+                    let size = manifest.estimated_memory_bytes();
+                    if let Some(cached) = self.cached(
+                        Arc::clone(&manifest),
+                        MemoryCategory::CachedManifests,
+                        size,
+                    ) {
+                        let _fail_is_ok = guard.insert(cached);
+                    }
+                    Ok(manifest)
+                }
+            }
+        })
+    }
+
+    fn fetch_chunk<'a>(
+        &'a self,
+        id: &'a ChunkId,
+        range: &'a ByteRange,
+    ) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move { Ok(self.fetch_chunk_with_status(id, range).await?.0) })
+    }
+
+    fn fetch_chunk_with_status<'a>(
+        &'a self,
+        id: &'a ChunkId,
+        range: &'a ByteRange,
+    ) -> StorageFuture<'a, (Bytes, CacheStatus)> {
+        Box::pin(async move {
+            let key = (id.clone(), range.clone());
+            match self.chunk_cache.get_value_or_guard_async(&key).await {
+                Ok(bytes) => Ok((bytes.value, CacheStatus::Hit)),
+                Err(guard) => {
+                    let bytes = self.backend.fetch_chunk(id, range).await?;
+                    let size = bytes.len() as u64;
+                    if let Some(cached) =
+                        self.cached(bytes.clone(), MemoryCategory::CachedChunks, size)
+                    {
+                        let _fail_is_ok = guard.insert(cached);
+                    }
+                    Ok((bytes, CacheStatus::Miss))
+                }
+            }
+        })
+    }
+
+    fn write_snapshot<'a>(
+        &'a self,
         id: SnapshotId,
         snapshot: Arc<Snapshot>,
-    ) -> Result<(), StorageError> {
-        self.backend.write_snapshot(id.clone(), Arc::clone(&snapshot)).await?;
-        self.snapshot_cache.insert(id, snapshot);
-        Ok(())
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.backend.write_snapshot(id.clone(), Arc::clone(&snapshot)).await?;
+            let size = snapshot.estimated_size_bytes();
+            if let Some(cached) =
+                self.cached(snapshot, MemoryCategory::CachedSnapshots, size)
+            {
+                self.snapshot_cache.insert(id, cached);
+            }
+            Ok(())
+        })
     }
 
-    async fn write_attributes(
-        &self,
+    fn write_attributes<'a>(
+        &'a self,
         id: AttributesId,
         table: Arc<AttributesTable>,
-    ) -> Result<(), StorageError> {
-        self.backend.write_attributes(id.clone(), Arc::clone(&table)).await?;
-        self.attributes_cache.insert(id, table);
-        Ok(())
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.backend.write_attributes(id.clone(), Arc::clone(&table)).await?;
+            self.attributes_cache.insert(id, table);
+            Ok(())
+        })
     }
 
-    async fn write_manifests(
-        &self,
+    fn write_manifests<'a>(
+        &'a self,
         id: ManifestId,
         manifest: Arc<Manifest>,
-    ) -> Result<(), StorageError> {
-        self.backend.write_manifests(id.clone(), Arc::clone(&manifest)).await?;
-        self.manifest_cache.insert(id, manifest);
-        Ok(())
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.backend.write_manifests(id.clone(), Arc::clone(&manifest)).await?;
+            let size = manifest.estimated_memory_bytes();
+            if let Some(cached) =
+                self.cached(manifest, MemoryCategory::CachedManifests, size)
+            {
+                self.manifest_cache.insert(id, cached);
+            }
+            Ok(())
+        })
     }
 
-    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> Result<(), StorageError> {
-        self.backend.write_chunk(id.clone(), bytes.clone()).await?;
-        // we don't pre-populate the chunk cache, there are too many of them for this to be useful
-        Ok(())
+    fn write_chunk<'a>(&'a self, id: ChunkId, bytes: Bytes) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.backend.write_chunk(id.clone(), bytes.clone()).await?;
+            // we don't pre-populate the chunk cache, there are too many of them for this to be useful
+            Ok(())
+        })
     }
 
-    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
-        self.backend.get_ref(ref_key).await
+    fn delete_snapshot<'a>(&'a self, id: &'a SnapshotId) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.backend.delete_snapshot(id).await?;
+            self.snapshot_cache.remove(id);
+            Ok(())
+        })
     }
 
-    async fn ref_names(&self) -> StorageResult<Vec<String>> {
-        self.backend.ref_names().await
+    fn delete_manifests<'a>(&'a self, id: &'a ManifestId) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.backend.delete_manifests(id).await?;
+            self.manifest_cache.remove(id);
+            Ok(())
+        })
     }
 
-    async fn write_ref(
-        &self,
-        ref_key: &str,
-        overwrite_refs: bool,
-        bytes: Bytes,
-    ) -> StorageResult<()> {
-        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
+    fn delete_chunk<'a>(&'a self, id: &'a ChunkId) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.backend.delete_chunk(id).await?;
+            // chunks are cached by byte range, only the full object entry can be evicted here
+            self.chunk_cache.remove(&(id.clone(), ByteRange::ALL));
+            Ok(())
+        })
     }
 
-    async fn ref_versions(
-        &self,
-        ref_name: &str,
-    ) -> StorageResult<BoxStream<StorageResult<String>>> {
-        self.backend.ref_versions(ref_name).await
+    fn move_object<'a>(
+        &'a self,
+        kind: ObjectKind,
+        id: &'a str,
+        to: ObjectLocation,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.backend.move_object(kind, id, to).await?;
+            if to == ObjectLocation::Trash {
+                self.evict(kind, id);
+            }
+            Ok(())
+        })
     }
+
+    fn delete_object<'a>(
+        &'a self,
+        kind: ObjectKind,
+        id: &'a str,
+        location: ObjectLocation,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.backend.delete_object(kind, id, location).await?;
+            if location == ObjectLocation::Live {
+                self.evict(kind, id);
+            }
+            Ok(())
+        })
+    }
+
+    // sub storages read the backend without caching
+    crate::forward_storage!(
+        backend =>
+            fetch_manifest_bytes, get_ref, ref_names, write_ref, delete_ref, ref_versions,
+            list_objects, fetch_snapshot_artifact, write_snapshot_artifact,
+            fetch_audit_records, write_audit_record, list_prefixes, sub_storage,
+            key_layout, chunk_copy_source, can_copy_from, copy_chunk_from,
+    );
 }
 
 #[cfg(test)]
@@ -175,13 +340,14 @@ mod test {
     use super::*;
     use crate::{
         format::manifest::ChunkInfo,
+        memory::MemoryBudget,
         repository::{ChunkIndices, ChunkPayload},
         storage::{logging::LoggingStorage, ObjectStorage, Storage},
     };
 
     #[tokio::test(flavor = "multi_thread")]
     async fn test_caching_storage_caches() -> Result<(), Box<dyn std::error::Error>> {
-        let backend: Arc<dyn Storage + Send + Sync> =
+        let backend: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
 
         let ci1 = ChunkInfo {
@@ -201,7 +367,7 @@ mod test {
             .await?;
 
         let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
-        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let logging_c: Arc<dyn Storage> = logging.clone();
         let caching = MemCachingStorage::new(Arc::clone(&logging_c), 0, 2, 0, 0);
 
         let manifest = Arc::new(vec![ci2].into_iter().collect());
@@ -244,7 +410,7 @@ mod test {
 
     #[tokio::test(flavor = "multi_thread")]
     async fn test_caching_storage_has_limit() -> Result<(), Box<dyn std::error::Error>> {
-        let backend: Arc<dyn Storage + Send + Sync> =
+        let backend: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
 
         let ci1 = ChunkInfo {
@@ -272,7 +438,7 @@ mod test {
         backend.write_manifests(id3.clone(), Arc::clone(&table3)).await?;
 
         let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
-        let logging_c: Arc<dyn Storage + Send + Sync> = logging.clone();
+        let logging_c: Arc<dyn Storage> = logging.clone();
         let caching = MemCachingStorage::new(
             Arc::clone(&logging_c),
             // the cache can only fit 2 manifests.
@@ -293,4 +459,51 @@ mod test {
 
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_caching_storage_memory_budget() -> Result<(), Box<dyn std::error::Error>>
+    {
+        let backend: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+
+        let ci = ChunkInfo {
+            node: 1,
+            coord: ChunkIndices(vec![]),
+            payload: ChunkPayload::Inline(Bytes::copy_from_slice(b"a")),
+        };
+        let manifest: Arc<Manifest> = Arc::new(vec![ci].into_iter().collect());
+        let size = manifest.estimated_memory_bytes();
+        let id1 = ManifestId::random();
+        let id2 = ManifestId::random();
+        backend.write_manifests(id1.clone(), Arc::clone(&manifest)).await?;
+        backend.write_manifests(id2.clone(), Arc::clone(&manifest)).await?;
+
+        let logging = Arc::new(LoggingStorage::new(Arc::clone(&backend)));
+        let logging_c: Arc<dyn Storage> = logging.clone();
+        // room for a single manifest
+        let budget = MemoryBudget::new(size + size / 2);
+        let caching = MemCachingStorage::new(Arc::clone(&logging_c), 0, 10, 0, 0)
+            .with_memory_budget(Arc::clone(&budget));
+
+        caching.fetch_manifests(&id1).await?;
+        caching.fetch_manifests(&id2).await?;
+        assert_eq!(budget.used_by(MemoryCategory::CachedManifests), size);
+
+        // the first one is cached, the second didn't fit
+        caching.fetch_manifests(&id1).await?;
+        caching.fetch_manifests(&id2).await?;
+        assert_eq!(logging.fetch_operations().len(), 3);
+
+        // buffering chunks evicts the cache
+        let permit = budget.acquire(MemoryCategory::BufferedChunks, size).await;
+        assert_eq!(budget.used_by(MemoryCategory::CachedManifests), 0);
+        assert_eq!(budget.used(), size);
+        caching.fetch_manifests(&id1).await?;
+        assert_eq!(logging.fetch_operations().len(), 4);
+
+        drop(permit);
+        drop(caching);
+        assert_eq!(budget.used(), 0);
+        Ok(())
+    }
 }
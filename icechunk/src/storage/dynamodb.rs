@@ -0,0 +1,198 @@
+//! A [`RefLockProvider`] that claims keys with conditional writes to a DynamoDB table.
+//!
+//! The table needs a string partition key, named `key` by default, and no sort key.
+//! Items are never updated, a claim is a `PutItem` with `attribute_not_exists`. Needs the
+//! `dynamodb` feature.
+use aws_config::SdkConfig;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use super::{
+    aws_http::{self, AwsHttpError},
+    ref_lock::RefLockProvider,
+    s3::S3Credentials,
+    StorageError, StorageFuture, StorageResult,
+};
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct DynamoDbConfig {
+    pub table: String,
+    pub region: Option<String>,
+    /// Send requests to this URL instead of AWS, for example DynamoDB local
+    pub endpoint: Option<String>,
+    pub credentials: S3Credentials,
+    /// The name of the partition key of the table, `key` if not set
+    #[serde(default)]
+    pub partition_key: Option<String>,
+    /// Prepended to every claimed key, to share a table between repositories
+    #[serde(default)]
+    pub key_prefix: String,
+}
+
+#[derive(Debug)]
+pub struct DynamoDbRefLock {
+    aws_config: SdkConfig,
+    endpoint: String,
+    table: String,
+    partition_key: String,
+    key_prefix: String,
+}
+
+const TARGET_PREFIX: &str = "DynamoDB_20120810";
+const CONDITION_FAILED: &str = "ConditionalCheckFailedException";
+
+impl DynamoDbRefLock {
+    pub async fn new(config: &DynamoDbConfig) -> StorageResult<Self> {
+        let aws_config =
+            aws_http::load_config(config.region.as_deref(), &config.credentials).await;
+        let region = aws_config.region().ok_or_else(|| {
+            StorageError::InvalidConfig("no region configured for DynamoDB".to_string())
+        })?;
+        let endpoint = config
+            .endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://dynamodb.{region}.amazonaws.com"));
+        Ok(Self {
+            aws_config,
+            endpoint,
+            table: config.table.clone(),
+            partition_key: config
+                .partition_key
+                .clone()
+                .unwrap_or_else(|| "key".to_string()),
+            key_prefix: config.key_prefix.clone(),
+        })
+    }
+
+    fn item_key(&self, key: &str) -> serde_json::Value {
+        json!({ &self.partition_key: { "S": format!("{}{}", self.key_prefix, key) } })
+    }
+
+    /// Send a DynamoDB API call, returns `false` if its condition failed
+    async fn call(
+        &self,
+        operation: &str,
+        body: serde_json::Value,
+    ) -> StorageResult<bool> {
+        let body = serde_json::to_vec(&body).map_err(|err| {
+            StorageError::Other(format!("cannot encode request: {err}"))
+        })?;
+        let headers = vec![
+            ("content-type".to_string(), "application/x-amz-json-1.0".to_string()),
+            ("x-amz-target".to_string(), format!("{TARGET_PREFIX}.{operation}")),
+        ];
+        let (status, body) =
+            aws_http::post(&self.aws_config, "dynamodb", &self.endpoint, headers, body)
+                .await
+                .map_err(|err| match err {
+                    AwsHttpError::Config(message) => StorageError::InvalidConfig(message),
+                    AwsHttpError::Transport(message) => StorageError::Other(format!(
+                        "error contacting DynamoDB: {message}"
+                    )),
+                })?;
+        let body = String::from_utf8_lossy(body.as_ref());
+        if (200..300).contains(&status) {
+            Ok(true)
+        } else if status == 400 && body.contains(CONDITION_FAILED) {
+            Ok(false)
+        } else {
+            Err(StorageError::Other(format!(
+                "DynamoDB {operation} failed ({status}): {body}"
+            )))
+        }
+    }
+}
+
+impl RefLockProvider for DynamoDbRefLock {
+    fn try_claim<'a>(&'a self, key: &'a str) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            let body = json!({
+                "TableName": self.table,
+                "Item": self.item_key(key),
+                "ConditionExpression": "attribute_not_exists(#key)",
+                "ExpressionAttributeNames": { "#key": self.partition_key },
+            });
+            self.call("PutItem", body).await
+        })
+    }
+
+    fn release<'a>(&'a self, key: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let body = json!({ "TableName": self.table, "Key": self.item_key(key) });
+            self.call("DeleteItem", body).await.map(|_| ())
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::storage::s3::StaticS3Credentials;
+
+    /// A server answering each request with the next response, returns the requests
+    async fn serve(
+        responses: Vec<&'static str>,
+    ) -> (String, tokio::task::JoinHandle<Vec<String>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0; 16 * 1024];
+                let read = socket.read(&mut buf).await.unwrap();
+                requests.push(String::from_utf8_lossy(&buf[..read]).to_string());
+                let response = format!(
+                    "HTTP/1.1 {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    if response.is_empty() { "200 OK" } else { "400 Bad Request" },
+                    response.len(),
+                    response
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+            requests
+        });
+        (endpoint, server)
+    }
+
+    #[tokio::test]
+    async fn test_dynamodb_claims() {
+        let failed = r#"{"__type":"com.amazonaws.dynamodb.v20120810#ConditionalCheckFailedException"}"#;
+        let denied = r#"{"__type":"com.amazon.coral.service#AccessDeniedException"}"#;
+        let (endpoint, server) = serve(vec!["", failed, "", denied]).await;
+        let lock = DynamoDbRefLock::new(&DynamoDbConfig {
+            table: "locks".to_string(),
+            region: Some("eu-west-1".to_string()),
+            endpoint: Some(endpoint),
+            credentials: S3Credentials::Static(StaticS3Credentials {
+                access_key_id: "key".to_string(),
+                secret_access_key: "secret".to_string(),
+                session_token: None,
+            }),
+            key_prefix: "repo/".to_string(),
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+
+        assert!(lock.try_claim("branch.main/ZZZZZZZZ.json").await.unwrap());
+        assert!(!lock.try_claim("branch.main/ZZZZZZZZ.json").await.unwrap());
+        lock.release("branch.main/ZZZZZZZZ.json").await.unwrap();
+        assert!(matches!(
+            lock.try_claim("branch.main/ZZZZZZZY.json").await,
+            Err(StorageError::Other(message)) if message.contains("AccessDenied")
+        ));
+
+        let requests = server.await.unwrap();
+        let put = requests[0].to_lowercase();
+        assert!(put.contains("x-amz-target: dynamodb_20120810.putitem"));
+        assert!(put.contains("/eu-west-1/dynamodb/aws4_request"));
+        assert!(put.contains("signedheaders=content-type;host;"));
+        assert!(requests[0].contains(r#""key":{"S":"repo/branch.main/ZZZZZZZZ.json"}"#));
+        assert!(requests[0].contains("attribute_not_exists(#key)"));
+        assert!(requests[2].to_lowercase().contains("dynamodb_20120810.deleteitem"));
+    }
+}
@@ -0,0 +1,188 @@
+//! How object ids map to storage keys.
+//!
+//! Objects of each kind are stored under their own prefix, `chunks/`, `manifests/`,
+//! `snapshots/` and `attributes/` by default. Large repositories hold millions of
+//! chunks, and some filesystems slow down with that many files in a directory: a
+//! [`FanOut::Hex2`] layout spreads the objects over two levels of directories named
+//! by the first two bytes of their id, in hex, like `chunks/3f/a0/<id>`. The layout is
+//! part of the storage configuration, every client of a repository must use the same:
+//! repositories created with a layout other than the default record it next to their
+//! refs, and opening them with a storage configured differently fails.
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ErrorKind;
+
+use super::{ObjectKind, Storage, StorageError, StorageResult};
+
+/// The key of the recorded layout, in the refs of the repository
+const LAYOUT_KEY: &str = "layout.json";
+
+/// The directories between the prefix of a kind and its objects
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FanOut {
+    /// Objects are stored right under their prefix
+    #[default]
+    Flat,
+    /// Objects are stored under `<first byte>/<second byte>/`, in hex
+    Hex2,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeyLayout {
+    pub fan_out: FanOut,
+    pub snapshots: String,
+    pub manifests: String,
+    pub chunks: String,
+    pub attributes: String,
+}
+
+impl Default for KeyLayout {
+    fn default() -> Self {
+        Self {
+            fan_out: FanOut::Flat,
+            snapshots: "snapshots".to_string(),
+            manifests: "manifests".to_string(),
+            chunks: "chunks".to_string(),
+            attributes: "attributes".to_string(),
+        }
+    }
+}
+
+impl KeyLayout {
+    pub fn with_fan_out(mut self, fan_out: FanOut) -> Self {
+        self.fan_out = fan_out;
+        self
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The prefix of the objects of `kind`, relative to the repository prefix
+    pub fn prefix(&self, kind: ObjectKind) -> &str {
+        match kind {
+            ObjectKind::Snapshot => self.snapshots.as_str(),
+            ObjectKind::Manifest => self.manifests.as_str(),
+            ObjectKind::Chunk => self.chunks.as_str(),
+            ObjectKind::Attributes => self.attributes.as_str(),
+        }
+    }
+
+    /// The key of the object `id`, relative to the prefix of its kind
+    pub fn relative_key(&self, id: &str) -> String {
+        match self.fan_out {
+            FanOut::Flat => id.to_string(),
+            FanOut::Hex2 => {
+                // ids that are not crockford base32 can't be looked up by their bytes
+                match base32::decode(base32::Alphabet::Crockford, id).as_deref() {
+                    Some([first, second, ..]) => {
+                        format!("{first:02x}/{second:02x}/{id}")
+                    }
+                    _ => id.to_string(),
+                }
+            }
+        }
+    }
+}
+
+/// Record the layout of `storage` in a new repository, repositories without a recorded
+/// layout use the default
+pub(crate) async fn write_key_layout(
+    storage: &dyn Storage,
+    overwrite_refs: bool,
+) -> StorageResult<()> {
+    let layout = storage.key_layout();
+    if layout.is_default() {
+        return Ok(());
+    }
+    let bytes = serde_json::to_vec(&layout)
+        .map_err(|err| StorageError::Other(err.to_string()))?;
+    storage.write_ref(LAYOUT_KEY, overwrite_refs, Bytes::from(bytes)).await
+}
+
+/// Fail if `storage` is not configured with the layout recorded in the repository
+pub(crate) async fn check_key_layout(storage: &dyn Storage) -> StorageResult<()> {
+    let recorded = match storage.get_ref(LAYOUT_KEY).await {
+        Ok(bytes) => serde_json::from_slice(&bytes).map_err(|err| {
+            StorageError::Other(format!("cannot read the key layout: {err}"))
+        })?,
+        Err(err) if err.kind() == ErrorKind::NotFound => KeyLayout::default(),
+        Err(err) => return Err(err),
+    };
+    let configured = storage.key_layout();
+    if recorded != configured {
+        return Err(StorageError::InvalidConfig(format!(
+            "the repository uses the key layout {recorded:?}, the storage is configured \
+             with {configured:?}"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use bytes::Bytes;
+    use futures::TryStreamExt;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{ByteRange, ChunkId},
+        refs::list_refs,
+        storage::{ObjectLocation, ObjectStorage},
+        Repository, Storage,
+    };
+
+    #[tokio::test]
+    async fn test_hex_fan_out() -> Result<(), Box<dyn Error>> {
+        let layout = KeyLayout::default().with_fan_out(FanOut::Hex2);
+        let id = ChunkId::new([0x3f, 0xa0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        assert_eq!(layout.relative_key(&id.to_string()), format!("3f/a0/{id}"));
+        assert_eq!(KeyLayout::default().relative_key(&id.to_string()), id.to_string());
+
+        let dir = tempfile::tempdir()?;
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_local_store(dir.path())?.with_key_layout(layout));
+        storage.write_chunk(id.clone(), Bytes::from_static(b"hello")).await?;
+        assert!(dir.path().join("chunks/3f/a0").join(id.to_string()).is_file());
+        assert_eq!(storage.fetch_chunk(&id, &ByteRange::ALL).await?, "hello");
+
+        let listed: Vec<_> = storage
+            .list_objects(ObjectKind::Chunk, ObjectLocation::Live)
+            .await?
+            .map_ok(|info| info.id)
+            .try_collect()
+            .await?;
+        assert_eq!(listed, vec![id.to_string()]);
+        storage
+            .move_object(ObjectKind::Chunk, &id.to_string(), ObjectLocation::Trash)
+            .await?;
+        assert!(storage.fetch_chunk(&id, &ByteRange::ALL).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_recorded_layout() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let hex = KeyLayout::default().with_fan_out(FanOut::Hex2);
+        let storage: Arc<dyn Storage> = Arc::new(
+            ObjectStorage::new_local_store(dir.path())?.with_key_layout(hex.clone()),
+        );
+        Repository::init(Arc::clone(&storage), false).await?.build();
+        Repository::from_branch_tip(Arc::clone(&storage), "main").await?;
+        // the layout file is not a ref
+        assert_eq!(list_refs(storage.as_ref()).await?.len(), 1);
+
+        let flat: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_local_store(dir.path())?);
+        let err = Repository::from_branch_tip(flat, "main").await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidRequest);
+        Ok(())
+    }
+}
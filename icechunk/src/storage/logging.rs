@@ -1,27 +1,22 @@
 use std::sync::{Arc, Mutex};
 
-use async_trait::async_trait;
 use bytes::Bytes;
-use futures::stream::BoxStream;
 
-use super::{Storage, StorageError, StorageResult};
-use crate::{
-    format::{
-        attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
-        AttributesId, ByteRange, ChunkId, ManifestId, SnapshotId,
-    },
-    private,
+use super::{Storage, StorageFuture};
+use crate::format::{
+    attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot, AttributesId,
+    ByteRange, ChunkId, ManifestId, SnapshotId,
 };
 
 #[derive(Debug)]
 pub struct LoggingStorage {
-    backend: Arc<dyn Storage + Send + Sync>,
+    backend: Arc<dyn Storage>,
     fetch_log: Mutex<Vec<(String, Vec<u8>)>>,
 }
 
 #[cfg(test)]
 impl LoggingStorage {
-    pub fn new(backend: Arc<dyn Storage + Send + Sync>) -> Self {
+    pub fn new(backend: Arc<dyn Storage>) -> Self {
         Self { backend, fetch_log: Mutex::new(Vec::new()) }
     }
 
@@ -31,105 +26,105 @@ impl LoggingStorage {
     }
 }
 
-impl private::Sealed for LoggingStorage {}
-
-#[async_trait]
 #[allow(clippy::expect_used)] // this implementation is intended for tests only
 impl Storage for LoggingStorage {
-    async fn fetch_snapshot(
-        &self,
-        id: &SnapshotId,
-    ) -> Result<Arc<Snapshot>, StorageError> {
-        self.fetch_log
-            .lock()
-            .expect("poison lock")
-            .push(("fetch_snapshot".to_string(), id.0.to_vec()));
-        self.backend.fetch_snapshot(id).await
-    }
-
-    async fn fetch_attributes(
-        &self,
-        id: &AttributesId,
-    ) -> Result<Arc<AttributesTable>, StorageError> {
-        self.fetch_log
-            .lock()
-            .expect("poison lock")
-            .push(("fetch_attributes".to_string(), id.0.to_vec()));
-        self.backend.fetch_attributes(id).await
-    }
-
-    async fn fetch_manifests(
-        &self,
-        id: &ManifestId,
-    ) -> Result<Arc<Manifest>, StorageError> {
-        self.fetch_log
-            .lock()
-            .expect("poison lock")
-            .push(("fetch_manifests".to_string(), id.0.to_vec()));
-        self.backend.fetch_manifests(id).await
+    fn fetch_snapshot<'a>(
+        &'a self,
+        id: &'a SnapshotId,
+    ) -> StorageFuture<'a, Arc<Snapshot>> {
+        Box::pin(async move {
+            self.fetch_log
+                .lock()
+                .expect("poison lock")
+                .push(("fetch_snapshot".to_string(), id.0.to_vec()));
+            self.backend.fetch_snapshot(id).await
+        })
     }
 
-    async fn fetch_chunk(
-        &self,
-        id: &ChunkId,
-        range: &ByteRange,
-    ) -> Result<Bytes, StorageError> {
-        self.fetch_log
-            .lock()
-            .expect("poison lock")
-            .push(("fetch_chunk".to_string(), id.0.to_vec()));
-        self.backend.fetch_chunk(id, range).await
+    fn fetch_attributes<'a>(
+        &'a self,
+        id: &'a AttributesId,
+    ) -> StorageFuture<'a, Arc<AttributesTable>> {
+        Box::pin(async move {
+            self.fetch_log
+                .lock()
+                .expect("poison lock")
+                .push(("fetch_attributes".to_string(), id.0.to_vec()));
+            self.backend.fetch_attributes(id).await
+        })
     }
 
-    async fn write_snapshot(
-        &self,
-        id: SnapshotId,
-        table: Arc<Snapshot>,
-    ) -> Result<(), StorageError> {
-        self.backend.write_snapshot(id, table).await
+    fn fetch_manifests<'a>(
+        &'a self,
+        id: &'a ManifestId,
+    ) -> StorageFuture<'a, Arc<Manifest>> {
+        Box::pin(async move {
+            self.fetch_log
+                .lock()
+                .expect("poison lock")
+                .push(("fetch_manifests".to_string(), id.0.to_vec()));
+            self.backend.fetch_manifests(id).await
+        })
     }
 
-    async fn write_attributes(
-        &self,
-        id: AttributesId,
-        table: Arc<AttributesTable>,
-    ) -> Result<(), StorageError> {
-        self.backend.write_attributes(id, table).await
+    fn fetch_manifest_bytes<'a>(
+        &'a self,
+        id: &'a ManifestId,
+    ) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            self.fetch_log
+                .lock()
+                .expect("poison lock")
+                .push(("fetch_manifest_bytes".to_string(), id.0.to_vec()));
+            self.backend.fetch_manifest_bytes(id).await
+        })
     }
 
-    async fn write_manifests(
-        &self,
-        id: ManifestId,
-        table: Arc<Manifest>,
-    ) -> Result<(), StorageError> {
-        self.backend.write_manifests(id, table).await
+    fn fetch_chunk<'a>(
+        &'a self,
+        id: &'a ChunkId,
+        range: &'a ByteRange,
+    ) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            self.fetch_log
+                .lock()
+                .expect("poison lock")
+                .push(("fetch_chunk".to_string(), id.0.to_vec()));
+            self.backend.fetch_chunk(id, range).await
+        })
     }
 
-    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> Result<(), StorageError> {
-        self.backend.write_chunk(id, bytes).await
+    fn fetch_snapshot_artifact<'a>(
+        &'a self,
+        id: &'a SnapshotId,
+        name: &'a str,
+    ) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            self.fetch_log
+                .lock()
+                .expect("poison lock")
+                .push(("fetch_snapshot_artifact".to_string(), id.0.to_vec()));
+            self.backend.fetch_snapshot_artifact(id, name).await
+        })
     }
 
-    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
-        self.backend.get_ref(ref_key).await
+    fn fetch_audit_records(&self) -> StorageFuture<'_, Vec<Bytes>> {
+        Box::pin(async move {
+            self.fetch_log
+                .lock()
+                .expect("poison lock")
+                .push(("fetch_audit_records".to_string(), Vec::new()));
+            self.backend.fetch_audit_records().await
+        })
     }
 
-    async fn ref_names(&self) -> StorageResult<Vec<String>> {
-        self.backend.ref_names().await
-    }
-
-    async fn write_ref(
-        &self,
-        ref_key: &str,
-        overwrite_refs: bool,
-        bytes: Bytes,
-    ) -> StorageResult<()> {
-        self.backend.write_ref(ref_key, overwrite_refs, bytes).await
-    }
-
-    async fn ref_versions(
-        &self,
-        ref_name: &str,
-    ) -> StorageResult<BoxStream<StorageResult<String>>> {
-        self.backend.ref_versions(ref_name).await
-    }
+    // calls to the storages of the sub prefixes are not logged
+    crate::forward_storage!(
+        backend =>
+            write_snapshot, write_attributes, write_manifests, write_chunk,
+            delete_snapshot, delete_manifests, delete_chunk, get_ref, ref_names,
+            write_ref, delete_ref, ref_versions, list_objects, move_object, delete_object,
+            write_snapshot_artifact, write_audit_record, list_prefixes, sub_storage,
+            key_layout, chunk_copy_source, can_copy_from, copy_chunk_from,
+    );
 }
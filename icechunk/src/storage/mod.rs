@@ -2,37 +2,54 @@ use aws_sdk_s3::{
     config::http::HttpResponse,
     error::SdkError,
     operation::{
-        get_object::GetObjectError, list_objects_v2::ListObjectsV2Error,
-        put_object::PutObjectError,
+        complete_multipart_upload::CompleteMultipartUploadError,
+        copy_object::CopyObjectError,
+        create_multipart_upload::CreateMultipartUploadError,
+        delete_object::DeleteObjectError, get_object::GetObjectError,
+        head_object::HeadObjectError, list_objects_v2::ListObjectsV2Error,
+        put_object::PutObjectError, upload_part::UploadPartError,
     },
     primitives::ByteStreamError,
 };
+use chrono::{DateTime, Utc};
 use core::fmt;
-use futures::stream::BoxStream;
+use futures::{future::BoxFuture, stream::BoxStream};
 use std::{ffi::OsString, sync::Arc};
 
-use async_trait::async_trait;
 use bytes::Bytes;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
+#[cfg(any(feature = "dynamodb", feature = "notifications"))]
+pub(crate) mod aws_http;
 pub mod caching;
+#[cfg(feature = "dynamodb")]
+pub mod dynamodb;
+pub mod layout;
 
 #[cfg(test)]
 pub mod logging;
 
 pub mod object_store;
+pub mod packing;
+pub mod range_cache;
+pub mod recording;
+pub mod ref_lock;
 pub mod s3;
+pub mod salvage;
 pub mod virtual_ref;
 
 pub use caching::MemCachingStorage;
+pub use layout::{FanOut, KeyLayout};
 pub use object_store::ObjectStorage;
+pub use packing::PackingStorage;
+pub use recording::RecordingStorage;
+pub use ref_lock::{LockedRefStorage, RefLockProvider};
 
-use crate::{
-    format::{
-        attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
-        AttributesId, ByteRange, ChunkId, ManifestId, SnapshotId,
-    },
-    private,
+use crate::error::ErrorKind;
+use crate::format::{
+    attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot, AttributesId,
+    AuditRecordId, ByteRange, ChunkId, ManifestId, SnapshotId,
 };
 
 #[derive(Debug, Error)]
@@ -42,11 +59,15 @@ pub enum StorageError {
     #[error("bad object store prefix {0:?}")]
     BadPrefix(OsString),
     #[error("error getting object from object store {0}")]
-    S3GetObjectError(#[from] SdkError<GetObjectError, HttpResponse>),
+    S3GetObjectError(#[source] Box<SdkError<GetObjectError, HttpResponse>>),
+    #[error("error getting object metadata from object store {0}")]
+    S3HeadObjectError(#[source] Box<SdkError<HeadObjectError, HttpResponse>>),
     #[error("error writing object to object store {0}")]
-    S3PutObjectError(#[from] SdkError<PutObjectError, HttpResponse>),
+    S3PutObjectError(#[source] Box<SdkError<PutObjectError, HttpResponse>>),
+    #[error("error deleting object from object store {0}")]
+    S3DeleteObjectError(#[source] Box<SdkError<DeleteObjectError, HttpResponse>>),
     #[error("error listing objects in object store {0}")]
-    S3ListObjectError(#[from] SdkError<ListObjectsV2Error, HttpResponse>),
+    S3ListObjectError(#[source] Box<SdkError<ListObjectsV2Error, HttpResponse>>),
     #[error("error streaming bytes from object store {0}")]
     S3StreamError(#[from] ByteStreamError),
     #[error("messagepack decode error: {0}")]
@@ -57,53 +78,787 @@ pub enum StorageError {
     RefAlreadyExists(String),
     #[error("ref not found: {0}")]
     RefNotFound(String),
+    #[error("error copying object in object store {0}")]
+    S3CopyObjectError(#[source] Box<SdkError<CopyObjectError, HttpResponse>>),
+    #[error("error starting multipart upload to object store {0}")]
+    S3CreateMultipartUploadError(
+        #[source] Box<SdkError<CreateMultipartUploadError, HttpResponse>>,
+    ),
+    #[error("error uploading part to object store {0}")]
+    S3UploadPartError(#[source] Box<SdkError<UploadPartError, HttpResponse>>),
+    #[error("error completing multipart upload to object store {0}")]
+    S3CompleteMultipartUploadError(
+        #[source] Box<SdkError<CompleteMultipartUploadError, HttpResponse>>,
+    ),
+    #[error("operation not supported by this storage: {0}")]
+    Unsupported(String),
+    #[error("invalid storage configuration: {0}")]
+    InvalidConfig(String),
     #[error("unknown storage error: {0}")]
     Other(String),
+    #[error("error accessing object `{key}`: {source}")]
+    Object {
+        key: String,
+        #[source]
+        source: Box<StorageError>,
+    },
+}
+
+// the SDK errors are hundreds of bytes, boxed they don't make every result that can
+// carry a storage error as large
+macro_rules! boxed_sdk_errors {
+    ($($variant:ident($error:ty)),* $(,)?) => {
+        $(
+            impl From<SdkError<$error, HttpResponse>> for StorageError {
+                fn from(err: SdkError<$error, HttpResponse>) -> Self {
+                    StorageError::$variant(Box::new(err))
+                }
+            }
+        )*
+    };
+}
+
+boxed_sdk_errors!(
+    S3GetObjectError(GetObjectError),
+    S3HeadObjectError(HeadObjectError),
+    S3PutObjectError(PutObjectError),
+    S3DeleteObjectError(DeleteObjectError),
+    S3ListObjectError(ListObjectsV2Error),
+    S3CopyObjectError(CopyObjectError),
+    S3CreateMultipartUploadError(CreateMultipartUploadError),
+    S3UploadPartError(UploadPartError),
+    S3CompleteMultipartUploadError(CompleteMultipartUploadError),
+);
+
+impl StorageError {
+    /// Record the key of the object the failed operation was about. Errors that have a
+    /// key already keep it.
+    pub fn with_key(self, key: &str) -> Self {
+        match self {
+            err @ StorageError::Object { .. } => err,
+            err => StorageError::Object { key: key.to_string(), source: Box::new(err) },
+        }
+    }
+
+    /// The key of the object involved, if known
+    pub fn object_key(&self) -> Option<&str> {
+        match self {
+            StorageError::Object { key, .. } => Some(key),
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            StorageError::ObjectStore(err) => match err {
+                ::object_store::Error::NotFound { .. } => ErrorKind::NotFound,
+                ::object_store::Error::AlreadyExists { .. }
+                | ::object_store::Error::Precondition { .. } => ErrorKind::Conflict,
+                ::object_store::Error::NotSupported { .. }
+                | ::object_store::Error::NotImplemented => ErrorKind::Unsupported,
+                ::object_store::Error::InvalidPath { .. } => ErrorKind::InvalidRequest,
+                _ => ErrorKind::Other,
+            },
+            StorageError::S3GetObjectError(err) => sdk_error_kind(err.as_ref()),
+            StorageError::S3HeadObjectError(err) => sdk_error_kind(err.as_ref()),
+            StorageError::S3PutObjectError(err) => sdk_error_kind(err.as_ref()),
+            StorageError::S3DeleteObjectError(err) => sdk_error_kind(err.as_ref()),
+            StorageError::S3ListObjectError(err) => sdk_error_kind(err.as_ref()),
+            StorageError::S3CopyObjectError(err) => sdk_error_kind(err.as_ref()),
+            StorageError::S3CreateMultipartUploadError(err) => {
+                sdk_error_kind(err.as_ref())
+            }
+            StorageError::S3UploadPartError(err) => sdk_error_kind(err.as_ref()),
+            StorageError::S3CompleteMultipartUploadError(err) => {
+                sdk_error_kind(err.as_ref())
+            }
+            StorageError::S3StreamError(_) => ErrorKind::Transient,
+            StorageError::MsgPackDecodeError(_) => ErrorKind::Corruption,
+            StorageError::RefAlreadyExists(_) => ErrorKind::Conflict,
+            StorageError::RefNotFound(_) => ErrorKind::NotFound,
+            StorageError::Unsupported(_) => ErrorKind::Unsupported,
+            StorageError::BadPrefix(_) | StorageError::InvalidConfig(_) => {
+                ErrorKind::InvalidRequest
+            }
+            StorageError::Object { source, .. } => source.kind(),
+            StorageError::MsgPackEncodeError(_) | StorageError::Other(_) => {
+                ErrorKind::Other
+            }
+        }
+    }
+
+    /// True if the operation can succeed if tried again, see [`ErrorKind::is_retryable`]
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
+    }
+}
+
+fn sdk_error_kind<E>(err: &SdkError<E, HttpResponse>) -> ErrorKind {
+    match err {
+        SdkError::TimeoutError(_)
+        | SdkError::DispatchFailure(_)
+        | SdkError::ResponseError(_) => ErrorKind::Transient,
+        SdkError::ServiceError(_) => err
+            .raw_response()
+            .map(|res| ErrorKind::from_http_status(res.status().as_u16()))
+            .unwrap_or(ErrorKind::Other),
+        _ => ErrorKind::Other,
+    }
 }
 
 pub type StorageResult<A> = Result<A, StorageError>;
 
+/// The future returned by the [`Storage`] methods
+pub type StorageFuture<'a, A> = BoxFuture<'a, StorageResult<A>>;
+
+/// The kinds of immutable objects stored by a repository
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum ObjectKind {
+    Snapshot,
+    Manifest,
+    Chunk,
+    /// The tables of user attributes, see [`AttributesTable`]
+    Attributes,
+}
+
+/// Where an object is stored.
+///
+/// Objects removed by garbage collection can be moved to the trash instead of deleted,
+/// they are invisible to repositories there, but can be restored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum ObjectLocation {
+    Live,
+    Trash,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ObjectInfo {
+    /// The id of the object, as encoded in its key
+    pub id: String,
+    /// When the object was written, for trashed objects when it was moved to the trash
+    pub modified_at: DateTime<Utc>,
+}
+
+/// Whether a read was served by a cache
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+    /// No cache was involved, or it doesn't report hits
+    Uncached,
+}
+
+/// Where an object is stored, for copies made by the storage service.
+///
+/// Storages can only copy from sources of their own `provider`, see
+/// [`Storage::copy_chunk_from`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CopySource {
+    pub provider: &'static str,
+    /// The bucket, or the store, holding the object
+    pub container: String,
+    pub key: String,
+}
+
 /// Fetch and write the parquet files that represent the repository in object store
 ///
 /// Different implementation can cache the files differently, or not at all.
 /// Implementations are free to assume files are never overwritten.
-#[async_trait]
-pub trait Storage: fmt::Debug + private::Sealed {
-    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>>;
-    async fn fetch_attributes(
-        &self,
-        id: &AttributesId,
-    ) -> StorageResult<Arc<AttributesTable>>; // FIXME: format flags
-    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>>; // FIXME: format flags
-    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes>; // FIXME: format flags
-
-    async fn write_snapshot(
-        &self,
+///
+/// The trait is object safe, repositories use it as `Arc<dyn Storage>`, and it can be
+/// implemented outside this crate. Methods return boxed futures borrowing their
+/// arguments, implementations usually wrap an `async move` block with `Box::pin`.
+pub trait Storage: fmt::Debug + Send + Sync {
+    /// Fetch a snapshot with its whole node table, snapshots stored as changes to an
+    /// ancestor must be completed with [`with_structure_base`]
+    fn fetch_snapshot<'a>(
+        &'a self,
+        id: &'a SnapshotId,
+    ) -> StorageFuture<'a, Arc<Snapshot>>;
+    fn fetch_attributes<'a>(
+        &'a self,
+        id: &'a AttributesId,
+    ) -> StorageFuture<'a, Arc<AttributesTable>>; // FIXME: format flags
+    fn fetch_manifests<'a>(
+        &'a self,
+        id: &'a ManifestId,
+    ) -> StorageFuture<'a, Arc<Manifest>>; // FIXME: format flags
+    fn fetch_chunk<'a>(
+        &'a self,
+        id: &'a ChunkId,
+        range: &'a ByteRange,
+    ) -> StorageFuture<'a, Bytes>; // FIXME: format flags
+
+    fn write_snapshot<'a>(
+        &'a self,
         id: SnapshotId,
         table: Arc<Snapshot>,
-    ) -> StorageResult<()>;
-    async fn write_attributes(
-        &self,
+    ) -> StorageFuture<'a, ()>;
+    fn write_attributes<'a>(
+        &'a self,
         id: AttributesId,
         table: Arc<AttributesTable>,
-    ) -> StorageResult<()>;
-    async fn write_manifests(
-        &self,
+    ) -> StorageFuture<'a, ()>;
+    fn write_manifests<'a>(
+        &'a self,
         id: ManifestId,
         table: Arc<Manifest>,
-    ) -> StorageResult<()>;
-    async fn write_chunk(&self, id: ChunkId, bytes: Bytes) -> StorageResult<()>;
-
-    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes>;
-    async fn ref_names(&self) -> StorageResult<Vec<String>>;
-    async fn ref_versions(
-        &self,
-        ref_name: &str,
-    ) -> StorageResult<BoxStream<StorageResult<String>>>;
-    async fn write_ref(
-        &self,
-        ref_key: &str,
+    ) -> StorageFuture<'a, ()>;
+    fn write_chunk<'a>(&'a self, id: ChunkId, bytes: Bytes) -> StorageFuture<'a, ()>;
+
+    /// Deleting a missing object is not an error
+    fn delete_snapshot<'a>(&'a self, id: &'a SnapshotId) -> StorageFuture<'a, ()>;
+    fn delete_manifests<'a>(&'a self, id: &'a ManifestId) -> StorageFuture<'a, ()>;
+    fn delete_chunk<'a>(&'a self, id: &'a ChunkId) -> StorageFuture<'a, ()>;
+
+    fn get_ref<'a>(&'a self, ref_key: &'a str) -> StorageFuture<'a, Bytes>;
+    fn ref_names(&self) -> StorageFuture<'_, Vec<String>>;
+    fn ref_versions<'a, 'b>(
+        &'a self,
+        ref_name: &'b str,
+    ) -> StorageFuture<'b, BoxStream<'a, StorageResult<String>>>
+    where
+        'a: 'b;
+    fn write_ref<'a>(
+        &'a self,
+        ref_key: &'a str,
         overwrite_refs: bool,
         bytes: Bytes,
-    ) -> StorageResult<()>;
+    ) -> StorageFuture<'a, ()>;
+
+    /// Delete a ref, deleting a missing ref is not an error.
+    ///
+    /// The default implementation fails with [`StorageError::Unsupported`].
+    fn delete_ref<'a>(&'a self, ref_key: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            Err(StorageError::Unsupported(format!("deleting ref {ref_key}")))
+        })
+    }
+
+    /// Like [`Storage::fetch_chunk`], also reporting if a cache served the bytes.
+    ///
+    /// The default implementation calls `fetch_chunk` and reports
+    /// [`CacheStatus::Uncached`], wrappers should forward it to their backend.
+    fn fetch_chunk_with_status<'a>(
+        &'a self,
+        id: &'a ChunkId,
+        range: &'a ByteRange,
+    ) -> StorageFuture<'a, (Bytes, CacheStatus)> {
+        Box::pin(async move {
+            Ok((self.fetch_chunk(id, range).await?, CacheStatus::Uncached))
+        })
+    }
+
+    /// The serialized manifest `id`, as stored, used to salvage manifests that can't be
+    /// decoded, see [`crate::repair`].
+    ///
+    /// The default implementation fails with [`StorageError::Unsupported`].
+    fn fetch_manifest_bytes<'a>(
+        &'a self,
+        id: &'a ManifestId,
+    ) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            Err(StorageError::Unsupported(format!("fetching the bytes of manifest {id}")))
+        })
+    }
+
+    /// List the objects of a kind, used by garbage collection.
+    ///
+    /// The default implementation fails with [`StorageError::Unsupported`].
+    fn list_objects<'a>(
+        &'a self,
+        kind: ObjectKind,
+        location: ObjectLocation,
+    ) -> StorageFuture<'a, BoxStream<'a, StorageResult<ObjectInfo>>> {
+        Box::pin(async move {
+            Err(StorageError::Unsupported(format!("listing {kind:?} in {location:?}")))
+        })
+    }
+
+    /// Move an object to the trash, or restore it, updating its modification time.
+    ///
+    /// The default implementation fails with [`StorageError::Unsupported`].
+    fn move_object<'a>(
+        &'a self,
+        kind: ObjectKind,
+        id: &'a str,
+        to: ObjectLocation,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            Err(StorageError::Unsupported(format!("moving {kind:?} {id} to {to:?}")))
+        })
+    }
+
+    /// Delete an object by id, deleting a missing object is not an error.
+    ///
+    /// The default implementation fails with [`StorageError::Unsupported`].
+    fn delete_object<'a>(
+        &'a self,
+        kind: ObjectKind,
+        id: &'a str,
+        location: ObjectLocation,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            Err(StorageError::Unsupported(format!(
+                "deleting {kind:?} {id} from {location:?}"
+            )))
+        })
+    }
+
+    /// Fetch the artifact `name` derived from a snapshot, see [`crate::postprocess`].
+    ///
+    /// The default implementation fails with [`StorageError::Unsupported`].
+    fn fetch_snapshot_artifact<'a>(
+        &'a self,
+        id: &'a SnapshotId,
+        name: &'a str,
+    ) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            Err(StorageError::Unsupported(format!("fetching artifact {name} of {id}")))
+        })
+    }
+
+    /// Write the artifact `name` derived from a snapshot, replacing any previous one.
+    ///
+    /// The default implementation fails with [`StorageError::Unsupported`].
+    fn write_snapshot_artifact<'a>(
+        &'a self,
+        id: &'a SnapshotId,
+        name: &'a str,
+        bytes: Bytes,
+    ) -> StorageFuture<'a, ()> {
+        let _ = bytes;
+        Box::pin(async move {
+            Err(StorageError::Unsupported(format!("writing artifact {name} of {id}")))
+        })
+    }
+
+    /// The records of the audit log, in no particular order, see [`crate::audit`].
+    ///
+    /// The default implementation fails with [`StorageError::Unsupported`].
+    fn fetch_audit_records(&self) -> StorageFuture<'_, Vec<Bytes>> {
+        Box::pin(async move {
+            Err(StorageError::Unsupported("reading the audit log".to_string()))
+        })
+    }
+
+    /// Add a record to the audit log, records are never modified.
+    ///
+    /// The default implementation fails with [`StorageError::Unsupported`].
+    fn write_audit_record<'a>(
+        &'a self,
+        id: &'a AuditRecordId,
+        bytes: Bytes,
+    ) -> StorageFuture<'a, ()> {
+        let _ = bytes;
+        Box::pin(async move {
+            Err(StorageError::Unsupported(format!("writing audit record {id}")))
+        })
+    }
+
+    /// The names of the directories directly under `prefix`, relative to the prefix of
+    /// this storage, used to discover repositories.
+    ///
+    /// The default implementation fails with [`StorageError::Unsupported`].
+    fn list_prefixes<'a>(&'a self, prefix: &'a str) -> StorageFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            Err(StorageError::Unsupported(format!("listing prefixes of `{prefix}`")))
+        })
+    }
+
+    /// A storage for the repository under `prefix`, relative to the prefix of this
+    /// storage, sharing its connections.
+    ///
+    /// The default implementation fails with [`StorageError::Unsupported`].
+    fn sub_storage(&self, prefix: &str) -> StorageResult<Arc<dyn Storage>> {
+        Err(StorageError::Unsupported(format!("storage for prefix `{prefix}`")))
+    }
+
+    /// How the ids of the objects map to their keys, see [`KeyLayout`].
+    ///
+    /// The default implementation returns the default layout.
+    fn key_layout(&self) -> KeyLayout {
+        KeyLayout::default()
+    }
+
+    /// Where the chunk `id` is stored, if other storages of the same provider can copy
+    /// it without downloading it.
+    ///
+    /// The default implementation returns `None`.
+    fn chunk_copy_source(&self, id: &ChunkId) -> StorageResult<Option<CopySource>> {
+        let _ = id;
+        Ok(None)
+    }
+
+    /// True if [`Storage::copy_chunk_from`] can copy the object at `source`.
+    ///
+    /// The default implementation returns `false`.
+    fn can_copy_from(&self, source: &CopySource) -> bool {
+        let _ = source;
+        false
+    }
+
+    /// Write the chunk `id` with a copy of the object at `source`, made by the storage
+    /// service, without its bytes going through this process.
+    ///
+    /// The default implementation fails with [`StorageError::Unsupported`].
+    fn copy_chunk_from<'a>(
+        &'a self,
+        id: ChunkId,
+        source: &'a CopySource,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            Err(StorageError::Unsupported(format!(
+                "copying chunk {id} from {}",
+                source.key
+            )))
+        })
+    }
+}
+
+/// Implement the listed methods of [`Storage`] by calling the same methods of the storage
+/// in a field, or returned by a method, of `self`. Wrappers of another storage implement
+/// the methods they change and forward the rest:
+///
+/// ```ignore
+/// impl Storage for CountingStorage {
+///     fn fetch_chunk<'a>(&'a self, id: &'a ChunkId, range: &'a ByteRange) -> ... {
+///         self.fetched();
+///         self.backend.fetch_chunk(id, range)
+///     }
+///
+///     icechunk::forward_storage!(backend => fetch_snapshot, write_chunk, get_ref);
+/// }
+/// ```
+#[macro_export]
+macro_rules! forward_storage {
+    ($backend:ident => $($method:ident),+ $(,)?) => {
+        $($crate::forward_storage!(@method [$backend] $method);)+
+    };
+    ($backend:ident() => $($method:ident),+ $(,)?) => {
+        $($crate::forward_storage!(@method [$backend()] $method);)+
+    };
+
+    (@method [$($backend:tt)+] fetch_snapshot) => {
+        fn fetch_snapshot<'a>(
+            &'a self,
+            id: &'a $crate::format::SnapshotId,
+        ) -> $crate::storage::StorageFuture<
+            'a,
+            ::std::sync::Arc<$crate::format::snapshot::Snapshot>,
+        > {
+            self.$($backend)+.fetch_snapshot(id)
+        }
+    };
+    (@method [$($backend:tt)+] fetch_attributes) => {
+        fn fetch_attributes<'a>(
+            &'a self,
+            id: &'a $crate::format::AttributesId,
+        ) -> $crate::storage::StorageFuture<
+            'a,
+            ::std::sync::Arc<$crate::format::attributes::AttributesTable>,
+        > {
+            self.$($backend)+.fetch_attributes(id)
+        }
+    };
+    (@method [$($backend:tt)+] fetch_manifests) => {
+        fn fetch_manifests<'a>(
+            &'a self,
+            id: &'a $crate::format::ManifestId,
+        ) -> $crate::storage::StorageFuture<
+            'a,
+            ::std::sync::Arc<$crate::format::manifest::Manifest>,
+        > {
+            self.$($backend)+.fetch_manifests(id)
+        }
+    };
+    (@method [$($backend:tt)+] fetch_chunk) => {
+        fn fetch_chunk<'a>(
+            &'a self,
+            id: &'a $crate::format::ChunkId,
+            range: &'a $crate::format::ByteRange,
+        ) -> $crate::storage::StorageFuture<'a, ::bytes::Bytes> {
+            self.$($backend)+.fetch_chunk(id, range)
+        }
+    };
+    (@method [$($backend:tt)+] write_snapshot) => {
+        fn write_snapshot<'a>(
+            &'a self,
+            id: $crate::format::SnapshotId,
+            table: ::std::sync::Arc<$crate::format::snapshot::Snapshot>,
+        ) -> $crate::storage::StorageFuture<'a, ()> {
+            self.$($backend)+.write_snapshot(id, table)
+        }
+    };
+    (@method [$($backend:tt)+] write_attributes) => {
+        fn write_attributes<'a>(
+            &'a self,
+            id: $crate::format::AttributesId,
+            table: ::std::sync::Arc<$crate::format::attributes::AttributesTable>,
+        ) -> $crate::storage::StorageFuture<'a, ()> {
+            self.$($backend)+.write_attributes(id, table)
+        }
+    };
+    (@method [$($backend:tt)+] write_manifests) => {
+        fn write_manifests<'a>(
+            &'a self,
+            id: $crate::format::ManifestId,
+            table: ::std::sync::Arc<$crate::format::manifest::Manifest>,
+        ) -> $crate::storage::StorageFuture<'a, ()> {
+            self.$($backend)+.write_manifests(id, table)
+        }
+    };
+    (@method [$($backend:tt)+] write_chunk) => {
+        fn write_chunk<'a>(
+            &'a self,
+            id: $crate::format::ChunkId,
+            bytes: ::bytes::Bytes,
+        ) -> $crate::storage::StorageFuture<'a, ()> {
+            self.$($backend)+.write_chunk(id, bytes)
+        }
+    };
+    (@method [$($backend:tt)+] delete_snapshot) => {
+        fn delete_snapshot<'a>(
+            &'a self,
+            id: &'a $crate::format::SnapshotId,
+        ) -> $crate::storage::StorageFuture<'a, ()> {
+            self.$($backend)+.delete_snapshot(id)
+        }
+    };
+    (@method [$($backend:tt)+] delete_manifests) => {
+        fn delete_manifests<'a>(
+            &'a self,
+            id: &'a $crate::format::ManifestId,
+        ) -> $crate::storage::StorageFuture<'a, ()> {
+            self.$($backend)+.delete_manifests(id)
+        }
+    };
+    (@method [$($backend:tt)+] delete_chunk) => {
+        fn delete_chunk<'a>(
+            &'a self,
+            id: &'a $crate::format::ChunkId,
+        ) -> $crate::storage::StorageFuture<'a, ()> {
+            self.$($backend)+.delete_chunk(id)
+        }
+    };
+    (@method [$($backend:tt)+] get_ref) => {
+        fn get_ref<'a>(
+            &'a self,
+            ref_key: &'a str,
+        ) -> $crate::storage::StorageFuture<'a, ::bytes::Bytes> {
+            self.$($backend)+.get_ref(ref_key)
+        }
+    };
+    (@method [$($backend:tt)+] ref_names) => {
+        fn ref_names(&self) -> $crate::storage::StorageFuture<'_, Vec<String>> {
+            self.$($backend)+.ref_names()
+        }
+    };
+    (@method [$($backend:tt)+] ref_versions) => {
+        fn ref_versions<'a, 'b>(
+            &'a self,
+            ref_name: &'b str,
+        ) -> $crate::storage::StorageFuture<
+            'b,
+            ::futures::stream::BoxStream<'a, $crate::storage::StorageResult<String>>,
+        >
+        where
+            'a: 'b,
+        {
+            self.$($backend)+.ref_versions(ref_name)
+        }
+    };
+    (@method [$($backend:tt)+] write_ref) => {
+        fn write_ref<'a>(
+            &'a self,
+            ref_key: &'a str,
+            overwrite_refs: bool,
+            bytes: ::bytes::Bytes,
+        ) -> $crate::storage::StorageFuture<'a, ()> {
+            self.$($backend)+.write_ref(ref_key, overwrite_refs, bytes)
+        }
+    };
+    (@method [$($backend:tt)+] delete_ref) => {
+        fn delete_ref<'a>(
+            &'a self,
+            ref_key: &'a str,
+        ) -> $crate::storage::StorageFuture<'a, ()> {
+            self.$($backend)+.delete_ref(ref_key)
+        }
+    };
+    (@method [$($backend:tt)+] fetch_chunk_with_status) => {
+        fn fetch_chunk_with_status<'a>(
+            &'a self,
+            id: &'a $crate::format::ChunkId,
+            range: &'a $crate::format::ByteRange,
+        ) -> $crate::storage::StorageFuture<
+            'a,
+            (::bytes::Bytes, $crate::storage::CacheStatus),
+        > {
+            self.$($backend)+.fetch_chunk_with_status(id, range)
+        }
+    };
+    (@method [$($backend:tt)+] fetch_manifest_bytes) => {
+        fn fetch_manifest_bytes<'a>(
+            &'a self,
+            id: &'a $crate::format::ManifestId,
+        ) -> $crate::storage::StorageFuture<'a, ::bytes::Bytes> {
+            self.$($backend)+.fetch_manifest_bytes(id)
+        }
+    };
+    (@method [$($backend:tt)+] list_objects) => {
+        fn list_objects<'a>(
+            &'a self,
+            kind: $crate::storage::ObjectKind,
+            location: $crate::storage::ObjectLocation,
+        ) -> $crate::storage::StorageFuture<
+            'a,
+            ::futures::stream::BoxStream<
+                'a,
+                $crate::storage::StorageResult<$crate::storage::ObjectInfo>,
+            >,
+        > {
+            self.$($backend)+.list_objects(kind, location)
+        }
+    };
+    (@method [$($backend:tt)+] move_object) => {
+        fn move_object<'a>(
+            &'a self,
+            kind: $crate::storage::ObjectKind,
+            id: &'a str,
+            to: $crate::storage::ObjectLocation,
+        ) -> $crate::storage::StorageFuture<'a, ()> {
+            self.$($backend)+.move_object(kind, id, to)
+        }
+    };
+    (@method [$($backend:tt)+] delete_object) => {
+        fn delete_object<'a>(
+            &'a self,
+            kind: $crate::storage::ObjectKind,
+            id: &'a str,
+            location: $crate::storage::ObjectLocation,
+        ) -> $crate::storage::StorageFuture<'a, ()> {
+            self.$($backend)+.delete_object(kind, id, location)
+        }
+    };
+    (@method [$($backend:tt)+] fetch_snapshot_artifact) => {
+        fn fetch_snapshot_artifact<'a>(
+            &'a self,
+            id: &'a $crate::format::SnapshotId,
+            name: &'a str,
+        ) -> $crate::storage::StorageFuture<'a, ::bytes::Bytes> {
+            self.$($backend)+.fetch_snapshot_artifact(id, name)
+        }
+    };
+    (@method [$($backend:tt)+] write_snapshot_artifact) => {
+        fn write_snapshot_artifact<'a>(
+            &'a self,
+            id: &'a $crate::format::SnapshotId,
+            name: &'a str,
+            bytes: ::bytes::Bytes,
+        ) -> $crate::storage::StorageFuture<'a, ()> {
+            self.$($backend)+.write_snapshot_artifact(id, name, bytes)
+        }
+    };
+    (@method [$($backend:tt)+] fetch_audit_records) => {
+        fn fetch_audit_records(
+            &self,
+        ) -> $crate::storage::StorageFuture<'_, Vec<::bytes::Bytes>> {
+            self.$($backend)+.fetch_audit_records()
+        }
+    };
+    (@method [$($backend:tt)+] write_audit_record) => {
+        fn write_audit_record<'a>(
+            &'a self,
+            id: &'a $crate::format::AuditRecordId,
+            bytes: ::bytes::Bytes,
+        ) -> $crate::storage::StorageFuture<'a, ()> {
+            self.$($backend)+.write_audit_record(id, bytes)
+        }
+    };
+    (@method [$($backend:tt)+] list_prefixes) => {
+        fn list_prefixes<'a>(
+            &'a self,
+            prefix: &'a str,
+        ) -> $crate::storage::StorageFuture<'a, Vec<String>> {
+            self.$($backend)+.list_prefixes(prefix)
+        }
+    };
+    (@method [$($backend:tt)+] sub_storage) => {
+        fn sub_storage(
+            &self,
+            prefix: &str,
+        ) -> $crate::storage::StorageResult<
+            ::std::sync::Arc<dyn $crate::storage::Storage>,
+        > {
+            self.$($backend)+.sub_storage(prefix)
+        }
+    };
+    (@method [$($backend:tt)+] key_layout) => {
+        fn key_layout(&self) -> $crate::storage::KeyLayout {
+            self.$($backend)+.key_layout()
+        }
+    };
+    (@method [$($backend:tt)+] chunk_copy_source) => {
+        fn chunk_copy_source(
+            &self,
+            id: &$crate::format::ChunkId,
+        ) -> $crate::storage::StorageResult<Option<$crate::storage::CopySource>> {
+            self.$($backend)+.chunk_copy_source(id)
+        }
+    };
+    (@method [$($backend:tt)+] can_copy_from) => {
+        fn can_copy_from(&self, source: &$crate::storage::CopySource) -> bool {
+            self.$($backend)+.can_copy_from(source)
+        }
+    };
+    (@method [$($backend:tt)+] copy_chunk_from) => {
+        fn copy_chunk_from<'a>(
+            &'a self,
+            id: $crate::format::ChunkId,
+            source: &'a $crate::storage::CopySource,
+        ) -> $crate::storage::StorageFuture<'a, ()> {
+            self.$($backend)+.copy_chunk_from(id, source)
+        }
+    };
+}
+
+/// Complete a snapshot read by a backend with the nodes of its structure base, if it's
+/// stored as a [`crate::format::snapshot::StructureDelta`].
+///
+/// Backends that deserialize snapshots themselves must call it before returning them from
+/// [`Storage::fetch_snapshot`], without it the snapshot lacks the nodes it shares with
+/// its base.
+pub async fn with_structure_base(
+    storage: &dyn Storage,
+    snapshot: Snapshot,
+) -> StorageResult<Snapshot> {
+    match snapshot.structure_base().cloned() {
+        Some(base) => {
+            Ok(snapshot.with_base(storage.fetch_snapshot(&base).await?.as_ref()))
+        }
+        None => Ok(snapshot),
+    }
+}
+
+/// Copy the chunk `id` from `source` to `target`, server side if they support it,
+/// downloading and uploading it otherwise. Returns true for server side copies.
+pub async fn copy_chunk(
+    source: &dyn Storage,
+    target: &dyn Storage,
+    id: &ChunkId,
+) -> StorageResult<bool> {
+    if let Some(copy_source) = source.chunk_copy_source(id)? {
+        if target.can_copy_from(&copy_source) {
+            match target.copy_chunk_from(id.clone(), &copy_source).await {
+                Ok(()) => return Ok(true),
+                Err(StorageError::Unsupported(_)) => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    let bytes = source.fetch_chunk(id, &ByteRange::ALL).await?;
+    target.write_chunk(id.clone(), bytes).await?;
+    Ok(false)
 }
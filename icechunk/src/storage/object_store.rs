@@ -1,24 +1,28 @@
-use crate::{
-    format::{
-        attributes::AttributesTable, format_constants, manifest::Manifest,
-        snapshot::Snapshot, AttributesId, ByteRange, ChunkId, FileTypeTag, ManifestId,
-        ObjectId, SnapshotId,
-    },
-    private,
+use crate::format::{
+    attributes::AttributesTable, format_constants, manifest::Manifest,
+    snapshot::Snapshot, AttributesId, AuditRecordId, ByteRange, ChunkId, FileTypeTag,
+    ManifestId, ObjectId, SnapshotId,
 };
-use async_trait::async_trait;
 use bytes::Bytes;
 use futures::{stream::BoxStream, StreamExt, TryStreamExt};
 use object_store::{
     local::LocalFileSystem, memory::InMemory, path::Path as ObjectPath, Attribute,
-    AttributeValue, Attributes, GetOptions, GetRange, ObjectStore, PutMode, PutOptions,
-    PutPayload,
+    AttributeValue, Attributes, GetOptions, GetRange, ObjectStore, PutMode,
+    PutMultipartOpts, PutOptions, PutPayload, WriteMultipart,
 };
+use serde::de::DeserializeOwned;
 use std::{
-    fs::create_dir_all, future::ready, ops::Bound, path::Path as StdPath, sync::Arc,
+    fs::create_dir_all,
+    future::ready,
+    ops::Bound,
+    path::{Path as StdPath, PathBuf},
+    sync::Arc,
 };
 
-use super::{Storage, StorageError, StorageResult};
+use super::{
+    layout::KeyLayout, with_structure_base, CopySource, ObjectInfo, ObjectKind,
+    ObjectLocation, Storage, StorageError, StorageFuture, StorageResult,
+};
 
 // Get Range is object_store specific, keep it with this module
 impl From<&ByteRange> for Option<GetRange> {
@@ -53,11 +57,16 @@ impl From<&ByteRange> for Option<GetRange> {
     }
 }
 
-const SNAPSHOT_PREFIX: &str = "snapshots/";
-const MANIFEST_PREFIX: &str = "manifests/";
-// const ATTRIBUTES_PREFIX: &str = "attributes/";
-const CHUNK_PREFIX: &str = "chunks/";
+const ARTIFACTS_PREFIX: &str = "artifacts";
+const AUDIT_PREFIX: &str = "audit";
 const REF_PREFIX: &str = "refs";
+const TRASH_PREFIX: &str = "trash";
+
+/// Manifests larger than this are serialized in parts, straight into a multipart upload
+const MULTIPART_THRESHOLD_BYTES: usize = 16 * 1024 * 1024;
+const MULTIPART_PART_BYTES: usize = 8 * 1024 * 1024;
+/// The parts of a multipart upload that are uploaded, and held in memory, at once
+const MAX_CONCURRENT_PARTS: usize = 8;
 
 #[derive(Debug)]
 pub struct ObjectStorage {
@@ -69,6 +78,11 @@ pub struct ObjectStorage {
 
     supports_create_if_not_exists: bool,
     supports_metadata: bool,
+    layout: KeyLayout,
+    // The directory of local filesystem stores, their snapshots and manifests are
+    // memory mapped instead of read
+    #[cfg_attr(not(feature = "mmap"), allow(dead_code))]
+    local_root: Option<PathBuf>,
 }
 
 impl ObjectStorage {
@@ -85,6 +99,8 @@ impl ObjectStorage {
             artificially_sort_refs_in_mem: false,
             supports_create_if_not_exists: true,
             supports_metadata: true,
+            layout: KeyLayout::default(),
+            local_root: None,
         }
     }
 
@@ -93,6 +109,7 @@ impl ObjectStorage {
     /// This implementation should not be used in production code.
     pub fn new_local_store(prefix: &StdPath) -> Result<ObjectStorage, std::io::Error> {
         create_dir_all(prefix)?;
+        let root = prefix.to_path_buf();
         let prefix = prefix.display().to_string();
         let store = Arc::new(LocalFileSystem::new_with_prefix(prefix.clone())?);
         Ok(ObjectStorage {
@@ -101,9 +118,16 @@ impl ObjectStorage {
             artificially_sort_refs_in_mem: true,
             supports_create_if_not_exists: true,
             supports_metadata: false,
+            layout: KeyLayout::default(),
+            local_root: Some(root),
         })
     }
 
+    pub fn with_key_layout(mut self, layout: KeyLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
     /// Return all keys in the store
     ///
     /// Intended for testing and debugging purposes only.
@@ -128,15 +152,28 @@ impl ObjectStorage {
     }
 
     fn get_snapshot_path(&self, id: &SnapshotId) -> ObjectPath {
-        self.get_path(SNAPSHOT_PREFIX, id)
+        self.object_path(ObjectKind::Snapshot, &id.to_string(), ObjectLocation::Live)
+    }
+
+    fn get_attributes_path(&self, id: &AttributesId) -> ObjectPath {
+        self.object_path(ObjectKind::Attributes, &id.to_string(), ObjectLocation::Live)
+    }
+
+    fn get_artifact_path(&self, id: &SnapshotId, name: &str) -> ObjectPath {
+        ObjectPath::from(format!("{}/{}/{}/{}", self.prefix, ARTIFACTS_PREFIX, id, name))
     }
 
     fn get_manifest_path(&self, id: &ManifestId) -> ObjectPath {
-        self.get_path(MANIFEST_PREFIX, id)
+        self.object_path(ObjectKind::Manifest, &id.to_string(), ObjectLocation::Live)
+    }
+
+    // stores have no name, only storages sharing the same store can copy between them
+    fn store_address(&self) -> String {
+        format!("{:p}", Arc::as_ptr(&self.store))
     }
 
     fn get_chunk_path(&self, id: &ChunkId) -> ObjectPath {
-        self.get_path(CHUNK_PREFIX, id)
+        self.object_path(ObjectKind::Chunk, &id.to_string(), ObjectLocation::Live)
     }
 
     fn drop_prefix(&self, prefix: &ObjectPath, path: &ObjectPath) -> Option<ObjectPath> {
@@ -148,7 +185,108 @@ impl ObjectStorage {
         ObjectPath::from(format!("{}/{}/{}", self.prefix.as_str(), REF_PREFIX, ref_key))
     }
 
-    async fn do_ref_versions(&self, ref_name: &str) -> BoxStream<StorageResult<String>> {
+    fn object_dir(&self, kind: ObjectKind, location: ObjectLocation) -> ObjectPath {
+        let kind_prefix = self.layout.prefix(kind);
+        match location {
+            ObjectLocation::Live => {
+                ObjectPath::from(format!("{}/{}", self.prefix, kind_prefix))
+            }
+            ObjectLocation::Trash => ObjectPath::from(format!(
+                "{}/{}/{}",
+                self.prefix, TRASH_PREFIX, kind_prefix
+            )),
+        }
+    }
+
+    fn object_path(
+        &self,
+        kind: ObjectKind,
+        id: &str,
+        location: ObjectLocation,
+    ) -> ObjectPath {
+        // ObjectPath splits the fan out directories of the key into parts
+        ObjectPath::from(format!(
+            "{}/{}",
+            self.object_dir(kind, location),
+            self.layout.relative_key(id)
+        ))
+    }
+
+    async fn delete_path(&self, path: &ObjectPath) -> StorageResult<()> {
+        match self.store.delete(path).await {
+            Ok(_) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(err) => Err(StorageError::from(err).with_key(path.as_ref())),
+        }
+    }
+
+    async fn get_path_bytes(
+        &self,
+        path: &ObjectPath,
+        options: GetOptions,
+    ) -> StorageResult<Bytes> {
+        let res: StorageResult<Bytes> =
+            async { Ok(self.store.get_opts(path, options).await?.bytes().await?) }.await;
+        res.map_err(|err| err.with_key(path.as_ref()))
+    }
+
+    /// Fetch and decode a messagepack object, local filesystem stores decode it straight
+    /// from a memory map of its file with the `mmap` feature
+    async fn get_path_msgpack<T: DeserializeOwned>(
+        &self,
+        path: &ObjectPath,
+    ) -> StorageResult<T> {
+        #[cfg(feature = "mmap")]
+        if let Some(root) = &self.local_root {
+            return read_mapped(&root.join(path.as_ref()))
+                .map_err(|err| err.with_key(path.as_ref()));
+        }
+        let bytes = self.get_path_bytes(path, GetOptions::default()).await?;
+        rmp_serde::from_slice(bytes.as_ref())
+            .map_err(|err| StorageError::from(err).with_key(path.as_ref()))
+    }
+
+    /// Write `parts` as a multipart upload, holding at most [`MAX_CONCURRENT_PARTS`] of
+    /// them in memory, the upload is aborted if a part fails
+    async fn put_path_parts(
+        &self,
+        path: &ObjectPath,
+        attributes: Attributes,
+        parts: impl Iterator<Item = StorageResult<Bytes>>,
+    ) -> StorageResult<()> {
+        let options = PutMultipartOpts { attributes, ..PutMultipartOpts::default() };
+        let upload = self
+            .store
+            .put_multipart_opts(path, options)
+            .await
+            .map_err(|err| StorageError::from(err).with_key(path.as_ref()))?;
+        let mut write = WriteMultipart::new_with_chunk_size(upload, MULTIPART_PART_BYTES);
+        let mut res: StorageResult<()> = Ok(());
+        for part in parts {
+            res = async {
+                write.wait_for_capacity(MAX_CONCURRENT_PARTS).await?;
+                write.put(part?);
+                Ok::<_, StorageError>(())
+            }
+            .await;
+            if res.is_err() {
+                break;
+            }
+        }
+        let res = match res {
+            Ok(()) => write.finish().await.map(|_| ()).map_err(StorageError::from),
+            Err(err) => {
+                // best effort, the store cleans up uploads left behind
+                let _ = write.abort().await;
+                Err(err)
+            }
+        };
+        res.map_err(|err| err.with_key(path.as_ref()))
+    }
+
+    async fn do_ref_versions(
+        &self,
+        ref_name: &str,
+    ) -> BoxStream<'_, StorageResult<String>> {
         let prefix = self.ref_key(ref_name);
         self.store
             .list(Some(prefix.clone()).as_ref())
@@ -166,48 +304,86 @@ impl ObjectStorage {
     }
 }
 
-impl private::Sealed for ObjectStorage {}
+/// Decode the messagepack file at `path` from a memory map, the pages of large manifests
+/// are read by the kernel as they are decoded, without copying the file to a buffer
+#[cfg(feature = "mmap")]
+fn read_mapped<T: DeserializeOwned>(path: &StdPath) -> StorageResult<T> {
+    let io_error = |err: std::io::Error| -> StorageError {
+        let path = path.display().to_string();
+        // the same errors the object store would return, callers check for NotFound
+        match err.kind() {
+            std::io::ErrorKind::NotFound => {
+                object_store::Error::NotFound { path, source: Box::new(err) }.into()
+            }
+            _ => object_store::Error::Generic {
+                store: "LocalFileSystem",
+                source: Box::new(err),
+            }
+            .into(),
+        }
+    };
+    let file = std::fs::File::open(path).map_err(io_error)?;
+    // SAFETY: objects are never overwritten once written, the file doesn't change
+    // while it's mapped. Deleting it leaves the mapping valid.
+    let map = unsafe { memmap2::Mmap::map(&file) }.map_err(io_error)?;
+    Ok(rmp_serde::from_slice(&map)?)
+}
 
-#[async_trait]
 impl Storage for ObjectStorage {
-    async fn fetch_snapshot(
-        &self,
-        id: &SnapshotId,
-    ) -> Result<Arc<Snapshot>, StorageError> {
-        let path = self.get_snapshot_path(id);
-        let bytes = self.store.get(&path).await?.bytes().await?;
-        // TODO: optimize using from_read
-        let res = rmp_serde::from_slice(bytes.as_ref())?;
-        Ok(Arc::new(res))
+    fn fetch_snapshot<'a>(
+        &'a self,
+        id: &'a SnapshotId,
+    ) -> StorageFuture<'a, Arc<Snapshot>> {
+        Box::pin(async move {
+            let path = self.get_snapshot_path(id);
+            let snapshot = self.get_path_msgpack(&path).await?;
+            Ok(Arc::new(with_structure_base(self, snapshot).await?))
+        })
     }
 
-    async fn fetch_attributes(
-        &self,
-        _id: &AttributesId,
-    ) -> Result<Arc<AttributesTable>, StorageError> {
-        todo!();
+    fn fetch_attributes<'a>(
+        &'a self,
+        id: &'a AttributesId,
+    ) -> StorageFuture<'a, Arc<AttributesTable>> {
+        Box::pin(async move {
+            let path = self.get_attributes_path(id);
+            let bytes = self.get_path_bytes(&path, GetOptions::default()).await?;
+            let res = rmp_serde::from_slice(bytes.as_ref())
+                .map_err(|err| StorageError::from(err).with_key(path.as_ref()))?;
+            Ok(Arc::new(res))
+        })
     }
 
-    async fn fetch_manifests(
-        &self,
-        id: &ManifestId,
-    ) -> Result<Arc<Manifest>, StorageError> {
-        let path = self.get_manifest_path(id);
-        let bytes = self.store.get(&path).await?.bytes().await?;
-        // TODO: optimize using from_read
-        let res = rmp_serde::from_slice(bytes.as_ref())?;
-        Ok(Arc::new(res))
+    fn fetch_manifests<'a>(
+        &'a self,
+        id: &'a ManifestId,
+    ) -> StorageFuture<'a, Arc<Manifest>> {
+        Box::pin(async move {
+            let path = self.get_manifest_path(id);
+            Ok(Arc::new(self.get_path_msgpack(&path).await?))
+        })
     }
 
-    async fn write_snapshot(
-        &self,
+    fn fetch_manifest_bytes<'a>(
+        &'a self,
+        id: &'a ManifestId,
+    ) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            let path = self.get_manifest_path(id);
+            self.get_path_bytes(&path, GetOptions::default()).await
+        })
+    }
+
+    fn write_snapshot<'a>(
+        &'a self,
         id: SnapshotId,
         snapshot: Arc<Snapshot>,
-    ) -> Result<(), StorageError> {
-        let path = self.get_snapshot_path(&id);
-        let bytes = rmp_serde::to_vec(snapshot.as_ref())?;
-        let attributes = if self.supports_metadata {
-            Attributes::from_iter(vec![
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let path = self.get_snapshot_path(&id);
+            let bytes = rmp_serde::to_vec(snapshot.as_ref())?;
+            let attributes = if self.supports_metadata {
+                Attributes::from_iter(vec![
                 (
                     Attribute::ContentType,
                     AttributeValue::from(
@@ -223,32 +399,65 @@ impl Storage for ObjectStorage {
                     ),
                 ),
             ])
-        } else {
-            Attributes::new()
-        };
-        let options = PutOptions { attributes, ..PutOptions::default() };
-        // FIXME: use multipart
-        self.store.put_opts(&path, bytes.into(), options).await?;
-        Ok(())
+            } else {
+                Attributes::new()
+            };
+            let options = PutOptions { attributes, ..PutOptions::default() };
+            // FIXME: use multipart
+            self.store
+                .put_opts(&path, bytes.into(), options)
+                .await
+                .map_err(|err| StorageError::from(err).with_key(path.as_ref()))?;
+            Ok(())
+        })
     }
 
-    async fn write_attributes(
-        &self,
-        _id: AttributesId,
-        _table: Arc<AttributesTable>,
-    ) -> Result<(), StorageError> {
-        todo!()
+    fn write_attributes<'a>(
+        &'a self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let path = self.get_attributes_path(&id);
+            let bytes = rmp_serde::to_vec(table.as_ref())?;
+            let attributes = if self.supports_metadata {
+                Attributes::from_iter(vec![
+                (
+                    Attribute::ContentType,
+                    AttributeValue::from(
+                        format_constants::LATEST_ICECHUNK_ATTRIBUTES_CONTENT_TYPE,
+                    ),
+                ),
+                (
+                    Attribute::Metadata(std::borrow::Cow::Borrowed(
+                        format_constants::LATEST_ICECHUNK_ATTRIBUTES_VERSION_METADATA_KEY,
+                    )),
+                    AttributeValue::from(
+                        table.icechunk_attributes_format_version.to_string(),
+                    ),
+                ),
+            ])
+            } else {
+                Attributes::new()
+            };
+            let options = PutOptions { attributes, ..PutOptions::default() };
+            self.store
+                .put_opts(&path, bytes.into(), options)
+                .await
+                .map_err(|err| StorageError::from(err).with_key(path.as_ref()))?;
+            Ok(())
+        })
     }
 
-    async fn write_manifests(
-        &self,
+    fn write_manifests<'a>(
+        &'a self,
         id: ManifestId,
         manifest: Arc<Manifest>,
-    ) -> Result<(), StorageError> {
-        let path = self.get_manifest_path(&id);
-        let bytes = rmp_serde::to_vec(manifest.as_ref())?;
-        let attributes = if self.supports_metadata {
-            Attributes::from_iter(vec![
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let path = self.get_manifest_path(&id);
+            let attributes = if self.supports_metadata {
+                Attributes::from_iter(vec![
                 (
                     Attribute::ContentType,
                     AttributeValue::from(
@@ -264,113 +473,377 @@ impl Storage for ObjectStorage {
                     ),
                 ),
             ])
-        } else {
-            Attributes::new()
-        };
-        let options = PutOptions { attributes, ..PutOptions::default() };
-        // FIXME: use multipart
-        self.store.put_opts(&path, bytes.into(), options).await?;
-        Ok(())
+            } else {
+                Attributes::new()
+            };
+            if manifest.estimated_size_bytes() > MULTIPART_THRESHOLD_BYTES as u64 {
+                // serialized while it's uploaded, large manifests are never in memory
+                // twice
+                let parts = manifest
+                    .serialized_parts(MULTIPART_PART_BYTES)
+                    .map(|part| part.map_err(StorageError::from));
+                return self.put_path_parts(&path, attributes, parts).await;
+            }
+            let bytes = rmp_serde::to_vec(manifest.as_ref())?;
+            let options = PutOptions { attributes, ..PutOptions::default() };
+            self.store
+                .put_opts(&path, bytes.into(), options)
+                .await
+                .map_err(|err| StorageError::from(err).with_key(path.as_ref()))?;
+            Ok(())
+        })
     }
 
-    async fn fetch_chunk(
-        &self,
-        id: &ChunkId,
-        range: &ByteRange,
-    ) -> Result<Bytes, StorageError> {
-        let path = self.get_chunk_path(id);
-        // TODO: shall we split `range` into multiple ranges and use get_ranges?
-        // I can't tell that `get_range` does splitting
-        let options =
-            GetOptions { range: Option::<GetRange>::from(range), ..Default::default() };
-        let chunk = self.store.get_opts(&path, options).await?.bytes().await?;
-        Ok(chunk)
-    }
-
-    async fn write_chunk(
-        &self,
+    fn fetch_snapshot_artifact<'a>(
+        &'a self,
+        id: &'a SnapshotId,
+        name: &'a str,
+    ) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            let path = self.get_artifact_path(id, name);
+            self.get_path_bytes(&path, GetOptions::default()).await
+        })
+    }
+
+    fn write_snapshot_artifact<'a>(
+        &'a self,
+        id: &'a SnapshotId,
+        name: &'a str,
+        bytes: Bytes,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let path = self.get_artifact_path(id, name);
+            self.store
+                .put(&path, bytes.into())
+                .await
+                .map_err(|err| StorageError::from(err).with_key(path.as_ref()))?;
+            Ok(())
+        })
+    }
+
+    fn fetch_audit_records(&self) -> StorageFuture<'_, Vec<Bytes>> {
+        Box::pin(async move {
+            let dir = ObjectPath::from(format!("{}/{}", self.prefix, AUDIT_PREFIX));
+            let paths: Vec<_> = self
+                .store
+                .list(Some(&dir))
+                .map_ok(|meta| meta.location)
+                .try_collect()
+                .await?;
+            let mut res = Vec::with_capacity(paths.len());
+            for path in paths {
+                res.push(self.get_path_bytes(&path, GetOptions::default()).await?);
+            }
+            Ok(res)
+        })
+    }
+
+    fn write_audit_record<'a>(
+        &'a self,
+        id: &'a AuditRecordId,
+        bytes: Bytes,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let path = self.get_path(AUDIT_PREFIX, id);
+            self.store
+                .put(&path, bytes.into())
+                .await
+                .map_err(|err| StorageError::from(err).with_key(path.as_ref()))?;
+            Ok(())
+        })
+    }
+
+    fn delete_snapshot<'a>(&'a self, id: &'a SnapshotId) -> StorageFuture<'a, ()> {
+        Box::pin(async move { self.delete_path(&self.get_snapshot_path(id)).await })
+    }
+
+    fn delete_manifests<'a>(&'a self, id: &'a ManifestId) -> StorageFuture<'a, ()> {
+        Box::pin(async move { self.delete_path(&self.get_manifest_path(id)).await })
+    }
+
+    fn delete_chunk<'a>(&'a self, id: &'a ChunkId) -> StorageFuture<'a, ()> {
+        Box::pin(async move { self.delete_path(&self.get_chunk_path(id)).await })
+    }
+
+    fn fetch_chunk<'a>(
+        &'a self,
+        id: &'a ChunkId,
+        range: &'a ByteRange,
+    ) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            let path = self.get_chunk_path(id);
+            // TODO: shall we split `range` into multiple ranges and use get_ranges?
+            // I can't tell that `get_range` does splitting
+            let options = GetOptions {
+                range: Option::<GetRange>::from(range),
+                ..Default::default()
+            };
+            self.get_path_bytes(&path, options).await
+        })
+    }
+
+    fn write_chunk<'a>(
+        &'a self,
         id: ChunkId,
         bytes: bytes::Bytes,
-    ) -> Result<(), StorageError> {
-        let path = self.get_chunk_path(&id);
-        let upload = self.store.put_multipart(&path).await?;
-        // TODO: new_with_chunk_size?
-        let mut write = object_store::WriteMultipart::new(upload);
-        write.write(&bytes);
-        write.finish().await?;
-        Ok(())
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let path = self.get_chunk_path(&id);
+            let res: StorageResult<()> = async {
+                let upload = self.store.put_multipart(&path).await?;
+                // TODO: new_with_chunk_size?
+                let mut write = object_store::WriteMultipart::new(upload);
+                write.write(&bytes);
+                write.finish().await?;
+                Ok(())
+            }
+            .await;
+            res.map_err(|err| err.with_key(path.as_ref()))
+        })
     }
 
-    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
-        let key = self.ref_key(ref_key);
-        match self.store.get(&key).await {
-            Ok(res) => Ok(res.bytes().await?),
-            Err(object_store::Error::NotFound { .. }) => {
-                Err(StorageError::RefNotFound(key.to_string()))
+    fn get_ref<'a>(&'a self, ref_key: &'a str) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            let key = self.ref_key(ref_key);
+            match self.store.get(&key).await {
+                Ok(res) => Ok(res.bytes().await?),
+                Err(object_store::Error::NotFound { .. }) => {
+                    Err(StorageError::RefNotFound(key.to_string()))
+                }
+                Err(err) => Err(err.into()),
             }
-            Err(err) => Err(err.into()),
-        }
+        })
     }
 
-    async fn ref_names(&self) -> StorageResult<Vec<String>> {
-        // FIXME: i don't think object_store's implementation of list_with_delimiter is any good
-        // we need to test if it even works beyond 1k refs
-        let prefix = self.ref_key("");
+    fn ref_names<'a>(&'a self) -> StorageFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            // FIXME: i don't think object_store's implementation of list_with_delimiter is any good
+            // we need to test if it even works beyond 1k refs
+            let prefix = self.ref_key("");
 
-        Ok(self
-            .store
-            .list_with_delimiter(Some(prefix.clone()).as_ref())
-            .await?
-            .common_prefixes
-            .iter()
-            .filter_map(|path| {
-                self.drop_prefix(&prefix, path).map(|path| path.to_string())
-            })
-            .collect())
+            Ok(self
+                .store
+                .list_with_delimiter(Some(prefix.clone()).as_ref())
+                .await?
+                .common_prefixes
+                .iter()
+                .filter_map(|path| {
+                    self.drop_prefix(&prefix, path).map(|path| path.to_string())
+                })
+                .collect())
+        })
     }
 
-    async fn ref_versions(
-        &self,
-        ref_name: &str,
-    ) -> StorageResult<BoxStream<StorageResult<String>>> {
-        let res = self.do_ref_versions(ref_name).await;
-        if self.artificially_sort_refs_in_mem {
-            #[allow(clippy::expect_used)]
-            // This branch is used for local tests, not in production. We don't expect the size of
-            // these streams to be large, so we can collect in memory and fail early if there is an
-            // error
-            let mut all =
-                res.try_collect::<Vec<_>>().await.expect("Error fetching ref versions");
-            all.sort();
-            Ok(futures::stream::iter(all.into_iter().map(Ok)).boxed())
-        } else {
-            Ok(res)
-        }
+    fn ref_versions<'a, 'b>(
+        &'a self,
+        ref_name: &'b str,
+    ) -> StorageFuture<'b, BoxStream<'a, StorageResult<String>>>
+    where
+        'a: 'b,
+    {
+        Box::pin(async move {
+            let res = self.do_ref_versions(ref_name).await;
+            if self.artificially_sort_refs_in_mem {
+                #[allow(clippy::expect_used)]
+                // This branch is used for local tests, not in production. We don't expect the size of
+                // these streams to be large, so we can collect in memory and fail early if there is an
+                // error
+                let mut all = res
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .expect("Error fetching ref versions");
+                all.sort();
+                Ok(futures::stream::iter(all.into_iter().map(Ok)).boxed())
+            } else {
+                Ok(res)
+            }
+        })
     }
 
-    async fn write_ref(
-        &self,
-        ref_key: &str,
+    fn write_ref<'a>(
+        &'a self,
+        ref_key: &'a str,
         overwrite_refs: bool,
         bytes: Bytes,
-    ) -> StorageResult<()> {
-        let key = self.ref_key(ref_key);
-        let mode = if overwrite_refs || !self.supports_create_if_not_exists {
-            PutMode::Overwrite
-        } else {
-            PutMode::Create
-        };
-        let opts = PutOptions { mode, ..PutOptions::default() };
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.ref_key(ref_key);
+            let mode = if overwrite_refs || !self.supports_create_if_not_exists {
+                PutMode::Overwrite
+            } else {
+                PutMode::Create
+            };
+            let opts = PutOptions { mode, ..PutOptions::default() };
 
-        self.store
-            .put_opts(&key, PutPayload::from_bytes(bytes), opts)
-            .await
-            .map_err(|e| match e {
-                object_store::Error::AlreadyExists { path, .. } => {
-                    StorageError::RefAlreadyExists(path)
-                }
-                _ => e.into(),
+            self.store
+                .put_opts(&key, PutPayload::from_bytes(bytes), opts)
+                .await
+                .map_err(|e| match e {
+                    object_store::Error::AlreadyExists { path, .. } => {
+                        StorageError::RefAlreadyExists(path)
+                    }
+                    _ => e.into(),
+                })
+                .map(|_| ())
+        })
+    }
+
+    fn delete_ref<'a>(&'a self, ref_key: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move { self.delete_path(&self.ref_key(ref_key)).await })
+    }
+
+    fn list_objects<'a>(
+        &'a self,
+        kind: ObjectKind,
+        location: ObjectLocation,
+    ) -> StorageFuture<'a, BoxStream<'a, StorageResult<ObjectInfo>>> {
+        Box::pin(async move {
+            let dir = self.object_dir(kind, location);
+            let objects =
+                self.store.list(Some(&dir)).map_err(|e| e.into()).and_then(|meta| {
+                    ready(
+                        meta.location
+                            .filename()
+                            .map(|id| ObjectInfo {
+                                id: id.to_string(),
+                                modified_at: meta.last_modified,
+                            })
+                            .ok_or(StorageError::Other(
+                                "Bug in object prefix logic".to_string(),
+                            )),
+                    )
+                });
+            Ok(objects.boxed())
+        })
+    }
+
+    fn move_object<'a>(
+        &'a self,
+        kind: ObjectKind,
+        id: &'a str,
+        to: ObjectLocation,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let from = match to {
+                ObjectLocation::Live => ObjectLocation::Trash,
+                ObjectLocation::Trash => ObjectLocation::Live,
+            };
+            let from = self.object_path(kind, id, from);
+            // the object is rewritten instead of renamed, renames keep the modification
+            // time in the local filesystem, and trashed objects are expired by it
+            let res = self.store.get(&from).await?;
+            let attributes = res.attributes.clone();
+            let bytes = res.bytes().await?;
+            let options = PutOptions { attributes, ..PutOptions::default() };
+            self.store
+                .put_opts(&self.object_path(kind, id, to), bytes.into(), options)
+                .await?;
+            self.delete_path(&from).await
+        })
+    }
+
+    fn delete_object<'a>(
+        &'a self,
+        kind: ObjectKind,
+        id: &'a str,
+        location: ObjectLocation,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(
+            async move { self.delete_path(&self.object_path(kind, id, location)).await },
+        )
+    }
+
+    fn list_prefixes<'a>(&'a self, prefix: &'a str) -> StorageFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            let dir = ObjectPath::from(format!("{}/{}", self.prefix, prefix));
+            Ok(self
+                .store
+                .list_with_delimiter(Some(&dir))
+                .await?
+                .common_prefixes
+                .iter()
+                .filter_map(|path| path.filename().map(|name| name.to_string()))
+                .collect())
+        })
+    }
+
+    fn sub_storage(&self, prefix: &str) -> StorageResult<Arc<dyn Storage>> {
+        Ok(Arc::new(ObjectStorage {
+            store: Arc::clone(&self.store),
+            prefix: format!("{}/{}", self.prefix, prefix),
+            artificially_sort_refs_in_mem: self.artificially_sort_refs_in_mem,
+            supports_create_if_not_exists: self.supports_create_if_not_exists,
+            supports_metadata: self.supports_metadata,
+            layout: self.layout.clone(),
+            local_root: self.local_root.clone(),
+        }))
+    }
+
+    fn key_layout(&self) -> KeyLayout {
+        self.layout.clone()
+    }
+
+    fn chunk_copy_source(&self, id: &ChunkId) -> StorageResult<Option<CopySource>> {
+        Ok(Some(CopySource {
+            provider: "object_store",
+            container: self.store_address(),
+            key: self.get_chunk_path(id).to_string(),
+        }))
+    }
+
+    fn can_copy_from(&self, source: &CopySource) -> bool {
+        source.provider == "object_store" && source.container == self.store_address()
+    }
+
+    fn copy_chunk_from<'a>(
+        &'a self,
+        id: ChunkId,
+        source: &'a CopySource,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.store
+                .copy(&ObjectPath::from(source.key.as_str()), &self.get_chunk_path(&id))
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        error::ErrorKind,
+        format::{
+            manifest::{ChunkInfo, ChunkPayload},
+            ChunkIndices,
+        },
+    };
+
+    #[tokio::test]
+    async fn test_local_manifest_round_trip() -> Result<(), Box<dyn Error>> {
+        let dir = tempfile::tempdir()?;
+        let storage = ObjectStorage::new_local_store(dir.path())?;
+        let manifest: Manifest = (0..4)
+            .map(|i| ChunkInfo {
+                node: 1,
+                coord: ChunkIndices(vec![i]),
+                payload: ChunkPayload::Inline(Bytes::from(vec![i as u8; 8])),
             })
-            .map(|_| ())
+            .collect();
+        let id = ManifestId::random();
+        storage.write_manifests(id.clone(), Arc::new(manifest.clone())).await?;
+        // with the `mmap` feature, the manifest is decoded from a memory map
+        assert_eq!(storage.fetch_manifests(&id).await?.as_ref(), &manifest);
+
+        let missing = storage.fetch_manifests(&ManifestId::random()).await.unwrap_err();
+        assert_eq!(missing.kind(), ErrorKind::NotFound);
+        assert!(missing.object_key().is_some());
+        Ok(())
     }
 }
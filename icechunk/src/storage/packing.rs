@@ -0,0 +1,329 @@
+//! Packfiles, many small manifests stored as a single object.
+//!
+//! Commits that change a few chunks in many arrays write many manifests of a few KB,
+//! each one a request on write and on read. [`PackingStorage`] holds the small manifests
+//! until the snapshot that uses them is written, and writes them before it as a single
+//! object, the packfile. The snapshot records the byte range of each packed manifest it
+//! uses, including those packed by previous commits, so readers only need the snapshot
+//! to find them.
+//!
+//! Packfiles are stored as chunks. All readers and writers of a repository with packed
+//! manifests, including garbage collection, must go through a [`PackingStorage`].
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use bytes::{Bytes, BytesMut};
+use quick_cache::sync::Cache;
+
+use super::{Storage, StorageError, StorageFuture, StorageResult};
+use crate::format::{
+    manifest::Manifest,
+    snapshot::{NodeData, PackedManifest, Snapshot},
+    ByteRange, ChunkId, ManifestId, SnapshotId,
+};
+
+/// Manifests up to this size are packed by default
+pub const DEFAULT_MAX_PACKED_BYTES: u64 = 64 * 1024;
+
+// packfiles are small, keeping a few avoids fetching them again for each manifest
+const CACHED_PACKS: usize = 16;
+
+#[derive(Debug)]
+pub struct PackingStorage {
+    backend: Arc<dyn Storage>,
+    max_packed_bytes: u64,
+    // serialized manifests waiting for their snapshot
+    pending: Mutex<HashMap<ManifestId, Bytes>>,
+    // every packed manifest seen in a written or fetched snapshot
+    locations: Mutex<HashMap<ManifestId, PackedManifest>>,
+    packs: Cache<ChunkId, Bytes>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl PackingStorage {
+    pub fn new(backend: Arc<dyn Storage>) -> Self {
+        Self {
+            backend,
+            max_packed_bytes: DEFAULT_MAX_PACKED_BYTES,
+            pending: Mutex::new(HashMap::new()),
+            locations: Mutex::new(HashMap::new()),
+            packs: Cache::new(CACHED_PACKS),
+        }
+    }
+
+    /// Pack manifests up to `max_bytes` when serialized, larger ones are written alone
+    pub fn with_max_packed_bytes(self, max_bytes: u64) -> Self {
+        Self { max_packed_bytes: max_bytes, ..self }
+    }
+
+    fn register(&self, packed: &[PackedManifest]) {
+        let mut locations = lock(&self.locations);
+        for manifest in packed {
+            locations.insert(manifest.id.clone(), manifest.clone());
+        }
+    }
+
+    async fn fetch_packed(&self, location: &PackedManifest) -> StorageResult<Bytes> {
+        let pack = match self.packs.get_value_or_guard_async(&location.pack).await {
+            Ok(pack) => pack,
+            Err(guard) => {
+                let pack =
+                    self.backend.fetch_chunk(&location.pack, &ByteRange::ALL).await?;
+                let _fail_is_ok = guard.insert(pack.clone());
+                pack
+            }
+        };
+        ByteRange::checked_from_offset_with_length(location.offset, location.length)
+            .and_then(|range| range.slice(pack))
+            .map_err(|err| {
+                StorageError::Other(format!(
+                    "packfile {} is too short for manifest {}: {err}",
+                    location.pack, location.id
+                ))
+            })
+    }
+
+    /// Write the pending manifests of `snapshot` as a packfile, returns the location of
+    /// every packed manifest the snapshot uses
+    async fn pack(&self, snapshot: &Snapshot) -> StorageResult<Vec<PackedManifest>> {
+        let new: Vec<(ManifestId, Bytes)> = {
+            let mut pending = lock(&self.pending);
+            snapshot
+                .manifest_files
+                .iter()
+                .filter_map(|file| pending.remove_entry(&file.id))
+                .collect()
+        };
+        if !new.is_empty() {
+            let pack = ChunkId::random();
+            let mut bytes = BytesMut::new();
+            let mut packed = Vec::with_capacity(new.len());
+            for (id, manifest) in new {
+                packed.push(PackedManifest {
+                    id,
+                    pack: pack.clone(),
+                    offset: bytes.len() as u64,
+                    length: manifest.len() as u64,
+                });
+                bytes.extend_from_slice(&manifest);
+            }
+            self.backend.write_chunk(pack, bytes.freeze()).await?;
+            self.register(&packed);
+        }
+
+        let used: HashSet<&ManifestId> = snapshot
+            .iter()
+            .filter_map(|node| match &node.node_data {
+                NodeData::Array(_, refs) => Some(refs.iter().map(|mref| &mref.object_id)),
+                NodeData::Group | NodeData::Concatenated(..) => None,
+            })
+            .flatten()
+            .collect();
+        let locations = lock(&self.locations);
+        let mut packed: Vec<_> =
+            used.into_iter().filter_map(|id| locations.get(id).cloned()).collect();
+        packed.sort_by(|a, b| (&a.pack, a.offset).cmp(&(&b.pack, b.offset)));
+        Ok(packed)
+    }
+}
+
+impl Storage for PackingStorage {
+    fn fetch_snapshot<'a>(
+        &'a self,
+        id: &'a SnapshotId,
+    ) -> StorageFuture<'a, Arc<Snapshot>> {
+        Box::pin(async move {
+            let snapshot = self.backend.fetch_snapshot(id).await?;
+            self.register(&snapshot.packed_manifests);
+            Ok(snapshot)
+        })
+    }
+
+    fn fetch_manifests<'a>(
+        &'a self,
+        id: &'a ManifestId,
+    ) -> StorageFuture<'a, Arc<Manifest>> {
+        Box::pin(async move {
+            let pending = lock(&self.pending).get(id).cloned();
+            let location = lock(&self.locations).get(id).cloned();
+            let bytes = match (pending, location) {
+                (Some(bytes), _) => bytes,
+                (None, Some(location)) => self.fetch_packed(&location).await?,
+                (None, None) => return self.backend.fetch_manifests(id).await,
+            };
+            Ok(Arc::new(rmp_serde::from_slice(bytes.as_ref())?))
+        })
+    }
+
+    fn fetch_manifest_bytes<'a>(
+        &'a self,
+        id: &'a ManifestId,
+    ) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            let pending = lock(&self.pending).get(id).cloned();
+            let location = lock(&self.locations).get(id).cloned();
+            match (pending, location) {
+                (Some(bytes), _) => Ok(bytes),
+                (None, Some(location)) => self.fetch_packed(&location).await,
+                (None, None) => self.backend.fetch_manifest_bytes(id).await,
+            }
+        })
+    }
+
+    fn write_snapshot<'a>(
+        &'a self,
+        id: SnapshotId,
+        snapshot: Arc<Snapshot>,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let packed = self.pack(snapshot.as_ref()).await?;
+            let snapshot = if packed.is_empty() && snapshot.packed_manifests.is_empty() {
+                snapshot
+            } else {
+                let mut snapshot = snapshot.as_ref().clone();
+                snapshot.packed_manifests = packed;
+                Arc::new(snapshot)
+            };
+            self.backend.write_snapshot(id, snapshot).await
+        })
+    }
+
+    fn write_manifests<'a>(
+        &'a self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let bytes = rmp_serde::to_vec(table.as_ref())?;
+            if bytes.len() as u64 > self.max_packed_bytes {
+                return self.backend.write_manifests(id, table).await;
+            }
+            lock(&self.pending).insert(id, Bytes::from(bytes));
+            Ok(())
+        })
+    }
+
+    fn delete_manifests<'a>(&'a self, id: &'a ManifestId) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            if lock(&self.pending).remove(id).is_some() {
+                return Ok(());
+            }
+            self.backend.delete_manifests(id).await
+        })
+    }
+
+    fn sub_storage(&self, prefix: &str) -> StorageResult<Arc<dyn Storage>> {
+        Ok(Arc::new(
+            PackingStorage::new(self.backend.sub_storage(prefix)?)
+                .with_max_packed_bytes(self.max_packed_bytes),
+        ))
+    }
+
+    crate::forward_storage!(
+        backend =>
+            fetch_attributes, fetch_chunk, write_attributes, write_chunk, delete_snapshot,
+            delete_chunk, get_ref, ref_names, ref_versions, write_ref, delete_ref,
+            list_objects, move_object, delete_object, fetch_snapshot_artifact,
+            write_snapshot_artifact, fetch_audit_records, write_audit_record,
+            list_prefixes, key_layout, chunk_copy_source, can_copy_from, copy_chunk_from,
+    );
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{
+            manifest::{ChunkPayload, ManifestSplitPolicy},
+            ChunkIndices, Path,
+        },
+        gc::{garbage_collect, GcConfig},
+        metadata::FillValue,
+        refs::Ref,
+        storage::{recording::StorageOperation, RecordingStorage},
+        synthetic::vector_metadata,
+        ObjectStorage, Repository,
+    };
+
+    #[tokio::test]
+    async fn test_packed_manifests() {
+        let backend: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(None));
+        let recording = Arc::new(RecordingStorage::new(Arc::clone(&backend)));
+        let storage: Arc<dyn Storage> =
+            Arc::new(PackingStorage::new(Arc::clone(&recording) as Arc<dyn Storage>));
+        let mut repo =
+            Repository::init(Arc::clone(&storage), false).await.unwrap().build();
+        let meta = vector_metadata(4, 1, FillValue::Int32(0));
+        let paths: Vec<Path> = ["/a", "/b", "/c"]
+            .iter()
+            .map(|p| p.to_string().try_into().unwrap())
+            .collect();
+        repo.add_group(Path::root()).await.unwrap();
+        for path in paths.iter() {
+            repo.add_array(path.clone(), meta.clone()).await.unwrap();
+            repo.set_chunk_ref(
+                path.clone(),
+                ChunkIndices(vec![0]),
+                Some(ChunkPayload::Inline(Bytes::from(path.to_string()))),
+            )
+            .await
+            .unwrap();
+        }
+        let first = repo.commit(Ref::DEFAULT_BRANCH, "first", None).await.unwrap();
+        let snapshot = backend.fetch_snapshot(&first).await.unwrap();
+        assert_eq!(snapshot.packed_manifests.len(), snapshot.manifest_files.len());
+        assert_eq!(recording.count(StorageOperation::WriteManifests), 0);
+        assert_eq!(recording.count(StorageOperation::WriteChunk), 1);
+
+        // a commit that only changes `/a` keeps using the packed manifests of the others
+        let mut repo = Repository::update(Arc::clone(&storage), first)
+            .with_manifest_split_policy(ManifestSplitPolicy {
+                max_rows: Some(1),
+                ..Default::default()
+            })
+            .build();
+        repo.set_chunk_ref(
+            paths[0].clone(),
+            ChunkIndices(vec![1]),
+            Some(ChunkPayload::Inline(Bytes::from_static(b"new"))),
+        )
+        .await
+        .unwrap();
+        let second = repo.commit(Ref::DEFAULT_BRANCH, "second", None).await.unwrap();
+
+        // a new reader finds every manifest through the snapshot
+        let reader: Arc<dyn Storage> =
+            Arc::new(PackingStorage::new(Arc::clone(&backend)));
+        let repo = Repository::update(Arc::clone(&reader), second.clone()).build();
+        for path in paths.iter() {
+            assert_eq!(
+                repo.get_chunk_ref(path, &ChunkIndices(vec![0])).await.unwrap(),
+                Some(ChunkPayload::Inline(Bytes::from(path.to_string())))
+            );
+        }
+        assert_eq!(
+            repo.get_chunk_ref(&paths[0], &ChunkIndices(vec![1])).await.unwrap(),
+            Some(ChunkPayload::Inline(Bytes::from_static(b"new")))
+        );
+
+        // packfiles are reachable for garbage collection
+        let summary =
+            garbage_collect(reader.as_ref(), &GcConfig::new(chrono::Utc::now()))
+                .await
+                .unwrap();
+        assert_eq!(summary.chunks, 0);
+        let reader: Arc<dyn Storage> = Arc::new(PackingStorage::new(backend));
+        let repo = Repository::update(reader, second).build();
+        assert!(repo.get_chunk_ref(&paths[1], &ChunkIndices(vec![0])).await.is_ok());
+    }
+}
@@ -0,0 +1,265 @@
+//! Caches of the byte ranges read through virtual chunk references.
+//!
+//! Workloads that slice the same external files over and over fetch the same ranges
+//! many times. A [`RangeCache`] set with
+//! [`crate::RepositoryBuilder::with_virtual_range_cache`] keeps them, keyed by the file,
+//! its etag and the range, so a file that changes is never served from stale entries.
+//! The etag of each file is read once per repository instance.
+//!
+//! [`MemoryRangeCache`] keeps ranges in memory, up to a size, and [`DiskRangeCache`]
+//! keeps them as files in a local directory, that can be reused between processes.
+use std::{
+    fmt::Debug,
+    path::Path as StdPath,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use object_store::{local::LocalFileSystem, path::Path as ObjectPath, ObjectStore};
+use quick_cache::{sync::Cache, Weighter};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::format::{manifest::VirtualChunkLocation, ByteRange};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RangeCacheKey {
+    pub location: VirtualChunkLocation,
+    /// The version of the file the range was read from
+    pub etag: String,
+    pub range: ByteRange,
+}
+
+impl RangeCacheKey {
+    /// A hash of the key, usable as a file name
+    pub fn digest(&self) -> String {
+        let VirtualChunkLocation::Absolute(location) = &self.location;
+        let mut hasher = Sha256::new();
+        for part in [location.as_str(), self.etag.as_str(), &format!("{:?}", self.range)]
+        {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+        hasher.finalize().iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+}
+
+/// The values of the counters at some point, see [`RangeCacheMetrics::stats`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RangeCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Bytes served from the cache
+    pub bytes_hit: u64,
+    /// Bytes fetched from the files, because they weren't in the cache
+    pub bytes_fetched: u64,
+}
+
+impl RangeCacheStats {
+    /// The fraction of reads served from the cache, 0 if there were no reads
+    pub fn hit_rate(&self) -> f64 {
+        let reads = self.hits + self.misses;
+        if reads == 0 {
+            0.0
+        } else {
+            self.hits as f64 / reads as f64
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RangeCacheMetrics {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    bytes_hit: AtomicU64,
+    bytes_fetched: AtomicU64,
+}
+
+impl RangeCacheMetrics {
+    pub fn stats(&self) -> RangeCacheStats {
+        RangeCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bytes_hit: self.bytes_hit.load(Ordering::Relaxed),
+            bytes_fetched: self.bytes_fetched.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn record_hit(&self, bytes: u64) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        self.bytes_hit.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_miss(&self, bytes: u64) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        self.bytes_fetched.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Storage for byte ranges of virtual chunk files.
+///
+/// Caches are best effort, failing to read or write an entry is the same as a miss.
+#[async_trait]
+pub trait RangeCache: Debug + Send + Sync {
+    async fn get(&self, key: &RangeCacheKey) -> Option<Bytes>;
+
+    async fn insert(&self, key: RangeCacheKey, bytes: Bytes);
+
+    /// The hits and misses of the cache, recorded by the virtual chunk resolver
+    fn metrics(&self) -> &RangeCacheMetrics;
+}
+
+#[derive(Debug, Clone)]
+struct BytesWeighter;
+
+impl Weighter<RangeCacheKey, Bytes> for BytesWeighter {
+    fn weight(&self, _key: &RangeCacheKey, val: &Bytes) -> u64 {
+        // empty ranges still take an entry
+        (val.len() as u64).max(1)
+    }
+}
+
+/// Ranges kept in memory, the least recently used are evicted past `max_bytes`
+#[derive(Debug)]
+pub struct MemoryRangeCache {
+    cache: Cache<RangeCacheKey, Bytes, BytesWeighter>,
+    metrics: RangeCacheMetrics,
+}
+
+impl MemoryRangeCache {
+    /// A cache of up to `max_bytes`, `estimated_ranges` is used to size the index
+    pub fn new(max_bytes: u64, estimated_ranges: usize) -> Self {
+        Self {
+            cache: Cache::with_weighter(estimated_ranges, max_bytes, BytesWeighter),
+            metrics: RangeCacheMetrics::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl RangeCache for MemoryRangeCache {
+    async fn get(&self, key: &RangeCacheKey) -> Option<Bytes> {
+        self.cache.get(key)
+    }
+
+    async fn insert(&self, key: RangeCacheKey, bytes: Bytes) {
+        self.cache.insert(key, bytes);
+    }
+
+    fn metrics(&self) -> &RangeCacheMetrics {
+        &self.metrics
+    }
+}
+
+/// Ranges kept as files in a local directory, named by [`RangeCacheKey::digest`].
+///
+/// Entries are never evicted, the directory can be deleted at any time to free space.
+#[derive(Debug)]
+pub struct DiskRangeCache {
+    store: LocalFileSystem,
+    metrics: RangeCacheMetrics,
+}
+
+impl DiskRangeCache {
+    pub fn new(directory: &StdPath) -> Result<Self, std::io::Error> {
+        std::fs::create_dir_all(directory)?;
+        let store = LocalFileSystem::new_with_prefix(directory)?;
+        Ok(Self { store, metrics: RangeCacheMetrics::default() })
+    }
+}
+
+#[async_trait]
+impl RangeCache for DiskRangeCache {
+    async fn get(&self, key: &RangeCacheKey) -> Option<Bytes> {
+        let path = ObjectPath::from(key.digest());
+        self.store.get(&path).await.ok()?.bytes().await.ok()
+    }
+
+    async fn insert(&self, key: RangeCacheKey, bytes: Bytes) {
+        let path = ObjectPath::from(key.digest());
+        let _fail_is_ok = self.store.put(&path, bytes.into()).await;
+    }
+
+    fn metrics(&self) -> &RangeCacheMetrics {
+        &self.metrics
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::storage::virtual_ref::{
+        ObjectStoreVirtualChunkResolver, VirtualChunkResolver,
+    };
+
+    fn key(etag: &str, start: u64) -> RangeCacheKey {
+        RangeCacheKey {
+            location: VirtualChunkLocation::Absolute("s3://bucket/file.nc".to_string()),
+            etag: etag.to_string(),
+            range: ByteRange::from_offset_with_length(start, 4),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_and_disk_caches() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let caches: [Box<dyn RangeCache>; 2] = [
+            Box::new(MemoryRangeCache::new(1024, 16)),
+            Box::new(DiskRangeCache::new(dir.path())?),
+        ];
+        for cache in caches {
+            assert_eq!(cache.get(&key("v1", 0)).await, None);
+            cache.insert(key("v1", 0), Bytes::from_static(b"1234")).await;
+            assert_eq!(cache.get(&key("v1", 0)).await, Some(Bytes::from_static(b"1234")));
+            // other versions of the file and other ranges are different entries
+            assert_eq!(cache.get(&key("v2", 0)).await, None);
+            assert_eq!(cache.get(&key("v1", 1)).await, None);
+        }
+
+        // the memory cache evicts past its size
+        let cache = MemoryRangeCache::new(8, 16);
+        for start in 0..4 {
+            cache.insert(key("v1", start), Bytes::from_static(b"1234")).await;
+        }
+        assert!(cache.cache.weight() <= 8);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolver_uses_range_cache() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let file = dir.path().join("data.nc");
+        std::fs::write(&file, b"hello world")?;
+        let location = VirtualChunkLocation::from_absolute_path(&format!(
+            "file://{}",
+            file.display()
+        ))?;
+        let range = ByteRange::from_offset_with_length(6, 5);
+
+        let cache = Arc::new(MemoryRangeCache::new(1024, 16));
+        let resolver = ObjectStoreVirtualChunkResolver::new(None)
+            .with_range_cache(Arc::clone(&cache) as Arc<dyn RangeCache>);
+        for _ in 0..3 {
+            assert_eq!(resolver.fetch_chunk(&location, &range).await?, "world");
+        }
+        assert_eq!(
+            cache.metrics().stats(),
+            RangeCacheStats { hits: 2, misses: 1, bytes_hit: 10, bytes_fetched: 5 }
+        );
+
+        // a new version of the file gets a new etag, old entries are not used
+        std::fs::write(&file, b"hello there, world")?;
+        let resolver = ObjectStoreVirtualChunkResolver::new(None)
+            .with_range_cache(Arc::clone(&cache) as Arc<dyn RangeCache>);
+        assert_eq!(resolver.fetch_chunk(&location, &range).await?, "there");
+        assert_eq!(cache.metrics().stats().misses, 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,729 @@
+//! A [`Storage`] decorator that records every call it forwards.
+//!
+//! The recorded trace can be inspected to assert which objects an operation touched,
+//! for example in performance regression tests, and it can be replayed against another
+//! storage to compare timings.
+use std::{
+    future::Future,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+use futures::{stream::BoxStream, TryStreamExt};
+
+use super::{
+    CopySource, ObjectInfo, ObjectKind, ObjectLocation, Storage, StorageFuture,
+    StorageResult,
+};
+use crate::format::{
+    attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot, AttributesId,
+    AuditRecordId, ByteRange, ChunkId, ManifestId, SnapshotId,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum StorageOperation {
+    FetchSnapshot,
+    FetchAttributes,
+    FetchManifests,
+    FetchChunk,
+    WriteSnapshot,
+    WriteAttributes,
+    WriteManifests,
+    WriteChunk,
+    DeleteSnapshot,
+    DeleteManifests,
+    DeleteChunk,
+    GetRef,
+    RefNames,
+    RefVersions,
+    WriteRef,
+    DeleteRef,
+    ListObjects,
+    MoveObject,
+    DeleteObject,
+    ListPrefixes,
+    FetchSnapshotArtifact,
+    WriteSnapshotArtifact,
+    FetchAuditRecords,
+    WriteAuditRecord,
+}
+
+impl StorageOperation {
+    pub fn is_read(&self) -> bool {
+        matches!(
+            self,
+            StorageOperation::FetchSnapshot
+                | StorageOperation::FetchAttributes
+                | StorageOperation::FetchManifests
+                | StorageOperation::FetchChunk
+                | StorageOperation::GetRef
+                | StorageOperation::RefNames
+                | StorageOperation::RefVersions
+                | StorageOperation::ListObjects
+                | StorageOperation::ListPrefixes
+                | StorageOperation::FetchSnapshotArtifact
+                | StorageOperation::FetchAuditRecords
+        )
+    }
+}
+
+/// The object a storage call operated on
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum StorageKey {
+    Snapshot(SnapshotId),
+    Attributes(AttributesId),
+    Manifest(ManifestId),
+    Chunk(ChunkId),
+    Ref(String),
+    /// The listing of all refs
+    RefList,
+    /// An object addressed by kind and id, by garbage collection
+    Object(ObjectKind, String),
+    /// The listing of the objects of a kind
+    ObjectList(ObjectKind, ObjectLocation),
+    /// The listing of the directories under a prefix
+    PrefixList(String),
+    /// An artifact derived from a snapshot, by name
+    SnapshotArtifact(SnapshotId, String),
+    /// The listing of all the audit records
+    AuditLog,
+    AuditRecord(AuditRecordId),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageCall {
+    pub operation: StorageOperation,
+    pub key: StorageKey,
+    /// The requested range, for chunk fetches
+    pub range: Option<ByteRange>,
+    /// Bytes read or written, only known for chunks and refs
+    pub size: Option<u64>,
+    pub duration: Duration,
+    pub succeeded: bool,
+}
+
+#[derive(Debug)]
+pub struct RecordingStorage {
+    backend: Arc<dyn Storage>,
+    dry_run: bool,
+    trace: Mutex<Vec<StorageCall>>,
+}
+
+impl RecordingStorage {
+    pub fn new(backend: Arc<dyn Storage>) -> Self {
+        Self { backend, dry_run: false, trace: Mutex::new(Vec::new()) }
+    }
+
+    /// A recorder that doesn't forward writes and deletes to the backend.
+    ///
+    /// They are recorded and reported as successful, reads are still forwarded.
+    pub fn dry_run(backend: Arc<dyn Storage>) -> Self {
+        Self { backend, dry_run: true, trace: Mutex::new(Vec::new()) }
+    }
+
+    /// All the calls recorded so far, in the order they completed
+    pub fn trace(&self) -> Vec<StorageCall> {
+        self.lock_trace().clone()
+    }
+
+    /// Return the recorded calls and start a new trace
+    pub fn take_trace(&self) -> Vec<StorageCall> {
+        std::mem::take(&mut *self.lock_trace())
+    }
+
+    pub fn count(&self, operation: StorageOperation) -> usize {
+        self.lock_trace().iter().filter(|call| call.operation == operation).count()
+    }
+
+    /// Issue again the reads in `trace` against `storage`, in order.
+    ///
+    /// Writes and deletes are skipped, the trace doesn't include their payloads.
+    /// Returns the trace of the replayed calls.
+    pub async fn replay(
+        trace: &[StorageCall],
+        storage: Arc<dyn Storage>,
+    ) -> StorageResult<Vec<StorageCall>> {
+        let recorder = Self::new(storage);
+        for call in trace.iter().filter(|call| call.operation.is_read()) {
+            match (&call.key, call.operation) {
+                (StorageKey::Snapshot(id), _) => {
+                    recorder.fetch_snapshot(id).await?;
+                }
+                (StorageKey::Attributes(id), _) => {
+                    recorder.fetch_attributes(id).await?;
+                }
+                (StorageKey::Manifest(id), _) => {
+                    recorder.fetch_manifests(id).await?;
+                }
+                (StorageKey::Chunk(id), _) => {
+                    let range = call.range.clone().unwrap_or(ByteRange::ALL);
+                    recorder.fetch_chunk(id, &range).await?;
+                }
+                (StorageKey::Ref(name), StorageOperation::RefVersions) => {
+                    recorder.ref_versions(name).await?.try_collect::<Vec<_>>().await?;
+                }
+                (StorageKey::Ref(name), _) => {
+                    recorder.get_ref(name).await?;
+                }
+                (StorageKey::RefList, _) => {
+                    recorder.ref_names().await?;
+                }
+                (StorageKey::ObjectList(kind, location), _) => {
+                    recorder
+                        .list_objects(*kind, *location)
+                        .await?
+                        .try_collect::<Vec<_>>()
+                        .await?;
+                }
+                (StorageKey::PrefixList(prefix), _) => {
+                    recorder.list_prefixes(prefix).await?;
+                }
+                (StorageKey::SnapshotArtifact(id, name), _) => {
+                    recorder.fetch_snapshot_artifact(id, name).await?;
+                }
+                (StorageKey::AuditLog, _) => {
+                    recorder.fetch_audit_records().await?;
+                }
+                (StorageKey::Object(..) | StorageKey::AuditRecord(_), _) => {}
+            }
+        }
+        Ok(recorder.take_trace())
+    }
+
+    fn lock_trace(&self) -> MutexGuard<'_, Vec<StorageCall>> {
+        self.trace.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn record<T>(
+        &self,
+        operation: StorageOperation,
+        key: StorageKey,
+        range: Option<&ByteRange>,
+        size: impl FnOnce(&T) -> Option<u64>,
+        call: impl Future<Output = StorageResult<T>>,
+    ) -> StorageResult<T> {
+        let start = Instant::now();
+        let res = call.await;
+        let duration = start.elapsed();
+        self.lock_trace().push(StorageCall {
+            operation,
+            key,
+            range: range.cloned(),
+            size: res.as_ref().ok().and_then(size),
+            duration,
+            succeeded: res.is_ok(),
+        });
+        res
+    }
+
+    async fn record_mutation(
+        &self,
+        operation: StorageOperation,
+        key: StorageKey,
+        size: Option<u64>,
+        call: impl Future<Output = StorageResult<()>>,
+    ) -> StorageResult<()> {
+        let dry_run = self.dry_run;
+        self.record(operation, key, None, |_| size, async move {
+            if dry_run {
+                Ok(())
+            } else {
+                call.await
+            }
+        })
+        .await
+    }
+}
+
+fn no_size<T>(_: &T) -> Option<u64> {
+    None
+}
+
+impl Storage for RecordingStorage {
+    fn fetch_snapshot<'a>(
+        &'a self,
+        id: &'a SnapshotId,
+    ) -> StorageFuture<'a, Arc<Snapshot>> {
+        Box::pin(async move {
+            let key = StorageKey::Snapshot(id.clone());
+            self.record(
+                StorageOperation::FetchSnapshot,
+                key,
+                None,
+                no_size,
+                self.backend.fetch_snapshot(id),
+            )
+            .await
+        })
+    }
+
+    fn fetch_attributes<'a>(
+        &'a self,
+        id: &'a AttributesId,
+    ) -> StorageFuture<'a, Arc<AttributesTable>> {
+        Box::pin(async move {
+            let key = StorageKey::Attributes(id.clone());
+            self.record(
+                StorageOperation::FetchAttributes,
+                key,
+                None,
+                no_size,
+                self.backend.fetch_attributes(id),
+            )
+            .await
+        })
+    }
+
+    fn fetch_manifests<'a>(
+        &'a self,
+        id: &'a ManifestId,
+    ) -> StorageFuture<'a, Arc<Manifest>> {
+        Box::pin(async move {
+            let key = StorageKey::Manifest(id.clone());
+            self.record(
+                StorageOperation::FetchManifests,
+                key,
+                None,
+                no_size,
+                self.backend.fetch_manifests(id),
+            )
+            .await
+        })
+    }
+
+    fn fetch_manifest_bytes<'a>(
+        &'a self,
+        id: &'a ManifestId,
+    ) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            let key = StorageKey::Manifest(id.clone());
+            self.record(
+                StorageOperation::FetchManifests,
+                key,
+                None,
+                |bytes: &Bytes| Some(bytes.len() as u64),
+                self.backend.fetch_manifest_bytes(id),
+            )
+            .await
+        })
+    }
+
+    fn fetch_chunk<'a>(
+        &'a self,
+        id: &'a ChunkId,
+        range: &'a ByteRange,
+    ) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            let key = StorageKey::Chunk(id.clone());
+            self.record(
+                StorageOperation::FetchChunk,
+                key,
+                Some(range),
+                |bytes: &Bytes| Some(bytes.len() as u64),
+                self.backend.fetch_chunk(id, range),
+            )
+            .await
+        })
+    }
+
+    fn write_snapshot<'a>(
+        &'a self,
+        id: SnapshotId,
+        table: Arc<Snapshot>,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = StorageKey::Snapshot(id.clone());
+            self.record_mutation(
+                StorageOperation::WriteSnapshot,
+                key,
+                None,
+                self.backend.write_snapshot(id, table),
+            )
+            .await
+        })
+    }
+
+    fn write_attributes<'a>(
+        &'a self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = StorageKey::Attributes(id.clone());
+            self.record_mutation(
+                StorageOperation::WriteAttributes,
+                key,
+                None,
+                self.backend.write_attributes(id, table),
+            )
+            .await
+        })
+    }
+
+    fn write_manifests<'a>(
+        &'a self,
+        id: ManifestId,
+        table: Arc<Manifest>,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = StorageKey::Manifest(id.clone());
+            self.record_mutation(
+                StorageOperation::WriteManifests,
+                key,
+                None,
+                self.backend.write_manifests(id, table),
+            )
+            .await
+        })
+    }
+
+    fn write_chunk<'a>(&'a self, id: ChunkId, bytes: Bytes) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = StorageKey::Chunk(id.clone());
+            let size = Some(bytes.len() as u64);
+            self.record_mutation(
+                StorageOperation::WriteChunk,
+                key,
+                size,
+                self.backend.write_chunk(id, bytes),
+            )
+            .await
+        })
+    }
+
+    fn delete_snapshot<'a>(&'a self, id: &'a SnapshotId) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = StorageKey::Snapshot(id.clone());
+            self.record_mutation(
+                StorageOperation::DeleteSnapshot,
+                key,
+                None,
+                self.backend.delete_snapshot(id),
+            )
+            .await
+        })
+    }
+
+    fn delete_manifests<'a>(&'a self, id: &'a ManifestId) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = StorageKey::Manifest(id.clone());
+            self.record_mutation(
+                StorageOperation::DeleteManifests,
+                key,
+                None,
+                self.backend.delete_manifests(id),
+            )
+            .await
+        })
+    }
+
+    fn delete_chunk<'a>(&'a self, id: &'a ChunkId) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = StorageKey::Chunk(id.clone());
+            self.record_mutation(
+                StorageOperation::DeleteChunk,
+                key,
+                None,
+                self.backend.delete_chunk(id),
+            )
+            .await
+        })
+    }
+
+    fn get_ref<'a>(&'a self, ref_key: &'a str) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            self.record(
+                StorageOperation::GetRef,
+                StorageKey::Ref(ref_key.to_string()),
+                None,
+                |bytes: &Bytes| Some(bytes.len() as u64),
+                self.backend.get_ref(ref_key),
+            )
+            .await
+        })
+    }
+
+    fn ref_names<'a>(&'a self) -> StorageFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            self.record(
+                StorageOperation::RefNames,
+                StorageKey::RefList,
+                None,
+                no_size,
+                self.backend.ref_names(),
+            )
+            .await
+        })
+    }
+
+    fn ref_versions<'a, 'b>(
+        &'a self,
+        ref_name: &'b str,
+    ) -> StorageFuture<'b, BoxStream<'a, StorageResult<String>>>
+    where
+        'a: 'b,
+    {
+        Box::pin(async move {
+            self.record(
+                StorageOperation::RefVersions,
+                StorageKey::Ref(ref_name.to_string()),
+                None,
+                no_size,
+                self.backend.ref_versions(ref_name),
+            )
+            .await
+        })
+    }
+
+    fn write_ref<'a>(
+        &'a self,
+        ref_key: &'a str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let size = Some(bytes.len() as u64);
+            self.record_mutation(
+                StorageOperation::WriteRef,
+                StorageKey::Ref(ref_key.to_string()),
+                size,
+                self.backend.write_ref(ref_key, overwrite_refs, bytes),
+            )
+            .await
+        })
+    }
+
+    fn delete_ref<'a>(&'a self, ref_key: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.record_mutation(
+                StorageOperation::DeleteRef,
+                StorageKey::Ref(ref_key.to_string()),
+                None,
+                self.backend.delete_ref(ref_key),
+            )
+            .await
+        })
+    }
+
+    fn list_objects<'a>(
+        &'a self,
+        kind: ObjectKind,
+        location: ObjectLocation,
+    ) -> StorageFuture<'a, BoxStream<'a, StorageResult<ObjectInfo>>> {
+        Box::pin(async move {
+            self.record(
+                StorageOperation::ListObjects,
+                StorageKey::ObjectList(kind, location),
+                None,
+                no_size,
+                self.backend.list_objects(kind, location),
+            )
+            .await
+        })
+    }
+
+    fn move_object<'a>(
+        &'a self,
+        kind: ObjectKind,
+        id: &'a str,
+        to: ObjectLocation,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.record_mutation(
+                StorageOperation::MoveObject,
+                StorageKey::Object(kind, id.to_string()),
+                None,
+                self.backend.move_object(kind, id, to),
+            )
+            .await
+        })
+    }
+
+    fn delete_object<'a>(
+        &'a self,
+        kind: ObjectKind,
+        id: &'a str,
+        location: ObjectLocation,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.record_mutation(
+                StorageOperation::DeleteObject,
+                StorageKey::Object(kind, id.to_string()),
+                None,
+                self.backend.delete_object(kind, id, location),
+            )
+            .await
+        })
+    }
+
+    fn list_prefixes<'a>(&'a self, prefix: &'a str) -> StorageFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            self.record(
+                StorageOperation::ListPrefixes,
+                StorageKey::PrefixList(prefix.to_string()),
+                None,
+                no_size,
+                self.backend.list_prefixes(prefix),
+            )
+            .await
+        })
+    }
+
+    fn fetch_snapshot_artifact<'a>(
+        &'a self,
+        id: &'a SnapshotId,
+        name: &'a str,
+    ) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            self.record(
+                StorageOperation::FetchSnapshotArtifact,
+                StorageKey::SnapshotArtifact(id.clone(), name.to_string()),
+                None,
+                |bytes: &Bytes| Some(bytes.len() as u64),
+                self.backend.fetch_snapshot_artifact(id, name),
+            )
+            .await
+        })
+    }
+
+    fn write_snapshot_artifact<'a>(
+        &'a self,
+        id: &'a SnapshotId,
+        name: &'a str,
+        bytes: Bytes,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let size = Some(bytes.len() as u64);
+            self.record_mutation(
+                StorageOperation::WriteSnapshotArtifact,
+                StorageKey::SnapshotArtifact(id.clone(), name.to_string()),
+                size,
+                self.backend.write_snapshot_artifact(id, name, bytes),
+            )
+            .await
+        })
+    }
+
+    fn fetch_audit_records(&self) -> StorageFuture<'_, Vec<Bytes>> {
+        Box::pin(async move {
+            self.record(
+                StorageOperation::FetchAuditRecords,
+                StorageKey::AuditLog,
+                None,
+                |records: &Vec<Bytes>| Some(records.iter().map(|r| r.len() as u64).sum()),
+                self.backend.fetch_audit_records(),
+            )
+            .await
+        })
+    }
+
+    fn write_audit_record<'a>(
+        &'a self,
+        id: &'a AuditRecordId,
+        bytes: Bytes,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let size = Some(bytes.len() as u64);
+            self.record_mutation(
+                StorageOperation::WriteAuditRecord,
+                StorageKey::AuditRecord(id.clone()),
+                size,
+                self.backend.write_audit_record(id, bytes),
+            )
+            .await
+        })
+    }
+
+    fn copy_chunk_from<'a>(
+        &'a self,
+        id: ChunkId,
+        source: &'a CopySource,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            // the size of copied chunks is not known
+            self.record_mutation(
+                StorageOperation::WriteChunk,
+                StorageKey::Chunk(id.clone()),
+                None,
+                self.backend.copy_chunk_from(id, source),
+            )
+            .await
+        })
+    }
+
+    // calls to the storages of the sub prefixes are not recorded
+    crate::forward_storage!(
+        backend =>
+            sub_storage, key_layout, chunk_copy_source, can_copy_from,
+    );
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::storage::ObjectStorage;
+
+    #[tokio::test]
+    async fn test_recording_and_replay() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let recorder = RecordingStorage::new(Arc::clone(&backend));
+
+        let id = ChunkId::random();
+        recorder.write_chunk(id.clone(), Bytes::from_static(b"hello")).await?;
+        recorder.fetch_chunk(&id, &ByteRange::from_offset(1)).await?;
+        assert!(recorder.fetch_snapshot(&SnapshotId::random()).await.is_err());
+
+        let trace = recorder.trace();
+        assert_eq!(
+            trace
+                .iter()
+                .map(|call| (call.operation, call.size, call.succeeded))
+                .collect::<Vec<_>>(),
+            vec![
+                (StorageOperation::WriteChunk, Some(5), true),
+                (StorageOperation::FetchChunk, Some(4), true),
+                (StorageOperation::FetchSnapshot, None, false),
+            ]
+        );
+        assert_eq!(trace[1].key, StorageKey::Chunk(id.clone()));
+        assert_eq!(trace[1].range, Some(ByteRange::from_offset(1)));
+        assert_eq!(recorder.count(StorageOperation::FetchChunk), 1);
+
+        // only the successful read is replayed
+        let ok_reads: Vec<_> = trace.into_iter().filter(|call| call.succeeded).collect();
+        let replayed = RecordingStorage::replay(&ok_reads, Arc::clone(&backend)).await?;
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(replayed[0].operation, StorageOperation::FetchChunk);
+        assert_eq!(replayed[0].size, Some(4));
+
+        assert_eq!(recorder.take_trace().len(), 3);
+        assert!(recorder.trace().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_does_not_write() -> Result<(), Box<dyn Error>> {
+        let backend: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let recorder = RecordingStorage::dry_run(Arc::clone(&backend));
+
+        let id = ChunkId::random();
+        recorder.write_chunk(id.clone(), Bytes::from_static(b"hello")).await?;
+        recorder.write_ref("branch.main/ZZZZZZZZ.json", false, Bytes::new()).await?;
+        assert_eq!(recorder.count(StorageOperation::WriteChunk), 1);
+        assert_eq!(recorder.count(StorageOperation::WriteRef), 1);
+
+        assert!(backend.fetch_chunk(&id, &ByteRange::ALL).await.is_err());
+        assert!(backend.ref_names().await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+//! Atomic ref updates for object stores without conditional writes.
+//!
+//! Commits rely on refs being created only once, [`Storage::write_ref`] with
+//! `overwrite_refs = false` must fail if the key already exists. Some S3 compatible stores
+//! ignore `If-None-Match`, [`LockedRefStorage`] keeps commits atomic on them by claiming
+//! each ref key in an external service, like the DynamoDB table of
+//! `storage::dynamodb::DynamoDbRefLock` with the `dynamodb` feature, before writing it.
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use bytes::Bytes;
+
+use super::{Storage, StorageError, StorageFuture};
+
+/// An external service that can atomically claim keys
+pub trait RefLockProvider: fmt::Debug + Send + Sync {
+    /// Claim `key` if nobody did before, returns `false` if it was already claimed
+    fn try_claim<'a>(&'a self, key: &'a str) -> StorageFuture<'a, bool>;
+
+    /// Give up a claim, after the write it protected failed or the ref was deleted
+    fn release<'a>(&'a self, key: &'a str) -> StorageFuture<'a, ()>;
+}
+
+/// Claims kept in memory, they only protect writers in the same process
+#[derive(Debug, Default)]
+pub struct InMemoryRefLock {
+    claimed: Mutex<HashSet<String>>,
+}
+
+impl RefLockProvider for InMemoryRefLock {
+    fn try_claim<'a>(&'a self, key: &'a str) -> StorageFuture<'a, bool> {
+        Box::pin(async move {
+            Ok(self
+                .claimed
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .insert(key.to_string()))
+        })
+    }
+
+    fn release<'a>(&'a self, key: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.claimed.lock().unwrap_or_else(PoisonError::into_inner).remove(key);
+            Ok(())
+        })
+    }
+}
+
+/// A [`Storage`] decorator that creates refs through a [`RefLockProvider`].
+///
+/// Refs written without `overwrite_refs` are claimed in the provider first, and then
+/// written to the backend unconditionally. All writers of a repository must use the
+/// same provider. A writer that crashes between the claim and the write leaves the key
+/// claimed, the ref must then be written again with `overwrite_refs`.
+#[derive(Debug)]
+pub struct LockedRefStorage {
+    backend: Arc<dyn Storage>,
+    lock: Arc<dyn RefLockProvider>,
+}
+
+impl LockedRefStorage {
+    pub fn new(backend: Arc<dyn Storage>, lock: Arc<dyn RefLockProvider>) -> Self {
+        Self { backend, lock }
+    }
+}
+
+impl Storage for LockedRefStorage {
+    fn write_ref<'a>(
+        &'a self,
+        ref_key: &'a str,
+        overwrite_refs: bool,
+        bytes: Bytes,
+    ) -> StorageFuture<'a, ()> {
+        if overwrite_refs {
+            return self.backend.write_ref(ref_key, true, bytes);
+        }
+        Box::pin(async move {
+            if !self.lock.try_claim(ref_key).await? {
+                return Err(StorageError::RefAlreadyExists(ref_key.to_string()));
+            }
+            match self.backend.write_ref(ref_key, true, bytes).await {
+                Ok(()) => Ok(()),
+                Err(err) => {
+                    // without the release the key would look taken to every retry, but
+                    // the write error is the one to report, a failed release only makes
+                    // retries fail as conflicts
+                    let _ = self.lock.release(ref_key).await;
+                    Err(err)
+                }
+            }
+        })
+    }
+
+    fn delete_ref<'a>(&'a self, ref_key: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.backend.delete_ref(ref_key).await?;
+            // a deleted tag can be created again
+            self.lock.release(ref_key).await
+        })
+    }
+
+    // sub storages are not locked, claims would collide between repositories
+    crate::forward_storage!(
+        backend =>
+            fetch_snapshot, fetch_attributes, fetch_manifests, fetch_manifest_bytes,
+            fetch_chunk, fetch_chunk_with_status, write_snapshot, write_attributes,
+            write_manifests, write_chunk, delete_snapshot, delete_manifests, delete_chunk,
+            get_ref, ref_names, ref_versions, list_objects, move_object, delete_object,
+            fetch_snapshot_artifact, write_snapshot_artifact, fetch_audit_records,
+            write_audit_record, list_prefixes, key_layout, chunk_copy_source,
+            can_copy_from, copy_chunk_from,
+    );
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::SnapshotId,
+        refs::{fetch_branch_tip, update_branch, RefError},
+        ObjectStorage,
+    };
+
+    #[tokio::test]
+    async fn test_locked_ref_storage() {
+        let lock = Arc::new(InMemoryRefLock::default());
+        let storage = LockedRefStorage::new(
+            Arc::new(ObjectStorage::new_in_memory_store(None)),
+            Arc::clone(&lock) as Arc<dyn RefLockProvider>,
+        );
+        storage
+            .write_ref("tag.v1/ref.json", false, Bytes::from_static(b"1"))
+            .await
+            .unwrap();
+        assert!(matches!(
+            storage.write_ref("tag.v1/ref.json", false, Bytes::from_static(b"2")).await,
+            Err(StorageError::RefAlreadyExists(key)) if key == "tag.v1/ref.json"
+        ));
+        assert_eq!(
+            storage.get_ref("tag.v1/ref.json").await.unwrap(),
+            Bytes::from_static(b"1")
+        );
+        // overwrites don't need a claim
+        storage
+            .write_ref("tag.v1/ref.json", true, Bytes::from_static(b"3"))
+            .await
+            .unwrap();
+        assert_eq!(
+            storage.get_ref("tag.v1/ref.json").await.unwrap(),
+            Bytes::from_static(b"3")
+        );
+
+        let first = SnapshotId::random();
+        update_branch(&storage, "main", first.clone(), None, false).await.unwrap();
+        // the version key is claimed, writers that race for it get a conflict
+        assert!(matches!(
+            update_branch(&storage, "main", SnapshotId::random(), None, false).await,
+            Err(RefError::Conflict { actual_parent: Some(parent), .. }) if parent == first
+        ));
+        assert_eq!(fetch_branch_tip(&storage, "main").await.unwrap().snapshot, first);
+        assert!(!lock.try_claim("branch.main/ZZZZZZZZ.json").await.unwrap());
+    }
+}
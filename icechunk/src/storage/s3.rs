@@ -1,37 +1,57 @@
-use std::{ops::Bound, path::PathBuf, sync::Arc};
+use std::{
+    fs::File,
+    io::BufReader,
+    ops::Bound,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
 
 use async_stream::try_stream;
-use async_trait::async_trait;
 use aws_config::{meta::region::RegionProviderChain, AppName, BehaviorVersion};
 use aws_credential_types::Credentials;
+use aws_runtime::auth::SigV4OperationSigningConfig;
 use aws_sdk_s3::{
-    config::{Builder, Region},
+    config::{
+        endpoint::Endpoint, interceptors::BeforeTransmitInterceptorContextMut, Builder,
+        ConfigBag, Intercept, Region, RuntimeComponents, SharedHttpClient,
+    },
     error::ProvideErrorMetadata,
     primitives::ByteStream,
+    types::{CompletedMultipartUpload, CompletedPart},
     Client,
 };
+use aws_sigv4::http_request::SignableBody;
+use aws_smithy_runtime::client::http::hyper_014::HyperClientBuilder;
+use aws_smithy_runtime_api::box_error::BoxError;
+use aws_smithy_types::{retry::RetryConfig, Document};
+use aws_types::{region::SigningRegion, SigningName};
 use bytes::Bytes;
-use futures::StreamExt;
+use chrono::DateTime;
+use futures::{stream::BoxStream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
 
 use crate::{
     format::{
         attributes::AttributesTable, format_constants, manifest::Manifest,
-        snapshot::Snapshot, AttributesId, ByteRange, ChunkId, FileTypeTag, ManifestId,
-        SnapshotId,
+        snapshot::Snapshot, AttributesId, AuditRecordId, ByteRange, ChunkId, FileTypeTag,
+        ManifestId, SnapshotId,
     },
-    private,
     zarr::ObjectId,
     Storage, StorageError,
 };
 
-use super::StorageResult;
+use super::{
+    layout::KeyLayout, with_structure_base, CopySource, ObjectInfo, ObjectKind,
+    ObjectLocation, StorageFuture, StorageResult,
+};
 
 #[derive(Debug)]
 pub struct S3Storage {
     client: Arc<Client>,
     prefix: String,
     bucket: String,
+    layout: KeyLayout,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
@@ -56,12 +76,46 @@ pub enum S3Credentials {
 #[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
 pub struct S3Config {
     pub region: Option<String>,
+    /// Send requests to this URL instead of AWS, for MinIO, Ceph, localstack or other
+    /// compatible object stores
     pub endpoint: Option<String>,
     pub credentials: S3Credentials,
+    /// Allow `http://` endpoints, this also enables path-style addressing
     pub allow_http: bool,
+    /// Address buckets as `endpoint/bucket` instead of `bucket.endpoint`
+    #[serde(default)]
+    pub force_path_style: bool,
+    /// A PEM file with certificate authorities to trust, in addition to those of the
+    /// platform, for endpoints with private or self-signed certificates
+    #[serde(default)]
+    pub ca_bundle: Option<PathBuf>,
+    /// Accept any TLS certificate. This is insecure, it should only be used for testing
+    #[serde(default)]
+    pub insecure_skip_tls_verify: bool,
+    /// Sign requests for this region instead of `region`
+    #[serde(default)]
+    pub signing_region: Option<String>,
+    /// Sign requests for this service name instead of `s3`
+    #[serde(default)]
+    pub signing_service: Option<String>,
+    /// Sign requests with `UNSIGNED-PAYLOAD` instead of the hash of their body, for stores
+    /// that cannot verify payload signatures
+    #[serde(default)]
+    pub unsigned_payload: bool,
+    /// Try requests at most this many times, including the first attempt. Throttling and
+    /// transient failures are retried, by default 3 attempts are made
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
 }
 
-pub async fn mk_client(config: Option<&S3Config>) -> Client {
+/// Chunks larger than this are written with multipart uploads
+pub const MULTIPART_THRESHOLD_BYTES: usize = 16 * 1024 * 1024;
+/// The size of the parts of multipart uploads, S3 requires at least 5MiB
+const MULTIPART_PART_BYTES: usize = 8 * 1024 * 1024;
+/// The parts of a multipart upload that are uploaded, and held in memory, at once
+const MAX_CONCURRENT_PARTS: usize = 8;
+
+pub async fn mk_client(config: Option<&S3Config>) -> StorageResult<Client> {
     let region = config
         .and_then(|c| c.region.as_ref())
         .map(|r| RegionProviderChain::first_try(Some(Region::new(r.clone()))))
@@ -69,6 +123,7 @@ pub async fn mk_client(config: Option<&S3Config>) -> Client {
 
     let endpoint = config.and_then(|c| c.endpoint.clone());
     let allow_http = config.map(|c| c.allow_http).unwrap_or(false);
+    let force_path_style = config.map(|c| c.force_path_style).unwrap_or(false);
     let credentials =
         config.map(|c| c.credentials.clone()).unwrap_or(S3Credentials::FromEnv);
     #[allow(clippy::unwrap_used)]
@@ -81,6 +136,15 @@ pub async fn mk_client(config: Option<&S3Config>) -> Client {
         aws_config = aws_config.endpoint_url(endpoint)
     }
 
+    if let Some(max_attempts) = config.and_then(|c| c.max_attempts) {
+        aws_config = aws_config
+            .retry_config(RetryConfig::standard().with_max_attempts(max_attempts));
+    }
+
+    if let Some(http_client) = config.map(tls_http_client).transpose()?.flatten() {
+        aws_config = aws_config.http_client(http_client);
+    }
+
     match credentials {
         S3Credentials::FromEnv => {}
         S3Credentials::Anonymous => aws_config = aws_config.no_credentials(),
@@ -97,20 +161,179 @@ pub async fn mk_client(config: Option<&S3Config>) -> Client {
 
     let mut s3_builder = Builder::from(&aws_config.load().await);
 
-    if allow_http {
+    if allow_http || force_path_style {
         s3_builder = s3_builder.force_path_style(true);
     }
 
+    if let Some(overrides) = config.and_then(SigningOverrides::new) {
+        s3_builder = s3_builder.interceptor(overrides);
+    }
+
     let config = s3_builder.build();
 
-    Client::from_conf(config)
+    Ok(Client::from_conf(config))
 }
 
-const SNAPSHOT_PREFIX: &str = "snapshots/";
-const MANIFEST_PREFIX: &str = "manifests/";
-// const ATTRIBUTES_PREFIX: &str = "attributes/";
-const CHUNK_PREFIX: &str = "chunks/";
+/// An HTTP client with the TLS options of `config`, `None` if the defaults are used
+fn tls_http_client(config: &S3Config) -> StorageResult<Option<SharedHttpClient>> {
+    if config.ca_bundle.is_none() && !config.insecure_skip_tls_verify {
+        return Ok(None);
+    }
+
+    let mut roots = rustls::RootCertStore::empty();
+    // a missing platform store is not an error if we have a bundle
+    for cert in rustls_native_certs::load_native_certs().unwrap_or_default() {
+        let _ = roots.add(&rustls::Certificate(cert.0));
+    }
+    if let Some(path) = &config.ca_bundle {
+        for cert in read_ca_bundle(path)? {
+            roots.add(&rustls::Certificate(cert)).map_err(|err| {
+                StorageError::InvalidConfig(format!(
+                    "invalid certificate in CA bundle {}: {err}",
+                    path.display()
+                ))
+            })?;
+        }
+    }
+
+    let mut tls = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    if config.insecure_skip_tls_verify {
+        tls.dangerous().set_certificate_verifier(Arc::new(NoCertificateVerification));
+    }
+    let connector = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_or_http()
+        .enable_http1()
+        .enable_http2()
+        .build();
+    Ok(Some(HyperClientBuilder::new().build(connector)))
+}
+
+fn read_ca_bundle(path: &Path) -> StorageResult<Vec<Vec<u8>>> {
+    let invalid = |message: String| {
+        StorageError::InvalidConfig(format!(
+            "cannot read CA bundle {}: {message}",
+            path.display()
+        ))
+    };
+    let file = File::open(path).map_err(|err| invalid(err.to_string()))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|err| invalid(err.to_string()))?;
+    if certs.is_empty() {
+        return Err(invalid("no PEM certificates found".to_string()));
+    }
+    Ok(certs)
+}
+
+/// Changes the SigV4 parameters the SDK picks for each request
+#[derive(Debug)]
+struct SigningOverrides {
+    region: Option<String>,
+    service: Option<String>,
+    unsigned_payload: bool,
+}
+
+impl SigningOverrides {
+    fn new(config: &S3Config) -> Option<Self> {
+        let overrides = SigningOverrides {
+            region: config.signing_region.clone(),
+            service: config.signing_service.clone(),
+            unsigned_payload: config.unsigned_payload,
+        };
+        (overrides.region.is_some()
+            || overrides.service.is_some()
+            || overrides.unsigned_payload)
+            .then_some(overrides)
+    }
+}
+
+impl Intercept for SigningOverrides {
+    fn name(&self) -> &'static str {
+        "SigningOverrides"
+    }
+
+    fn modify_before_signing(
+        &self,
+        _context: &mut BeforeTransmitInterceptorContextMut<'_>,
+        _runtime_components: &RuntimeComponents,
+        cfg: &mut ConfigBag,
+    ) -> Result<(), BoxError> {
+        // the signer prefers the parameters of the resolved endpoint, if it has them
+        if let Some(endpoint) = cfg.load::<Endpoint>().cloned() {
+            if let Some(Document::Array(schemes)) =
+                endpoint.properties().get("authSchemes")
+            {
+                let schemes = schemes
+                    .iter()
+                    .cloned()
+                    .map(|scheme| match scheme {
+                        Document::Object(mut scheme) => {
+                            if let Some(region) = &self.region {
+                                scheme.insert(
+                                    "signingRegion".to_string(),
+                                    Document::String(region.clone()),
+                                );
+                            }
+                            if let Some(service) = &self.service {
+                                scheme.insert(
+                                    "signingName".to_string(),
+                                    Document::String(service.clone()),
+                                );
+                            }
+                            Document::Object(scheme)
+                        }
+                        other => other,
+                    })
+                    .collect();
+                let endpoint = endpoint
+                    .into_builder()
+                    .property("authSchemes", Document::Array(schemes))
+                    .build();
+                cfg.interceptor_state().store_put(endpoint);
+            }
+        }
+
+        let mut signing =
+            cfg.load::<SigV4OperationSigningConfig>().cloned().unwrap_or_default();
+        if let Some(region) = &self.region {
+            signing.region = Some(SigningRegion::from(Region::new(region.clone())));
+        }
+        if let Some(service) = &self.service {
+            signing.name = Some(SigningName::from(service.clone()));
+        }
+        if self.unsigned_payload {
+            signing.signing_options.payload_override =
+                Some(SignableBody::UnsignedPayload);
+        }
+        cfg.interceptor_state().store_put(signing);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct NoCertificateVerification;
+
+impl rustls::client::ServerCertVerifier for NoCertificateVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
+
+const ARTIFACTS_PREFIX: &str = "artifacts";
+const AUDIT_PREFIX: &str = "audit";
 const REF_PREFIX: &str = "refs";
+const TRASH_PREFIX: &str = "trash";
 
 impl S3Storage {
     pub async fn new_s3_store(
@@ -118,8 +341,18 @@ impl S3Storage {
         prefix: impl Into<String>,
         config: Option<&S3Config>,
     ) -> Result<S3Storage, StorageError> {
-        let client = Arc::new(mk_client(config).await);
-        Ok(S3Storage { client, prefix: prefix.into(), bucket: bucket_name.into() })
+        let client = Arc::new(mk_client(config).await?);
+        Ok(S3Storage {
+            client,
+            prefix: prefix.into(),
+            bucket: bucket_name.into(),
+            layout: KeyLayout::default(),
+        })
+    }
+
+    pub fn with_key_layout(mut self, layout: KeyLayout) -> Self {
+        self.layout = layout;
+        self
     }
 
     fn get_path<const SIZE: usize, T: FileTypeTag>(
@@ -137,15 +370,61 @@ impl S3Storage {
     }
 
     fn get_snapshot_path(&self, id: &SnapshotId) -> StorageResult<String> {
-        self.get_path(SNAPSHOT_PREFIX, id)
+        self.object_key(ObjectKind::Snapshot, &id.to_string(), ObjectLocation::Live)
+    }
+
+    fn get_attributes_path(&self, id: &AttributesId) -> StorageResult<String> {
+        self.object_key(ObjectKind::Attributes, &id.to_string(), ObjectLocation::Live)
+    }
+
+    fn get_artifact_path(&self, id: &SnapshotId, name: &str) -> StorageResult<String> {
+        let path = PathBuf::from_iter([
+            self.prefix.as_str(),
+            ARTIFACTS_PREFIX,
+            id.to_string().as_str(),
+            name,
+        ]);
+        path.into_os_string().into_string().map_err(StorageError::BadPrefix)
     }
 
     fn get_manifest_path(&self, id: &ManifestId) -> StorageResult<String> {
-        self.get_path(MANIFEST_PREFIX, id)
+        self.object_key(ObjectKind::Manifest, &id.to_string(), ObjectLocation::Live)
     }
 
     fn get_chunk_path(&self, id: &ChunkId) -> StorageResult<String> {
-        self.get_path(CHUNK_PREFIX, id)
+        self.object_key(ObjectKind::Chunk, &id.to_string(), ObjectLocation::Live)
+    }
+
+    fn object_dir(
+        &self,
+        kind: ObjectKind,
+        location: ObjectLocation,
+    ) -> StorageResult<String> {
+        let kind_prefix = self.layout.prefix(kind);
+        // the empty component adds the trailing slash, listings must not match other
+        // prefixes starting with the same name
+        let path = match location {
+            ObjectLocation::Live => {
+                PathBuf::from_iter([self.prefix.as_str(), kind_prefix, ""])
+            }
+            ObjectLocation::Trash => {
+                PathBuf::from_iter([self.prefix.as_str(), TRASH_PREFIX, kind_prefix, ""])
+            }
+        };
+        path.into_os_string().into_string().map_err(StorageError::BadPrefix)
+    }
+
+    fn object_key(
+        &self,
+        kind: ObjectKind,
+        id: &str,
+        location: ObjectLocation,
+    ) -> StorageResult<String> {
+        let path = PathBuf::from_iter([
+            self.object_dir(kind, location)?.as_str(),
+            self.layout.relative_key(id).as_str(),
+        ]);
+        path.into_os_string().into_string().map_err(StorageError::BadPrefix)
     }
 
     fn ref_key(&self, ref_key: &str) -> StorageResult<String> {
@@ -154,17 +433,21 @@ impl S3Storage {
     }
 
     async fn get_object(&self, key: &str) -> StorageResult<Bytes> {
-        Ok(self
-            .client
-            .get_object()
-            .bucket(self.bucket.clone())
-            .key(key)
-            .send()
-            .await?
-            .body
-            .collect()
-            .await?
-            .into_bytes())
+        let res: StorageResult<Bytes> = async {
+            Ok(self
+                .client
+                .get_object()
+                .bucket(self.bucket.clone())
+                .key(key)
+                .send()
+                .await?
+                .body
+                .collect()
+                .await?
+                .into_bytes())
+        }
+        .await;
+        res.map_err(|err| err.with_key(key))
     }
 
     async fn get_object_range(
@@ -178,7 +461,21 @@ impl S3Storage {
             b = b.range(header)
         };
 
-        Ok(b.send().await?.body.collect().await?.into_bytes())
+        let res: StorageResult<Bytes> =
+            async { Ok(b.send().await?.body.collect().await?.into_bytes()) }.await;
+        res.map_err(|err| err.with_key(key))
+    }
+
+    async fn delete_key(&self, key: &str) -> StorageResult<()> {
+        // S3 deletes succeed for missing keys
+        self.client
+            .delete_object()
+            .bucket(self.bucket.clone())
+            .key(key)
+            .send()
+            .await
+            .map_err(|err| StorageError::from(err).with_key(key))?;
+        Ok(())
     }
 
     async fn put_object<
@@ -200,9 +497,94 @@ impl S3Storage {
             b = b.metadata(k, v);
         }
 
-        b.body(bytes.into()).send().await?;
+        b.body(bytes.into())
+            .send()
+            .await
+            .map_err(|err| StorageError::from(err).with_key(key))?;
         Ok(())
     }
+
+    /// Write `parts` as a multipart upload, the upload is aborted if a part fails.
+    ///
+    /// Parts are consumed as they are uploaded, at most [`MAX_CONCURRENT_PARTS`] at a
+    /// time, so they don't need to be in memory at once. All parts except the last one
+    /// must be at least 5MiB long.
+    async fn put_object_multipart<
+        I: IntoIterator<Item = (impl Into<String>, impl Into<String>)>,
+    >(
+        &self,
+        key: &str,
+        content_type: Option<impl Into<String>>,
+        metadata: I,
+        parts: impl Iterator<Item = StorageResult<Bytes>> + Send + 'static,
+    ) -> StorageResult<()> {
+        let mut b =
+            self.client.create_multipart_upload().bucket(self.bucket.clone()).key(key);
+        if let Some(ct) = content_type {
+            b = b.content_type(ct)
+        };
+        for (k, v) in metadata {
+            b = b.metadata(k, v);
+        }
+        let upload =
+            b.send().await.map_err(|err| StorageError::from(err).with_key(key))?;
+        let upload_id = upload.upload_id().ok_or_else(|| {
+            StorageError::Other("multipart upload without id".to_string()).with_key(key)
+        })?;
+
+        let parts = futures::stream::iter(parts.enumerate())
+            .map(|(index, body)| {
+                // part numbers start at 1
+                let part_number = index as i32 + 1;
+                async move {
+                    let res = self
+                        .client
+                        .upload_part()
+                        .bucket(self.bucket.clone())
+                        .key(key)
+                        .upload_id(upload_id)
+                        .part_number(part_number)
+                        .body(body?.into())
+                        .send()
+                        .await?;
+                    Ok::<_, StorageError>(
+                        CompletedPart::builder()
+                            .set_e_tag(res.e_tag().map(String::from))
+                            .part_number(part_number)
+                            .build(),
+                    )
+                }
+            })
+            .buffered(MAX_CONCURRENT_PARTS);
+        let res: StorageResult<()> = async {
+            let parts: Vec<CompletedPart> = parts.try_collect().await?;
+            self.client
+                .complete_multipart_upload()
+                .bucket(self.bucket.clone())
+                .key(key)
+                .upload_id(upload_id)
+                .multipart_upload(
+                    CompletedMultipartUpload::builder().set_parts(Some(parts)).build(),
+                )
+                .send()
+                .await?;
+            Ok(())
+        }
+        .await;
+
+        if res.is_err() {
+            // best effort, the bucket lifecycle rules clean up uploads left behind
+            let _ = self
+                .client
+                .abort_multipart_upload()
+                .bucket(self.bucket.clone())
+                .key(key)
+                .upload_id(upload_id)
+                .send()
+                .await;
+        }
+        res.map_err(|err| err.with_key(key))
+    }
 }
 
 pub fn range_to_header(range: &ByteRange) -> Option<String> {
@@ -235,205 +617,639 @@ pub fn range_to_header(range: &ByteRange) -> Option<String> {
     }
 }
 
-impl private::Sealed for S3Storage {}
-
-#[async_trait]
 impl Storage for S3Storage {
-    async fn fetch_snapshot(&self, id: &SnapshotId) -> StorageResult<Arc<Snapshot>> {
-        let key = self.get_snapshot_path(id)?;
-        let bytes = self.get_object(key.as_str()).await?;
-        // TODO: optimize using from_read
-        let res = rmp_serde::from_slice(bytes.as_ref())?;
-        Ok(Arc::new(res))
+    fn fetch_snapshot<'a>(
+        &'a self,
+        id: &'a SnapshotId,
+    ) -> StorageFuture<'a, Arc<Snapshot>> {
+        Box::pin(async move {
+            let key = self.get_snapshot_path(id)?;
+            let bytes = self.get_object(key.as_str()).await?;
+            // TODO: optimize using from_read
+            let res = rmp_serde::from_slice(bytes.as_ref())
+                .map_err(|err| StorageError::from(err).with_key(&key))?;
+            Ok(Arc::new(with_structure_base(self, res).await?))
+        })
     }
 
-    async fn fetch_attributes(
-        &self,
-        _id: &AttributesId,
-    ) -> StorageResult<Arc<AttributesTable>> {
-        todo!()
+    fn fetch_attributes<'a>(
+        &'a self,
+        id: &'a AttributesId,
+    ) -> StorageFuture<'a, Arc<AttributesTable>> {
+        Box::pin(async move {
+            let key = self.get_attributes_path(id)?;
+            let bytes = self.get_object(key.as_str()).await?;
+            let res = rmp_serde::from_slice(bytes.as_ref())
+                .map_err(|err| StorageError::from(err).with_key(&key))?;
+            Ok(Arc::new(res))
+        })
     }
 
-    async fn fetch_manifests(&self, id: &ManifestId) -> StorageResult<Arc<Manifest>> {
-        let key = self.get_manifest_path(id)?;
-        let bytes = self.get_object(key.as_str()).await?;
-        // TODO: optimize using from_read
-        let res = rmp_serde::from_slice(bytes.as_ref())?;
-        Ok(Arc::new(res))
+    fn fetch_manifests<'a>(
+        &'a self,
+        id: &'a ManifestId,
+    ) -> StorageFuture<'a, Arc<Manifest>> {
+        Box::pin(async move {
+            let key = self.get_manifest_path(id)?;
+            let bytes = self.get_object(key.as_str()).await?;
+            // TODO: optimize using from_read
+            let res = rmp_serde::from_slice(bytes.as_ref())
+                .map_err(|err| StorageError::from(err).with_key(&key))?;
+            Ok(Arc::new(res))
+        })
     }
 
-    async fn fetch_chunk(&self, id: &ChunkId, range: &ByteRange) -> StorageResult<Bytes> {
-        let key = self.get_chunk_path(id)?;
-        let bytes = self.get_object_range(key.as_str(), range).await?;
-        Ok(bytes)
+    fn fetch_manifest_bytes<'a>(
+        &'a self,
+        id: &'a ManifestId,
+    ) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            let key = self.get_manifest_path(id)?;
+            self.get_object(key.as_str()).await
+        })
     }
 
-    async fn write_snapshot(
-        &self,
+    fn fetch_chunk<'a>(
+        &'a self,
+        id: &'a ChunkId,
+        range: &'a ByteRange,
+    ) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            let key = self.get_chunk_path(id)?;
+            let bytes = self.get_object_range(key.as_str(), range).await?;
+            Ok(bytes)
+        })
+    }
+
+    fn write_snapshot<'a>(
+        &'a self,
         id: SnapshotId,
         snapshot: Arc<Snapshot>,
-    ) -> StorageResult<()> {
-        let key = self.get_snapshot_path(&id)?;
-        let bytes = rmp_serde::to_vec(snapshot.as_ref())?;
-        let metadata = [(
-            format_constants::LATEST_ICECHUNK_SNAPSHOT_VERSION_METADATA_KEY,
-            snapshot.icechunk_snapshot_format_version.to_string(),
-        )];
-        self.put_object(
-            key.as_str(),
-            Some(format_constants::LATEST_ICECHUNK_SNAPSHOT_CONTENT_TYPE),
-            metadata,
-            bytes,
-        )
-        .await
-    }
-
-    async fn write_attributes(
-        &self,
-        _id: AttributesId,
-        _table: Arc<AttributesTable>,
-    ) -> StorageResult<()> {
-        todo!()
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.get_snapshot_path(&id)?;
+            let bytes = rmp_serde::to_vec(snapshot.as_ref())?;
+            let metadata = [(
+                format_constants::LATEST_ICECHUNK_SNAPSHOT_VERSION_METADATA_KEY,
+                snapshot.icechunk_snapshot_format_version.to_string(),
+            )];
+            self.put_object(
+                key.as_str(),
+                Some(format_constants::LATEST_ICECHUNK_SNAPSHOT_CONTENT_TYPE),
+                metadata,
+                bytes,
+            )
+            .await
+        })
     }
 
-    async fn write_manifests(
-        &self,
+    fn write_attributes<'a>(
+        &'a self,
+        id: AttributesId,
+        table: Arc<AttributesTable>,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.get_attributes_path(&id)?;
+            let bytes = rmp_serde::to_vec(table.as_ref())?;
+            let metadata = [(
+                format_constants::LATEST_ICECHUNK_ATTRIBUTES_VERSION_METADATA_KEY,
+                table.icechunk_attributes_format_version.to_string(),
+            )];
+            self.put_object(
+                key.as_str(),
+                Some(format_constants::LATEST_ICECHUNK_ATTRIBUTES_CONTENT_TYPE),
+                metadata,
+                bytes,
+            )
+            .await
+        })
+    }
+
+    fn write_manifests<'a>(
+        &'a self,
         id: ManifestId,
         manifest: Arc<Manifest>,
-    ) -> Result<(), StorageError> {
-        let key = self.get_manifest_path(&id)?;
-        let bytes = rmp_serde::to_vec(manifest.as_ref())?;
-        let metadata = [(
-            format_constants::LATEST_ICECHUNK_MANIFEST_VERSION_METADATA_KEY,
-            manifest.icechunk_manifest_format_version.to_string(),
-        )];
-        self.put_object(
-            key.as_str(),
-            Some(format_constants::LATEST_ICECHUNK_MANIFEST_CONTENT_TYPE),
-            metadata,
-            bytes,
-        )
-        .await
-    }
-
-    async fn write_chunk(
-        &self,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.get_manifest_path(&id)?;
+            let metadata = [(
+                format_constants::LATEST_ICECHUNK_MANIFEST_VERSION_METADATA_KEY,
+                manifest.icechunk_manifest_format_version.to_string(),
+            )];
+            if manifest.estimated_size_bytes() > MULTIPART_THRESHOLD_BYTES as u64 {
+                // serialized in parts, so no single buffer holds the whole manifest
+                let parts = manifest
+                    .serialized_parts(MULTIPART_PART_BYTES)
+                    .collect::<Result<Vec<_>, _>>()?
+                    .into_iter()
+                    .map(Ok);
+                return self
+                    .put_object_multipart(
+                        key.as_str(),
+                        Some(format_constants::LATEST_ICECHUNK_MANIFEST_CONTENT_TYPE),
+                        metadata,
+                        parts,
+                    )
+                    .await;
+            }
+            let bytes = rmp_serde::to_vec(manifest.as_ref())?;
+            self.put_object(
+                key.as_str(),
+                Some(format_constants::LATEST_ICECHUNK_MANIFEST_CONTENT_TYPE),
+                metadata,
+                bytes,
+            )
+            .await
+        })
+    }
+
+    fn write_chunk<'a>(
+        &'a self,
         id: ChunkId,
         bytes: bytes::Bytes,
-    ) -> Result<(), StorageError> {
-        let key = self.get_chunk_path(&id)?;
-        //FIXME: use multipart upload
-        let metadata: [(String, String); 0] = [];
-        self.put_object(key.as_str(), None::<String>, metadata, bytes).await
-    }
-
-    async fn get_ref(&self, ref_key: &str) -> StorageResult<Bytes> {
-        let key = self.ref_key(ref_key)?;
-        let res = self
-            .client
-            .get_object()
-            .bucket(self.bucket.clone())
-            .key(key.clone())
-            .send()
-            .await;
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.get_chunk_path(&id)?;
+            let metadata: [(String, String); 0] = [];
+            if bytes.len() > MULTIPART_THRESHOLD_BYTES {
+                let len = bytes.len();
+                let parts = (0..len).step_by(MULTIPART_PART_BYTES).map(move |start| {
+                    Ok(bytes.slice(start..(start + MULTIPART_PART_BYTES).min(len)))
+                });
+                return self
+                    .put_object_multipart(key.as_str(), None::<String>, metadata, parts)
+                    .await;
+            }
+            self.put_object(key.as_str(), None::<String>, metadata, bytes).await
+        })
+    }
 
-        match res {
-            Ok(res) => Ok(res.body.collect().await?.into_bytes()),
-            Err(err)
-                if err
-                    .as_service_error()
-                    .map(|e| e.is_no_such_key())
-                    .unwrap_or(false) =>
-            {
-                Err(StorageError::RefNotFound(key.to_string()))
+    fn fetch_snapshot_artifact<'a>(
+        &'a self,
+        id: &'a SnapshotId,
+        name: &'a str,
+    ) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            let key = self.get_artifact_path(id, name)?;
+            self.get_object(key.as_str()).await
+        })
+    }
+
+    fn write_snapshot_artifact<'a>(
+        &'a self,
+        id: &'a SnapshotId,
+        name: &'a str,
+        bytes: Bytes,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.get_artifact_path(id, name)?;
+            self.put_object(
+                key.as_str(),
+                None::<String>,
+                Vec::<(String, String)>::new(),
+                bytes,
+            )
+            .await
+        })
+    }
+
+    fn fetch_audit_records(&self) -> StorageFuture<'_, Vec<Bytes>> {
+        Box::pin(async move {
+            // the empty component adds the trailing slash
+            let dir = PathBuf::from_iter([self.prefix.as_str(), AUDIT_PREFIX, ""])
+                .into_os_string()
+                .into_string()
+                .map_err(StorageError::BadPrefix)?;
+            let mut paginator = self
+                .client
+                .list_objects_v2()
+                .bucket(self.bucket.clone())
+                .prefix(dir)
+                .into_paginator()
+                .send();
+            let mut keys = Vec::new();
+            while let Some(page) = paginator.try_next().await? {
+                keys.extend(
+                    page.contents().iter().filter_map(|o| o.key()).map(String::from),
+                );
             }
-            Err(err) => Err(err.into()),
-        }
+            let mut res = Vec::with_capacity(keys.len());
+            for key in keys {
+                res.push(self.get_object(key.as_str()).await?);
+            }
+            Ok(res)
+        })
     }
 
-    async fn ref_names(&self) -> StorageResult<Vec<String>> {
-        let prefix = self.ref_key("")?;
-        let mut paginator = self
-            .client
-            .list_objects_v2()
-            .bucket(self.bucket.clone())
-            .prefix(prefix.clone())
-            .delimiter("/")
-            .into_paginator()
-            .send();
-
-        let mut res = Vec::new();
-
-        while let Some(page) = paginator.try_next().await? {
-            for common_prefix in page.common_prefixes() {
-                if let Some(key) = common_prefix
-                    .prefix()
-                    .as_ref()
-                    .and_then(|key| key.strip_prefix(prefix.as_str()))
-                    .and_then(|key| key.strip_suffix('/'))
+    fn write_audit_record<'a>(
+        &'a self,
+        id: &'a AuditRecordId,
+        bytes: Bytes,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.get_path(AUDIT_PREFIX, id)?;
+            self.put_object(
+                key.as_str(),
+                None::<String>,
+                Vec::<(String, String)>::new(),
+                bytes,
+            )
+            .await
+        })
+    }
+
+    fn delete_snapshot<'a>(&'a self, id: &'a SnapshotId) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.get_snapshot_path(id)?;
+            self.delete_key(key.as_str()).await
+        })
+    }
+
+    fn delete_manifests<'a>(&'a self, id: &'a ManifestId) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.get_manifest_path(id)?;
+            self.delete_key(key.as_str()).await
+        })
+    }
+
+    fn delete_chunk<'a>(&'a self, id: &'a ChunkId) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.get_chunk_path(id)?;
+            self.delete_key(key.as_str()).await
+        })
+    }
+
+    fn get_ref<'a>(&'a self, ref_key: &'a str) -> StorageFuture<'a, Bytes> {
+        Box::pin(async move {
+            let key = self.ref_key(ref_key)?;
+            let res = self
+                .client
+                .get_object()
+                .bucket(self.bucket.clone())
+                .key(key.clone())
+                .send()
+                .await;
+
+            match res {
+                Ok(res) => Ok(res.body.collect().await?.into_bytes()),
+                Err(err)
+                    if err
+                        .as_service_error()
+                        .map(|e| e.is_no_such_key())
+                        .unwrap_or(false) =>
                 {
-                    res.push(key.to_string());
+                    Err(StorageError::RefNotFound(key.to_string()))
                 }
+                Err(err) => Err(err.into()),
             }
-        }
-
-        Ok(res)
+        })
     }
 
-    async fn ref_versions(
-        &self,
-        ref_name: &str,
-    ) -> StorageResult<futures::stream::BoxStream<StorageResult<String>>> {
-        let prefix = self.ref_key(ref_name)?;
-        let mut paginator = self
-            .client
-            .list_objects_v2()
-            .bucket(self.bucket.clone())
-            .prefix(prefix.clone())
-            .into_paginator()
-            .send();
+    fn ref_names<'a>(&'a self) -> StorageFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            let prefix = self.ref_key("")?;
+            let mut paginator = self
+                .client
+                .list_objects_v2()
+                .bucket(self.bucket.clone())
+                .prefix(prefix.clone())
+                .delimiter("/")
+                .into_paginator()
+                .send();
+
+            let mut res = Vec::new();
 
-        let prefix = prefix + "/";
-        let stream = try_stream! {
             while let Some(page) = paginator.try_next().await? {
-                for object in page.contents() {
-                    if let Some(key) = object.key.as_ref().and_then(|key| key.strip_prefix(prefix.as_str())) {
-                        yield key.to_string()
+                for common_prefix in page.common_prefixes() {
+                    if let Some(key) = common_prefix
+                        .prefix()
+                        .as_ref()
+                        .and_then(|key| key.strip_prefix(prefix.as_str()))
+                        .and_then(|key| key.strip_suffix('/'))
+                    {
+                        res.push(key.to_string());
                     }
                 }
             }
-        };
-        Ok(stream.boxed())
+
+            Ok(res)
+        })
     }
 
-    async fn write_ref(
-        &self,
-        ref_key: &str,
+    fn ref_versions<'a, 'b>(
+        &'a self,
+        ref_name: &'b str,
+    ) -> StorageFuture<'b, futures::stream::BoxStream<'a, StorageResult<String>>>
+    where
+        'a: 'b,
+    {
+        Box::pin(async move {
+            let prefix = self.ref_key(ref_name)?;
+            let mut paginator = self
+                .client
+                .list_objects_v2()
+                .bucket(self.bucket.clone())
+                .prefix(prefix.clone())
+                .into_paginator()
+                .send();
+
+            let prefix = prefix + "/";
+            let stream = try_stream! {
+                while let Some(page) = paginator.try_next().await? {
+                    for object in page.contents() {
+                        if let Some(key) = object.key.as_ref().and_then(|key| key.strip_prefix(prefix.as_str())) {
+                            yield key.to_string()
+                        }
+                    }
+                }
+            };
+            Ok(stream.boxed())
+        })
+    }
+
+    fn write_ref<'a>(
+        &'a self,
+        ref_key: &'a str,
         overwrite_refs: bool,
         bytes: Bytes,
-    ) -> StorageResult<()> {
-        let key = self.ref_key(ref_key)?;
-        let mut builder =
-            self.client.put_object().bucket(self.bucket.clone()).key(key.clone());
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.ref_key(ref_key)?;
+            let mut builder =
+                self.client.put_object().bucket(self.bucket.clone()).key(key.clone());
 
-        if !overwrite_refs {
-            builder = builder.if_none_match("*")
-        }
+            if !overwrite_refs {
+                builder = builder.if_none_match("*")
+            }
 
-        let res = builder.body(bytes.into()).send().await;
+            let res = builder.body(bytes.into()).send().await;
 
-        match res {
-            Ok(_) => Ok(()),
-            Err(err) => {
-                let code = err.as_service_error().and_then(|e| e.code()).unwrap_or("");
-                if code.contains("PreconditionFailed")
-                    || code.contains("ConditionalRequestConflict")
-                {
-                    Err(StorageError::RefAlreadyExists(key))
-                } else {
-                    Err(err.into())
+            match res {
+                Ok(_) => Ok(()),
+                Err(err) => {
+                    let code =
+                        err.as_service_error().and_then(|e| e.code()).unwrap_or("");
+                    if code.contains("PreconditionFailed")
+                        || code.contains("ConditionalRequestConflict")
+                    {
+                        Err(StorageError::RefAlreadyExists(key))
+                    } else {
+                        Err(err.into())
+                    }
                 }
             }
-        }
+        })
+    }
+
+    fn delete_ref<'a>(&'a self, ref_key: &'a str) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.ref_key(ref_key)?;
+            self.delete_key(key.as_str()).await
+        })
+    }
+
+    fn list_objects<'a>(
+        &'a self,
+        kind: ObjectKind,
+        location: ObjectLocation,
+    ) -> StorageFuture<'a, BoxStream<'a, StorageResult<ObjectInfo>>> {
+        Box::pin(async move {
+            let prefix = self.object_dir(kind, location)?;
+            let mut paginator = self
+                .client
+                .list_objects_v2()
+                .bucket(self.bucket.clone())
+                .prefix(prefix.clone())
+                .into_paginator()
+                .send();
+
+            let stream = try_stream! {
+                while let Some(page) = paginator.try_next().await? {
+                    for object in page.contents() {
+                        // the id is the last part of the key, after the fan out
+                        let id = object
+                            .key()
+                            .and_then(|key| key.strip_prefix(prefix.as_str()))
+                            .and_then(|key| key.rsplit('/').next());
+                        let modified_at = object
+                            .last_modified()
+                            .and_then(|t| DateTime::from_timestamp(t.secs(), t.subsec_nanos()));
+                        if let (Some(id), Some(modified_at)) = (id, modified_at) {
+                            yield ObjectInfo { id: id.to_string(), modified_at }
+                        }
+                    }
+                }
+            };
+            Ok(stream.boxed())
+        })
+    }
+
+    fn move_object<'a>(
+        &'a self,
+        kind: ObjectKind,
+        id: &'a str,
+        to: ObjectLocation,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let from = match to {
+                ObjectLocation::Live => ObjectLocation::Trash,
+                ObjectLocation::Trash => ObjectLocation::Live,
+            };
+            let from = self.object_key(kind, id, from)?;
+            // copies get a new modification time, trashed objects are expired by it
+            self.client
+                .copy_object()
+                .bucket(self.bucket.clone())
+                .copy_source(format!("{}/{}", self.bucket, from))
+                .key(self.object_key(kind, id, to)?)
+                .send()
+                .await?;
+            self.delete_key(from.as_str()).await
+        })
+    }
+
+    fn delete_object<'a>(
+        &'a self,
+        kind: ObjectKind,
+        id: &'a str,
+        location: ObjectLocation,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            let key = self.object_key(kind, id, location)?;
+            self.delete_key(key.as_str()).await
+        })
+    }
+
+    fn list_prefixes<'a>(&'a self, prefix: &'a str) -> StorageFuture<'a, Vec<String>> {
+        Box::pin(async move {
+            // the empty component adds the trailing slash, unless the key is empty
+            let dir = PathBuf::from_iter([self.prefix.as_str(), prefix, ""])
+                .into_os_string()
+                .into_string()
+                .map_err(StorageError::BadPrefix)?;
+            let mut paginator = self
+                .client
+                .list_objects_v2()
+                .bucket(self.bucket.clone())
+                .prefix(dir.clone())
+                .delimiter("/")
+                .into_paginator()
+                .send();
+
+            let mut res = Vec::new();
+            while let Some(page) = paginator.try_next().await? {
+                for common_prefix in page.common_prefixes() {
+                    if let Some(name) = common_prefix
+                        .prefix()
+                        .and_then(|key| key.strip_prefix(dir.as_str()))
+                        .and_then(|key| key.strip_suffix('/'))
+                    {
+                        res.push(name.to_string());
+                    }
+                }
+            }
+            Ok(res)
+        })
+    }
+
+    fn sub_storage(&self, prefix: &str) -> StorageResult<Arc<dyn Storage>> {
+        let prefix = PathBuf::from_iter([self.prefix.as_str(), prefix])
+            .into_os_string()
+            .into_string()
+            .map_err(StorageError::BadPrefix)?;
+        Ok(Arc::new(S3Storage {
+            client: Arc::clone(&self.client),
+            prefix,
+            bucket: self.bucket.clone(),
+            layout: self.layout.clone(),
+        }))
+    }
+
+    fn key_layout(&self) -> KeyLayout {
+        self.layout.clone()
+    }
+
+    fn chunk_copy_source(&self, id: &ChunkId) -> StorageResult<Option<CopySource>> {
+        Ok(Some(CopySource {
+            provider: "s3",
+            container: self.bucket.clone(),
+            key: self.get_chunk_path(id)?,
+        }))
+    }
+
+    fn can_copy_from(&self, source: &CopySource) -> bool {
+        // the credentials of this client must also be able to read the source bucket
+        source.provider == "s3"
+    }
+
+    fn copy_chunk_from<'a>(
+        &'a self,
+        id: ChunkId,
+        source: &'a CopySource,
+    ) -> StorageFuture<'a, ()> {
+        Box::pin(async move {
+            self.client
+                .copy_object()
+                .bucket(self.bucket.clone())
+                .copy_source(format!("{}/{}", source.container, source.key))
+                .key(self.get_chunk_path(&id)?)
+                .send()
+                .await?;
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tls_config() {
+        let config = S3Config {
+            region: Some("us-east-1".to_string()),
+            endpoint: Some("https://localhost:9000".to_string()),
+            credentials: S3Credentials::Anonymous,
+            force_path_style: true,
+            ..Default::default()
+        };
+        assert!(tls_http_client(&config).unwrap().is_none());
+        let insecure = S3Config { insecure_skip_tls_verify: true, ..config.clone() };
+        assert!(tls_http_client(&insecure).unwrap().is_some());
+        assert!(mk_client(Some(&insecure)).await.is_ok());
+
+        let missing =
+            S3Config { ca_bundle: Some("/missing/ca.pem".into()), ..config.clone() };
+        assert!(matches!(
+            mk_client(Some(&missing)).await,
+            Err(StorageError::InvalidConfig(message)) if message.contains("/missing/ca.pem")
+        ));
+
+        let mut not_pem = tempfile::NamedTempFile::new().unwrap();
+        not_pem.write_all(b"not a certificate").unwrap();
+        let bad = S3Config { ca_bundle: Some(not_pem.path().into()), ..config };
+        assert!(matches!(
+            tls_http_client(&bad),
+            Err(StorageError::InvalidConfig(message)) if message.contains("no PEM certificates")
+        ));
+    }
+
+    /// The head of the first request sent to a server that refuses every request
+    async fn captured_request(config: S3Config) -> String {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 16 * 1024];
+            let read = socket.read(&mut buf).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 403 Forbidden\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8_lossy(&buf[..read]).to_lowercase()
+        });
+
+        let config = S3Config { endpoint: Some(endpoint), allow_http: true, ..config };
+        let client = mk_client(Some(&config)).await.unwrap();
+        let res = client
+            .put_object()
+            .bucket("bucket")
+            .key("key")
+            .body(Bytes::from_static(b"hello").into())
+            .send()
+            .await;
+        assert!(res.is_err());
+        server.await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_signing_overrides() {
+        let config = S3Config {
+            region: Some("us-east-1".to_string()),
+            credentials: S3Credentials::Static(StaticS3Credentials {
+                access_key_id: "key".to_string(),
+                secret_access_key: "secret".to_string(),
+                session_token: None,
+            }),
+            ..Default::default()
+        };
+        let request = captured_request(config.clone()).await;
+        assert!(request.contains("/us-east-1/s3/aws4_request"));
+        assert!(!request.contains("unsigned-payload"));
+
+        let request = captured_request(S3Config {
+            signing_region: Some("appliance-1".to_string()),
+            signing_service: Some("storage".to_string()),
+            unsigned_payload: true,
+            ..config
+        })
+        .await;
+        assert!(request.contains("/appliance-1/storage/aws4_request"));
+        assert!(request.contains("x-amz-content-sha256: unsigned-payload"));
     }
 }
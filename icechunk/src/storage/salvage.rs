@@ -0,0 +1,95 @@
+//! Reading repositories with corrupted manifests.
+//!
+//! A manifest with a single unreadable chunk reference fails to decode, and with it every
+//! read of the chunks it holds. [`SalvagingStorage`] decodes such manifests again with
+//! [`Manifest::salvage`], skipping the unreadable references, and remembers which chunks
+//! were lost. Reads of the lost chunks return the fill value, like missing chunks. See
+//! [`crate::repair`] to write the cleaned manifests in a new commit.
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Storage, StorageError, StorageFuture};
+use crate::{
+    error::ErrorKind,
+    format::{manifest::Manifest, ChunkIndices, ManifestId, NodeId},
+};
+
+/// What was lost from a salvaged manifest
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SalvageReport {
+    /// The chunks whose references couldn't be read
+    pub lost: Vec<(NodeId, ChunkIndices)>,
+    /// References lost without knowing their chunks
+    pub unidentified: u64,
+}
+
+/// A [`Storage`] decorator that salvages the manifests that fail to decode.
+///
+/// Manifests that can't be salvaged, because their header is unreadable, still fail.
+#[derive(Debug)]
+pub struct SalvagingStorage {
+    backend: Arc<dyn Storage>,
+    salvaged: Mutex<BTreeMap<ManifestId, SalvageReport>>,
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl SalvagingStorage {
+    pub fn new(backend: Arc<dyn Storage>) -> Self {
+        Self { backend, salvaged: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// The manifests read so far that lost chunk references
+    pub fn salvaged(&self) -> BTreeMap<ManifestId, SalvageReport> {
+        lock(&self.salvaged).clone()
+    }
+}
+
+impl Storage for SalvagingStorage {
+    fn fetch_manifests<'a>(
+        &'a self,
+        id: &'a ManifestId,
+    ) -> StorageFuture<'a, Arc<Manifest>> {
+        Box::pin(async move {
+            match self.backend.fetch_manifests(id).await {
+                Err(err) if err.kind() == ErrorKind::Corruption => {
+                    let bytes = self.backend.fetch_manifest_bytes(id).await?;
+                    let salvaged =
+                        Manifest::salvage(bytes.as_ref()).map_err(|salvage_err| {
+                            StorageError::Other(salvage_err.to_string())
+                                .with_key(&id.to_string())
+                        })?;
+                    let manifest = Arc::new(salvaged.manifest);
+                    if !salvaged.lost.is_empty() || salvaged.unidentified > 0 {
+                        lock(&self.salvaged).insert(
+                            id.clone(),
+                            SalvageReport {
+                                lost: salvaged.lost,
+                                unidentified: salvaged.unidentified,
+                            },
+                        );
+                    }
+                    Ok(manifest)
+                }
+                res => res,
+            }
+        })
+    }
+
+    crate::forward_storage!(
+        backend =>
+            fetch_snapshot, fetch_attributes, fetch_manifest_bytes, fetch_chunk,
+            fetch_chunk_with_status, write_snapshot, write_attributes, write_manifests,
+            write_chunk, delete_snapshot, delete_manifests, delete_chunk, get_ref,
+            ref_names, ref_versions, write_ref, delete_ref, list_objects, move_object,
+            delete_object, fetch_snapshot_artifact, write_snapshot_artifact,
+            fetch_audit_records, write_audit_record, list_prefixes, key_layout,
+            chunk_copy_source, can_copy_from, copy_chunk_from,
+    );
+}
@@ -1,5 +1,5 @@
 use crate::format::manifest::{VirtualChunkLocation, VirtualReferenceError};
-use crate::format::ByteRange;
+use crate::format::{ByteRange, IcechunkFormatError, IcechunkResult};
 use crate::private;
 use async_trait::async_trait;
 use aws_sdk_s3::Client;
@@ -7,13 +7,17 @@ use bytes::Bytes;
 use object_store::local::LocalFileSystem;
 use object_store::{path::Path as ObjectPath, GetOptions, GetRange, ObjectStore};
 use serde::{Deserialize, Serialize};
-use std::cmp::{max, min};
+use std::cmp::min;
+use std::collections::HashMap;
 use std::fmt::Debug;
 use std::ops::Bound;
+use std::sync::{Arc, Mutex, PoisonError};
 use tokio::sync::OnceCell;
 use url::{self, Url};
 
+use super::range_cache::{RangeCache, RangeCacheKey};
 use super::s3::{mk_client, range_to_header, S3Config};
+use super::{CacheStatus, StorageError};
 
 #[async_trait]
 pub trait VirtualChunkResolver: Debug + private::Sealed {
@@ -22,6 +26,43 @@ pub trait VirtualChunkResolver: Debug + private::Sealed {
         location: &VirtualChunkLocation,
         range: &ByteRange,
     ) -> Result<Bytes, VirtualReferenceError>;
+
+    /// Like [`VirtualChunkResolver::fetch_chunk`], also returning what the response
+    /// says about the file
+    async fn fetch_range(
+        &self,
+        location: &VirtualChunkLocation,
+        range: &ByteRange,
+    ) -> Result<FetchedRange, VirtualReferenceError>;
+
+    /// The etag of the file at `location`, `None` if the store doesn't have one
+    async fn fetch_etag(
+        &self,
+        location: &VirtualChunkLocation,
+    ) -> Result<Option<String>, VirtualReferenceError>;
+
+    /// The size and etag of the file at `location`, with a `HEAD` request
+    async fn fetch_file_info(
+        &self,
+        location: &VirtualChunkLocation,
+    ) -> Result<VirtualFileInfo, VirtualReferenceError>;
+}
+
+/// What a `HEAD` request says about a file, see [`VirtualChunkResolver::fetch_file_info`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VirtualFileInfo {
+    pub size: u64,
+    /// `None` if the store doesn't have one
+    pub etag: Option<String>,
+}
+
+/// The bytes of a file range, see [`VirtualChunkResolver::fetch_range`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FetchedRange {
+    pub bytes: Bytes,
+    /// The etag of the file, `None` if the store doesn't have one
+    pub etag: Option<String>,
+    pub cache: CacheStatus,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -33,17 +74,31 @@ pub enum ObjectStoreVirtualChunkResolverConfig {
 pub struct ObjectStoreVirtualChunkResolver {
     s3: OnceCell<Client>,
     config: Box<Option<ObjectStoreVirtualChunkResolverConfig>>,
+    range_cache: Option<Arc<dyn RangeCache>>,
+    /// The etag of every file read through the cache, from the last response
+    etags: Mutex<HashMap<String, Option<String>>>,
 }
 
 impl ObjectStoreVirtualChunkResolver {
     pub fn new(config: Option<ObjectStoreVirtualChunkResolverConfig>) -> Self {
-        Self { s3: Default::default(), config: Box::new(config) }
+        Self {
+            s3: Default::default(),
+            config: Box::new(config),
+            range_cache: None,
+            etags: Default::default(),
+        }
+    }
+
+    /// Serve repeated reads of the same ranges from `cache`
+    pub fn with_range_cache(mut self, cache: Arc<dyn RangeCache>) -> Self {
+        self.range_cache = Some(cache);
+        self
     }
 
-    async fn s3(&self) -> &Client {
+    async fn s3(&self) -> Result<&Client, VirtualReferenceError> {
         let config = self.config.clone();
         self.s3
-            .get_or_init(|| async move {
+            .get_or_try_init(|| async move {
                 match config.as_ref() {
                     Some(ObjectStoreVirtualChunkResolverConfig::S3(config)) => {
                         mk_client(Some(config)).await
@@ -52,58 +107,126 @@ impl ObjectStoreVirtualChunkResolver {
                 }
             })
             .await
+            .map_err(|err| VirtualReferenceError::FetchError(Box::new(err)))
     }
 
     async fn fetch_file(
         &self,
         url: &Url,
         range: &ByteRange,
-    ) -> Result<Bytes, VirtualReferenceError> {
+    ) -> Result<(Bytes, Option<String>), VirtualReferenceError> {
         let store = LocalFileSystem::new();
         let options =
             GetOptions { range: Option::<GetRange>::from(range), ..Default::default() };
-        let path = ObjectPath::parse(url.path())
-            .map_err(|e| VirtualReferenceError::OtherError(Box::new(e)))?;
+        let path = file_path(url)?;
 
-        store
-            .get_opts(&path, options)
-            .await
-            .map_err(|e| VirtualReferenceError::FetchError(Box::new(e)))?
-            .bytes()
-            .await
-            .map_err(|e| VirtualReferenceError::FetchError(Box::new(e)))
+        let res = store.get_opts(&path, options).await.map_err(|e| {
+            VirtualReferenceError::FetchError(Box::new(StorageError::from(e)))
+        })?;
+        let etag = res.meta.e_tag.clone();
+        let bytes = res.bytes().await.map_err(|e| {
+            VirtualReferenceError::FetchError(Box::new(StorageError::from(e)))
+        })?;
+        Ok((bytes, etag))
     }
 
     async fn fetch_s3(
         &self,
         url: &Url,
         range: &ByteRange,
-    ) -> Result<Bytes, VirtualReferenceError> {
-        let bucket_name = if let Some(host) = url.host_str() {
-            host.to_string()
-        } else {
-            Err(VirtualReferenceError::CannotParseBucketName(
-                "No bucket name found".to_string(),
-            ))?
-        };
-
-        let key = url.path();
-        let key = key.strip_prefix('/').unwrap_or(key);
-        let mut b = self.s3().await.get_object().bucket(bucket_name).key(key);
+    ) -> Result<(Bytes, Option<String>), VirtualReferenceError> {
+        let (bucket_name, key) = s3_bucket_and_key(url)?;
+        let mut b = self.s3().await?.get_object().bucket(bucket_name).key(key);
 
         if let Some(header) = range_to_header(range) {
             b = b.range(header)
         };
 
-        Ok(b.send()
-            .await
-            .map_err(|e| VirtualReferenceError::FetchError(Box::new(e)))?
+        let res = b.send().await.map_err(|e| {
+            VirtualReferenceError::FetchError(Box::new(StorageError::from(e)))
+        })?;
+        let etag = res.e_tag().map(|etag| etag.to_string());
+        let bytes = res
             .body
             .collect()
             .await
-            .map_err(|e| VirtualReferenceError::FetchError(Box::new(e)))?
-            .into_bytes())
+            .map_err(|e| {
+                VirtualReferenceError::FetchError(Box::new(StorageError::from(e)))
+            })?
+            .into_bytes();
+        Ok((bytes, etag))
+    }
+
+    async fn head(&self, url: &Url) -> Result<VirtualFileInfo, VirtualReferenceError> {
+        match url.scheme() {
+            "file" => {
+                let meta =
+                    LocalFileSystem::new().head(&file_path(url)?).await.map_err(|e| {
+                        VirtualReferenceError::FetchError(Box::new(StorageError::from(e)))
+                    })?;
+                Ok(VirtualFileInfo { size: meta.size as u64, etag: meta.e_tag })
+            }
+            "s3" => {
+                let (bucket_name, key) = s3_bucket_and_key(url)?;
+                let res = self
+                    .s3()
+                    .await?
+                    .head_object()
+                    .bucket(bucket_name)
+                    .key(key)
+                    .send()
+                    .await
+                    .map_err(|e| {
+                        VirtualReferenceError::FetchError(Box::new(StorageError::from(e)))
+                    })?;
+                Ok(VirtualFileInfo {
+                    size: res.content_length().unwrap_or_default().max(0) as u64,
+                    etag: res.e_tag().map(|etag| etag.to_string()),
+                })
+            }
+            scheme => Err(VirtualReferenceError::UnsupportedScheme(scheme.to_string())),
+        }
     }
+
+    /// The etag of the file at `url` seen in the last response, reads never send
+    /// `HEAD` requests to learn it
+    fn known_etag(&self, url: &Url) -> Option<String> {
+        self.etags
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(url.as_str())
+            .cloned()
+            .flatten()
+    }
+
+    async fn fetch_uncached(
+        &self,
+        url: &Url,
+        range: &ByteRange,
+    ) -> Result<(Bytes, Option<String>), VirtualReferenceError> {
+        match url.scheme() {
+            "file" => self.fetch_file(url, range).await,
+            "s3" => self.fetch_s3(url, range).await,
+            scheme => Err(VirtualReferenceError::UnsupportedScheme(scheme.to_string())),
+        }
+    }
+}
+
+fn file_path(url: &Url) -> Result<ObjectPath, VirtualReferenceError> {
+    ObjectPath::parse(url.path())
+        .map_err(|e| VirtualReferenceError::OtherError(Box::new(e)))
+}
+
+fn s3_bucket_and_key(url: &Url) -> Result<(String, &str), VirtualReferenceError> {
+    let bucket_name = if let Some(host) = url.host_str() {
+        host.to_string()
+    } else {
+        Err(VirtualReferenceError::CannotParseBucketName(
+            "No bucket name found".to_string(),
+        ))?
+    };
+    let key = url.path();
+    Ok((bucket_name, key.strip_prefix('/').unwrap_or(key)))
 }
 
 // Converts the requested ByteRange to a valid ByteRange appropriate
@@ -112,28 +235,31 @@ pub fn construct_valid_byte_range(
     request: &ByteRange,
     chunk_offset: u64,
     chunk_length: u64,
-) -> ByteRange {
-    // TODO: error for offset<0
-    // TODO: error if request.start > offset + length
-    // FIXME: we allow creating a ByteRange(start, end) where end < start
-    let new_offset = match request.0 {
-        Bound::Unbounded => chunk_offset,
-        Bound::Included(start) => max(start, 0) + chunk_offset,
-        Bound::Excluded(start) => max(start, 0) + chunk_offset + 1,
+) -> IcechunkResult<ByteRange> {
+    let overflow = || IcechunkFormatError::ByteRangeOverflow {
+        offset: chunk_offset,
+        length: chunk_length,
     };
-    request.length().map_or(
-        ByteRange(
-            Bound::Included(new_offset),
-            Bound::Excluded(chunk_offset + chunk_length),
-        ),
-        |reqlen| {
-            ByteRange(
-                Bound::Included(new_offset),
-                // no request can go past offset + length, so clamp it
-                Bound::Excluded(min(new_offset + reqlen, chunk_offset + chunk_length)),
-            )
-        },
-    )
+    let chunk_end = chunk_offset.checked_add(chunk_length).ok_or_else(overflow)?;
+    let new_offset = match request.0 {
+        Bound::Unbounded => Some(chunk_offset),
+        Bound::Included(start) => start.checked_add(chunk_offset),
+        Bound::Excluded(start) => {
+            start.checked_add(chunk_offset).and_then(|start| start.checked_add(1))
+        }
+    }
+    .ok_or_else(overflow)?;
+    if new_offset > chunk_end {
+        return Err(IcechunkFormatError::ByteRangeOutOfBounds {
+            range: request.clone(),
+            size: chunk_length,
+        });
+    }
+    // no request can go past offset + length, so clamp it
+    let end = request
+        .length()
+        .map_or(chunk_end, |reqlen| min(new_offset.saturating_add(reqlen), chunk_end));
+    Ok(ByteRange(Bound::Included(new_offset), Bound::Excluded(end)))
 }
 
 impl private::Sealed for ObjectStoreVirtualChunkResolver {}
@@ -145,22 +271,76 @@ impl VirtualChunkResolver for ObjectStoreVirtualChunkResolver {
         location: &VirtualChunkLocation,
         range: &ByteRange,
     ) -> Result<Bytes, VirtualReferenceError> {
-        let VirtualChunkLocation::Absolute(location) = location;
-        let parsed =
-            url::Url::parse(location).map_err(VirtualReferenceError::CannotParseUrl)?;
-        let scheme = parsed.scheme();
+        Ok(self.fetch_range(location, range).await?.bytes)
+    }
 
-        match scheme {
-            "file" => self.fetch_file(&parsed, range).await,
-            "s3" => self.fetch_s3(&parsed, range).await,
-            _ => Err(VirtualReferenceError::UnsupportedScheme(scheme.to_string())),
+    async fn fetch_range(
+        &self,
+        location: &VirtualChunkLocation,
+        range: &ByteRange,
+    ) -> Result<FetchedRange, VirtualReferenceError> {
+        let VirtualChunkLocation::Absolute(url) = location;
+        let parsed =
+            url::Url::parse(url).map_err(VirtualReferenceError::CannotParseUrl)?;
+        let Some(cache) = self.range_cache.as_ref() else {
+            let (bytes, etag) = self.fetch_uncached(&parsed, range).await?;
+            return Ok(FetchedRange { bytes, etag, cache: CacheStatus::Uncached });
+        };
+        let key = |etag| RangeCacheKey {
+            location: location.clone(),
+            etag,
+            range: range.clone(),
+        };
+        if let Some(etag) = self.known_etag(&parsed) {
+            if let Some(bytes) = cache.get(&key(etag.clone())).await {
+                cache.metrics().record_hit(bytes.len() as u64);
+                return Ok(FetchedRange {
+                    bytes,
+                    etag: Some(etag),
+                    cache: CacheStatus::Hit,
+                });
+            }
         }
+
+        let (bytes, etag) = self.fetch_uncached(&parsed, range).await?;
+        self.etags
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(parsed.as_str().to_string(), etag.clone());
+        // files without an etag are not cached
+        let Some(etag) = etag else {
+            return Ok(FetchedRange { bytes, etag: None, cache: CacheStatus::Uncached });
+        };
+        cache.metrics().record_miss(bytes.len() as u64);
+        cache.insert(key(etag.clone()), bytes.clone()).await;
+        Ok(FetchedRange { bytes, etag: Some(etag), cache: CacheStatus::Miss })
+    }
+
+    async fn fetch_etag(
+        &self,
+        location: &VirtualChunkLocation,
+    ) -> Result<Option<String>, VirtualReferenceError> {
+        let VirtualChunkLocation::Absolute(url) = location;
+        let parsed =
+            url::Url::parse(url).map_err(VirtualReferenceError::CannotParseUrl)?;
+        Ok(self.head(&parsed).await?.etag)
+    }
+
+    async fn fetch_file_info(
+        &self,
+        location: &VirtualChunkLocation,
+    ) -> Result<VirtualFileInfo, VirtualReferenceError> {
+        let VirtualChunkLocation::Absolute(url) = location;
+        let parsed =
+            url::Url::parse(url).map_err(VirtualReferenceError::CannotParseUrl)?;
+        self.head(&parsed).await
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
 mod tests {
-    use proptest::prop_assert_eq;
+    use proptest::{prop_assert, prop_assert_eq};
     use test_strategy::proptest;
 
     use super::*;
@@ -200,7 +380,8 @@ mod tests {
                 &ByteRange(Bound::Included(0), Bound::Excluded(length)),
                 offset,
                 length,
-            ),
+            )
+            .unwrap(),
             ByteRange(Bound::Included(offset), Bound::Excluded(max_end))
         );
         prop_assert_eq!(
@@ -208,7 +389,8 @@ mod tests {
                 &ByteRange(Bound::Unbounded, Bound::Excluded(length)),
                 offset,
                 length
-            ),
+            )
+            .unwrap(),
             ByteRange(Bound::Included(offset), Bound::Excluded(max_end))
         );
         prop_assert_eq!(
@@ -216,11 +398,12 @@ mod tests {
                 &ByteRange(Bound::Included(request_offset), Bound::Excluded(max_end)),
                 offset,
                 length
-            ),
+            )
+            .unwrap(),
             ByteRange(Bound::Included(request_offset + offset), Bound::Excluded(max_end))
         );
         prop_assert_eq!(
-            construct_valid_byte_range(&ByteRange::ALL, offset, length),
+            construct_valid_byte_range(&ByteRange::ALL, offset, length).unwrap(),
             ByteRange(Bound::Included(offset), Bound::Excluded(max_end))
         );
         prop_assert_eq!(
@@ -228,11 +411,21 @@ mod tests {
                 &ByteRange(Bound::Excluded(request_offset), Bound::Unbounded),
                 offset,
                 length
-            ),
+            )
+            .unwrap(),
             ByteRange(
                 Bound::Included(offset + request_offset + 1),
                 Bound::Excluded(max_end)
             )
         );
+        prop_assert!(
+            construct_valid_byte_range(&ByteRange::ALL, u64::MAX, length).is_err()
+        );
+        prop_assert!(construct_valid_byte_range(
+            &ByteRange::from_offset(length + 1),
+            offset,
+            length
+        )
+        .is_err());
     }
 }
@@ -7,7 +7,7 @@ use proptest::{collection::vec, option, strategy::Strategy};
 
 use crate::format::snapshot::ZarrArrayMetadata;
 use crate::format::Path;
-use crate::metadata::{ArrayShape, DimensionNames};
+use crate::metadata::{ArrayShape, DataType, DimensionNames};
 use crate::repository::{
     ChunkKeyEncoding, ChunkShape, Codec, FillValue, StorageTransformer,
 };
@@ -62,9 +62,9 @@ pub struct ShapeDim {
 }
 
 pub fn shapes_and_dims(max_ndim: Option<usize>) -> impl Strategy<Value = ShapeDim> {
-    // FIXME: ndim = 0
+    // ndim = 0 generates scalar arrays
     let max_ndim = max_ndim.unwrap_or(4usize);
-    vec(1u64..26u64, 1..max_ndim)
+    vec(1u64..26u64, 0..max_ndim)
         .prop_flat_map(|shape| {
             let ndim = shape.len();
             let chunk_shape: Vec<BoxedStrategy<NonZeroU64>> = shape
@@ -89,6 +89,42 @@ pub fn shapes_and_dims(max_ndim: Option<usize>) -> impl Strategy<Value = ShapeDi
         })
 }
 
+/// Shapes with up to `max_ndim` dimensions and extents anywhere in the `u64` range.
+///
+/// Small chunk sizes are favored so grids with more than `2^32` chunks along an axis
+/// are common.
+pub fn large_shapes_and_dims(max_ndim: usize) -> impl Strategy<Value = ShapeDim> {
+    let chunk_sizes = prop_oneof![1u64..16, 1u64..=u64::MAX];
+    vec((any::<u64>(), chunk_sizes), 0..max_ndim).prop_map(|dims| {
+        let (shape, chunk_shape): (Vec<_>, Vec<_>) = dims.into_iter().unzip();
+        ShapeDim {
+            shape,
+            chunk_shape: ChunkShape(
+                chunk_shape.into_iter().filter_map(NonZeroU64::new).collect(),
+            ),
+            dimension_names: None,
+        }
+    })
+}
+
+prop_compose! {
+    pub fn large_zarr_array_metadata()(
+        shape_and_dim in large_shapes_and_dims(33),
+    ) -> ZarrArrayMetadata {
+        ZarrArrayMetadata {
+            shape: shape_and_dim.shape,
+            data_type: DataType::Int8,
+            chunk_shape: shape_and_dim.chunk_shape,
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int8(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: shape_and_dim.dimension_names,
+            rectilinear_grid: None,
+        }
+    }
+}
+
 prop_compose! {
     pub fn zarr_array_metadata()(
         chunk_key_encoding: ChunkKeyEncoding,
@@ -106,6 +142,7 @@ prop_compose! {
             codecs,
             storage_transformers,
             dimension_names: shape_and_dim.dimension_names,
+            rectilinear_grid: None,
         }
     }
 }
@@ -0,0 +1,317 @@
+//! Reading the nodes of very large hierarchies a part at a time.
+//!
+//! A snapshot stores all its nodes in a single table, which must be fetched in full
+//! before any node can be read. For hierarchies with hundreds of thousands of nodes,
+//! [`StructureShards`] splits the table of a committed snapshot into one artifact per
+//! subtree, rooted at a configured depth, and an index with the nodes above them.
+//! [`StructureReader`] uses them to page or stream the nodes under a path, fetching only
+//! the subtrees it reads. Snapshots without the index are read from the full table.
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use bytes::Bytes;
+use futures::{future::BoxFuture, stream, Stream, TryStreamExt};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::ErrorKind,
+    format::{
+        snapshot::{NodeSnapshot, Snapshot},
+        Path, SnapshotId,
+    },
+    postprocess::{
+        fetch_artifact, PostProcessError, PostProcessResult, SnapshotProcessor,
+    },
+    repository::RepositoryError,
+    Repository, Storage,
+};
+
+/// Writes the nodes of every subtree rooted at `depth` to their own artifact
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StructureShards {
+    pub depth: usize,
+}
+
+/// The nodes above the shards and the artifact of each shard, keyed by its root
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StructureIndex {
+    pub depth: usize,
+    pub top: Vec<NodeSnapshot>,
+    pub shards: BTreeMap<Path, String>,
+}
+
+/// Up to a page of nodes, in path order
+#[derive(Debug, Clone, PartialEq)]
+pub struct NodePage {
+    pub nodes: Vec<NodeSnapshot>,
+    /// Pass it as `start_after` to read the next page, `None` after the last one
+    pub next: Option<Path>,
+}
+
+fn depth(path: &Path) -> usize {
+    path.ancestors().count() - 1
+}
+
+impl StructureShards {
+    pub const NAME: &'static str = "structure_index.msgpack";
+
+    pub fn new(depth: usize) -> Self {
+        Self { depth }
+    }
+
+    // the shards are written before the index, readers never see an index whose
+    // shards are missing
+    async fn compute(&self, repository: &Repository) -> PostProcessResult<Bytes> {
+        let storage = repository.storage();
+        let snapshot_id = repository.snapshot_id();
+        let snapshot = storage.fetch_snapshot(snapshot_id).await?;
+        let mut index = StructureIndex {
+            depth: self.depth,
+            top: Vec::new(),
+            shards: BTreeMap::new(),
+        };
+        for node in snapshot.iter().filter(|node| depth(&node.path) <= self.depth) {
+            if depth(&node.path) < self.depth {
+                index.top.push(node.clone());
+                continue;
+            }
+            let nodes: Vec<_> = snapshot.iter_subtree(&node.path).collect();
+            let bytes = rmp_serde::to_vec(&nodes).map_err(RepositoryError::from)?;
+            let name = format!("structure-{}.msgpack", index.shards.len());
+            storage
+                .write_snapshot_artifact(snapshot_id, &name, Bytes::from(bytes))
+                .await?;
+            index.shards.insert(node.path.clone(), name);
+        }
+        Ok(Bytes::from(rmp_serde::to_vec(&index).map_err(RepositoryError::from)?))
+    }
+}
+
+impl SnapshotProcessor for StructureShards {
+    fn name(&self) -> &str {
+        Self::NAME
+    }
+
+    fn process<'a>(
+        &'a self,
+        repository: &'a Repository,
+    ) -> BoxFuture<'a, PostProcessResult<Bytes>> {
+        Box::pin(self.compute(repository))
+    }
+}
+
+#[derive(Debug)]
+enum Source {
+    Sharded(StructureIndex),
+    Full(Arc<Snapshot>),
+}
+
+enum Entry<'a> {
+    Node(&'a NodeSnapshot),
+    Shard(&'a Path, &'a str),
+}
+
+/// Reads the nodes of a snapshot by path prefix, loading shards as they are needed
+#[derive(Debug)]
+pub struct StructureReader {
+    storage: Arc<dyn Storage>,
+    snapshot: SnapshotId,
+    source: Source,
+    shards: Mutex<HashMap<String, Arc<Vec<NodeSnapshot>>>>,
+}
+
+impl StructureReader {
+    /// Fetches the index of `snapshot`, or its full table if it has no index
+    pub async fn open(
+        storage: Arc<dyn Storage>,
+        snapshot: &SnapshotId,
+    ) -> PostProcessResult<Self> {
+        let source =
+            match fetch_artifact(storage.as_ref(), snapshot, StructureShards::NAME).await
+            {
+                Ok(bytes) => Source::Sharded(
+                    rmp_serde::from_slice(&bytes).map_err(RepositoryError::from)?,
+                ),
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    Source::Full(storage.fetch_snapshot(snapshot).await?)
+                }
+                Err(err) => return Err(err),
+            };
+        Ok(Self {
+            storage,
+            snapshot: snapshot.clone(),
+            source,
+            shards: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn is_sharded(&self) -> bool {
+        matches!(self.source, Source::Sharded(_))
+    }
+
+    fn lock_shards(&self) -> MutexGuard<'_, HashMap<String, Arc<Vec<NodeSnapshot>>>> {
+        // the cache is never left half updated
+        self.shards.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn shard(&self, name: &str) -> PostProcessResult<Arc<Vec<NodeSnapshot>>> {
+        if let Some(nodes) = self.lock_shards().get(name) {
+            return Ok(Arc::clone(nodes));
+        }
+        let bytes = fetch_artifact(self.storage.as_ref(), &self.snapshot, name).await?;
+        let nodes: Arc<Vec<NodeSnapshot>> =
+            Arc::new(rmp_serde::from_slice(&bytes).map_err(RepositoryError::from)?);
+        self.lock_shards().insert(name.to_string(), Arc::clone(&nodes));
+        Ok(nodes)
+    }
+
+    /// The node at `prefix` and its descendants that come after `start_after`, at most
+    /// `limit` of them and at least one
+    pub async fn page(
+        &self,
+        prefix: &Path,
+        start_after: Option<&Path>,
+        limit: usize,
+    ) -> PostProcessResult<NodePage> {
+        let limit = limit.max(1);
+        // one more node tells if there is a next page
+        let fetch = limit.saturating_add(1);
+        let after = |path: &Path| start_after.is_none_or(|start| path > start);
+        let mut nodes = Vec::new();
+        match &self.source {
+            Source::Full(snapshot) => nodes.extend(
+                snapshot
+                    .iter_subtree(prefix)
+                    .filter(|node| after(&node.path))
+                    .take(fetch)
+                    .cloned(),
+            ),
+            Source::Sharded(index) => {
+                // a prefix below the shard depth is inside a single shard
+                let entries: BTreeMap<&Path, Entry<'_>> = index
+                    .top
+                    .iter()
+                    .filter(|node| node.path.starts_with(prefix))
+                    .map(|node| (&node.path, Entry::Node(node)))
+                    .chain(
+                        index
+                            .shards
+                            .iter()
+                            .filter(|(root, _)| {
+                                root.starts_with(prefix) || prefix.starts_with(root)
+                            })
+                            .map(|(root, name)| (root, Entry::Shard(root, name))),
+                    )
+                    .collect();
+                for entry in entries.into_values() {
+                    if nodes.len() > limit {
+                        break;
+                    }
+                    match entry {
+                        Entry::Node(node) if after(&node.path) => {
+                            nodes.push(node.clone())
+                        }
+                        Entry::Node(_) => {}
+                        // the whole subtree sorts before `start_after`
+                        Entry::Shard(root, _)
+                            if start_after.is_some_and(|start| {
+                                start > root && !start.starts_with(root)
+                            }) => {}
+                        Entry::Shard(_, name) => {
+                            let shard = self.shard(name).await?;
+                            nodes.extend(
+                                shard
+                                    .iter()
+                                    .filter(|node| {
+                                        node.path.starts_with(prefix) && after(&node.path)
+                                    })
+                                    .take(fetch - nodes.len())
+                                    .cloned(),
+                            );
+                        }
+                    }
+                }
+            }
+        }
+        let next = if nodes.len() > limit {
+            nodes.truncate(limit);
+            nodes.last().map(|node| node.path.clone())
+        } else {
+            None
+        };
+        Ok(NodePage { nodes, next })
+    }
+
+    /// All the nodes under `prefix`, in path order, fetched a page at a time
+    pub fn stream<'a>(
+        &'a self,
+        prefix: &'a Path,
+        page_size: usize,
+    ) -> impl Stream<Item = PostProcessResult<NodeSnapshot>> + 'a {
+        stream::try_unfold(
+            Some(None),
+            move |start_after: Option<Option<Path>>| async move {
+                let Some(start_after) = start_after else {
+                    return Ok::<_, PostProcessError>(None);
+                };
+                let page = self.page(prefix, start_after.as_ref(), page_size).await?;
+                let nodes = stream::iter(page.nodes.into_iter().map(Ok));
+                Ok(Some((nodes, page.next.map(Some))))
+            },
+        )
+        .try_flatten()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{postprocess::process_snapshot, ObjectStorage};
+
+    #[tokio::test]
+    async fn test_structure_pages() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        for path in ["/", "/a", "/a/x", "/a/x/deep", "/a/y", "/b", "/b/z", "/c"] {
+            repo.add_group(path.try_into().unwrap()).await?;
+        }
+        let snapshot = repo.commit("main", "hierarchy", None).await?;
+        let full = storage.fetch_snapshot(&snapshot).await?;
+        let paths = |nodes: &[NodeSnapshot]| -> Vec<String> {
+            nodes.iter().map(|node| node.path.to_string()).collect()
+        };
+
+        let unsharded = StructureReader::open(Arc::clone(&storage), &snapshot).await?;
+        assert!(!unsharded.is_sharded());
+        let processors: Vec<Arc<dyn SnapshotProcessor>> =
+            vec![Arc::new(StructureShards::new(2))];
+        process_snapshot(Arc::clone(&storage), &snapshot, &processors).await?;
+        let sharded = StructureReader::open(Arc::clone(&storage), &snapshot).await?;
+        assert!(sharded.is_sharded());
+
+        for reader in [&unsharded, &sharded] {
+            let all: Vec<_> = reader.stream(&Path::root(), 3).try_collect().await?;
+            assert_eq!(all, full.iter().cloned().collect::<Vec<_>>());
+
+            let a: Path = "/a".try_into().unwrap();
+            let first = reader.page(&a, None, 2).await?;
+            assert_eq!(paths(&first.nodes), vec!["/a", "/a/x"]);
+            let second = reader.page(&a, first.next.as_ref(), 2).await?;
+            assert_eq!(paths(&second.nodes), vec!["/a/x/deep", "/a/y"]);
+            assert_eq!(second.next, None);
+
+            let x: Path = "/a/x".try_into().unwrap();
+            let inside = reader.page(&x, None, 10).await?;
+            assert_eq!(paths(&inside.nodes), vec!["/a/x", "/a/x/deep"]);
+        }
+        Ok(())
+    }
+}
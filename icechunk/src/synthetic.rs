@@ -0,0 +1,269 @@
+//! Generation of synthetic repositories, for tests and benchmarks.
+//!
+//! [`generate`] writes a repository with a configurable number of groups, arrays and
+//! chunks, a mix of inline, materialized and virtual chunk payloads, and a history of
+//! commits to the main branch. It can also leave behind chunks that no commit
+//! references, like the ones from sessions that were never committed.
+//!
+//! Generation is deterministic for a given [`SyntheticRepoConfig::seed`], except for
+//! object ids, which are always random.
+//!
+//! Available in the crate tests, and to other crates with the `test-support` feature.
+use std::{num::NonZeroU64, sync::Arc};
+
+use bytes::Bytes;
+use rand::{rngs::StdRng, seq::index, Rng, SeedableRng};
+
+use crate::{
+    format::{
+        manifest::{ChunkPayload, VirtualChunkLocation, VirtualChunkRef},
+        ChunkId, ChunkIndices, Path, SnapshotId,
+    },
+    metadata::{ChunkKeyEncoding, ChunkShape, FillValue},
+    refs::Ref,
+    repository::{RepositoryResult, ZarrArrayMetadata},
+    Repository, Storage,
+};
+
+/// Relative weights of the kinds of chunk payloads generated
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PayloadMix {
+    pub inline: u32,
+    /// Chunks written as objects in storage
+    pub materialized: u32,
+    /// References to chunks in other files, they are never fetched
+    pub virtual_refs: u32,
+}
+
+impl Default for PayloadMix {
+    fn default() -> Self {
+        Self { inline: 1, materialized: 1, virtual_refs: 1 }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyntheticRepoConfig {
+    /// Groups under the root group, arrays are spread between them
+    pub groups: usize,
+    pub arrays: usize,
+    /// Chunks of each array, they are all written by the first commit
+    pub chunks_per_array: u64,
+    pub payloads: PayloadMix,
+    /// Size of the generated chunks, in bytes
+    pub chunk_size_bytes: usize,
+    /// Commits to the main branch, not counting the initial empty one
+    pub commits: usize,
+    /// Chunks of each array rewritten by every commit after the first one
+    pub updates_per_commit: u64,
+    /// Chunks written to storage but not referenced by any commit
+    pub dangling_chunks: usize,
+    pub seed: u64,
+}
+
+impl Default for SyntheticRepoConfig {
+    fn default() -> Self {
+        Self {
+            groups: 2,
+            arrays: 4,
+            chunks_per_array: 16,
+            payloads: PayloadMix::default(),
+            chunk_size_bytes: 64,
+            commits: 3,
+            updates_per_commit: 4,
+            dangling_chunks: 0,
+            seed: 0,
+        }
+    }
+}
+
+/// What [`generate`] wrote
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SyntheticRepo {
+    /// The snapshots of the main branch, oldest first, starting with the initial one
+    pub snapshots: Vec<SnapshotId>,
+    pub groups: Vec<Path>,
+    pub arrays: Vec<Path>,
+    /// Materialized chunks referenced by some commit
+    pub committed_chunks: Vec<ChunkId>,
+    pub dangling_chunks: Vec<ChunkId>,
+}
+
+impl SyntheticRepo {
+    /// The tip of the main branch
+    // generate always records the initial snapshot
+    #[allow(clippy::expect_used)]
+    pub fn tip(&self) -> &SnapshotId {
+        self.snapshots.last().expect("synthetic repos have at least one snapshot")
+    }
+}
+
+/// Write a synthetic repository to `storage`, which must be empty
+pub async fn generate(
+    storage: Arc<dyn Storage>,
+    config: &SyntheticRepoConfig,
+) -> RepositoryResult<SyntheticRepo> {
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut repo = Repository::init(Arc::clone(&storage), false)
+        .await?
+        .with_inline_threshold_bytes(0)
+        .build();
+    let mut res = SyntheticRepo {
+        snapshots: vec![repo.snapshot_id().clone()],
+        groups: Vec::new(),
+        arrays: Vec::new(),
+        committed_chunks: Vec::new(),
+        dangling_chunks: Vec::new(),
+    };
+
+    repo.add_group(Path::root()).await?;
+    for group in 0..config.groups {
+        let path = child(&Path::root(), &format!("group{group}"));
+        repo.add_group(path.clone()).await?;
+        res.groups.push(path);
+    }
+    for array in 0..config.arrays {
+        let parent = match res.groups.len() {
+            0 => Path::root(),
+            n => res.groups[array % n].clone(),
+        };
+        let path = child(&parent, &format!("array{array}"));
+        repo.add_array(path.clone(), array_metadata(config)).await?;
+        res.arrays.push(path);
+    }
+
+    let arrays = res.arrays.clone();
+    for commit in 0..config.commits {
+        for array in arrays.iter() {
+            let coords: Vec<u64> = if commit == 0 {
+                (0..config.chunks_per_array).collect()
+            } else {
+                // distinct coordinates, a chunk overwritten before the commit would
+                // never be committed
+                let chunks = config.chunks_per_array as usize;
+                let updates = (config.updates_per_commit as usize).min(chunks);
+                index::sample(&mut rng, chunks, updates)
+                    .into_iter()
+                    .map(|coord| coord as u64)
+                    .collect()
+            };
+            for coord in coords {
+                let payload = random_payload(&repo, &mut rng, config).await?;
+                if let ChunkPayload::Ref(chunk_ref) = &payload {
+                    res.committed_chunks.push(chunk_ref.id.clone());
+                }
+                repo.set_chunk_ref(
+                    array.clone(),
+                    ChunkIndices(vec![coord]),
+                    Some(payload),
+                )
+                .await?;
+            }
+        }
+        let message = format!("synthetic commit {commit}");
+        res.snapshots.push(repo.commit(Ref::DEFAULT_BRANCH, &message, None).await?);
+    }
+
+    for _ in 0..config.dangling_chunks {
+        let id = ChunkId::random();
+        storage.write_chunk(id.clone(), random_bytes(&mut rng, config)).await?;
+        res.dangling_chunks.push(id);
+    }
+    Ok(res)
+}
+
+// generated names are always valid
+#[allow(clippy::expect_used)]
+fn child(parent: &Path, name: &str) -> Path {
+    format!("{}/{name}", parent.to_string().trim_end_matches('/'))
+        .try_into()
+        .expect("invalid synthetic path")
+}
+
+/// The metadata of a one dimensional array of `size` elements, without codecs
+pub fn vector_metadata(
+    size: u64,
+    chunk_size: u64,
+    fill_value: FillValue,
+) -> ZarrArrayMetadata {
+    ZarrArrayMetadata {
+        shape: vec![size],
+        data_type: fill_value.get_data_type(),
+        chunk_shape: ChunkShape(vec![
+            NonZeroU64::new(chunk_size).unwrap_or(NonZeroU64::MIN)
+        ]),
+        chunk_key_encoding: ChunkKeyEncoding::Slash,
+        fill_value,
+        codecs: vec![],
+        storage_transformers: None,
+        dimension_names: None,
+        rectilinear_grid: None,
+    }
+}
+
+fn array_metadata(config: &SyntheticRepoConfig) -> ZarrArrayMetadata {
+    let chunk_size = config.chunk_size_bytes as u64;
+    vector_metadata(config.chunks_per_array * chunk_size, chunk_size, FillValue::UInt8(0))
+}
+
+fn random_bytes(rng: &mut StdRng, config: &SyntheticRepoConfig) -> Bytes {
+    let mut data = vec![0; config.chunk_size_bytes];
+    rng.fill(data.as_mut_slice());
+    Bytes::from(data)
+}
+
+async fn random_payload(
+    repo: &Repository,
+    rng: &mut StdRng,
+    config: &SyntheticRepoConfig,
+) -> RepositoryResult<ChunkPayload> {
+    let PayloadMix { inline, materialized, virtual_refs } = config.payloads;
+    let pick = rng.gen_range(0..(inline + materialized + virtual_refs).max(1));
+    let data = random_bytes(rng, config);
+    if pick < materialized && !data.is_empty() {
+        repo.get_chunk_writer()(data).await
+    } else if pick < materialized + virtual_refs {
+        let file = rng.gen_range(0..16);
+        Ok(ChunkPayload::Virtual(VirtualChunkRef {
+            location: VirtualChunkLocation::Absolute(format!(
+                "s3://synthetic/file{file}.nc"
+            )),
+            offset: rng.gen_range(0..1 << 20),
+            length: data.len() as u64,
+        }))
+    } else {
+        Ok(ChunkPayload::Inline(data))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::error::Error;
+
+    use futures::TryStreamExt;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::ObjectStorage;
+
+    #[tokio::test]
+    async fn test_generate() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("synthetic".into())));
+        let config = SyntheticRepoConfig { dangling_chunks: 2, ..Default::default() };
+        let synthetic = generate(Arc::clone(&storage), &config).await?;
+        assert_eq!(synthetic.snapshots.len(), config.commits + 1);
+        assert_eq!(synthetic.groups.len(), config.groups);
+        assert_eq!(synthetic.dangling_chunks.len(), config.dangling_chunks);
+        assert!(!synthetic.committed_chunks.is_empty());
+
+        let repo =
+            Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+        assert_eq!(repo.snapshot_id(), synthetic.tip());
+        let chunks: Vec<_> = repo.all_chunks().await?.try_collect().await?;
+        assert_eq!(chunks.len() as u64, config.arrays as u64 * config.chunks_per_array);
+        let parents: Vec<_> = repo.ancestry().await?.try_collect().await?;
+        assert_eq!(parents.len(), synthetic.snapshots.len());
+        Ok(())
+    }
+}
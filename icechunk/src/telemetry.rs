@@ -0,0 +1,161 @@
+//! Counters of commit contention.
+//!
+//! Every [`crate::Repository`] records its commit attempts, conflicts and rebases in a
+//! [`CommitTelemetry`]. Writers of the same repository can share one, passing it to
+//! [`crate::RepositoryBuilder::with_commit_telemetry`], to see the contention of the
+//! whole job. High conflict rates usually mean writers need disjoint write regions.
+//!
+//! Reads are counted too, with the integrity checks done on them, and reported by
+//! [`crate::Repository::io_summary`].
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use serde::{Deserialize, Serialize};
+
+use crate::verification::VerificationPolicy;
+
+/// The values of the counters at some point, see [`CommitTelemetry::stats`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CommitStats {
+    /// Calls to commit, successful or not
+    pub attempts: u64,
+    /// Commits that updated the branch
+    pub commits: u64,
+    /// Commits rejected because the branch moved
+    pub conflicts: u64,
+    /// Commits moved onto a newer branch tip, because the writes since then were to
+    /// other write regions
+    pub rebases: u64,
+    /// Commits that declared write regions but couldn't be moved onto the newer branch
+    /// tip, because the regions overlap or other changes were made
+    pub failed_rebases: u64,
+}
+
+impl CommitStats {
+    /// The fraction of attempts that ended in a conflict, 0 if there were no attempts
+    pub fn conflict_rate(&self) -> f64 {
+        if self.attempts == 0 {
+            0.0
+        } else {
+            self.conflicts as f64 / self.attempts as f64
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct CommitTelemetry {
+    attempts: AtomicU64,
+    commits: AtomicU64,
+    conflicts: AtomicU64,
+    rebases: AtomicU64,
+    failed_rebases: AtomicU64,
+}
+
+impl CommitTelemetry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> CommitStats {
+        CommitStats {
+            attempts: self.attempts.load(Ordering::Relaxed),
+            commits: self.commits.load(Ordering::Relaxed),
+            conflicts: self.conflicts.load(Ordering::Relaxed),
+            rebases: self.rebases.load(Ordering::Relaxed),
+            failed_rebases: self.failed_rebases.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Set all counters back to zero
+    pub fn reset(&self) {
+        for counter in [
+            &self.attempts,
+            &self.commits,
+            &self.conflicts,
+            &self.rebases,
+            &self.failed_rebases,
+        ] {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_attempt(&self) {
+        self.attempts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_commit(&self) {
+        self.commits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_conflict(&self) {
+        self.conflicts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_rebase(&self, succeeded: bool) {
+        if succeeded {
+            self.rebases.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.failed_rebases.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// The reads of a repository and how many of them were checked, with the policy that
+/// decided it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IoSummary {
+    pub policy: VerificationPolicy,
+    /// Reads of materialized chunks
+    pub chunk_reads: u64,
+    /// Chunk reads checked against their checksum
+    pub chunks_verified: u64,
+    pub virtual_chunk_reads: u64,
+    /// Virtual chunk reads whose file etag was checked
+    pub virtual_etags_verified: u64,
+    /// Manifests read looking for chunk references
+    pub manifest_reads: u64,
+    /// Manifests checked against their hash
+    pub manifests_verified: u64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct IoTelemetry {
+    chunk_reads: AtomicU64,
+    chunks_verified: AtomicU64,
+    virtual_chunk_reads: AtomicU64,
+    virtual_etags_verified: AtomicU64,
+    manifest_reads: AtomicU64,
+    manifests_verified: AtomicU64,
+}
+
+impl IoTelemetry {
+    pub(crate) fn summary(&self, policy: VerificationPolicy) -> IoSummary {
+        IoSummary {
+            policy,
+            chunk_reads: self.chunk_reads.load(Ordering::Relaxed),
+            chunks_verified: self.chunks_verified.load(Ordering::Relaxed),
+            virtual_chunk_reads: self.virtual_chunk_reads.load(Ordering::Relaxed),
+            virtual_etags_verified: self.virtual_etags_verified.load(Ordering::Relaxed),
+            manifest_reads: self.manifest_reads.load(Ordering::Relaxed),
+            manifests_verified: self.manifests_verified.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn record_chunk_read(&self, verified: bool) {
+        Self::record(&self.chunk_reads, &self.chunks_verified, verified);
+    }
+
+    pub(crate) fn record_virtual_chunk_read(&self, verified: bool) {
+        Self::record(&self.virtual_chunk_reads, &self.virtual_etags_verified, verified);
+    }
+
+    pub(crate) fn record_manifest_read(&self, verified: bool) {
+        Self::record(&self.manifest_reads, &self.manifests_verified, verified);
+    }
+
+    fn record(reads: &AtomicU64, verified_reads: &AtomicU64, verified: bool) {
+        reads.fetch_add(1, Ordering::Relaxed);
+        if verified {
+            verified_reads.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+//! Commits to several repositories that become visible together, experimental.
+//!
+//! Pipelines that write related outputs to several repositories commit them with a
+//! [`Transaction`], in two phases. [`Transaction::prepare`] writes the snapshots of all
+//! the commits without moving any branch, and fails, discarding the others, if any of
+//! them can't be written or its branch moved. [`PreparedTransaction::finalize`] then
+//! moves the branches one after the other, moving back the ones already moved if one
+//! fails.
+//!
+//! Branches are not locked: readers can see some of the commits while the transaction
+//! finalizes, and a commit made on top of one of them in that time can't be rolled back.
+//! Those are reported in [`TransactionError::Finalize`].
+use thiserror::Error;
+
+use crate::{
+    change_set::ChangeSet,
+    error::ErrorKind,
+    format::{snapshot::SnapshotProperties, SnapshotId},
+    repository::RepositoryError,
+    Repository,
+};
+
+/// A commit whose snapshot is written, but not its branch
+#[derive(Debug, Clone)]
+pub struct PreparedCommit {
+    pub(crate) branch: String,
+    pub(crate) parent: SnapshotId,
+    pub(crate) snapshot: SnapshotId,
+    // the changes to restore on rollback
+    pub(crate) change_set: ChangeSet,
+}
+
+impl PreparedCommit {
+    pub fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    pub fn snapshot(&self) -> &SnapshotId {
+        &self.snapshot
+    }
+}
+
+/// Participants are numbered in the order they were added to the [`Transaction`]
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum TransactionError {
+    #[error("cannot prepare the commit of participant {participant}: {source}")]
+    Prepare {
+        participant: usize,
+        #[source]
+        source: RepositoryError,
+    },
+    #[error("cannot finalize the commit of participant {participant}: {source}")]
+    Finalize {
+        participant: usize,
+        #[source]
+        source: RepositoryError,
+        /// The participants whose commits stayed visible, with the error that kept
+        /// them from being rolled back
+        not_rolled_back: Vec<(usize, RepositoryError)>,
+    },
+}
+
+impl TransactionError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            TransactionError::Prepare { source, .. }
+            | TransactionError::Finalize { source, .. } => source.kind(),
+        }
+    }
+}
+
+pub type TransactionResult<A> = Result<A, TransactionError>;
+
+/// The commits of a transaction, each one to an existing branch of its repository
+#[derive(Debug, Default)]
+pub struct Transaction<'a> {
+    participants: Vec<(&'a mut Repository, String)>,
+}
+
+/// A [`Transaction`] whose snapshots are all written
+#[derive(Debug)]
+pub struct PreparedTransaction<'a> {
+    commits: Vec<(&'a mut Repository, PreparedCommit)>,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Commit the changes of `repository` to `branch`, the tip of the branch must be the
+    /// snapshot of the repository
+    pub fn with_commit(
+        mut self,
+        repository: &'a mut Repository,
+        branch: impl Into<String>,
+    ) -> Self {
+        self.participants.push((repository, branch.into()));
+        self
+    }
+
+    /// Write the snapshots of all the commits.
+    ///
+    /// On failure the repositories keep their uncommitted changes, and the snapshots
+    /// already written are deleted, or left for garbage collection if that fails.
+    pub async fn prepare(
+        self,
+        message: &str,
+        properties: Option<SnapshotProperties>,
+    ) -> TransactionResult<PreparedTransaction<'a>> {
+        let mut commits = Vec::with_capacity(self.participants.len());
+        for (participant, (repository, branch)) in
+            self.participants.into_iter().enumerate()
+        {
+            match repository.prepare_commit(&branch, message, properties.clone()).await {
+                Ok(prepared) => commits.push((repository, prepared)),
+                Err(source) => {
+                    for (repository, prepared) in commits.into_iter().rev() {
+                        repository.discard_commit(prepared).await;
+                    }
+                    return Err(TransactionError::Prepare { participant, source });
+                }
+            }
+        }
+        Ok(PreparedTransaction { commits })
+    }
+
+    /// [`Transaction::prepare`] and [`PreparedTransaction::finalize`]
+    pub async fn commit(
+        self,
+        message: &str,
+        properties: Option<SnapshotProperties>,
+    ) -> TransactionResult<Vec<SnapshotId>> {
+        self.prepare(message, properties).await?.finalize().await
+    }
+}
+
+impl PreparedTransaction<'_> {
+    pub fn commits(&self) -> impl Iterator<Item = &PreparedCommit> {
+        self.commits.iter().map(|(_, prepared)| prepared)
+    }
+
+    /// Move all the branches to their new snapshots, returning the snapshots in the
+    /// order of the participants
+    pub async fn finalize(mut self) -> TransactionResult<Vec<SnapshotId>> {
+        let mut finalized = 0;
+        let mut failure = None;
+        for (participant, (repository, prepared)) in self.commits.iter_mut().enumerate() {
+            if let Err(source) = repository.finalize_commit(prepared).await {
+                failure = Some((participant, source));
+                break;
+            }
+            finalized += 1;
+        }
+        let Some((participant, source)) = failure else {
+            return Ok(self.commits.into_iter().map(|(_, p)| p.snapshot).collect());
+        };
+
+        let mut not_rolled_back = Vec::new();
+        for (index, (repository, prepared)) in self.commits.into_iter().enumerate().rev()
+        {
+            if index >= finalized {
+                repository.discard_commit(prepared).await;
+            } else if let Err(err) = repository.rollback_commit(prepared).await {
+                not_rolled_back.push((index, err));
+            }
+        }
+        not_rolled_back.reverse();
+        Err(TransactionError::Finalize { participant, source, not_rolled_back })
+    }
+
+    /// Delete the snapshots, the repositories get back their uncommitted changes.
+    ///
+    /// Snapshots that can't be deleted are left for garbage collection.
+    pub async fn rollback(self) {
+        for (repository, prepared) in self.commits {
+            repository.discard_commit(prepared).await;
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::Path,
+        refs::{fetch_branch_tip, Ref},
+        ObjectStorage, Storage,
+    };
+
+    #[tokio::test]
+    async fn test_transaction() -> Result<(), Box<dyn Error>> {
+        let first: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("first".into())));
+        let second: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("second".into())));
+        let mut repo1 = Repository::init(Arc::clone(&first), false).await?.build();
+        let mut repo2 = Repository::init(Arc::clone(&second), false).await?.build();
+        let initial1 = repo1.snapshot_id().clone();
+        let initial2 = repo2.snapshot_id().clone();
+        repo1.add_group(Path::root()).await?;
+        repo2.add_group(Path::root()).await?;
+
+        // the second repository can't prepare, the first one is undone
+        let mut other = Repository::update(Arc::clone(&second), initial2.clone()).build();
+        other.add_group(Path::root()).await?;
+        let moved = other.commit(Ref::DEFAULT_BRANCH, "other", None).await?;
+        let failed = Transaction::new()
+            .with_commit(&mut repo1, Ref::DEFAULT_BRANCH)
+            .with_commit(&mut repo2, Ref::DEFAULT_BRANCH)
+            .prepare("both", None)
+            .await;
+        assert!(matches!(
+            failed,
+            Err(TransactionError::Prepare {
+                participant: 1,
+                source: RepositoryError::Conflict { .. }
+            })
+        ));
+        assert_eq!(repo1.snapshot_id(), &initial1);
+        assert!(repo1.has_uncommitted_changes());
+        assert_eq!(
+            fetch_branch_tip(first.as_ref(), Ref::DEFAULT_BRANCH).await?.snapshot,
+            initial1
+        );
+
+        let mut repo2 = Repository::update(Arc::clone(&second), moved).build();
+        repo2.add_group("/output".try_into().unwrap()).await?;
+        let prepared = Transaction::new()
+            .with_commit(&mut repo1, Ref::DEFAULT_BRANCH)
+            .with_commit(&mut repo2, Ref::DEFAULT_BRANCH)
+            .prepare("both", None)
+            .await?;
+        let snapshots: Vec<_> =
+            prepared.commits().map(|c| c.snapshot().clone()).collect();
+        // nothing is visible before finalizing
+        assert_eq!(
+            fetch_branch_tip(first.as_ref(), Ref::DEFAULT_BRANCH).await?.snapshot,
+            initial1
+        );
+        assert_eq!(prepared.finalize().await?, snapshots);
+        for (storage, snapshot) in [first, second].iter().zip(&snapshots) {
+            assert_eq!(
+                &fetch_branch_tip(storage.as_ref(), Ref::DEFAULT_BRANCH).await?.snapshot,
+                snapshot
+            );
+        }
+        assert_eq!(repo1.snapshot_id(), &snapshots[0]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rollback() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false).await?.build();
+        let initial = repo.snapshot_id().clone();
+        repo.add_group(Path::root()).await?;
+        let prepared = Transaction::new()
+            .with_commit(&mut repo, Ref::DEFAULT_BRANCH)
+            .prepare("rolled back", None)
+            .await?;
+        let snapshot = prepared.commits().next().unwrap().snapshot().clone();
+        assert!(storage.fetch_snapshot(&snapshot).await.is_ok());
+
+        prepared.rollback().await;
+        assert_eq!(repo.snapshot_id(), &initial);
+        assert!(repo.has_uncommitted_changes());
+        assert!(storage.fetch_snapshot(&snapshot).await.is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,161 @@
+//! Attribution of stored chunk bytes to the refs that retain them.
+//!
+//! Every branch and tag keeps its history alive, and with it the chunks that history
+//! uses. [`storage_usage`] reports, for each ref, the chunk bytes only that ref retains,
+//! freed by garbage collection if it is deleted, and the bytes it shares with other refs.
+//! Sizes come from the manifests, snapshots and manifests themselves are not counted.
+use std::collections::{BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    format::{manifest::ChunkPayload, ChunkId, ManifestId},
+    gc::{reachable_objects, ref_snapshots, GcResult},
+    refs::{list_refs, Ref},
+    Storage,
+};
+
+/// The chunks retained by one ref
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RefUsage {
+    /// Chunks no other ref retains
+    pub exclusive_chunks: u64,
+    pub exclusive_bytes: u64,
+    /// Chunks some other ref retains too
+    pub shared_chunks: u64,
+    pub shared_bytes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UsageReport {
+    /// Every ref, in the order of [`list_refs`]
+    pub refs: Vec<(Ref, RefUsage)>,
+    /// The chunks retained by any ref, each counted once
+    pub total_chunks: u64,
+    pub total_bytes: u64,
+}
+
+impl UsageReport {
+    pub fn get(&self, r: &Ref) -> Option<&RefUsage> {
+        self.refs.iter().find(|(other, _)| other == r).map(|(_, usage)| usage)
+    }
+}
+
+/// Attribute the chunks of the repository to the refs that retain them
+pub async fn storage_usage(storage: &dyn Storage) -> GcResult<UsageReport> {
+    let refs = list_refs(storage).await?;
+    // the refs using each manifest, so every manifest is read once
+    let mut manifest_refs: HashMap<ManifestId, BTreeSet<usize>> = HashMap::new();
+    for (index, r) in refs.iter().enumerate() {
+        let snapshots = ref_snapshots(storage, r).await?;
+        for manifest in reachable_objects(storage, snapshots).await?.manifests {
+            manifest_refs.entry(manifest).or_default().insert(index);
+        }
+    }
+
+    let mut chunks: HashMap<ChunkId, (u64, BTreeSet<usize>)> = HashMap::new();
+    for (id, owners) in manifest_refs {
+        let manifest = storage.fetch_manifests(&id).await?;
+        for payload in manifest.chunks().values() {
+            if let ChunkPayload::Ref(chunk_ref) = payload {
+                let (_, chunk_owners) = chunks
+                    .entry(chunk_ref.id.clone())
+                    .or_insert((chunk_ref.length, owners.clone()));
+                chunk_owners.extend(owners.iter().copied());
+            }
+        }
+    }
+
+    let mut usage = vec![RefUsage::default(); refs.len()];
+    for (length, owners) in chunks.values() {
+        for owner in owners {
+            let usage = &mut usage[*owner];
+            if owners.len() == 1 {
+                usage.exclusive_chunks += 1;
+                usage.exclusive_bytes += length;
+            } else {
+                usage.shared_chunks += 1;
+                usage.shared_bytes += length;
+            }
+        }
+    }
+    Ok(UsageReport {
+        total_chunks: chunks.len() as u64,
+        total_bytes: chunks.values().map(|(length, _)| length).sum(),
+        refs: refs.into_iter().zip(usage).collect(),
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{error::Error, sync::Arc};
+
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+    use crate::{
+        format::{ChunkIndices, Path},
+        metadata::FillValue,
+        synthetic::vector_metadata,
+        ObjectStorage, Repository,
+    };
+
+    async fn write(
+        repo: &mut Repository,
+        array: &Path,
+        index: u64,
+        size: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        let payload = repo.get_chunk_writer()(Bytes::from(vec![1; size])).await?;
+        repo.set_chunk_ref(array.clone(), ChunkIndices(vec![index]), Some(payload))
+            .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_storage_usage() -> Result<(), Box<dyn Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let mut repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(1)
+            .build();
+        let array: Path = "/array".try_into()?;
+        repo.add_group(Path::root()).await?;
+        repo.add_array(array.clone(), vector_metadata(4, 1, FillValue::UInt8(0))).await?;
+        write(&mut repo, &array, 0, 10).await?;
+        let base = repo.commit("main", "base", None).await?;
+
+        repo.new_branch("experiment").await?;
+        write(&mut repo, &array, 1, 100).await?;
+        repo.commit("experiment", "experiment", None).await?;
+
+        let mut repo = Repository::update(Arc::clone(&storage), base).build();
+        write(&mut repo, &array, 2, 1000).await?;
+        repo.commit("main", "more data", None).await?;
+
+        let report = storage_usage(storage.as_ref()).await?;
+        assert_eq!((report.total_chunks, report.total_bytes), (3, 1110));
+        assert_eq!(
+            report.get(&Ref::Branch("experiment".to_string())),
+            Some(&RefUsage {
+                exclusive_chunks: 1,
+                exclusive_bytes: 100,
+                shared_chunks: 1,
+                shared_bytes: 10,
+            })
+        );
+        assert_eq!(
+            report.get(&Ref::Branch("main".to_string())),
+            Some(&RefUsage {
+                exclusive_chunks: 1,
+                exclusive_bytes: 1000,
+                shared_chunks: 1,
+                shared_bytes: 10,
+            })
+        );
+        Ok(())
+    }
+}
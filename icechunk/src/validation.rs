@@ -0,0 +1,151 @@
+//! Constraints on the data written to arrays.
+//!
+//! [`ArrayConstraints`] are registered per array path in the [`RepositoryConfig`], so
+//! every writer using the same configuration enforces them.
+//! [`Repository::set_chunk_ref`] rejects chunks that violate them, and commits refuse
+//! change sets with violations, including the ones merged from other writers.
+//!
+//! [`RepositoryConfig`]: crate::RepositoryConfig
+//! [`Repository::set_chunk_ref`]: crate::Repository::set_chunk_ref
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    format::{manifest::ChunkPayload, snapshot::ZarrArrayMetadata, ChunkIndices},
+    metadata::DataType,
+};
+
+/// What the chunks and metadata of an array must look like, unset fields aren't checked
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ArrayConstraints {
+    pub data_type: Option<DataType>,
+    /// The length of every chunk, in bytes
+    pub chunk_length_bytes: Option<u64>,
+    /// The allowed chunk coordinates, a range for each dimension
+    pub coordinate_domain: Option<Vec<Range<u64>>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[non_exhaustive]
+pub enum ConstraintViolation {
+    #[error("data type `{found:?}` doesn't match the expected `{expected:?}`")]
+    DataType { expected: DataType, found: DataType },
+    #[error("chunk `{coords:?}` is {found} bytes long, expected {expected}")]
+    ChunkLength { coords: ChunkIndices, expected: u64, found: u64 },
+    #[error("chunk `{coords:?}` is outside the allowed domain `{domain:?}`")]
+    OutsideDomain { coords: ChunkIndices, domain: Vec<Range<u64>> },
+}
+
+impl ArrayConstraints {
+    pub fn with_data_type(mut self, data_type: DataType) -> Self {
+        self.data_type = Some(data_type);
+        self
+    }
+
+    pub fn with_chunk_length_bytes(mut self, length: u64) -> Self {
+        self.chunk_length_bytes = Some(length);
+        self
+    }
+
+    pub fn with_coordinate_domain(mut self, domain: Vec<Range<u64>>) -> Self {
+        self.coordinate_domain = Some(domain);
+        self
+    }
+
+    pub fn check_metadata(
+        &self,
+        metadata: &ZarrArrayMetadata,
+    ) -> Result<(), ConstraintViolation> {
+        match &self.data_type {
+            Some(expected) if expected != &metadata.data_type => {
+                Err(ConstraintViolation::DataType {
+                    expected: expected.clone(),
+                    found: metadata.data_type.clone(),
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check a chunk about to be written, deleting chunks is always allowed
+    pub fn check_chunk(
+        &self,
+        coords: &ChunkIndices,
+        payload: Option<&ChunkPayload>,
+    ) -> Result<(), ConstraintViolation> {
+        if let Some(domain) = &self.coordinate_domain {
+            let inside = coords.0.len() == domain.len()
+                && coords
+                    .0
+                    .iter()
+                    .zip(domain)
+                    .all(|(coord, range)| range.contains(coord));
+            if !inside {
+                return Err(ConstraintViolation::OutsideDomain {
+                    coords: coords.clone(),
+                    domain: domain.clone(),
+                });
+            }
+        }
+        if let (Some(expected), Some(payload)) = (self.chunk_length_bytes, payload) {
+            let found = match payload {
+                ChunkPayload::Inline(bytes) => bytes.len() as u64,
+                ChunkPayload::Ref(chunk_ref) => chunk_ref.length,
+                ChunkPayload::Virtual(chunk_ref) => chunk_ref.length,
+            };
+            if found != expected {
+                return Err(ConstraintViolation::ChunkLength {
+                    coords: coords.clone(),
+                    expected,
+                    found,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use bytes::Bytes;
+    use pretty_assertions::assert_eq;
+
+    use super::*;
+
+    #[test]
+    fn test_check_chunk() {
+        let constraints = ArrayConstraints::default()
+            .with_chunk_length_bytes(4)
+            .with_coordinate_domain(vec![0..2, 0..10]);
+        let payload = ChunkPayload::Inline(Bytes::from_static(b"1234"));
+        assert!(constraints
+            .check_chunk(&ChunkIndices(vec![1, 9]), Some(&payload))
+            .is_ok());
+        assert!(constraints.check_chunk(&ChunkIndices(vec![1, 9]), None).is_ok());
+        assert!(matches!(
+            constraints.check_chunk(&ChunkIndices(vec![2, 0]), None),
+            Err(ConstraintViolation::OutsideDomain { .. })
+        ));
+        assert!(matches!(
+            constraints.check_chunk(&ChunkIndices(vec![0]), Some(&payload)),
+            Err(ConstraintViolation::OutsideDomain { .. })
+        ));
+        let short = ChunkPayload::Inline(Bytes::from_static(b"12"));
+        assert_eq!(
+            constraints.check_chunk(&ChunkIndices(vec![0, 0]), Some(&short)),
+            Err(ConstraintViolation::ChunkLength {
+                coords: ChunkIndices(vec![0, 0]),
+                expected: 4,
+                found: 2
+            })
+        );
+
+        // nothing is checked without constraints
+        let unconstrained = ArrayConstraints::default();
+        assert!(unconstrained.check_chunk(&ChunkIndices(vec![99]), Some(&short)).is_ok());
+    }
+}
@@ -0,0 +1,103 @@
+//! Integrity checks on the read path.
+//!
+//! A [`VerificationPolicy`], set in the [`RepositoryConfig`] of a session, decides which
+//! reads are checked against the hashes recorded when the data was written: chunk
+//! checksums, the etags of the files behind virtual chunks, and manifest hashes. Each
+//! check can be disabled, done on every read or on a random sample of them, trading
+//! integrity guarantees for latency. [`Repository::io_summary`] reports the policy with
+//! the number of reads and checks, so it can be audited.
+//!
+//! Reads without a recorded hash are not checked. Chunk checksums are recorded by the
+//! [`Store`] when [`RepositoryConfig::compute_chunk_checksums`] is set, etags must be
+//! set with [`Repository::set_chunk_extra`], and every manifest has a hash.
+//!
+//! [`RepositoryConfig`]: crate::RepositoryConfig
+//! [`RepositoryConfig::compute_chunk_checksums`]: crate::RepositoryConfig::compute_chunk_checksums
+//! [`Repository::io_summary`]: crate::Repository::io_summary
+//! [`Repository::set_chunk_extra`]: crate::Repository::set_chunk_extra
+//! [`Store`]: crate::Store
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::format::{ChunkId, ManifestId};
+
+/// When reads are checked
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum VerificationMode {
+    #[default]
+    Never,
+    Always,
+    /// A random sample of the reads, this percentage of them
+    Sampled(u8),
+}
+
+impl VerificationMode {
+    /// Decide if the next read is checked
+    pub fn should_verify(&self) -> bool {
+        match self {
+            VerificationMode::Never => false,
+            VerificationMode::Always => true,
+            VerificationMode::Sampled(percent) => {
+                rand::thread_rng().gen_range(0..100) < *percent
+            }
+        }
+    }
+}
+
+/// Which reads are checked, by default none
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VerificationPolicy {
+    /// Materialized chunks, against their recorded checksum
+    pub chunk_checksums: VerificationMode,
+    /// Virtual chunks, the etag of their file against the one recorded
+    pub virtual_etags: VerificationMode,
+    /// Manifests, the first time they are read by a session
+    pub manifest_hashes: VerificationMode,
+}
+
+impl VerificationPolicy {
+    pub fn with_chunk_checksums(mut self, mode: VerificationMode) -> Self {
+        self.chunk_checksums = mode;
+        self
+    }
+
+    pub fn with_virtual_etags(mut self, mode: VerificationMode) -> Self {
+        self.virtual_etags = mode;
+        self
+    }
+
+    pub fn with_manifest_hashes(mut self, mode: VerificationMode) -> Self {
+        self.manifest_hashes = mode;
+        self
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[non_exhaustive]
+pub enum VerificationError {
+    #[error("chunk `{id}` has checksum `{found}`, expected `{expected}`")]
+    ChunkChecksum { id: ChunkId, expected: String, found: String },
+    #[error("file `{location}` has etag `{found:?}`, expected `{expected}`")]
+    VirtualEtag { location: String, expected: String, found: Option<String> },
+    #[error("manifest `{id}` has hash `{found}`, expected `{expected}`")]
+    ManifestHash { id: ManifestId, expected: String, found: String },
+}
+
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verification_modes() {
+        assert!(!VerificationMode::Never.should_verify());
+        assert!(VerificationMode::Always.should_verify());
+        assert!(!VerificationMode::Sampled(0).should_verify());
+        assert!(VerificationMode::Sampled(100).should_verify());
+        let sampled =
+            (0..10_000).filter(|_| VerificationMode::Sampled(10).should_verify()).count();
+        assert!((500..1500).contains(&sampled));
+    }
+}
@@ -1,19 +1,14 @@
 use std::{
     collections::HashSet,
     fmt::Display,
-    iter,
-    num::NonZeroU64,
     ops::{Deref, DerefMut},
     path::PathBuf,
-    sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc, Mutex,
-    },
+    sync::Arc,
 };
 
 use async_stream::try_stream;
 use bytes::Bytes;
-use futures::{Stream, StreamExt, TryStreamExt};
+use futures::{future::ready, Stream, StreamExt, TryStreamExt};
 use itertools::Itertools;
 use serde::{de, Deserialize, Serialize};
 use serde_with::{serde_as, skip_serializing_none, TryFromInto};
@@ -22,20 +17,26 @@ use tokio::sync::RwLock;
 
 use crate::{
     change_set::ChangeSet,
+    error::ErrorKind,
     format::{
         manifest::VirtualChunkRef,
-        snapshot::{NodeData, UserAttributesSnapshot},
+        snapshot::{NodeData, NodeSnapshot, UserAttributesSnapshot},
         ByteRange, ChunkOffset, IcechunkFormatError, SnapshotId,
     },
+    metadata::FillValueDocument,
     refs::{BranchVersion, Ref},
     repository::{
-        get_chunk, ArrayShape, ChunkIndices, ChunkKeyEncoding, ChunkPayload, ChunkShape,
-        Codec, DataType, DimensionNames, FillValue, Path, RepositoryError,
-        RepositoryResult, StorageTransformer, UserAttributes, ZarrArrayMetadata,
+        get_chunk, resolve_user_attributes, ArrayShape, ChunkGrid, ChunkIndices,
+        ChunkKeyEncoding, ChunkPayload, Codec, DataType, DimensionNames, FillValue, Path,
+        RepositoryError, RepositoryResult, StorageTransformer, UserAttributes,
+        ZarrArrayMetadata,
     },
+    revision,
     storage::{
+        layout::check_key_layout,
         s3::{S3Config, S3Storage},
         virtual_ref::ObjectStoreVirtualChunkResolverConfig,
+        KeyLayout,
     },
     ObjectStorage, Repository, RepositoryBuilder, SnapshotMetadata, Storage,
 };
@@ -47,43 +48,52 @@ pub use crate::format::ObjectId;
 #[non_exhaustive]
 pub enum StorageConfig {
     #[serde(rename = "in_memory")]
-    InMemory { prefix: Option<String> },
+    InMemory {
+        prefix: Option<String>,
+        #[serde(default, skip_serializing_if = "KeyLayout::is_default")]
+        layout: KeyLayout,
+    },
 
     #[serde(rename = "local_filesystem")]
-    LocalFileSystem { root: PathBuf },
+    LocalFileSystem {
+        root: PathBuf,
+        #[serde(default, skip_serializing_if = "KeyLayout::is_default")]
+        layout: KeyLayout,
+    },
 
     #[serde(rename = "s3")]
     S3ObjectStore {
         bucket: String,
         prefix: String,
         #[serde(flatten)]
-        config: Option<S3Config>,
+        config: Option<Box<S3Config>>,
+        #[serde(default, skip_serializing_if = "KeyLayout::is_default")]
+        layout: KeyLayout,
     },
 }
 
 impl StorageConfig {
-    pub async fn make_storage(&self) -> Result<Arc<dyn Storage + Send + Sync>, String> {
+    pub async fn make_storage(&self) -> Result<Arc<dyn Storage>, String> {
         match self {
-            StorageConfig::InMemory { prefix } => {
-                Ok(Arc::new(ObjectStorage::new_in_memory_store(prefix.clone())))
-            }
-            StorageConfig::LocalFileSystem { root } => {
+            StorageConfig::InMemory { prefix, layout } => Ok(Arc::new(
+                ObjectStorage::new_in_memory_store(prefix.clone())
+                    .with_key_layout(layout.clone()),
+            )),
+            StorageConfig::LocalFileSystem { root, layout } => {
                 let storage = ObjectStorage::new_local_store(root)
                     .map_err(|e| format!("Error creating storage: {e}"))?;
-                Ok(Arc::new(storage))
+                Ok(Arc::new(storage.with_key_layout(layout.clone())))
             }
-            StorageConfig::S3ObjectStore { bucket, prefix, config } => {
-                let storage = S3Storage::new_s3_store(bucket, prefix, config.as_ref())
+            StorageConfig::S3ObjectStore { bucket, prefix, config, layout } => {
+                let storage = S3Storage::new_s3_store(bucket, prefix, config.as_deref())
                     .await
                     .map_err(|e| format!("Error creating storage: {e}"))?;
-                Ok(Arc::new(storage))
+                Ok(Arc::new(storage.with_key_layout(layout.clone())))
             }
         }
     }
 
-    pub async fn make_cached_storage(
-        &self,
-    ) -> Result<Arc<dyn Storage + Send + Sync>, String> {
+    pub async fn make_cached_storage(&self) -> Result<Arc<dyn Storage>, String> {
         let storage = self.make_storage().await?;
         let cached_storage = Repository::add_in_mem_asset_caching(storage);
         Ok(cached_storage)
@@ -99,6 +109,9 @@ pub enum VersionInfo {
     TagRef(String),
     #[serde(rename = "branch")]
     BranchTipRef(String),
+    /// A ref expression, like `main~2`, see [`crate::revision`]
+    #[serde(rename = "expression")]
+    Expression(String),
 }
 
 #[skip_serializing_none]
@@ -150,36 +163,49 @@ impl RepositoryConfig {
 
     pub async fn make_repository(
         &self,
-        storage: Arc<dyn Storage + Send + Sync>,
+        storage: Arc<dyn Storage>,
     ) -> Result<(Repository, Option<String>), String> {
-        let (mut builder, branch): (RepositoryBuilder, Option<String>) =
-            match &self.version {
-                None => {
-                    let builder = Repository::init(
-                        storage,
-                        self.unsafe_overwrite_refs.unwrap_or(false),
-                    )
+        let (mut builder, branch): (RepositoryBuilder, Option<String>) = match &self
+            .version
+        {
+            None => {
+                let builder = Repository::init(
+                    storage,
+                    self.unsafe_overwrite_refs.unwrap_or(false),
+                )
+                .await
+                .map_err(|err| format!("Error initializing repository: {err}"))?;
+                (builder, Some(String::from(Ref::DEFAULT_BRANCH)))
+            }
+            Some(VersionInfo::SnapshotId(sid)) => {
+                check_key_layout(storage.as_ref())
                     .await
-                    .map_err(|err| format!("Error initializing repository: {err}"))?;
-                    (builder, Some(String::from(Ref::DEFAULT_BRANCH)))
-                }
-                Some(VersionInfo::SnapshotId(sid)) => {
-                    let builder = Repository::update(storage, sid.clone());
-                    (builder, None)
-                }
-                Some(VersionInfo::TagRef(tag)) => {
-                    let builder = Repository::from_tag(storage, tag)
-                        .await
-                        .map_err(|err| format!("Error fetching tag: {err}"))?;
-                    (builder, None)
-                }
-                Some(VersionInfo::BranchTipRef(branch)) => {
-                    let builder = Repository::from_branch_tip(storage, branch)
-                        .await
-                        .map_err(|err| format!("Error fetching branch: {err}"))?;
-                    (builder, Some(branch.clone()))
-                }
-            };
+                    .map_err(|err| format!("Error opening repository: {err}"))?;
+                let builder = Repository::update(storage, sid.clone());
+                (builder, None)
+            }
+            Some(VersionInfo::TagRef(tag)) => {
+                let builder = Repository::from_tag(storage, tag)
+                    .await
+                    .map_err(|err| format!("Error fetching tag: {err}"))?;
+                (builder, None)
+            }
+            Some(VersionInfo::BranchTipRef(branch)) => {
+                let builder = Repository::from_branch_tip(storage, branch)
+                    .await
+                    .map_err(|err| format!("Error fetching branch: {err}"))?;
+                (builder, Some(branch.clone()))
+            }
+            Some(VersionInfo::Expression(expression)) => {
+                check_key_layout(storage.as_ref())
+                    .await
+                    .map_err(|err| format!("Error opening repository: {err}"))?;
+                let resolved = revision::resolve(storage.as_ref(), expression)
+                    .await
+                    .map_err(|err| format!("Error resolving ref expression: {err}"))?;
+                (Repository::update(storage, resolved.snapshot), resolved.branch)
+            }
+        };
 
         if let Some(inline_theshold) = self.inline_chunk_threshold_bytes {
             builder.with_inline_threshold_bytes(inline_theshold);
@@ -247,6 +273,18 @@ pub enum AccessMode {
 
 pub type StoreResult<A> = Result<A, StoreError>;
 
+/// What batched reads do with the rest of the batch when an item fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BatchErrors {
+    /// Keep going, every item gets its own result
+    #[default]
+    Continue,
+    /// The first error is the last item of the batch, reads in flight are cancelled and
+    /// no more reads are started
+    FailFast,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Error)]
 #[non_exhaustive]
 pub enum KeyNotFoundError {
@@ -291,6 +329,30 @@ pub enum StoreError {
     Unknown(Box<dyn std::error::Error + Send + Sync>),
 }
 
+impl StoreError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            StoreError::NotFound(_) => ErrorKind::NotFound,
+            StoreError::RepositoryError(err) => err.kind(),
+            StoreError::Unimplemented(_) => ErrorKind::Unsupported,
+            StoreError::BadMetadata(_) => ErrorKind::Corruption,
+            StoreError::InvalidKey { .. }
+            | StoreError::BadKeyPrefix(_)
+            | StoreError::NotAllowed(_)
+            | StoreError::NoSnapshot
+            | StoreError::NotOnBranch
+            | StoreError::ReadOnly
+            | StoreError::UncommittedChanges => ErrorKind::InvalidRequest,
+            StoreError::PartialValuesPanic | StoreError::Unknown(_) => ErrorKind::Other,
+        }
+    }
+
+    /// True if the operation can succeed if tried again, see [`ErrorKind::is_retryable`]
+    pub fn is_retryable(&self) -> bool {
+        self.kind().is_retryable()
+    }
+}
+
 #[derive(Debug)]
 pub struct Store {
     repository: Arc<RwLock<Repository>>,
@@ -316,9 +378,7 @@ impl Store {
         Self::from_consolidated(&config, mode).await
     }
 
-    pub async fn new_from_storage(
-        storage: Arc<dyn Storage + Send + Sync>,
-    ) -> Result<Self, String> {
+    pub async fn new_from_storage(storage: Arc<dyn Storage>) -> Result<Self, String> {
         let (repository, branch) =
             RepositoryConfig::default().make_repository(storage).await?;
         Ok(Self::from_repository(repository, AccessMode::ReadWrite, branch, None))
@@ -413,6 +473,13 @@ impl Store {
                 self.current_branch = Some(branch.clone());
                 repo.set_snapshot_from_branch(&branch).await?
             }
+            VersionInfo::Expression(expression) => {
+                let resolved = revision::resolve(repo.storage().as_ref(), &expression)
+                    .await
+                    .map_err(RepositoryError::from)?;
+                self.current_branch = resolved.branch;
+                repo.set_snapshot_id(resolved.snapshot);
+            }
         }
 
         Ok(())
@@ -443,7 +510,7 @@ impl Store {
         self.distributed_commit(message, vec![]).await
     }
 
-    pub async fn distributed_commit<'a, I: IntoIterator<Item = Vec<u8>>>(
+    pub async fn distributed_commit<I: IntoIterator<Item = Vec<u8>>>(
         &mut self,
         message: &str,
         other_changesets_bytes: I,
@@ -504,7 +571,8 @@ impl Store {
     /// Get all the requested keys concurrently.
     ///
     /// Returns a vector of the results, in the same order as the keys passed. Errors retrieving
-    /// individual keys will be flagged in the inner [`StoreResult`].
+    /// individual keys will be flagged in the inner [`StoreResult`], they don't stop the
+    /// retrieval of other keys.
     ///
     /// The outer [`StoreResult`] is used to flag a global failure and it could be [`StoreError::PartialValuesPanic`].
     ///
@@ -514,8 +582,31 @@ impl Store {
         &self,
         key_ranges: impl IntoIterator<Item = (String, ByteRange)>,
     ) -> StoreResult<Vec<StoreResult<Bytes>>> {
-        // TODO: prototype argument
-        //
+        let mut results: Vec<_> =
+            self.get_many(key_ranges, BatchErrors::Continue).collect().await;
+        results.sort_by_key(|(index, _)| *index);
+        if results.iter().enumerate().any(|(i, (index, _))| i != *index) {
+            return Err(StoreError::PartialValuesPanic);
+        }
+        Ok(results.into_iter().map(|(_, res)| res).collect())
+    }
+
+    /// Get the requested keys concurrently, as a stream of results.
+    ///
+    /// Items are yielded as soon as they are retrieved, with the position of their key in
+    /// `key_ranges`, so they can arrive in any order. What happens after a key fails
+    /// depends on `errors`, see [`BatchErrors`].
+    ///
+    /// Reads don't have side effects, dropping the stream cancels the reads in flight and
+    /// doesn't start any more of them.
+    ///
+    /// At most `get_partial_values_concurrency` keys, from the Store config, are retrieved
+    /// at the same time.
+    pub fn get_many(
+        &self,
+        key_ranges: impl IntoIterator<Item = (String, ByteRange)>,
+        errors: BatchErrors,
+    ) -> impl Stream<Item = (usize, StoreResult<Bytes>)> + '_ {
         // There is a challenges implementing this function: async rust is not well prepared to
         // do scoped tasks. We want to spawn parallel tasks for each key_range, but spawn requires
         // a `'static` `Future`. Since the `Future` needs `&self`, it cannot be `'static`. One
@@ -525,41 +616,23 @@ impl Store {
         // This [excellent post](https://without.boats/blog/the-scoped-task-trilemma/) explains why something like this is not currently achievable:
         // [Here](https://github.com/tokio-rs/tokio/issues/3162) is a a tokio thread explaining this cannot be done with current Rust.
         //
-        // The compromise we found is using [`Stream::for_each_concurrent`]. This achieves the
+        // The compromise we found is using [`StreamExt::buffer_unordered`]. This achieves the
         // borrowing and the concurrency but not the parallelism. So all the concurrent tasks will
         // execute on the same thread. This is not as bad as it sounds, since most of this will be
         // IO bound.
-
-        let stream = futures::stream::iter(key_ranges);
-        let results = Arc::new(Mutex::new(Vec::new()));
-        let num_keys = AtomicUsize::new(0);
-        stream
-            .for_each_concurrent(
-                self.config.get_partial_values_concurrency as usize,
-                |(key, range)| {
-                    let index = num_keys.fetch_add(1, Ordering::Release);
-                    let results = Arc::clone(&results);
-                    async move {
-                        let value = self.get(&key, &range).await;
-                        if let Ok(mut results) = results.lock() {
-                            if index >= results.len() {
-                                results.resize_with(index + 1, || None);
-                            }
-                            results[index] = Some(value);
-                        }
-                    }
-                },
-            )
-            .await;
-
-        let results = Arc::into_inner(results)
-            .ok_or(StoreError::PartialValuesPanic)?
-            .into_inner()
-            .map_err(|_| StoreError::PartialValuesPanic)?;
-
-        debug_assert!(results.len() == num_keys.into_inner());
-        let res: Option<Vec<_>> = results.into_iter().collect();
-        res.ok_or(StoreError::PartialValuesPanic)
+        let key_ranges: Vec<_> = key_ranges.into_iter().collect();
+        futures::stream::iter(key_ranges.into_iter().enumerate())
+            .map(move |(index, (key, range))| async move {
+                (index, self.get(&key, &range).await)
+            })
+            .buffer_unordered(self.config.get_partial_values_concurrency.max(1) as usize)
+            .scan(false, move |failed, (index, res)| {
+                if *failed {
+                    return ready(None);
+                }
+                *failed = errors == BatchErrors::FailFast && res.is_err();
+                ready(Some((index, res)))
+            })
     }
 
     pub async fn exists(&self, key: &str) -> StoreResult<bool> {
@@ -605,21 +678,64 @@ impl Store {
             Key::Chunk { node_path, coords } => {
                 match locked_repo {
                     Some(repo) => {
+                        if repo.is_noop_chunk_write(&node_path, &coords, &value).await? {
+                            repo.unset_chunk_ref(&node_path, &coords).await?;
+                            return Ok(());
+                        }
+                        let stats =
+                            repo.compute_chunk_statistics(&node_path, &value).await?;
+                        let checksum = repo.compute_chunk_checksum(&value);
                         let writer = repo.get_chunk_writer();
                         let payload = writer(value).await?;
-                        repo.set_chunk_ref(node_path, coords, Some(payload)).await?
+                        repo.set_chunk_ref(
+                            node_path.clone(),
+                            coords.clone(),
+                            Some(payload),
+                        )
+                        .await?;
+                        if let Some(checksum) = checksum {
+                            repo.set_chunk_extra(&node_path, coords.clone(), &checksum)
+                                .await?;
+                        }
+                        if let Some(stats) = stats {
+                            repo.set_chunk_statistics(&node_path, coords, stats).await?;
+                        }
                     }
                     None => {
-                        // we only lock the repository to get the writer
-                        let writer = self.repository.read().await.get_chunk_writer();
+                        // we only lock the repository to get the writer, statistics and checksum
+                        let (writer, stats, checksum) = {
+                            let repo = self.repository.read().await;
+                            if repo
+                                .is_noop_chunk_write(&node_path, &coords, &value)
+                                .await?
+                            {
+                                drop(repo);
+                                let mut repo = self.repository.write().await;
+                                repo.unset_chunk_ref(&node_path, &coords).await?;
+                                return Ok(());
+                            }
+                            let stats =
+                                repo.compute_chunk_statistics(&node_path, &value).await?;
+                            let checksum = repo.compute_chunk_checksum(&value);
+                            (repo.get_chunk_writer(), stats, checksum)
+                        };
                         // then we can write the bytes without holding the lock
                         let payload = writer(value).await?;
                         // and finally we lock for write and update the reference
-                        self.repository
-                            .write()
-                            .await
-                            .set_chunk_ref(node_path, coords, Some(payload))
-                            .await?
+                        let mut repo = self.repository.write().await;
+                        repo.set_chunk_ref(
+                            node_path.clone(),
+                            coords.clone(),
+                            Some(payload),
+                        )
+                        .await?;
+                        if let Some(checksum) = checksum {
+                            repo.set_chunk_extra(&node_path, coords.clone(), &checksum)
+                                .await?;
+                        }
+                        if let Some(stats) = stats {
+                            repo.set_chunk_statistics(&node_path, coords, stats).await?;
+                        }
                     }
                 }
                 Ok(())
@@ -683,7 +799,7 @@ impl Store {
                     KeyNotFoundError::NodeNotFound { path: node_path.clone() }
                 })?;
                 match node.node_data {
-                    NodeData::Array(_, _) => {
+                    NodeData::Array(_, _) | NodeData::Concatenated(_, _) => {
                         Ok(guard.deref_mut().delete_array(node_path).await?)
                     }
                     NodeData::Group => {
@@ -824,19 +940,16 @@ impl Store {
                 // TODO: handle non-utf8?
                 let meta_key = Key::Metadata { node_path: node.path }.to_string();
                     match meta_key.strip_prefix(prefix) {
-                        None => {}
-                        Some(rest) => {
-                            // we have a few cases
-                            if prefix.is_empty()   // if prefix was empty anything matches
-                               || rest.is_empty()  // if stripping prefix left empty we have a match
-                               || rest.starts_with('/') // next component so we match
-                               // what we don't include is other matches,
-                               // we want to catch prefix/foo but not prefix-foo
-                            {
-                                yield meta_key;
-                            }
-
-                    }
+                        // we have a few cases
+                        Some(rest) if prefix.is_empty()   // if prefix was empty anything matches
+                           || rest.is_empty()  // if stripping prefix left empty we have a match
+                           || rest.starts_with('/') // next component so we match
+                           // what we don't include is other matches,
+                           // we want to catch prefix/foo but not prefix-foo
+                        => {
+                            yield meta_key;
+                        }
+                        _ => {}
                 }
             }
         };
@@ -913,22 +1026,31 @@ async fn get_metadata(
     let node = repo.get_node(path).await.map_err(|_| {
         StoreError::NotFound(KeyNotFoundError::NodeNotFound { path: path.clone() })
     })?;
-    let user_attributes = match node.user_attributes {
-        None => None,
-        Some(UserAttributesSnapshot::Inline(atts)) => Some(atts),
-        // FIXME: implement
-        Some(UserAttributesSnapshot::Ref(_)) => todo!(),
-    };
-    let full_metadata = match node.node_data {
-        NodeData::Group => {
-            Ok::<Bytes, StoreError>(GroupMetadata::new(user_attributes).to_bytes())
-        }
-        NodeData::Array(zarr_metadata, _) => {
-            Ok(ArrayMetadata::new(user_attributes, zarr_metadata).to_bytes())
-        }
-    }?;
+    let bytes = node_metadata_bytes(repo.storage().as_ref(), node).await?;
+    Ok(range.slice(bytes).map_err(RepositoryError::from)?)
+}
 
-    Ok(range.slice(full_metadata))
+/// The `zarr.json` document of a node returned by [`Repository::get_node`], user
+/// attributes stored out of the snapshot are fetched if the node only references them
+pub(crate) async fn node_metadata_bytes(
+    storage: &dyn Storage,
+    node: NodeSnapshot,
+) -> RepositoryResult<Bytes> {
+    Ok(match NodeMetadata::new(storage, node).await? {
+        NodeMetadata::Group(metadata) => metadata.to_bytes(),
+        NodeMetadata::Array(metadata) => metadata.to_bytes(),
+    })
+}
+
+/// Like [`node_metadata_bytes`], as a JSON value to embed in other documents
+pub(crate) async fn node_metadata_value(
+    storage: &dyn Storage,
+    node: NodeSnapshot,
+) -> RepositoryResult<serde_json::Value> {
+    let metadata = NodeMetadata::new(storage, node).await?;
+    // metadata built from controlled datastructures can always be serialized
+    #[allow(clippy::expect_used)]
+    Ok(serde_json::to_value(metadata).expect("bug in NodeMetadata serialization"))
 }
 
 async fn get_chunk_bytes(
@@ -1026,7 +1148,7 @@ impl Key {
                         .strip_prefix('/')
                         .ok_or(StoreError::InvalidKey { key: key.to_string() })?
                         .split('/')
-                        .map(|s| s.parse::<u32>())
+                        .map(|s| s.parse::<u64>())
                         .collect::<Result<Vec<_>, _>>()
                         .map(|coords| Key::Chunk {
                             node_path: absolute,
@@ -1077,6 +1199,16 @@ impl Display for Key {
     }
 }
 
+/// The store key of the `zarr.json` document of the node at `path`
+pub(crate) fn metadata_key(path: &Path) -> String {
+    Key::Metadata { node_path: path.clone() }.to_string()
+}
+
+/// The store key of the chunk at `coords` of the array at `path`
+pub(crate) fn chunk_key(path: &Path, coords: &ChunkIndices) -> String {
+    Key::Chunk { node_path: path.clone(), coords: coords.clone() }.to_string()
+}
+
 #[serde_as]
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 struct ArrayMetadata {
@@ -1095,13 +1227,11 @@ pub struct ZarrArrayMetadataSerialzer {
     pub shape: ArrayShape,
     pub data_type: DataType,
 
-    #[serde_as(as = "TryFromInto<NameConfigSerializer>")]
-    #[serde(rename = "chunk_grid")]
-    pub chunk_shape: ChunkShape,
+    pub chunk_grid: ChunkGrid,
 
-    #[serde_as(as = "TryFromInto<NameConfigSerializer>")]
+    #[serde_as(as = "TryFromInto<ChunkKeyEncodingDocument>")]
     pub chunk_key_encoding: ChunkKeyEncoding,
-    pub fill_value: serde_json::Value,
+    pub fill_value: FillValueDocument,
     pub codecs: Vec<Codec>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub storage_transformers: Option<Vec<StorageTransformer>>,
@@ -1116,7 +1246,7 @@ impl TryFrom<ZarrArrayMetadataSerialzer> for ZarrArrayMetadata {
         let ZarrArrayMetadataSerialzer {
             shape,
             data_type,
-            chunk_shape,
+            chunk_grid,
             chunk_key_encoding,
             fill_value,
             codecs,
@@ -1124,90 +1254,45 @@ impl TryFrom<ZarrArrayMetadataSerialzer> for ZarrArrayMetadata {
             dimension_names,
         } = value;
         {
-            let fill_value = FillValue::from_data_type_and_json(&data_type, &fill_value)?;
-            Ok(ZarrArrayMetadata {
+            let fill_value = FillValue::from_document(&data_type, &fill_value)?;
+            let mut metadata = ZarrArrayMetadata {
                 fill_value,
                 shape,
                 data_type,
-                chunk_shape,
+                chunk_shape: chunk_grid.chunk_shape(),
                 chunk_key_encoding,
                 codecs,
                 storage_transformers,
                 dimension_names,
-            })
+                rectilinear_grid: None,
+            };
+            metadata.set_chunk_grid(chunk_grid);
+            metadata.validate_chunk_grid()?;
+            Ok(metadata)
         }
     }
 }
 
 impl From<ZarrArrayMetadata> for ZarrArrayMetadataSerialzer {
     fn from(value: ZarrArrayMetadata) -> Self {
+        let chunk_grid = value.chunk_grid();
         let ZarrArrayMetadata {
             shape,
             data_type,
-            chunk_shape,
+            chunk_shape: _,
             chunk_key_encoding,
             fill_value,
             codecs,
             storage_transformers,
             dimension_names,
+            rectilinear_grid: _,
         } = value;
         {
-            fn fill_value_to_json(f: FillValue) -> serde_json::Value {
-                match f {
-                    FillValue::Bool(b) => b.into(),
-                    FillValue::Int8(n) => n.into(),
-                    FillValue::Int16(n) => n.into(),
-                    FillValue::Int32(n) => n.into(),
-                    FillValue::Int64(n) => n.into(),
-                    FillValue::UInt8(n) => n.into(),
-                    FillValue::UInt16(n) => n.into(),
-                    FillValue::UInt32(n) => n.into(),
-                    FillValue::UInt64(n) => n.into(),
-                    FillValue::Float16(f) => {
-                        if f.is_nan() {
-                            FillValue::NAN_STR.into()
-                        } else if f == f32::INFINITY {
-                            FillValue::INF_STR.into()
-                        } else if f == f32::NEG_INFINITY {
-                            FillValue::NEG_INF_STR.into()
-                        } else {
-                            f.into()
-                        }
-                    }
-                    FillValue::Float32(f) => {
-                        if f.is_nan() {
-                            FillValue::NAN_STR.into()
-                        } else if f == f32::INFINITY {
-                            FillValue::INF_STR.into()
-                        } else if f == f32::NEG_INFINITY {
-                            FillValue::NEG_INF_STR.into()
-                        } else {
-                            f.into()
-                        }
-                    }
-                    FillValue::Float64(f) => {
-                        if f.is_nan() {
-                            FillValue::NAN_STR.into()
-                        } else if f == f64::INFINITY {
-                            FillValue::INF_STR.into()
-                        } else if f == f64::NEG_INFINITY {
-                            FillValue::NEG_INF_STR.into()
-                        } else {
-                            f.into()
-                        }
-                    }
-                    FillValue::Complex64(r, i) => ([r, i].as_ref()).into(),
-                    FillValue::Complex128(r, i) => ([r, i].as_ref()).into(),
-                    FillValue::String(s) => s.into(),
-                    FillValue::Bytes(b) => b.into(),
-                }
-            }
-
-            let fill_value = fill_value_to_json(fill_value);
+            let fill_value = fill_value.to_document();
             ZarrArrayMetadataSerialzer {
                 shape,
                 data_type,
-                chunk_shape,
+                chunk_grid,
                 chunk_key_encoding,
                 codecs,
                 storage_transformers,
@@ -1218,6 +1303,32 @@ impl From<ZarrArrayMetadata> for ZarrArrayMetadataSerialzer {
     }
 }
 
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum NodeMetadata {
+    Group(GroupMetadata),
+    Array(ArrayMetadata),
+}
+
+impl NodeMetadata {
+    async fn new(storage: &dyn Storage, node: NodeSnapshot) -> RepositoryResult<Self> {
+        // get_node replaces references with the attributes they point to, unless the
+        // repository was opened without resolving them, see `crate::open`
+        let user_attributes =
+            match resolve_user_attributes(storage, node.user_attributes).await? {
+                Some(UserAttributesSnapshot::Inline(atts)) => Some(atts),
+                _ => None,
+            };
+        Ok(match node.node_data {
+            NodeData::Group => NodeMetadata::Group(GroupMetadata::new(user_attributes)),
+            NodeData::Array(zarr_metadata, _)
+            | NodeData::Concatenated(zarr_metadata, _) => {
+                NodeMetadata::Array(ArrayMetadata::new(user_attributes, zarr_metadata))
+            }
+        })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct GroupMetadata {
     zarr_format: u8,
@@ -1286,89 +1397,28 @@ impl GroupMetadata {
     }
 }
 
+// chunk key encodings are written as `{"name": "default", "configuration": {"separator":
+// "/"}}`
 #[derive(Serialize, Deserialize)]
-struct NameConfigSerializer {
-    name: String,
-    configuration: serde_json::Value,
+#[serde(tag = "name", content = "configuration", rename_all = "lowercase")]
+enum ChunkKeyEncodingDocument {
+    Default { separator: String },
 }
 
-impl From<ChunkShape> for NameConfigSerializer {
-    fn from(value: ChunkShape) -> Self {
-        let arr = serde_json::Value::Array(
-            value
-                .0
-                .iter()
-                .map(|v| {
-                    serde_json::Value::Number(serde_json::value::Number::from(v.get()))
-                })
-                .collect(),
-        );
-        let kvs = serde_json::value::Map::from_iter(iter::once((
-            "chunk_shape".to_string(),
-            arr,
-        )));
-        Self {
-            name: "regular".to_string(),
-            configuration: serde_json::Value::Object(kvs),
-        }
-    }
-}
-
-impl TryFrom<NameConfigSerializer> for ChunkShape {
-    type Error = &'static str;
-
-    fn try_from(value: NameConfigSerializer) -> Result<Self, Self::Error> {
-        match value {
-            NameConfigSerializer {
-                name,
-                configuration: serde_json::Value::Object(kvs),
-            } if name == "regular" => {
-                let values = kvs
-                    .get("chunk_shape")
-                    .and_then(|v| v.as_array())
-                    .ok_or("cannot parse ChunkShape")?;
-                let shape = values
-                    .iter()
-                    .map(|v| v.as_u64().and_then(|u64| NonZeroU64::try_from(u64).ok()))
-                    .collect::<Option<Vec<_>>>()
-                    .ok_or("cannot parse ChunkShape")?;
-                Ok(ChunkShape(shape))
-            }
-            _ => Err("cannot parse ChunkShape"),
-        }
-    }
-}
-
-impl From<ChunkKeyEncoding> for NameConfigSerializer {
+impl From<ChunkKeyEncoding> for ChunkKeyEncodingDocument {
     fn from(_value: ChunkKeyEncoding) -> Self {
-        let kvs = serde_json::value::Map::from_iter(iter::once((
-            "separator".to_string(),
-            serde_json::Value::String("/".to_string()),
-        )));
-        Self {
-            name: "default".to_string(),
-            configuration: serde_json::Value::Object(kvs),
-        }
+        ChunkKeyEncodingDocument::Default { separator: "/".to_string() }
     }
 }
 
-impl TryFrom<NameConfigSerializer> for ChunkKeyEncoding {
+impl TryFrom<ChunkKeyEncodingDocument> for ChunkKeyEncoding {
     type Error = &'static str;
 
-    fn try_from(value: NameConfigSerializer) -> Result<Self, Self::Error> {
+    fn try_from(value: ChunkKeyEncodingDocument) -> Result<Self, Self::Error> {
         //FIXME: we are hardcoding / as the separator
         match value {
-            NameConfigSerializer {
-                name,
-                configuration: serde_json::Value::Object(kvs),
-            } if name == "default" => {
-                if let Some("/") =
-                    kvs.get("separator").ok_or("cannot parse ChunkKeyEncoding")?.as_str()
-                {
-                    Ok(ChunkKeyEncoding::Slash)
-                } else {
-                    Err("cannot parse ChunkKeyEncoding")
-                }
+            ChunkKeyEncodingDocument::Default { separator } if separator == "/" => {
+                Ok(ChunkKeyEncoding::Slash)
             }
             _ => Err("cannot parse ChunkKeyEncoding"),
         }
@@ -1379,9 +1429,16 @@ impl TryFrom<NameConfigSerializer> for ChunkKeyEncoding {
 #[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
 mod tests {
 
-    use std::borrow::BorrowMut;
+    use std::{borrow::BorrowMut, num::NonZeroU64};
 
-    use crate::storage::s3::{S3Credentials, StaticS3Credentials};
+    use crate::{
+        metadata::{DimensionChunks, RectilinearGrid},
+        repository::{ChunkShape, EmptyCommitPolicy},
+        storage::{
+            s3::{S3Credentials, StaticS3Credentials},
+            FanOut,
+        },
+    };
 
     use super::*;
     use pretty_assertions::assert_eq;
@@ -1439,6 +1496,14 @@ mod tests {
                 coords,
             }) if node_path.to_string() == "/foo/bar/baz" && coords == ChunkIndices(vec![1,2,3])
         ));
+        assert!(matches!(
+            Key::parse("foo/c/4294967296/18446744073709551615"),
+            Ok(Key::Chunk {
+                node_path,
+                coords,
+            }) if node_path.to_string() == "/foo" && coords == ChunkIndices(vec![1 << 32, u64::MAX])
+        ));
+        assert!(Key::parse("foo/c/18446744073709551616").is_err());
         assert!(matches!(
             Key::parse("c"),
             Ok(Key::Chunk { node_path, coords}) if node_path.to_string() == "/" && coords == ChunkIndices(vec![])
@@ -1629,6 +1694,7 @@ mod tests {
                 configuration: None,
             }]),
             dimension_names: Some(vec![Some("t".to_string())]),
+            rectilinear_grid: None,
         };
         let zarr_meta = ArrayMetadata::new(None, zarr_meta);
 
@@ -1638,12 +1704,88 @@ mod tests {
             )
             .unwrap(),
             zarr_meta,
+        );
+
+        // rectilinear chunk grids
+        let rectilinear = serde_json::from_str::<ArrayMetadata>(
+            r#"{"zarr_format":3,"node_type":"array","shape":[10,6],"data_type":"int32","chunk_grid":{"name":"rectilinear","configuration":{"kind":"inline","chunk_shapes":[[2,5,3],4]}},"chunk_key_encoding":{"name":"default","configuration":{"separator":"/"}},"fill_value":0,"codecs":[],"dimension_names":null}"#,
         )
+        .unwrap();
+        let size = |n| NonZeroU64::new(n).unwrap();
+        assert_eq!(
+            rectilinear.zarr_metadata.rectilinear_grid,
+            Some(RectilinearGrid(vec![
+                DimensionChunks::Sizes(vec![size(2), size(5), size(3)]),
+                DimensionChunks::Regular(size(4)),
+            ]))
+        );
+        assert_eq!(
+            rectilinear.zarr_metadata.chunk_shape,
+            ChunkShape(vec![size(5), size(4)])
+        );
+        assert_eq!(
+            serde_json::from_str::<ArrayMetadata>(
+                serde_json::to_string(&rectilinear).unwrap().as_str()
+            )
+            .unwrap(),
+            rectilinear,
+        );
+        // the chunks must add up to the shape
+        assert!(serde_json::from_str::<ArrayMetadata>(
+            r#"{"zarr_format":3,"node_type":"array","shape":[11,6],"data_type":"int32","chunk_grid":{"name":"rectilinear","configuration":{"kind":"inline","chunk_shapes":[[2,5,3],4]}},"chunk_key_encoding":{"name":"default","configuration":{"separator":"/"}},"fill_value":0,"codecs":[],"dimension_names":null}"#,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_chunk_grid_serialization() {
+        let size = |n| NonZeroU64::new(n).unwrap();
+        let regular: ChunkGrid = serde_json::from_str(
+            r#"{"name":"regular","configuration":{"chunk_shape":[2,3]}}"#,
+        )
+        .unwrap();
+        assert_eq!(regular, ChunkGrid::Regular(ChunkShape(vec![size(2), size(3)])));
+        let rectilinear: ChunkGrid = serde_json::from_str(
+            r#"{"name":"rectilinear","configuration":{"kind":"inline","chunk_shapes":[[2,5,3],4]}}"#,
+        )
+        .unwrap();
+        assert_eq!(rectilinear.chunk_shape(), ChunkShape(vec![size(5), size(4)]));
+        assert_eq!(rectilinear.ndim(), 2);
+        for grid in [regular, rectilinear] {
+            let json = serde_json::to_string(&grid).unwrap();
+            assert_eq!(serde_json::from_str::<ChunkGrid>(&json).unwrap(), grid);
+        }
+        for invalid in [
+            r#"{"name":"regular","configuration":{"chunk_shape":[0]}}"#,
+            r#"{"name":"rectilinear","configuration":{"kind":"external","chunk_shapes":[4]}}"#,
+            r#"{"name":"irregular","configuration":{}}"#,
+        ] {
+            assert!(serde_json::from_str::<ChunkGrid>(invalid).is_err());
+        }
+
+        let mut metadata = ZarrArrayMetadata {
+            shape: vec![10],
+            data_type: DataType::Int32,
+            chunk_shape: ChunkShape(vec![size(4)]),
+            chunk_key_encoding: ChunkKeyEncoding::Slash,
+            fill_value: FillValue::Int32(0),
+            codecs: vec![],
+            storage_transformers: None,
+            dimension_names: None,
+            rectilinear_grid: None,
+        };
+        let grid = RectilinearGrid(vec![DimensionChunks::Sizes(vec![size(2), size(8)])]);
+        metadata.set_chunk_grid(ChunkGrid::Rectilinear(grid.clone()));
+        assert_eq!(metadata.chunk_shape, ChunkShape(vec![size(8)]));
+        assert_eq!(metadata.chunk_grid(), ChunkGrid::Rectilinear(grid));
+        metadata.set_chunk_grid(ChunkGrid::Regular(ChunkShape(vec![size(5)])));
+        assert_eq!(metadata.rectilinear_grid, None);
+        assert_eq!(metadata.chunk_grid_shape(), vec![2]);
     }
 
     #[tokio::test]
     async fn test_metadata_set_and_get() -> Result<(), Box<dyn std::error::Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
         let ds = Repository::init(Arc::clone(&storage), false).await?.build();
         let store = Store::from_repository(
@@ -1691,10 +1833,9 @@ mod tests {
 
     #[tokio::test]
     async fn test_metadata_delete() -> Result<(), Box<dyn std::error::Error>> {
-        let in_mem_storage: Arc<dyn Storage + Send + Sync> =
+        let in_mem_storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
-        let storage =
-            Arc::clone(&(in_mem_storage.clone() as Arc<dyn Storage + Send + Sync>));
+        let storage = Arc::clone(&(in_mem_storage.clone() as Arc<dyn Storage>));
         let ds = Repository::init(Arc::clone(&storage), false).await?.build();
         let store = Store::from_repository(
             ds,
@@ -1736,10 +1877,9 @@ mod tests {
     #[tokio::test]
     async fn test_chunk_set_and_get() -> Result<(), Box<dyn std::error::Error>> {
         // TODO: turn this test into pure Store operations once we support writes through Zarr
-        let in_mem_storage: Arc<dyn Storage + Send + Sync> =
+        let in_mem_storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
-        let storage =
-            Arc::clone(&(in_mem_storage.clone() as Arc<dyn Storage + Send + Sync>));
+        let storage = Arc::clone(&(in_mem_storage.clone() as Arc<dyn Storage>));
         let ds = Repository::init(Arc::clone(&storage), false).await?.build();
         let mut store = Store::from_repository(
             ds,
@@ -1829,12 +1969,77 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_scalar_array() -> Result<(), Box<dyn std::error::Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        let mut store = Store::from_repository(
+            ds,
+            AccessMode::ReadWrite,
+            Some("main".to_string()),
+            None,
+        );
+
+        store
+            .set(
+                "zarr.json",
+                Bytes::copy_from_slice(br#"{"zarr_format":3, "node_type":"group"}"#),
+            )
+            .await?;
+        let zarr_meta = Bytes::copy_from_slice(br#"{"zarr_format":3,"node_type":"array","attributes":{"foo":42},"shape":[],"data_type":"int32","chunk_grid":{"name":"regular","configuration":{"chunk_shape":[]}},"chunk_key_encoding":{"name":"default","configuration":{"separator":"/"}},"fill_value":0,"codecs":[{"name":"mycodec","configuration":{"foo":42}}],"dimension_names":[]}"#);
+        store.set("scalar/zarr.json", zarr_meta.clone()).await?;
+        assert_eq!(store.get("scalar/zarr.json", &ByteRange::ALL).await?, zarr_meta);
+
+        // the single chunk of a scalar array has no coordinates
+        let data = Bytes::copy_from_slice(b"42");
+        store.set("scalar/c", data.clone()).await?;
+        assert_eq!(store.get("scalar/c", &ByteRange::ALL).await?, data);
+        assert!(store.exists("scalar/c").await?);
+        assert!(!store.exists("scalar/c/0").await?);
+        assert_eq!(
+            all_keys(&store).await?,
+            vec![
+                "scalar/c".to_string(),
+                "scalar/zarr.json".to_string(),
+                "zarr.json".to_string()
+            ]
+        );
+
+        let oid = store.commit("commit").await?;
+        let ds = Repository::update(Arc::clone(&storage), oid).build();
+        let mut store = Store::from_repository(
+            ds,
+            AccessMode::ReadWrite,
+            Some("main".to_string()),
+            None,
+        );
+        assert_eq!(store.get("scalar/c", &ByteRange::ALL).await?, data);
+        assert_eq!(
+            all_keys(&store).await?,
+            vec![
+                "scalar/c".to_string(),
+                "scalar/zarr.json".to_string(),
+                "zarr.json".to_string()
+            ]
+        );
+
+        store.delete("scalar/c").await?;
+        assert!(!store.exists("scalar/c").await?);
+        store.commit("delete").await?;
+        assert_eq!(
+            all_keys(&store).await?,
+            vec!["scalar/zarr.json".to_string(), "zarr.json".to_string()]
+        );
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_chunk_delete() -> Result<(), Box<dyn std::error::Error>> {
-        let in_mem_storage: Arc<dyn Storage + Send + Sync> =
+        let in_mem_storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
-        let storage =
-            Arc::clone(&(in_mem_storage.clone() as Arc<dyn Storage + Send + Sync>));
+        let storage = Arc::clone(&(in_mem_storage.clone() as Arc<dyn Storage>));
         let ds = Repository::init(Arc::clone(&storage), false).await?.build();
         let store = Store::from_repository(
             ds,
@@ -1879,7 +2084,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_metadata_list() -> Result<(), Box<dyn std::error::Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
         let ds = Repository::init(Arc::clone(&storage), false).await?.build();
         let mut store = Store::from_repository(
@@ -1948,7 +2153,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_set_array_metadata() -> Result<(), Box<dyn std::error::Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
         let ds = Repository::init(Arc::clone(&storage), false).await?.build();
         let mut store = Store::from_repository(
@@ -1987,7 +2192,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_chunk_list() -> Result<(), Box<dyn std::error::Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
         let ds = Repository::init(Arc::clone(&storage), false).await?.build();
         let mut store = Store::from_repository(
@@ -2027,7 +2232,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_list_dir() -> Result<(), Box<dyn std::error::Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
         let ds = Repository::init(Arc::clone(&storage), false).await?.build();
         let mut store = Store::from_repository(
@@ -2086,7 +2291,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_list_dir_with_prefix() -> Result<(), Box<dyn std::error::Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
         let ds = Repository::init(Arc::clone(&storage), false).await?.build();
         let mut store = Store::from_repository(
@@ -2129,7 +2334,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_get_partial_values() -> Result<(), Box<dyn std::error::Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
         let ds = Repository::init(Arc::clone(&storage), false).await?.build();
         let mut store = Store::from_repository(
@@ -2191,9 +2396,53 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_get_many_errors() -> Result<(), Box<dyn std::error::Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        // one key at a time, to know which reads were started before the failure
+        let store = Store::from_repository(
+            ds,
+            AccessMode::ReadWrite,
+            Some("main".to_string()),
+            Some(StoreOptions { get_partial_values_concurrency: 1 }),
+        );
+        store
+            .set(
+                "zarr.json",
+                Bytes::copy_from_slice(br#"{"zarr_format":3, "node_type":"group"}"#),
+            )
+            .await?;
+        let zarr_meta = Bytes::copy_from_slice(br#"{"zarr_format":3,"node_type":"array","attributes":{"foo":42},"shape":[20],"data_type":"int32","chunk_grid":{"name":"regular","configuration":{"chunk_shape":[1]}},"chunk_key_encoding":{"name":"default","configuration":{"separator":"/"}},"fill_value":0,"codecs":[{"name":"mycodec","configuration":{"foo":42}}],"storage_transformers":[{"name":"mytransformer","configuration":{"bar":43}}],"dimension_names":["x"]}"#);
+        store.set("array/zarr.json", zarr_meta).await?;
+        store.set("array/c/0", Bytes::from_static(b"0")).await?;
+        store.set("array/c/2", Bytes::from_static(b"2")).await?;
+
+        let keys = || {
+            ["array/c/0", "array/c/1", "array/c/2"]
+                .map(|k| (k.to_string(), ByteRange::ALL))
+        };
+        let values = store.get_partial_values(keys()).await?;
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[0].as_ref().unwrap(), &Bytes::from_static(b"0"));
+        assert!(matches!(values[1], Err(StoreError::NotFound(_))));
+        assert_eq!(values[2].as_ref().unwrap(), &Bytes::from_static(b"2"));
+
+        let all: Vec<_> = store.get_many(keys(), BatchErrors::Continue).collect().await;
+        assert_eq!(all.iter().map(|(i, _)| *i).collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        let fail_fast: Vec<_> =
+            store.get_many(keys(), BatchErrors::FailFast).collect().await;
+        assert_eq!(fail_fast.len(), 2);
+        assert!(fail_fast[0].1.is_ok());
+        assert!(matches!(fail_fast[1], (1, Err(StoreError::NotFound(_)))));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_commit_and_checkout() -> Result<(), Box<dyn std::error::Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
 
         let mut store = Store::new_from_storage(Arc::clone(&storage)).await?;
@@ -2246,6 +2495,12 @@ mod tests {
         store.checkout(VersionInfo::SnapshotId(dev_snapshot_id)).await?;
         assert_eq!(store.get("array/c/0/1/0", &ByteRange::ALL).await.unwrap(), new_data);
 
+        store.checkout(VersionInfo::Expression("main~1".to_string())).await?;
+        assert_eq!(store.snapshot_id().await, snapshot_id);
+        assert_eq!(store.current_branch(), &None);
+        store.checkout(VersionInfo::Expression("branch:dev".to_string())).await?;
+        assert_eq!(store.current_branch(), &Some("dev".to_string()));
+
         let new_store_from_snapshot = Store::from_repository(
             Repository::update(Arc::clone(&storage), snapshot_id).build(),
             AccessMode::ReadWrite,
@@ -2260,9 +2515,70 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_skip_noop_writes() -> Result<(), Box<dyn std::error::Error>> {
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
+        let repo = Repository::init(Arc::clone(&storage), false)
+            .await?
+            .with_inline_threshold_bytes(2)
+            .with_compute_chunk_checksums(true)
+            .with_skip_noop_writes(true)
+            .with_empty_commit_policy(EmptyCommitPolicy::ReturnHead)
+            .build();
+        let mut store = Store::from_repository(
+            repo,
+            AccessMode::ReadWrite,
+            Some("main".to_string()),
+            None,
+        );
+
+        let group_meta =
+            Bytes::copy_from_slice(br#"{"zarr_format":3, "node_type":"group"}"#);
+        let zarr_meta = Bytes::copy_from_slice(br#"{"zarr_format":3,"node_type":"array","attributes":{"foo":42},"shape":[2,2,2],"data_type":"int32","chunk_grid":{"name":"regular","configuration":{"chunk_shape":[1,1,1]}},"chunk_key_encoding":{"name":"default","configuration":{"separator":"/"}},"fill_value":0,"codecs":[],"dimension_names":["x","y","t"]}"#);
+        let materialized = Bytes::copy_from_slice(b"hello");
+        let inline = Bytes::copy_from_slice(b"a");
+
+        async fn write_all(store: &Store, values: &[(&str, &Bytes)]) -> StoreResult<()> {
+            for (key, value) in values {
+                store.set(key, (*value).clone()).await?;
+            }
+            Ok(())
+        }
+        let values = [
+            ("zarr.json", &group_meta),
+            ("array/zarr.json", &zarr_meta),
+            ("array/c/0/1/0", &materialized),
+            ("array/c/1/1/0", &inline),
+        ];
+
+        write_all(&store, &values).await?;
+        let snapshot_id = store.commit("first run").await?;
+
+        // writing the same values again doesn't change anything
+        write_all(&store, &values).await?;
+        assert!(!store.has_uncommitted_changes().await);
+        assert_eq!(store.commit("second run").await?, snapshot_id);
+
+        // changing a chunk back to its committed value undoes the change
+        store.set("array/c/0/1/0", Bytes::copy_from_slice(b"world")).await?;
+        assert!(store.has_uncommitted_changes().await);
+        store.set("array/c/0/1/0", materialized.clone()).await?;
+        assert!(!store.has_uncommitted_changes().await);
+
+        store.set("array/c/1/1/0", Bytes::copy_from_slice(b"b")).await?;
+        let new_snapshot_id = store.commit("update").await?;
+        assert_ne!(new_snapshot_id, snapshot_id);
+        assert_eq!(
+            store.get("array/c/1/1/0", &ByteRange::ALL).await?,
+            Bytes::copy_from_slice(b"b")
+        );
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_clear() -> Result<(), Box<dyn std::error::Error>> {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
 
         let mut store = Store::new_from_storage(Arc::clone(&storage)).await?;
@@ -2345,7 +2661,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_access_mode() {
-        let storage: Arc<dyn Storage + Send + Sync> =
+        let storage: Arc<dyn Storage> =
             Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
 
         let writeable_store =
@@ -2369,7 +2685,7 @@ mod tests {
                 Bytes::copy_from_slice(br#"{"zarr_format":3, "node_type":"group"}"#),
             )
             .await;
-        let correct_error = matches!(result, Err(StoreError::ReadOnly { .. }));
+        let correct_error = matches!(result, Err(StoreError::ReadOnly));
         assert!(correct_error);
 
         readable_store.get("zarr.json", &ByteRange::ALL).await.unwrap();
@@ -2378,7 +2694,10 @@ mod tests {
     #[test]
     fn test_store_config_deserialization() -> Result<(), Box<dyn std::error::Error>> {
         let expected = ConsolidatedStore {
-            storage: StorageConfig::LocalFileSystem { root: "/tmp/test".into() },
+            storage: StorageConfig::LocalFileSystem {
+                root: "/tmp/test".into(),
+                layout: KeyLayout::default(),
+            },
             repository: RepositoryConfig {
                 inline_chunk_threshold_bytes: Some(128),
                 version: Some(VersionInfo::SnapshotId(SnapshotId::new([
@@ -2451,7 +2770,8 @@ mod tests {
         );
 
         let json = r#"
-            {"storage":{"type": "in_memory", "prefix": "prefix"},
+            {"storage":{"type": "in_memory", "prefix": "prefix",
+                        "layout": {"fan_out": "hex2", "chunks": "c"}},
              "repository": {}
             }
         "#;
@@ -2464,7 +2784,14 @@ mod tests {
                     change_set_bytes: None,
                     virtual_ref_config: None,
                 },
-                storage: StorageConfig::InMemory { prefix: Some("prefix".to_string()) },
+                storage: StorageConfig::InMemory {
+                    prefix: Some("prefix".to_string()),
+                    layout: KeyLayout {
+                        fan_out: FanOut::Hex2,
+                        chunks: "c".to_string(),
+                        ..KeyLayout::default()
+                    },
+                },
                 config: None,
             },
             serde_json::from_str(json)?
@@ -2484,7 +2811,10 @@ mod tests {
                     change_set_bytes: None,
                     virtual_ref_config: None,
                 },
-                storage: StorageConfig::InMemory { prefix: None },
+                storage: StorageConfig::InMemory {
+                    prefix: None,
+                    layout: KeyLayout::default(),
+                },
                 config: None,
             },
             serde_json::from_str(json)?
@@ -2508,6 +2838,7 @@ mod tests {
                     bucket: String::from("test"),
                     prefix: String::from("root"),
                     config: None,
+                    layout: KeyLayout::default(),
                 },
                 config: None,
             },
@@ -2542,7 +2873,7 @@ mod tests {
                 storage: StorageConfig::S3ObjectStore {
                     bucket: String::from("test"),
                     prefix: String::from("root"),
-                    config: Some(S3Config {
+                    config: Some(Box::new(S3Config {
                         region: None,
                         endpoint: Some(String::from("http://localhost:9000")),
                         credentials: S3Credentials::Static(StaticS3Credentials {
@@ -2551,7 +2882,9 @@ mod tests {
                             session_token: None,
                         }),
                         allow_http: true,
-                    })
+                        ..Default::default()
+                    })),
+                    layout: KeyLayout::default(),
                 },
                 config: None,
             },
@@ -29,7 +29,7 @@ const N: usize = 20;
 /// read. While that happens, another Task lists the chunk contents and only finishes when it finds
 /// all chunks written.
 async fn test_concurrency() -> Result<(), Box<dyn std::error::Error>> {
-    let storage: Arc<dyn Storage + Send + Sync> =
+    let storage: Arc<dyn Storage> =
         Arc::new(ObjectStorage::new_in_memory_store(Some("prefix".into())));
     let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
 
@@ -49,6 +49,7 @@ async fn test_concurrency() -> Result<(), Box<dyn std::error::Error>> {
             configuration: None,
         }]),
         dimension_names: Some(vec![Some("x".to_string()), Some("y".to_string())]),
+        rectilinear_grid: None,
     };
 
     let new_array_path: Path = "/array".try_into().unwrap();
@@ -67,14 +68,14 @@ async fn test_concurrency() -> Result<(), Box<dyn std::error::Error>> {
         for y in 0..N {
             let ds = Arc::clone(&ds);
             let barrier = Arc::clone(&barrier);
-            set.spawn(async move { read_task(ds, x as u32, y as u32, barrier).await });
+            set.spawn(async move { read_task(ds, x as u64, y as u64, barrier).await });
         }
     }
 
     for x in 0..N {
         for y in 0..N {
             let ds = Arc::clone(&ds);
-            set.spawn(async move { write_task(ds, x as u32, y as u32).await });
+            set.spawn(async move { write_task(ds, x as u64, y as u64).await });
         }
     }
 
@@ -83,7 +84,7 @@ async fn test_concurrency() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-async fn write_task(ds: Arc<RwLock<Repository>>, x: u32, y: u32) {
+async fn write_task(ds: Arc<RwLock<Repository>>, x: u64, y: u64) {
     let value = x as f64 * y as f64;
     let bytes = Bytes::copy_from_slice(&value.to_be_bytes());
 
@@ -110,7 +111,7 @@ async fn write_task(ds: Arc<RwLock<Repository>>, x: u32, y: u32) {
         .expect("Failed to write chunk ref");
 }
 
-async fn read_task(ds: Arc<RwLock<Repository>>, x: u32, y: u32, barrier: Arc<Barrier>) {
+async fn read_task(ds: Arc<RwLock<Repository>>, x: u64, y: u64, barrier: Arc<Barrier>) {
     let value = x as f64 * y as f64;
     let expected_bytes = Bytes::copy_from_slice(&value.to_be_bytes());
     barrier.wait().await;
@@ -128,13 +129,8 @@ async fn read_task(ds: Arc<RwLock<Repository>>, x: u32, y: u32, barrier: Arc<Bar
         let actual_bytes =
             get_chunk(chunk_reader).await.expect("Failed to getch chunk payload");
 
-        match &actual_bytes {
-            Some(bytes) => {
-                if bytes == &expected_bytes {
-                    break;
-                }
-            }
-            None => {}
+        if actual_bytes.as_ref() == Some(&expected_bytes) {
+            break;
         }
         let random_sleep = {
             let mut rng = thread_rng();
@@ -146,8 +142,8 @@ async fn read_task(ds: Arc<RwLock<Repository>>, x: u32, y: u32, barrier: Arc<Bar
 
 async fn list_task(ds: Arc<RwLock<Repository>>, barrier: Arc<Barrier>) {
     let mut expected_indices = HashSet::new();
-    for x in 0..(N as u32) {
-        for y in 0..(N as u32) {
+    for x in 0..(N as u64) {
+        for y in 0..(N as u64) {
             expected_indices.insert(ChunkIndices(vec![x, y]));
         }
     }
@@ -0,0 +1,319 @@
+#[cfg(test)]
+#[allow(clippy::panic, clippy::unwrap_used, clippy::expect_used)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        error::Error,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Mutex,
+        },
+    };
+
+    use bytes::Bytes;
+    use futures::stream::{self, BoxStream, StreamExt};
+    use icechunk::{
+        format::{
+            attributes::AttributesTable, manifest::Manifest, snapshot::Snapshot,
+            AttributesId, ByteRange, ChunkId, ChunkIndices, ManifestId, Path, SnapshotId,
+        },
+        metadata::FillValue,
+        repository::ChunkPayload,
+        storage::{with_structure_base, StorageError, StorageFuture, StorageResult},
+        synthetic::vector_metadata,
+        ObjectStorage, Repository, Storage,
+    };
+    use pretty_assertions::assert_eq;
+    use serde::de::DeserializeOwned;
+
+    /// A storage implemented outside of the crate, counting the fetches it forwards
+    #[derive(Debug)]
+    struct CountingStorage {
+        backend: Arc<dyn Storage>,
+        fetches: AtomicUsize,
+    }
+
+    impl CountingStorage {
+        fn fetched(&self) {
+            self.fetches.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl Storage for CountingStorage {
+        fn fetch_snapshot<'a>(
+            &'a self,
+            id: &'a SnapshotId,
+        ) -> StorageFuture<'a, Arc<Snapshot>> {
+            self.fetched();
+            self.backend.fetch_snapshot(id)
+        }
+
+        fn fetch_attributes<'a>(
+            &'a self,
+            id: &'a AttributesId,
+        ) -> StorageFuture<'a, Arc<AttributesTable>> {
+            self.fetched();
+            self.backend.fetch_attributes(id)
+        }
+
+        fn fetch_manifests<'a>(
+            &'a self,
+            id: &'a ManifestId,
+        ) -> StorageFuture<'a, Arc<Manifest>> {
+            self.fetched();
+            self.backend.fetch_manifests(id)
+        }
+
+        fn fetch_chunk<'a>(
+            &'a self,
+            id: &'a ChunkId,
+            range: &'a ByteRange,
+        ) -> StorageFuture<'a, Bytes> {
+            self.fetched();
+            self.backend.fetch_chunk(id, range)
+        }
+
+        icechunk::forward_storage!(
+            backend =>
+                write_snapshot, write_attributes, write_manifests, write_chunk,
+                delete_snapshot, delete_manifests, delete_chunk, get_ref, ref_names,
+                ref_versions, write_ref,
+        );
+    }
+
+    /// A storage written from scratch, keeping the serialized objects in memory
+    #[derive(Debug, Default)]
+    struct MapStorage {
+        objects: Mutex<BTreeMap<String, Bytes>>,
+    }
+
+    impl MapStorage {
+        fn get(&self, key: String) -> StorageResult<Bytes> {
+            self.objects
+                .lock()
+                .unwrap()
+                .get(&key)
+                .cloned()
+                .ok_or_else(|| StorageError::Other(format!("{key} not found")))
+        }
+
+        fn decode<T: DeserializeOwned>(&self, key: String) -> StorageResult<T> {
+            Ok(rmp_serde::from_slice(self.get(key)?.as_ref())?)
+        }
+
+        fn put(&self, key: String, bytes: Bytes) {
+            self.objects.lock().unwrap().insert(key, bytes);
+        }
+
+        fn encode<T: serde::Serialize>(
+            &self,
+            key: String,
+            value: &T,
+        ) -> StorageResult<()> {
+            self.put(key, Bytes::from(rmp_serde::to_vec(value)?));
+            Ok(())
+        }
+
+        fn delete(&self, key: String) -> StorageFuture<'_, ()> {
+            self.objects.lock().unwrap().remove(&key);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn keys_under(&self, prefix: &str) -> Vec<String> {
+            self.objects
+                .lock()
+                .unwrap()
+                .keys()
+                .filter_map(|key| key.strip_prefix(prefix).map(str::to_string))
+                .collect()
+        }
+    }
+
+    impl Storage for MapStorage {
+        fn fetch_snapshot<'a>(
+            &'a self,
+            id: &'a SnapshotId,
+        ) -> StorageFuture<'a, Arc<Snapshot>> {
+            Box::pin(async move {
+                let snapshot = self.decode(format!("snapshots/{id}"))?;
+                Ok(Arc::new(with_structure_base(self, snapshot).await?))
+            })
+        }
+
+        fn fetch_attributes<'a>(
+            &'a self,
+            id: &'a AttributesId,
+        ) -> StorageFuture<'a, Arc<AttributesTable>> {
+            Box::pin(
+                async move { Ok(Arc::new(self.decode(format!("attributes/{id}"))?)) },
+            )
+        }
+
+        fn fetch_manifests<'a>(
+            &'a self,
+            id: &'a ManifestId,
+        ) -> StorageFuture<'a, Arc<Manifest>> {
+            Box::pin(async move { Ok(Arc::new(self.decode(format!("manifests/{id}"))?)) })
+        }
+
+        fn fetch_chunk<'a>(
+            &'a self,
+            id: &'a ChunkId,
+            range: &'a ByteRange,
+        ) -> StorageFuture<'a, Bytes> {
+            Box::pin(async move {
+                let bytes = self.get(format!("chunks/{id}"))?;
+                range.slice(bytes).map_err(|err| StorageError::Other(err.to_string()))
+            })
+        }
+
+        fn write_snapshot<'a>(
+            &'a self,
+            id: SnapshotId,
+            table: Arc<Snapshot>,
+        ) -> StorageFuture<'a, ()> {
+            Box::pin(
+                async move { self.encode(format!("snapshots/{id}"), table.as_ref()) },
+            )
+        }
+
+        fn write_attributes<'a>(
+            &'a self,
+            id: AttributesId,
+            table: Arc<AttributesTable>,
+        ) -> StorageFuture<'a, ()> {
+            Box::pin(
+                async move { self.encode(format!("attributes/{id}"), table.as_ref()) },
+            )
+        }
+
+        fn write_manifests<'a>(
+            &'a self,
+            id: ManifestId,
+            table: Arc<Manifest>,
+        ) -> StorageFuture<'a, ()> {
+            Box::pin(
+                async move { self.encode(format!("manifests/{id}"), table.as_ref()) },
+            )
+        }
+
+        fn write_chunk<'a>(&'a self, id: ChunkId, bytes: Bytes) -> StorageFuture<'a, ()> {
+            self.put(format!("chunks/{id}"), bytes);
+            Box::pin(async { Ok(()) })
+        }
+
+        fn delete_snapshot<'a>(&'a self, id: &'a SnapshotId) -> StorageFuture<'a, ()> {
+            self.delete(format!("snapshots/{id}"))
+        }
+
+        fn delete_manifests<'a>(&'a self, id: &'a ManifestId) -> StorageFuture<'a, ()> {
+            self.delete(format!("manifests/{id}"))
+        }
+
+        fn delete_chunk<'a>(&'a self, id: &'a ChunkId) -> StorageFuture<'a, ()> {
+            self.delete(format!("chunks/{id}"))
+        }
+
+        fn get_ref<'a>(&'a self, ref_key: &'a str) -> StorageFuture<'a, Bytes> {
+            Box::pin(async move {
+                self.get(format!("refs/{ref_key}"))
+                    .map_err(|_| StorageError::RefNotFound(ref_key.to_string()))
+            })
+        }
+
+        fn ref_names(&self) -> StorageFuture<'_, Vec<String>> {
+            Box::pin(async move {
+                let mut names: Vec<String> = self
+                    .keys_under("refs/")
+                    .into_iter()
+                    .filter_map(|key| key.split('/').next().map(str::to_string))
+                    .collect();
+                names.dedup();
+                Ok(names)
+            })
+        }
+
+        fn ref_versions<'a, 'b>(
+            &'a self,
+            ref_name: &'b str,
+        ) -> StorageFuture<'b, BoxStream<'a, StorageResult<String>>>
+        where
+            'a: 'b,
+        {
+            Box::pin(async move {
+                let versions = self.keys_under(&format!("refs/{ref_name}/"));
+                Ok(stream::iter(versions.into_iter().map(Ok)).boxed())
+            })
+        }
+
+        fn write_ref<'a>(
+            &'a self,
+            ref_key: &'a str,
+            overwrite_refs: bool,
+            bytes: Bytes,
+        ) -> StorageFuture<'a, ()> {
+            Box::pin(async move {
+                let key = format!("refs/{ref_key}");
+                let mut objects = self.objects.lock().unwrap();
+                if !overwrite_refs && objects.contains_key(&key) {
+                    return Err(StorageError::RefAlreadyExists(key));
+                }
+                objects.insert(key, bytes);
+                Ok(())
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_repository_with_custom_storage() -> Result<(), Box<dyn Error>> {
+        let counting = Arc::new(CountingStorage {
+            backend: Arc::new(ObjectStorage::new_in_memory_store(None)),
+            fetches: AtomicUsize::new(0),
+        });
+        let storage: Arc<dyn Storage> = Arc::clone(&counting) as Arc<dyn Storage>;
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+
+        let array: Path = "/array".try_into()?;
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(2, 1, FillValue::Int32(0))).await?;
+        let payload = ChunkPayload::Inline(Bytes::from_static(b"hello"));
+        ds.set_chunk_ref(array.clone(), ChunkIndices(vec![0]), Some(payload.clone()))
+            .await?;
+        let snapshot = ds.commit("main", "first", None).await?;
+
+        let ds = Repository::from_branch_tip(storage, "main").await?.build();
+        assert_eq!(ds.snapshot_id(), &snapshot);
+        let before = counting.fetches.load(Ordering::Relaxed);
+        assert_eq!(
+            ds.get_chunk_ref(&array, &ChunkIndices(vec![0])).await?,
+            Some(payload)
+        );
+        assert!(counting.fetches.load(Ordering::Relaxed) > before);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_storage_written_from_scratch() -> Result<(), Box<dyn Error>> {
+        let backend = Arc::new(MapStorage::default());
+        let storage: Arc<dyn Storage> = Arc::clone(&backend) as Arc<dyn Storage>;
+        let mut ds = Repository::init(Arc::clone(&storage), false).await?.build();
+        ds.add_group(Path::root()).await?;
+        for n in 0..20 {
+            ds.add_group(format!("/group{n}").try_into()?).await?;
+        }
+        ds.commit("main", "groups", None).await?;
+        ds.add_group("/one_more".try_into()?).await?;
+        let snapshot = ds.commit("main", "one more group", None).await?;
+
+        // the second snapshot only stores the node it adds
+        let stored: Snapshot = backend.decode(format!("snapshots/{snapshot}"))?;
+        assert!(stored.structure_base().is_some());
+        assert_eq!(stored.len(), 1);
+
+        let ds = Repository::from_branch_tip(storage, "main").await?.build();
+        assert_eq!(ds.snapshot_id(), &snapshot);
+        assert_eq!(ds.list_nodes().await?.count(), 22);
+        assert!(ds.get_group(&"/group7".try_into()?).await.is_ok());
+        Ok(())
+    }
+}
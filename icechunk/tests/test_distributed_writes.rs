@@ -16,8 +16,8 @@ const SIZE: usize = 10;
 
 async fn mk_storage(
     prefix: &str,
-) -> Result<Arc<dyn Storage + Send + Sync>, Box<dyn std::error::Error + Send + Sync>> {
-    let storage: Arc<dyn Storage + Send + Sync> = Arc::new(
+) -> Result<Arc<dyn Storage>, Box<dyn std::error::Error + Send + Sync>> {
+    let storage: Arc<dyn Storage> = Arc::new(
         S3Storage::new_s3_store(
             "testbucket",
             prefix,
@@ -30,6 +30,7 @@ async fn mk_storage(
                     session_token: None,
                 }),
                 allow_http: true,
+                ..Default::default()
             }),
         )
         .await?,
@@ -38,7 +39,7 @@ async fn mk_storage(
 }
 
 async fn mk_repo(
-    storage: Arc<dyn Storage + Send + Sync>,
+    storage: Arc<dyn Storage>,
     init: bool,
 ) -> Result<Repository, Box<dyn std::error::Error + Send + Sync>> {
     if init {
@@ -53,18 +54,15 @@ async fn mk_repo(
 
 async fn write_chunks(
     mut repo: Repository,
-    xs: Range<u32>,
-    ys: Range<u32>,
+    xs: Range<u64>,
+    ys: Range<u64>,
 ) -> Result<Repository, Box<dyn std::error::Error + Send + Sync>> {
     for x in xs {
         for y in ys.clone() {
             let fx = x as f64;
             let fy = y as f64;
-            let bytes: Vec<u8> = fx
-                .to_le_bytes()
-                .into_iter()
-                .chain(fy.to_le_bytes().into_iter())
-                .collect();
+            let bytes: Vec<u8> =
+                fx.to_le_bytes().into_iter().chain(fy.to_le_bytes()).collect();
             let payload =
                 repo.get_chunk_writer()(Bytes::copy_from_slice(bytes.as_slice())).await?;
             repo.set_chunk_ref(
@@ -81,8 +79,8 @@ async fn write_chunks(
 async fn verify(
     repo: Repository,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    for x in 0..(SIZE / 2) as u32 {
-        for y in 0..(SIZE / 2) as u32 {
+    for x in 0..(SIZE / 2) as u64 {
+        for y in 0..(SIZE / 2) as u64 {
             let bytes = get_chunk(
                 repo.get_chunk_reader(
                     &"/array".try_into().unwrap(),
@@ -134,6 +132,7 @@ async fn test_distributed_writes() -> Result<(), Box<dyn std::error::Error + Sen
         codecs: vec![],
         storage_transformers: None,
         dimension_names: None,
+        rectilinear_grid: None,
     };
 
     let new_array_path: Path = "/array".try_into().unwrap();
@@ -147,7 +146,7 @@ async fn test_distributed_writes() -> Result<(), Box<dyn std::error::Error + Sen
     let mut set = JoinSet::new();
     #[allow(clippy::erasing_op, clippy::identity_op)]
     {
-        let size2 = SIZE as u32;
+        let size2 = SIZE as u64;
         let size24 = size2 / 4;
         let xrange1 = size24 * 0..size24 * 1;
         let xrange2 = size24 * 1..size24 * 2;
@@ -0,0 +1,182 @@
+//! Tests of the S3 backend against a real object store.
+//!
+//! Enabled with the `integration-tests` feature, `just integration-tests` starts MinIO
+//! with docker compose and runs them. Other S3 compatible stores, like localstack, are
+//! selected with the environment variables read by [`s3_config`].
+#![cfg(feature = "integration-tests")]
+#![allow(clippy::unwrap_used, clippy::expect_used)]
+use std::{env, sync::Arc};
+
+use bytes::Bytes;
+use chrono::Utc;
+use futures::{stream, StreamExt, TryStreamExt};
+use icechunk::{
+    format::{manifest::ChunkPayload, ByteRange, ChunkId, SnapshotId},
+    refs::{fetch_branch_tip, update_branch, RefError},
+    repository::get_chunk,
+    storage::{
+        s3::{
+            mk_client, S3Config, S3Credentials, S3Storage, StaticS3Credentials,
+            MULTIPART_THRESHOLD_BYTES,
+        },
+        ObjectKind, ObjectLocation, StorageError,
+    },
+    synthetic::{generate, SyntheticRepoConfig},
+    ErrorKind, Repository, Storage,
+};
+use pretty_assertions::assert_eq;
+
+type TestResult = Result<(), Box<dyn std::error::Error>>;
+
+fn env_or(name: &str, default: &str) -> String {
+    env::var(name).unwrap_or_else(|_| default.to_string())
+}
+
+/// The store under test, MinIO from `compose.yaml` by default
+fn s3_config() -> S3Config {
+    S3Config {
+        region: Some(env_or("ICECHUNK_S3_REGION", "us-east-1")),
+        endpoint: Some(env_or("ICECHUNK_S3_ENDPOINT", "http://localhost:9000")),
+        credentials: S3Credentials::Static(StaticS3Credentials {
+            access_key_id: env_or("ICECHUNK_S3_ACCESS_KEY_ID", "minio123"),
+            secret_access_key: env_or("ICECHUNK_S3_SECRET_ACCESS_KEY", "minio123"),
+            session_token: None,
+        }),
+        allow_http: true,
+        ..Default::default()
+    }
+}
+
+/// A storage in a new prefix of the test bucket, the bucket is created if needed
+async fn mk_storage(test: &str) -> Result<Arc<dyn Storage>, Box<dyn std::error::Error>> {
+    let config = s3_config();
+    let bucket = env_or("ICECHUNK_S3_BUCKET", "testbucket");
+    let client = mk_client(Some(&config)).await?;
+    if client.head_bucket().bucket(bucket.as_str()).send().await.is_err() {
+        client.create_bucket().bucket(bucket.as_str()).send().await?;
+    }
+    let prefix = format!("test_s3_integration__{test}__{}", Utc::now().to_rfc3339());
+    Ok(Arc::new(S3Storage::new_s3_store(bucket, prefix, Some(&config)).await?))
+}
+
+#[tokio::test]
+async fn test_multipart_chunk_write_read() -> TestResult {
+    let storage = mk_storage("multipart").await?;
+    let id = ChunkId::random();
+    let bytes: Bytes =
+        (0..MULTIPART_THRESHOLD_BYTES + 1234).map(|i| (i % 251) as u8).collect();
+    storage.write_chunk(id.clone(), bytes.clone()).await?;
+
+    assert_eq!(storage.fetch_chunk(&id, &ByteRange::ALL).await?, bytes);
+    // a range crossing the boundary of the parts
+    let start = 8 * 1024 * 1024 - 10;
+    let range = ByteRange::from_offset_with_length(start as u64, 20);
+    assert_eq!(storage.fetch_chunk(&id, &range).await?, bytes.slice(start..start + 20));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_conditional_ref_writes() -> TestResult {
+    let storage = mk_storage("conditional").await?;
+    storage.write_ref("tag.v1/ref.json", false, Bytes::from_static(b"{}")).await?;
+    let res =
+        storage.write_ref("tag.v1/ref.json", false, Bytes::from_static(b"{}")).await;
+    assert!(matches!(res, Err(StorageError::RefAlreadyExists(_))));
+
+    // concurrent updates from the same parent, only one of them wins
+    let parent = SnapshotId::random();
+    update_branch(storage.as_ref(), "main", parent.clone(), None, false).await?;
+    let results: Vec<_> = stream::iter(0..8)
+        .map(|_| {
+            let storage = Arc::clone(&storage);
+            let parent = parent.clone();
+            async move {
+                let id = SnapshotId::random();
+                let res = update_branch(
+                    storage.as_ref(),
+                    "main",
+                    id.clone(),
+                    Some(&parent),
+                    false,
+                )
+                .await;
+                (id, res)
+            }
+        })
+        .buffer_unordered(8)
+        .collect()
+        .await;
+
+    let winners: Vec<_> = results.iter().filter(|(_, res)| res.is_ok()).collect();
+    assert_eq!(winners.len(), 1);
+    assert!(results
+        .iter()
+        .filter(|(_, res)| res.is_err())
+        .all(|(_, res)| matches!(res, Err(RefError::Conflict { .. }))));
+    assert_eq!(fetch_branch_tip(storage.as_ref(), "main").await?.snapshot, winners[0].0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_list_pagination() -> TestResult {
+    let storage = mk_storage("pagination").await?;
+    // listings return at most 1000 keys per page
+    let written = 1234;
+    stream::iter(0..written)
+        .map(|_| storage.write_chunk(ChunkId::random(), Bytes::from_static(b"chunk")))
+        .buffer_unordered(64)
+        .try_collect::<Vec<_>>()
+        .await?;
+
+    let listed: Vec<_> = storage
+        .list_objects(ObjectKind::Chunk, ObjectLocation::Live)
+        .await?
+        .try_collect()
+        .await?;
+    assert_eq!(listed.len(), written);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_error_classification() -> TestResult {
+    let storage = mk_storage("errors").await?;
+    let err = storage.fetch_snapshot(&SnapshotId::random()).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert!(!err.is_retryable());
+
+    // nothing listens on this port, every attempt fails to connect
+    let config = S3Config {
+        endpoint: Some("http://localhost:1".to_string()),
+        max_attempts: Some(2),
+        ..s3_config()
+    };
+    let unreachable =
+        S3Storage::new_s3_store("testbucket", "prefix", Some(&config)).await?;
+    let err = unreachable.fetch_snapshot(&SnapshotId::random()).await.unwrap_err();
+    assert!(err.is_retryable());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_synthetic_repository() -> TestResult {
+    let storage = mk_storage("synthetic").await?;
+    let config = SyntheticRepoConfig { chunk_size_bytes: 1024, ..Default::default() };
+    let synthetic = generate(Arc::clone(&storage), &config).await?;
+
+    let repo = Repository::from_branch_tip(Arc::clone(&storage), "main").await?.build();
+    assert_eq!(repo.snapshot_id(), synthetic.tip());
+    let chunks: Vec<_> = repo.all_chunks().await?.try_collect().await?;
+    assert_eq!(chunks.len() as u64, config.arrays as u64 * config.chunks_per_array);
+    for id in synthetic.committed_chunks.iter() {
+        storage.fetch_chunk(id, &ByteRange::ALL).await?;
+    }
+    // inline chunks are read from the manifests, without fetching objects
+    for (path, chunk) in chunks.iter() {
+        if let ChunkPayload::Inline(bytes) = &chunk.payload {
+            let reader =
+                repo.get_chunk_reader(path, &chunk.coord, &ByteRange::ALL).await?;
+            assert_eq!(get_chunk(reader).await?.as_ref(), Some(bytes));
+        }
+    }
+    Ok(())
+}
@@ -31,6 +31,7 @@ async fn mk_storage() -> StorageResult<S3Storage> {
                 session_token: None,
             }),
             allow_http: true,
+            ..Default::default()
         }),
     )
     .await
@@ -3,16 +3,17 @@
 mod tests {
     use icechunk::{
         format::{
-            manifest::{VirtualChunkLocation, VirtualChunkRef},
+            manifest::{VirtualChunkLocation, VirtualChunkRef, VirtualReferenceError},
             ByteRange, ChunkId, ChunkIndices, Path,
         },
         metadata::{ChunkKeyEncoding, ChunkShape, DataType, FillValue},
-        repository::{get_chunk, ChunkPayload, ZarrArrayMetadata},
+        repository::{get_chunk, ChunkPayload, RepositoryError, ZarrArrayMetadata},
         storage::{
             s3::{mk_client, S3Config, S3Credentials, S3Storage, StaticS3Credentials},
             virtual_ref::ObjectStoreVirtualChunkResolverConfig,
             ObjectStorage,
         },
+        synthetic::vector_metadata,
         zarr::AccessMode,
         Repository, Storage, Store,
     };
@@ -36,6 +37,7 @@ mod tests {
                 session_token: None,
             }),
             allow_http: true,
+            ..Default::default()
         }
     }
 
@@ -45,11 +47,12 @@ mod tests {
             endpoint: None,
             credentials: S3Credentials::Anonymous,
             allow_http: false,
+            ..Default::default()
         }
     }
 
     async fn create_repository(
-        storage: Arc<dyn Storage + Send + Sync>,
+        storage: Arc<dyn Storage>,
         virtual_s3_config: S3Config,
     ) -> Repository {
         Repository::init(storage, true)
@@ -83,7 +86,7 @@ mod tests {
         path: &StdPath,
         virtual_s3_config: S3Config,
     ) -> Repository {
-        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(
+        let storage: Arc<dyn Storage> = Arc::new(
             ObjectStorage::new_local_store(path).expect("Creating local storage failed"),
         );
 
@@ -91,7 +94,7 @@ mod tests {
     }
 
     async fn create_minio_repository() -> Repository {
-        let storage: Arc<dyn Storage + Send + Sync> = Arc::new(
+        let storage: Arc<dyn Storage> = Arc::new(
             S3Storage::new_s3_store(
                 "testbucket".to_string(),
                 format!("{:?}", ChunkId::random()),
@@ -111,7 +114,7 @@ mod tests {
     }
 
     async fn write_chunks_to_minio(chunks: impl Iterator<Item = (String, Bytes)>) {
-        let client = mk_client(Some(&minino_s3_config())).await;
+        let client = mk_client(Some(&minino_s3_config())).await.unwrap();
 
         let bucket_name = "testbucket".to_string();
         for (key, bytes) in chunks {
@@ -149,6 +152,7 @@ mod tests {
             codecs: vec![],
             storage_transformers: None,
             dimension_names: None,
+            rectilinear_grid: None,
         };
         let payload1 = ChunkPayload::Virtual(VirtualChunkRef {
             location: VirtualChunkLocation::from_absolute_path(&format!(
@@ -232,12 +236,130 @@ mod tests {
                 )
                 .await
                 .unwrap(),
-                Some(range.slice(bytes1.clone()))
+                Some(range.slice(bytes1.clone()).unwrap())
             );
         }
         Ok(())
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_materialize_local_virtual_refs() -> Result<(), Box<dyn Error>> {
+        let chunk_dir = TempDir::new()?;
+        let chunk_1 = chunk_dir.path().join("chunk-1").to_str().unwrap().to_owned();
+        let chunk_2 = chunk_dir.path().join("chunk-2").to_str().unwrap().to_owned();
+        let bytes1 = Bytes::copy_from_slice(b"first");
+        let bytes2 = Bytes::copy_from_slice(b"second0000");
+        let chunks = [(chunk_1.clone(), bytes1.clone()), (chunk_2, bytes2.clone())];
+        write_chunks_to_local_fs(chunks.iter().cloned()).await;
+
+        let repo_dir = TempDir::new()?;
+        let mut ds = create_local_repository(repo_dir.path(), anon_s3_config()).await;
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(2, 1, FillValue::Int32(0))).await?;
+        for (coord, (file, _)) in chunks.iter().enumerate() {
+            let location =
+                VirtualChunkLocation::from_absolute_path(&format!("file://{file}"))?;
+            let chunk_ref = VirtualChunkRef { location, offset: 1, length: 4 };
+            ds.set_chunk_ref(
+                array.clone(),
+                ChunkIndices(vec![coord as u64]),
+                Some(ChunkPayload::Virtual(chunk_ref)),
+            )
+            .await?;
+        }
+        ds.commit("main", "virtual", None).await?;
+
+        // only the chunks from the first file
+        let first_file =
+            VirtualChunkLocation::from_absolute_path(&format!("file://{chunk_1}"))?;
+        let copied = ds
+            .materialize_virtual_refs(|_, chunk_ref| chunk_ref.location == first_file)
+            .await?;
+        assert_eq!(copied, 1);
+        ds.commit("main", "materialized", None).await?;
+        std::fs::remove_file(&chunk_1)?;
+
+        let payload = ds.get_chunk_ref(&array, &ChunkIndices(vec![0])).await?;
+        assert!(matches!(payload, Some(ChunkPayload::Inline(_))));
+        let payload = ds.get_chunk_ref(&array, &ChunkIndices(vec![1])).await?;
+        assert!(matches!(payload, Some(ChunkPayload::Virtual(_))));
+        for (coord, bytes) in [bytes1, bytes2].into_iter().enumerate() {
+            let reader = ds
+                .get_chunk_reader(
+                    &array,
+                    &ChunkIndices(vec![coord as u64]),
+                    &ByteRange::ALL,
+                )
+                .await?;
+            assert_eq!(get_chunk(reader).await?, Some(bytes.slice(1..5)));
+        }
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_validate_local_virtual_refs() -> Result<(), Box<dyn Error>> {
+        let chunk_dir = TempDir::new()?;
+        let file = chunk_dir.path().join("chunk-1").to_str().unwrap().to_owned();
+        write_chunks_to_local_fs(
+            [(file.clone(), Bytes::copy_from_slice(b"first"))].into_iter(),
+        )
+        .await;
+
+        let repo_dir = TempDir::new()?;
+        let storage: Arc<dyn Storage> =
+            Arc::new(ObjectStorage::new_local_store(repo_dir.path())?);
+        let mut ds = Repository::init(storage, true)
+            .await?
+            .with_virtual_ref_config(ObjectStoreVirtualChunkResolverConfig::S3(
+                anon_s3_config(),
+            ))
+            .with_validate_virtual_refs(true)
+            .build();
+        let array: Path = "/array".try_into().unwrap();
+        ds.add_group(Path::root()).await?;
+        ds.add_array(array.clone(), vector_metadata(3, 1, FillValue::Int32(0))).await?;
+        let location =
+            VirtualChunkLocation::from_absolute_path(&format!("file://{file}"))?;
+        let missing = VirtualChunkLocation::from_absolute_path(&format!(
+            "file://{}",
+            chunk_dir.path().join("typo").to_str().unwrap()
+        ))?;
+        let payload = |location, offset, length| {
+            Some(ChunkPayload::Virtual(VirtualChunkRef { location, offset, length }))
+        };
+
+        ds.set_chunk_ref(
+            array.clone(),
+            ChunkIndices(vec![0]),
+            payload(location.clone(), 1, 4),
+        )
+        .await?;
+        assert!(matches!(
+            ds.set_chunk_ref(
+                array.clone(),
+                ChunkIndices(vec![1]),
+                payload(location, 2, 4)
+            )
+            .await,
+            Err(RepositoryError::VirtualReferenceError(
+                VirtualReferenceError::OutOfBounds { offset: 2, length: 4, size: 5, .. }
+            ))
+        ));
+        assert!(matches!(
+            ds.set_chunk_ref(
+                array.clone(),
+                ChunkIndices(vec![2]),
+                payload(missing, 0, 1)
+            )
+            .await,
+            Err(RepositoryError::VirtualReferenceError(
+                VirtualReferenceError::FetchError(_)
+            ))
+        ));
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_repository_with_minio_virtual_refs() -> Result<(), Box<dyn Error>> {
         let bytes1 = Bytes::copy_from_slice(b"first");
@@ -259,6 +381,7 @@ mod tests {
             codecs: vec![],
             storage_transformers: None,
             dimension_names: None,
+            rectilinear_grid: None,
         };
         let payload1 = ChunkPayload::Virtual(VirtualChunkRef {
             location: VirtualChunkLocation::from_absolute_path(&format!(
@@ -342,7 +465,7 @@ mod tests {
                 )
                 .await
                 .unwrap(),
-                Some(range.slice(bytes1.clone()))
+                Some(range.slice(bytes1.clone()).unwrap())
             );
         }
         Ok(())